@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS store_metadata (
+	id			INTEGER PRIMARY KEY,
+	min_reader_version	INTEGER
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS gc_pending (
+	hash_id		INTEGER PRIMARY KEY,
+	marked_utc	INTEGER
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS snapshot_keys (
+	snapshot_id	INTEGER PRIMARY KEY,
+	key_id		VARCHAR
+);
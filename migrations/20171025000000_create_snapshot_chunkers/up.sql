@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS snapshot_chunkers (
+	snapshot_id	INTEGER PRIMARY KEY,
+	chunker		VARCHAR
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS blob_checksums (
+	blob_id		INTEGER PRIMARY KEY,
+	crc32c		BIGINT,
+	length		BIGINT
+);
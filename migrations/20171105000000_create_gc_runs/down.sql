@@ -0,0 +1 @@
+DROP TABLE gc_runs;
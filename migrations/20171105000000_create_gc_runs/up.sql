@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS gc_runs (
+	id		INTEGER PRIMARY KEY,
+	utc		BIGINT
+);
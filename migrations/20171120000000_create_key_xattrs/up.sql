@@ -0,0 +1,6 @@
+CREATE TABLE key_xattrs (
+	node_id        INTEGER PRIMARY KEY ON CONFLICT REPLACE,
+	attributes     BLOB NOT NULL,
+
+	FOREIGN KEY(node_id) REFERENCES key_tree(node_id) ON DELETE CASCADE
+);
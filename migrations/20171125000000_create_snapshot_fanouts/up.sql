@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS snapshot_fanouts (
+	snapshot_id	INTEGER PRIMARY KEY,
+	fanout		INTEGER
+);
@@ -0,0 +1,2 @@
+DROP INDEX snapshots_family_snapshot_id;
+DROP TABLE snapshot_parents;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS snapshot_parents (
+	snapshot_id	INTEGER PRIMARY KEY,
+	parent_id	INTEGER
+);
+CREATE INDEX IF NOT EXISTS snapshots_family_snapshot_id ON snapshots (family_id, snapshot_id);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+	seq		INTEGER PRIMARY KEY,
+	utc		BIGINT,
+	operation	VARCHAR,
+	affected	VARCHAR,
+	chain		BLOB
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS hash_verified (
+	hash_id		INTEGER PRIMARY KEY,
+	verified_utc	BIGINT
+);
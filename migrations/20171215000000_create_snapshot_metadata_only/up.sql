@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS snapshot_metadata_only (
+	snapshot_id	INTEGER PRIMARY KEY
+);
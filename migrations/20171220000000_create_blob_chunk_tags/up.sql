@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS blob_chunk_tags (
+	blob_id		INTEGER PRIMARY KEY,
+	tags		BLOB
+);
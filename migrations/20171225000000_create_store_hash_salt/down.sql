@@ -0,0 +1 @@
+DROP TABLE store_hash_salt;
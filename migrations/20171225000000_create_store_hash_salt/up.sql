@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS store_hash_salt (
+	id		INTEGER PRIMARY KEY,
+	salt		BLOB NOT NULL
+);
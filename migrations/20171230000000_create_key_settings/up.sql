@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS key_settings (
+	id			INTEGER PRIMARY KEY,
+	encrypt_filenames	INTEGER NOT NULL
+);
@@ -0,0 +1,368 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Failing fast while a backend is down.
+//!
+//! A `CircuitBreakerBackend` keeps the outcome of the last calls to the backend it wraps. Once
+//! too many of them failed, the breaker opens: calls fail right away, without reaching the
+//! backend, until the cooldown is over. The next call after that is let through as a probe,
+//! while the calls around it keep failing fast. A probe that succeeds closes the breaker and
+//! forgets the failures before it; one that fails opens it for another cooldown.
+
+use backend::{BlobBytes, ListPage, StorageClass, StoreBackend, StoreOutcome};
+use crypto::CipherText;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use util::FnBox;
+
+/// Starts the error of a call that was not tried because the breaker is open.
+const FAST_FAIL: &'static str = "Backend unavailable";
+
+/// Whether `error` is from a call that was not tried because the breaker is open.
+pub fn is_fast_fail(error: &str) -> bool {
+    error.starts_with(FAST_FAIL)
+}
+
+/// When a breaker opens, and for how long.
+#[derive(Clone, Debug)]
+pub struct BreakerSettings {
+    /// Calls to look back over.
+    pub window: usize,
+    /// Fewest calls in the window before the breaker can open.
+    pub min_calls: usize,
+    /// Open when at least this share of the calls in the window failed, from 0 to 1.
+    pub failure_rate: f64,
+    /// Fail fast for this long before letting a probe through.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> BreakerSettings {
+        BreakerSettings {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+enum BreakerState {
+    /// Calls go through; the outcomes of the latest, `true` for a failure, the most recent last.
+    Closed(VecDeque<bool>),
+    /// Calls fail fast until the given time.
+    Open(Instant),
+    /// A probe is out, and calls fail fast until it returns.
+    HalfOpen,
+}
+
+pub struct CircuitBreakerBackend<B> {
+    backend: B,
+    settings: BreakerSettings,
+    state: Mutex<BreakerState>,
+    clock: Box<Fn() -> Instant + Send + Sync>,
+}
+
+impl<B: StoreBackend> CircuitBreakerBackend<B> {
+    pub fn new(backend: B, settings: BreakerSettings) -> CircuitBreakerBackend<B> {
+        CircuitBreakerBackend::with_clock(backend, settings, Instant::now)
+    }
+
+    fn with_clock<C>(backend: B, settings: BreakerSettings, clock: C) -> CircuitBreakerBackend<B>
+    where
+        C: Fn() -> Instant + Send + Sync + 'static,
+    {
+        assert!(settings.window > 0 && settings.min_calls <= settings.window);
+        CircuitBreakerBackend {
+            backend: backend,
+            settings: settings,
+            state: Mutex::new(BreakerState::Closed(VecDeque::new())),
+            clock: Box::new(clock),
+        }
+    }
+
+    /// Whether calls are failing fast.
+    pub fn is_open(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            BreakerState::Closed(_) => false,
+            BreakerState::Open(_) | BreakerState::HalfOpen => true,
+        }
+    }
+
+    /// Make `call` to the backend, unless the breaker is open.
+    fn guard<T, F>(&self, call: F) -> Result<T, String>
+    where
+        F: FnOnce(&B) -> Result<T, String>,
+    {
+        let probe = {
+            let mut state = self.state.lock().unwrap();
+            let reopen_at = match *state {
+                BreakerState::Closed(_) => None,
+                BreakerState::Open(until) => Some(until),
+                BreakerState::HalfOpen => {
+                    return Err(format!("{}: waiting for a probe to return", FAST_FAIL))
+                }
+            };
+            match reopen_at {
+                None => false,
+                Some(until) => {
+                    let now = (self.clock)();
+                    if now < until {
+                        return Err(format!(
+                            "{}: failing fast for another {:?}",
+                            FAST_FAIL,
+                            until - now
+                        ));
+                    }
+                    *state = BreakerState::HalfOpen;
+                    true
+                }
+            }
+        };
+
+        let res = call(&self.backend);
+        self.record(probe, res.is_err());
+        res
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let open = if probe {
+            failed
+        } else {
+            match *state {
+                BreakerState::Closed(ref mut recent) => {
+                    if recent.len() == self.settings.window {
+                        recent.pop_front();
+                    }
+                    recent.push_back(failed);
+                    let failures = recent.iter().filter(|&&f| f).count();
+                    recent.len() >= self.settings.min_calls &&
+                        failures as f64 >= self.settings.failure_rate * recent.len() as f64
+                }
+                // Started before the breaker opened; the probe decides what happens next.
+                BreakerState::Open(_) | BreakerState::HalfOpen => false,
+            }
+        };
+        if open {
+            *state = BreakerState::Open((self.clock)() + self.settings.cooldown);
+        } else if probe {
+            *state = BreakerState::Closed(VecDeque::new());
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for CircuitBreakerBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.guard(|b| b.store(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.guard(|b| b.retrieve(name))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.guard(|b| b.delete(name))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.guard(|b| b.list())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.guard(|b| b.flush())
+    }
+
+    fn store_in_class(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        class: StorageClass,
+    ) -> Result<(), String> {
+        self.guard(|b| b.store_in_class(name, data, class))
+    }
+
+    fn when_durable(&self, name: &[u8], done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.guard(|b| b.when_durable(name, done))
+    }
+
+    fn needs_thaw(&self, name: &[u8]) -> Result<bool, String> {
+        self.guard(|b| b.needs_thaw(name))
+    }
+
+    fn retrieve_bytes(&self, name: &[u8]) -> Result<Option<BlobBytes>, String> {
+        self.guard(|b| b.retrieve_bytes(name))
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, String> {
+        self.guard(|b| b.exists(name))
+    }
+
+    fn store_blob_if_absent(&self, name: &[u8], data: &CipherText) -> Result<StoreOutcome, String> {
+        self.guard(|b| b.store_blob_if_absent(name, data))
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        self.guard(|b| b.list_page(token))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Fails every call while `down` is set, and counts the calls that reach it.
+    struct FlakyBackend {
+        down: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl StoreBackend for FlakyBackend {
+        fn store(&self, _name: &[u8], _data: &CipherText) -> Result<(), String> {
+            self.call().map(|_| ())
+        }
+
+        fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.call()
+        }
+
+        fn delete(&self, _name: &[u8]) -> Result<(), String> {
+            self.call().map(|_| ())
+        }
+
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            self.call().map(|_| vec![])
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            self.call().map(|_| ())
+        }
+    }
+
+    impl FlakyBackend {
+        fn call(&self) -> Result<Option<Vec<u8>>, String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                Err("connection timed out".to_owned())
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    struct Harness {
+        breaker: CircuitBreakerBackend<FlakyBackend>,
+        down: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+        elapsed_secs: Arc<AtomicUsize>,
+    }
+
+    fn harness(settings: BreakerSettings) -> Harness {
+        let down = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let elapsed_secs = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        let clock_secs = elapsed_secs.clone();
+        let backend = FlakyBackend {
+            down: down.clone(),
+            calls: calls.clone(),
+        };
+        Harness {
+            breaker: CircuitBreakerBackend::with_clock(backend, settings, move || {
+                start + Duration::from_secs(clock_secs.load(Ordering::SeqCst) as u64)
+            }),
+            down: down,
+            calls: calls,
+            elapsed_secs: elapsed_secs,
+        }
+    }
+
+    fn settings() -> BreakerSettings {
+        BreakerSettings {
+            window: 10,
+            min_calls: 4,
+            failure_rate: 0.5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_failure_burst() {
+        let h = harness(settings());
+
+        // Scattered failures stay below the rate.
+        for i in 0..10 {
+            h.down.store(i % 4 == 0, Ordering::SeqCst);
+            let _ = h.breaker.retrieve(b"blob");
+        }
+        assert!(!h.breaker.is_open());
+
+        // A burst takes the failures in the window to half.
+        h.down.store(true, Ordering::SeqCst);
+        for _ in 0..3 {
+            assert!(!h.breaker.is_open());
+            assert!(!is_fast_fail(&h.breaker.retrieve(b"blob").unwrap_err()));
+        }
+        assert!(h.breaker.is_open());
+
+        // Calls now fail without reaching the backend.
+        let calls = h.calls.load(Ordering::SeqCst);
+        for _ in 0..5 {
+            assert!(is_fast_fail(&h.breaker.retrieve(b"blob").unwrap_err()));
+            assert!(is_fast_fail(&h.breaker.store(b"blob", &CipherText::new(vec![])).unwrap_err()));
+        }
+        assert_eq!(h.calls.load(Ordering::SeqCst), calls);
+    }
+
+    #[test]
+    fn successful_probe_closes_breaker() {
+        let h = harness(settings());
+        h.down.store(true, Ordering::SeqCst);
+        for _ in 0..4 {
+            assert!(h.breaker.delete(b"blob").is_err());
+        }
+        assert!(h.breaker.is_open());
+
+        // A probe after the cooldown fails while the backend is still down, and reopens it.
+        h.elapsed_secs.store(29, Ordering::SeqCst);
+        assert!(is_fast_fail(&h.breaker.flush().unwrap_err()));
+        h.elapsed_secs.store(30, Ordering::SeqCst);
+        let calls = h.calls.load(Ordering::SeqCst);
+        assert!(!is_fast_fail(&h.breaker.flush().unwrap_err()));
+        assert_eq!(h.calls.load(Ordering::SeqCst), calls + 1);
+        assert!(h.breaker.is_open());
+        h.elapsed_secs.store(59, Ordering::SeqCst);
+        assert!(is_fast_fail(&h.breaker.flush().unwrap_err()));
+
+        // Once it is back, the next probe closes the breaker.
+        h.down.store(false, Ordering::SeqCst);
+        h.elapsed_secs.store(60, Ordering::SeqCst);
+        h.breaker.flush().unwrap();
+        assert!(!h.breaker.is_open());
+
+        // The failures from before are forgotten: it takes a new burst to open it again.
+        h.down.store(true, Ordering::SeqCst);
+        for _ in 0..3 {
+            assert!(h.breaker.delete(b"blob").is_err());
+            assert!(!h.breaker.is_open());
+        }
+        assert!(h.breaker.delete(b"blob").is_err());
+        assert!(h.breaker.is_open());
+    }
+}
@@ -13,19 +13,117 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{BlobBytes, BlobListing, StoreBackend, StoreOutcome};
 use crypto::CipherText;
 use hex::{FromHex, ToHex};
-use std::collections::BTreeMap;
+use libc;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io;
+use std::mem;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::ptr;
+use std::slice;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use util::FnBox;
+
+/// How many written blobs may share one fsync.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncBatch {
+    /// Sync once this many blobs are waiting.
+    pub max_blobs: usize,
+    /// Sync when a blob is stored and the oldest waiting blob was written this long ago.
+    pub max_delay: Duration,
+}
+
+impl Default for SyncBatch {
+    fn default() -> SyncBatch {
+        SyncBatch {
+            max_blobs: 1,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Blobs that have been written, but not synced yet.
+#[derive(Default)]
+struct PendingSync {
+    files: Vec<(Vec<u8>, fs::File)>,
+    since: Option<Instant>,
+    // Called once all of `files` are synced.
+    durable: Vec<Box<FnBox<(), ()>>>,
+    // A failed sync loses its blobs, so every later store fails as well.
+    error: Option<String>,
+}
+
+/// A blob file mapped read-only into memory. It stays readable after the file is deleted, as
+/// the mapping keeps the file alive until it is dropped.
+pub struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is private and read-only, and only unmapped on drop.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`, which must not be empty.
+    fn map(file: &fs::File, len: usize) -> io::Result<Mapping> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mapping {
+                addr: addr,
+                len: len,
+            })
+        }
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+/// The file in the root of a sharded store that records its shard depth.
+const SHARD_DEPTH_FILE: &'static str = "shard-depth";
 
 pub struct FileBackend {
     root: PathBuf,
     read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
     max_cache_size: usize,
+    sync_batch: SyncBatch,
+    pending: Mutex<PendingSync>,
+    mmap_reads: bool,
+    buffered_reads: AtomicUsize,
+    shard_depth: usize,
 }
 
 impl FileBackend {
@@ -34,7 +132,182 @@ impl FileBackend {
             root: root,
             read_cache: Mutex::new(BTreeMap::new()),
             max_cache_size: 10,
+            sync_batch: SyncBatch::default(),
+            pending: Mutex::new(PendingSync::default()),
+            mmap_reads: false,
+            buffered_reads: AtomicUsize::new(0),
+            shard_depth: 0,
+        }
+    }
+
+    /// Keep blobs in `depth` levels of subdirectories, named by the next two hex digits of the
+    /// blob name each, so that no directory grows to millions of entries. Blobs are found by
+    /// name alone, so a store must always be opened with the depth it was written with; see
+    /// `check_shard_depth`.
+    pub fn with_shard_depth(mut self, depth: usize) -> FileBackend {
+        self.shard_depth = depth;
+        self
+    }
+
+    /// Refuse a store written with another shard depth than this one, as none of its blobs would
+    /// be found. A sharded store records its depth in a file in its root, written here on first
+    /// use; a store without that file is unsharded.
+    pub fn check_shard_depth(&self) -> Result<(), String> {
+        let path = self.root.join(SHARD_DEPTH_FILE);
+        let stored = match fs::read_to_string(&path) {
+            Ok(depth) => {
+                Some(depth.trim().parse::<usize>().map_err(|e| {
+                    format!("Could not read {}: {}", path.display(), e)
+                })?)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+        };
+        let written = match stored {
+            Some(depth) => depth,
+            None if self.shard_depth == 0 => return Ok(()),
+            None => {
+                let unsharded = FileBackend::new(self.root.clone());
+                let mut blobs = unsharded.blob_files().map_err(|e| e.to_string())?;
+                if blobs.next().is_none() {
+                    self.write_shard_depth(&path).map_err(|e| {
+                        format!("Could not write {}: {}", path.display(), e)
+                    })?;
+                    return Ok(());
+                }
+                0
+            }
+        };
+        if written != self.shard_depth {
+            return Err(format!(
+                "{} was written with shard depth {}, not {}",
+                self.root.display(),
+                written,
+                self.shard_depth
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_shard_depth(&self, path: &PathBuf) -> io::Result<()> {
+        use self::io::Write;
+
+        let mut file = fs::File::create(path)?;
+        file.write_all(format!("{}\n", self.shard_depth).as_bytes())?;
+        file.sync_all()?;
+        fs::File::open(&self.root)?.sync_all()
+    }
+
+    /// The directories holding blob `name`, from the root down.
+    fn blob_dirs(&self, name: &[u8]) -> Vec<PathBuf> {
+        let hex = name.to_hex();
+        let mut dir = self.root.clone();
+        let mut dirs = vec![dir.clone()];
+        // Names too short for every level stop early.
+        for level in 0..cmp::min(self.shard_depth, hex.len() / 2) {
+            dir.push(&hex[2 * level..2 * level + 2]);
+            dirs.push(dir.clone());
         }
+        dirs
+    }
+
+    /// Where blob `name` is kept.
+    pub fn blob_path(&self, name: &[u8]) -> PathBuf {
+        let mut path = self.blob_dirs(name).pop().expect("root is a blob dir");
+        path.push(&name.to_hex());
+        path
+    }
+
+    /// The path of a new blob `name`, with its directories created.
+    fn new_blob_path(&self, name: &[u8]) -> Result<PathBuf, String> {
+        let path = self.blob_path(name);
+        if self.shard_depth > 0 {
+            let dir = path.parent().expect("blobs are kept in a directory");
+            fs::create_dir_all(dir).map_err(|e| {
+                format!("Could not create {}: {}", dir.display(), e)
+            })?;
+        }
+        Ok(path)
+    }
+
+    /// Every blob file, with the directories it is in walked in turn.
+    fn blob_files(&self) -> Result<BlobFiles, io::Error> {
+        Ok(BlobFiles { dirs: vec![(fs::read_dir(&self.root)?, self.shard_depth)] })
+    }
+
+    /// Map blob files into memory to read them in `retrieve_bytes`, so that chunks are opened
+    /// straight from the page cache instead of from a copy of the whole blob.
+    pub fn with_mmap_reads(mut self, mmap_reads: bool) -> FileBackend {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Blobs read into a buffer so far, rather than mapped.
+    pub fn buffered_reads(&self) -> usize {
+        self.buffered_reads.load(Ordering::SeqCst)
+    }
+
+    /// Cover several blobs with one fsync of the files and the directory. Blobs are only
+    /// reported durable, and so only committed to the index, once that fsync is done.
+    pub fn with_sync_batch(mut self, sync_batch: SyncBatch) -> FileBackend {
+        assert!(sync_batch.max_blobs > 0);
+        self.sync_batch = sync_batch;
+        self
+    }
+
+    fn sync_pending(&self, pending: &mut PendingSync) -> Result<(), String> {
+        if let Some(ref e) = pending.error {
+            return Err(e.clone());
+        }
+        pending.since = None;
+        let files = mem::replace(&mut pending.files, vec![]);
+        let durable = mem::replace(&mut pending.durable, vec![]);
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        // New files, and shard directories, are entries in the directories above them.
+        let dirs: BTreeSet<PathBuf> = files
+            .iter()
+            .flat_map(|&(ref name, _)| self.blob_dirs(&name[..]))
+            .collect();
+        match sync_all(&dirs, &files[..]) {
+            Ok(()) => {
+                for done in durable {
+                    done.call(());
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let e = format!("Could not sync {}: {}", self.root.display(), e);
+                pending.error = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    /// Write `data` to the newly created `file` of blob `name`, and sync it with its batch.
+    fn write_blob(&self, name: &[u8], mut file: fs::File, data: &CipherText) -> Result<(), String> {
+        use self::io::Write;
+
+        for r in data.slices() {
+            if let Err(e) = file.write_all(r) {
+                return Err(e.to_string());
+            }
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(ref e) = pending.error {
+            return Err(e.clone());
+        }
+        pending.files.push((name.to_vec(), file));
+        let since = *pending.since.get_or_insert_with(Instant::now);
+        if pending.files.len() >= self.sync_batch.max_blobs ||
+            since.elapsed() >= self.sync_batch.max_delay
+        {
+            self.sync_pending(&mut pending)?;
+        }
+        Ok(())
     }
 
     fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
@@ -47,16 +320,10 @@ impl FileBackend {
     fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
         use self::io::Read;
 
-        // Read key:
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
-
-        match fs::File::open(&path) {
+        match fs::File::open(&self.blob_path(name)) {
             Err(_) => Ok(None),
             Ok(mut fd) => {
+                self.buffered_reads.fetch_add(1, Ordering::SeqCst);
                 let mut buf = Vec::new();
                 match fd.read_to_end(&mut buf) {
                     Ok(_) => Ok(Some(buf)),
@@ -81,21 +348,51 @@ impl FileBackend {
 
 impl StoreBackend for FileBackend {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
-        use self::io::Write;
+        let path = self.new_blob_path(name)?;
 
-        let mut path = self.root.clone();
-        path.push(&name.to_hex());
+        if self.mmap_reads {
+            // Truncating a file that is mapped would make reading the mapping fault; the old
+            // file is unlinked instead, and lives on for as long as it is mapped.
+            match fs::remove_file(&path) {
+                Ok(()) => self.guarded_cache_delete(name),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
 
-        let mut file = match fs::File::create(&path) {
+        let file = match fs::File::create(&path) {
             Err(e) => return Err(e.to_string()),
             Ok(f) => f,
         };
+        self.write_blob(name, file, data)
+    }
 
-        for r in data.slices() {
-            if let Err(e) = file.write_all(r) {
-                return Err(e.to_string());
+    fn store_blob_if_absent(&self, name: &[u8], data: &CipherText) -> Result<StoreOutcome, String> {
+        let path = self.new_blob_path(name)?;
+
+        // Only one of several processes creating the same file gets to write it.
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => self.write_blob(name, file, data).map(|()| StoreOutcome::Stored),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Ok(StoreOutcome::AlreadyPresent)
             }
+            Err(e) => Err(e.to_string()),
         }
+    }
+
+    fn when_durable(&self, name: &[u8], done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(ref e) = pending.error {
+                return Err(e.clone());
+            }
+            if pending.files.iter().any(|&(ref n, _)| &n[..] == name) {
+                pending.durable.push(done);
+                return Ok(());
+            }
+        }
+        // Synced already.
+        done.call(());
         Ok(())
     }
 
@@ -114,17 +411,35 @@ impl StoreBackend for FileBackend {
         res
     }
 
+    fn retrieve_bytes(&self, name: &[u8]) -> Result<Option<BlobBytes>, String> {
+        if !self.mmap_reads {
+            return self.retrieve(name).map(|blob| blob.map(BlobBytes::Owned));
+        }
+
+        let path = self.blob_path(name);
+        let file = match fs::File::open(&path) {
+            Err(_) => return Ok(None),
+            Ok(file) => file,
+        };
+        let len = match file.metadata() {
+            Ok(meta) => meta.len() as usize,
+            Err(e) => return Err(format!("Could not stat {}: {}", path.display(), e)),
+        };
+        if len == 0 {
+            // Nothing to map.
+            return Ok(Some(BlobBytes::Owned(vec![])));
+        }
+        match Mapping::map(&file, len) {
+            Ok(mapping) => Ok(Some(BlobBytes::Mapped(mapping))),
+            Err(e) => Err(format!("Could not map {}: {}", path.display(), e)),
+        }
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String> {
         let name = name.to_vec();
         self.guarded_cache_delete(&name);
 
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
-
-        match fs::remove_file(&path) {
+        match fs::remove_file(&self.blob_path(&name[..])) {
             Ok(_) => Ok(()),
             Err(e) => Err(e.to_string()),
         }
@@ -134,17 +449,285 @@ impl StoreBackend for FileBackend {
         let es = &|e: io::Error| e.to_string();
 
         let mut out = vec![];
-        for p in fs::read_dir(&self.root).map_err(es)? {
-            if let Some(name) = p.map_err(es)?.path().file_name() {
-                name.to_str().map(|s| Vec::from_hex(s).unwrap()).map(|b| {
-                    out.push(b.into_boxed_slice())
-                });
-            }
+        for entry in self.blob_files().map_err(es)? {
+            let (name, _) = entry.map_err(es)?;
+            out.push(name.into_boxed_slice());
         }
         Ok(out)
     }
 
     fn flush(&self) -> Result<(), String> {
-        Ok(())
+        let mut pending = self.pending.lock().unwrap();
+        self.sync_pending(&mut pending)
+    }
+
+    fn is_sharded(&self) -> bool {
+        self.shard_depth > 0
+    }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, String> {
+        let path = self.blob_path(name);
+        match fs::metadata(&path) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(format!("Could not stat {}: {}", path.display(), e)),
+        }
+    }
+
+    fn list_blobs<'a>(&'a self) -> Box<Iterator<Item = Result<BlobListing, String>> + 'a> {
+        let files = match self.blob_files() {
+            Ok(files) => files,
+            Err(e) => return Box::new(Some(Err(e.to_string())).into_iter()),
+        };
+        Box::new(files.map(|entry| {
+            let (name, entry) = entry.map_err(|e| e.to_string())?;
+            entry.metadata().map_err(|e| e.to_string()).map(|meta| {
+                BlobListing {
+                    name: name.into_boxed_slice(),
+                    size: meta.len(),
+                }
+            })
+        }))
+    }
+}
+
+/// The blob files under a root, found by walking the shard directories depth first.
+struct BlobFiles {
+    // Directories being read, with how many levels of shards are below each.
+    dirs: Vec<(fs::ReadDir, usize)>,
+}
+
+impl Iterator for BlobFiles {
+    type Item = io::Result<(Vec<u8>, fs::DirEntry)>;
+
+    fn next(&mut self) -> Option<io::Result<(Vec<u8>, fs::DirEntry)>> {
+        loop {
+            let (entry, depth) = match self.dirs.last_mut() {
+                None => return None,
+                Some(&mut (ref mut dir, depth)) => (dir.next(), depth),
+            };
+            let entry = match entry {
+                None => {
+                    self.dirs.pop();
+                    continue;
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(entry)) => entry,
+            };
+            let is_dir = match entry.file_type() {
+                Ok(file_type) => file_type.is_dir(),
+                Err(e) => return Some(Err(e)),
+            };
+            if is_dir {
+                if depth > 0 {
+                    match fs::read_dir(entry.path()) {
+                        Ok(dir) => self.dirs.push((dir, depth - 1)),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                continue;
+            }
+            match entry.file_name().to_str().map(|s| Vec::<u8>::from_hex(s)) {
+                Some(Ok(name)) => return Some(Ok((name, entry))),
+                // Not a blob.
+                _ => continue,
+            }
+        }
+    }
+}
+
+fn sync_all(dirs: &BTreeSet<PathBuf>, files: &[(Vec<u8>, fs::File)]) -> io::Result<()> {
+    for &(_, ref file) in files {
+        file.sync_all()?;
+    }
+    // Make the new directory entries durable as well.
+    for dir in dirs {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+    use std::sync::mpsc;
+
+    #[test]
+    fn batch_is_durable_together() {
+        let root = env::temp_dir().join(format!("hat-sync-batch-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let backend = FileBackend::new(root.clone()).with_sync_batch(SyncBatch {
+            max_blobs: 2,
+            max_delay: Duration::from_secs(3600),
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        let store = |name: &'static [u8]| {
+            backend.store(name, &CipherText::new(name.to_vec())).unwrap();
+            let sender = sender.clone();
+            backend
+                .when_durable(name, Box::new(move |()| sender.send(name).unwrap()))
+                .unwrap();
+        };
+
+        // Written, but waiting for the rest of its batch.
+        store(b"a");
+        assert_eq!(backend.retrieve(b"a").unwrap(), Some(b"a".to_vec()));
+        assert!(receiver.try_recv().is_err());
+
+        // Filling the batch syncs both.
+        store(b"b");
+        let mut synced: Vec<_> = receiver.try_iter().collect();
+        synced.sort();
+        assert_eq!(synced, vec![&b"a"[..], &b"b"[..]]);
+
+        // A flush syncs a partial batch.
+        store(b"c");
+        assert!(receiver.try_recv().is_err());
+        backend.flush().unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![&b"c"[..]]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn exists_stats_the_blob() {
+        let root = env::temp_dir().join(format!("hat-exists-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let backend = FileBackend::new(root.clone());
+
+        assert_eq!(backend.exists(b"a"), Ok(false));
+        backend.store(b"a", &CipherText::new(b"a".to_vec())).unwrap();
+        assert_eq!(backend.exists(b"a"), Ok(true));
+        backend.delete(b"a").unwrap();
+        assert_eq!(backend.exists(b"a"), Ok(false));
+
+        // A root that cannot be looked in is an error, not a missing blob.
+        let not_a_dir = root.join("file");
+        fs::File::create(&not_a_dir).unwrap();
+        assert!(FileBackend::new(not_a_dir).exists(b"a").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn shard_depth_is_kept_with_the_store() {
+        let root = env::temp_dir().join(format!("hat-shard-depth-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+
+        // A new store takes the depth it is first used with.
+        FileBackend::new(root.clone()).with_shard_depth(2).check_shard_depth().unwrap();
+        FileBackend::new(root.clone()).with_shard_depth(2).check_shard_depth().unwrap();
+        assert!(FileBackend::new(root.clone()).check_shard_depth().is_err());
+        assert!(FileBackend::new(root.clone()).with_shard_depth(1).check_shard_depth().is_err());
+        fs::remove_dir_all(&root).unwrap();
+
+        // An unsharded store with blobs in it stays unsharded.
+        fs::create_dir_all(&root).unwrap();
+        let backend = FileBackend::new(root.clone());
+        backend.check_shard_depth().unwrap();
+        backend.store(b"blob", &CipherText::new(vec![1])).unwrap();
+        backend.flush().unwrap();
+        assert!(FileBackend::new(root.clone()).with_shard_depth(2).check_shard_depth().is_err());
+        assert!(!root.join(SHARD_DEPTH_FILE).exists());
+        backend.check_shard_depth().unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sharded_blobs_are_found_by_name() {
+        use std::collections::HashSet;
+
+        let root = env::temp_dir().join(format!("hat-sharded-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let backend = FileBackend::new(root.clone()).with_shard_depth(2);
+
+        let mut names = HashSet::new();
+        for i in 0..500u32 {
+            let name: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+            backend.store_blob_if_absent(&name[..], &CipherText::new(vec![0; i as usize])).unwrap();
+            let hex = name.to_hex();
+            let path = root.join(&hex[0..2]).join(&hex[2..4]).join(&hex);
+            assert_eq!(backend.blob_path(&name[..]), path);
+            assert_eq!(fs::metadata(&path).unwrap().len(), i as u64);
+            names.insert(name.into_boxed_slice());
+        }
+        // Too short for both levels.
+        backend.store(b"a", &CipherText::new(b"a".to_vec())).unwrap();
+        assert!(root.join("61").join("61").is_file());
+        names.insert(b"a".to_vec().into_boxed_slice());
+        backend.flush().unwrap();
+
+        // Nothing but shard directories at the top.
+        for entry in fs::read_dir(&root).unwrap() {
+            let entry = entry.unwrap();
+            assert!(entry.file_type().unwrap().is_dir());
+            assert_eq!(entry.file_name().len(), 2);
+        }
+
+        let listed: HashSet<Box<[u8]>> = backend.list().unwrap().into_iter().collect();
+        assert_eq!(listed, names);
+        let listed: HashSet<Box<[u8]>> = backend.list_blobs().map(|b| b.unwrap().name).collect();
+        assert_eq!(listed, names);
+        for name in names.iter() {
+            assert!(backend.exists(&name[..]).unwrap());
+            assert!(backend.retrieve(&name[..]).unwrap().is_some());
+        }
+
+        backend.delete(b"a").unwrap();
+        assert!(!backend.exists(b"a").unwrap());
+        assert_eq!(backend.list().unwrap().len(), names.len() - 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn store_blob_if_absent_writes_once() {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let root = env::temp_dir().join(format!("hat-if-absent-{}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let backend = Arc::new(FileBackend::new(root.clone()));
+
+        for round in 0..20 {
+            let name = format!("blob-{}", round).into_bytes();
+            let barrier = Arc::new(Barrier::new(2));
+            let racers: Vec<_> = (0..2u8)
+                .map(|i| {
+                    let backend = backend.clone();
+                    let barrier = barrier.clone();
+                    let name = name.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        let data = CipherText::new(vec![i; 4096]);
+                        (i, backend.store_blob_if_absent(&name[..], &data).unwrap())
+                    })
+                })
+                .collect();
+            let outcomes: Vec<(u8, StoreOutcome)> =
+                racers.into_iter().map(|t| t.join().unwrap()).collect();
+
+            let winners: Vec<u8> = outcomes
+                .iter()
+                .filter(|&&(_, outcome)| outcome == StoreOutcome::Stored)
+                .map(|&(i, _)| i)
+                .collect();
+            assert_eq!(winners.len(), 1);
+            // The blob is all of what the one that stored it wrote.
+            assert_eq!(backend.retrieve(&name[..]).unwrap(), Some(vec![winners[0]; 4096]));
+        }
+
+        let data = CipherText::new(vec![9; 10]);
+        assert_eq!(
+            backend.store_blob_if_absent(b"blob-0", &data),
+            Ok(StoreOutcome::AlreadyPresent)
+        );
+        backend.flush().unwrap();
+        fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -13,11 +13,15 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{BlobListing, ListPage, StoreBackend, StoreOutcome};
 use crypto::CipherText;
 use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Unbounded};
 use std::sync::Mutex;
 
+/// Blobs listed per page. The continuation token is the name of the last blob on the page.
+const PAGE_SIZE: usize = 1000;
+
 pub struct MemoryBackend {
     files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
 }
@@ -81,4 +85,47 @@ impl StoreBackend for MemoryBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn exists(&self, name: &[u8]) -> Result<bool, String> {
+        match self.files.lock() {
+            Err(e) => Err(e.to_string()),
+            Ok(map) => Ok(map.contains_key(name)),
+        }
+    }
+
+    fn store_blob_if_absent(&self, name: &[u8], data: &CipherText) -> Result<StoreOutcome, String> {
+        let mut guarded_files = self.files.lock().unwrap();
+        if guarded_files.contains_key(name) {
+            return Ok(StoreOutcome::AlreadyPresent);
+        }
+        guarded_files.insert(name.to_vec(), data.to_vec());
+        Ok(StoreOutcome::Stored)
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        let guarded_files = self.files.lock().unwrap();
+        let start = match token {
+            Some(token) => Excluded(token.to_vec()),
+            None => Unbounded,
+        };
+        let blobs: Vec<BlobListing> = guarded_files
+            .range((start, Unbounded))
+            .take(PAGE_SIZE)
+            .map(|(name, data)| {
+                BlobListing {
+                    name: name.clone().into_boxed_slice(),
+                    size: data.len() as u64,
+                }
+            })
+            .collect();
+        let next = if blobs.len() < PAGE_SIZE {
+            None
+        } else {
+            blobs.last().map(|b| b.name.to_vec())
+        };
+        Ok(ListPage {
+            blobs: blobs,
+            next: next,
+        })
+    }
 }
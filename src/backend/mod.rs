@@ -12,15 +12,96 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod breaker;
 mod devnull;
 mod file;
 mod memory;
+mod threaded;
 
 use crypto::CipherText;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::vec;
+use util::FnBox;
 
+pub use self::breaker::{BreakerSettings, CircuitBreakerBackend, is_fast_fail};
 pub use self::devnull::DevNullBackend;
-pub use self::file::FileBackend;
+pub use self::file::{FileBackend, Mapping, SyncBatch};
 pub use self::memory::MemoryBackend;
+pub use self::threaded::{AsyncStoreBackend, BlockingBackend, Callback, ThreadedBackend};
+
+/// How a backend should keep a blob, from the quickest to read to the cheapest to keep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StorageClass {
+    Standard,
+    InfrequentAccess,
+    /// Must be thawed before it can be read again.
+    Archive,
+}
+
+impl StorageClass {
+    pub fn from_name(name: &str) -> Option<StorageClass> {
+        match name {
+            "standard" => Some(StorageClass::Standard),
+            "infrequent-access" => Some(StorageClass::InfrequentAccess),
+            "archive" => Some(StorageClass::Archive),
+            _ => None,
+        }
+    }
+
+    /// The matching value of the `x-amz-storage-class` header, for backends on S3.
+    pub fn s3_name(&self) -> &'static str {
+        match *self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::InfrequentAccess => "STANDARD_IA",
+            StorageClass::Archive => "GLACIER",
+        }
+    }
+}
+
+/// What `store_blob_if_absent` did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreOutcome {
+    Stored,
+    /// A blob was already stored under the name, and was left as it was.
+    AlreadyPresent,
+}
+
+/// A blob found when listing a store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobListing {
+    pub name: Box<[u8]>,
+    pub size: u64,
+}
+
+/// Part of the blobs in a store, and where to continue from.
+pub struct ListPage {
+    pub blobs: Vec<BlobListing>,
+    /// Token to pass to `list_page` for the next page. `None` on the last page.
+    pub next: Option<Vec<u8>>,
+}
+
+/// The contents of a blob, as returned by `StoreBackend::retrieve_bytes`.
+pub enum BlobBytes {
+    /// Read into memory.
+    Owned(Vec<u8>),
+    /// Mapped from a file, for as long as this is kept.
+    Mapped(Mapping),
+    /// Shared with a cache of blobs that were read before.
+    Shared(Arc<Vec<u8>>),
+}
+
+impl Deref for BlobBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            BlobBytes::Owned(ref bytes) => &bytes[..],
+            BlobBytes::Mapped(ref mapping) => &mapping[..],
+            BlobBytes::Shared(ref bytes) => &bytes[..],
+        }
+    }
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
     fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String>;
@@ -28,4 +109,266 @@ pub trait StoreBackend: Sync + Send + 'static {
     fn delete(&self, name: &[u8]) -> Result<(), String>;
     fn list(&self) -> Result<Vec<Box<[u8]>>, String>;
     fn flush(&self) -> Result<(), String>;
+
+    /// Store a blob with a hint of how it is expected to be read. Backends without storage
+    /// classes ignore the hint.
+    fn store_in_class(
+        &self,
+        name: &[u8],
+        data: &CipherText,
+        _class: StorageClass,
+    ) -> Result<(), String> {
+        self.store(name, data)
+    }
+
+    /// Call `done` once the blob just stored under `name` will survive a crash. Backends that
+    /// only make a blob durable some time after `store` returns hold on to `done` until then,
+    /// and drop it if that fails.
+    fn when_durable(&self, _name: &[u8], done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        done.call(());
+        Ok(())
+    }
+
+    /// Whether blobs are kept in subdirectories, where readers from before sharding do not look
+    /// for them.
+    fn is_sharded(&self) -> bool {
+        false
+    }
+
+    /// Whether the blob is in archival storage and must be thawed before it can be retrieved.
+    /// Asked when a retrieve fails, to tell the two apart; a retrieve of such a blob should fail
+    /// right away rather than wait for it to thaw.
+    fn needs_thaw(&self, _name: &[u8]) -> Result<bool, String> {
+        Ok(false)
+    }
+
+    /// Retrieve a blob for reading. Backends that can hand out the blob without copying it,
+    /// such as by mapping its file, override this; by default it is read with `retrieve`.
+    fn retrieve_bytes(&self, name: &[u8]) -> Result<Option<BlobBytes>, String> {
+        self.retrieve(name).map(|blob| blob.map(BlobBytes::Owned))
+    }
+
+    /// Whether a blob is stored under `name`, asked before uploading it so that a resumed backup
+    /// does not send it again. Stores should answer this cheaply, like a HEAD request on S3. A
+    /// failure to find out is an error, and never taken to mean that the blob is missing.
+    ///
+    /// The default retrieves the whole blob.
+    fn exists(&self, name: &[u8]) -> Result<bool, String> {
+        self.retrieve(name).map(|data| data.is_some())
+    }
+
+    /// Store a blob unless one is already stored under `name`, so that two processes storing
+    /// the same blob neither overwrite each other nor both upload it. Stores should decide this
+    /// atomically, like a PUT with `If-None-Match: *` on S3.
+    ///
+    /// The default checks with `exists` first, which leaves a window for both to store it.
+    fn store_blob_if_absent(&self, name: &[u8], data: &CipherText) -> Result<StoreOutcome, String> {
+        if self.exists(name)? {
+            return Ok(StoreOutcome::AlreadyPresent);
+        }
+        self.store(name, data).map(|()| StoreOutcome::Stored)
+    }
+
+    /// List the blobs after `token`, which is `None` for the first page and otherwise the `next`
+    /// token of the page before. Stores that list in pages, like S3, should return those.
+    ///
+    /// The default lists everything as one page, and fetches every blob to learn its size.
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        assert!(token.is_none(), "unexpected continuation token");
+        let mut blobs = vec![];
+        for name in self.list()? {
+            if let Some(data) = self.retrieve(&name[..])? {
+                blobs.push(BlobListing {
+                    name: name,
+                    size: data.len() as u64,
+                });
+            }
+        }
+        Ok(ListPage {
+            blobs: blobs,
+            next: None,
+        })
+    }
+
+    /// Every blob in the store, one page at a time. An error ends the listing.
+    fn list_blobs<'a>(&'a self) -> Box<Iterator<Item = Result<BlobListing, String>> + 'a> {
+        Box::new(Pages {
+            backend: self,
+            page: vec![].into_iter(),
+            next: None,
+            done: false,
+        })
+    }
+}
+
+struct Pages<'a, B: 'a + ?Sized> {
+    backend: &'a B,
+    page: vec::IntoIter<BlobListing>,
+    next: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a, B: StoreBackend + ?Sized> Iterator for Pages<'a, B> {
+    type Item = Result<BlobListing, String>;
+
+    fn next(&mut self) -> Option<Result<BlobListing, String>> {
+        loop {
+            if let Some(blob) = self.page.next() {
+                return Some(Ok(blob));
+            }
+            if self.done {
+                return None;
+            }
+            match self.backend.list_page(self.next.as_ref().map(|t| &t[..])) {
+                Ok(page) => {
+                    self.done = page.next.is_none();
+                    self.next = page.next;
+                    self.page = page.blobs.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::str;
+
+    /// Lists `count` blobs, `page_size` at a time, and fails on the page starting at `fail_at`.
+    struct PagedBackend {
+        count: usize,
+        page_size: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl StoreBackend for PagedBackend {
+        fn store(&self, _name: &[u8], _data: &CipherText) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+
+        fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            Ok(None)
+        }
+
+        fn delete(&self, _name: &[u8]) -> Result<(), String> {
+            Err("read-only".to_owned())
+        }
+
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            unreachable!("listing is paginated")
+        }
+
+        fn flush(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+            let start = token.map_or(0, |t| str::from_utf8(t).unwrap().parse().unwrap());
+            if Some(start) == self.fail_at {
+                return Err("connection reset".to_owned());
+            }
+            let end = ::std::cmp::min(start + self.page_size, self.count);
+            Ok(ListPage {
+                blobs: (start..end)
+                    .map(|i| {
+                        BlobListing {
+                            name: format!("blob-{}", i).into_bytes().into_boxed_slice(),
+                            size: i as u64,
+                        }
+                    })
+                    .collect(),
+                next: if end < self.count {
+                    Some(end.to_string().into_bytes())
+                } else {
+                    None
+                },
+            })
+        }
+    }
+
+    fn all_names<B: StoreBackend>(backend: &B) -> HashSet<Box<[u8]>> {
+        let mut names = HashSet::new();
+        for blob in backend.list_blobs() {
+            assert!(names.insert(blob.unwrap().name));
+        }
+        names
+    }
+
+    #[test]
+    fn list_blobs_across_pages() {
+        for &count in &[0, 1, 3, 10] {
+            let backend = PagedBackend {
+                count: count,
+                page_size: 3,
+                fail_at: None,
+            };
+            assert_eq!(all_names(&backend).len(), count);
+        }
+    }
+
+    #[test]
+    fn list_blobs_reports_errors() {
+        let backend = PagedBackend {
+            count: 10,
+            page_size: 3,
+            fail_at: Some(6),
+        };
+        let listed: Vec<_> = backend.list_blobs().collect();
+        assert_eq!(listed.len(), 7);
+        assert!(listed[..6].iter().all(|b| b.is_ok()));
+        assert!(listed[6].is_err());
+    }
+
+    #[test]
+    fn list_blobs_in_memory() {
+        let backend = MemoryBackend::new();
+        for i in 0..2500 {
+            let name = format!("blob-{}", i).into_bytes();
+            backend.store(&name[..], &CipherText::new(vec![0; i % 7])).unwrap();
+        }
+        let names = all_names(&backend);
+        assert_eq!(names.len(), 2500);
+        assert_eq!(
+            backend.list_blobs().map(|b| b.unwrap().size).sum::<u64>(),
+            (0..2500).map(|i| (i % 7) as u64).sum::<u64>()
+        );
+        for name in backend.list().unwrap() {
+            assert!(names.contains(&name));
+        }
+    }
+
+    #[test]
+    fn store_blob_if_absent_in_memory_writes_once() {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let backend = Arc::new(MemoryBackend::new());
+        let barrier = Arc::new(Barrier::new(8));
+        let racers: Vec<_> = (0..8u8)
+            .map(|i| {
+                let backend = backend.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    let data = CipherText::new(vec![i; 100]);
+                    (i, backend.store_blob_if_absent(b"blob", &data).unwrap())
+                })
+            })
+            .collect();
+        let winners: Vec<u8> = racers
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|&(_, outcome)| outcome == StoreOutcome::Stored)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(backend.retrieve(b"blob").unwrap(), Some(vec![winners[0]; 100]));
+    }
 }
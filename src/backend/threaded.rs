@@ -0,0 +1,182 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Callback based backends.
+//!
+//! `AsyncStoreBackend` lets a host with its own event loop issue many requests at once without
+//! tying up a thread per request. `ThreadedBackend` provides one on top of any `StoreBackend`,
+//! and `BlockingBackend` lets the rest of hat use an `AsyncStoreBackend` as a normal backend.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use scoped_pool;
+use std::sync::{Arc, mpsc};
+use util::FnBox;
+
+pub type Callback<T> = Box<FnBox<Result<T, String>, ()>>;
+
+/// Non-blocking variant of `StoreBackend`.
+/// Every call returns immediately and reports its result through `done`, possibly from a
+/// different thread.
+pub trait AsyncStoreBackend: Sync + Send + 'static {
+    fn store(&self, name: Vec<u8>, data: Vec<u8>, done: Callback<()>);
+    fn retrieve(&self, name: Vec<u8>, done: Callback<Option<Vec<u8>>>);
+    fn delete(&self, name: Vec<u8>, done: Callback<()>);
+    fn list(&self, done: Callback<Vec<Box<[u8]>>>);
+    fn flush(&self, done: Callback<()>);
+}
+
+/// Runs the requests of a synchronous backend on a pool of worker threads.
+pub struct ThreadedBackend<B> {
+    backend: Arc<B>,
+    pool: scoped_pool::Pool,
+}
+
+impl<B: StoreBackend> ThreadedBackend<B> {
+    pub fn new(backend: B, threads: usize) -> ThreadedBackend<B> {
+        ThreadedBackend {
+            backend: Arc::new(backend),
+            pool: scoped_pool::Pool::new(threads),
+        }
+    }
+}
+
+impl<B> Drop for ThreadedBackend<B> {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+impl<B: StoreBackend> AsyncStoreBackend for ThreadedBackend<B> {
+    fn store(&self, name: Vec<u8>, data: Vec<u8>, done: Callback<()>) {
+        let backend = self.backend.clone();
+        self.pool.spawn(move || {
+            done.call(backend.store(&name[..], &CipherText::new(data)))
+        });
+    }
+
+    fn retrieve(&self, name: Vec<u8>, done: Callback<Option<Vec<u8>>>) {
+        let backend = self.backend.clone();
+        self.pool.spawn(move || done.call(backend.retrieve(&name[..])));
+    }
+
+    fn delete(&self, name: Vec<u8>, done: Callback<()>) {
+        let backend = self.backend.clone();
+        self.pool.spawn(move || done.call(backend.delete(&name[..])));
+    }
+
+    fn list(&self, done: Callback<Vec<Box<[u8]>>>) {
+        let backend = self.backend.clone();
+        self.pool.spawn(move || done.call(backend.list()));
+    }
+
+    fn flush(&self, done: Callback<()>) {
+        let backend = self.backend.clone();
+        self.pool.spawn(move || done.call(backend.flush()));
+    }
+}
+
+/// Presents an `AsyncStoreBackend` as a `StoreBackend` by waiting for each request to finish.
+pub struct BlockingBackend<A> {
+    backend: Arc<A>,
+}
+
+impl<A: AsyncStoreBackend> BlockingBackend<A> {
+    pub fn new(backend: Arc<A>) -> BlockingBackend<A> {
+        BlockingBackend { backend: backend }
+    }
+}
+
+fn wait_for<T, F>(request: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(Callback<T>),
+{
+    let (sender, receiver) = mpsc::channel();
+    request(Box::new(move |res: Result<T, String>| {
+        let _ = sender.send(res);
+    }));
+    match receiver.recv() {
+        Ok(res) => res,
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl<A: AsyncStoreBackend> StoreBackend for BlockingBackend<A> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        wait_for(|done| self.backend.store(name.to_vec(), data.to_vec(), done))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        wait_for(|done| self.backend.retrieve(name.to_vec(), done))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        wait_for(|done| self.backend.delete(name.to_vec(), done))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        wait_for(|done| self.backend.list(done))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        wait_for(|done| self.backend.flush(done))
+    }
+}
+
+#[test]
+fn concurrent_store_and_retrieve() {
+    use backend::MemoryBackend;
+
+    let backend = Arc::new(ThreadedBackend::new(MemoryBackend::new(), 4));
+    let names: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i]).collect();
+
+    let (sender, receiver) = mpsc::channel();
+    for name in names.iter() {
+        let sender = sender.clone();
+        backend.store(
+            name.clone(),
+            vec![name[0]; 1000],
+            Box::new(move |res: Result<(), String>| sender.send(res).unwrap()),
+        );
+    }
+    for _ in names.iter() {
+        receiver.recv().unwrap().unwrap();
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    for name in names.iter() {
+        let sender = sender.clone();
+        let expected = name[0];
+        backend.retrieve(
+            name.clone(),
+            Box::new(move |res: Result<Option<Vec<u8>>, String>| {
+                sender.send((expected, res)).unwrap()
+            }),
+        );
+    }
+    for _ in names.iter() {
+        let (expected, res) = receiver.recv().unwrap();
+        assert_eq!(res.unwrap(), Some(vec![expected; 1000]));
+    }
+
+    // The same data is visible through the blocking adapter.
+    let blocking = BlockingBackend::new(backend);
+    assert_eq!(blocking.list().unwrap().len(), names.len());
+    blocking.delete(&[0]).unwrap();
+    assert_eq!(blocking.retrieve(&[0]).unwrap(), None);
+    assert_eq!(blocking.retrieve(&[1]).unwrap(), Some(vec![1; 1000]));
+    assert!(blocking.store(&[1], &CipherText::new(vec![])).is_err());
+}
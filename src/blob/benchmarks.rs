@@ -40,6 +40,7 @@ fn dummy_hashref(keys: &crypto::keys::Keeper) -> HashRef {
             offset: 0,
             length: 0,
             packing: None,
+            pipeline: None,
             key: None,
         },
         info: None,
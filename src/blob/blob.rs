@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use byteorder::{ByteOrder, LittleEndian};
 use crypto;
 use crypto::{CipherText, CipherTextRef, PlainTextRef};
 use hash::tree::HashRef;
@@ -20,15 +21,84 @@ use std::mem;
 use std::sync::Arc;
 
 use super::BlobError;
+use super::pipeline::{ChunkKeys, ChunkPipeline};
+
+
+/// Footer entries are prefixed with their length, which is always below this. An entry with this
+/// prefix instead holds the length of the padding, so that it is authenticated with the footer.
+/// Readers before `hat::PADDED_READER_VERSION` do not know it, so it is only written on request.
+const PADDING_ENTRY: u16 = 0xffff;
+
+/// Length prefix and little-endian u64 of a padding entry.
+const PADDING_ENTRY_BYTES: usize = 2 + 8;
+
+
+/// The integrity tag of a chunk in a blob, with where the chunk is in the blob.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkTag {
+    pub offset: u64,
+    pub length: u64,
+    pub tag: crypto::IntegrityTag,
+}
+
+/// Offset, length and tag of a `ChunkTag` as encoded by `ChunkTag::encode_all`.
+const CHUNK_TAG_BYTES: usize = 8 + 8 + crypto::INTEGRITY_TAG_BYTES;
+
+impl ChunkTag {
+    /// Whether the chunk in `blob`, the whole blob as it is stored, still matches the tag. This
+    /// needs no keys.
+    pub fn verify(&self, blob: &[u8]) -> bool {
+        let end = self.offset + self.length;
+        end <= blob.len() as u64 &&
+            crypto::IntegrityTag::of(&blob[self.offset as usize..end as usize]) == self.tag
+    }
+
+    /// The tags of the chunks of a blob, as one string of bytes.
+    pub fn encode_all(tags: &[ChunkTag]) -> Vec<u8> {
+        let mut out = vec![0u8; tags.len() * CHUNK_TAG_BYTES];
+        for (tag, entry) in tags.iter().zip(out.chunks_mut(CHUNK_TAG_BYTES)) {
+            LittleEndian::write_u64(&mut entry[..8], tag.offset);
+            LittleEndian::write_u64(&mut entry[8..16], tag.length);
+            entry[16..].copy_from_slice(&tag.tag.0[..]);
+        }
+        out
+    }
+
+    /// Read back the output of `encode_all`. `None` if it is cut short.
+    pub fn decode_all(bytes: &[u8]) -> Option<Vec<ChunkTag>> {
+        if bytes.len() % CHUNK_TAG_BYTES != 0 {
+            return None;
+        }
+        Some(
+            bytes
+                .chunks(CHUNK_TAG_BYTES)
+                .map(|entry| {
+                    ChunkTag {
+                        offset: LittleEndian::read_u64(&entry[..8]),
+                        length: LittleEndian::read_u64(&entry[8..16]),
+                        tag: crypto::IntegrityTag(entry[16..].to_vec()),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
 
 
 pub struct Blob {
     keys: Arc<crypto::keys::Keeper>,
     access_key: crypto::authed::desc::Key,
+    algorithm: &'static str,
+    pipeline: Arc<ChunkPipeline>,
     chunks: CipherText,
     footer: Vec<u8>,
     overhead: usize,
+    record_padding: bool,
     max_len: usize,
+    /// Tags of the chunks appended since the blob was last turned into ciphertext.
+    tags: Vec<ChunkTag>,
+    /// Tags of the chunks in the blob that was last turned into ciphertext.
+    sealed_tags: Vec<ChunkTag>,
 }
 
 impl Blob {
@@ -36,15 +106,49 @@ impl Blob {
         Blob {
             keys: keys,
             access_key: crypto::FixedKey::new_access_partial_key(),
-            chunks: CipherText::empty(),
+            algorithm: crypto::SEAL_ALGORITHM,
+            pipeline: Arc::new(ChunkPipeline::default()),
+            chunks: CipherText::with_capacity(max_len),
             footer: Vec::with_capacity(max_len / 2),
             overhead: crypto::sealed::desc::overhead() + crypto::authed::hash::DIGESTBYTES,
+            record_padding: false,
             max_len: max_len,
+            tags: vec![],
+            sealed_tags: vec![],
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Seal chunks appended from now on with the chunk key algorithm `algorithm`. Chunks already
+    /// in the blob keep theirs.
+    pub fn set_algorithm(&mut self, algorithm: &'static str) {
+        self.algorithm = algorithm;
+    }
+
+    /// Put chunks appended from now on through `pipeline` instead of only sealing them.
+    pub fn set_pipeline(&mut self, pipeline: Arc<ChunkPipeline>) {
+        self.pipeline = pipeline;
+    }
+
+    /// Record the length of the padding in the footer, so that readers check it. The blob must
+    /// be empty, as the record takes room in it.
+    pub fn set_record_padding(&mut self, record: bool) {
+        assert!(self.chunks.is_empty());
+        if record != self.record_padding {
+            if record {
+                self.overhead += PADDING_ENTRY_BYTES;
+            } else {
+                self.overhead -= PADDING_ENTRY_BYTES;
+            }
+            self.record_padding = record;
         }
     }
 
     pub fn upperbound_len(&self) -> usize {
-        if self.chunks.len() == 0 {
+        if self.chunks.is_empty() {
             0
         } else {
             self.chunks.len() + self.footer.len() + self.overhead
@@ -52,24 +156,31 @@ impl Blob {
     }
 
     pub fn try_append(&mut self, chunk: &[u8], mut href: &mut HashRef) -> Result<(), ()> {
-        let ct = crypto::RefKey::seal(&mut href, &self.access_key, PlainTextRef::new(chunk));
+        let keys = ChunkKeys {
+            access_key: &self.access_key,
+            algorithm: self.algorithm,
+        };
+        // The stages only transform bytes in memory.
+        let ct = self.pipeline.forward(&mut href, &keys, chunk).expect(
+            "Chunk pipeline failed",
+        );
 
         href.persistent_ref.offset = self.chunks.len();
         let mut href_bytes = href.as_bytes();
         assert!(href_bytes.len() < 65535);
 
-        if self.upperbound_len() + 1 + href_bytes.len() + ct.len() >= self.max_len {
-            if self.chunks.len() == 0 {
-                panic!(
-                    "Can never fit chunk of size {} in blob of size {}",
-                    chunk.len(),
-                    self.max_len
-                );
-            }
+        // The footer and any padding entry count even while the blob is still empty.
+        let len = self.chunks.len() + self.footer.len() + self.overhead;
+        if len + 2 + href_bytes.len() + ct.len() > self.max_len {
             return Err(());
         }
 
-        self.chunks.append(ct);
+        self.tags.push(ChunkTag {
+            offset: self.chunks.len() as u64,
+            length: ct.len() as u64,
+            tag: crypto::IntegrityTag::of(&ct[..]),
+        });
+        self.chunks.append(CipherText::new(ct));
 
         // Generate footer entry.
         self.footer.push((href_bytes.len() % 256) as u8);
@@ -79,8 +190,27 @@ impl Blob {
         Ok(())
     }
 
+    /// Read back a chunk appended since the blob was last turned into ciphertext. Gives `None`
+    /// if `href` is not within what has been appended.
+    pub fn read_chunk(&self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
+        let end = href.persistent_ref.offset + href.persistent_ref.length;
+        if href.persistent_ref.length == 0 || end > self.chunks.len() {
+            return Ok(None);
+        }
+        let chunks = self.chunks.to_vec();
+        Ok(Some(
+            ChunkPipeline::inverse(href, &self.access_key, CipherTextRef::new(&chunks[..]))?
+                .into_vec(),
+        ))
+    }
+
+    /// The integrity tags of the chunks in the blob that `to_ciphertext` last returned.
+    pub fn take_chunk_tags(&mut self) -> Vec<ChunkTag> {
+        mem::replace(&mut self.sealed_tags, vec![])
+    }
+
     pub fn to_ciphertext(&mut self) -> Option<CipherText> {
-        if self.chunks.len() == 0 {
+        if self.chunks.is_empty() {
             return None;
         }
 
@@ -91,6 +221,16 @@ impl Blob {
         );
 
         let footer_overhead = self.footer.len() + self.overhead;
+        assert!(self.chunks.len() + footer_overhead <= self.max_len);
+
+        if self.record_padding {
+            let padding = self.max_len - footer_overhead - self.chunks.len();
+            let mut entry = [0u8; PADDING_ENTRY_BYTES];
+            LittleEndian::write_u16(&mut entry[..2], PADDING_ENTRY);
+            LittleEndian::write_u64(&mut entry[2..], padding as u64);
+            self.footer.extend_from_slice(&entry[..]);
+        }
+
         let footer = crypto::FixedKey::new(&self.keys).seal(
             &access_key,
             PlainTextRef::new(
@@ -99,15 +239,16 @@ impl Blob {
         );
         self.footer.truncate(0);
 
-        assert!(self.chunks.len() + footer_overhead <= self.max_len);
-
-        let mut out = mem::replace(&mut self.chunks, CipherText::empty());
+        // The whole blob, authentication included, fits the buffer reserved for it.
+        let mut out = mem::replace(&mut self.chunks, CipherText::with_capacity(self.max_len));
         out.random_pad_upto(self.max_len - footer_overhead);
         out.append(footer);
         out.append_authentication(&self.keys);
 
         assert_eq!(out.len(), self.max_len);
 
+        self.sealed_tags = mem::replace(&mut self.tags, vec![]);
+
         // Everything has been reset. We are ready to go again.
         assert_eq!(0, self.chunks.len());
         assert_eq!(0, self.footer.len());
@@ -128,7 +269,35 @@ impl<'b> BlobReader<'b> {
         keys: Arc<crypto::keys::Keeper>,
         blob: CipherTextRef<'b>,
     ) -> Result<BlobReader<'b>, crypto::CryptoError> {
-        let rest = blob.strip_authentication(&keys)?;
+        let rest = blob.strip_authentication(&keys)?.len();
+        BlobReader::open(keys, blob, rest)
+    }
+
+    /// Like `new`, but also opens a blob that fails the authentication of the blob as a whole,
+    /// e.g. because some of its chunks are corrupt, and tells whether it passed. Chunks are still
+    /// authenticated one by one as they are read, but the footer is only trustworthy if it did.
+    pub fn new_best_effort(
+        keys: Arc<crypto::keys::Keeper>,
+        blob: CipherTextRef<'b>,
+    ) -> Result<(BlobReader<'b>, bool), crypto::CryptoError> {
+        let (rest, authentic) = match blob.strip_authentication(&keys) {
+            Ok(rest) => (rest.len(), true),
+            Err(_) => {
+                let (rest, _) = blob.split_from_right(crypto::authed::hash::DIGESTBYTES)?;
+                (rest.len(), false)
+            }
+        };
+        Ok((BlobReader::open(keys, blob, rest)?, authentic))
+    }
+
+    /// Open the first `len` bytes of `blob`, which are what is left once its authentication is
+    /// stripped.
+    fn open(
+        keys: Arc<crypto::keys::Keeper>,
+        blob: CipherTextRef<'b>,
+        len: usize,
+    ) -> Result<BlobReader<'b>, crypto::CryptoError> {
+        let rest = blob.slice(0, len);
         let (access_key, footer_ct, rest) = crypto::FixedKey::new(&keys).unseal_access_ctx(rest)?;
 
         // TODO(jos): Figure out how to make the borrow checker happy without this.
@@ -143,7 +312,7 @@ impl<'b> BlobReader<'b> {
     }
 
     pub fn refs(&self) -> Result<Vec<HashRef>, BlobError> {
-        let (_rest, footer_vec) = crypto::FixedKey::new(&self.keys).unseal(
+        let (rest, footer_vec) = crypto::FixedKey::new(&self.keys).unseal(
             CipherTextRef::new(
                 &self.footer_ct[..],
             ),
@@ -152,21 +321,52 @@ impl<'b> BlobReader<'b> {
         let mut footer_pos = footer_vec.as_bytes();
 
         let mut hrefs = Vec::new();
+        let mut padding = None;
         while footer_pos.len() > 0 {
+            if footer_pos.len() < 2 {
+                return Err(crypto_error("crypto read failed: footer entry"));
+            }
             let len = footer_pos[0] as usize + 256 * (footer_pos[1] as usize);
-            assert!(footer_pos.len() > len);
+            if len == PADDING_ENTRY as usize {
+                if footer_pos.len() < PADDING_ENTRY_BYTES {
+                    return Err(crypto_error("crypto read failed: padding entry"));
+                }
+                padding = Some(LittleEndian::read_u64(&footer_pos[2..PADDING_ENTRY_BYTES]));
+                footer_pos = &footer_pos[PADDING_ENTRY_BYTES..];
+                continue;
+            }
+            if footer_pos.len() < 2 + len {
+                return Err(crypto_error("crypto read failed: footer entry"));
+            }
 
             hrefs.push(HashRef::from_bytes(&mut &footer_pos[2..2 + len])?);
             footer_pos = &footer_pos[len + 2..];
         }
 
+        // The chunks are followed by exactly the padding. Blobs written before the padding was
+        // recorded have nothing to check.
+        if let Some(padding) = padding {
+            let chunks_len = hrefs
+                .iter()
+                .map(|h| h.persistent_ref.offset + h.persistent_ref.length)
+                .max()
+                .unwrap_or(0);
+            if chunks_len as u64 + padding != rest.len() as u64 {
+                return Err(crypto_error("crypto read failed: padding length"));
+            }
+        }
+
         Ok(hrefs)
     }
 
     pub fn read_chunk(&self, href: &HashRef) -> Result<Vec<u8>, BlobError> {
         Ok(
-            crypto::RefKey::unseal(&self.access_key, href, self.blob.as_ref())?
+            ChunkPipeline::inverse(href, &self.access_key, self.blob.as_ref())?
                 .into_vec(),
         )
     }
 }
+
+fn crypto_error(msg: &'static str) -> BlobError {
+    BlobError::from(crypto::CryptoError::from(msg))
+}
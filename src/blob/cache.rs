@@ -0,0 +1,238 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Remembers where chunks stored during this run went, so that storing the same content again
+//! reuses the earlier copy instead of sealing it a second time, and keeps blobs that were read
+//! so that reading more chunks from them does not fetch them again.
+
+use blob::ChunkRef;
+use hash::Hash;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::Arc;
+
+
+/// Bytes of memory used by the default cache; tens of thousands of chunks, or several GiB of
+/// distinct data at the default chunk size.
+pub const DEFAULT_CHUNK_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+
+/// Lookup from the hash of a chunk's plaintext to the place it was stored.
+///
+/// The blob store consults the cache while holding its own lock, so implementations need not
+/// synchronize, but they must be safe to move between the threads that store chunks.
+pub trait ChunkCache: Send {
+    /// Where the chunk was stored, if it is remembered. Looking a chunk up counts as seeing it.
+    fn get(&mut self, hash: &Hash) -> Option<ChunkRef>;
+    fn insert(&mut self, hash: Hash, chunk_ref: ChunkRef);
+
+    /// Forget all chunks, as the blobs they are in may no longer exist.
+    fn clear(&mut self);
+
+    /// Roughly how many bytes of memory the cache holds on to.
+    fn memory_use(&self) -> usize;
+}
+
+
+/// An in-memory cache of at most `max_bytes`, which forgets the chunks seen least recently to
+/// stay within that. Chunks it forgot are still found in the hash index, just more slowly.
+pub struct MemoryChunkCache {
+    max_bytes: usize,
+    bytes: usize,
+    last_seen: u64,
+    refs: HashMap<Hash, (ChunkRef, u64)>,
+    // Hashes by when they were last seen, oldest first.
+    seen: BTreeMap<u64, Hash>,
+}
+
+impl MemoryChunkCache {
+    pub fn new(max_bytes: usize) -> MemoryChunkCache {
+        MemoryChunkCache {
+            max_bytes: max_bytes,
+            bytes: 0,
+            last_seen: 0,
+            refs: HashMap::new(),
+            seen: BTreeMap::new(),
+        }
+    }
+
+    fn see(&mut self) -> u64 {
+        self.last_seen += 1;
+        self.last_seen
+    }
+
+    fn remove(&mut self, hash: &Hash) {
+        if let Some((chunk_ref, seen)) = self.refs.remove(hash) {
+            self.seen.remove(&seen);
+            self.bytes -= entry_size(hash, &chunk_ref);
+        }
+    }
+}
+
+/// Memory held for one chunk: the hash is kept in both maps, next to the reference itself.
+fn entry_size(hash: &Hash, chunk_ref: &ChunkRef) -> usize {
+    2 * (mem::size_of::<Hash>() + hash.bytes.len() + mem::size_of::<u64>()) +
+        mem::size_of::<ChunkRef>() + chunk_ref.blob_name.len()
+}
+
+impl ChunkCache for MemoryChunkCache {
+    fn get(&mut self, hash: &Hash) -> Option<ChunkRef> {
+        let now = self.see();
+        match self.refs.get_mut(hash) {
+            Some(&mut (ref chunk_ref, ref mut seen)) => {
+                self.seen.remove(&*seen);
+                self.seen.insert(now, hash.clone());
+                *seen = now;
+                Some(chunk_ref.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, hash: Hash, chunk_ref: ChunkRef) {
+        self.remove(&hash);
+        let size = entry_size(&hash, &chunk_ref);
+        if size > self.max_bytes {
+            return;
+        }
+        while self.bytes + size > self.max_bytes {
+            let oldest = match self.seen.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+        let now = self.see();
+        self.seen.insert(now, hash.clone());
+        self.refs.insert(hash, (chunk_ref, now));
+        self.bytes += size;
+    }
+
+    fn clear(&mut self) {
+        self.refs.clear();
+        self.seen.clear();
+        self.bytes = 0;
+    }
+
+    fn memory_use(&self) -> usize {
+        self.bytes
+    }
+}
+
+
+/// Blobs as fetched from the backend, of at most `max_bytes` in total. The blobs read least
+/// recently are forgotten first. A cache of 0 bytes keeps nothing.
+pub struct BlobReadCache {
+    max_bytes: usize,
+    bytes: usize,
+    last_seen: u64,
+    blobs: HashMap<Vec<u8>, (Arc<Vec<u8>>, u64)>,
+    // Blob names by when they were last read, oldest first.
+    seen: BTreeMap<u64, Vec<u8>>,
+}
+
+impl BlobReadCache {
+    pub fn new(max_bytes: usize) -> BlobReadCache {
+        BlobReadCache {
+            max_bytes: max_bytes,
+            bytes: 0,
+            last_seen: 0,
+            blobs: HashMap::new(),
+            seen: BTreeMap::new(),
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    fn see(&mut self) -> u64 {
+        self.last_seen += 1;
+        self.last_seen
+    }
+
+    /// The blob, if it is kept. Reading a blob counts as seeing it.
+    pub fn get(&mut self, name: &[u8]) -> Option<Arc<Vec<u8>>> {
+        let now = self.see();
+        match self.blobs.get_mut(name) {
+            Some(&mut (ref blob, ref mut seen)) => {
+                self.seen.remove(&*seen);
+                self.seen.insert(now, name.to_vec());
+                *seen = now;
+                Some(blob.clone())
+            }
+            None => None,
+        }
+    }
+
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.blobs.contains_key(name)
+    }
+
+    /// Keep a blob that was just read, forgetting the blobs read least recently to make room.
+    pub fn insert(&mut self, name: Vec<u8>, blob: Arc<Vec<u8>>) {
+        self.remove(&name[..]);
+        if blob.len() > self.max_bytes {
+            return;
+        }
+        while self.bytes + blob.len() > self.max_bytes {
+            let oldest = match self.seen.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest[..]);
+        }
+        self.keep(name, blob);
+    }
+
+    /// Keep a blob that may be read later, but only in room that is still free; blobs that were
+    /// actually read are never forgotten for it. Returns whether the blob is kept.
+    pub fn insert_if_room(&mut self, name: Vec<u8>, blob: Arc<Vec<u8>>) -> bool {
+        if self.blobs.contains_key(&name[..]) {
+            return true;
+        }
+        if self.bytes + blob.len() > self.max_bytes {
+            return false;
+        }
+        self.keep(name, blob);
+        true
+    }
+
+    fn keep(&mut self, name: Vec<u8>, blob: Arc<Vec<u8>>) {
+        let now = self.see();
+        self.bytes += blob.len();
+        self.seen.insert(now, name.clone());
+        self.blobs.insert(name, (blob, now));
+    }
+
+    /// Forget a blob, e.g. because it was deleted.
+    pub fn remove(&mut self, name: &[u8]) {
+        if let Some((blob, seen)) = self.blobs.remove(name) {
+            self.seen.remove(&seen);
+            self.bytes -= blob.len();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blobs.clear();
+        self.seen.clear();
+        self.bytes = 0;
+    }
+
+    /// Bytes of blob contents kept.
+    pub fn memory_use(&self) -> usize {
+        self.bytes
+    }
+}
@@ -13,9 +13,13 @@
 // limitations under the License.
 
 use capnp;
+use crypto;
+use hex::ToHex;
 use root_capnp;
 use secstr;
 
+use super::BlobError;
+
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Packing {
@@ -23,9 +27,122 @@ pub enum Packing {
     Snappy,
 }
 
+/// Version of the format written by `Key::serialize`.
+const KEY_FORMAT_VERSION: u8 = 1;
+
+/// Ids of the key algorithms in serialized keys. These are never reused.
+const KEY_ALGORITHM_CHACHA20POLY1305: u8 = 1;
+const KEY_ALGORITHM_CHACHA20POLY1305_COMMITTED: u8 = 2;
+
 #[derive(Debug, Clone)]
 pub enum Key {
     AeadChacha20Poly1305(secstr::SecStr),
+    /// As above, with a commitment to the key stored after the ciphertext.
+    /// Older versions of hat can not read chunks sealed this way.
+    AeadChacha20Poly1305Committed(secstr::SecStr),
+}
+
+impl Packing {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Packing::GZip => "gzip",
+            Packing::Snappy => "snappy",
+        }
+    }
+}
+
+impl Key {
+    /// Build a key for the algorithm named `algorithm` (as returned by `algorithm()`) from raw
+    /// key bytes, refusing bytes that are not exactly as long as the algorithm requires. Keys
+    /// that come from outside, like ones read back from the index, should be built this way.
+    pub fn from_bytes(algorithm: &str, bytes: &[u8]) -> Result<Key, crypto::CryptoError> {
+        let key = match algorithm {
+            "chacha20poly1305" => Key::AeadChacha20Poly1305(secstr::SecStr::from(bytes)),
+            "chacha20poly1305-committed" => {
+                Key::AeadChacha20Poly1305Committed(secstr::SecStr::from(bytes))
+            }
+            _ => return Err(format!("Unknown key algorithm: {}", algorithm).into()),
+        };
+        if bytes.len() != key.raw_len() {
+            return Err(
+                format!(
+                    "Wrong key length for {}: expected {} bytes, got {}",
+                    algorithm,
+                    key.raw_len(),
+                    bytes.len()
+                ).into(),
+            );
+        }
+        Ok(key)
+    }
+
+    /// The key on its own, for keyfiles and the like: a format version, the algorithm's id and
+    /// the raw key bytes. Unlike keys inside chunk references, this does not depend on the
+    /// capnp schema.
+    pub fn serialize(&self) -> Vec<u8> {
+        let (id, key) = match *self {
+            Key::AeadChacha20Poly1305(ref k) => (KEY_ALGORITHM_CHACHA20POLY1305, k),
+            Key::AeadChacha20Poly1305Committed(ref k) => {
+                (KEY_ALGORITHM_CHACHA20POLY1305_COMMITTED, k)
+            }
+        };
+        let mut out = Vec::with_capacity(2 + key.unsecure().len());
+        out.push(KEY_FORMAT_VERSION);
+        out.push(id);
+        out.extend_from_slice(key.unsecure());
+        out
+    }
+
+    /// Read back a key written by `serialize`.
+    pub fn deserialize(bytes: &[u8]) -> Result<Key, crypto::CryptoError> {
+        if bytes.len() < 2 {
+            return Err("Serialized key is too short".into());
+        }
+        if bytes[0] != KEY_FORMAT_VERSION {
+            return Err(format!("Unknown serialized key version: {}", bytes[0]).into());
+        }
+        let algorithm = match bytes[1] {
+            KEY_ALGORITHM_CHACHA20POLY1305 => "chacha20poly1305",
+            KEY_ALGORITHM_CHACHA20POLY1305_COMMITTED => "chacha20poly1305-committed",
+            id => return Err(format!("Unknown key algorithm id: {}", id).into()),
+        };
+        Key::from_bytes(algorithm, &bytes[2..])
+    }
+
+    /// Number of bytes in a key for this algorithm.
+    pub fn raw_len(&self) -> usize {
+        match *self {
+            Key::AeadChacha20Poly1305(_) |
+            Key::AeadChacha20Poly1305Committed(_) => crypto::authed::desc::KEYBYTES,
+        }
+    }
+
+    /// Whether chunks sealed with the algorithm named `algorithm` open under a single key only.
+    /// Without a commitment, a chunk can be crafted to open under two keys.
+    pub fn commits_to_key(algorithm: &str) -> bool {
+        match algorithm {
+            "chacha20poly1305-committed" => true,
+            _ => false,
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match *self {
+            Key::AeadChacha20Poly1305(_) => "chacha20poly1305",
+            Key::AeadChacha20Poly1305Committed(_) => "chacha20poly1305-committed",
+        }
+    }
+
+    /// Short digest of the key that tells keys apart without revealing them.
+    pub fn fingerprint(&self) -> String {
+        let key = match *self {
+            Key::AeadChacha20Poly1305(ref k) |
+            Key::AeadChacha20Poly1305Committed(ref k) => k,
+        };
+        let mut out = vec![0; 16];
+        crypto::keys::digest(key.unsecure(), &mut out[..]);
+        out[..4].to_vec().to_hex()
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -105,12 +222,14 @@ pub struct ChunkRef {
     pub length: usize,
     pub packing: Option<Packing>,
     pub key: Option<Key>,
+    /// The stages that the chunk went through, if it was not only sealed.
+    pub pipeline: Option<String>,
 }
 
 impl ChunkRef {
     pub fn from_bytes(bytes: &mut &[u8]) -> Result<ChunkRef, capnp::Error> {
         let reader =
-            capnp::serialize_packed::read_message(bytes, capnp::message::ReaderOptions::new())?;
+            ::util::read_packed_message(bytes, capnp::message::ReaderOptions::new())?;
         let root = reader.get_root::<root_capnp::chunk_ref::Reader>()?;
 
         Ok(ChunkRef::read_msg(&root)?)
@@ -123,7 +242,7 @@ impl ChunkRef {
             self.populate_msg(root.borrow());
         }
         let mut out = Vec::new();
-        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        ::util::write_packed_message(&mut out, &message).unwrap();
         out
     }
 
@@ -134,7 +253,7 @@ impl ChunkRef {
             self.populate_msg_no_name(root.borrow());
         }
         let mut out = Vec::new();
-        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        ::util::write_packed_message(&mut out, &message).unwrap();
         out
     }
 
@@ -151,12 +270,18 @@ impl ChunkRef {
         msg.set_offset(self.offset as u64);
         msg.set_length(self.length as u64);
 
-        if let Some(Key::AeadChacha20Poly1305(ref chacha)) = self.key {
-            msg.borrow().init_key().set_aead_chacha20_poly1305(
-                chacha.unsecure(),
-            );
-        } else {
-            msg.borrow().init_key().set_none(());
+        match self.key {
+            Some(Key::AeadChacha20Poly1305(ref chacha)) => {
+                msg.borrow().init_key().set_aead_chacha20_poly1305(
+                    chacha.unsecure(),
+                )
+            }
+            Some(Key::AeadChacha20Poly1305Committed(ref chacha)) => {
+                msg.borrow().init_key().set_aead_chacha20_poly1305_committed(
+                    chacha.unsecure(),
+                )
+            }
+            None => msg.borrow().init_key().set_none(()),
         }
 
         match self.packing {
@@ -164,6 +289,10 @@ impl ChunkRef {
             Some(Packing::GZip) => msg.borrow().init_packing().set_gzip(()),
             Some(Packing::Snappy) => msg.borrow().init_packing().set_snappy(()),
         }
+
+        if let Some(ref pipeline) = self.pipeline {
+            msg.set_pipeline(&pipeline[..]);
+        }
     }
 
     pub fn read_msg(msg: &root_capnp::chunk_ref::Reader) -> Result<ChunkRef, capnp::Error> {
@@ -180,9 +309,125 @@ impl ChunkRef {
             key: match msg.get_key().which()? {
                 root_capnp::chunk_ref::key::None(()) => None,
                 root_capnp::chunk_ref::key::AeadChacha20Poly1305(res) => {
-                    Some(Key::AeadChacha20Poly1305(secstr::SecStr::from(res?)))
+                    Some(read_key("chacha20poly1305", res?)?)
                 }
+                root_capnp::chunk_ref::key::AeadChacha20Poly1305Committed(res) => {
+                    Some(read_key("chacha20poly1305-committed", res?)?)
+                }
+            },
+            pipeline: if msg.has_pipeline() {
+                Some(msg.get_pipeline()?.to_owned())
+            } else {
+                None
             },
         })
     }
 }
+
+/// Builds a `ChunkRef`, refusing combinations of fields that could not describe a stored chunk.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkRefBuilder {
+    blob_id: Option<i64>,
+    blob_name: Vec<u8>,
+    offset: usize,
+    length: usize,
+    packing: Option<Packing>,
+    key: Option<Key>,
+    pipeline: Option<String>,
+}
+
+impl ChunkRefBuilder {
+    pub fn new() -> ChunkRefBuilder {
+        ChunkRefBuilder::default()
+    }
+
+    pub fn with_blob_id(mut self, blob_id: i64) -> ChunkRefBuilder {
+        self.blob_id = Some(blob_id);
+        self
+    }
+
+    pub fn with_blob_name(mut self, blob_name: Vec<u8>) -> ChunkRefBuilder {
+        self.blob_name = blob_name;
+        self
+    }
+
+    /// Where the sealed chunk starts in its blob, and how many bytes it takes there.
+    pub fn with_range(mut self, offset: usize, length: usize) -> ChunkRefBuilder {
+        self.offset = offset;
+        self.length = length;
+        self
+    }
+
+    pub fn with_packing(mut self, packing: Packing) -> ChunkRefBuilder {
+        self.packing = Some(packing);
+        self
+    }
+
+    pub fn with_key(mut self, key: Key) -> ChunkRefBuilder {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: String) -> ChunkRefBuilder {
+        self.pipeline = Some(pipeline);
+        self
+    }
+
+    /// The reference, if its fields fit together: a chunk with contents has a key to read them
+    /// with and is long enough to hold what that key seals, and only sealed contents are packed
+    /// or put through a pipeline. The empty chunk has neither contents nor a key.
+    pub fn build(self) -> Result<ChunkRef, BlobError> {
+        let chunk_ref = ChunkRef {
+            blob_id: self.blob_id,
+            blob_name: self.blob_name,
+            offset: self.offset,
+            length: self.length,
+            packing: self.packing,
+            key: self.key,
+            pipeline: self.pipeline,
+        };
+        match chunk_ref.key {
+            None if chunk_ref.length > 0 => {
+                return Err(From::from(format!(
+                    "Chunk reference of {} bytes has no key to read them with",
+                    chunk_ref.length
+                )))
+            }
+            None if chunk_ref.packing.is_some() => {
+                return Err(From::from("Chunk reference is packed but not sealed"))
+            }
+            None if chunk_ref.pipeline.is_some() => {
+                return Err(From::from("Chunk reference went through a pipeline but is not sealed"))
+            }
+            Some(ref key)
+                if chunk_ref.pipeline.is_none() &&
+                    crypto::RefKey::plaintext_len(&chunk_ref).is_none() => {
+                return Err(From::from(format!(
+                    "Chunk reference of {} bytes is too short to be sealed with {}",
+                    chunk_ref.length,
+                    key.algorithm()
+                )))
+            }
+            _ => (),
+        }
+        Ok(chunk_ref)
+    }
+}
+
+impl From<ChunkRef> for ChunkRefBuilder {
+    fn from(chunk_ref: ChunkRef) -> ChunkRefBuilder {
+        ChunkRefBuilder {
+            blob_id: chunk_ref.blob_id,
+            blob_name: chunk_ref.blob_name,
+            offset: chunk_ref.offset,
+            length: chunk_ref.length,
+            packing: chunk_ref.packing,
+            key: chunk_ref.key,
+            pipeline: chunk_ref.pipeline,
+        }
+    }
+}
+
+fn read_key(algorithm: &str, bytes: &[u8]) -> Result<Key, capnp::Error> {
+    Key::from_bytes(algorithm, bytes).map_err(|e| capnp::Error::failed(e.to_string()))
+}
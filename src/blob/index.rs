@@ -15,15 +15,23 @@
 //! Local state for external blobs and their states.
 
 
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use crypto;
 use db;
+use hash::Hash;
 
 use errors::DieselError;
 
+use std::cmp;
 use std::sync::{Arc, Mutex};
 
 use tags;
 
+use super::ChunkTag;
+
+
+/// Derived blob ids keep this many of the top bits clear.
+const DERIVED_ID_SHIFT: u32 = 2;
 
 #[derive(Clone, Debug, Default)]
 pub struct BlobDesc {
@@ -78,12 +86,14 @@ impl InternalBlobIndex {
         }
     }
 
+    /// Make sure new ids are above every id in the index. Ids already handed out are never
+    /// handed out again.
     pub fn refresh_next_id(&self) {
         let id = {
             self.index.lock().blob_next_id()
         };
         let mut next_id = self.next_id.lock().unwrap();
-        *next_id = 1 + id;
+        *next_id = cmp::max(*next_id, 1 + id);
     }
 
     fn next_id(&self) -> i64 {
@@ -117,6 +127,26 @@ impl InternalBlobIndex {
     fn reserve(&self) -> BlobDesc {
         self.new_blob_desc()
     }
+
+    fn reserve_derived(&self, first_chunk: &Hash, in_use: &Fn(i64) -> bool) -> BlobDesc {
+        let mut counter = 0u64;
+        loop {
+            let mut msg = first_chunk.bytes.clone();
+            msg.write_u64::<LittleEndian>(counter).unwrap();
+            let mut out = [0u8; 8];
+            self.keys.fingerprint(&msg[..], b"hat-blob-id~~~~~", &mut out);
+            // Positive and well below the maximum, so that counting up from it cannot overflow.
+            let id = 1 + (LittleEndian::read_u64(&out) >> DERIVED_ID_SHIFT) as i64;
+            if !in_use(id) && self.index.lock().blob_name_from_id(id).is_none() {
+                // The name is derived too, so that the same blob is stored under the same name.
+                let name = crypto::FixedKey::new(&self.keys)
+                    .seal_blob_name_derived(crypto::PlainText::from_i64(id).as_ref())
+                    .to_vec();
+                return BlobDesc { name: name, id: id };
+            }
+            counter += 1;
+        }
+    }
 }
 
 impl BlobIndex {
@@ -132,6 +162,18 @@ impl BlobIndex {
         self.0.reserve()
     }
 
+    /// Reserve a `BlobDesc` whose id is derived from the hash of the first chunk that goes in the
+    /// blob, so that packing the same chunks again gives the same id. If the id is in the index
+    /// already, or `in_use` says it is taken, a counter is mixed in until it is not.
+    pub fn reserve_derived(&self, first_chunk: &Hash, in_use: &Fn(i64) -> bool) -> BlobDesc {
+        self.0.reserve_derived(first_chunk, in_use)
+    }
+
+    /// Catch up with blobs added to the index behind our back, like by an import.
+    pub fn refresh_next_id(&self) {
+        self.0.refresh_next_id()
+    }
+
     /// Report that this blob is in the process of being committed to persistent storage. If a
     /// blob is in this state when the system starts up, it may or may not exist in the persistent
     /// storage, but **should not** be referenced elsewhere, and is therefore safe to delete.
@@ -145,6 +187,24 @@ impl BlobIndex {
         self.0.index.lock().blob_commit(blob)
     }
 
+    /// Record the checksum of the blob as it is sent to the backend.
+    pub fn set_checksum(&self, blob: &BlobDesc, checksum: &crypto::Checksum) {
+        self.0.index.lock().blob_set_checksum(blob, checksum)
+    }
+
+    pub fn checksum(&self, blob: &BlobDesc) -> Option<crypto::Checksum> {
+        self.0.index.lock().blob_checksum(blob)
+    }
+
+    /// Record the integrity tags of the chunks in the blob.
+    pub fn set_chunk_tags(&self, blob: &BlobDesc, tags: &[ChunkTag]) {
+        self.0.index.lock().blob_set_chunk_tags(blob, tags)
+    }
+
+    pub fn chunk_tags(&self, blob: &BlobDesc) -> Vec<ChunkTag> {
+        self.0.index.lock().blob_chunk_tags(blob)
+    }
+
     /// Reinstall blob recovered by from external storage.
     /// Creates a new blob by a known external name.
     pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
@@ -162,6 +222,12 @@ impl BlobIndex {
         }
     }
 
+    pub fn find_by_id(&self, id: i64) -> Option<BlobDesc> {
+        self.0.index.lock().blob_name_from_id(id).map(|name| {
+            BlobDesc { name: name, id: id }
+        })
+    }
+
     pub fn tag(&self, blob: &BlobDesc, tag: tags::Tag) {
         self.0.index.lock().blob_set_tag(tag, Some(blob))
     }
@@ -174,6 +240,10 @@ impl BlobIndex {
         self.0.index.lock().blob_list_by_tag(tag)
     }
 
+    pub fn delete(&self, blob: &BlobDesc) {
+        self.0.index.lock().blob_delete(blob)
+    }
+
     pub fn delete_by_tag(&self, tag: tags::Tag) {
         self.0.index.lock().blob_delete_by_tag(tag)
     }
@@ -15,13 +15,16 @@
 //! Combines data chunks into larger blobs to be stored externally.
 
 
-use backend::StoreBackend;
+use backend::{BlobBytes, StorageClass, StoreBackend};
 use capnp;
 use crypto;
 use errors;
 use hash::Hash;
 use hash::tree::HashRef;
+use hex::ToHex;
+use scoped_pool;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
@@ -30,9 +33,13 @@ use util::FnBox;
 use key;
 
 
+mod cache;
 mod chunk;
 mod blob;
 mod index;
+mod pipeline;
+mod storage_policy;
+mod upload;
 #[cfg(test)]
 pub mod tests;
 
@@ -40,9 +47,14 @@ pub mod tests;
 mod benchmarks;
 
 
-pub use self::blob::{Blob, BlobReader};
-pub use self::chunk::{ChunkRef, Key, NodeType, LeafType, Packing};
+pub use self::blob::{Blob, BlobReader, ChunkTag};
+pub use self::cache::{BlobReadCache, ChunkCache, DEFAULT_CHUNK_CACHE_SIZE, MemoryChunkCache};
+pub use self::chunk::{ChunkRef, ChunkRefBuilder, Key, NodeType, LeafType, Packing};
 pub use self::index::{BlobDesc, BlobIndex};
+pub use self::pipeline::{ChunkKeys, ChunkPipeline, Gzip, Pad, Seal, Stage};
+pub use self::storage_policy::StoragePolicy;
+pub use self::upload::DEFAULT_MAX_UPLOADS;
+use self::upload::Uploader;
 
 
 error_type! {
@@ -58,6 +70,48 @@ error_type! {
         },
         DataSerialization(capnp::Error) {
             cause;
+        },
+        NeedsThaw(errors::NeedsThawError) {
+            cause;
+        },
+        Backend(errors::BackendError) {
+            cause;
+        }
+    }
+}
+
+/// Carry a failure of the backend as such, rather than as a plain message.
+fn from_backend(e: String) -> BlobError {
+    BlobError::Backend(errors::BackendError::new(e))
+}
+
+/// Metadata of a single chunk in a blob, as read from the blob's footer.
+#[derive(Clone, Debug)]
+pub struct ChunkInfo {
+    pub hash: Hash,
+    pub node: NodeType,
+    pub leaf: LeafType,
+    pub offset: usize,
+    pub length: usize,
+    pub plaintext_length: Option<usize>,
+    pub algorithm: Option<&'static str>,
+    pub key_fingerprint: Option<String>,
+    pub packing: Option<Packing>,
+}
+
+impl ChunkInfo {
+    fn from_hash_ref(href: HashRef) -> ChunkInfo {
+        let cref = href.persistent_ref;
+        ChunkInfo {
+            hash: href.hash,
+            node: href.node,
+            leaf: href.leaf,
+            offset: cref.offset,
+            length: cref.length,
+            plaintext_length: crypto::RefKey::plaintext_len(&cref),
+            algorithm: cref.key.as_ref().map(|k| k.algorithm()),
+            key_fingerprint: cref.key.as_ref().map(|k| k.fingerprint()),
+            packing: cref.packing,
         }
     }
 }
@@ -68,15 +122,77 @@ pub struct StoreInner<B> {
     keys: Arc<crypto::keys::Keeper>,
     backend: Arc<B>,
     blob_index: Arc<BlobIndex>,
-    blob_desc: BlobDesc,
-    blob_refs: Vec<(Box<FnBox<(), ()>>)>,
+    max_blob_size: usize,
+    seal_algorithm: &'static str,
+    pipeline: Arc<ChunkPipeline>,
+    record_padding: bool,
+    // Name blobs after their first chunk rather than by counting.
+    deterministic_ids: bool,
+    // One blob is filled per storage class, so that a blob can be stored in the class that all
+    // of its chunks asked for.
+    open: BTreeMap<StorageClass, OpenBlob>,
+    chunk_cache: Box<ChunkCache>,
+    // Shared with readers that do not hold on to the store.
+    read_cache: Arc<Mutex<BlobReadCache>>,
+    uploader: Uploader<B>,
+}
+
+struct OpenBlob {
+    desc: BlobDesc,
+    refs: Vec<(Box<FnBox<(), ()>>)>,
     blob: Blob,
 }
 
 impl<B> Drop for StoreInner<B> {
     fn drop(&mut self) {
         // Sanity check that we flushed this blob store before dropping it.
-        assert_eq!(0, self.blob.upperbound_len());
+        for open in self.open.values() {
+            assert_eq!(0, open.blob.upperbound_len());
+        }
+    }
+}
+
+/// Fetch a whole blob from the backend, telling a blob that must be thawed first from one that
+/// failed to be read.
+fn fetch_blob<B: StoreBackend>(backend: &B, name: &[u8]) -> Result<Option<BlobBytes>, BlobError> {
+    match backend.retrieve_bytes(name) {
+        Ok(blob) => Ok(blob),
+        Err(e) => {
+            if backend.needs_thaw(name).map_err(from_backend)? {
+                Err(BlobError::NeedsThaw(
+                    errors::NeedsThawError { blob_name: name.to_hex() },
+                ))
+            } else {
+                Err(from_backend(e))
+            }
+        }
+    }
+}
+
+/// Like `fetch_blob`, but blobs kept in `cache` are not fetched again, and fetched blobs are kept
+/// there.
+fn fetch_cached<B: StoreBackend>(
+    backend: &B,
+    cache: &Mutex<BlobReadCache>,
+    name: &[u8],
+) -> Result<Option<BlobBytes>, BlobError> {
+    let enabled = {
+        let mut cache = cache.lock().expect("Blob read cache was poisoned");
+        if let Some(blob) = cache.get(name) {
+            return Ok(Some(BlobBytes::Shared(blob)));
+        }
+        cache.max_bytes() > 0
+    };
+    match fetch_blob(backend, name)? {
+        Some(blob) if enabled => {
+            let blob = Arc::new(blob.to_vec());
+            cache.lock().expect("Blob read cache was poisoned").insert(
+                name.to_vec(),
+                blob.clone(),
+            );
+            Ok(Some(BlobBytes::Shared(blob)))
+        }
+        fetched => Ok(fetched),
     }
 }
 
@@ -86,42 +202,110 @@ impl<B: StoreBackend> StoreInner<B> {
         index: Arc<BlobIndex>,
         backend: Arc<B>,
         max_blob_size: usize,
+        chunk_cache: Box<ChunkCache>,
     ) -> StoreInner<B> {
         let mut bs = StoreInner {
-            keys: keys.clone(),
-            backend: backend,
+            keys: keys,
+            backend: backend.clone(),
             blob_index: index,
-            blob_desc: Default::default(),
-            blob_refs: Vec::new(),
-            blob: Blob::new(keys, max_blob_size),
+            max_blob_size: max_blob_size,
+            seal_algorithm: crypto::SEAL_ALGORITHM,
+            pipeline: Arc::new(ChunkPipeline::default()),
+            record_padding: false,
+            deterministic_ids: false,
+            open: BTreeMap::new(),
+            chunk_cache: chunk_cache,
+            read_cache: Arc::new(Mutex::new(BlobReadCache::new(0))),
+            uploader: Uploader::new(backend, DEFAULT_MAX_UPLOADS),
         };
-        bs.reserve_new_blob();
+        bs.open_blob(StorageClass::Standard);
         bs
     }
 
-    fn reserve_new_blob(&mut self) -> BlobDesc {
-        mem::replace(&mut self.blob_desc, self.blob_index.reserve())
+    /// The blob being filled for `class`, started if there is none yet.
+    fn open_blob(&mut self, class: StorageClass) -> &mut OpenBlob {
+        let &mut StoreInner {
+            ref keys,
+            ref blob_index,
+            max_blob_size,
+            seal_algorithm,
+            ref pipeline,
+            record_padding,
+            ref mut open,
+            ..
+        } = self;
+        open.entry(class).or_insert_with(|| {
+            let mut blob = Blob::new(keys.clone(), max_blob_size);
+            blob.set_algorithm(seal_algorithm);
+            blob.set_pipeline(pipeline.clone());
+            blob.set_record_padding(record_padding);
+            OpenBlob {
+                desc: blob_index.reserve(),
+                refs: Vec::new(),
+                blob: blob,
+            }
+        })
     }
 
-    fn flush(&mut self) {
-        let ct = match self.blob.to_ciphertext() {
-            None => return,
-            Some(ct) => ct,
-        };
+    /// The blob being filled for `class`, about to take the chunk `hash`. With deterministic ids,
+    /// an empty blob is renamed after the chunk first.
+    fn open_blob_for(&mut self, class: StorageClass, hash: &Hash) -> &mut OpenBlob {
+        let rename = self.deterministic_ids && self.open_blob(class).blob.upperbound_len() == 0;
+        if rename {
+            let taken: Vec<i64> = self.open
+                .iter()
+                .filter(|&(c, _)| *c != class)
+                .map(|(_, o)| o.desc.id)
+                .collect();
+            let desc = self.blob_index.reserve_derived(hash, &|id| taken.contains(&id));
+            self.open_blob(class).desc = desc;
+        }
+        self.open_blob(class)
+    }
 
-        // Replace blob id
-        let old_blob_desc = self.reserve_new_blob();
+    /// Hand the current blobs over for upload. This blocks while the maximum number of uploads
+    /// are already in flight.
+    fn flush(&mut self) -> Result<(), BlobError> {
+        let classes: Vec<StorageClass> = self.open.keys().cloned().collect();
+        for class in classes {
+            self.flush_class(class)?;
+        }
+        Ok(())
+    }
 
+    fn flush_class(&mut self, class: StorageClass) -> Result<(), BlobError> {
+        let (ct, old_blob_desc, mut callbacks) = {
+            let blob_index = self.blob_index.clone();
+            let open = self.open_blob(class);
+            let ct = match open.blob.to_ciphertext() {
+                None => return Ok(()),
+                Some(ct) => ct,
+            };
+            // Replace blob id
+            let old_blob_desc = mem::replace(&mut open.desc, blob_index.reserve());
+            blob_index.set_chunk_tags(&old_blob_desc, &open.blob.take_chunk_tags()[..]);
+            (ct, old_blob_desc, mem::replace(&mut open.refs, Vec::new()))
+        };
         self.blob_index.in_air(&old_blob_desc);
-        self.backend.store(&old_blob_desc.name[..], &ct).expect(
-            "Store operation failed",
-        );
-        self.blob_index.commit_done(&old_blob_desc);
+        self.blob_index.set_checksum(&old_blob_desc, &ct.checksum());
+
+        let blob_index = self.blob_index.clone();
+        let name = old_blob_desc.name.clone();
+        self.uploader.upload(
+            name,
+            ct,
+            class,
+            Box::new(move |()| {
+                blob_index.commit_done(&old_blob_desc);
+
+                // Go through callbacks
+                while let Some(callback) = callbacks.pop() {
+                    callback.call(());
+                }
+            }),
+        ).map_err(from_backend)?;
 
-        // Go through callbacks
-        while let Some(callback) = self.blob_refs.pop() {
-            callback.call(());
-        }
+        Ok(())
     }
 
     fn store(
@@ -131,8 +315,9 @@ impl<B: StoreBackend> StoreInner<B> {
         node: NodeType,
         leaf: LeafType,
         info: Option<&key::Info>,
+        class: StorageClass,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
         let mut href = HashRef {
             hash: hash,
             node: node,
@@ -142,6 +327,7 @@ impl<B: StoreBackend> StoreInner<B> {
                 blob_id: Some(0),
                 blob_name: vec![0],
                 packing: None,
+                pipeline: None,
                 // Updated by try_append.
                 offset: 0,
                 length: 0,
@@ -152,46 +338,147 @@ impl<B: StoreBackend> StoreInner<B> {
         if chunk.is_empty() {
             // We are not going to store an empty chunk, so commit it ASAP.
             thread::spawn(move || callback.call(()));
+        } else if let Some(chunk_ref) = self.chunk_cache.get(&href.hash) {
+            // The same content was stored earlier in this run; point to that copy.
+            match self.open.values_mut().find(
+                |o| chunk_ref.blob_id == Some(o.desc.id),
+            ) {
+                // Still in an open blob, so wait for it to be pushed like the original.
+                Some(open) => open.refs.push(callback),
+                None => {
+                    thread::spawn(move || callback.call(()));
+                }
+            }
+            href.persistent_ref = chunk_ref;
         } else {
-            href.persistent_ref.blob_id = Some(self.blob_desc.id);
-            href.persistent_ref.blob_name = self.blob_desc.name.clone();
-            if let Err(()) = self.blob.try_append(chunk, &mut href) {
-                self.flush();
-                href.persistent_ref.blob_id = Some(self.blob_desc.id);
-                href.persistent_ref.blob_name = self.blob_desc.name.clone();
-
-                self.blob.try_append(chunk, &mut href).unwrap();
+            let appended = {
+                let open = self.open_blob_for(class, &href.hash);
+                href.persistent_ref.blob_id = Some(open.desc.id);
+                href.persistent_ref.blob_name = open.desc.name.clone();
+                open.blob.try_append(chunk, &mut href)
+            };
+            if let Err(()) = appended {
+                self.flush_class(class)?;
+                let open = self.open_blob_for(class, &href.hash);
+                href.persistent_ref.blob_id = Some(open.desc.id);
+                href.persistent_ref.blob_name = open.desc.name.clone();
+
+                // Blobs never grow beyond their maximum size, so a chunk that does not fit in an
+                // empty blob cannot be stored at all.
+                if let Err(()) = open.blob.try_append(chunk, &mut href) {
+                    return Err(From::from(format!(
+                        "Chunk of {} bytes does not fit in a blob of at most {} bytes",
+                        chunk.len(),
+                        open.blob.max_len()
+                    )));
+                }
             }
 
+            self.chunk_cache.insert(
+                href.hash.clone(),
+                href.persistent_ref.clone(),
+            );
             // Queue the callback; we will trigger it when the blob has been pushed.
-            self.blob_refs.push(callback);
+            self.open_blob(class).refs.push(callback);
         }
 
         // Info is internal to the blob only.
         href.info = None;
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        href
+        Ok(href)
     }
 
     fn retrieve(&mut self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         if href.persistent_ref.offset == 0 && href.persistent_ref.length == 0 {
             return Ok(Some(Vec::new()));
         }
-        match self.backend.retrieve(&href.persistent_ref.blob_name[..]) {
-            Ok(Some(blob)) => {
+        // Chunks in a blob that is still being filled are read from memory.
+        let blob_id = href.persistent_ref.blob_id;
+        if let Some(open) = self.open.values().find(|o| blob_id == Some(o.desc.id)) {
+            return open.blob.read_chunk(href);
+        }
+        match self.fetch(&href.persistent_ref.blob_name[..])? {
+            Some(blob) => {
                 Ok(Some(BlobReader::new(
                     self.keys.clone(),
                     crypto::CipherTextRef::new(&blob[..]),
                 )?
                     .read_chunk(href)?))
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e.into()),
+            None => Ok(None),
         }
     }
 
+    /// Fetch a whole blob from the backend.
+    fn fetch(&mut self, name: &[u8]) -> Result<Option<BlobBytes>, BlobError> {
+        // The blob may still be on its way to the backend.
+        self.uploader.wait().map_err(from_backend)?;
+        fetch_cached(&*self.backend, &self.read_cache, name)
+    }
+
+    /// Whether `href` is read without fetching a blob: it is empty, or in a blob being filled.
+    fn is_local(&self, href: &HashRef) -> bool {
+        let blob_id = href.persistent_ref.blob_id;
+        (href.persistent_ref.offset == 0 && href.persistent_ref.length == 0) ||
+            self.open.values().any(|o| blob_id == Some(o.desc.id))
+    }
+
+    fn retrieve_chunks(
+        &mut self,
+        hrefs: &[HashRef],
+    ) -> Result<Vec<Result<Option<Vec<u8>>, BlobError>>, BlobError> {
+        let mut out: Vec<Option<Result<Option<Vec<u8>>, BlobError>>> =
+            hrefs.iter().map(|_| None).collect();
+
+        // Chunks by the blob they are in, so that each blob is fetched once.
+        let mut by_blob: BTreeMap<&[u8], Vec<usize>> = BTreeMap::new();
+        for (i, href) in hrefs.iter().enumerate() {
+            if self.is_local(href) {
+                out[i] = Some(self.retrieve(href));
+            } else {
+                by_blob
+                    .entry(&href.persistent_ref.blob_name[..])
+                    .or_insert_with(Vec::new)
+                    .push(i);
+            }
+        }
+
+        for (name, indices) in by_blob {
+            let fetched = self.fetch(name)?;
+            let blob = match fetched {
+                Some(ref blob) => blob,
+                None => {
+                    for i in indices {
+                        out[i] = Some(Ok(None));
+                    }
+                    continue;
+                }
+            };
+            // Chunks are authenticated one by one, so a corrupt chunk spoils only itself.
+            let reader = match BlobReader::new_best_effort(
+                self.keys.clone(),
+                crypto::CipherTextRef::new(&blob[..]),
+            ) {
+                Ok((reader, _)) => reader,
+                Err(e) => {
+                    // Without its access key, none of the chunks in the blob can be read.
+                    for i in indices {
+                        out[i] = Some(Err(From::from(crypto::CryptoError::from(e.to_string()))));
+                    }
+                    continue;
+                }
+            };
+            for i in indices {
+                out[i] = Some(reader.read_chunk(&hrefs[i]).map(Some));
+            }
+        }
+
+        Ok(out.into_iter().map(|r| r.unwrap()).collect())
+    }
+
     fn retrieve_refs(&mut self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
-        match self.backend.retrieve(&blob.name[..])? {
+        self.uploader.wait().map_err(from_backend)?;
+        match self.backend.retrieve(&blob.name[..]).map_err(from_backend)? {
             None => Ok(None),
             Some(ct) => {
                 let hrefs = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?
@@ -207,9 +494,14 @@ impl<B: StoreBackend> StoreInner<B> {
     }
 
     fn recover(&mut self) -> Result<(), String> {
-        self.backend.list()?.into_iter()
-            .filter(|b| b.len() > 4)  // FIXME(jos): Remove when "root" is gone.
-            .map(|b| self.blob_index.recover(b.into_vec())).last();
+        for blob in self.backend.list_blobs() {
+            let blob = blob?;
+            // Blob names are sealed boxes. Anything shorter is something else kept in the
+            // backend, like the legacy "root" or the store settings.
+            if blob.name.len() >= crypto::sealed::desc::SEALBYTES {
+                self.blob_index.recover(blob.name.into_vec());
+            }
+        }
         Ok(())
     }
 
@@ -227,10 +519,45 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.tag_all(tag);
     }
 
-    fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
+    fn delete(&mut self, blob: &BlobDesc) -> Result<(), String> {
+        self.uploader.wait()?;
+        self.chunk_cache.clear();
+        self.read_cache.lock().unwrap().remove(&blob.name[..]);
+        self.backend.delete(&blob.name)?;
+        self.blob_index.delete(blob);
+        Ok(())
+    }
+
+    fn delete_by_tag(&mut self, tag: tags::Tag, concurrency: usize) -> Result<(), String> {
+        // Uploads that are still running belong to blobs that may be about to be deleted.
+        self.uploader.wait()?;
         let blobs = self.blob_index.list_by_tag(tag);
-        for b in &blobs {
-            self.backend.delete(&b.name)?;
+        // Some of the cached chunks may be in the blobs that are going away.
+        self.chunk_cache.clear();
+        self.read_cache.lock().unwrap().clear();
+        if concurrency <= 1 {
+            for b in &blobs {
+                self.backend.delete(&b.name)?;
+            }
+        } else {
+            let errors = Mutex::new(vec![]);
+            let pool = scoped_pool::Pool::new(concurrency);
+            pool.scoped(|scope| {
+                for b in &blobs {
+                    let backend = &self.backend;
+                    let errors = &errors;
+                    scope.execute(move || {
+                        if let Err(e) = backend.delete(&b.name) {
+                            errors.lock().unwrap().push(e);
+                        }
+                    });
+                }
+            });
+            pool.shutdown();
+            // The index keeps every blob until all of them are gone, as when deleting serially.
+            if let Some(e) = errors.into_inner().unwrap().into_iter().next() {
+                return Err(e);
+            }
         }
         self.blob_index.delete_by_tag(tag);
         Ok(())
@@ -243,9 +570,27 @@ impl<B: StoreBackend> BlobStore<B> {
         index: Arc<BlobIndex>,
         backend: Arc<B>,
         max_blob_size: usize,
+    ) -> BlobStore<B> {
+        BlobStore::with_chunk_cache(
+            keys,
+            index,
+            backend,
+            max_blob_size,
+            Box::new(MemoryChunkCache::new(DEFAULT_CHUNK_CACHE_SIZE)),
+        )
+    }
+
+    /// Create a blob store that looks up chunks in `chunk_cache` before storing them, and skips
+    /// those already stored.
+    pub fn with_chunk_cache(
+        keys: Arc<crypto::keys::Keeper>,
+        index: Arc<BlobIndex>,
+        backend: Arc<B>,
+        max_blob_size: usize,
+        chunk_cache: Box<ChunkCache>,
     ) -> BlobStore<B> {
         BlobStore(Arc::new(Mutex::new(
-            StoreInner::new(keys, index, backend, max_blob_size),
+            StoreInner::new(keys, index, backend, max_blob_size, chunk_cache),
         )))
     }
 
@@ -256,6 +601,9 @@ impl<B: StoreBackend> BlobStore<B> {
     /// Store a new data chunk into the current blob. The callback is triggered after the blob
     /// containing the chunk has been committed to persistent storage (it is then safe to use the
     /// `ChunkRef` as persistent reference).
+    ///
+    /// A full blob is flushed before starting on the next one. Chunks too large to fit in a blob
+    /// of `max_blob_size` are refused, and their callback is dropped without being called.
     pub fn store(
         &self,
         chunk: &[u8],
@@ -264,21 +612,118 @@ impl<B: StoreBackend> BlobStore<B> {
         leaf: LeafType,
         info: Option<&key::Info>,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
+        self.store_in_class(chunk, hash, node, leaf, info, StorageClass::Standard, callback)
+    }
+
+    /// Like `store()`, but put the chunk in a blob that is stored in `class`. Chunks for
+    /// different classes never share a blob.
+    pub fn store_in_class(
+        &self,
+        chunk: &[u8],
+        hash: Hash,
+        node: NodeType,
+        leaf: LeafType,
+        info: Option<&key::Info>,
+        class: StorageClass,
+        callback: Box<FnBox<(), ()>>,
+    ) -> Result<HashRef, BlobError> {
         let mut guard = self.lock();
-        guard.store(chunk, hash, node, leaf, info, callback)
+        guard.store(chunk, hash, node, leaf, info, class, callback)
     }
 
-    /// Retrieve the data chunk identified by `ChunkRef`.
+    /// Retrieve the data chunk identified by `ChunkRef`, also while its blob is still being
+    /// filled.
     pub fn retrieve(&self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         self.lock().retrieve(href)
     }
 
+    /// Like `retrieve`, but the blob is fetched and decrypted without holding on to the store,
+    /// so that several threads can retrieve chunks at the same time.
+    pub fn retrieve_concurrently(&self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
+        let (keys, backend, read_cache) = {
+            let mut guard = self.lock();
+            if guard.is_local(href) {
+                return guard.retrieve(href);
+            }
+            guard.uploader.wait().map_err(from_backend)?;
+            (guard.keys.clone(), guard.backend.clone(), guard.read_cache.clone())
+        };
+        match fetch_cached(&*backend, &read_cache, &href.persistent_ref.blob_name[..])? {
+            Some(blob) => {
+                Ok(Some(
+                    BlobReader::new(keys, crypto::CipherTextRef::new(&blob[..]))?
+                        .read_chunk(href)?,
+                ))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve several chunks at once, fetching each blob only once. A chunk that fails to
+    /// authenticate only fails its own result, so that the other chunks of its blob are still
+    /// read; only a failure to fetch a blob fails the whole call.
+    pub fn retrieve_chunks(
+        &self,
+        hrefs: &[HashRef],
+    ) -> Result<Vec<Result<Option<Vec<u8>>, BlobError>>, BlobError> {
+        self.lock().retrieve_chunks(hrefs)
+    }
+
     /// Fetch a blob and recover the HashRefs for its contents.
     pub fn retrieve_refs(&self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
         self.lock().retrieve_refs(blob)
     }
 
+    /// Describe the chunks stored in the blob with the given id, in the order they were written.
+    /// Only the footer of the blob is decrypted; the chunks themselves are not read.
+    pub fn inspect(&self, blob_id: i64) -> Result<Option<Vec<ChunkInfo>>, BlobError> {
+        let mut guard = self.lock();
+        let blob = match guard.blob_index.find_by_id(blob_id) {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        Ok(guard.retrieve_refs(blob)?.map(|hrefs| {
+            hrefs.into_iter().map(ChunkInfo::from_hash_ref).collect()
+        }))
+    }
+
+    /// Compare the blob with the given id, as the backend has it, with the checksum recorded
+    /// when it was uploaded. The blob is not decrypted. Gives `None` if the blob is unknown, has
+    /// no recorded checksum or is missing from the backend.
+    pub fn verify_checksum(&self, blob_id: i64) -> Result<Option<bool>, BlobError> {
+        let mut guard = self.lock();
+        let blob = match guard.blob_index.find_by_id(blob_id) {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        let want = match guard.blob_index.checksum(&blob) {
+            Some(checksum) => checksum,
+            None => return Ok(None),
+        };
+        guard.uploader.wait().map_err(from_backend)?;
+        Ok(guard.backend.retrieve(&blob.name[..]).map_err(from_backend)?.map(|ct| {
+            crypto::CipherTextRef::new(&ct[..]).checksum() == want
+        }))
+    }
+
+    /// Check the chunks of the blob with the given id, as the backend has it, against the
+    /// integrity tags recorded when they were sealed, and give the tags of those that no longer
+    /// match. Nothing is decrypted. Gives `None` if the blob is unknown or missing from the
+    /// backend; chunks sealed by older versions of hat have no tags and are not checked.
+    pub fn verify_chunk_tags(&self, blob_id: i64) -> Result<Option<Vec<ChunkTag>>, BlobError> {
+        let mut guard = self.lock();
+        let blob = match guard.blob_index.find_by_id(blob_id) {
+            Some(blob) => blob,
+            None => return Ok(None),
+        };
+        let tags = guard.blob_index.chunk_tags(&blob);
+        guard.uploader.wait().map_err(from_backend)?;
+        Ok(guard.backend.retrieve(&blob.name[..]).map_err(from_backend)?.map(|ct| {
+            tags.into_iter().filter(|tag| !tag.verify(&ct[..])).collect()
+        }))
+    }
+
     /// Reinstall a blob recovered from external storage.
     pub fn recover(&self) -> Result<(), String> {
         self.lock().recover()
@@ -292,8 +737,23 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().tag_all(tag)
     }
 
+    /// Delete a single blob, from the backend and the index.
+    pub fn delete(&self, blob: &BlobDesc) -> Result<(), String> {
+        self.lock().delete(blob)
+    }
+
     pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), String> {
-        self.lock().delete_by_tag(tag)
+        self.delete_by_tag_concurrently(tag, 1)
+    }
+
+    /// Like `delete_by_tag`, with up to `concurrency` blobs deleted from the backend at the same
+    /// time. The store stays locked throughout, so nothing is stored meanwhile.
+    pub fn delete_by_tag_concurrently(
+        &self,
+        tag: tags::Tag,
+        concurrency: usize,
+    ) -> Result<(), String> {
+        self.lock().delete_by_tag(tag, concurrency)
     }
 
     pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
@@ -311,10 +771,133 @@ impl<B: StoreBackend> BlobStore<B> {
         }
     }
 
-    /// Flush the current blob, independent of its size.
-    pub fn flush(&self) {
+    pub fn find_by_id(&self, id: i64) -> Option<BlobDesc> {
+        self.lock().blob_index.find_by_id(id)
+    }
+
+    /// Forget which chunks were stored earlier in this run, so that storing them again makes new
+    /// copies.
+    pub fn clear_chunk_cache(&self) {
+        self.lock().chunk_cache.clear()
+    }
+
+    /// Flush the current blob, independent of its size, and wait for all blobs to be uploaded
+    /// and made durable by the backend.
+    pub fn flush(&self) -> Result<(), BlobError> {
         let mut guard = self.lock();
-        guard.flush();
+        guard.flush()?;
+        guard.uploader.wait().map_err(from_backend)?;
+        guard.backend.flush().map_err(from_backend)?;
         guard.blob_index.flush();
+        Ok(())
+    }
+
+    /// Remember chunks stored from now on in a cache of at most `bytes` of memory, forgetting
+    /// those stored earlier.
+    pub fn set_chunk_cache_size(&self, bytes: usize) {
+        self.lock().chunk_cache = Box::new(MemoryChunkCache::new(bytes));
+    }
+
+    /// Roughly how many bytes of memory the chunk cache holds on to.
+    pub fn chunk_cache_memory(&self) -> usize {
+        self.lock().chunk_cache.memory_use()
+    }
+
+    /// Keep up to `bytes` of blobs that were read in memory, so that reading more chunks from
+    /// them does not fetch them again. 0, the default, keeps none.
+    pub fn set_blob_cache_size(&self, bytes: usize) {
+        *self.lock().read_cache.lock().unwrap() = BlobReadCache::new(bytes);
+    }
+
+    /// Bytes of blobs kept in memory for reading.
+    pub fn blob_cache_memory(&self) -> usize {
+        self.lock().read_cache.lock().unwrap().memory_use()
+    }
+
+    /// Keep the blobs read through this store in the same cache as those read through `other`,
+    /// so that neither fetches what the other has kept.
+    pub fn share_read_cache(&self, other: &BlobStore<B>) {
+        let read_cache = other.lock().read_cache.clone();
+        self.lock().read_cache = read_cache;
+    }
+
+    /// Fetch a blob into the blob cache ahead of reading from it, if it fits in the room that is
+    /// left there. Blobs already kept stay as they are. Returns whether the blob is kept; a blob
+    /// that no longer exists is not.
+    pub fn prefetch(&self, name: &[u8]) -> Result<bool, BlobError> {
+        let (backend, read_cache) = {
+            let guard = self.lock();
+            (guard.backend.clone(), guard.read_cache.clone())
+        };
+        {
+            let cache = read_cache.lock().unwrap();
+            if cache.contains(name) {
+                return Ok(true);
+            }
+            if cache.max_bytes() <= cache.memory_use() {
+                return Ok(false);
+            }
+        }
+        match fetch_blob(&*backend, name)? {
+            Some(blob) => {
+                Ok(read_cache.lock().unwrap().insert_if_room(
+                    name.to_vec(),
+                    Arc::new(blob.to_vec()),
+                ))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Seal chunks stored from now on with the chunk key algorithm `algorithm`, refusing one
+    /// that can not be used on this host.
+    pub fn set_seal_algorithm(&self, algorithm: &str) -> Result<(), BlobError> {
+        let algorithm = crypto::capabilities().require(algorithm)?;
+        let mut guard = self.lock();
+        guard.seal_algorithm = algorithm;
+        for open in guard.open.values_mut() {
+            open.blob.set_algorithm(algorithm);
+        }
+        Ok(())
+    }
+
+    /// Put chunks stored from now on through `pipeline`, like "gzip+seal", instead of only
+    /// sealing them. Chunks already stored are read back with the stages recorded for them.
+    pub fn set_chunk_pipeline(&self, pipeline: Arc<ChunkPipeline>) {
+        let mut guard = self.lock();
+        guard.pipeline = pipeline.clone();
+        for open in guard.open.values_mut() {
+            open.blob.set_pipeline(pipeline.clone());
+        }
+    }
+
+    /// Record the padding length in the footer of blobs that have no chunks yet, so that reading
+    /// them checks it. Blobs already filling are finished the way they were started.
+    pub fn set_record_padding(&self, record: bool) {
+        let mut guard = self.lock();
+        guard.record_padding = record;
+        for open in guard.open.values_mut() {
+            if open.blob.upperbound_len() == 0 {
+                open.blob.set_record_padding(record);
+            }
+        }
+    }
+
+    /// Derive the id of each new blob from the hash of its first chunk, so that packing the same
+    /// chunks in the same order gives blobs of the same names, like when a backup is run again
+    /// after a crash. Blobs already filling keep their ids.
+    pub fn set_deterministic_ids(&self, enabled: bool) {
+        self.lock().deterministic_ids = enabled;
+    }
+
+    /// The chunk key algorithm new chunks are sealed with.
+    pub fn seal_algorithm(&self) -> &'static str {
+        self.lock().seal_algorithm
+    }
+
+    /// Limit the number of blobs that are uploaded at the same time. Storing chunks blocks while
+    /// this many full blobs wait for the backend.
+    pub fn set_max_uploads(&self, max_uploads: usize) {
+        self.lock().uploader.set_max_in_flight(max_uploads);
     }
 }
@@ -0,0 +1,286 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The stages a chunk goes through before it is appended to a blob.
+//!
+//! By default a chunk is only sealed. A pipeline can add stages before sealing, like
+//! compression, and after it, like padding that hides the length of the chunk. The stages that
+//! were applied to a chunk are recorded in its reference, in the text form of `describe`, so
+//! that reading it back undoes exactly those, in reverse order, whatever the pipeline in use
+//! when it is read. A stage can leave a chunk be, like compression of data that does not
+//! compress; it is then not recorded for that chunk.
+
+use crypto::{self, CipherTextRef, PlainText, PlainTextRef};
+use flate2;
+use hash::tree::HashRef;
+use std::io::Write;
+
+use super::BlobError;
+
+
+/// Most bytes a compressed chunk may inflate to, so that a corrupt chunk can not fill memory.
+const MAX_INFLATED_LEN: usize = 256 * 1024 * 1024;
+
+/// Marks where the data ends and padding begins, as in ISO/IEC 7816-4.
+const PADDING_MARKER: u8 = 0x80;
+
+/// What the stages need to seal and unseal a chunk.
+pub struct ChunkKeys<'a> {
+    /// The access key of the blob that the chunk is in.
+    pub access_key: &'a crypto::authed::desc::Key,
+    /// Chunk key algorithm to seal with, as returned by `Key::algorithm`.
+    pub algorithm: &'a str,
+}
+
+/// One transformation of a chunk, and its inverse.
+pub trait Stage: Send + Sync {
+    /// The stage with its parameters, as `ChunkPipeline::parse` reads it.
+    fn describe(&self) -> String;
+
+    /// Transform `data` on its way to the blob, or leave it as it is by returning `None`. The
+    /// stage may update the reference of the chunk, like the seal stage does with its key.
+    fn forward(
+        &self,
+        href: &mut HashRef,
+        keys: &ChunkKeys,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, BlobError>;
+
+    /// Undo `forward` on data read back from the blob.
+    fn inverse(
+        &self,
+        href: &HashRef,
+        keys: &ChunkKeys,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, BlobError>;
+}
+
+/// Seals the chunk with its own key, as every chunk is.
+pub struct Seal;
+
+impl Stage for Seal {
+    fn describe(&self) -> String {
+        "seal".to_owned()
+    }
+
+    fn forward(
+        &self,
+        href: &mut HashRef,
+        keys: &ChunkKeys,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, BlobError> {
+        let ct = crypto::RefKey::seal_with_algorithm(
+            href,
+            keys.access_key,
+            keys.algorithm,
+            PlainTextRef::new(data),
+        )?;
+        Ok(Some(ct.to_vec()))
+    }
+
+    fn inverse(
+        &self,
+        href: &HashRef,
+        keys: &ChunkKeys,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, BlobError> {
+        // What the later stages left is exactly the sealed chunk.
+        let mut sealed = href.clone();
+        sealed.persistent_ref.offset = 0;
+        sealed.persistent_ref.length = data.len();
+        let pt = CipherTextRef::new(&data[..]).verify_and_split(&sealed, keys.access_key)?;
+        Ok(pt.into_vec())
+    }
+}
+
+/// Compresses the chunk with DEFLATE in a gzip stream, unless that does not make it smaller.
+pub struct Gzip;
+
+impl Stage for Gzip {
+    fn describe(&self) -> String {
+        "gzip".to_owned()
+    }
+
+    fn forward(
+        &self,
+        _href: &mut HashRef,
+        _keys: &ChunkKeys,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, BlobError> {
+        let mut encoder = flate2::write::GzEncoder::new(
+            Vec::with_capacity(data.len()),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(data).map_err(|e| format!("gzip failed: {}", e))?;
+        let compressed = encoder.finish().map_err(|e| format!("gzip failed: {}", e))?;
+        if compressed.len() >= data.len() {
+            return Ok(None);
+        }
+        Ok(Some(compressed))
+    }
+
+    fn inverse(
+        &self,
+        _href: &HashRef,
+        _keys: &ChunkKeys,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, BlobError> {
+        let mut decoder = flate2::read::GzDecoder::new(&data[..]);
+        let pt = PlainText::from_reader(&mut decoder, MAX_INFLATED_LEN)
+            .map_err(|e| format!("gunzip failed: {}", e))?;
+        Ok(pt.into_vec())
+    }
+}
+
+/// Pads the chunk to a multiple of `block` bytes: a marker byte, then zeros.
+pub struct Pad {
+    pub block: usize,
+}
+
+impl Stage for Pad {
+    fn describe(&self) -> String {
+        format!("pad:{}", self.block)
+    }
+
+    fn forward(
+        &self,
+        _href: &mut HashRef,
+        _keys: &ChunkKeys,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, BlobError> {
+        let mut data = data.to_vec();
+        data.push(PADDING_MARKER);
+        let rest = data.len() % self.block;
+        if rest > 0 {
+            let len = data.len() + self.block - rest;
+            data.resize(len, 0);
+        }
+        Ok(Some(data))
+    }
+
+    fn inverse(
+        &self,
+        _href: &HashRef,
+        _keys: &ChunkKeys,
+        mut data: Vec<u8>,
+    ) -> Result<Vec<u8>, BlobError> {
+        if data.len() % self.block != 0 {
+            return Err(From::from("Padded chunk is not a whole number of blocks"));
+        }
+        let end = match data.iter().rposition(|&b| b != 0) {
+            Some(end) if data[end] == PADDING_MARKER => end,
+            _ => return Err(From::from("Padded chunk has no padding marker")),
+        };
+        data.truncate(end);
+        Ok(data)
+    }
+}
+
+/// The stages a chunk goes through, in order. Exactly one of them seals the chunk.
+pub struct ChunkPipeline {
+    stages: Vec<Box<Stage>>,
+}
+
+impl Default for ChunkPipeline {
+    fn default() -> ChunkPipeline {
+        ChunkPipeline { stages: vec![Box::new(Seal)] }
+    }
+}
+
+impl ChunkPipeline {
+    pub fn new(stages: Vec<Box<Stage>>) -> Result<ChunkPipeline, BlobError> {
+        let seals = stages.iter().filter(|s| s.describe() == "seal").count();
+        if seals != 1 {
+            return Err(From::from(format!(
+                "A chunk pipeline must seal exactly once, not {} times",
+                seals
+            )));
+        }
+        Ok(ChunkPipeline { stages: stages })
+    }
+
+    /// Parse stages joined by "+", like "gzip+seal+pad:4096".
+    pub fn parse(text: &str) -> Result<ChunkPipeline, BlobError> {
+        let mut stages: Vec<Box<Stage>> = vec![];
+        for name in text.split('+') {
+            let mut parts = name.splitn(2, ':');
+            let stage: Box<Stage> = match (parts.next(), parts.next()) {
+                (Some("seal"), None) => Box::new(Seal),
+                (Some("gzip"), None) => Box::new(Gzip),
+                (Some("pad"), Some(block)) => {
+                    match block.parse::<usize>() {
+                        Ok(block) if block > 0 => Box::new(Pad { block: block }),
+                        _ => return Err(From::from(format!("Invalid padding block: {:?}", name))),
+                    }
+                }
+                _ => return Err(From::from(format!("Unknown chunk pipeline stage: {:?}", name))),
+            };
+            stages.push(stage);
+        }
+        ChunkPipeline::new(stages)
+    }
+
+    /// Stable text form of the pipeline, as `parse` reads it.
+    pub fn describe(&self) -> String {
+        self.stages.iter().map(|s| s.describe()).collect::<Vec<_>>().join("+")
+    }
+
+    /// Put `chunk` through the stages, and record in `href` those that were applied.
+    pub fn forward(
+        &self,
+        href: &mut HashRef,
+        keys: &ChunkKeys,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, BlobError> {
+        let mut data = chunk.to_vec();
+        let mut applied = vec![];
+        for stage in self.stages.iter() {
+            if let Some(out) = stage.forward(href, keys, &data[..])? {
+                data = out;
+                applied.push(stage.describe());
+            }
+        }
+        href.persistent_ref.length = data.len();
+        // Chunks that were only sealed look the same as those from before there were pipelines.
+        href.persistent_ref.pipeline = if applied == ["seal"] {
+            None
+        } else {
+            Some(applied.join("+"))
+        };
+        Ok(data)
+    }
+
+    /// Read back the chunk `href` from `blob`, undoing the stages recorded for it.
+    pub fn inverse(
+        href: &HashRef,
+        access_key: &crypto::authed::desc::Key,
+        blob: CipherTextRef,
+    ) -> Result<PlainText, BlobError> {
+        let pipeline = match href.persistent_ref.pipeline {
+            None => return Ok(crypto::RefKey::unseal(access_key, href, blob)?),
+            Some(ref text) => ChunkPipeline::parse(text)?,
+        };
+        let chunk_ref = &href.persistent_ref;
+        let end = chunk_ref.offset.saturating_add(chunk_ref.length);
+        let mut data = blob.checked_slice(chunk_ref.offset, end)?.to_vec();
+        let keys = ChunkKeys {
+            access_key: access_key,
+            algorithm: crypto::SEAL_ALGORITHM,
+        };
+        for stage in pipeline.stages.iter().rev() {
+            data = stage.inverse(href, &keys, data)?;
+        }
+        Ok(PlainText::new(data))
+    }
+}
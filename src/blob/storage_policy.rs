@@ -0,0 +1,84 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decides which storage class to ask the backend for, from what a blob holds.
+
+use backend::StorageClass;
+use chrono;
+use key;
+
+
+/// Keeps file data that has not been modified for a while in a colder storage class. Everything
+/// else, like tree nodes and directory listings, is read on every restore and stays standard.
+#[derive(Clone, Debug)]
+pub struct StoragePolicy {
+    /// Age since last modification after which file data goes cold. `None` keeps all of it
+    /// standard.
+    pub cold_after: Option<chrono::Duration>,
+    pub cold_class: StorageClass,
+}
+
+impl Default for StoragePolicy {
+    fn default() -> StoragePolicy {
+        StoragePolicy {
+            cold_after: None,
+            cold_class: StorageClass::InfrequentAccess,
+        }
+    }
+}
+
+impl StoragePolicy {
+    /// The class for the data of a file with metadata `info`, at time `now` in seconds since the
+    /// epoch.
+    pub fn file_data_class(&self, info: &key::Info, now: i64) -> StorageClass {
+        match (self.cold_after, info.modified_ts_secs) {
+            (Some(cold_after), Some(modified))
+                if chrono::Duration::seconds(now - modified as i64) >= cold_after => {
+                self.cold_class
+            }
+            _ => StorageClass::Standard,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(modified: u64) -> key::Info {
+        let mut e = key::Entry::new(None, b"f".to_vec(), key::Data::FilePlaceholder, None);
+        e.info.modified_ts_secs = Some(modified);
+        e.info
+    }
+
+    #[test]
+    fn old_file_data_goes_cold() {
+        let day = 24 * 3600;
+        let now = 100 * day;
+        let policy = StoragePolicy {
+            cold_after: Some(chrono::Duration::days(30)),
+            cold_class: StorageClass::Archive,
+        };
+        let old = info((now - 31 * day) as u64);
+        let new = info((now - 29 * day) as u64);
+        let mut unknown = new.clone();
+        unknown.modified_ts_secs = None;
+
+        assert_eq!(policy.file_data_class(&old, now), StorageClass::Archive);
+        assert_eq!(policy.file_data_class(&new, now), StorageClass::Standard);
+        assert_eq!(policy.file_data_class(&unknown, now), StorageClass::Standard);
+        assert_eq!(StoragePolicy::default().file_data_class(&old, now), StorageClass::Standard);
+    }
+}
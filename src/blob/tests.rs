@@ -12,15 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
-use backend::{MemoryBackend, StoreBackend};
-use blob::{Blob, BlobReader, BlobError, BlobIndex, BlobStore, ChunkRef, NodeType, LeafType};
+use backend::{FileBackend, ListPage, MemoryBackend, StorageClass, StoreBackend, SyncBatch};
+use blob::{Blob, BlobReader, BlobError, BlobIndex, BlobReadCache, BlobStore, ChunkCache,
+           ChunkPipeline, ChunkRef, ChunkRefBuilder, ChunkTag, DEFAULT_CHUNK_CACHE_SIZE, Key,
+           MemoryChunkCache, NodeType, LeafType, Packing};
+use blob::upload::Uploader;
 use crypto;
 use db;
 use hash;
+use hash::tree::HashRef;
 use quickcheck;
+use rand;
 
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
 
 #[test]
 fn identity() {
@@ -44,12 +53,12 @@ fn identity() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -93,10 +102,10 @@ fn identity_with_excessive_flushing() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
-            bs_p.flush();
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
             assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), &chunk[..]);
         }
@@ -130,6 +139,7 @@ fn blobid_identity() {
             offset: offset,
             length: length,
             packing: None,
+            pipeline: None,
             key: None,
         };
         let blob_name_bytes = blob_name.as_bytes();
@@ -146,6 +156,42 @@ fn blobid_identity() {
     quickcheck::quickcheck(prop as fn(Vec<u8>, usize, usize) -> bool);
 }
 
+#[test]
+fn chunk_ref_builder_checks_fields() {
+    use crypto::authed::desc::{COMMITBYTES, KEYBYTES, MACBYTES};
+
+    let key = || Key::from_bytes("chacha20poly1305-committed", &[7; KEYBYTES]).unwrap();
+    let in_blob = || ChunkRefBuilder::new().with_blob_id(3).with_blob_name(b"blob".to_vec());
+
+    // The empty chunk, and sealed chunks with or without packing.
+    let empty = in_blob().build().unwrap();
+    assert_eq!((empty.offset, empty.length), (0, 0));
+    assert!(empty.key.is_none());
+    let sealed = in_blob().with_range(10, 100).with_key(key()).build().unwrap();
+    assert_eq!(crypto::RefKey::plaintext_len(&sealed), Some(100 - MACBYTES - COMMITBYTES));
+    assert_eq!(sealed.blob_id, Some(3));
+    in_blob()
+        .with_range(10, 100)
+        .with_key(key())
+        .with_packing(Packing::Snappy)
+        .build()
+        .unwrap();
+
+    // Contents without a key to read them with.
+    assert!(in_blob().with_range(10, 100).build().is_err());
+    // Packing without sealed contents to unpack.
+    assert!(in_blob().with_packing(Packing::GZip).build().is_err());
+    // A key without room for what it seals.
+    assert!(in_blob().with_key(key()).build().is_err());
+    let overhead = MACBYTES + COMMITBYTES;
+    assert!(in_blob().with_range(10, overhead - 1).with_key(key()).build().is_err());
+    in_blob().with_range(10, overhead).with_key(key()).build().unwrap();
+
+    // Rebuilding a valid reference keeps it as it was.
+    let rebuilt = ChunkRefBuilder::from(sealed.clone()).build().unwrap();
+    assert_eq!(rebuilt.as_bytes(), sealed.as_bytes());
+}
+
 #[test]
 fn blob_reuse() {
     let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
@@ -163,6 +209,7 @@ fn blob_reuse() {
             offset: 0,
             length: 0,
             packing: None,
+            pipeline: None,
             key: None,
         },
     };
@@ -190,6 +237,75 @@ fn blob_reuse() {
     assert_eq!(vec![1, 2], reader.read_chunk(&c3).unwrap());
 }
 
+#[test]
+fn blob_pipelines_round_trip() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let compressible = vec![7u8; 20000];
+    let random: Vec<u8> = (0..20000).map(|_| rand::random::<u8>()).collect();
+
+    // For each pipeline, what is recorded for a chunk that compresses and one that does not.
+    let cases = vec![
+        ("seal", None, None),
+        ("gzip+seal", Some("gzip+seal"), None),
+        ("gzip+seal+pad:4096", Some("gzip+seal+pad:4096"), Some("seal+pad:4096")),
+        ("seal+pad:512", Some("seal+pad:512"), Some("seal+pad:512")),
+    ];
+    for (text, want_compressible, want_random) in cases {
+        let pipeline = ChunkPipeline::parse(text).unwrap();
+        assert_eq!(pipeline.describe(), text);
+
+        let mut b = Blob::new(keys.clone(), 100000);
+        b.set_pipeline(Arc::new(pipeline));
+        let mut hrefs = vec![];
+        for chunk in &[&compressible, &random] {
+            let node = NodeType::Leaf;
+            let leaf = LeafType::FileChunk;
+            let mut href = hash::tree::HashRef {
+                hash: hash::Hash::new(&keys, node, leaf, &chunk[..]),
+                node: node,
+                leaf: leaf,
+                info: None,
+                persistent_ref: ChunkRefBuilder::new().build().unwrap(),
+            };
+            b.try_append(&chunk[..], &mut href).unwrap();
+            hrefs.push(href);
+        }
+        let recorded: Vec<Option<&str>> = hrefs
+            .iter()
+            .map(|h| h.persistent_ref.pipeline.as_ref().map(|p| &p[..]))
+            .collect();
+        assert_eq!(recorded, vec![want_compressible, want_random], "{}", text);
+        if text.starts_with("gzip") {
+            assert!(hrefs[0].persistent_ref.length < compressible.len());
+        }
+        if let Some(block) = text.split("pad:").nth(1) {
+            let block: usize = block.parse().unwrap();
+            assert!(hrefs.iter().all(|h| h.persistent_ref.length % block == 0));
+        }
+
+        // The chunks are read back by what the footer recorded, not by the pipeline in use.
+        b.set_pipeline(Arc::new(ChunkPipeline::default()));
+        let out = b.to_ciphertext().unwrap().to_vec();
+        let reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&out[..])).unwrap();
+        let refs = reader.refs().unwrap();
+        assert_eq!(refs[0].persistent_ref.pipeline, hrefs[0].persistent_ref.pipeline);
+        assert_eq!(reader.read_chunk(&refs[0]).unwrap(), compressible);
+        assert_eq!(reader.read_chunk(&refs[1]).unwrap(), random);
+    }
+}
+
+#[test]
+fn chunk_pipeline_parse_checks_stages() {
+    assert!(ChunkPipeline::parse("seal").is_ok());
+    assert_eq!(ChunkPipeline::default().describe(), "seal");
+    // Every pipeline seals, once.
+    assert!(ChunkPipeline::parse("gzip").is_err());
+    assert!(ChunkPipeline::parse("seal+seal").is_err());
+    assert!(ChunkPipeline::parse("seal+pad:0").is_err());
+    assert!(ChunkPipeline::parse("seal+pad").is_err());
+    assert!(ChunkPipeline::parse("seal+rot13").is_err());
+}
+
 #[test]
 fn blob_identity() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
@@ -213,6 +329,7 @@ fn blob_identity() {
                     offset: 0,
                     length: 0,
                     packing: None,
+                    pipeline: None,
                     key: None,
                 },
             };
@@ -278,6 +395,7 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
                 offset: 0,
                 length: block.len(),
                 packing: None,
+                pipeline: None,
                 key: None,
             },
         };
@@ -348,3 +466,720 @@ fn blob_ciphertext_authed_allbytes() {
     // We did not corrupt the blob.
     assert_eq!(vs, verify(&keys, &bytes[..]).unwrap());
 }
+
+#[test]
+fn padding_length_is_authenticated() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let chunk = vec![5u8; 100];
+
+    let mut blob = Blob::new(keys.clone(), 1024);
+    blob.set_record_padding(true);
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node: node,
+        leaf: leaf,
+        info: None,
+        persistent_ref: ChunkRef {
+            blob_id: None,
+            blob_name: Vec::new(),
+            offset: 0,
+            length: 0,
+            packing: None,
+            pipeline: None,
+            key: None,
+        },
+    };
+    blob.try_append(&chunk[..], &mut href).unwrap();
+    let bytes = blob.to_ciphertext().unwrap().to_vec();
+    let pad_start = href.persistent_ref.offset + href.persistent_ref.length;
+
+    let reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&bytes[..])).unwrap();
+    assert_eq!(reader.refs().unwrap().len(), 1);
+
+    // The padding is covered by the authentication of the whole blob.
+    let mut flipped = bytes.clone();
+    flipped[pad_start] ^= 1;
+    assert!(BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&flipped[..])).is_err());
+
+    // Even with that authentication redone, padding that was cut short or added to no longer
+    // matches the length in the footer.
+    let reauthenticate = |edit: &Fn(&mut Vec<u8>)| {
+        let mut body = bytes[..bytes.len() - crypto::authed::hash::DIGESTBYTES].to_vec();
+        edit(&mut body);
+        let mut ct = crypto::CipherText::new(body);
+        ct.append_authentication(&keys);
+        ct.to_vec()
+    };
+    let truncated = reauthenticate(&|b| {
+        b.remove(pad_start);
+    });
+    let extended = reauthenticate(&|b| b.insert(pad_start, 0));
+    for modified in vec![truncated, extended] {
+        let reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&modified[..]))
+            .unwrap();
+        match reader.refs() {
+            Err(BlobError::CryptoError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("padding length was not checked"),
+        }
+    }
+}
+
+fn store_chunk<B: StoreBackend>(
+    bs_p: &BlobStore<B>,
+    keys: &crypto::keys::Keeper,
+    chunk: &[u8],
+) -> Result<HashRef, BlobError> {
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    bs_p.store(
+        chunk,
+        hash::Hash::new(keys, node, leaf, chunk),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    )
+}
+
+#[test]
+fn packing_respects_max_blob_size() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let chunks: Vec<Vec<u8>> = (0..50).map(|i| vec![i as u8; 100]).collect();
+    let hrefs: Vec<HashRef> = chunks
+        .iter()
+        .map(|c| store_chunk(&bs_p, &keys, &c[..]).unwrap())
+        .collect();
+    bs_p.flush().unwrap();
+
+    // The chunks are spread over several blobs, none of which is larger than the maximum.
+    let names = backend.list().unwrap();
+    assert!(names.len() > 1);
+    for name in names.iter() {
+        assert_eq!(backend.retrieve(&name[..]).unwrap().unwrap().len(), 1024);
+    }
+    for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+        assert_eq!(&bs_p.retrieve(href).unwrap().unwrap(), chunk);
+    }
+}
+
+#[test]
+fn deterministic_ids_follow_the_first_chunk() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let pack = |chunks: &[Vec<u8>]| {
+        let backend = Arc::new(MemoryBackend::new());
+        let db = Arc::new(db::Index::new_for_testing());
+        let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+        let bs_p = BlobStore::new(keys.clone(), blob_index.clone(), backend.clone(), 1024);
+        bs_p.set_deterministic_ids(true);
+        let hrefs: Vec<HashRef> = chunks
+            .iter()
+            .map(|c| store_chunk(&bs_p, &keys, &c[..]).unwrap())
+            .collect();
+        bs_p.flush().unwrap();
+
+        // Derived names still open to the ids they were derived with.
+        for (href, chunk) in hrefs.iter().zip(chunks) {
+            assert_eq!(bs_p.retrieve(href).unwrap().unwrap(), &chunk[..]);
+            let name = &href.persistent_ref.blob_name[..];
+            assert_eq!(blob_index.recover(name.to_vec()).id, href.persistent_ref.blob_id.unwrap());
+        }
+        hrefs
+            .into_iter()
+            .map(|href| (href.persistent_ref.blob_id, href.persistent_ref.blob_name))
+            .collect::<Vec<(Option<i64>, Vec<u8>)>>()
+    };
+
+    let chunks: Vec<Vec<u8>> = (0..30).map(|i| vec![i as u8; 100]).collect();
+    let first = pack(&chunks[..]);
+    let again = pack(&chunks[..]);
+    assert_eq!(first, again);
+    let ids: HashSet<Option<i64>> = first.iter().map(|r| r.0).collect();
+    assert!(ids.len() > 1);
+
+    // Other content starts blobs of other names.
+    let other = pack(&chunks[1..]);
+    assert!(other.iter().all(|r| !ids.contains(&r.0)));
+}
+
+#[test]
+fn oversize_chunk_is_refused() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let small = store_chunk(&bs_p, &keys, &[1; 100]).unwrap();
+    assert!(store_chunk(&bs_p, &keys, &[2; 1024]).is_err());
+
+    // The chunks stored before are unaffected.
+    bs_p.flush().unwrap();
+    assert_eq!(backend.list().unwrap().len(), 1);
+    assert_eq!(bs_p.retrieve(&small).unwrap().unwrap(), vec![1; 100]);
+}
+
+#[test]
+fn concurrent_identical_chunks_are_sealed_once() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = Arc::new(BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024 * 1024));
+
+    let chunk = vec![7; 1000];
+    let (callback_sender, callback_receiver) = mpsc::channel();
+    let threads: Vec<_> = (0..16)
+        .map(|_| {
+            let bs_p = bs_p.clone();
+            let keys = keys.clone();
+            let chunk = chunk.clone();
+            let callback_sender = callback_sender.clone();
+            thread::spawn(move || {
+                let node = NodeType::Leaf;
+                let leaf = LeafType::FileChunk;
+                bs_p.store(
+                    &chunk[..],
+                    hash::Hash::new(&keys, node, leaf, &chunk[..]),
+                    node,
+                    leaf,
+                    None,
+                    Box::new(move |()| callback_sender.send(()).unwrap()),
+                ).unwrap()
+            })
+        })
+        .collect();
+    let hrefs: Vec<HashRef> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    bs_p.flush().unwrap();
+
+    // Every caller is told about the same copy, and all of them learn when it is stored.
+    for href in hrefs.iter() {
+        assert_eq!(href.persistent_ref.blob_name, hrefs[0].persistent_ref.blob_name);
+        assert_eq!(href.persistent_ref.offset, hrefs[0].persistent_ref.offset);
+        assert_eq!(bs_p.retrieve(href).unwrap().unwrap(), chunk);
+    }
+    for _ in 0..16 {
+        callback_receiver.recv().unwrap();
+    }
+
+    // Only one copy was sealed into the blob.
+    let names = backend.list().unwrap();
+    assert_eq!(names.len(), 1);
+    let blob = backend.retrieve(&names[0][..]).unwrap().unwrap();
+    let reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&blob[..])).unwrap();
+    assert_eq!(reader.refs().unwrap().len(), 1);
+}
+
+#[test]
+fn inspect_reports_chunk_layout() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 64 * 1024);
+
+    let chunks: Vec<Vec<u8>> = vec![vec![1; 10], vec![2; 1000], vec![3; 1], vec![4; 5000]];
+    let hrefs: Vec<HashRef> = chunks
+        .iter()
+        .map(|c| store_chunk(&bs_p, &keys, &c[..]).unwrap())
+        .collect();
+    bs_p.flush().unwrap();
+
+    let blob_id = hrefs[0].persistent_ref.blob_id.unwrap();
+    let infos = bs_p.inspect(blob_id).unwrap().unwrap();
+    assert_eq!(infos.len(), chunks.len());
+
+    let mut offset = 0;
+    for ((info, href), chunk) in infos.iter().zip(hrefs.iter()).zip(chunks.iter()) {
+        assert_eq!(info.hash, href.hash);
+        assert_eq!(info.offset, href.persistent_ref.offset);
+        assert_eq!(info.length, href.persistent_ref.length);
+        assert_eq!(info.plaintext_length, Some(chunk.len()));
+        assert_eq!(info.algorithm, Some("chacha20poly1305-committed"));
+        assert!(info.packing.is_none());
+
+        // Chunks are written back to back.
+        assert_eq!(info.offset, offset);
+        offset += info.length;
+    }
+
+    // Every chunk has its own key; the fingerprints tell them apart without showing them.
+    let fingerprints: HashSet<String> = infos
+        .iter()
+        .map(|i| i.key_fingerprint.clone().unwrap())
+        .collect();
+    assert_eq!(fingerprints.len(), chunks.len());
+
+    assert!(bs_p.inspect(blob_id + 100).unwrap().is_none());
+}
+
+#[test]
+fn retrieve_chunks_isolates_corrupt_chunks() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 64 * 1024);
+
+    let chunks: Vec<Vec<u8>> = (0..4).map(|i| vec![i as u8; 1000]).collect();
+    let hrefs: Vec<HashRef> = chunks
+        .iter()
+        .map(|c| store_chunk(&bs_p, &keys, &c[..]).unwrap())
+        .collect();
+    bs_p.flush().unwrap();
+    let names = backend.list().unwrap();
+    assert_eq!(names.len(), 1);
+
+    // Corrupt the MAC of the third chunk, which comes right before its key commitment.
+    let bad = hrefs[2].persistent_ref.clone();
+    let mut blob = backend.retrieve(&names[0][..]).unwrap().unwrap();
+    blob[bad.offset + bad.length - crypto::authed::desc::COMMITBYTES - 1] ^= 1;
+    backend.delete(&names[0][..]).unwrap();
+    backend.store(&names[0][..], &crypto::CipherText::new(blob)).unwrap();
+
+    // Read one at a time, every chunk fails with the blob.
+    assert!(bs_p.retrieve(&hrefs[0]).is_err());
+
+    let results = bs_p.retrieve_chunks(&hrefs[..]).unwrap();
+    assert_eq!(results.len(), chunks.len());
+    for (i, (result, chunk)) in results.into_iter().zip(chunks.iter()).enumerate() {
+        match result {
+            Ok(Some(ref got)) if i != 2 => assert_eq!(got, chunk),
+            Err(BlobError::CryptoError(_)) if i == 2 => (),
+            other => panic!("chunk {}: {:?}", i, other.map(|c| c.map(|c| c.len()))),
+        }
+    }
+}
+
+#[test]
+fn memory_chunk_cache_forgets_least_recently_seen() {
+    let keys = crypto::keys::Keeper::new_for_testing();
+    let hash = |i: u8| hash::Hash::new(&keys, NodeType::Leaf, LeafType::FileChunk, &[i]);
+    let chunk_ref = |i: u8| {
+        ChunkRefBuilder::new()
+            .with_blob_id(i as i64)
+            .with_blob_name(vec![i; 16])
+            .build()
+            .unwrap()
+    };
+
+    let mut one = MemoryChunkCache::new(DEFAULT_CHUNK_CACHE_SIZE);
+    one.insert(hash(0), chunk_ref(0));
+    let entry_size = one.memory_use();
+    assert!(entry_size > 0);
+
+    // Room for two chunks.
+    let mut cache = MemoryChunkCache::new(2 * entry_size);
+    cache.insert(hash(1), chunk_ref(1));
+    cache.insert(hash(2), chunk_ref(2));
+    assert_eq!(cache.get(&hash(1)).unwrap().blob_id, Some(1));
+    cache.insert(hash(3), chunk_ref(3));
+
+    // The second chunk was seen longest ago.
+    assert!(cache.get(&hash(2)).is_none());
+    assert_eq!(cache.get(&hash(1)).unwrap().blob_id, Some(1));
+    assert_eq!(cache.get(&hash(3)).unwrap().blob_id, Some(3));
+    assert_eq!(cache.memory_use(), 2 * entry_size);
+
+    // Too little room for even one chunk.
+    let mut tiny = MemoryChunkCache::new(entry_size - 1);
+    tiny.insert(hash(1), chunk_ref(1));
+    assert!(tiny.get(&hash(1)).is_none());
+    assert_eq!(tiny.memory_use(), 0);
+}
+
+#[test]
+fn blob_read_cache_prefetches_only_into_free_room() {
+    let blob = |i: u8| Arc::new(vec![i; 100]);
+    let mut cache = BlobReadCache::new(250);
+
+    cache.insert(vec![1], blob(1));
+    assert!(cache.insert_if_room(vec![2], blob(2)));
+    // Full: prefetching does not push out blobs that were read.
+    assert!(!cache.insert_if_room(vec![3], blob(3)));
+    assert!(cache.contains(&[1]) && cache.contains(&[2]));
+    assert_eq!(cache.memory_use(), 200);
+
+    // Reading does, least recently read first.
+    assert!(cache.get(&[1]).is_some());
+    cache.insert(vec![3], blob(3));
+    assert!(!cache.contains(&[2]));
+    assert_eq!(&cache.get(&[1]).unwrap()[..], &[1; 100][..]);
+    assert_eq!(cache.memory_use(), 200);
+
+    let mut off = BlobReadCache::new(0);
+    off.insert(vec![1], blob(1));
+    assert!(!off.insert_if_room(vec![2], blob(2)));
+    assert_eq!(off.memory_use(), 0);
+}
+
+/// Backend that takes a while to store each blob, and records how many stores overlap.
+struct SlowBackend {
+    inner: MemoryBackend,
+    fail: bool,
+    // Stores running now, and the most that ever ran at once.
+    concurrency: Mutex<(usize, usize)>,
+}
+
+impl SlowBackend {
+    fn new(fail: bool) -> SlowBackend {
+        SlowBackend {
+            inner: MemoryBackend::new(),
+            fail: fail,
+            concurrency: Mutex::new((0, 0)),
+        }
+    }
+
+    fn max_concurrent_stores(&self) -> usize {
+        self.concurrency.lock().unwrap().1
+    }
+}
+
+impl StoreBackend for SlowBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        {
+            let mut c = self.concurrency.lock().unwrap();
+            c.0 += 1;
+            c.1 = ::std::cmp::max(c.0, c.1);
+        }
+        thread::sleep(Duration::from_millis(20));
+        self.concurrency.lock().unwrap().0 -= 1;
+
+        if self.fail {
+            Err("backend unavailable".to_owned())
+        } else {
+            self.inner.store(name, data)
+        }
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        self.inner.list_page(token)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn uploads_are_bounded_and_complete() {
+    let backend = Arc::new(SlowBackend::new(false));
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+    bs_p.set_max_uploads(2);
+
+    let chunks: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; 300]).collect();
+    let hrefs: Vec<HashRef> = chunks
+        .iter()
+        .map(|c| store_chunk(&bs_p, &keys, &c[..]).unwrap())
+        .collect();
+    bs_p.flush().unwrap();
+
+    assert!(backend.max_concurrent_stores() > 1);
+    assert!(backend.max_concurrent_stores() <= 2);
+
+    // Every blob made it to the backend.
+    let names: HashSet<Vec<u8>> = hrefs
+        .iter()
+        .map(|h| h.persistent_ref.blob_name.clone())
+        .collect();
+    assert!(names.len() > 2);
+    assert_eq!(backend.list().unwrap().len(), names.len());
+    for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+        assert_eq!(&bs_p.retrieve(href).unwrap().unwrap(), chunk);
+    }
+}
+
+#[test]
+fn upload_errors_abort_the_store() {
+    let backend = Arc::new(SlowBackend::new(true));
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+    bs_p.set_max_uploads(1);
+
+    // The first full blob is handed over without trouble; its failure shows up later.
+    let mut failed = false;
+    for i in 0..20 {
+        if store_chunk(&bs_p, &keys, &[i as u8; 300]).is_err() {
+            failed = true;
+            break;
+        }
+    }
+    assert!(failed);
+    assert!(bs_p.flush().is_err());
+    assert_eq!(backend.list().unwrap().len(), 0);
+}
+
+/// Backend that cannot tell whether it has a blob.
+struct UnsureBackend(MemoryBackend);
+
+impl StoreBackend for UnsureBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        self.0.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.0.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.0.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.0.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.0.flush()
+    }
+
+    fn exists(&self, _name: &[u8]) -> Result<bool, String> {
+        Err("connection reset".to_owned())
+    }
+}
+
+#[test]
+fn upload_skips_blobs_already_stored() {
+    let backend = Arc::new(MemoryBackend::new());
+    backend.store(b"a", &crypto::CipherText::new(b"old".to_vec())).unwrap();
+    assert_eq!(backend.exists(b"a"), Ok(true));
+    assert_eq!(backend.exists(b"b"), Ok(false));
+
+    let uploader = Uploader::new(backend.clone(), 2);
+    let (sender, receiver) = mpsc::channel();
+    for name in vec![b"a".to_vec(), b"b".to_vec()] {
+        let sender = sender.clone();
+        uploader
+            .upload(
+                name.clone(),
+                crypto::CipherText::new(b"new".to_vec()),
+                StorageClass::Standard,
+                Box::new(move |()| sender.send(name).unwrap()),
+            )
+            .unwrap();
+    }
+    uploader.wait().unwrap();
+
+    // Both are durable, but only the missing one was sent.
+    let mut done: Vec<_> = receiver.try_iter().collect();
+    done.sort();
+    assert_eq!(done, vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(backend.retrieve(b"a").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(backend.retrieve(b"b").unwrap(), Some(b"new".to_vec()));
+}
+
+#[test]
+fn upload_fails_when_existence_is_unknown() {
+    let backend = Arc::new(UnsureBackend(MemoryBackend::new()));
+    let uploader = Uploader::new(backend.clone(), 1);
+    uploader
+        .upload(
+            b"a".to_vec(),
+            crypto::CipherText::new(b"new".to_vec()),
+            StorageClass::Standard,
+            Box::new(|()| panic!("not stored")),
+        )
+        .unwrap();
+
+    // The error is reported rather than taken to mean the blob is missing.
+    assert_eq!(uploader.wait(), Err("connection reset".to_owned()));
+    assert_eq!(backend.list().unwrap().len(), 0);
+}
+
+#[test]
+fn chunks_commit_after_batch_sync() {
+    let root = env::temp_dir().join(format!("hat-blob-sync-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let backend = Arc::new(FileBackend::new(root.clone()).with_sync_batch(SyncBatch {
+        max_blobs: 1000,
+        max_delay: Duration::from_secs(3600),
+    }));
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..10 {
+        let chunk = [i as u8; 300];
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let sender = sender.clone();
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, &chunk[..]),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| sender.send(i).unwrap()),
+        ).unwrap();
+    }
+
+    // Full blobs reach the disk, but nothing may use their chunks before they are synced.
+    for _ in 0..500 {
+        if backend.list().unwrap().len() >= 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(backend.list().unwrap().len() >= 2);
+    assert!(receiver.try_recv().is_err());
+
+    bs_p.flush().unwrap();
+    assert_eq!(receiver.try_iter().count(), 10);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn checksum_detects_changed_blob() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let href = store_chunk(&bs_p, &keys, &[1; 300]).unwrap();
+    bs_p.flush().unwrap();
+    let blob_id = href.persistent_ref.blob_id.unwrap();
+    let name = &href.persistent_ref.blob_name[..];
+    assert_eq!(bs_p.verify_checksum(blob_id).unwrap(), Some(true));
+    assert_eq!(bs_p.verify_checksum(blob_id + 100).unwrap(), None);
+
+    // Flip a single bit of the blob as the backend has it.
+    let mut stored = backend.retrieve(name).unwrap().unwrap();
+    stored[10] ^= 1;
+    backend.delete(name).unwrap();
+    backend.store(name, &crypto::CipherText::new(stored)).unwrap();
+    assert_eq!(bs_p.verify_checksum(blob_id).unwrap(), Some(false));
+}
+
+#[test]
+fn chunk_tags_find_damaged_chunks_without_keys() {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index.clone(), backend.clone(), 4096);
+
+    let first = store_chunk(&bs_p, &keys, &[1; 300]).unwrap();
+    let second = store_chunk(&bs_p, &keys, &[2; 300]).unwrap();
+    bs_p.flush().unwrap();
+    let blob_id = first.persistent_ref.blob_id.unwrap();
+    assert_eq!(second.persistent_ref.blob_id, Some(blob_id));
+    let name = &first.persistent_ref.blob_name[..];
+    assert_eq!(bs_p.verify_chunk_tags(blob_id).unwrap(), Some(vec![]));
+    assert_eq!(bs_p.verify_chunk_tags(blob_id + 100).unwrap(), None);
+
+    let tags = blob_index.chunk_tags(&blob_index.find_by_id(blob_id).unwrap());
+    assert_eq!(tags.len(), 2);
+    assert_eq!(ChunkTag::decode_all(&ChunkTag::encode_all(&tags[..])[..]), Some(tags.clone()));
+    assert_eq!(tags[1].offset, second.persistent_ref.offset as u64);
+    assert_eq!(tags[1].length, second.persistent_ref.length as u64);
+
+    // Flip a single bit inside the second chunk, as the backend has it.
+    let mut stored = backend.retrieve(name).unwrap().unwrap();
+    stored[second.persistent_ref.offset + 10] ^= 1;
+    backend.delete(name).unwrap();
+    backend.store(name, &crypto::CipherText::new(stored.clone())).unwrap();
+    assert_eq!(bs_p.verify_chunk_tags(blob_id).unwrap(), Some(vec![tags[1].clone()]));
+
+    // The blob and the tags are all it takes to find the damaged chunk.
+    let damaged: Vec<&ChunkTag> = tags.iter().filter(|tag| !tag.verify(&stored[..])).collect();
+    assert_eq!(damaged, vec![&tags[1]]);
+    assert!(tags[0].verify(&stored[..]));
+}
+
+#[test]
+fn key_length_is_checked() {
+    let len = crypto::authed::desc::KEYBYTES;
+    for algorithm in &["chacha20poly1305", "chacha20poly1305-committed"] {
+        let key = Key::from_bytes(algorithm, &vec![7; len][..]).unwrap();
+        assert_eq!(key.algorithm(), *algorithm);
+        assert_eq!(key.raw_len(), len);
+
+        for wrong in &[0, 1, len - 1, len + 1, 2 * len] {
+            let err = Key::from_bytes(algorithm, &vec![7; *wrong][..]).err().unwrap();
+            assert!(err.to_string().contains("Wrong key length"));
+        }
+    }
+    assert!(Key::from_bytes("rot13", &vec![7; len][..]).is_err());
+}
+
+#[test]
+fn key_serialization_round_trips() {
+    let len = crypto::authed::desc::KEYBYTES;
+    for algorithm in &["chacha20poly1305", "chacha20poly1305-committed"] {
+        let raw: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let key = Key::from_bytes(algorithm, &raw[..]).unwrap();
+        let bytes = key.serialize();
+        assert_eq!(bytes.len(), 2 + len);
+        assert_eq!(&bytes[2..], &raw[..]);
+
+        let back = Key::deserialize(&bytes[..]).unwrap();
+        assert_eq!(back.algorithm(), *algorithm);
+        assert_eq!(back.fingerprint(), key.fingerprint());
+    }
+}
+
+#[test]
+fn corrupt_serialized_key_is_refused() {
+    let len = crypto::authed::desc::KEYBYTES;
+    let key = Key::from_bytes("chacha20poly1305-committed", &vec![7; len][..]).unwrap();
+    let bytes = key.serialize();
+
+    let mut unknown_version = bytes.clone();
+    unknown_version[0] = 99;
+    let mut unknown_algorithm = bytes.clone();
+    unknown_algorithm[1] = 99;
+    let truncated = bytes[..bytes.len() - 1].to_vec();
+    let mut extended = bytes.clone();
+    extended.push(0);
+
+    let corrupt = vec![
+        vec![],
+        bytes[..1].to_vec(),
+        unknown_version,
+        unknown_algorithm,
+        truncated,
+        extended,
+    ];
+    for bad in corrupt {
+        assert!(Key::deserialize(&bad[..]).is_err());
+    }
+    assert!(Key::deserialize(&bytes[..]).is_ok());
+}
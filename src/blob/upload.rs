@@ -0,0 +1,129 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Pushes finished blobs to the backend in the background.
+//!
+//! At most a fixed number of blobs are uploaded at a time. Once that many are in flight, handing
+//! over another one blocks, which in turn holds up the sealing of new chunks; memory use stays
+//! bounded even when the backend is slower than the rest of the pipeline.
+
+use backend::{StorageClass, StoreBackend};
+use crypto::CipherText;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use util::FnBox;
+
+
+/// Number of blobs uploaded at the same time unless configured otherwise.
+pub const DEFAULT_MAX_UPLOADS: usize = 4;
+
+
+struct State {
+    in_flight: usize,
+    max_in_flight: usize,
+    // The first upload error. Once set, no more uploads are started.
+    error: Option<String>,
+}
+
+pub struct Uploader<B> {
+    backend: Arc<B>,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl<B: StoreBackend> Uploader<B> {
+    pub fn new(backend: Arc<B>, max_in_flight: usize) -> Uploader<B> {
+        assert!(max_in_flight > 0);
+        Uploader {
+            backend: backend,
+            state: Arc::new((
+                Mutex::new(State {
+                    in_flight: 0,
+                    max_in_flight: max_in_flight,
+                    error: None,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    pub fn set_max_in_flight(&self, max_in_flight: usize) {
+        assert!(max_in_flight > 0);
+        let &(ref lock, ref cvar) = &*self.state;
+        lock.lock().unwrap().max_in_flight = max_in_flight;
+        cvar.notify_all();
+    }
+
+    /// Queue a blob for upload to `class`, waiting for a free slot first. `done` is called once
+    /// the backend has made the blob durable, and not at all if storing it fails.
+    ///
+    /// A blob the backend has already, e.g. from an interrupted backup, is not sent again.
+    pub fn upload(
+        &self,
+        name: Vec<u8>,
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        {
+            let &(ref lock, ref cvar) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            while state.error.is_none() && state.in_flight >= state.max_in_flight {
+                state = cvar.wait(state).unwrap();
+            }
+            if let Some(ref e) = state.error {
+                return Err(e.clone());
+            }
+            state.in_flight += 1;
+        }
+
+        let backend = self.backend.clone();
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let res = backend
+                .exists(&name[..])
+                .and_then(|exists| if exists {
+                    Ok(())
+                } else {
+                    backend.store_in_class(&name[..], &data, class)
+                })
+                .and_then(|()| backend.when_durable(&name[..], done));
+
+            let &(ref lock, ref cvar) = &*state;
+            let mut state = lock.lock().unwrap();
+            state.in_flight -= 1;
+            if let Err(e) = res {
+                if state.error.is_none() {
+                    state.error = Some(e);
+                }
+            }
+            cvar.notify_all();
+        });
+
+        Ok(())
+    }
+
+    /// Wait for all queued uploads to finish, and report the first one that failed.
+    pub fn wait(&self) -> Result<(), String> {
+        let &(ref lock, ref cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.in_flight > 0 {
+            state = cvar.wait(state).unwrap();
+        }
+        match state.error {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+}
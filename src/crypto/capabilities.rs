@@ -0,0 +1,177 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Which crypto primitives work on this host.
+//!
+//! Every primitive is probed by using it, so that a library built without one, or a CPU that
+//! lacks the instructions it needs, shows up here at startup instead of in the middle of a
+//! backup.
+
+use crypto::{CryptoError, authed, init, keys, open_into, seal_into};
+use std::os::raw::c_int;
+
+// Not exported by the libsodium-sys version we build against.
+extern "C" {
+    fn crypto_aead_aes256gcm_is_available() -> c_int;
+}
+
+
+/// The algorithm new chunks are sealed with, as named by `blob::Key::algorithm`.
+pub const SEAL_ALGORITHM: &'static str = "chacha20poly1305-committed";
+
+/// Chunk key algorithms in order of preference, with the primitives each of them needs.
+const ALGORITHMS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("chacha20poly1305-committed", &["chacha20poly1305", "blake2b"]),
+    ("chacha20poly1305", &["chacha20poly1305"]),
+];
+
+/// Every primitive that is probed. AES-256-GCM is not used, but needs hardware support, so it is
+/// reported for hosts that would rather use it.
+const PRIMITIVES: &'static [&'static str] = &["chacha20poly1305", "blake2b", "aes256gcm"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Primitive {
+    pub name: &'static str,
+    pub available: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub primitives: Vec<Primitive>,
+}
+
+impl Capabilities {
+    /// Probe each primitive with `available`.
+    fn probe<F: Fn(&str) -> bool>(available: F) -> Capabilities {
+        Capabilities {
+            primitives: PRIMITIVES
+                .iter()
+                .map(|&name| {
+                    Primitive {
+                        name: name,
+                        available: available(name),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Names of the primitives that are available.
+    pub fn available(&self) -> Vec<&'static str> {
+        self.primitives.iter().filter(|p| p.available).map(|p| p.name).collect()
+    }
+
+    fn has(&self, primitive: &str) -> bool {
+        self.primitives.iter().any(|p| p.name == primitive && p.available)
+    }
+
+    /// The most preferred algorithm that can be used here.
+    pub fn default_algorithm(&self) -> Option<&'static str> {
+        ALGORITHMS
+            .iter()
+            .find(|&&(_, needs)| needs.iter().all(|p| self.has(p)))
+            .map(|&(name, _)| name)
+    }
+
+    /// Fail unless `algorithm` can be used here, naming what it is missing. Gives back the
+    /// algorithm's name as kept by chunk keys.
+    pub fn require(&self, algorithm: &str) -> Result<&'static str, CryptoError> {
+        let (name, needs) = match ALGORITHMS.iter().find(|&&(name, _)| name == algorithm) {
+            Some(&(name, needs)) => (name, needs),
+            None => return Err(format!("Unknown key algorithm: {}", algorithm).into()),
+        };
+        let missing: Vec<&str> = needs.iter().cloned().filter(|p| !self.has(p)).collect();
+        if missing.is_empty() {
+            Ok(name)
+        } else {
+            Err(
+                format!(
+                    "crypto algorithm {} is not available on this host: no {}",
+                    algorithm,
+                    missing.join(", ")
+                ).into(),
+            )
+        }
+    }
+}
+
+/// Probe the primitives of the crypto library.
+pub fn capabilities() -> Capabilities {
+    if init().is_err() {
+        return Capabilities::probe(|_| false);
+    }
+    Capabilities::probe(probe_primitive)
+}
+
+fn probe_primitive(name: &str) -> bool {
+    match name {
+        "chacha20poly1305" => {
+            let key = authed::imp::gen_key();
+            let nonce = authed::imp::gen_nonce();
+            let msg: &[u8] = b"hat-backup probe";
+            let mut ct = vec![];
+            let mut pt = vec![];
+            seal_into(&mut ct, msg, &[], &nonce, &key);
+            open_into(&mut pt, &ct[..], &[], &nonce, &key).is_ok() && pt == msg
+        }
+        "blake2b" => {
+            let mut first = [0u8; 32];
+            let mut second = [0u8; 32];
+            keys::keyed_fingerprint(b"probe-key", b"probe", b"probe~~~probe~~~", &mut first);
+            keys::keyed_fingerprint(b"probe-key", b"probe", b"probe~~~probe~~~", &mut second);
+            first == second && first.iter().any(|&b| b != first[0])
+        }
+        "aes256gcm" => unsafe { crypto_aead_aes256gcm_is_available() == 1 },
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_reflects_primitives() {
+        let all = Capabilities::probe(|_| true);
+        assert_eq!(all.available(), PRIMITIVES.to_vec());
+        assert_eq!(all.default_algorithm(), Some(SEAL_ALGORITHM));
+        all.require(SEAL_ALGORITHM).unwrap();
+
+        // Without the commitment hash, only plain chunk keys remain.
+        let no_blake2b = Capabilities::probe(|p| p != "blake2b");
+        assert!(!no_blake2b.available().contains(&"blake2b"));
+        assert_eq!(no_blake2b.default_algorithm(), Some("chacha20poly1305"));
+
+        let none = Capabilities::probe(|_| false);
+        assert!(none.available().is_empty());
+        assert_eq!(none.default_algorithm(), None);
+    }
+
+    #[test]
+    fn unavailable_algorithm_is_refused() {
+        let no_blake2b = Capabilities::probe(|p| p != "blake2b");
+        let err = no_blake2b.require(SEAL_ALGORITHM).unwrap_err();
+        assert!(err.to_string().contains("no blake2b"));
+        no_blake2b.require("chacha20poly1305").unwrap();
+        assert!(no_blake2b.require("rot13").is_err());
+    }
+
+    #[test]
+    fn this_host_can_seal() {
+        let caps = capabilities();
+        assert!(caps.available().contains(&"chacha20poly1305"));
+        caps.require(SEAL_ALGORITHM).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cheap checksums of sealed blobs.
+//!
+//! A `Checksum` is not a MAC: it only detects accidental damage to a blob on its way to or
+//! inside the backend, and can be checked without any keys. Authenticity of the contents is
+//! still established by the per-chunk MACs when the blob is read.
+//!
+//! An `IntegrityTag` does the same for a single sealed chunk, so that a scrub can tell which
+//! chunks of a damaged blob are still intact without the keys to open them.
+
+use crypto::keys;
+
+/// Reflected CRC-32C (Castagnoli) polynomial.
+const CRC32C_POLY: u32 = 0x82f63b78;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub crc32c: u32,
+    pub length: u64,
+}
+
+impl Checksum {
+    /// Checksum of the concatenation of `slices`.
+    pub fn of_slices(slices: &[&[u8]]) -> Checksum {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CRC32C_POLY
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+
+        let mut crc = !0u32;
+        let mut length = 0u64;
+        for slice in slices {
+            for byte in slice.iter() {
+                crc = table[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+            }
+            length += slice.len() as u64;
+        }
+        Checksum {
+            crc32c: !crc,
+            length: length,
+        }
+    }
+}
+
+/// Length of an `IntegrityTag`, in bytes.
+pub const INTEGRITY_TAG_BYTES: usize = 16;
+
+/// Truncated unkeyed BLAKE2b of a sealed chunk, as it is stored. Anyone with the chunk can
+/// compute it, so it only detects damage; the chunk's MAC still decides whether it is authentic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityTag(pub Vec<u8>);
+
+impl IntegrityTag {
+    pub fn of(sealed: &[u8]) -> IntegrityTag {
+        let mut tag = vec![0u8; INTEGRITY_TAG_BYTES];
+        keys::digest(sealed, &mut tag[..]);
+        IntegrityTag(tag)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_value() {
+        let sum = Checksum::of_slices(&[b"123456789"]);
+        assert_eq!(sum.crc32c, 0xe3069283);
+        assert_eq!(sum.length, 9);
+        assert_eq!(Checksum::of_slices(&[]), Checksum { crc32c: 0, length: 0 });
+    }
+
+    #[test]
+    fn independent_of_slicing() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let whole = Checksum::of_slices(&[&data[..]]);
+        assert_eq!(whole, Checksum::of_slices(&[&data[..1], &data[1..600], &[], &data[600..]]));
+        assert_eq!(whole, Checksum::of_slices(&[&data[..]]));
+    }
+
+    #[test]
+    fn changes_with_any_byte() {
+        let data: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let original = Checksum::of_slices(&[&data[..]]);
+        for i in 0..data.len() {
+            let mut changed = data.clone();
+            changed[i] ^= 1 << (i % 8);
+            assert!(Checksum::of_slices(&[&changed[..]]) != original);
+        }
+        assert!(Checksum::of_slices(&[&data[..299]]) != original);
+    }
+
+    #[test]
+    fn integrity_tag_changes_with_any_byte() {
+        let data: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let original = IntegrityTag::of(&data[..]);
+        assert_eq!(original.0.len(), INTEGRITY_TAG_BYTES);
+        assert_eq!(original, IntegrityTag::of(&data[..]));
+        for i in 0..data.len() {
+            let mut changed = data.clone();
+            changed[i] ^= 1 << (i % 8);
+            assert!(IntegrityTag::of(&changed[..]) != original);
+        }
+    }
+}
@@ -13,11 +13,20 @@
 // limitations under the License.
 
 use blob;
+use errors::CryptoError;
+use hex::ToHex;
 use libsodium_sys;
 use secstr;
 use argon2rs;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+#[derive(Clone)]
 struct PublicKey(secstr::SecStr);
+#[derive(Clone)]
 struct SecretKey(secstr::SecStr);
 
 pub fn compute_salt(node_type: blob::NodeType, leaf_type: blob::LeafType) -> Box<[u8]> {
@@ -36,7 +45,16 @@ pub fn compute_salt(node_type: blob::NodeType, leaf_type: blob::LeafType) -> Box
     Box::new(salt)
 }
 
+/// Bytes in a store's secret hashing salt.
+pub const HASH_SALT_BYTES: usize = 32;
+
+/// A fresh secret salt for the content hashes of a new store.
+pub fn new_hash_salt() -> Vec<u8> {
+    random_bytes(HASH_SALT_BYTES).unsecure().to_vec()
+}
+
 pub fn random_bytes(size: usize) -> secstr::SecStr {
+    super::ensure_init();
     let mut r = vec![0u8; size];
     unsafe { libsodium_sys::randombytes_buf(r.as_mut_ptr(), r.len()) };
     secstr::SecStr::new(r)
@@ -66,10 +84,50 @@ pub fn keyed_fingerprint(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
     assert_eq!(ret, 0);
 }
 
+/// Unkeyed digest of `msg`, for hashes that anyone must be able to recompute.
+pub fn digest(msg: &[u8], out: &mut [u8]) {
+    let ret = unsafe {
+        libsodium_sys::crypto_generichash_blake2b(
+            out.as_mut_ptr(),
+            out.len(),
+            msg.as_ptr(),
+            msg.len() as u64,
+            ::std::ptr::null(),
+            0,
+        )
+    };
+    assert_eq!(ret, 0);
+}
+
+/// Length of a signature made with `Keeper::manifest_sign`.
+pub const MANIFEST_SIGNATURE_BYTES: usize = libsodium_sys::crypto_sign_ed25519_BYTES;
+
+/// Check a signature made with `Keeper::manifest_sign`, given only the public manifest key.
+pub fn manifest_verify(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    if public_key.len() != libsodium_sys::crypto_sign_ed25519_PUBLICKEYBYTES ||
+        signature.len() != libsodium_sys::crypto_sign_ed25519_BYTES
+    {
+        return false;
+    }
+    super::ensure_init();
+    let ret = unsafe {
+        libsodium_sys::crypto_sign_ed25519_verify_detached(
+            signature.as_ptr() as *const [u8; 64],
+            msg.as_ptr(),
+            msg.len() as u64,
+            public_key.as_ptr() as *const [u8; 32],
+        )
+    };
+    ret == 0
+}
+
+#[derive(Clone)]
 pub struct Keeper {
     universal_key: secstr::SecStr,
     fingerprint_key: Option<secstr::SecStr>,
     blob_authentication_key: Option<secstr::SecStr>,
+    filename_key: Option<secstr::SecStr>,
+    filename_tag_key: Option<secstr::SecStr>,
 
     data_key_pk: Option<PublicKey>,
     data_key_sk: Option<SecretKey>,
@@ -79,21 +137,29 @@ pub struct Keeper {
 
     access_key_pk: Option<PublicKey>,
     access_key_sk: Option<SecretKey>,
+
+    manifest_key_pk: Option<PublicKey>,
+    manifest_key_sk: Option<SecretKey>,
 }
 
 impl Keeper {
     pub fn new(universal: &str) -> Keeper {
+        super::ensure_init();
         let app: &str = "hat-backup:universal-key";
         let mut keeper = Keeper {
             universal_key: Keeper::strengthen(universal, app),
             fingerprint_key: None,
             blob_authentication_key: None,
+            filename_key: None,
+            filename_tag_key: None,
             data_key_pk: None,
             data_key_sk: None,
             access_key_pk: None,
             access_key_sk: None,
             naming_key_pk: None,
             naming_key_sk: None,
+            manifest_key_pk: None,
+            manifest_key_sk: None,
         };
         keeper.init();
         keeper
@@ -101,16 +167,27 @@ impl Keeper {
 
     #[cfg(test)]
     pub fn new_for_testing() -> Keeper {
+        Keeper::new_for_testing_with_key(vec![0; 32])
+    }
+
+    /// Like `new_for_testing`, but with the given universal key, to stand in for another store.
+    #[cfg(test)]
+    pub fn new_for_testing_with_key(universal_key: Vec<u8>) -> Keeper {
+        super::ensure_init();
         let mut keeper = Keeper {
-            universal_key: secstr::SecStr::new(vec![0; 32]),
+            universal_key: secstr::SecStr::new(universal_key),
             fingerprint_key: None,
             blob_authentication_key: None,
+            filename_key: None,
+            filename_tag_key: None,
             data_key_pk: None,
             data_key_sk: None,
             access_key_pk: None,
             access_key_sk: None,
             naming_key_pk: None,
             naming_key_sk: None,
+            manifest_key_pk: None,
+            manifest_key_sk: None,
         };
         keeper.init();
         keeper
@@ -126,6 +203,10 @@ impl Keeper {
             64,
         ));
 
+        // Generate keys for encrypting filenames in the local index.
+        self.filename_key = Some(self.from_nonce("hat:FILENAME-key".as_bytes(), 32));
+        self.filename_tag_key = Some(self.from_nonce("hat:FILENAME-TAG-key".as_bytes(), 64));
+
         // Generate data key.
         // Required for reading blob data without a direct reference.
         let (pk, sk) = self.x25519_key_pair_from_nonce("hat:DATA-key-x25519".as_bytes());
@@ -143,6 +224,28 @@ impl Keeper {
         let (pk, sk) = self.x25519_key_pair_from_nonce("hat:NAMING-key-x25519".as_bytes());
         self.naming_key_pk = Some(pk);
         self.naming_key_sk = Some(sk);
+
+        // Generate manifest key.
+        // Required for signing statements about the store that others can check.
+        let (pk, sk) = self.ed25519_key_pair_from_nonce("hat:MANIFEST-key-ed25519".as_bytes());
+        self.manifest_key_pk = Some(pk);
+        self.manifest_key_sk = Some(sk);
+    }
+
+    /// These keys, with content hashes keyed by the store's secret `salt` as well. Stores with
+    /// different salts get different hashes for the same contents, even under the same
+    /// passphrase, so they can not be compared; nor can chunks be shared between them. Hashes
+    /// also pick the nonces of sealed chunks, so a store must keep one salt, or none, for all
+    /// of its data.
+    pub fn with_hash_salt(&self, salt: &[u8]) -> Keeper {
+        let mut key = secstr::SecStr::new(vec![0; 64]);
+        let unsalted = self.fingerprint_key.as_ref().expect("need fingerprint key");
+        let nonce: &[u8; 16] = b"hashsalt~~~~~~~~";
+        keyed_fingerprint(unsalted.unsecure(), salt, nonce, key.unsecure_mut());
+
+        let mut keeper = self.clone();
+        keeper.fingerprint_key = Some(key);
+        keeper
     }
 
     fn strengthen(phrase: &str, salt: &str) -> secstr::SecStr {
@@ -188,6 +291,24 @@ impl Keeper {
         (PublicKey(pk), SecretKey(sk))
     }
 
+    fn ed25519_key_pair_from_nonce(&self, nonce: &[u8]) -> (PublicKey, SecretKey) {
+        let mut pk = secstr::SecStr::new(vec![0; 32]);
+        let mut sk = secstr::SecStr::new(vec![0; 64]);
+
+        let seed = self.from_nonce(nonce, 32);
+
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_seed_keypair(
+                pk.unsecure_mut().as_mut_ptr() as *mut [u8; 32],
+                sk.unsecure_mut().as_mut_ptr() as *mut [u8; 64],
+                seed.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        (PublicKey(pk), SecretKey(sk))
+    }
+
     fn asymmetric_lock(pk: &PublicKey, msg: &[u8]) -> Vec<u8> {
         let mut out = vec![0; msg.len() + libsodium_sys::crypto_box_SEALBYTES];
         let ret = unsafe {
@@ -204,6 +325,17 @@ impl Keeper {
     }
 
     fn asymmetric_unlock(pk: &PublicKey, sk: &SecretKey, ciphertext: &[u8]) -> Vec<u8> {
+        Keeper::asymmetric_try_unlock(pk, sk, ciphertext).expect("asymmetric unlock failed")
+    }
+
+    fn asymmetric_try_unlock(
+        pk: &PublicKey,
+        sk: &SecretKey,
+        ciphertext: &[u8],
+    ) -> Option<Vec<u8>> {
+        if ciphertext.len() < libsodium_sys::crypto_box_SEALBYTES {
+            return None;
+        }
         let mut out = vec![0; ciphertext.len() - libsodium_sys::crypto_box_SEALBYTES];
         let ret = unsafe {
             libsodium_sys::crypto_box_seal_open(
@@ -214,9 +346,7 @@ impl Keeper {
                 sk.0.unsecure().as_ptr() as *const [u8; 32],
             )
         };
-        assert_eq!(0, ret);
-
-        out
+        if ret == 0 { Some(out) } else { None }
     }
 
     pub fn data_lock(&self, msg: &[u8]) -> Vec<u8> {
@@ -234,6 +364,15 @@ impl Keeper {
         )
     }
 
+    /// Like `data_unlock`, but fails instead of panicking on a corrupt ciphertext.
+    pub fn try_data_unlock(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        Keeper::asymmetric_try_unlock(
+            self.data_key_pk.as_ref().expect("need data public key"),
+            self.data_key_sk.as_ref().expect("need data private key"),
+            ciphertext,
+        )
+    }
+
     pub fn access_lock(&self, msg: &[u8]) -> Vec<u8> {
         Keeper::asymmetric_lock(
             self.access_key_pk.as_ref().expect(
@@ -253,6 +392,17 @@ impl Keeper {
         )
     }
 
+    /// Like `access_unlock`, but fails instead of panicking on a corrupt ciphertext.
+    pub fn try_access_unlock(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        Keeper::asymmetric_try_unlock(
+            self.access_key_pk.as_ref().expect("need access public key"),
+            self.access_key_sk.as_ref().expect(
+                "need access private key",
+            ),
+            ciphertext,
+        )
+    }
+
     pub fn naming_lock(&self, msg: &[u8]) -> Vec<u8> {
         Keeper::asymmetric_lock(
             self.naming_key_pk.as_ref().expect("need naming public key"),
@@ -260,6 +410,49 @@ impl Keeper {
         )
     }
 
+    /// Like `naming_lock`, but the same `msg` always seals to the same bytes: the ephemeral key
+    /// pair of the sealed box is derived from `msg` with the fingerprint key. The result opens
+    /// with `naming_unlock` like any other sealed box.
+    pub fn naming_lock_derived(&self, msg: &[u8]) -> Vec<u8> {
+        let pk = self.naming_key_pk.as_ref().expect("need naming public key");
+
+        let mut seed = secstr::SecStr::new(vec![0; libsodium_sys::crypto_box_SEEDBYTES]);
+        self.fingerprint(msg, b"hat-naming-seed~", seed.unsecure_mut());
+        let mut epk = vec![0; libsodium_sys::crypto_box_PUBLICKEYBYTES];
+        let mut esk = secstr::SecStr::new(vec![0; libsodium_sys::crypto_box_SECRETKEYBYTES]);
+        let ret = unsafe {
+            libsodium_sys::crypto_box_seed_keypair(
+                epk.as_mut_ptr() as *mut [u8; 32],
+                esk.unsecure_mut().as_mut_ptr() as *mut [u8; 32],
+                seed.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        // The nonce of a sealed box is the hash of both public keys.
+        let mut nonce = [0u8; libsodium_sys::crypto_box_NONCEBYTES];
+        let mut both = epk.clone();
+        both.extend_from_slice(pk.0.unsecure());
+        digest(&both[..], &mut nonce[..]);
+
+        let mut sealed = vec![0; msg.len() + libsodium_sys::crypto_box_MACBYTES];
+        let ret = unsafe {
+            libsodium_sys::crypto_box_easy(
+                sealed.as_mut_ptr(),
+                msg.as_ptr(),
+                msg.len() as u64,
+                &nonce,
+                pk.0.unsecure().as_ptr() as *const [u8; 32],
+                esk.unsecure().as_ptr() as *const [u8; 32],
+            )
+        };
+        assert_eq!(ret, 0);
+
+        let mut out = epk;
+        out.extend_from_slice(&sealed[..]);
+        out
+    }
+
     pub fn naming_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
         Keeper::asymmetric_unlock(
             self.naming_key_pk.as_ref().expect("need naming public key"),
@@ -270,6 +463,45 @@ impl Keeper {
         )
     }
 
+    /// Short identifier of the universal key. It tells keys apart, but reveals nothing about
+    /// them, so it can be stored next to the data they seal.
+    pub fn key_id(&self) -> String {
+        self.key_digest()[..8].to_vec().to_hex()
+    }
+
+    /// The whole of what `key_id` is a prefix of, to tell keys with the same id apart.
+    fn key_digest(&self) -> Vec<u8> {
+        self.from_nonce("hat:KEY-ID".as_bytes(), 32).unsecure().to_vec()
+    }
+
+    pub fn manifest_public_key(&self) -> Vec<u8> {
+        let pk = self.manifest_key_pk.as_ref().expect(
+            "need manifest public key",
+        );
+        pk.0.unsecure().to_vec()
+    }
+
+    pub fn manifest_sign(&self, msg: &[u8]) -> Vec<u8> {
+        let sk = self.manifest_key_sk.as_ref().expect(
+            "need manifest private key",
+        );
+        let mut signature = vec![0; libsodium_sys::crypto_sign_ed25519_BYTES];
+        let mut signature_len = 0;
+        let ret = unsafe {
+            libsodium_sys::crypto_sign_ed25519_detached(
+                signature.as_mut_ptr() as *mut [u8; 64],
+                &mut signature_len,
+                msg.as_ptr(),
+                msg.len() as u64,
+                sk.0.unsecure().as_ptr() as *const [u8; 64],
+            )
+        };
+        assert_eq!(0, ret);
+        assert_eq!(signature_len, signature.len() as u64);
+
+        signature
+    }
+
     pub fn fingerprint(&self, msg: &[u8], salt: &[u8], out: &mut [u8]) {
         let key = self.fingerprint_key.as_ref().expect("need fingerprint key");
         keyed_fingerprint(key.unsecure(), msg, salt, out);
@@ -283,8 +515,60 @@ impl Keeper {
         keyed_fingerprint(key.unsecure(), blob, salt, &mut out[..])
     }
 
-    pub fn symmetric_lock(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8> {
-        let mut out = vec![0u8; msg.len() + libsodium_sys::crypto_aead_chacha20poly1305_ABYTES];
+    /// Deterministic tag of a filename, to look the name up by without storing it in the clear.
+    /// Equal names get equal tags, so the tags do reveal which entries share a name.
+    pub fn filename_tag(&self, name: &[u8]) -> Vec<u8> {
+        let key = self.filename_tag_key.as_ref().expect("need filename tag key");
+        let salt: &[u8; 16] = b"name~~~~name~~~~";
+        let mut out = vec![0; 32];
+        keyed_fingerprint(key.unsecure(), name, salt, &mut out[..]);
+        out
+    }
+
+    /// Encrypt a filename, with the nonce kept in front of the ciphertext.
+    /// The cipher only has 8-byte nonces, too short to pick at random for every entry of every
+    /// snapshot. The nonce is derived from the name instead, as in SIV: a nonce is only ever
+    /// used again for the same name, which then gives the same ciphertext, revealing no more
+    /// than its tag already does.
+    /// The ciphertext is bound to `tag`, so it cannot be moved to another entry unnoticed.
+    pub fn filename_lock(&self, name: &[u8], tag: &[u8]) -> Vec<u8> {
+        let key = self.filename_key.as_ref().expect("need filename key");
+        let nonce_key = self.filename_tag_key.as_ref().expect("need filename tag key");
+        let salt: &[u8; 16] = b"nonce~~~nonce~~~";
+        let mut nonce = vec![0; 8];
+        keyed_fingerprint(nonce_key.unsecure(), name, salt, &mut nonce[..]);
+
+        let mut sealed = vec![];
+        Keeper::symmetric_lock_into(&mut sealed, name, tag, &nonce[..], key.unsecure());
+
+        let mut out = nonce;
+        out.extend_from_slice(&sealed[..]);
+        out
+    }
+
+    pub fn filename_unlock(&self, ciphertext: &[u8], tag: &[u8]) -> Option<Vec<u8>> {
+        let key = self.filename_key.as_ref().expect("need filename key");
+        if ciphertext.len() < 8 {
+            return None;
+        }
+        let (nonce, sealed) = ciphertext.split_at(8);
+        let mut out = vec![];
+        if Keeper::symmetric_unlock_into(&mut out, key.unsecure(), sealed, tag, nonce) {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    pub fn symmetric_lock_into(
+        out: &mut Vec<u8>,
+        msg: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+        key: &[u8],
+    ) {
+        out.clear();
+        out.resize(msg.len() + libsodium_sys::crypto_aead_chacha20poly1305_ABYTES, 0u8);
         let mut out_len = 0;
 
         let ret = unsafe {
@@ -302,13 +586,20 @@ impl Keeper {
         };
         assert_eq!(0, ret);
         assert_eq!(out_len, out.len() as u64);
-
-        out
     }
 
-    pub fn symmetric_unlock(key: &[u8], ciphertext: &[u8], ad: &[u8], nonce: &[u8]) -> Vec<u8> {
-        let mut out =
-            vec![0u8; ciphertext.len() - libsodium_sys::crypto_aead_chacha20poly1305_ABYTES];
+    pub fn symmetric_unlock_into(
+        out: &mut Vec<u8>,
+        key: &[u8],
+        ciphertext: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+    ) -> bool {
+        out.clear();
+        if ciphertext.len() < libsodium_sys::crypto_aead_chacha20poly1305_ABYTES {
+            return false;
+        }
+        out.resize(ciphertext.len() - libsodium_sys::crypto_aead_chacha20poly1305_ABYTES, 0u8);
         let mut out_len = 0;
 
         let ret = unsafe {
@@ -324,9 +615,120 @@ impl Keeper {
                 key.as_ptr() as *const [u8; 32],
             )
         };
-        assert_eq!(0, ret);
+        if ret != 0 {
+            out.clear();
+            return false;
+        }
         assert_eq!(out_len, out.len() as u64);
 
-        out
+        true
+    }
+}
+
+/// Several keys by their key id, such as those of a store from before and after it moved to a
+/// new key, so that each snapshot can be read with the key it was sealed with.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: BTreeMap<String, Arc<Keeper>>,
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring::default()
+    }
+
+    /// A keyring with the key derived from the passphrase in each of `paths`. Files with the
+    /// same passphrase give one key.
+    pub fn from_keyfiles(paths: &[PathBuf]) -> Result<Keyring, CryptoError> {
+        let mut keyring = Keyring::new();
+        for path in paths {
+            let mut phrase = String::new();
+            fs::File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut phrase))
+                .map_err(|e| format!("Could not read key file {}: {}", path.display(), e))?;
+            keyring.insert(Arc::new(Keeper::new(phrase.trim()))).map_err(|e| {
+                format!("Key file {}: {}", path.display(), e)
+            })?;
+        }
+        Ok(keyring)
+    }
+
+    /// Add `keys`, unless they are in the ring already. Returns whether they were added. Another
+    /// key with the same id could not be told apart from them, and is refused.
+    pub fn insert(&mut self, keys: Arc<Keeper>) -> Result<bool, CryptoError> {
+        let key_id = keys.key_id();
+        self.insert_as(key_id, keys)
+    }
+
+    fn insert_as(&mut self, key_id: String, keys: Arc<Keeper>) -> Result<bool, CryptoError> {
+        if let Some(known) = self.keys.get(&key_id) {
+            if known.key_digest() == keys.key_digest() {
+                return Ok(false);
+            }
+            return Err(From::from(format!("Two different keys have key id {}", key_id)));
+        }
+        self.keys.insert(key_id, keys);
+        Ok(true)
+    }
+
+    /// The key with id `key_id`, if it is in the ring.
+    pub fn lookup(&self, key_id: &str) -> Option<&Arc<Keeper>> {
+        self.keys.get(key_id)
+    }
+
+    /// Ids of the keys in the ring, in order.
+    pub fn key_ids(&self) -> Vec<String> {
+        self.keys.keys().cloned().collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use std::env;
+    use std::io::Write;
+
+    #[test]
+    fn keyring_finds_keys_by_id() {
+        let a = Arc::new(Keeper::new_for_testing_with_key(vec![1; 32]));
+        let b = Arc::new(Keeper::new_for_testing_with_key(vec![2; 32]));
+        let mut keyring = Keyring::new();
+        assert!(keyring.insert(a.clone()).unwrap());
+        assert!(keyring.insert(b.clone()).unwrap());
+        assert!(!keyring.insert(Arc::new(Keeper::new_for_testing_with_key(vec![1; 32]))).unwrap());
+
+        let mut ids = vec![a.key_id(), b.key_id()];
+        ids.sort();
+        assert_eq!(keyring.key_ids(), ids);
+        assert_eq!(keyring.lookup(&a.key_id()).unwrap().key_digest(), a.key_digest());
+        assert_eq!(keyring.lookup(&b.key_id()).unwrap().key_digest(), b.key_digest());
+        assert!(keyring.lookup("0000000000000000").is_none());
+
+        // Another key under a known id is refused, and the known key is kept.
+        assert!(keyring.insert_as(a.key_id(), b.clone()).is_err());
+        assert_eq!(keyring.lookup(&a.key_id()).unwrap().key_digest(), a.key_digest());
+    }
+
+    #[test]
+    fn keyring_from_keyfiles_merges_duplicates() {
+        let dir = env::temp_dir().join(format!("hat-keyring-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut paths = vec![];
+        let files = [("one", "first phrase\n"), ("two", "second"), ("again", "first phrase")];
+        for &(name, phrase) in &files {
+            let path = dir.join(name);
+            fs::File::create(&path).unwrap().write_all(phrase.as_bytes()).unwrap();
+            paths.push(path);
+        }
+
+        let keyring = Keyring::from_keyfiles(&paths[..]).unwrap();
+        assert_eq!(keyring.key_ids().len(), 2);
+        assert!(keyring.lookup(&Keeper::new("second").key_id()).is_some());
+
+        paths.push(dir.join("missing"));
+        assert!(Keyring::from_keyfiles(&paths[..]).is_err());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -12,14 +12,83 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use blob::Key;
+use blob::{ChunkRef, ChunkRefBuilder, Key};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 pub use errors::CryptoError;
 use hash::tree::HashRef;
+use libsodium_sys;
+use std::cmp;
+use std::fmt;
 use std::io;
 use std::mem;
+use std::sync::{ONCE_INIT, Once};
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, AtomicBool, AtomicUsize, Ordering};
 
+mod capabilities;
+mod checksum;
 pub mod keys;
+mod sha256;
+#[cfg(test)]
+pub mod testing;
+
+pub use self::capabilities::{Capabilities, Primitive, SEAL_ALGORITHM, capabilities};
+pub use self::checksum::{Checksum, INTEGRITY_TAG_BYTES, IntegrityTag};
+pub use self::sha256::{SHA256_BYTES, Sha256};
+
+static SODIUM_INIT: Once = ONCE_INIT;
+static SODIUM_INIT_RUNS: AtomicUsize = ATOMIC_USIZE_INIT;
+static SODIUM_READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initialize libsodium. This must happen before any other crypto operation; it is safe to call
+/// any number of times and from any thread, as only the first call does any work.
+pub fn init() -> Result<(), CryptoError> {
+    SODIUM_INIT.call_once(|| {
+        SODIUM_INIT_RUNS.fetch_add(1, Ordering::SeqCst);
+        // Returns 1 if some other user of the library already initialized it.
+        let ret = unsafe { libsodium_sys::sodium_init() };
+        SODIUM_READY.store(ret >= 0, Ordering::SeqCst);
+    });
+    if SODIUM_READY.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err("crypto init failed: libsodium could not be initialized".into())
+    }
+}
+
+/// Check that the crypto library is usable on this host: that it initializes, that its random
+/// number generator is not stuck and that sealed data can be opened again.
+pub fn self_test() -> Result<(), CryptoError> {
+    init()?;
+
+    let a = keys::random_bytes(32);
+    let b = keys::random_bytes(32);
+    let constant = |r: &[u8]| r.iter().all(|x| *x == r[0]);
+    if a.unsecure() == b.unsecure() || constant(a.unsecure()) || constant(b.unsecure()) {
+        return Err("crypto self-test failed: random generator output is constant".into());
+    }
+
+    let key = authed::imp::gen_key();
+    let nonce = authed::imp::gen_nonce();
+    let msg: &[u8] = b"hat-backup self-test";
+    let mut ct = vec![];
+    let mut pt = vec![];
+    seal_into(&mut ct, msg, &[], &nonce, &key);
+    open_into(&mut pt, &ct[..], &[], &nonce, &key)?;
+    if pt != msg {
+        return Err("crypto self-test failed: unsealed data does not match".into());
+    }
+
+    ct[0] ^= 1;
+    if open_into(&mut pt, &ct[..], &[], &nonce, &key).is_ok() {
+        return Err("crypto self-test failed: tampered data was accepted".into());
+    }
+
+    Ok(())
+}
+
+fn ensure_init() {
+    init().expect("Could not initialize libsodium");
+}
 
 pub struct PlainText(Vec<u8>);
 pub struct PlainTextRef<'a>(&'a [u8]);
@@ -30,6 +99,19 @@ pub struct CipherText {
 }
 pub struct CipherTextRef<'a>(&'a [u8]);
 
+// Only the length is printed, so that these can show up in debug output without leaking data.
+impl fmt::Debug for PlainText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "PlainText {{ len: {}, .. }}", self.len())
+    }
+}
+
+impl fmt::Debug for CipherText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "CipherText {{ len: {}, .. }}", self.len())
+    }
+}
+
 
 pub mod authed {
     pub mod desc {
@@ -39,6 +121,7 @@ pub mod authed {
         pub const KEYBYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_KEYBYTES;
         pub const NONCEBYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_NPUBBYTES;
         pub const MACBYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_ABYTES;
+        pub const COMMITBYTES: usize = 32;
         pub type Key = secstr::SecStr;
         pub type Nonce = secstr::SecStr;
     }
@@ -67,6 +150,32 @@ pub mod authed {
             );
             super::desc::Key::from(&mixed_key[..super::desc::KEYBYTES])
         }
+
+        pub fn key_commitment(key: &super::desc::Key, nonce: &super::desc::Nonce) -> Vec<u8> {
+            let mut commitment = vec![0u8; super::desc::COMMITBYTES];
+            let salt: &[u8; 16] = b"commit~~commit~~";
+            ::crypto::keys::keyed_fingerprint(
+                &key.unsecure()[..],
+                &nonce.unsecure()[..],
+                salt,
+                &mut commitment[..],
+            );
+            commitment
+        }
+
+        pub fn verify_key_commitment(
+            key: &super::desc::Key,
+            nonce: &super::desc::Nonce,
+            commitment: &[u8],
+        ) -> bool {
+            use libsodium_sys;
+
+            let want = key_commitment(key, nonce);
+            want.len() == commitment.len() &&
+                unsafe {
+                    libsodium_sys::sodium_memcmp(want.as_ptr(), commitment.as_ptr(), want.len())
+                } == 0
+        }
     }
 
     pub mod hash {
@@ -113,8 +222,44 @@ pub mod sealed {
     }
 }
 
-fn wrap_key(key: authed::desc::Key) -> Key {
-    Key::AeadChacha20Poly1305(key)
+/// Encrypt `plaintext` into `out`, replacing its contents but reusing its allocation.
+pub fn seal_into(
+    out: &mut Vec<u8>,
+    plaintext: &[u8],
+    additional_data: &[u8],
+    nonce: &authed::desc::Nonce,
+    key: &authed::desc::Key,
+) {
+    keys::Keeper::symmetric_lock_into(
+        out,
+        plaintext,
+        additional_data,
+        nonce.unsecure(),
+        key.unsecure(),
+    )
+}
+
+/// Decrypt and authenticate `ciphertext` into `out`, replacing its contents but reusing its
+/// allocation. On failure `out` is left empty.
+pub fn open_into(
+    out: &mut Vec<u8>,
+    ciphertext: &[u8],
+    additional_data: &[u8],
+    nonce: &authed::desc::Nonce,
+    key: &authed::desc::Key,
+) -> Result<(), CryptoError> {
+    if keys::Keeper::symmetric_unlock_into(
+        out,
+        key.unsecure(),
+        ciphertext,
+        additional_data,
+        nonce.unsecure(),
+    )
+    {
+        Ok(())
+    } else {
+        Err("crypto read failed: open_into".into())
+    }
 }
 
 impl<'a> PlainTextRef<'a> {
@@ -124,6 +269,9 @@ impl<'a> PlainTextRef<'a> {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
     pub fn read_i64(&self) -> Result<i64, io::Error> {
         return (&self.0[..]).read_i64::<LittleEndian>();
     }
@@ -149,12 +297,9 @@ impl<'a> PlainTextRef<'a> {
         nonce: &authed::desc::Nonce,
         key: &authed::desc::Key,
     ) -> CipherText {
-        CipherText::new(keys::Keeper::symmetric_lock(
-            self.0,
-            additional_data,
-            nonce.unsecure(),
-            key.unsecure(),
-        ))
+        let mut out = Vec::with_capacity(self.0.len() + authed::desc::MACBYTES);
+        seal_into(&mut out, self.0, additional_data, nonce, key);
+        CipherText::new(out)
     }
 }
 
@@ -165,6 +310,9 @@ impl PlainText {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
     pub fn as_ref(&self) -> PlainTextRef {
         PlainTextRef::new(&self.0[..])
     }
@@ -174,11 +322,98 @@ impl PlainText {
     pub fn into_vec(self) -> Vec<u8> {
         self.0
     }
+    /// Read all of `r` into a new plaintext, failing if it holds more than `max_len` bytes.
+    pub fn from_reader<R: io::Read>(r: &mut R, max_len: usize) -> io::Result<PlainText> {
+        use std::io::Read;
+
+        let mut buf = Vec::new();
+        r.take(max_len as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("plaintext exceeds {} bytes", max_len),
+            ));
+        }
+        buf.shrink_to_fit();
+        Ok(PlainText(buf))
+    }
     pub fn from_i64(n: i64) -> Self {
         let mut buf = PlainText::new(Vec::with_capacity(8));
         buf.0.write_i64::<LittleEndian>(n).unwrap();
         return buf;
     }
+    /// Split into pieces of `size` bytes, with a shorter last piece if `size` does not divide
+    /// the length. The first piece keeps the buffer, so only the bytes after it are copied.
+    pub fn chunk_into(mut self, size: usize) -> Result<Vec<PlainText>, CryptoError> {
+        if size == 0 {
+            return Err(From::from("plaintext pieces must be at least 1 byte"));
+        }
+        let count = cmp::max(1, (self.0.len() + size - 1) / size);
+        let mut pieces = Vec::with_capacity(count);
+        for i in (1..count).rev() {
+            pieces.push(PlainText(self.0.split_off(i * size)));
+        }
+        pieces.push(self);
+        pieces.reverse();
+        Ok(pieces)
+    }
+    /// Write `pieces` to `out` in order, each straight from its own buffer. Returns the number
+    /// of bytes written.
+    pub fn write_concat<I, W>(pieces: I, out: &mut W) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = PlainText>,
+        W: io::Write,
+    {
+        let mut written = 0;
+        for piece in pieces {
+            out.write_all(&piece.0[..])?;
+            written += piece.0.len() as u64;
+        }
+        Ok(written)
+    }
+    /// Like `write_concat`, but fail unless the pieces add up to exactly `expected_len` bytes,
+    /// as when a chunk of a file is missing or cut short. Stops before writing past it.
+    pub fn write_concat_exact<I, W>(pieces: I, out: &mut W, expected_len: u64) -> io::Result<()>
+    where
+        I: IntoIterator<Item = PlainText>,
+        W: io::Write,
+    {
+        let mut written = 0;
+        for piece in pieces {
+            let len = piece.0.len() as u64;
+            if written + len > expected_len {
+                return Err(length_mismatch(written + len, expected_len));
+            }
+            out.write_all(&piece.0[..])?;
+            written += len;
+        }
+        if written != expected_len {
+            return Err(length_mismatch(written, expected_len));
+        }
+        Ok(())
+    }
+    /// Join `pieces` into one plaintext of exactly `expected_len` bytes, allocated once.
+    pub fn concat<I>(pieces: I, expected_len: usize) -> Result<PlainText, CryptoError>
+    where
+        I: IntoIterator<Item = PlainText>,
+    {
+        let mut bytes = Vec::with_capacity(expected_len);
+        PlainText::write_concat_exact(pieces, &mut bytes, expected_len as u64)
+            .map_err(|e| CryptoError::from(e.to_string()))?;
+        Ok(PlainText(bytes))
+    }
+}
+
+fn length_mismatch(len: u64, expected_len: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "plaintext pieces hold {}{} bytes, expected {}",
+            if len > expected_len { "at least " } else { "" },
+            len,
+            expected_len
+        ),
+    )
 }
 
 impl CipherText {
@@ -195,9 +430,42 @@ impl CipherText {
             len: len,
         }
     }
+    /// An empty ciphertext with one buffer of `capacity` bytes, which appends fill in place.
+    pub fn with_capacity(capacity: usize) -> CipherText {
+        CipherText {
+            chunks: vec![Vec::with_capacity(capacity)],
+            len: 0,
+        }
+    }
+    /// Make room for at least `additional` more bytes in a single buffer, so that appending them
+    /// does not reallocate.
+    pub fn reserve(&mut self, additional: usize) {
+        self.collapse();
+        if self.chunks.is_empty() {
+            self.chunks.push(Vec::with_capacity(additional));
+        } else {
+            self.chunks[0].reserve(additional);
+        }
+    }
+    /// Bytes held by the buffers of this ciphertext before they have to grow.
+    pub fn capacity(&self) -> usize {
+        self.chunks.iter().map(|c| c.capacity()).sum()
+    }
     pub fn append(&mut self, mut other: CipherText) {
+        if other.is_empty() {
+            return;
+        }
+        let fits = self.chunks.len() == 1 &&
+            self.chunks[0].capacity() - self.chunks[0].len() >= other.len();
         self.len += other.len();
-        self.chunks.append(&mut other.chunks);
+        if fits {
+            // Copy into the reserved buffer, so that it never has to be concatenated later.
+            for c in &other.chunks {
+                self.chunks[0].extend_from_slice(&c[..]);
+            }
+        } else {
+            self.chunks.append(&mut other.chunks);
+        }
     }
     fn collapse(&mut self) {
         if self.chunks.len() > 1 {
@@ -209,6 +477,9 @@ impl CipherText {
     pub fn len(&self) -> usize {
         self.len
     }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
     pub fn random_pad_upto(&mut self, final_len: usize) {
         let len = self.len;
         if final_len > len {
@@ -260,6 +531,11 @@ impl CipherText {
     pub fn slices(&self) -> Vec<&[u8]> {
         self.chunks.iter().map(|x| &x[..]).collect()
     }
+
+    /// Checksum of the bytes as they are sent to the backend.
+    pub fn checksum(&self) -> Checksum {
+        Checksum::of_slices(&self.slices()[..])
+    }
 }
 
 impl<'a> CipherTextRef<'a> {
@@ -275,6 +551,64 @@ impl<'a> CipherTextRef<'a> {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+    /// Like `slice`, but fails instead of panicking if the range is not within.
+    pub fn checked_slice(&self, from: usize, to: usize) -> Result<CipherTextRef<'a>, CryptoError> {
+        if from > to || to > self.len() {
+            return Err("crypto read failed: chunk is outside of blob".into());
+        }
+        Ok(self.slice(from, to))
+    }
+    /// Open the chunk `href` in this blob: check that its reference is within the blob, cut it
+    /// out, and authenticate and decrypt it with the key of the reference mixed with
+    /// `access_key`. Each way this can fail has its own message.
+    pub fn verify_and_split(
+        &self,
+        href: &HashRef,
+        access_key: &authed::desc::Key,
+    ) -> Result<PlainText, CryptoError> {
+        let chunk_ref = &href.persistent_ref;
+        // An empty chunk is never sealed, and a reference past the end of the blob is corrupt.
+        if chunk_ref.length == 0 {
+            return Err("crypto read failed: chunk is empty".into());
+        }
+        let end = chunk_ref.offset.checked_add(chunk_ref.length).ok_or_else(|| {
+            CryptoError::from("crypto read failed: chunk is outside of blob")
+        })?;
+        let ct = self.checked_slice(chunk_ref.offset, end)?;
+
+        let key = match chunk_ref.key {
+            Some(ref key) => key,
+            None => return Err("crypto read failed: chunk has no key".into()),
+        };
+        if href.hash.bytes.len() < authed::desc::NONCEBYTES {
+            return Err("crypto read failed: hash is too short for a nonce".into());
+        }
+        let nonce = authed::desc::Nonce::from(&href.hash.bytes[..authed::desc::NONCEBYTES]);
+        let additional_data = keys::compute_salt(href.node, href.leaf);
+        match *key {
+            Key::AeadChacha20Poly1305(ref key) => {
+                let real_key = authed::imp::mix_keys(access_key, key);
+                ct.to_plaintext(&additional_data, &nonce, &real_key)
+            }
+            Key::AeadChacha20Poly1305Committed(ref key) => {
+                let real_key = authed::imp::mix_keys(access_key, key);
+                let (ct, commitment) = ct.split_from_right(authed::desc::COMMITBYTES)?;
+                if !authed::imp::verify_key_commitment(&real_key, &nonce, commitment.0) {
+                    return Err("crypto read failed: key commitment".into());
+                }
+                ct.to_plaintext(&additional_data, &nonce, &real_key)
+            }
+        }
+    }
+    pub fn checksum(&self) -> Checksum {
+        Checksum::of_slices(&[self.0])
+    }
     pub fn split_from_right(
         &self,
         len: usize,
@@ -288,18 +622,26 @@ impl<'a> CipherTextRef<'a> {
             ))
         }
     }
+    /// Split off the first `len` bytes, e.g. to read a header. Fails if there are not that many.
+    pub fn split_from_left(
+        &self,
+        len: usize,
+    ) -> Result<(CipherTextRef<'a>, CipherTextRef<'a>), CryptoError> {
+        if self.len() < len {
+            Err("crypto read failed: split_from_left".into())
+        } else {
+            Ok((self.slice(0, len), self.slice(len, self.len())))
+        }
+    }
     pub fn to_plaintext(
         &self,
         additional_data: &[u8],
         nonce: &authed::desc::Nonce,
         key: &authed::desc::Key,
     ) -> Result<PlainText, CryptoError> {
-        Ok(PlainText::new(keys::Keeper::symmetric_unlock(
-            key.unsecure(),
-            &self.0,
-            additional_data,
-            nonce.unsecure(),
-        )))
+        let mut out = Vec::with_capacity(self.0.len());
+        open_into(&mut out, self.0, additional_data, nonce, key)?;
+        Ok(PlainText::new(out))
     }
 
     pub fn strip_authentication(&self, keys: &keys::Keeper) -> Result<CipherTextRef, CryptoError> {
@@ -325,18 +667,66 @@ impl RefKey {
         href: &mut HashRef,
         access_key: &::crypto::authed::desc::Key,
         pt: PlainTextRef,
-    ) -> CipherText {
+    ) -> Result<CipherText, CryptoError> {
+        RefKey::seal_with_algorithm(href, access_key, SEAL_ALGORITHM, pt)
+    }
+
+    /// `seal` with the chunk key algorithm named `algorithm`, as returned by `Key::algorithm`.
+    /// Every seal uses a fresh partial key, so sealing the same chunk again never reuses a key
+    /// and nonce pair.
+    pub fn seal_with_algorithm(
+        href: &mut HashRef,
+        access_key: &::crypto::authed::desc::Key,
+        algorithm: &str,
+        pt: PlainTextRef,
+    ) -> Result<CipherText, CryptoError> {
         let partial_key = authed::imp::gen_key();
-        href.persistent_ref.key = Some(wrap_key(partial_key.clone()));
 
         let nonce = authed::desc::Nonce::from(&href.hash.bytes[..authed::desc::NONCEBYTES]);
         let key = ::crypto::authed::imp::mix_keys(&access_key, &partial_key);
 
         let additional_data = keys::compute_salt(href.node, href.leaf);
-        let ct = pt.to_ciphertext(&additional_data, &nonce, &key);
-        href.persistent_ref.length = ct.len();
+        let mut ct = pt.to_ciphertext(&additional_data, &nonce, &key);
 
-        ct
+        let chunk_key = Key::from_bytes(algorithm, partial_key.unsecure())?;
+        if let Key::AeadChacha20Poly1305Committed(_) = chunk_key {
+            // Poly1305 alone does not bind the ciphertext to a single key, so we append a
+            // commitment to the key that is checked before decrypting.
+            ct.append(CipherText::new(authed::imp::key_commitment(&key, &nonce)));
+        }
+
+        let offset = href.persistent_ref.offset;
+        href.persistent_ref = ChunkRefBuilder::from(href.persistent_ref.clone())
+            .with_key(chunk_key)
+            .with_range(offset, ct.len())
+            .build()
+            .map_err(|e| format!("Sealed chunk reference is inconsistent: {}", e))?;
+
+        Ok(ct)
+    }
+
+    /// Length of the plaintext sealed into a chunk, derived from its reference alone. Chunks
+    /// that went through more than sealing do not tell.
+    pub fn plaintext_len(chunk_ref: &ChunkRef) -> Option<usize> {
+        if chunk_ref.pipeline.is_some() {
+            return None;
+        }
+        chunk_ref
+            .key
+            .as_ref()
+            .and_then(|key| RefKey::seal_overhead(key.algorithm()))
+            .and_then(|overhead| chunk_ref.length.checked_sub(overhead))
+    }
+
+    /// Bytes that sealing adds to each chunk with the chunk key algorithm `algorithm`.
+    pub fn seal_overhead(algorithm: &str) -> Option<usize> {
+        match algorithm {
+            "chacha20poly1305" => Some(authed::desc::MACBYTES),
+            "chacha20poly1305-committed" => {
+                Some(authed::desc::MACBYTES + authed::desc::COMMITBYTES)
+            }
+            _ => None,
+        }
     }
 
     pub fn unseal(
@@ -344,22 +734,7 @@ impl RefKey {
         href: &HashRef,
         ct: CipherTextRef,
     ) -> Result<PlainText, CryptoError> {
-        assert!(ct.len() > href.persistent_ref.offset + href.persistent_ref.length);
-        let ct = ct.slice(
-            href.persistent_ref.offset,
-            href.persistent_ref.offset + href.persistent_ref.length,
-        );
-        match href.persistent_ref.key {
-            Some(Key::AeadChacha20Poly1305(ref key))
-                if href.hash.bytes.len() >= authed::desc::NONCEBYTES => {
-                let nonce = authed::desc::Nonce::from(&href.hash.bytes[..authed::desc::NONCEBYTES]);
-
-                let additional_data = keys::compute_salt(href.node, href.leaf);
-                let real_key = ::crypto::authed::imp::mix_keys(access_key, &key);
-                Ok(ct.to_plaintext(&additional_data, &nonce, &real_key)?)
-            }
-            _ => Err("crypto read failed: unseal".into()),
-        }
+        ct.verify_and_split(href, access_key)
     }
 }
 
@@ -376,6 +751,11 @@ impl<'k> FixedKey<'k> {
         CipherText::new(self.keeper.naming_lock(pt.0))
     }
 
+    /// Like `seal_blob_name`, but the same plaintext always seals to the same name.
+    pub fn seal_blob_name_derived(&self, pt: PlainTextRef) -> CipherText {
+        CipherText::new(self.keeper.naming_lock_derived(pt.0))
+    }
+
     pub fn unseal_blob_name(&self, ct: CipherTextRef) -> PlainText {
         PlainText::new(self.keeper.naming_unlock(ct.0))
     }
@@ -407,10 +787,20 @@ impl<'k> FixedKey<'k> {
         // Generate inner symmetric key.
         let inner_key = ::crypto::authed::imp::gen_key();
         let nonce = ::crypto::authed::imp::gen_nonce();
+        self.seal_with(access_key, &inner_key, &nonce, pt)
+    }
 
+    /// `seal` with the given inner key and nonce, which must never be used twice.
+    fn seal_with(
+        &self,
+        access_key: &::crypto::authed::desc::Key,
+        inner_key: &::crypto::authed::desc::Key,
+        nonce: &::crypto::authed::desc::Nonce,
+        pt: PlainTextRef,
+    ) -> CipherText {
         // Encrypt plaintext with inner key.
         let additional_data: &[u8] = b"hat_blob_seal~";
-        let mut ct = pt.to_ciphertext(additional_data, &nonce, &inner_key);
+        let mut ct = pt.to_ciphertext(additional_data, nonce, inner_key);
         ct.append(CipherText::new(nonce.unsecure().to_vec()));
 
         // Construct footer of length as LittleEndian and inner key.
@@ -441,7 +831,10 @@ impl<'k> FixedKey<'k> {
     ) -> Result<(::crypto::authed::desc::Key, CipherText, CipherTextRef<'a>), CryptoError> {
         // Read sealed ciphertext length and unseal it.
         let (rest, access_ct) = ct.split_from_right(sealed::desc::access_cipher_bytes())?;
-        let mut access_pt = self.unseal_blob_access(access_ct).into_vec();
+        // The blob may not have been authenticated as a whole, so this can fail.
+        let mut access_pt = self.keeper.try_access_unlock(access_ct.0).ok_or(
+            "crypto read failed: unseal_access_ctx",
+        )?;
         assert_eq!(access_pt.len(), sealed::desc::access_plain_bytes());
 
         let access_key = access_pt.split_off(
@@ -473,7 +866,10 @@ impl<'k> FixedKey<'k> {
         let ct_len = foot_len.read_i64().map_err(
             |_| "crypto read failed: unseal",
         )?;
-        assert!(ct_len > 0);
+        // Even empty plaintext seals to a tag and a nonce.
+        if ct_len < (authed::desc::MACBYTES + authed::desc::NONCEBYTES) as i64 {
+            return Err("crypto read failed: unseal".into());
+        }
 
         // Read and unseal inner symmetric cipher text.
         let additional_data: &[u8] = b"hat_blob_seal~";
@@ -491,3 +887,390 @@ impl<'k> FixedKey<'k> {
         ))
     }
 }
+
+#[cfg(test)]
+fn test_hash_ref() -> HashRef {
+    use blob::{LeafType, NodeType};
+    use hash::Hash;
+
+    HashRef {
+        hash: Hash { bytes: keys::random_bytes(authed::hash::DIGESTBYTES).unsecure().to_vec() },
+        node: NodeType::Leaf,
+        leaf: LeafType::FileChunk,
+        info: None,
+        persistent_ref: ChunkRef {
+            blob_id: None,
+            blob_name: vec![],
+            offset: 0,
+            length: 0,
+            packing: None,
+            pipeline: None,
+            key: None,
+        },
+    }
+}
+
+#[test]
+fn ref_key_round_trip() {
+    let access_key = authed::imp::gen_key();
+    let mut href = test_hash_ref();
+
+    let mut blob = RefKey::seal(&mut href, &access_key, PlainTextRef::new(b"hello"))
+        .unwrap()
+        .to_vec();
+    blob.push(0);
+    let pt = RefKey::unseal(&access_key, &href, CipherTextRef::new(&blob[..])).unwrap();
+    assert_eq!(pt.as_bytes(), b"hello");
+
+    // Chunks sealed without a key commitment are still readable.
+    let partial_key = authed::imp::gen_key();
+    let nonce = authed::desc::Nonce::from(&href.hash.bytes[..authed::desc::NONCEBYTES]);
+    let key = authed::imp::mix_keys(&access_key, &partial_key);
+    let additional_data = keys::compute_salt(href.node, href.leaf);
+    let mut blob = PlainTextRef::new(b"hello")
+        .to_ciphertext(&additional_data, &nonce, &key)
+        .to_vec();
+    href.persistent_ref.length = blob.len();
+    href.persistent_ref.key = Some(Key::AeadChacha20Poly1305(partial_key));
+    blob.push(0);
+    let pt = RefKey::unseal(&access_key, &href, CipherTextRef::new(&blob[..])).unwrap();
+    assert_eq!(pt.as_bytes(), b"hello");
+}
+
+#[test]
+fn ref_key_commitment_rejects_other_key() {
+    let access_key = authed::imp::gen_key();
+    let mut href = test_hash_ref();
+
+    let mut blob = RefKey::seal(&mut href, &access_key, PlainTextRef::new(b"hello"))
+        .unwrap()
+        .to_vec();
+    blob.push(0);
+
+    // The commitment is checked before the ciphertext is opened.
+    let other_key = authed::imp::gen_key();
+    assert!(RefKey::unseal(&other_key, &href, CipherTextRef::new(&blob[..])).is_err());
+
+    // A modified commitment is rejected under the right key.
+    let mut blob = testing::tamper::flip_commitment_byte(&blob[..href.persistent_ref.length]);
+    blob.push(0);
+    assert!(RefKey::unseal(&access_key, &href, CipherTextRef::new(&blob[..])).is_err());
+}
+
+#[test]
+fn ref_key_seal_refuses_unknown_algorithm() {
+    let access_key = authed::imp::gen_key();
+    let mut href = test_hash_ref();
+    let pt = PlainTextRef::new(b"hello");
+    assert!(RefKey::seal_with_algorithm(&mut href, &access_key, "rot13", pt).is_err());
+}
+
+#[test]
+fn ref_key_unseal_refuses_refs_outside_blob() {
+    let access_key = authed::imp::gen_key();
+    let mut href = test_hash_ref();
+
+    let blob = RefKey::seal(&mut href, &access_key, PlainTextRef::new(b""))
+        .unwrap()
+        .to_vec();
+    assert!(!blob.is_empty());
+    let pt = RefKey::unseal(&access_key, &href, CipherTextRef::new(&blob[..])).unwrap();
+    assert!(pt.is_empty());
+
+    // Too short to hold the chunk.
+    let short = &blob[..blob.len() - 1];
+    assert!(RefKey::unseal(&access_key, &href, CipherTextRef::new(short)).is_err());
+    href.persistent_ref.length = 0;
+    assert!(RefKey::unseal(&access_key, &href, CipherTextRef::new(&blob[..])).is_err());
+}
+
+#[test]
+fn verify_and_split_reports_each_failure() {
+    let access_key = authed::imp::gen_key();
+    let mut href = test_hash_ref();
+
+    // A chunk between other data, as in a blob.
+    let sealed = RefKey::seal(&mut href, &access_key, PlainTextRef::new(b"hello"))
+        .unwrap()
+        .to_vec();
+    let mut blob = vec![1, 2, 3];
+    href.persistent_ref.offset = blob.len();
+    blob.extend_from_slice(&sealed[..]);
+    blob.extend_from_slice(&[4, 5]);
+    let fails_with = |href: &HashRef, key: &authed::desc::Key, blob: &[u8], expected: &str| {
+        let err = CipherTextRef::new(blob).verify_and_split(href, key).unwrap_err();
+        assert!(err.to_string().contains(expected), "{} is not {}", err, expected);
+    };
+
+    let pt = CipherTextRef::new(&blob[..]).verify_and_split(&href, &access_key).unwrap();
+    assert_eq!(pt.as_bytes(), b"hello");
+
+    // Out of range, without overflowing.
+    let mut outside = href.clone();
+    outside.persistent_ref.offset = blob.len() - 1;
+    fails_with(&outside, &access_key, &blob[..], "outside of blob");
+    outside.persistent_ref.offset = usize::max_value();
+    fails_with(&outside, &access_key, &blob[..], "outside of blob");
+    fails_with(&href, &access_key, &blob[..blob.len() - 3], "outside of blob");
+    let mut empty = href.clone();
+    empty.persistent_ref.length = 0;
+    fails_with(&empty, &access_key, &blob[..], "chunk is empty");
+
+    // The wrong key, no key, or a corrupt MAC.
+    fails_with(&href, &authed::imp::gen_key(), &blob[..], "key commitment");
+    let mut keyless = href.clone();
+    keyless.persistent_ref.key = None;
+    fails_with(&keyless, &access_key, &blob[..], "no key");
+    let mut corrupt = blob.clone();
+    let mac = testing::tamper::flip_mac_byte(&sealed[..]);
+    corrupt[3..3 + sealed.len()].copy_from_slice(&mac[..]);
+    fails_with(&href, &access_key, &corrupt[..], "open_into");
+}
+
+#[test]
+fn empty_ciphertext_appends_nothing() {
+    let mut ct = CipherText::empty();
+    assert!(ct.is_empty());
+    ct.append(CipherText::empty());
+    assert!(ct.is_empty());
+    assert!(ct.slices().is_empty());
+    ct.append(CipherText::new(vec![1, 2]));
+    assert!(!ct.is_empty());
+    assert_eq!(ct.to_vec(), vec![1, 2]);
+}
+
+#[test]
+fn ciphertext_split_from_left() {
+    let ct = CipherTextRef::new(b"header-body");
+
+    let (head, tail) = ct.split_from_left(7).unwrap();
+    assert_eq!(head.0, b"header-");
+    assert_eq!(tail.0, b"body");
+
+    let (head, tail) = ct.split_from_left(0).unwrap();
+    assert_eq!(head.len(), 0);
+    assert_eq!(tail.0, b"header-body");
+
+    let (head, tail) = ct.split_from_left(ct.len()).unwrap();
+    assert_eq!(head.0, b"header-body");
+    assert_eq!(tail.len(), 0);
+
+    assert!(ct.split_from_left(ct.len() + 1).is_err());
+}
+
+#[test]
+fn plaintext_from_reader() {
+    let data = vec![7u8; 100];
+
+    // Exact size.
+    let pt = PlainText::from_reader(&mut &data[..], 100).unwrap();
+    assert_eq!(pt.as_bytes(), &data[..]);
+
+    // Early EOF.
+    let pt = PlainText::from_reader(&mut &data[..50], 100).unwrap();
+    assert_eq!(pt.as_bytes(), &data[..50]);
+
+    // Over the limit.
+    let err = PlainText::from_reader(&mut &data[..], 99).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn debug_output_is_redacted() {
+    let secret = vec![0xab_u8; 4096];
+
+    let pt = PlainText::new(secret.clone());
+    let mut ct = CipherText::new(secret[..1000].to_vec());
+    ct.append(CipherText::new(secret[1000..].to_vec()));
+
+    let pt_out = format!("{:?}", pt);
+    let ct_out = format!("{:?}", ct);
+    assert_eq!(pt_out, "PlainText { len: 4096, .. }");
+    assert_eq!(ct_out, "CipherText { len: 4096, .. }");
+
+    // Neither the decimal nor the hex form of the content bytes appears.
+    for out in &[pt_out, ct_out] {
+        assert!(!out.contains("171"));
+        assert!(!out.to_lowercase().contains("ab"));
+    }
+}
+
+#[test]
+fn reserved_ciphertext_appends_in_place() {
+    let pieces: Vec<Vec<u8>> = (0..1000).map(|i| vec![(i % 251) as u8; 1 + i % 37]).collect();
+    let total: usize = pieces.iter().map(|p| p.len()).sum();
+
+    let mut unreserved = CipherText::empty();
+    let mut reserved = CipherText::with_capacity(total);
+    let mut late = CipherText::new(pieces[0].clone());
+    late.reserve(total - pieces[0].len());
+    let capacity = reserved.capacity();
+    let late_capacity = late.capacity();
+    assert!(capacity >= total);
+
+    for (i, piece) in pieces.iter().enumerate() {
+        unreserved.append(CipherText::new(piece.clone()));
+        reserved.append(CipherText::new(piece.clone()));
+        if i > 0 {
+            late.append(CipherText::new(piece.clone()));
+        }
+    }
+
+    assert_eq!(reserved.len(), total);
+    assert_eq!(reserved.to_vec(), unreserved.to_vec());
+    assert_eq!(late.to_vec(), unreserved.to_vec());
+
+    // The reserved buffers never grew, and hold everything in one piece.
+    assert_eq!(reserved.capacity(), capacity);
+    assert_eq!(reserved.slices().len(), 1);
+    assert_eq!(late.capacity(), late_capacity);
+    assert_eq!(late.slices().len(), 1);
+}
+
+#[test]
+fn seal_into_matches_ciphertext() {
+    let key = authed::imp::gen_key();
+    let nonce = authed::imp::gen_nonce();
+    let ad: &[u8] = b"additional";
+    let mut ct_buf = vec![1, 2, 3];
+    let mut pt_buf = vec![];
+
+    for text in vec![vec![], vec![42u8; 10], vec![7u8; 100000]] {
+        let ct = PlainTextRef::new(&text[..]).to_ciphertext(ad, &nonce, &key);
+        seal_into(&mut ct_buf, &text[..], ad, &nonce, &key);
+        assert_eq!(ct.to_vec(), ct_buf);
+
+        let ct_vec = ct.to_vec();
+        let pt = CipherTextRef::new(&ct_vec[..])
+            .to_plaintext(ad, &nonce, &key)
+            .unwrap();
+        open_into(&mut pt_buf, &ct_buf[..], ad, &nonce, &key).unwrap();
+        assert_eq!(pt.as_bytes(), &pt_buf[..]);
+        assert_eq!(pt_buf, text);
+    }
+
+    // Tampering is reported rather than returning bad data.
+    ct_buf[0] ^= 1;
+    assert!(open_into(&mut pt_buf, &ct_buf[..], ad, &nonce, &key).is_err());
+    assert!(pt_buf.is_empty());
+    assert!(open_into(&mut pt_buf, &[0u8; 3], ad, &nonce, &key).is_err());
+}
+
+#[test]
+fn init_runs_once() {
+    use std::thread;
+
+    let threads: Vec<_> = (0..4).map(|_| thread::spawn(|| init().unwrap())).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    init().unwrap();
+    assert_eq!(SODIUM_INIT_RUNS.load(Ordering::SeqCst), 1);
+
+    self_test().unwrap();
+}
+
+#[test]
+fn seal_matches_pinned_vectors() {
+    use hex::ToHex;
+    use self::testing::vectors;
+
+    let keeper = keys::Keeper::new_for_testing();
+    let fixed = FixedKey::new(&keeper);
+    let access_key = authed::imp::gen_key();
+    let inner_key = authed::desc::Key::from(&vectors::SEAL_INNER_KEY[..]);
+    let nonce = authed::desc::Nonce::from(&vectors::SEAL_NONCE[..]);
+    assert_eq!(sealed::desc::overhead(), vectors::SEAL_OVERHEAD);
+    assert_eq!(sealed::desc::access_cipher_bytes(), vectors::SEAL_FOOTER_BYTES);
+
+    for vector in vectors::SEAL_VECTORS {
+        let pt = PlainTextRef::new(vector.plaintext);
+        let ct = fixed.seal_with(&access_key, &inner_key, &nonce, pt).to_vec();
+        assert_eq!(ct.len(), vector.plaintext.len() + vectors::SEAL_OVERHEAD);
+
+        // Everything before the footer is exactly as pinned.
+        let (ct_body, ct_footer) = ct.split_at(ct.len() - vectors::SEAL_FOOTER_BYTES);
+        assert_eq!(ct_body.to_hex(), vector.body);
+
+        // The footer opens to the pinned layout, with the access key after it.
+        let mut access_pt = keeper.access_unlock(ct_footer);
+        let key_at = access_pt.len() - authed::desc::KEYBYTES;
+        assert_eq!(&access_pt.split_off(key_at)[..], access_key.unsecure());
+        let footer = keeper.data_unlock(&access_pt[..]);
+        assert_eq!(footer.to_hex(), vector.footer);
+
+        // And the plaintext comes back out.
+        let (access, footer_ct, rest) = fixed.unseal_access_ctx(CipherTextRef::new(&ct[..]))
+            .unwrap();
+        assert_eq!(access.unsecure(), access_key.unsecure());
+        let footer_ct = footer_ct.to_vec();
+        let (rest, unsealed) = fixed.unseal(CipherTextRef::new(&footer_ct[..]), rest).unwrap();
+        assert_eq!(rest.len(), 0);
+        assert_eq!(unsealed.as_bytes(), vector.plaintext);
+    }
+}
+
+#[test]
+fn plaintext_chunk_into_exact_multiple() {
+    let bytes: Vec<u8> = (0..12).collect();
+    let pieces = PlainText::new(bytes.clone()).chunk_into(4).unwrap();
+    let lens: Vec<usize> = pieces.iter().map(|p| p.len()).collect();
+    assert_eq!(lens, vec![4, 4, 4]);
+    assert_eq!(pieces[1].as_bytes(), &[4, 5, 6, 7]);
+    let joined: Vec<u8> = pieces.into_iter().flat_map(|p| p.into_vec()).collect();
+    assert_eq!(joined, bytes);
+}
+
+#[test]
+fn plaintext_chunk_into_short_last_piece() {
+    let bytes: Vec<u8> = (0..10).collect();
+    let pieces = PlainText::new(bytes.clone()).chunk_into(4).unwrap();
+    let lens: Vec<usize> = pieces.iter().map(|p| p.len()).collect();
+    assert_eq!(lens, vec![4, 4, 2]);
+    assert_eq!(pieces[2].as_bytes(), &[8, 9]);
+    let joined: Vec<u8> = pieces.into_iter().flat_map(|p| p.into_vec()).collect();
+    assert_eq!(joined, bytes);
+
+    // A size of at least the length leaves a single piece; a size of 0 is refused.
+    let whole = PlainText::new(bytes.clone()).chunk_into(10).unwrap();
+    assert_eq!(whole.len(), 1);
+    assert_eq!(whole[0].as_bytes(), &bytes[..]);
+    assert_eq!(PlainText::new(vec![]).chunk_into(4).unwrap().len(), 1);
+    assert!(PlainText::new(bytes).chunk_into(0).is_err());
+}
+
+#[test]
+fn plaintext_concat_assembles_pieces_in_order() {
+    let bytes: Vec<u8> = (0..100).collect();
+    let pieces = || PlainText::new(bytes.clone()).chunk_into(30).unwrap();
+
+    let mut out = vec![];
+    assert_eq!(PlainText::write_concat(pieces(), &mut out).unwrap(), 100);
+    assert_eq!(out, bytes);
+
+    let mut out = vec![];
+    PlainText::write_concat_exact(pieces(), &mut out, 100).unwrap();
+    assert_eq!(out, bytes);
+
+    let whole = PlainText::concat(pieces(), 100).unwrap();
+    assert_eq!(whole.as_bytes(), &bytes[..]);
+    assert_eq!(PlainText::concat(vec![], 0).unwrap().len(), 0);
+}
+
+#[test]
+fn plaintext_concat_rejects_wrong_length() {
+    let bytes: Vec<u8> = (0..100).collect();
+    let pieces = || PlainText::new(bytes.clone()).chunk_into(30).unwrap();
+
+    // Too long: nothing past the expected length is written.
+    let mut out = vec![];
+    let err = PlainText::write_concat_exact(pieces(), &mut out, 80).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(out, &bytes[..60]);
+
+    // A missing chunk.
+    let mut pieces = pieces();
+    pieces.remove(1);
+    let err = PlainText::concat(pieces, 100).unwrap_err();
+    assert!(err.to_string().contains("hold 70 bytes, expected 100"));
+}
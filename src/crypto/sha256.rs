@@ -0,0 +1,76 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Plain SHA-256 digests of file contents.
+//!
+//! These are not keyed and play no part in how data is stored or deduplicated; they are kept
+//! only so that restored files can be checked with standard tools such as `sha256sum`.
+
+use libsodium_sys::{crypto_hash_sha256_BYTES, crypto_hash_sha256_final, crypto_hash_sha256_init,
+                    crypto_hash_sha256_state, crypto_hash_sha256_update};
+use std::mem;
+
+pub const SHA256_BYTES: usize = crypto_hash_sha256_BYTES;
+
+/// SHA-256 of data given in any number of pieces.
+pub struct Sha256 {
+    state: crypto_hash_sha256_state,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        super::ensure_init();
+        let mut state = unsafe { mem::zeroed() };
+        let ret = unsafe { crypto_hash_sha256_init(&mut state) };
+        assert_eq!(0, ret);
+        Sha256 { state: state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let ret =
+            unsafe { crypto_hash_sha256_update(&mut self.state, data.as_ptr(), data.len() as u64) };
+        assert_eq!(0, ret);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut digest = [0u8; SHA256_BYTES];
+        let ret = unsafe { crypto_hash_sha256_final(&mut self.state, &mut digest) };
+        assert_eq!(0, ret);
+        digest.to_vec()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::ToHex;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(
+            Sha256::new().finish().to_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut sha = Sha256::new();
+        sha.update(b"a");
+        sha.update(b"");
+        sha.update(b"bc");
+        assert_eq!(
+            sha.finish().to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deliberately invalid ciphertext for negative tests.
+//!
+//! Chunk helpers take a chunk as sealed by `RefKey::seal`: the AEAD ciphertext and its MAC,
+//! followed by the key commitment. Blob helpers take a complete blob as produced by
+//! `Blob::to_ciphertext`. The ones that damage something behind the blob authentication
+//! re-authenticate their result, so that the check under test is the one that fails.
+
+use crypto::{CipherText, CipherTextRef, FixedKey, PlainTextRef, authed, sealed};
+use crypto::keys::Keeper;
+
+
+/// Flip a bit in the last byte of the MAC of a sealed chunk.
+pub fn flip_mac_byte(chunk: &[u8]) -> Vec<u8> {
+    let mut out = chunk.to_vec();
+    let pos = out.len() - authed::desc::COMMITBYTES - 1;
+    out[pos] ^= 1;
+    out
+}
+
+/// Flip a bit in the key commitment of a sealed chunk.
+pub fn flip_commitment_byte(chunk: &[u8]) -> Vec<u8> {
+    let mut out = chunk.to_vec();
+    let pos = out.len() - 1;
+    out[pos] ^= 1;
+    out
+}
+
+/// Drop the last `n` bytes, or everything if there are fewer.
+pub fn truncate(ct: &[u8], n: usize) -> Vec<u8> {
+    ct[..ct.len().saturating_sub(n)].to_vec()
+}
+
+/// Change the length of the inner ciphertext recorded in the sealed footer of a blob.
+///
+/// The footer is decrypted and sealed again, so only the keeper that wrote the blob can do this.
+pub fn flip_footer_length(keys: &Keeper, blob: &[u8]) -> Vec<u8> {
+    let fixed = FixedKey::new(keys);
+    let data = without_authentication(blob);
+
+    let (access_key, footer_ct, rest_len) = {
+        let (access_key, footer_ct, rest) = fixed
+            .unseal_access_ctx(CipherTextRef::new(&data[..]))
+            .expect("blob to tamper with must be valid");
+        (access_key, footer_ct.to_vec(), rest.len())
+    };
+
+    // The footer starts with the length as LittleEndian i64.
+    let mut foot_pt = fixed.unseal_blob_data(CipherTextRef::new(&footer_ct[..])).into_vec();
+    foot_pt[0] ^= 1;
+    let foot_ct = fixed.seal_blob_data(PlainTextRef::new(&foot_pt[..]));
+
+    let mut access_pt = foot_ct.to_vec();
+    access_pt.extend_from_slice(access_key.unsecure());
+    let access_ct = fixed.seal_blob_access(PlainTextRef::new(&access_pt[..]));
+
+    let mut out = data[..rest_len].to_vec();
+    out.extend_from_slice(&access_ct.to_vec()[..]);
+    authenticate(keys, out)
+}
+
+/// Flip a bit in the nonce of the inner ciphertext of a blob.
+pub fn corrupt_nonce_region(keys: &Keeper, blob: &[u8]) -> Vec<u8> {
+    let mut data = without_authentication(blob);
+
+    // The nonce sits right before the sealed access footer.
+    let pos = data.len() - sealed::desc::access_cipher_bytes() - 1;
+    data[pos] ^= 1;
+    authenticate(keys, data)
+}
+
+fn without_authentication(blob: &[u8]) -> Vec<u8> {
+    truncate(blob, authed::hash::DIGESTBYTES)
+}
+
+fn authenticate(keys: &Keeper, data: Vec<u8>) -> Vec<u8> {
+    let mut ct = CipherText::new(data);
+    ct.append_authentication(keys);
+    ct.to_vec()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blob::Blob;
+    use crypto::{CryptoError, PlainText, RefKey};
+    use crypto::test_hash_ref;
+    use std::sync::Arc;
+
+    fn assert_fails_with<T>(res: Result<T, CryptoError>, expected: &str) {
+        match res {
+            Err(CryptoError::Message(ref msg)) => {
+                assert!(msg.contains(expected), "unexpected error: {}", msg)
+            }
+            Ok(_) => panic!("tampered ciphertext was accepted"),
+        }
+    }
+
+    fn test_blob(keys: &Arc<Keeper>) -> Vec<u8> {
+        let mut blob = Blob::new(keys.clone(), 1024);
+        blob.try_append(b"hello", &mut test_hash_ref()).unwrap();
+        blob.to_ciphertext().unwrap().to_vec()
+    }
+
+    fn open_blob(keys: &Keeper, blob: &[u8]) -> Result<PlainText, CryptoError> {
+        let fixed = FixedKey::new(keys);
+        let blob = CipherTextRef::new(blob);
+        let rest = blob.strip_authentication(keys)?;
+        let (_access_key, footer_ct, rest) = fixed.unseal_access_ctx(rest)?;
+        let footer_ct = footer_ct.to_vec();
+        let (_rest, footer) = fixed.unseal(CipherTextRef::new(&footer_ct[..]), rest)?;
+        Ok(footer)
+    }
+
+    #[test]
+    fn chunk_helpers_break_unseal() {
+        let access_key = authed::imp::gen_key();
+        let mut href = test_hash_ref();
+        let mut chunk = RefKey::seal(&mut href, &access_key, PlainTextRef::new(b"hello"))
+            .unwrap()
+            .to_vec();
+        // Unsealing expects more data after the chunk, as in a blob.
+        chunk.push(0);
+        let unseal = |ct: &[u8]| RefKey::unseal(&access_key, &href, CipherTextRef::new(ct));
+
+        assert!(unseal(&chunk[..]).is_ok());
+
+        let sealed_len = chunk.len() - 1;
+        let mut mac = flip_mac_byte(&chunk[..sealed_len]);
+        mac.push(0);
+        assert_fails_with(unseal(&mac[..]), "open_into");
+        let mut commitment = flip_commitment_byte(&chunk[..sealed_len]);
+        commitment.push(0);
+        assert_fails_with(unseal(&commitment[..]), "key commitment");
+    }
+
+    #[test]
+    fn blob_helpers_break_unseal() {
+        let keys = Arc::new(Keeper::new_for_testing());
+        let blob = test_blob(&keys);
+        assert!(open_blob(&keys, &blob[..]).is_ok());
+
+        assert_fails_with(open_blob(&keys, &truncate(&blob[..], 1)), "strip_authentication");
+        assert_fails_with(
+            open_blob(&keys, &truncate(&blob[..], blob.len())),
+            "split_from_right",
+        );
+        assert_fails_with(open_blob(&keys, &flip_footer_length(&keys, &blob[..])), "open_into");
+        assert_fails_with(
+            open_blob(&keys, &corrupt_nonce_region(&keys, &blob[..])),
+            "open_into",
+        );
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pinned output of `FixedKey::seal`, as stored at the end of every blob.
+//!
+//! The symmetric part of a seal is deterministic given its inner key and nonce, so its bytes are
+//! pinned exactly. The footer is sealed to a public key with an ephemeral key pair and differs
+//! every time; its length and what it decrypts to are pinned instead.
+//!
+//! These must never be updated to match new output. If a test against them fails, the format
+//! of stored blobs has changed, and that change needs a new format version.
+
+/// Inner key and nonce the vectors were sealed with.
+pub const SEAL_INNER_KEY: [u8; 32] = [1; 32];
+pub const SEAL_NONCE: [u8; 8] = [2; 8];
+
+/// Bytes a seal adds to its plaintext: MAC and nonce, then the sealed footer.
+pub const SEAL_OVERHEAD: usize = 192;
+pub const SEAL_FOOTER_BYTES: usize = 168;
+
+pub struct SealVector {
+    pub plaintext: &'static [u8],
+    /// The ciphertext, its MAC and the nonce, in hex: everything before the footer.
+    pub body: &'static str,
+    /// The footer plaintext, in hex: the length of the body, little endian, and the inner key.
+    pub footer: &'static str,
+}
+
+pub const SEAL_VECTORS: &'static [SealVector] = &[
+    SealVector {
+        plaintext: b"",
+        body: "e439fb3e84dc230bba4ffe74123a324c0202020202020202",
+        footer: "18000000000000000101010101010101010101010101010101010101010101010101010101010101",
+    },
+    SealVector {
+        plaintext: b"hat seal test vector",
+        body: "c80f4afa2cc6382386c4068546b2959d70ad9c636065ca8eb158df91f62e75af6a3990b5\
+               0202020202020202",
+        footer: "2c000000000000000101010101010101010101010101010101010101010101010101010101010101",
+    },
+    SealVector {
+        plaintext: &[
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45,
+            46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67,
+            68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89,
+            90, 91, 92, 93, 94, 95, 96, 97, 98, 99,
+        ],
+        body: "a06f3cd95ba65f48aeb969fd3e9fedf703c8e102ce1f069c39ddf9724bb8775bf018b3e287dfccf4\
+               2acbe6562969ad28087a03e7827d311606fe764338c6793417dce612b586fa5c11d9c19dfc12e4\
+               132713f615f33a0a2d7871abc436c48729038e24fbd0afae456879ecc4f7e229bf0205a902020202\
+               0202020202",
+        footer: "7c000000000000000101010101010101010101010101010101010101010101010101010101010101",
+    },
+];
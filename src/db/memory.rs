@@ -0,0 +1,737 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The index kept in memory, for stores that do not outlive the process.
+//!
+//! Nothing survives a crash, so every flush trivially commits atomically.
+
+use blob;
+use chrono;
+use crypto;
+use db::{AuditEntry, Entry, GcData, MetadataStore, QueueEntry, SnapshotInfo, SnapshotStatus,
+         SnapshotWorkStatus, decode_chunk_ref, tag_to_work_status, work_status_to_tag};
+use errors::DieselError;
+use hash;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use tags;
+use util::Counter;
+
+
+struct HashRow {
+    hash: Vec<u8>,
+    tag: i64,
+    height: i64,
+    leaf_type: i64,
+    childs: Option<Vec<u64>>,
+    blob_id: i64,
+    blob_ref: Option<Vec<u8>>,
+    ready: bool,
+}
+
+struct BlobRow {
+    name: Vec<u8>,
+    tag: i32,
+}
+
+struct SnapshotRow {
+    family_id: i64,
+    snapshot_id: i64,
+    tag: i32,
+    created: chrono::DateTime<chrono::Utc>,
+    msg: Option<String>,
+    hash: Option<Vec<u8>>,
+    hash_ref: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Tables {
+    hashes: BTreeMap<u64, HashRow>,
+    hash_ids: BTreeMap<Vec<u8>, u64>,
+    gc_data: BTreeMap<(u64, u64), GcData>,
+    gc_pending: BTreeMap<u64, i64>,
+    hash_verified: BTreeMap<u64, i64>,
+    gc_runs: i64,
+    audit_log: Vec<AuditEntry>,
+    blobs: BTreeMap<i64, BlobRow>,
+    blob_checksums: BTreeMap<i64, crypto::Checksum>,
+    blob_chunk_tags: BTreeMap<i64, Vec<blob::ChunkTag>>,
+    min_reader_version: Option<i64>,
+    hash_salt: Option<Vec<u8>>,
+    // Family names; the id of a family is its position plus one.
+    families: Vec<String>,
+    snapshots: BTreeMap<i64, SnapshotRow>,
+    snapshot_keys: BTreeMap<u64, String>,
+    snapshot_chunkers: BTreeMap<u64, String>,
+    snapshot_fanouts: BTreeMap<u64, usize>,
+    snapshot_metadata_only: BTreeSet<u64>,
+    snapshot_parents: BTreeMap<u64, u64>,
+}
+
+impl Tables {
+    fn chunk_ref(&self, row: &HashRow) -> Option<blob::ChunkRef> {
+        decode_chunk_ref(
+            row.blob_ref.as_ref(),
+            self.blobs.get(&row.blob_id).map(|b| (row.blob_id, b.name.clone())),
+        )
+    }
+
+    fn entry(&self, row: &HashRow) -> Entry {
+        Entry {
+            hash: hash::Hash { bytes: row.hash.clone() },
+            node: From::from(row.height as u64),
+            leaf: From::from(row.leaf_type as u64),
+            childs: row.childs.clone(),
+            persistent_ref: self.chunk_ref(row),
+            ready: row.ready,
+        }
+    }
+
+    fn family_id(&self, name: &str) -> Option<i64> {
+        self.families.iter().position(|f| f == name).map(|i| i as i64 + 1)
+    }
+
+    fn family_id_or_create(&mut self, name: &str) -> i64 {
+        match self.family_id(name) {
+            Some(id) => id,
+            None => {
+                self.families.push(name.to_owned());
+                self.families.len() as i64
+            }
+        }
+    }
+
+    fn snapshot(
+        &self,
+        unique_id: i64,
+        row: &SnapshotRow,
+    ) -> (SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>) {
+        (
+            SnapshotInfo {
+                unique_id: unique_id as u64,
+                family_id: row.family_id as u64,
+                snapshot_id: row.snapshot_id as u64,
+            },
+            hash::Hash { bytes: row.hash.clone().expect("Snapshot without top hash") },
+            row.hash_ref.as_ref().and_then(|r| {
+                hash::tree::HashRef::from_bytes(&mut &r[..]).ok()
+            }),
+        )
+    }
+
+    fn insert_snapshot(&mut self, row: SnapshotRow) -> i64 {
+        let unique_id = self.snapshots.keys().next_back().map_or(1, |id| id + 1);
+        self.snapshots.insert(unique_id, row);
+        unique_id
+    }
+}
+
+pub struct MemoryStore {
+    tables: RefCell<Tables>,
+    hash_id_counter: Counter,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            tables: RefCell::new(Tables::default()),
+            hash_id_counter: Counter::new(0),
+        }
+    }
+}
+
+impl MetadataStore for MemoryStore {
+    fn hash_locate(&mut self, hash_: &hash::Hash) -> Option<QueueEntry> {
+        assert!(!hash_.bytes.is_empty());
+        let tables = self.tables.borrow();
+        tables.hash_ids.get(&hash_.bytes).map(|id| {
+            let row = &tables.hashes[id];
+            QueueEntry {
+                id: *id,
+                node: From::from(row.height as u64),
+                leaf: From::from(row.leaf_type as u64),
+                tag: tags::tag_from_num(row.tag),
+                childs: row.childs.clone(),
+                persistent_ref: tables.chunk_ref(row),
+            }
+        })
+    }
+
+    fn hash_locate_by_id(&mut self, id_: u64) -> Option<Entry> {
+        let tables = self.tables.borrow();
+        tables.hashes.get(&id_).map(|row| tables.entry(row))
+    }
+
+    fn hash_next_id(&mut self) -> u64 {
+        self.hash_id_counter.next() as u64
+    }
+
+    fn hash_insert_new(&mut self, id_: u64, hash_bytes: Vec<u8>, entry: QueueEntry) {
+        let height_: u64 = From::from(entry.node);
+        let leaf_type_: u64 = From::from(entry.leaf);
+        let row = HashRow {
+            hash: hash_bytes.clone(),
+            tag: entry.tag.unwrap_or(tags::Tag::Done) as i64,
+            height: height_ as i64,
+            leaf_type: leaf_type_ as i64,
+            childs: entry.childs,
+            blob_id: entry.persistent_ref.as_ref().and_then(|r| r.blob_id).unwrap_or(0),
+            blob_ref: entry.persistent_ref.as_ref().map(|c| c.as_bytes_no_name()),
+            ready: false,
+        };
+
+        let mut tables = self.tables.borrow_mut();
+        assert!(!tables.hash_ids.contains_key(&hash_bytes), "Error inserting new hash");
+        assert!(!tables.hashes.contains_key(&id_), "Error inserting new hash");
+        tables.hash_ids.insert(hash_bytes, id_);
+        tables.hashes.insert(id_, row);
+    }
+
+    fn hash_set_tag(&mut self, id_opt: Option<u64>, tag_: tags::Tag) {
+        let mut tables = self.tables.borrow_mut();
+        match id_opt {
+            None => {
+                for row in tables.hashes.values_mut() {
+                    row.tag = tag_ as i64;
+                }
+            }
+            Some(id_) => {
+                if let Some(row) = tables.hashes.get_mut(&id_) {
+                    row.tag = tag_ as i64;
+                }
+            }
+        }
+    }
+
+    fn hash_delete_not_ready(&mut self) {
+        let mut tables = self.tables.borrow_mut();
+        let not_ready: Vec<(u64, Vec<u8>)> = tables
+            .hashes
+            .iter()
+            .filter(|&(_, row)| !row.ready)
+            .map(|(id, row)| (*id, row.hash.clone()))
+            .collect();
+        for (id, hash) in not_ready {
+            tables.hashes.remove(&id);
+            tables.hash_ids.remove(&hash);
+        }
+    }
+
+    fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry) {
+        let chunk_ref = entry.persistent_ref.as_ref().expect("ready");
+        let height_: u64 = From::from(entry.node);
+        let leaf_type_: u64 = From::from(entry.leaf);
+
+        let mut tables = self.tables.borrow_mut();
+        if let Some(row) = tables.hashes.get_mut(&id_) {
+            row.blob_id = chunk_ref.blob_id.expect("ready");
+            row.blob_ref = Some(chunk_ref.as_bytes_no_name());
+            row.ready = true;
+            row.height = height_ as i64;
+            row.leaf_type = leaf_type_ as i64;
+            row.childs = entry.childs.clone();
+        }
+    }
+
+    fn hash_set_persistent_ref(&mut self, id_: u64, chunk_ref: &blob::ChunkRef) {
+        let mut tables = self.tables.borrow_mut();
+        if let Some(row) = tables.hashes.get_mut(&id_) {
+            row.blob_id = chunk_ref.blob_id.expect("chunk ref without blob id");
+            row.blob_ref = Some(chunk_ref.as_bytes_no_name());
+        }
+    }
+
+    fn hash_get_tag(&mut self, id_: u64) -> Option<tags::Tag> {
+        self.tables.borrow().hashes.get(&id_).and_then(|row| tags::tag_from_num(row.tag))
+    }
+
+    fn hash_list_ids_by_tag(&mut self, tag_: u64) -> Vec<u64> {
+        // Top-down, like the SQLite store; safe deletion depends on it.
+        let tables = self.tables.borrow();
+        let mut rows: Vec<(i64, u64)> = tables
+            .hashes
+            .iter()
+            .filter(|&(_, row)| row.tag == tag_ as i64)
+            .map(|(id, row)| (row.height, *id))
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        rows.into_iter().map(|(_, id)| id).collect()
+    }
+
+    fn hash_read_gc_data(&mut self, hash_id_: u64, family_id_: u64) -> GcData {
+        self.tables
+            .borrow()
+            .gc_data
+            .get(&(hash_id_, family_id_))
+            .cloned()
+            .unwrap_or(GcData {
+                num: 0,
+                bytes: vec![],
+            })
+    }
+
+    fn hash_set_gc_data(&mut self, hash_id_: u64, family_id_: u64, data: GcData) {
+        self.tables.borrow_mut().gc_data.insert((hash_id_, family_id_), data);
+    }
+
+    fn hash_delete_gc_data(&mut self, hash_id_: u64, family_id_: u64) {
+        self.tables.borrow_mut().gc_data.remove(&(hash_id_, family_id_));
+    }
+
+    fn hash_list_gc_data_ids(&mut self, family_id_: u64) -> Vec<u64> {
+        self.tables
+            .borrow()
+            .gc_data
+            .keys()
+            .filter(|&&(_, family)| family == family_id_)
+            .map(|&(hash_id, _)| hash_id)
+            .collect()
+    }
+
+    fn hash_list_from(&mut self, from: &[u8], limit: i64) -> Vec<Vec<u8>> {
+        self.tables
+            .borrow()
+            .hash_ids
+            .range(from.to_vec()..)
+            .take(limit as usize)
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    fn hash_list(&mut self) -> Vec<Entry> {
+        let tables = self.tables.borrow();
+        tables.hashes.values().map(|row| tables.entry(row)).collect()
+    }
+
+    fn hash_delete(&mut self, id_: u64) {
+        let mut tables = self.tables.borrow_mut();
+        if let Some(row) = tables.hashes.remove(&id_) {
+            tables.hash_ids.remove(&row.hash);
+        }
+        let gc_keys: Vec<(u64, u64)> = tables
+            .gc_data
+            .keys()
+            .filter(|&&(hash_id, _)| hash_id == id_)
+            .cloned()
+            .collect();
+        for key in gc_keys {
+            tables.gc_data.remove(&key);
+        }
+        tables.gc_pending.remove(&id_);
+        tables.hash_verified.remove(&id_);
+    }
+
+    fn hash_gc_mark(&mut self, id_: u64, utc: i64) -> i64 {
+        *self.tables.borrow_mut().gc_pending.entry(id_).or_insert(utc)
+    }
+
+    fn hash_gc_unmark(&mut self, id_: u64) {
+        self.tables.borrow_mut().gc_pending.remove(&id_);
+    }
+
+    fn hash_gc_marked(&mut self) -> Vec<u64> {
+        self.tables.borrow().gc_pending.keys().cloned().collect()
+    }
+
+    fn hash_set_verified(&mut self, id_: u64, utc: i64) {
+        self.tables.borrow_mut().hash_verified.insert(id_, utc);
+    }
+
+    fn hash_list_verified(&mut self) -> Vec<(u64, i64)> {
+        self.tables.borrow().hash_verified.iter().map(|(&id, &utc)| (id, utc)).collect()
+    }
+
+    fn maybe_flush(&mut self) {}
+
+    fn set_auto_flush(&mut self, _enabled: bool) {}
+
+    fn flush(&mut self) {}
+
+    fn export(&mut self) -> Result<Vec<u8>, DieselError> {
+        Err(From::from("An index kept in memory can not be exported"))
+    }
+
+    fn import(&mut self, _dump: &[u8]) -> Result<(), DieselError> {
+        Err(From::from("An index kept in memory can not be imported into"))
+    }
+
+    fn blob_next_id(&mut self) -> i64 {
+        self.tables.borrow().blobs.keys().next_back().cloned().unwrap_or(0)
+    }
+
+    fn blob_in_air(&mut self, blob: &blob::BlobDesc) {
+        let mut tables = self.tables.borrow_mut();
+        assert!(!tables.blobs.contains_key(&blob.id), "Error inserting blob");
+        tables.blobs.insert(
+            blob.id,
+            BlobRow {
+                name: blob.name.clone(),
+                tag: tags::Tag::InProgress as i32,
+            },
+        );
+    }
+
+    fn blob_commit(&mut self, blob: &blob::BlobDesc) {
+        if let Some(row) = self.tables.borrow_mut().blobs.get_mut(&blob.id) {
+            row.tag = tags::Tag::Done as i32;
+        }
+    }
+
+    fn blob_id_from_name(&self, name_: &[u8]) -> Option<i64> {
+        self.tables
+            .borrow()
+            .blobs
+            .iter()
+            .find(|&(_, row)| &row.name[..] == name_)
+            .map(|(id, _)| *id)
+    }
+
+    fn blob_name_from_id(&self, id_: i64) -> Option<Vec<u8>> {
+        self.tables.borrow().blobs.get(&id_).map(|row| row.name.clone())
+    }
+
+    fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>) {
+        let mut tables = self.tables.borrow_mut();
+        for (id, row) in tables.blobs.iter_mut() {
+            let matches = match target {
+                None => true,
+                Some(t) if t.id > 0 => *id == t.id,
+                Some(t) if !t.name.is_empty() => row.name == t.name,
+                Some(t) => {
+                    unreachable!(
+                        "blob with neither id nor name: id={}, name={}",
+                        t.id,
+                        t.name.len()
+                    )
+                }
+            };
+            if matches {
+                row.tag = tag_ as i32;
+            }
+        }
+    }
+
+    fn blob_delete(&self, blob: &blob::BlobDesc) {
+        let mut tables = self.tables.borrow_mut();
+        tables.blobs.remove(&blob.id);
+        tables.blob_checksums.remove(&blob.id);
+        tables.blob_chunk_tags.remove(&blob.id);
+    }
+
+    fn blob_delete_by_tag(&self, tag_: tags::Tag) {
+        let mut tables = self.tables.borrow_mut();
+        let ids: Vec<i64> = tables
+            .blobs
+            .iter()
+            .filter(|&(_, row)| row.tag == tag_ as i32)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            tables.blobs.remove(&id);
+            tables.blob_checksums.remove(&id);
+            tables.blob_chunk_tags.remove(&id);
+        }
+    }
+
+    fn blob_set_checksum(&self, blob: &blob::BlobDesc, checksum: &crypto::Checksum) {
+        self.tables.borrow_mut().blob_checksums.insert(blob.id, *checksum);
+    }
+
+    fn blob_checksum(&self, blob: &blob::BlobDesc) -> Option<crypto::Checksum> {
+        self.tables.borrow().blob_checksums.get(&blob.id).cloned()
+    }
+
+    fn blob_set_chunk_tags(&self, blob: &blob::BlobDesc, tags: &[blob::ChunkTag]) {
+        self.tables.borrow_mut().blob_chunk_tags.insert(blob.id, tags.to_vec());
+    }
+
+    fn blob_chunk_tags(&self, blob: &blob::BlobDesc) -> Vec<blob::ChunkTag> {
+        self.tables.borrow().blob_chunk_tags.get(&blob.id).cloned().unwrap_or_else(Vec::new)
+    }
+
+    fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc> {
+        self.tables
+            .borrow()
+            .blobs
+            .iter()
+            .rev()
+            .filter(|&(_, row)| row.tag == tag_ as i32)
+            .map(|(id, row)| {
+                blob::BlobDesc {
+                    id: *id,
+                    name: row.name.clone(),
+                }
+            })
+            .collect()
+    }
+
+    fn gc_run_record(&mut self, _utc: i64) {
+        self.tables.borrow_mut().gc_runs += 1;
+    }
+
+    fn gc_generation(&mut self) -> i64 {
+        self.tables.borrow().gc_runs
+    }
+
+    fn audit_append(&mut self, entry: &AuditEntry) {
+        self.tables.borrow_mut().audit_log.push(entry.clone());
+    }
+
+    fn audit_last(&mut self) -> Option<AuditEntry> {
+        self.tables.borrow().audit_log.last().cloned()
+    }
+
+    fn audit_list(&mut self) -> Vec<AuditEntry> {
+        self.tables.borrow().audit_log.clone()
+    }
+
+    fn store_min_reader_version(&mut self) -> Option<i64> {
+        self.tables.borrow().min_reader_version
+    }
+
+    fn store_set_min_reader_version(&mut self, version: i64) {
+        self.tables.borrow_mut().min_reader_version = Some(version);
+    }
+
+    fn store_hash_salt(&mut self) -> Option<Vec<u8>> {
+        self.tables.borrow().hash_salt.clone()
+    }
+
+    fn store_set_hash_salt(&mut self, salt: &[u8]) {
+        let mut tables = self.tables.borrow_mut();
+        assert!(tables.hash_salt.is_none(), "Error inserting store hash salt");
+        tables.hash_salt = Some(salt.to_vec());
+    }
+
+    fn snapshot_delete(&self, info: SnapshotInfo) {
+        let mut tables = self.tables.borrow_mut();
+        let unique_id = info.unique_id as i64;
+        let matches = tables.snapshots.get(&unique_id).map_or(false, |row| {
+            row.family_id == info.family_id as i64 && row.snapshot_id == info.snapshot_id as i64
+        });
+        if matches {
+            tables.snapshots.remove(&unique_id);
+        }
+        tables.snapshot_keys.remove(&info.unique_id);
+        tables.snapshot_chunkers.remove(&info.unique_id);
+        tables.snapshot_fanouts.remove(&info.unique_id);
+        tables.snapshot_metadata_only.remove(&info.unique_id);
+        tables.snapshot_parents.remove(&info.unique_id);
+    }
+
+    fn snapshot_set_key_id(&self, info: &SnapshotInfo, key_id_: &str) {
+        self.tables.borrow_mut().snapshot_keys.insert(info.unique_id, key_id_.to_owned());
+    }
+
+    fn snapshot_key_id(&self, info: &SnapshotInfo) -> Option<String> {
+        self.tables.borrow().snapshot_keys.get(&info.unique_id).cloned()
+    }
+
+    fn snapshot_set_chunker(&self, info: &SnapshotInfo, chunker_: &str) {
+        self.tables.borrow_mut().snapshot_chunkers.insert(info.unique_id, chunker_.to_owned());
+    }
+
+    fn snapshot_chunker(&self, info: &SnapshotInfo) -> Option<String> {
+        self.tables.borrow().snapshot_chunkers.get(&info.unique_id).cloned()
+    }
+
+    fn snapshot_chunkers(&self) -> Vec<String> {
+        let mut chunkers: Vec<String> =
+            self.tables.borrow().snapshot_chunkers.values().cloned().collect();
+        chunkers.sort();
+        chunkers.dedup();
+        chunkers
+    }
+
+    fn snapshot_set_fanout(&self, info: &SnapshotInfo, fanout_: usize) {
+        self.tables.borrow_mut().snapshot_fanouts.insert(info.unique_id, fanout_);
+    }
+
+    fn snapshot_fanout(&self, info: &SnapshotInfo) -> Option<usize> {
+        self.tables.borrow().snapshot_fanouts.get(&info.unique_id).cloned()
+    }
+
+    fn snapshot_set_metadata_only(&self, info: &SnapshotInfo) {
+        self.tables.borrow_mut().snapshot_metadata_only.insert(info.unique_id);
+    }
+
+    fn snapshot_metadata_only(&self, info: &SnapshotInfo) -> bool {
+        self.tables.borrow().snapshot_metadata_only.contains(&info.unique_id)
+    }
+
+    fn snapshot_set_parent(&self, info: &SnapshotInfo, parent_id_: u64) {
+        self.tables.borrow_mut().snapshot_parents.insert(info.unique_id, parent_id_);
+    }
+
+    fn snapshot_parent(&self, info: &SnapshotInfo) -> Option<u64> {
+        self.tables.borrow().snapshot_parents.get(&info.unique_id).cloned()
+    }
+
+    fn snapshot_lookup(
+        &mut self,
+        family_name_: &str,
+        snapshot_id_: u64,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
+        let tables = self.tables.borrow();
+        let family_id_ = match tables.family_id(family_name_) {
+            Some(id) => id,
+            None => return None,
+        };
+        tables
+            .snapshots
+            .iter()
+            .find(|&(_, row)| {
+                row.family_id == family_id_ && row.snapshot_id == snapshot_id_ as i64
+            })
+            .map(|(id, row)| tables.snapshot(*id, row))
+    }
+
+    fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo {
+        let mut tables = self.tables.borrow_mut();
+        let family_id_ = tables.family_id_or_create(&family_);
+        let snapshot_id_ = 1 +
+            tables
+                .snapshots
+                .values()
+                .filter(|row| row.family_id == family_id_)
+                .map(|row| row.snapshot_id)
+                .max()
+                .unwrap_or(0);
+
+        let unique_id_ = tables.insert_snapshot(SnapshotRow {
+            family_id: family_id_,
+            snapshot_id: snapshot_id_,
+            tag: tags::Tag::Reserved as i32,
+            created: chrono::Utc::now(),
+            msg: None,
+            hash: None,
+            hash_ref: None,
+        });
+
+        SnapshotInfo {
+            unique_id: unique_id_ as u64,
+            family_id: family_id_ as u64,
+            snapshot_id: snapshot_id_ as u64,
+        }
+    }
+
+    fn snapshot_update(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        msg_: &str,
+        hash_: &hash::Hash,
+        hash_ref_: &hash::tree::HashRef,
+    ) {
+        let mut tables = self.tables.borrow_mut();
+        if let Some(row) = tables.snapshots.get_mut(&(snapshot_.unique_id as i64)) {
+            row.msg = Some(msg_.to_owned());
+            row.hash = Some(hash_.bytes.clone());
+            row.hash_ref = Some(hash_ref_.as_bytes());
+        }
+    }
+
+    fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag) {
+        let mut tables = self.tables.borrow_mut();
+        if let Some(row) = tables.snapshots.get_mut(&(snapshot_.unique_id as i64)) {
+            row.tag = tag_ as i32;
+        }
+    }
+
+    fn snapshot_latest(
+        &mut self,
+        family: &str,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
+        let tables = self.tables.borrow();
+        let family_id_ = match tables.family_id(family) {
+            Some(id) => id,
+            None => return None,
+        };
+        let uncommitted = [
+            tags::Tag::Reserved as i32,
+            tags::Tag::InProgress as i32,
+            tags::Tag::RecoverInProgress as i32,
+        ];
+        tables
+            .snapshots
+            .iter()
+            .filter(|&(_, row)| {
+                row.family_id == family_id_ && !uncommitted.contains(&row.tag)
+            })
+            .max_by_key(|&(_, row)| row.snapshot_id)
+            .map(|(id, row)| tables.snapshot(*id, row))
+    }
+
+    fn snapshot_list(&mut self, skip_tag: Option<tags::Tag>) -> Vec<SnapshotStatus> {
+        let tables = self.tables.borrow();
+        tables
+            .snapshots
+            .iter()
+            .filter(|&(_, row)| skip_tag.map_or(true, |skip| row.tag != skip as i32))
+            .map(|(id, row)| {
+                SnapshotStatus {
+                    family_name: tables.families[row.family_id as usize - 1].clone(),
+                    created: row.created,
+                    msg: row.msg.clone(),
+                    hash: row.hash.as_ref().and_then(|bytes| if bytes.is_empty() {
+                        None
+                    } else {
+                        Some(hash::Hash { bytes: bytes.clone() })
+                    }),
+                    hash_ref: row.hash_ref.clone(),
+                    status: tags::tag_from_num(row.tag as i64).map_or(
+                        SnapshotWorkStatus::CommitComplete,
+                        tag_to_work_status,
+                    ),
+                    info: SnapshotInfo {
+                        unique_id: *id as u64,
+                        snapshot_id: row.snapshot_id as u64,
+                        family_id: row.family_id as u64,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn snapshot_recover(
+        &mut self,
+        snapshot_id_: u64,
+        family: &str,
+        created: chrono::DateTime<chrono::Utc>,
+        msg_: &str,
+        hash_ref_: &hash::tree::HashRef,
+        work_opt_: Option<SnapshotWorkStatus>,
+    ) {
+        let family_id_ = self.tables.borrow_mut().family_id_or_create(family);
+        let insert = match self.snapshot_lookup(family, snapshot_id_) {
+            Some((_info, h, _r)) => {
+                if h.bytes != hash_ref_.hash.bytes {
+                    panic!("Snapshot already exists, but with different hash");
+                }
+                false
+            }
+            None => true,
+        };
+        if insert {
+            self.tables.borrow_mut().insert_snapshot(SnapshotRow {
+                family_id: family_id_,
+                snapshot_id: snapshot_id_ as i64,
+                tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
+                created: created,
+                msg: Some(msg_.to_owned()),
+                hash: Some(hash_ref_.hash.bytes.clone()),
+                hash_ref: Some(hash_ref_.as_bytes()),
+            });
+        }
+    }
+}
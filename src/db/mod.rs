@@ -12,88 +12,59 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Communication with SQLite.
+//! The local index of hashes, blobs and snapshots.
+//!
+//! All access goes through a `MetadataStore`. `SqliteStore` keeps the index in a single SQLite
+//! file; `MemoryStore` keeps it in memory.
 
 
 use blob;
-
-use capnp;
 use chrono;
-
-use diesel;
-use diesel::connection::TransactionManager;
-use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use crypto;
 use errors::DieselError;
-
 use hash;
-use root_capnp;
-use std::sync::{Mutex, MutexGuard};
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
 use tags;
-use time::Duration;
-use util::{Counter, InfoWriter, PeriodicTimer};
 
+mod memory;
 mod schema;
+mod sqlite;
+
+pub use self::memory::MemoryStore;
+pub use self::sqlite::SqliteStore;
 
 
-pub struct Index(Mutex<InternalIndex>);
-pub type IndexGuard<'a> = MutexGuard<'a, InternalIndex>;
+pub struct Index(Mutex<Box<MetadataStore>>);
+pub type IndexGuard<'a> = MutexGuard<'a, Box<MetadataStore>>;
 
 impl Index {
     pub fn new(migrations_dir: &Path, path: &str) -> Result<Index, DieselError> {
-        Ok(Index(Mutex::new(InternalIndex::new(migrations_dir, path)?)))
+        Ok(Index::with_store(Box::new(SqliteStore::new(migrations_dir, path)?)))
+    }
+    pub fn with_store(store: Box<MetadataStore>) -> Index {
+        Index(Mutex::new(store))
     }
-    pub fn lock(&self) -> MutexGuard<InternalIndex> {
+    pub fn lock(&self) -> IndexGuard {
         self.0.lock().expect("Database mutex is poisoned")
     }
     #[cfg(test)]
     pub fn new_for_testing() -> Index {
-        Index(Mutex::new(
-            InternalIndex::new(Path::new("migrations"), ":memory:")
-                .unwrap(),
-        ))
+        Index::new(Path::new("migrations"), ":memory:").unwrap()
     }
 }
 
 
-fn encode_childs(childs: &[u64]) -> Vec<u8> {
-    let mut message = capnp::message::Builder::new_default();
-    {
-        let root = message.init_root::<root_capnp::hash_ids::Builder>();
-        let mut list = root.init_hash_ids(childs.len() as u32);
-        for (i, id) in childs.iter().enumerate() {
-            list.set(i as u32, *id);
-        }
-    }
-    let mut out = Vec::new();
-    capnp::serialize_packed::write_message(&mut out, &message).unwrap();
-    out
-}
-
-fn decode_childs(bytes: &[u8]) -> Result<Vec<u64>, capnp::Error> {
-    let reader = capnp::serialize_packed::read_message(
-        &mut &bytes[..],
-        capnp::message::ReaderOptions::new(),
-    ).unwrap();
-    let msg = reader.get_root::<root_capnp::hash_ids::Reader>().unwrap();
-
-    let ids = msg.get_hash_ids()?;
-    let mut out = Vec::new();
-    for i in 0..ids.len() {
-        out.push(ids.get(i));
-    }
-    Ok(out)
-}
-
 fn decode_chunk_ref(
     cref: Option<&Vec<u8>>,
-    blob: Option<self::schema::Blob>,
+    blob: Option<(i64, Vec<u8>)>,
 ) -> Option<blob::ChunkRef> {
     cref.map(|c| {
         let mut r = blob::ChunkRef::from_bytes(&mut &c[..]).expect("Failed to decode chunk");
         if r.length > 0 {
-            r.blob_name = blob.expect("Non-empty chunk without blob name").name;
+            let (id, name) = blob.expect("Non-empty chunk without blob name");
+            r.blob_id = Some(id);
+            r.blob_name = name;
         } else {
             r.blob_name = vec![0];
         }
@@ -120,6 +91,19 @@ pub struct SnapshotInfo {
     pub snapshot_id: u64,
 }
 
+/// An entry of the audit log of changes to the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEntry {
+    /// Position in the log, counting from 1.
+    pub seq: i64,
+    pub utc: i64,
+    pub operation: String,
+    /// The ids of what was changed; their kind depends on the operation.
+    pub affected: Vec<i64>,
+    /// Hash of this entry together with the chain hash of the entry before it.
+    pub chain: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub enum SnapshotWorkStatus {
     CommitInProgress,
@@ -191,277 +175,165 @@ pub struct QueueEntry {
     pub tag: Option<tags::Tag>,
 }
 
-pub struct InternalIndex {
-    conn: SqliteConnection,
-    hash_id_counter: Counter,
-    flush_timer: PeriodicTimer,
-    flush_periodically: bool,
-}
-
-
-impl InternalIndex {
-    fn new(migrations_dir: &Path, path: &str) -> Result<InternalIndex, DieselError> {
-        let conn = SqliteConnection::establish(path)?;
-
-        let mut idx = InternalIndex {
-            conn: conn,
-            hash_id_counter: Counter::new(0),
-            flush_timer: PeriodicTimer::new(Duration::seconds(10)),
-            flush_periodically: true,
-        };
-
-        diesel::migrations::run_pending_migrations_in_directory(
-            &idx.conn,
-            &migrations_dir,
-            &mut InfoWriter,
-        )?;
-
-        {
-            let tm = idx.conn.transaction_manager();
-            tm.begin_transaction(&idx.conn)?;
-        }
-
-        idx.hash_refresh_id_counter();
-        Ok(idx)
-    }
-
-    pub fn hash_locate(&mut self, hash_: &hash::Hash) -> Option<QueueEntry> {
-        assert!(!hash_.bytes.is_empty());
-        use self::schema::hashes::dsl::*;
-        use self::schema::blobs::dsl::blobs;
-
-        let result_opt = hashes
-            .left_outer_join(blobs)
-            .filter(hash.eq(&hash_.bytes))
-            .first::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
-            .optional()
-            .expect("Error querying hashes");
-
-        result_opt.map(|(hash_, blob_)| {
-            let childs_ = hash_.childs.and_then(|b| if b.is_empty() {
-                None
-            } else {
-                Some(decode_childs(&b).unwrap())
-            });
-            let persistent_ref = decode_chunk_ref(hash_.blob_ref.as_ref(), blob_);
-            QueueEntry {
-                id: hash_.id as u64,
-                node: From::from(hash_.height as u64),
-                leaf: From::from(hash_.leaf_type as u64),
-                tag: tags::tag_from_num(hash_.tag),
-                childs: childs_,
-                persistent_ref: persistent_ref,
-            }
-        })
-    }
-
-    pub fn hash_locate_by_id(&mut self, id_: u64) -> Option<Entry> {
-        use self::schema::hashes::dsl::*;
-        use self::schema::blobs::dsl::blobs;
-
-        let result_opt = hashes
-            .left_outer_join(blobs)
-            .filter(id.eq(id_ as i64))
-            .first::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
-            .optional()
-            .expect("Error querying hashes");
-
-        result_opt.map(|(hash_, blob_)| {
-            Entry {
-                hash: self::hash::Hash { bytes: hash_.hash },
-                node: From::from(hash_.height as u64),
-                leaf: From::from(hash_.leaf_type as u64),
-                childs: hash_.childs.and_then(|p| if p.is_empty() {
-                    None
-                } else {
-                    Some(decode_childs(&p).unwrap())
-                }),
-                persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_),
-                ready: hash_.ready,
-            }
-        })
-    }
-
-    pub fn hash_refresh_id_counter(&mut self) {
-        use self::schema::hashes::dsl::*;
-        use diesel::expression::max;
-
-        let id_opt = hashes
-            .select(max(id))
-            .first::<Option<i64>>(&self.conn)
-            .expect("Error selecting max hash id");
-
-        self.hash_id_counter = Counter::new(id_opt.unwrap_or(0));
-    }
-
-    pub fn hash_next_id(&mut self) -> u64 {
-        self.hash_id_counter.next() as u64
-    }
-
-    pub fn hash_insert_new(&mut self, id_: u64, hash_bytes: Vec<u8>, entry: QueueEntry) {
-        use self::schema::hashes::dsl::*;
-
-        let blob_ref_ = entry.persistent_ref.as_ref().map(|c| c.as_bytes_no_name());
-        let childs_ = entry.childs.as_ref().map(|v| encode_childs(&v[..]));
-
-        let height_: u64 = From::from(entry.node);
-        let leaf_type_: u64 = From::from(entry.leaf);
-
-        let new = schema::NewHash {
-            id: id_ as i64,
-            hash: &hash_bytes,
-            tag: entry.tag.unwrap_or(tags::Tag::Done) as i64,
-            height: height_ as i64,
-            leaf_type: leaf_type_ as i64,
-            childs: childs_.as_ref().map(|v| &v[..]),
-            blob_id: entry.persistent_ref.and_then(|r| r.blob_id).unwrap_or(0),
-            blob_ref: blob_ref_.as_ref().map(|v| &v[..]),
-            ready: false,
-        };
-
-        diesel::insert(&new)
-            .into(hashes)
-            .execute(&self.conn)
-            .expect("Error inserting new hash");
-    }
-
-    pub fn hash_set_tag(&mut self, id_opt: Option<u64>, tag_: tags::Tag) {
-        use self::schema::hashes::dsl::*;
-
-        match id_opt {
-            None => {
-                diesel::update(hashes)
-                    .set(tag.eq(tag_ as i64))
-                    .execute(&self.conn)
-                    .expect("Error updating hash tags")
-            }
-            Some(id_) => {
-                diesel::update(hashes.find(id_ as i64))
-                    .set(tag.eq(tag_ as i64))
-                    .execute(&self.conn)
-                    .expect("Error updating specific hash tag")
-            }
-        };
-    }
-
-    pub fn hash_delete_not_ready(&mut self) {
-        use self::schema::hashes::dsl::*;
-        diesel::delete(hashes.filter(ready.eq(false)))
-            .execute(&self.conn)
-            .expect("Failed to delete non-ready hashes");
-    }
-
-    pub fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry) {
-        use self::schema::hashes::dsl::*;
-        let blob_ref_ = entry
-            .persistent_ref
-            .as_ref()
-            .expect("ready")
-            .as_bytes_no_name();
-        let blob_id_ = entry
-            .persistent_ref
-            .as_ref()
-            .expect("ready")
-            .blob_id
-            .expect("ready");
-        let childs_ = entry.childs.as_ref().map(|v| encode_childs(&v[..]));
-
-        let height_: u64 = From::from(entry.node);
-        let leaf_type_: u64 = From::from(entry.leaf);
-
-        diesel::update(hashes.find(id_ as i64))
-            .set((
-                blob_id.eq(blob_id_),
-                blob_ref.eq(&blob_ref_[..]),
-                ready.eq(true),
-                height.eq(height_ as i64),
-                leaf_type.eq(leaf_type_ as i64),
-                childs.eq(childs_.as_ref().map(|v| &v[..])),
-            ))
-            .execute(&self.conn)
-            .expect("Failed to set hash ready");
-    }
-
-    pub fn hash_get_tag(&mut self, id_: u64) -> Option<tags::Tag> {
-        use self::schema::hashes::dsl::*;
-
-        let tag_opt = hashes
-            .find(id_ as i64)
-            .select(tag)
-            .first::<i64>(&self.conn)
-            .optional()
-            .expect("Error querying hash tag");
-
-        tag_opt.and_then(tags::tag_from_num)
-    }
-
-    pub fn hash_list_ids_by_tag(&mut self, tag_: u64) -> Vec<u64> {
-        // We list hashes top-down.
-        // This is required for safe deletion.
-        // TODO(jos): consider moving this requirement closer to the code that needs it.
-        use self::schema::hashes::dsl::*;
-
-        hashes
-            .filter(tag.eq(tag_ as i64))
-            .order(height.desc())
-            .select(id)
-            .load::<i64>(&self.conn)
-            .expect("Error listing hashes")
-            .into_iter()
-            .map(|i| i as u64)
-            .collect()
-    }
-
-    pub fn hash_read_gc_data(&mut self, hash_id_: u64, family_id_: u64) -> GcData {
-        use self::schema::gc_metadata::dsl::*;
 
-        let result_opt = gc_metadata
-            .filter(hash_id.eq(hash_id_ as i64))
-            .filter(family_id.eq(family_id_ as i64))
-            .first::<schema::GcMetadata>(&self.conn)
-            .optional()
-            .expect("Error querying GC metadata");
-        match result_opt {
-            None => {
-                GcData {
-                    num: 0,
-                    bytes: vec![],
-                }
-            }
-            Some(row) => {
-                GcData {
-                    num: row.gc_int,
-                    bytes: row.gc_vec,
-                }
-            }
-        }
-    }
-
-    pub fn hash_set_gc_data(&mut self, hash_id_: u64, family_id_: u64, data: GcData) {
-        use self::schema::gc_metadata::dsl::*;
-
-        let count = diesel::update(gc_metadata.filter(hash_id.eq(hash_id_ as i64)).filter(
-            family_id.eq(family_id_ as i64),
-        )).set((gc_int.eq(data.num), gc_vec.eq(&data.bytes)))
-            .execute(&self.conn)
-            .expect("Error updating GC metadata");
-        assert!(count <= 1);
-
-        if count == 0 {
-            let new = schema::NewGcMetadata {
-                hash_id: hash_id_ as i64,
-                family_id: family_id_ as i64,
-                gc_int: data.num,
-                gc_vec: &data.bytes,
-            };
-
-            diesel::insert(&new)
-                .into(gc_metadata)
-                .execute(&self.conn)
-                .expect("Error inserting GC metadata");
-        }
-    }
+/// Storage for the index.
+///
+/// Every change is made in an open transaction, and reads see the changes made so far. `flush`
+/// commits the transaction and starts the next one as a single atomic step: after a crash, the
+/// store holds exactly the changes up to one of the flushes. Implementations may also commit
+/// from `maybe_flush`, unless that was turned off with `set_auto_flush(false)`; callers that
+/// need several changes to land together turn it off and flush themselves.
+pub trait MetadataStore: Send {
+    /// The hash with the given bytes, if it is known.
+    fn hash_locate(&mut self, hash_: &hash::Hash) -> Option<QueueEntry>;
+    fn hash_locate_by_id(&mut self, id_: u64) -> Option<Entry>;
+    /// A fresh hash id, higher than any in the index.
+    fn hash_next_id(&mut self) -> u64;
+    /// Insert a hash that is not ready yet, i.e. whose data is not known to be stored.
+    fn hash_insert_new(&mut self, id_: u64, hash_bytes: Vec<u8>, entry: QueueEntry);
+    /// Tag one hash, or all of them if `id_opt` is `None`.
+    fn hash_set_tag(&mut self, id_opt: Option<u64>, tag_: tags::Tag);
+    fn hash_delete_not_ready(&mut self);
+    fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry);
+    /// Point a hash at another copy of its data.
+    fn hash_set_persistent_ref(&mut self, id_: u64, chunk_ref: &blob::ChunkRef);
+    fn hash_get_tag(&mut self, id_: u64) -> Option<tags::Tag>;
+    /// The ids of hashes with the given tag, parents before their children.
+    fn hash_list_ids_by_tag(&mut self, tag_: u64) -> Vec<u64>;
+    fn hash_read_gc_data(&mut self, hash_id_: u64, family_id_: u64) -> GcData;
+    fn hash_set_gc_data(&mut self, hash_id_: u64, family_id_: u64, data: GcData);
+    fn hash_delete_gc_data(&mut self, hash_id_: u64, family_id_: u64);
+    /// The hashes with GC data for the family.
+    fn hash_list_gc_data_ids(&mut self, family_id_: u64) -> Vec<u64>;
+    /// List up to `limit` hashes that sort at or after `from`, in order.
+    fn hash_list_from(&mut self, from: &[u8], limit: i64) -> Vec<Vec<u8>>;
+    fn hash_list(&mut self) -> Vec<Entry>;
+    /// Delete a hash with its GC data, mark and time of verification.
+    fn hash_delete(&mut self, id_: u64);
+    /// Remember that the GC found a hash unused at `utc`, unless it was already marked.
+    /// Returns the time of the earliest mark.
+    fn hash_gc_mark(&mut self, id_: u64, utc: i64) -> i64;
+    fn hash_gc_unmark(&mut self, id_: u64);
+    fn hash_gc_marked(&mut self) -> Vec<u64>;
+    /// Remember that the data of a hash was read back and checked at `utc`.
+    fn hash_set_verified(&mut self, id_: u64, utc: i64);
+    /// When the data of each hash was last checked, as (hash id, utc). Hashes never checked are
+    /// not listed.
+    fn hash_list_verified(&mut self) -> Vec<(u64, i64)>;
+
+    fn maybe_flush(&mut self);
+    fn set_auto_flush(&mut self, enabled: bool);
+    /// Commit the open transaction and start a new one.
+    fn flush(&mut self);
+    /// A copy of the whole index, for `import`. Commits.
+    fn export(&mut self) -> Result<Vec<u8>, DieselError>;
+    /// Replace the contents of the index with a copy made by `export`, all at once. Commits.
+    fn import(&mut self, dump: &[u8]) -> Result<(), DieselError>;
+
+    /// The highest blob id in use, or 0.
+    fn blob_next_id(&mut self) -> i64;
+    /// Record a blob that is being uploaded. Commits.
+    fn blob_in_air(&mut self, blob: &blob::BlobDesc);
+    /// Record that a blob was uploaded. Commits.
+    fn blob_commit(&mut self, blob: &blob::BlobDesc);
+    fn blob_id_from_name(&self, name_: &[u8]) -> Option<i64>;
+    fn blob_name_from_id(&self, id_: i64) -> Option<Vec<u8>>;
+    /// Tag one blob, by id or else by name, or all of them if `target` is `None`.
+    fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>);
+    fn blob_delete(&self, blob: &blob::BlobDesc);
+    fn blob_delete_by_tag(&self, tag_: tags::Tag);
+    /// Record the checksum of a blob as it was handed to the backend.
+    fn blob_set_checksum(&self, blob: &blob::BlobDesc, checksum: &crypto::Checksum);
+    /// The checksum recorded for a blob. Blobs written by older versions of hat have none.
+    fn blob_checksum(&self, blob: &blob::BlobDesc) -> Option<crypto::Checksum>;
+    /// Record the integrity tags of the chunks in a blob.
+    fn blob_set_chunk_tags(&self, blob: &blob::BlobDesc, tags: &[blob::ChunkTag]);
+    /// The integrity tags recorded for the chunks in a blob, if any.
+    fn blob_chunk_tags(&self, blob: &blob::BlobDesc) -> Vec<blob::ChunkTag>;
+    /// The blobs with the given tag, newest first.
+    fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc>;
+
+    /// Record that the GC deleted data, or that chunks were moved to other blobs, which
+    /// invalidates anything that remembers what the store contains, like verification
+    /// checkpoints.
+    fn gc_run_record(&mut self, utc_: i64);
+    /// The id of the latest `gc_run_record`, or 0 before the first. It changes with every
+    /// record, so comparing it tells whether data was deleted or moved in between.
+    fn gc_generation(&mut self) -> i64;
+    /// Add an entry to the end of the audit log. Entries are never changed or removed.
+    fn audit_append(&mut self, entry: &AuditEntry);
+    /// The last entry of the audit log.
+    fn audit_last(&mut self) -> Option<AuditEntry>;
+    /// The whole audit log, oldest first.
+    fn audit_list(&mut self) -> Vec<AuditEntry>;
+    /// The oldest store format version that can read this store, if one has been recorded.
+    fn store_min_reader_version(&mut self) -> Option<i64>;
+    fn store_set_min_reader_version(&mut self, version: i64);
+    /// The secret salt that content hashes of this store are keyed with, if it has one.
+    fn store_hash_salt(&mut self) -> Option<Vec<u8>>;
+    /// Record the salt of the store. A store has at most one, which never changes.
+    fn store_set_hash_salt(&mut self, salt: &[u8]);
+
+    /// Delete a snapshot with its key, chunker, fan-out and parent.
+    fn snapshot_delete(&self, info: SnapshotInfo);
+    /// Record the id of the key that seals a snapshot.
+    fn snapshot_set_key_id(&self, info: &SnapshotInfo, key_id_: &str);
+    /// The id of the key that seals a snapshot, if it was recorded.
+    fn snapshot_key_id(&self, info: &SnapshotInfo) -> Option<String>;
+    /// Record how the files of a snapshot were split into chunks.
+    fn snapshot_set_chunker(&self, info: &SnapshotInfo, chunker_: &str);
+    /// How the files of a snapshot were split into chunks, if it was recorded.
+    fn snapshot_chunker(&self, info: &SnapshotInfo) -> Option<String>;
+    /// Every chunker recorded for a snapshot, without duplicates.
+    fn snapshot_chunkers(&self) -> Vec<String>;
+    /// Record how many children the branch nodes of a snapshot's hash trees have.
+    fn snapshot_set_fanout(&self, info: &SnapshotInfo, fanout_: usize);
+    /// How many children the branch nodes of a snapshot's hash trees have, if it was recorded.
+    fn snapshot_fanout(&self, info: &SnapshotInfo) -> Option<usize>;
+    /// Record that a snapshot only captured the contents of files that were unchanged.
+    fn snapshot_set_metadata_only(&self, info: &SnapshotInfo);
+    /// Whether a snapshot only captured the contents of files that were unchanged.
+    fn snapshot_metadata_only(&self, info: &SnapshotInfo) -> bool;
+    /// Record the id of the snapshot of the same family that a snapshot was taken after.
+    fn snapshot_set_parent(&self, info: &SnapshotInfo, parent_id_: u64);
+    /// The id of the snapshot of the same family that a snapshot was taken after, if any.
+    fn snapshot_parent(&self, info: &SnapshotInfo) -> Option<u64>;
+    /// Lookup exact snapshot info from family and snapshot id.
+    fn snapshot_lookup(
+        &mut self,
+        family_name_: &str,
+        snapshot_id_: u64,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)>;
+    /// Reserve the next snapshot id of a family, creating the family if needed.
+    fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo;
+    fn snapshot_update(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        msg_: &str,
+        hash_: &hash::Hash,
+        hash_ref_: &hash::tree::HashRef,
+    );
+    fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag);
+    /// Extract latest snapshot data for family, among the snapshots that were committed.
+    fn snapshot_latest(
+        &mut self,
+        family: &str,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)>;
+    /// All snapshots in the order they were reserved, except those tagged `skip_tag`.
+    fn snapshot_list(&mut self, skip_tag: Option<tags::Tag>) -> Vec<SnapshotStatus>;
+    /// Recover snapshot information.
+    fn snapshot_recover(
+        &mut self,
+        snapshot_id_: u64,
+        family: &str,
+        created: chrono::DateTime<chrono::Utc>,
+        msg_: &str,
+        hash_ref_: &hash::tree::HashRef,
+        work_opt_: Option<SnapshotWorkStatus>,
+    );
+}
 
+impl MetadataStore {
     pub fn hash_update_gc_data<F: UpdateFn>(
         &mut self,
         hash_id: u64,
@@ -483,474 +355,12 @@ impl InternalIndex {
 
     pub fn hash_update_family_gc_data<F: UpdateFn, I: Iterator<Item = F>>(
         &mut self,
-        family_id_: u64,
+        family_id: u64,
         mut fns: I,
     ) {
-        use self::schema::gc_metadata::dsl::*;
-
-        let hash_ids_ = gc_metadata
-            .filter(family_id.eq(family_id_ as i64))
-            .select(hash_id)
-            .load::<i64>(&self.conn)
-            .expect("Error loading GC metadata");
-
-        for hash_id_ in hash_ids_ {
+        for hash_id in self.hash_list_gc_data_ids(family_id) {
             let f = fns.next().expect("Failed to recv update function");
-            self.hash_update_gc_data(hash_id_ as u64, family_id_ as u64, f);
-        }
-    }
-
-    pub fn hash_delete_gc_data(&mut self, hash_id_: u64, family_id_: u64) {
-        use self::schema::gc_metadata::dsl::*;
-
-        diesel::delete(gc_metadata.filter(hash_id.eq(hash_id_ as i64)).filter(
-            family_id.eq(family_id_ as i64),
-        )).execute(&self.conn)
-            .expect("Error deleting GC metadata");
-    }
-
-    pub fn hash_list(&mut self) -> Vec<Entry> {
-        use self::schema::hashes::dsl::*;
-        use self::schema::blobs::dsl::blobs;
-
-        hashes
-            .left_outer_join(blobs)
-            .load::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
-            .expect("Error listing hashes")
-            .into_iter()
-            .map(|(hash_, blob_)| {
-                Entry {
-                    hash: self::hash::Hash { bytes: hash_.hash },
-                    node: From::from(hash_.height as u64),
-                    leaf: From::from(hash_.leaf_type as u64),
-                    childs: hash_.childs.as_ref().map(|p| decode_childs(p).unwrap()),
-                    persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_),
-                    ready: hash_.ready,
-                }
-            })
-            .collect()
-    }
-
-    pub fn hash_delete(&mut self, id_: u64) {
-        {
-            use self::schema::hashes::dsl::*;
-            let hash_count = diesel::delete(hashes.find(id_ as i64))
-                .execute(&self.conn)
-                .expect("Error deleting hash");
-            assert!(hash_count <= 1);
-        }
-
-        {
-            use self::schema::gc_metadata::dsl::*;
-            diesel::delete(gc_metadata.filter(hash_id.eq(id_ as i64)))
-                .execute(&self.conn)
-                .expect("Error deleting GC metadata");
-        }
-    }
-
-    pub fn maybe_flush(&mut self) {
-        if self.flush_periodically && self.flush_timer.did_fire() {
-            debug!("SQL: hash db maybe_flush commit");
-            self.flush();
-        }
-    }
-
-    pub fn set_auto_flush(&mut self, enabled: bool) {
-        self.flush_periodically = enabled;
-    }
-
-    pub fn flush(&mut self) {
-        debug!("SQL: hash db commit");
-
-        let tm = self.conn.transaction_manager();
-        tm.commit_transaction(&self.conn).unwrap();
-        tm.begin_transaction(&self.conn).unwrap();
-    }
-
-    pub fn blob_next_id(&mut self) -> i64 {
-        // TODO(jos): use an id_counter.
-        use diesel::expression::max;
-        use self::schema::blobs::dsl::*;
-
-        blobs
-            .select(max(id))
-            .first::<Option<i64>>(&self.conn)
-            .optional()
-            .expect("Error querying blobs")
-            .and_then(|x| x)
-            .unwrap_or(0)
-    }
-
-    pub fn blob_in_air(&mut self, blob: &blob::BlobDesc) {
-        use self::schema::blobs::dsl::*;
-
-        let new = schema::NewBlob {
-            id: blob.id,
-            name: &blob.name,
-            tag: tags::Tag::InProgress as i32,
-        };
-        diesel::insert(&new)
-            .into(blobs)
-            .execute(&self.conn)
-            .expect("Error inserting blob");
-
-        self.flush();
-    }
-
-    pub fn blob_commit(&mut self, blob: &blob::BlobDesc) {
-        use self::schema::blobs::dsl::*;
-
-        diesel::update(blobs.find(blob.id))
-            .set(tag.eq(tags::Tag::Done as i32))
-            .execute(&self.conn)
-            .expect("Error updating blob");
-        self.flush();
-    }
-
-    pub fn blob_id_from_name(&self, name_: &[u8]) -> Option<i64> {
-        use self::schema::blobs::dsl::*;
-        blobs
-            .filter(name.eq(name_))
-            .select(id)
-            .first::<i64>(&self.conn)
-            .optional()
-            .expect("Error reading blob")
-    }
-
-    pub fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>) {
-        use self::schema::blobs::dsl::*;
-        match target {
-            None => {
-                diesel::update(blobs)
-                    .set(tag.eq(tag_ as i32))
-                    .execute(&self.conn)
-                    .expect("Error updating blob tags")
-            }
-            Some(t) if t.id > 0 => {
-                diesel::update(blobs.find(t.id))
-                    .set(tag.eq(tag_ as i32))
-                    .execute(&self.conn)
-                    .expect("Error updating blob tags")
-            }
-            Some(t) if !t.name.is_empty() => {
-                diesel::update(blobs.filter(name.eq(&t.name)))
-                    .set(tag.eq(tag_ as i32))
-                    .execute(&self.conn)
-                    .expect("Error updating blob tags")
-            }
-            Some(t) => {
-                unreachable!(
-                    "blob with neither id nor name: id={}, name={}",
-                    t.id,
-                    t.name.len()
-                )
-            }
-        };
-    }
-
-    pub fn blob_delete_by_tag(&self, tag_: tags::Tag) {
-        use self::schema::blobs::dsl::*;
-        diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
-            .execute(&self.conn)
-            .expect("Error deleting blobs");
-    }
-
-    pub fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc> {
-        use self::schema::blobs::dsl::*;
-        blobs
-            .filter(tag.eq(tag_ as i32))
-            .order(id.desc())
-            .load::<schema::Blob>(&self.conn)
-            .expect("Error listing blobs")
-            .into_iter()
-            .map(|blob_| {
-                blob::BlobDesc {
-                    id: blob_.id,
-                    name: blob_.name,
-                }
-            })
-            .collect()
-    }
-
-    pub fn last_insert_rowid(&self) -> i64 {
-        diesel::select(diesel::expression::sql("last_insert_rowid()"))
-            .first::<i64>(&self.conn)
-            .unwrap()
-    }
-
-    pub fn family_id_from_name(&mut self, name_: &str) -> Option<i64> {
-        use self::schema::family::dsl::*;
-
-        family
-            .filter(name.eq(name_))
-            .select(id)
-            .first::<i64>(&self.conn)
-            .optional()
-            .expect("Error reading family")
-    }
-
-    /// Delete snapshot.
-    pub fn snapshot_delete(&self, info: SnapshotInfo) {
-        use self::schema::snapshots::dsl::*;
-
-        let count = diesel::delete(
-            snapshots
-                .find(info.unique_id as i64)
-                .filter(family_id.eq(info.family_id as i64))
-                .filter(snapshot_id.eq(info.snapshot_id as i64)),
-        ).execute(&self.conn)
-            .expect("Error deleting snapshots");
-        assert!(count <= 1);
-    }
-
-    pub fn get_or_create_family_id(&mut self, name_: &str) -> i64 {
-        let id_opt = self.family_id_from_name(name_);
-        match id_opt {
-            Some(id) => id,
-            None => {
-                use self::schema::family::dsl::*;
-
-                let new = self::schema::NewFamily { name: name_ };
-
-                diesel::insert(&new)
-                    .into(family)
-                    .execute(&self.conn)
-                    .expect("Error inserting family");
-                self.last_insert_rowid()
-            }
-        }
-    }
-
-    pub fn snapshot_latest_id(&mut self, family_id_: i64) -> Option<i64> {
-        use self::schema::snapshots::dsl::*;
-        use diesel::expression::max;
-
-        snapshots
-            .filter(family_id.eq(family_id_))
-            .select(max(snapshot_id))
-            .first::<Option<i64>>(&self.conn)
-            .optional()
-            .expect("Error reading latest snapshot id")
-            .and_then(|x| x)
-    }
-
-    /// Lookup exact snapshot info from family and snapshot id.
-    pub fn snapshot_lookup(
-        &mut self,
-        family_name_: &str,
-        snapshot_id_: u64,
-    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
-        use self::schema::snapshots::dsl::*;
-        use self::schema::family::dsl::{family, name};
-
-        let row_opt = snapshots
-            .inner_join(family)
-            .filter(name.eq(family_name_))
-            .filter(snapshot_id.eq(snapshot_id_ as i64))
-            .select((
-                id,
-                tag,
-                family_id,
-                snapshot_id,
-                utc_datetime,
-                msg,
-                hash,
-                hash_ref,
-            ))
-            .first::<self::schema::Snapshot>(&self.conn)
-            .optional()
-            .expect("Error reading snapshot info");
-
-        row_opt.map(|snap| {
-            (
-                SnapshotInfo {
-                    unique_id: snap.id as u64,
-                    family_id: snap.family_id as u64,
-                    snapshot_id: snap.snapshot_id as u64,
-                },
-                ::hash::Hash { bytes: snap.hash.unwrap().to_vec() },
-                snap.hash_ref.and_then(|r| {
-                    ::hash::tree::HashRef::from_bytes(&mut &r[..]).ok()
-                }),
-            )
-        })
-    }
-
-    pub fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo {
-        use self::schema::snapshots::dsl::*;
-
-        let family_id_ = self.get_or_create_family_id(&family_);
-        let snapshot_id_ = 1 + self.snapshot_latest_id(family_id_).unwrap_or(0);
-
-        let new = self::schema::NewSnapshot {
-            family_id: family_id_,
-            snapshot_id: snapshot_id_,
-            tag: tags::Tag::Reserved as i32,
-            utc_datetime: chrono::Utc::now().naive_utc(),
-            msg: None,
-            hash: None,
-            hash_ref: None,
-        };
-
-        diesel::insert(&new)
-            .into(snapshots)
-            .execute(&self.conn)
-            .expect("Error inserting snapshot");
-
-        let unique_id_ = self.last_insert_rowid();
-
-        SnapshotInfo {
-            unique_id: unique_id_ as u64,
-            family_id: family_id_ as u64,
-            snapshot_id: snapshot_id_ as u64,
-        }
-    }
-
-    pub fn snapshot_update(
-        &mut self,
-        snapshot_: &SnapshotInfo,
-        msg_: &str,
-        hash_: &hash::Hash,
-        hash_ref_: &hash::tree::HashRef,
-    ) {
-        use self::schema::snapshots::dsl::*;
-
-        diesel::update(snapshots.find(snapshot_.unique_id as i64))
-            .set((
-                msg.eq(Some(msg_)),
-                hash.eq(Some(&hash_.bytes)),
-                hash_ref.eq(Some(hash_ref_.as_bytes())),
-            ))
-            .execute(&self.conn)
-            .expect("Error updating snapshot");
-    }
-
-    pub fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag) {
-        use self::schema::snapshots::dsl::*;
-
-        diesel::update(snapshots.find(snapshot_.unique_id as i64))
-            .set(tag.eq(tag_ as i32))
-            .execute(&self.conn)
-            .expect("Error updating snapshot");
-    }
-
-    /// Extract latest snapshot data for family.
-    pub fn snapshot_latest(
-        &mut self,
-        family: &str,
-    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
-        let family_id_opt = self.family_id_from_name(family);
-        family_id_opt.and_then(|family_id_| {
-            use self::schema::snapshots::dsl::*;
-
-            let row_opt = snapshots
-                .filter(family_id.eq(family_id_))
-                .order(snapshot_id.desc())
-                .first::<self::schema::Snapshot>(&self.conn)
-                .optional()
-                .expect("Error reading latest snapshot");
-
-            row_opt.map(|snap| {
-                (
-                    SnapshotInfo {
-                        unique_id: snap.id as u64,
-                        family_id: snap.family_id as u64,
-                        snapshot_id: snap.snapshot_id as u64,
-                    },
-                    ::hash::Hash { bytes: snap.hash.expect("Snapshot without top hash") },
-                    snap.hash_ref.and_then(|r| {
-                        ::hash::tree::HashRef::from_bytes(&mut &r[..]).ok()
-                    }),
-                )
-            })
-        })
-    }
-
-    pub fn snapshot_list(&mut self, skip_tag: Option<tags::Tag>) -> Vec<SnapshotStatus> {
-        use diesel::*;
-        use self::schema::snapshots::dsl::*;
-        use self::schema::family::dsl::family;
-        let rows = match skip_tag {
-            None => {
-                snapshots
-                    .inner_join(family)
-                    .load::<(self::schema::Snapshot, self::schema::Family)>(&self.conn)
-            }
-            Some(skip) => {
-                snapshots
-                    .inner_join(family)
-                    .filter(tag.ne(skip as i32))
-                    .load::<(self::schema::Snapshot, self::schema::Family)>(&self.conn)
-            }
-        }.unwrap();
-
-        rows.into_iter()
-            .map(|(snap, fam)| {
-                let status = tags::tag_from_num(snap.tag as i64).map_or(
-                    SnapshotWorkStatus::CommitComplete,
-                    tag_to_work_status,
-                );
-                let hash_ = snap.hash.and_then(|bytes| if bytes.is_empty() {
-                    None
-                } else {
-                    Some(::hash::Hash { bytes: bytes })
-                });
-
-                SnapshotStatus {
-                    family_name: fam.name,
-                    created: chrono::DateTime::from_utc(snap.utc_datetime, chrono::Utc),
-                    msg: snap.msg,
-                    hash: hash_,
-                    hash_ref: snap.hash_ref,
-                    status: status,
-                    info: SnapshotInfo {
-                        unique_id: snap.id as u64,
-                        snapshot_id: snap.snapshot_id as u64,
-                        family_id: fam.id as u64,
-                    },
-                }
-            })
-            .collect()
-    }
-
-    /// Recover snapshot information.
-    pub fn snapshot_recover(
-        &mut self,
-        snapshot_id_: u64,
-        family: &str,
-        created: chrono::DateTime<chrono::Utc>,
-        msg_: &str,
-        hash_ref_: &hash::tree::HashRef,
-        work_opt_: Option<SnapshotWorkStatus>,
-    ) {
-        let family_id_ = self.get_or_create_family_id(&family);
-        let insert = match self.snapshot_lookup(family, snapshot_id_) {
-            Some((_info, h, _r)) => {
-                if h.bytes != hash_ref_.hash.bytes {
-                    panic!("Snapshot already exists, but with different hash");
-                }
-                false
-            }
-            None => true,
-        };
-        if insert {
-            use self::schema::snapshots::dsl::*;
-
-            let hash_ref_bytes = hash_ref_.as_bytes();
-            let new = self::schema::NewSnapshot {
-                family_id: family_id_,
-                snapshot_id: snapshot_id_ as i64,
-                utc_datetime: created.naive_utc(),
-                msg: Some(msg_),
-                hash: Some(&hash_ref_.hash.bytes[..]),
-                hash_ref: Some(&hash_ref_bytes[..]),
-                tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
-            };
-
-            diesel::insert(&new)
-                .into(snapshots)
-                .execute(&self.conn)
-                .expect("Error inserting new snapshot");
+            self.hash_update_gc_data(hash_id, family_id, f);
         }
     }
 }
@@ -49,6 +49,21 @@ table! {
     }
 }
 
+table! {
+    blob_checksums (blob_id) {
+        blob_id -> BigInt,
+        crc32c -> BigInt,
+        length -> BigInt,
+    }
+}
+
+table! {
+    blob_chunk_tags (blob_id) {
+        blob_id -> BigInt,
+        tags -> Binary,
+    }
+}
+
 table! {
     family {
         id -> BigInt,
@@ -69,6 +84,85 @@ table! {
     }
 }
 
+table! {
+    gc_pending (hash_id) {
+        hash_id -> BigInt,
+        marked_utc -> BigInt,
+    }
+}
+
+table! {
+    hash_verified (hash_id) {
+        hash_id -> BigInt,
+        verified_utc -> BigInt,
+    }
+}
+
+table! {
+    audit_log (seq) {
+        seq -> BigInt,
+        utc -> BigInt,
+        operation -> VarChar,
+        affected -> VarChar,
+        chain -> Binary,
+    }
+}
+
+table! {
+    gc_runs {
+        id -> BigInt,
+        utc -> BigInt,
+    }
+}
+
+table! {
+    snapshot_keys (snapshot_id) {
+        snapshot_id -> BigInt,
+        key_id -> VarChar,
+    }
+}
+
+table! {
+    snapshot_chunkers (snapshot_id) {
+        snapshot_id -> BigInt,
+        chunker -> VarChar,
+    }
+}
+
+table! {
+    snapshot_fanouts (snapshot_id) {
+        snapshot_id -> BigInt,
+        fanout -> BigInt,
+    }
+}
+
+table! {
+    snapshot_metadata_only (snapshot_id) {
+        snapshot_id -> BigInt,
+    }
+}
+
+table! {
+    snapshot_parents (snapshot_id) {
+        snapshot_id -> BigInt,
+        parent_id -> BigInt,
+    }
+}
+
+table! {
+    store_metadata {
+        id -> BigInt,
+        min_reader_version -> BigInt,
+    }
+}
+
+table! {
+    store_hash_salt {
+        id -> BigInt,
+        salt -> Binary,
+    }
+}
+
 joinable!(snapshots -> family (family_id));
 joinable!(hashes -> blobs (blob_id));
 
@@ -101,15 +195,6 @@ pub struct NewHash<'a> {
     pub ready: bool,
 }
 
-#[derive(Queryable)]
-pub struct GcMetadata {
-    pub id: i64,
-    pub hash_id: i64,
-    pub family_id: i64,
-    pub gc_int: i64,
-    pub gc_vec: Vec<u8>,
-}
-
 #[derive(Insertable)]
 #[table_name = "gc_metadata"]
 pub struct NewGcMetadata<'a> {
@@ -134,6 +219,21 @@ pub struct NewBlob<'a> {
     pub tag: i32,
 }
 
+#[derive(Insertable)]
+#[table_name = "blob_checksums"]
+pub struct NewBlobChecksum {
+    pub blob_id: i64,
+    pub crc32c: i64,
+    pub length: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "blob_chunk_tags"]
+pub struct NewBlobChunkTags<'a> {
+    pub blob_id: i64,
+    pub tags: &'a [u8],
+}
+
 #[derive(Queryable)]
 pub struct Family {
     pub id: i64,
@@ -170,3 +270,90 @@ pub struct NewSnapshot<'a> {
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
 }
+
+#[derive(Insertable)]
+#[table_name = "gc_pending"]
+pub struct NewGcPending {
+    pub hash_id: i64,
+    pub marked_utc: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "hash_verified"]
+pub struct NewHashVerified {
+    pub hash_id: i64,
+    pub verified_utc: i64,
+}
+
+#[derive(Queryable)]
+pub struct AuditEntry {
+    pub seq: i64,
+    pub utc: i64,
+    pub operation: String,
+    pub affected: String,
+    pub chain: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditEntry<'a> {
+    pub seq: i64,
+    pub utc: i64,
+    pub operation: &'a str,
+    pub affected: &'a str,
+    pub chain: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "gc_runs"]
+pub struct NewGcRun {
+    pub utc: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_keys"]
+pub struct NewSnapshotKey<'a> {
+    pub snapshot_id: i64,
+    pub key_id: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_chunkers"]
+pub struct NewSnapshotChunker<'a> {
+    pub snapshot_id: i64,
+    pub chunker: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_fanouts"]
+pub struct NewSnapshotFanout {
+    pub snapshot_id: i64,
+    pub fanout: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_metadata_only"]
+pub struct NewSnapshotMetadataOnly {
+    pub snapshot_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "snapshot_parents"]
+pub struct NewSnapshotParent {
+    pub snapshot_id: i64,
+    pub parent_id: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "store_metadata"]
+pub struct NewStoreMetadata {
+    pub id: i64,
+    pub min_reader_version: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "store_hash_salt"]
+pub struct NewStoreHashSalt<'a> {
+    pub id: i64,
+    pub salt: &'a [u8],
+}
@@ -0,0 +1,1538 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The index kept in a SQLite database.
+
+use blob;
+use capnp;
+use chrono;
+use crypto;
+use db::{AuditEntry, Entry, GcData, MetadataStore, QueueEntry, SnapshotInfo, SnapshotStatus,
+         SnapshotWorkStatus, decode_chunk_ref, tag_to_work_status, work_status_to_tag};
+use db::schema;
+use diesel;
+use diesel::connection::TransactionManager;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use errors::DieselError;
+use hash;
+use libsqlite3_sys as ffi;
+use rand;
+use root_capnp;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::ptr;
+use tags;
+use time::Duration;
+use util::{Counter, InfoWriter, PeriodicTimer};
+
+
+fn encode_childs(childs: &[u64]) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::hash_ids::Builder>();
+        let mut list = root.init_hash_ids(childs.len() as u32);
+        for (i, id) in childs.iter().enumerate() {
+            list.set(i as u32, *id);
+        }
+    }
+    let mut out = Vec::new();
+    ::util::write_packed_message(&mut out, &message).unwrap();
+    out
+}
+
+fn decode_childs(bytes: &[u8]) -> Result<Vec<u64>, capnp::Error> {
+    let reader = ::util::read_packed_message(
+        &mut &bytes[..],
+        capnp::message::ReaderOptions::new(),
+    ).unwrap();
+    let msg = reader.get_root::<root_capnp::hash_ids::Reader>().unwrap();
+
+    let ids = msg.get_hash_ids()?;
+    let mut out = Vec::new();
+    for i in 0..ids.len() {
+        out.push(ids.get(i));
+    }
+    Ok(out)
+}
+
+/// `path` as a quoted SQL string literal.
+fn audit_entry_from_row(row: schema::AuditEntry) -> AuditEntry {
+    AuditEntry {
+        seq: row.seq,
+        utc: row.utc,
+        operation: row.operation,
+        affected: row.affected
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.parse().expect("Invalid id in audit log"))
+            .collect(),
+        chain: row.chain,
+    }
+}
+
+fn sql_string(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+}
+
+/// The first SQLite version with `VACUUM INTO`, as given by `sqlite3_libversion_number`.
+const VACUUM_INTO_VERSION: i32 = 3027000;
+
+/// Copy the database in the file `from` to a new database at `to` with the online backup API,
+/// for SQLite versions without `VACUUM INTO`.
+fn backup_database(from: &str, to: &Path) -> Result<(), DieselError> {
+    if from == ":memory:" {
+        return Err(From::from(
+            "An index kept in memory can only be exported with SQLite 3.27 or later",
+        ));
+    }
+    let from = CString::new(from).map_err(|e| e.to_string())?;
+    let to = CString::new(to.to_string_lossy().into_owned()).map_err(|e| e.to_string())?;
+    let main = b"main\0".as_ptr() as *const c_char;
+
+    let rc = unsafe {
+        let mut src = ptr::null_mut();
+        let mut dst = ptr::null_mut();
+        let mut rc = ffi::sqlite3_open_v2(
+            from.as_ptr(),
+            &mut src,
+            ffi::SQLITE_OPEN_READONLY,
+            ptr::null(),
+        );
+        if rc == ffi::SQLITE_OK {
+            rc = ffi::sqlite3_open_v2(
+                to.as_ptr(),
+                &mut dst,
+                ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+                ptr::null(),
+            );
+        }
+        if rc == ffi::SQLITE_OK {
+            let backup = ffi::sqlite3_backup_init(dst, main, src, main);
+            if backup.is_null() {
+                rc = ffi::sqlite3_errcode(dst);
+            } else {
+                // Copy every page in one step; finishing reports any error of the step.
+                ffi::sqlite3_backup_step(backup, -1);
+                rc = ffi::sqlite3_backup_finish(backup);
+            }
+        }
+        // Handles are allocated even when opening fails, and closing null is a no-op.
+        ffi::sqlite3_close(dst);
+        ffi::sqlite3_close(src);
+        rc
+    };
+    if rc != ffi::SQLITE_OK {
+        return Err(From::from(format!("Could not copy the index: SQLite error {}", rc)));
+    }
+    Ok(())
+}
+
+pub struct SqliteStore {
+    conn: SqliteConnection,
+    /// Where the database is, or ":memory:".
+    path: String,
+    hash_id_counter: Counter,
+    flush_timer: PeriodicTimer,
+    flush_periodically: bool,
+}
+
+
+impl SqliteStore {
+    pub fn new(migrations_dir: &Path, path: &str) -> Result<SqliteStore, DieselError> {
+        let conn = SqliteConnection::establish(path)?;
+
+        let mut idx = SqliteStore {
+            conn: conn,
+            path: path.to_owned(),
+            hash_id_counter: Counter::new(0),
+            flush_timer: PeriodicTimer::new(Duration::seconds(10)),
+            flush_periodically: true,
+        };
+
+        diesel::migrations::run_pending_migrations_in_directory(
+            &idx.conn,
+            &migrations_dir,
+            &mut InfoWriter,
+        )?;
+
+        {
+            let tm = idx.conn.transaction_manager();
+            tm.begin_transaction(&idx.conn)?;
+        }
+
+        idx.hash_refresh_id_counter();
+        Ok(idx)
+    }
+
+    fn hash_refresh_id_counter(&mut self) {
+        use db::schema::hashes::dsl::*;
+        use diesel::expression::max;
+
+        let id_opt = hashes
+            .select(max(id))
+            .first::<Option<i64>>(&self.conn)
+            .expect("Error selecting max hash id");
+
+        self.hash_id_counter = Counter::new(id_opt.unwrap_or(0));
+    }
+
+    /// Replace every table of the index with its contents in the database at `path`, which
+    /// must have had the same migrations run. Runs in a transaction of its own.
+    fn copy_tables_from(&self, path: &Path) -> Result<(), DieselError> {
+        use diesel::expression::sql;
+        use diesel::types::{Nullable, Text};
+
+        let migrations = |db: &str| {
+            diesel::select(sql::<Nullable<Text>>(&format!(
+                "(SELECT group_concat(version, ',') FROM \
+                 (SELECT version FROM {}.__diesel_schema_migrations ORDER BY version))",
+                db
+            ))).first::<Option<String>>(&self.conn)
+        };
+
+        self.conn.execute(
+            &format!("ATTACH DATABASE {} AS dump", sql_string(path)),
+        )?;
+        let copied = self.conn.transaction::<_, DieselError, _>(|| {
+            if migrations("main")? != migrations("dump")? {
+                return Err(From::from(
+                    "The exported index was written by another version of hat",
+                ));
+            }
+            let tables = diesel::select(sql::<Nullable<Text>>(
+                "(SELECT group_concat(name, ',') FROM main.sqlite_master \
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%' \
+                 AND name != '__diesel_schema_migrations')",
+            )).first::<Option<String>>(&self.conn)?
+                .unwrap_or_default();
+            for table in tables.split(',').filter(|t| !t.is_empty()) {
+                self.conn.execute(&format!("DELETE FROM main.\"{}\"", table))?;
+                self.conn.execute(&format!(
+                    "INSERT INTO main.\"{0}\" SELECT * FROM dump.\"{0}\"",
+                    table
+                ))?;
+            }
+            Ok(())
+        });
+        let detached = self.conn.execute("DETACH DATABASE dump");
+        copied?;
+        detached?;
+        Ok(())
+    }
+
+    /// Forget the checksums and chunk tags of the given blobs.
+    fn blob_delete_checksums(&self, ids: &[i64]) {
+        {
+            use db::schema::blob_checksums::dsl::*;
+            diesel::delete(blob_checksums.filter(blob_id.eq_any(ids)))
+                .execute(&self.conn)
+                .expect("Error deleting blob checksums");
+        }
+        {
+            use db::schema::blob_chunk_tags::dsl::*;
+            diesel::delete(blob_chunk_tags.filter(blob_id.eq_any(ids)))
+                .execute(&self.conn)
+                .expect("Error deleting chunk tags");
+        }
+    }
+
+    fn last_insert_rowid(&self) -> i64 {
+        diesel::select(diesel::expression::sql("last_insert_rowid()"))
+            .first::<i64>(&self.conn)
+            .unwrap()
+    }
+
+    fn family_id_from_name(&mut self, name_: &str) -> Option<i64> {
+        use db::schema::family::dsl::*;
+
+        family
+            .filter(name.eq(name_))
+            .select(id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading family")
+    }
+
+    fn get_or_create_family_id(&mut self, name_: &str) -> i64 {
+        let id_opt = self.family_id_from_name(name_);
+        match id_opt {
+            Some(id) => id,
+            None => {
+                use db::schema::family::dsl::*;
+
+                let new = schema::NewFamily { name: name_ };
+
+                diesel::insert(&new)
+                    .into(family)
+                    .execute(&self.conn)
+                    .expect("Error inserting family");
+                self.last_insert_rowid()
+            }
+        }
+    }
+
+    fn snapshot_latest_id(&mut self, family_id_: i64) -> Option<i64> {
+        use db::schema::snapshots::dsl::*;
+        use diesel::expression::max;
+
+        snapshots
+            .filter(family_id.eq(family_id_))
+            .select(max(snapshot_id))
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error reading latest snapshot id")
+            .and_then(|x| x)
+    }
+}
+
+impl MetadataStore for SqliteStore {
+    fn hash_locate(&mut self, hash_: &hash::Hash) -> Option<QueueEntry> {
+        assert!(!hash_.bytes.is_empty());
+        use db::schema::hashes::dsl::*;
+        use db::schema::blobs::dsl::blobs;
+
+        let result_opt = hashes
+            .left_outer_join(blobs)
+            .filter(hash.eq(&hash_.bytes))
+            .first::<(schema::Hash, Option<schema::Blob>)>(&self.conn)
+            .optional()
+            .expect("Error querying hashes");
+
+        result_opt.map(|(hash_, blob_)| {
+            let childs_ = hash_.childs.and_then(|b| if b.is_empty() {
+                None
+            } else {
+                Some(decode_childs(&b).unwrap())
+            });
+            let persistent_ref = decode_chunk_ref(hash_.blob_ref.as_ref(), blob_.map(|b| (b.id, b.name)));
+            QueueEntry {
+                id: hash_.id as u64,
+                node: From::from(hash_.height as u64),
+                leaf: From::from(hash_.leaf_type as u64),
+                tag: tags::tag_from_num(hash_.tag),
+                childs: childs_,
+                persistent_ref: persistent_ref,
+            }
+        })
+    }
+
+    fn hash_locate_by_id(&mut self, id_: u64) -> Option<Entry> {
+        use db::schema::hashes::dsl::*;
+        use db::schema::blobs::dsl::blobs;
+
+        let result_opt = hashes
+            .left_outer_join(blobs)
+            .filter(id.eq(id_ as i64))
+            .first::<(schema::Hash, Option<schema::Blob>)>(&self.conn)
+            .optional()
+            .expect("Error querying hashes");
+
+        result_opt.map(|(hash_, blob_)| {
+            Entry {
+                hash: self::hash::Hash { bytes: hash_.hash },
+                node: From::from(hash_.height as u64),
+                leaf: From::from(hash_.leaf_type as u64),
+                childs: hash_.childs.and_then(|p| if p.is_empty() {
+                    None
+                } else {
+                    Some(decode_childs(&p).unwrap())
+                }),
+                persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_.map(|b| (b.id, b.name))),
+                ready: hash_.ready,
+            }
+        })
+    }
+
+    fn hash_next_id(&mut self) -> u64 {
+        self.hash_id_counter.next() as u64
+    }
+
+    fn hash_insert_new(&mut self, id_: u64, hash_bytes: Vec<u8>, entry: QueueEntry) {
+        use db::schema::hashes::dsl::*;
+
+        let blob_ref_ = entry.persistent_ref.as_ref().map(|c| c.as_bytes_no_name());
+        let childs_ = entry.childs.as_ref().map(|v| encode_childs(&v[..]));
+
+        let height_: u64 = From::from(entry.node);
+        let leaf_type_: u64 = From::from(entry.leaf);
+
+        let new = schema::NewHash {
+            id: id_ as i64,
+            hash: &hash_bytes,
+            tag: entry.tag.unwrap_or(tags::Tag::Done) as i64,
+            height: height_ as i64,
+            leaf_type: leaf_type_ as i64,
+            childs: childs_.as_ref().map(|v| &v[..]),
+            blob_id: entry.persistent_ref.and_then(|r| r.blob_id).unwrap_or(0),
+            blob_ref: blob_ref_.as_ref().map(|v| &v[..]),
+            ready: false,
+        };
+
+        diesel::insert(&new)
+            .into(hashes)
+            .execute(&self.conn)
+            .expect("Error inserting new hash");
+    }
+
+    fn hash_set_tag(&mut self, id_opt: Option<u64>, tag_: tags::Tag) {
+        use db::schema::hashes::dsl::*;
+
+        match id_opt {
+            None => {
+                diesel::update(hashes)
+                    .set(tag.eq(tag_ as i64))
+                    .execute(&self.conn)
+                    .expect("Error updating hash tags")
+            }
+            Some(id_) => {
+                diesel::update(hashes.find(id_ as i64))
+                    .set(tag.eq(tag_ as i64))
+                    .execute(&self.conn)
+                    .expect("Error updating specific hash tag")
+            }
+        };
+    }
+
+    fn hash_delete_not_ready(&mut self) {
+        use db::schema::hashes::dsl::*;
+        diesel::delete(hashes.filter(ready.eq(false)))
+            .execute(&self.conn)
+            .expect("Failed to delete non-ready hashes");
+    }
+
+    fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry) {
+        use db::schema::hashes::dsl::*;
+        let blob_ref_ = entry
+            .persistent_ref
+            .as_ref()
+            .expect("ready")
+            .as_bytes_no_name();
+        let blob_id_ = entry
+            .persistent_ref
+            .as_ref()
+            .expect("ready")
+            .blob_id
+            .expect("ready");
+        let childs_ = entry.childs.as_ref().map(|v| encode_childs(&v[..]));
+
+        let height_: u64 = From::from(entry.node);
+        let leaf_type_: u64 = From::from(entry.leaf);
+
+        diesel::update(hashes.find(id_ as i64))
+            .set((
+                blob_id.eq(blob_id_),
+                blob_ref.eq(&blob_ref_[..]),
+                ready.eq(true),
+                height.eq(height_ as i64),
+                leaf_type.eq(leaf_type_ as i64),
+                childs.eq(childs_.as_ref().map(|v| &v[..])),
+            ))
+            .execute(&self.conn)
+            .expect("Failed to set hash ready");
+    }
+
+    /// Point a hash at another copy of its data.
+    fn hash_set_persistent_ref(&mut self, id_: u64, chunk_ref: &blob::ChunkRef) {
+        use db::schema::hashes::dsl::*;
+
+        let blob_ref_ = chunk_ref.as_bytes_no_name();
+        let blob_id_ = chunk_ref.blob_id.expect("chunk ref without blob id");
+        diesel::update(hashes.find(id_ as i64))
+            .set((blob_id.eq(blob_id_), blob_ref.eq(&blob_ref_[..])))
+            .execute(&self.conn)
+            .expect("Error updating hash");
+    }
+
+    fn hash_get_tag(&mut self, id_: u64) -> Option<tags::Tag> {
+        use db::schema::hashes::dsl::*;
+
+        let tag_opt = hashes
+            .find(id_ as i64)
+            .select(tag)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error querying hash tag");
+
+        tag_opt.and_then(tags::tag_from_num)
+    }
+
+    fn hash_list_ids_by_tag(&mut self, tag_: u64) -> Vec<u64> {
+        // We list hashes top-down.
+        // This is required for safe deletion.
+        // TODO(jos): consider moving this requirement closer to the code that needs it.
+        use db::schema::hashes::dsl::*;
+
+        hashes
+            .filter(tag.eq(tag_ as i64))
+            .order(height.desc())
+            .select(id)
+            .load::<i64>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|i| i as u64)
+            .collect()
+    }
+
+    fn hash_read_gc_data(&mut self, hash_id_: u64, family_id_: u64) -> GcData {
+        use db::schema::gc_metadata::dsl::*;
+        use diesel::expression::sql;
+        use diesel::types::{Binary, Nullable};
+
+        // SQLite hands out a null pointer for an empty blob, and diesel makes a slice of it.
+        // Read empty vectors as NULL instead.
+        let result_opt = gc_metadata
+            .filter(hash_id.eq(hash_id_ as i64))
+            .filter(family_id.eq(family_id_ as i64))
+            .select((gc_int, sql::<Nullable<Binary>>("NULLIF(gc_vec, x'')")))
+            .first::<(i64, Option<Vec<u8>>)>(&self.conn)
+            .optional()
+            .expect("Error querying GC metadata");
+        match result_opt {
+            None => {
+                GcData {
+                    num: 0,
+                    bytes: vec![],
+                }
+            }
+            Some((num, bytes)) => {
+                GcData {
+                    num: num,
+                    bytes: bytes.unwrap_or_default(),
+                }
+            }
+        }
+    }
+
+    fn hash_set_gc_data(&mut self, hash_id_: u64, family_id_: u64, data: GcData) {
+        use db::schema::gc_metadata::dsl::*;
+
+        let count = diesel::update(gc_metadata.filter(hash_id.eq(hash_id_ as i64)).filter(
+            family_id.eq(family_id_ as i64),
+        )).set((gc_int.eq(data.num), gc_vec.eq(&data.bytes)))
+            .execute(&self.conn)
+            .expect("Error updating GC metadata");
+        assert!(count <= 1);
+
+        if count == 0 {
+            let new = schema::NewGcMetadata {
+                hash_id: hash_id_ as i64,
+                family_id: family_id_ as i64,
+                gc_int: data.num,
+                gc_vec: &data.bytes,
+            };
+
+            diesel::insert(&new)
+                .into(gc_metadata)
+                .execute(&self.conn)
+                .expect("Error inserting GC metadata");
+        }
+    }
+
+    fn hash_delete_gc_data(&mut self, hash_id_: u64, family_id_: u64) {
+        use db::schema::gc_metadata::dsl::*;
+
+        diesel::delete(gc_metadata.filter(hash_id.eq(hash_id_ as i64)).filter(
+            family_id.eq(family_id_ as i64),
+        )).execute(&self.conn)
+            .expect("Error deleting GC metadata");
+    }
+
+    fn hash_list_gc_data_ids(&mut self, family_id_: u64) -> Vec<u64> {
+        use db::schema::gc_metadata::dsl::*;
+
+        gc_metadata
+            .filter(family_id.eq(family_id_ as i64))
+            .select(hash_id)
+            .load::<i64>(&self.conn)
+            .expect("Error loading GC metadata")
+            .into_iter()
+            .map(|i| i as u64)
+            .collect()
+    }
+
+    /// List up to `limit` hashes that sort at or after `from`, in order.
+    fn hash_list_from(&mut self, from: &[u8], limit: i64) -> Vec<Vec<u8>> {
+        use db::schema::hashes::dsl::*;
+
+        hashes
+            .select(hash)
+            .filter(hash.ge(from))
+            .order(hash)
+            .limit(limit)
+            .load::<Vec<u8>>(&self.conn)
+            .expect("Error listing hashes")
+    }
+
+    fn hash_list(&mut self) -> Vec<Entry> {
+        use db::schema::hashes::dsl::*;
+        use db::schema::blobs::dsl::blobs;
+
+        hashes
+            .left_outer_join(blobs)
+            .load::<(schema::Hash, Option<schema::Blob>)>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|(hash_, blob_)| {
+                Entry {
+                    hash: self::hash::Hash { bytes: hash_.hash },
+                    node: From::from(hash_.height as u64),
+                    leaf: From::from(hash_.leaf_type as u64),
+                    childs: hash_.childs.as_ref().map(|p| decode_childs(p).unwrap()),
+                    persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_.map(|b| (b.id, b.name))),
+                    ready: hash_.ready,
+                }
+            })
+            .collect()
+    }
+
+    fn hash_delete(&mut self, id_: u64) {
+        {
+            use db::schema::hashes::dsl::*;
+            let hash_count = diesel::delete(hashes.find(id_ as i64))
+                .execute(&self.conn)
+                .expect("Error deleting hash");
+            assert!(hash_count <= 1);
+        }
+
+        {
+            use db::schema::gc_metadata::dsl::*;
+            diesel::delete(gc_metadata.filter(hash_id.eq(id_ as i64)))
+                .execute(&self.conn)
+                .expect("Error deleting GC metadata");
+        }
+
+        {
+            use db::schema::hash_verified::dsl::*;
+            diesel::delete(hash_verified.find(id_ as i64))
+                .execute(&self.conn)
+                .expect("Error deleting verification time");
+        }
+
+        self.hash_gc_unmark(id_);
+    }
+
+    /// Remember that the GC found a hash unused at `utc`, unless it was already marked.
+    /// Returns the time of the earliest mark.
+    fn hash_gc_mark(&mut self, id_: u64, utc: i64) -> i64 {
+        use db::schema::gc_pending::dsl::*;
+
+        let existing = gc_pending
+            .find(id_ as i64)
+            .select(marked_utc)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading GC marks");
+        if let Some(marked) = existing {
+            return marked;
+        }
+
+        let new = schema::NewGcPending {
+            hash_id: id_ as i64,
+            marked_utc: utc,
+        };
+        diesel::insert(&new)
+            .into(gc_pending)
+            .execute(&self.conn)
+            .expect("Error inserting GC mark");
+        utc
+    }
+
+    fn hash_gc_unmark(&mut self, id_: u64) {
+        use db::schema::gc_pending::dsl::*;
+
+        diesel::delete(gc_pending.find(id_ as i64))
+            .execute(&self.conn)
+            .expect("Error deleting GC mark");
+    }
+
+    fn hash_gc_marked(&mut self) -> Vec<u64> {
+        use db::schema::gc_pending::dsl::*;
+
+        gc_pending
+            .select(hash_id)
+            .load::<i64>(&self.conn)
+            .expect("Error listing GC marks")
+            .into_iter()
+            .map(|i| i as u64)
+            .collect()
+    }
+
+    /// Remember that the data of a hash was read back and checked at `utc`.
+    fn hash_set_verified(&mut self, id_: u64, utc: i64) {
+        use db::schema::hash_verified::dsl::*;
+
+        diesel::delete(hash_verified.find(id_ as i64))
+            .execute(&self.conn)
+            .expect("Error deleting verification time");
+        let new = schema::NewHashVerified {
+            hash_id: id_ as i64,
+            verified_utc: utc,
+        };
+        diesel::insert(&new)
+            .into(hash_verified)
+            .execute(&self.conn)
+            .expect("Error inserting verification time");
+    }
+
+    fn hash_list_verified(&mut self) -> Vec<(u64, i64)> {
+        use db::schema::hash_verified::dsl::*;
+
+        hash_verified
+            .select((hash_id, verified_utc))
+            .load::<(i64, i64)>(&self.conn)
+            .expect("Error listing verification times")
+            .into_iter()
+            .map(|(i, utc)| (i as u64, utc))
+            .collect()
+    }
+
+    fn maybe_flush(&mut self) {
+        if self.flush_periodically && self.flush_timer.did_fire() {
+            debug!("SQL: hash db maybe_flush commit");
+            self.flush();
+        }
+    }
+
+    fn set_auto_flush(&mut self, enabled: bool) {
+        self.flush_periodically = enabled;
+    }
+
+    fn flush(&mut self) {
+        debug!("SQL: hash db commit");
+
+        let tm = self.conn.transaction_manager();
+        tm.commit_transaction(&self.conn).unwrap();
+        tm.begin_transaction(&self.conn).unwrap();
+    }
+
+    fn export(&mut self) -> Result<Vec<u8>, DieselError> {
+        let path = env::temp_dir().join(format!("hat-index-{}", rand::random::<u64>()));
+        // Nothing can be vacuumed or backed up inside a transaction, so this flushes.
+        let tm = self.conn.transaction_manager();
+        tm.commit_transaction(&self.conn)?;
+        let copied = if unsafe { ffi::sqlite3_libversion_number() } >= VACUUM_INTO_VERSION {
+            self.conn
+                .execute(&format!("VACUUM INTO {}", sql_string(&path)))
+                .map(|_| ())
+                .map_err(From::from)
+        } else {
+            backup_database(&self.path, &path)
+        };
+        tm.begin_transaction(&self.conn)?;
+        copied?;
+
+        let mut dump = vec![];
+        let read = fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut dump));
+        fs::remove_file(&path)?;
+        read?;
+        Ok(dump)
+    }
+
+    fn import(&mut self, dump: &[u8]) -> Result<(), DieselError> {
+        let path = env::temp_dir().join(format!("hat-index-{}", rand::random::<u64>()));
+        fs::File::create(&path)?.write_all(dump)?;
+
+        // Databases can not be attached inside a transaction.
+        let tm = self.conn.transaction_manager();
+        tm.commit_transaction(&self.conn)?;
+        let copied = self.copy_tables_from(&path);
+        tm.begin_transaction(&self.conn)?;
+        fs::remove_file(&path)?;
+        copied?;
+
+        self.hash_refresh_id_counter();
+        Ok(())
+    }
+
+    fn blob_next_id(&mut self) -> i64 {
+        // TODO(jos): use an id_counter.
+        use diesel::expression::max;
+        use db::schema::blobs::dsl::*;
+
+        blobs
+            .select(max(id))
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying blobs")
+            .and_then(|x| x)
+            .unwrap_or(0)
+    }
+
+    fn blob_in_air(&mut self, blob: &blob::BlobDesc) {
+        use db::schema::blobs::dsl::*;
+
+        let new = schema::NewBlob {
+            id: blob.id,
+            name: &blob.name,
+            tag: tags::Tag::InProgress as i32,
+        };
+        diesel::insert(&new)
+            .into(blobs)
+            .execute(&self.conn)
+            .expect("Error inserting blob");
+
+        self.flush();
+    }
+
+    fn blob_commit(&mut self, blob: &blob::BlobDesc) {
+        use db::schema::blobs::dsl::*;
+
+        diesel::update(blobs.find(blob.id))
+            .set(tag.eq(tags::Tag::Done as i32))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+        self.flush();
+    }
+
+    fn blob_id_from_name(&self, name_: &[u8]) -> Option<i64> {
+        use db::schema::blobs::dsl::*;
+        blobs
+            .filter(name.eq(name_))
+            .select(id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+    }
+
+    fn blob_name_from_id(&self, id_: i64) -> Option<Vec<u8>> {
+        use db::schema::blobs::dsl::*;
+        blobs
+            .find(id_)
+            .select(name)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+    }
+
+    fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>) {
+        use db::schema::blobs::dsl::*;
+        match target {
+            None => {
+                diesel::update(blobs)
+                    .set(tag.eq(tag_ as i32))
+                    .execute(&self.conn)
+                    .expect("Error updating blob tags")
+            }
+            Some(t) if t.id > 0 => {
+                diesel::update(blobs.find(t.id))
+                    .set(tag.eq(tag_ as i32))
+                    .execute(&self.conn)
+                    .expect("Error updating blob tags")
+            }
+            Some(t) if !t.name.is_empty() => {
+                diesel::update(blobs.filter(name.eq(&t.name)))
+                    .set(tag.eq(tag_ as i32))
+                    .execute(&self.conn)
+                    .expect("Error updating blob tags")
+            }
+            Some(t) => {
+                unreachable!(
+                    "blob with neither id nor name: id={}, name={}",
+                    t.id,
+                    t.name.len()
+                )
+            }
+        };
+    }
+
+    fn blob_delete(&self, blob: &blob::BlobDesc) {
+        use db::schema::blobs::dsl::*;
+        diesel::delete(blobs.find(blob.id))
+            .execute(&self.conn)
+            .expect("Error deleting blob");
+        self.blob_delete_checksums(&[blob.id]);
+    }
+
+    fn blob_delete_by_tag(&self, tag_: tags::Tag) {
+        use db::schema::blobs::dsl::*;
+        let ids = blobs
+            .filter(tag.eq(tag_ as i32))
+            .select(id)
+            .load::<i64>(&self.conn)
+            .expect("Error listing blobs");
+        diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
+            .execute(&self.conn)
+            .expect("Error deleting blobs");
+        self.blob_delete_checksums(&ids[..]);
+    }
+
+    /// Record the checksum of a blob as it was handed to the backend.
+    fn blob_set_checksum(&self, blob: &blob::BlobDesc, checksum: &crypto::Checksum) {
+        use db::schema::blob_checksums::dsl::*;
+
+        // Only the checksum is replaced; the chunk tags are set apart from it.
+        diesel::delete(blob_checksums.find(blob.id))
+            .execute(&self.conn)
+            .expect("Error deleting blob checksum");
+        let new = schema::NewBlobChecksum {
+            blob_id: blob.id,
+            crc32c: checksum.crc32c as i64,
+            length: checksum.length as i64,
+        };
+        diesel::insert(&new)
+            .into(blob_checksums)
+            .execute(&self.conn)
+            .expect("Error inserting blob checksum");
+    }
+
+    /// The checksum recorded for a blob. Blobs written by older versions of hat have none.
+    fn blob_checksum(&self, blob: &blob::BlobDesc) -> Option<crypto::Checksum> {
+        use db::schema::blob_checksums::dsl::*;
+
+        blob_checksums
+            .find(blob.id)
+            .select((crc32c, length))
+            .first::<(i64, i64)>(&self.conn)
+            .optional()
+            .expect("Error reading blob checksum")
+            .map(|(crc, len)| {
+                crypto::Checksum {
+                    crc32c: crc as u32,
+                    length: len as u64,
+                }
+            })
+    }
+
+    /// Record the integrity tags of the chunks in a blob.
+    fn blob_set_chunk_tags(&self, blob: &blob::BlobDesc, tags_: &[blob::ChunkTag]) {
+        use db::schema::blob_chunk_tags::dsl::*;
+
+        diesel::delete(blob_chunk_tags.find(blob.id))
+            .execute(&self.conn)
+            .expect("Error deleting chunk tags");
+        let encoded = blob::ChunkTag::encode_all(tags_);
+        let new = schema::NewBlobChunkTags {
+            blob_id: blob.id,
+            tags: &encoded[..],
+        };
+        diesel::insert(&new)
+            .into(blob_chunk_tags)
+            .execute(&self.conn)
+            .expect("Error inserting chunk tags");
+    }
+
+    /// The integrity tags recorded for the chunks in a blob, if any.
+    fn blob_chunk_tags(&self, blob: &blob::BlobDesc) -> Vec<blob::ChunkTag> {
+        use db::schema::blob_chunk_tags::dsl::*;
+
+        blob_chunk_tags
+            .find(blob.id)
+            .select(tags)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()
+            .expect("Error reading chunk tags")
+            .map_or(vec![], |encoded| {
+                blob::ChunkTag::decode_all(&encoded[..]).expect("Malformed chunk tags")
+            })
+    }
+
+    fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc> {
+        use db::schema::blobs::dsl::*;
+        blobs
+            .filter(tag.eq(tag_ as i32))
+            .order(id.desc())
+            .load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| {
+                blob::BlobDesc {
+                    id: blob_.id,
+                    name: blob_.name,
+                }
+            })
+            .collect()
+    }
+
+    /// Record that the GC deleted data, or that chunks were moved to other blobs, which
+    /// invalidates anything that remembers what the store contains, like verification
+    /// checkpoints.
+    fn gc_run_record(&mut self, utc_: i64) {
+        use db::schema::gc_runs::dsl::*;
+
+        diesel::insert(&schema::NewGcRun { utc: utc_ })
+            .into(gc_runs)
+            .execute(&self.conn)
+            .expect("Error inserting GC run");
+    }
+
+    /// The id of the latest `gc_run_record`, or 0 before the first. It changes with every
+    /// record, so comparing it tells whether data was deleted or moved in between.
+    fn gc_generation(&mut self) -> i64 {
+        use diesel::expression::max;
+        use db::schema::gc_runs::dsl::*;
+
+        gc_runs
+            .select(max(id))
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error reading GC runs")
+            .and_then(|x| x)
+            .unwrap_or(0)
+    }
+
+    /// Add an entry to the end of the audit log. Entries are never changed or removed.
+    fn audit_append(&mut self, entry: &AuditEntry) {
+        use db::schema::audit_log::dsl::*;
+
+        let ids: Vec<String> = entry.affected.iter().map(|i| i.to_string()).collect();
+        let new = schema::NewAuditEntry {
+            seq: entry.seq,
+            utc: entry.utc,
+            operation: &entry.operation,
+            affected: &ids.join(","),
+            chain: &entry.chain[..],
+        };
+        diesel::insert(&new)
+            .into(audit_log)
+            .execute(&self.conn)
+            .expect("Error inserting audit log entry");
+    }
+
+    /// The last entry of the audit log.
+    fn audit_last(&mut self) -> Option<AuditEntry> {
+        use db::schema::audit_log::dsl::*;
+
+        audit_log
+            .order(seq.desc())
+            .first::<schema::AuditEntry>(&self.conn)
+            .optional()
+            .expect("Error reading audit log")
+            .map(audit_entry_from_row)
+    }
+
+    /// The whole audit log, oldest first.
+    fn audit_list(&mut self) -> Vec<AuditEntry> {
+        use db::schema::audit_log::dsl::*;
+
+        audit_log
+            .order(seq.asc())
+            .load::<schema::AuditEntry>(&self.conn)
+            .expect("Error reading audit log")
+            .into_iter()
+            .map(audit_entry_from_row)
+            .collect()
+    }
+
+    /// The oldest store format version that can read this store, if one has been recorded.
+    fn store_min_reader_version(&mut self) -> Option<i64> {
+        use db::schema::store_metadata::dsl::*;
+
+        store_metadata
+            .find(1)
+            .select(min_reader_version)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading store metadata")
+    }
+
+    fn store_set_min_reader_version(&mut self, version: i64) {
+        use db::schema::store_metadata::dsl::*;
+
+        if self.store_min_reader_version().is_some() {
+            diesel::update(store_metadata.find(1))
+                .set(min_reader_version.eq(version))
+                .execute(&self.conn)
+                .expect("Error updating store metadata");
+        } else {
+            let new = schema::NewStoreMetadata {
+                id: 1,
+                min_reader_version: version,
+            };
+            diesel::insert(&new)
+                .into(store_metadata)
+                .execute(&self.conn)
+                .expect("Error inserting store metadata");
+        }
+    }
+
+    fn store_hash_salt(&mut self) -> Option<Vec<u8>> {
+        use db::schema::store_hash_salt::dsl::*;
+
+        store_hash_salt
+            .find(1)
+            .select(salt)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()
+            .expect("Error reading store hash salt")
+    }
+
+    fn store_set_hash_salt(&mut self, salt_: &[u8]) {
+        use db::schema::store_hash_salt::dsl::*;
+
+        let new = schema::NewStoreHashSalt { id: 1, salt: salt_ };
+        diesel::insert(&new)
+            .into(store_hash_salt)
+            .execute(&self.conn)
+            .expect("Error inserting store hash salt");
+    }
+
+    /// Delete snapshot.
+    fn snapshot_delete(&self, info: SnapshotInfo) {
+        use db::schema::snapshots::dsl::*;
+
+        let count = diesel::delete(
+            snapshots
+                .find(info.unique_id as i64)
+                .filter(family_id.eq(info.family_id as i64))
+                .filter(snapshot_id.eq(info.snapshot_id as i64)),
+        ).execute(&self.conn)
+            .expect("Error deleting snapshots");
+        assert!(count <= 1);
+
+        {
+            use db::schema::snapshot_keys::dsl::*;
+            diesel::delete(snapshot_keys.find(info.unique_id as i64))
+                .execute(&self.conn)
+                .expect("Error deleting snapshot key");
+        }
+        {
+            use db::schema::snapshot_chunkers::dsl::*;
+            diesel::delete(snapshot_chunkers.find(info.unique_id as i64))
+                .execute(&self.conn)
+                .expect("Error deleting snapshot chunker");
+        }
+        {
+            use db::schema::snapshot_fanouts::dsl::*;
+            diesel::delete(snapshot_fanouts.find(info.unique_id as i64))
+                .execute(&self.conn)
+                .expect("Error deleting snapshot fan-out");
+        }
+        {
+            use db::schema::snapshot_metadata_only::dsl::*;
+            diesel::delete(snapshot_metadata_only.find(info.unique_id as i64))
+                .execute(&self.conn)
+                .expect("Error deleting snapshot metadata-only mark");
+        }
+        {
+            use db::schema::snapshot_parents::dsl::*;
+            diesel::delete(snapshot_parents.find(info.unique_id as i64))
+                .execute(&self.conn)
+                .expect("Error deleting snapshot parent");
+        }
+    }
+
+    /// Record the id of the key that seals a snapshot.
+    fn snapshot_set_key_id(&self, info: &SnapshotInfo, key_id_: &str) {
+        use db::schema::snapshot_keys::dsl::*;
+
+        diesel::delete(snapshot_keys.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot key");
+        let new = schema::NewSnapshotKey {
+            snapshot_id: info.unique_id as i64,
+            key_id: key_id_,
+        };
+        diesel::insert(&new)
+            .into(snapshot_keys)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot key");
+    }
+
+    /// The id of the key that seals a snapshot, if it was recorded.
+    fn snapshot_key_id(&self, info: &SnapshotInfo) -> Option<String> {
+        use db::schema::snapshot_keys::dsl::*;
+
+        snapshot_keys
+            .find(info.unique_id as i64)
+            .select(key_id)
+            .first::<String>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot key")
+    }
+
+    /// Record how the files of a snapshot were split into chunks.
+    fn snapshot_set_chunker(&self, info: &SnapshotInfo, chunker_: &str) {
+        use db::schema::snapshot_chunkers::dsl::*;
+
+        diesel::delete(snapshot_chunkers.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot chunker");
+        let new = schema::NewSnapshotChunker {
+            snapshot_id: info.unique_id as i64,
+            chunker: chunker_,
+        };
+        diesel::insert(&new)
+            .into(snapshot_chunkers)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot chunker");
+    }
+
+    /// How the files of a snapshot were split into chunks, if it was recorded.
+    fn snapshot_chunker(&self, info: &SnapshotInfo) -> Option<String> {
+        use db::schema::snapshot_chunkers::dsl::*;
+
+        snapshot_chunkers
+            .find(info.unique_id as i64)
+            .select(chunker)
+            .first::<String>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot chunker")
+    }
+
+    /// Every chunker recorded for a snapshot, without duplicates.
+    fn snapshot_chunkers(&self) -> Vec<String> {
+        use db::schema::snapshot_chunkers::dsl::*;
+
+        let mut chunkers = snapshot_chunkers
+            .select(chunker)
+            .load::<String>(&self.conn)
+            .expect("Error listing snapshot chunkers");
+        chunkers.sort();
+        chunkers.dedup();
+        chunkers
+    }
+
+    /// Record how many children the branch nodes of a snapshot's hash trees have.
+    fn snapshot_set_fanout(&self, info: &SnapshotInfo, fanout_: usize) {
+        use db::schema::snapshot_fanouts::dsl::*;
+
+        diesel::delete(snapshot_fanouts.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot fan-out");
+        let new = schema::NewSnapshotFanout {
+            snapshot_id: info.unique_id as i64,
+            fanout: fanout_ as i64,
+        };
+        diesel::insert(&new)
+            .into(snapshot_fanouts)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot fan-out");
+    }
+
+    /// How many children the branch nodes of a snapshot's hash trees have, if it was recorded.
+    fn snapshot_fanout(&self, info: &SnapshotInfo) -> Option<usize> {
+        use db::schema::snapshot_fanouts::dsl::*;
+
+        snapshot_fanouts
+            .find(info.unique_id as i64)
+            .select(fanout)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot fan-out")
+            .map(|f| f as usize)
+    }
+
+    /// Record that a snapshot only captured the contents of files that were unchanged.
+    fn snapshot_set_metadata_only(&self, info: &SnapshotInfo) {
+        use db::schema::snapshot_metadata_only::dsl::*;
+
+        diesel::delete(snapshot_metadata_only.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot metadata-only mark");
+        let new = schema::NewSnapshotMetadataOnly { snapshot_id: info.unique_id as i64 };
+        diesel::insert(&new)
+            .into(snapshot_metadata_only)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot metadata-only mark");
+    }
+
+    /// Whether a snapshot only captured the contents of files that were unchanged.
+    fn snapshot_metadata_only(&self, info: &SnapshotInfo) -> bool {
+        use db::schema::snapshot_metadata_only::dsl::*;
+
+        snapshot_metadata_only
+            .find(info.unique_id as i64)
+            .select(snapshot_id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot metadata-only mark")
+            .is_some()
+    }
+
+    /// Record the id of the snapshot of the same family that a snapshot was taken after.
+    fn snapshot_set_parent(&self, info: &SnapshotInfo, parent_id_: u64) {
+        use db::schema::snapshot_parents::dsl::*;
+
+        diesel::delete(snapshot_parents.find(info.unique_id as i64))
+            .execute(&self.conn)
+            .expect("Error deleting snapshot parent");
+        let new = schema::NewSnapshotParent {
+            snapshot_id: info.unique_id as i64,
+            parent_id: parent_id_ as i64,
+        };
+        diesel::insert(&new)
+            .into(snapshot_parents)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot parent");
+    }
+
+    /// The id of the snapshot of the same family that a snapshot was taken after, if any.
+    fn snapshot_parent(&self, info: &SnapshotInfo) -> Option<u64> {
+        use db::schema::snapshot_parents::dsl::*;
+
+        snapshot_parents
+            .find(info.unique_id as i64)
+            .select(parent_id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot parent")
+            .map(|p| p as u64)
+    }
+
+    /// Lookup exact snapshot info from family and snapshot id.
+    fn snapshot_lookup(
+        &mut self,
+        family_name_: &str,
+        snapshot_id_: u64,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
+        use db::schema::snapshots::dsl::*;
+        use db::schema::family::dsl::{family, name};
+
+        let row_opt = snapshots
+            .inner_join(family)
+            .filter(name.eq(family_name_))
+            .filter(snapshot_id.eq(snapshot_id_ as i64))
+            .select((
+                id,
+                tag,
+                family_id,
+                snapshot_id,
+                utc_datetime,
+                msg,
+                hash,
+                hash_ref,
+            ))
+            .first::<schema::Snapshot>(&self.conn)
+            .optional()
+            .expect("Error reading snapshot info");
+
+        row_opt.map(|snap| {
+            (
+                SnapshotInfo {
+                    unique_id: snap.id as u64,
+                    family_id: snap.family_id as u64,
+                    snapshot_id: snap.snapshot_id as u64,
+                },
+                ::hash::Hash { bytes: snap.hash.unwrap().to_vec() },
+                snap.hash_ref.and_then(|r| {
+                    ::hash::tree::HashRef::from_bytes(&mut &r[..]).ok()
+                }),
+            )
+        })
+    }
+
+    fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo {
+        use db::schema::snapshots::dsl::*;
+
+        let family_id_ = self.get_or_create_family_id(&family_);
+        let snapshot_id_ = 1 + self.snapshot_latest_id(family_id_).unwrap_or(0);
+
+        let new = schema::NewSnapshot {
+            family_id: family_id_,
+            snapshot_id: snapshot_id_,
+            tag: tags::Tag::Reserved as i32,
+            utc_datetime: chrono::Utc::now().naive_utc(),
+            msg: None,
+            hash: None,
+            hash_ref: None,
+        };
+
+        diesel::insert(&new)
+            .into(snapshots)
+            .execute(&self.conn)
+            .expect("Error inserting snapshot");
+
+        let unique_id_ = self.last_insert_rowid();
+
+        SnapshotInfo {
+            unique_id: unique_id_ as u64,
+            family_id: family_id_ as u64,
+            snapshot_id: snapshot_id_ as u64,
+        }
+    }
+
+    fn snapshot_update(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        msg_: &str,
+        hash_: &hash::Hash,
+        hash_ref_: &hash::tree::HashRef,
+    ) {
+        use db::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set((
+                msg.eq(Some(msg_)),
+                hash.eq(Some(&hash_.bytes)),
+                hash_ref.eq(Some(hash_ref_.as_bytes())),
+            ))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag) {
+        use db::schema::snapshots::dsl::*;
+
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set(tag.eq(tag_ as i32))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
+    /// Extract latest snapshot data for family, among the snapshots that were committed.
+    fn snapshot_latest(
+        &mut self,
+        family: &str,
+    ) -> Option<(SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
+        let family_id_opt = self.family_id_from_name(family);
+        family_id_opt.and_then(|family_id_| {
+            use db::schema::snapshots::dsl::*;
+
+            let row_opt = snapshots
+                .filter(family_id.eq(family_id_))
+                .filter(tag.ne(tags::Tag::Reserved as i32))
+                .filter(tag.ne(tags::Tag::InProgress as i32))
+                .filter(tag.ne(tags::Tag::RecoverInProgress as i32))
+                .order(snapshot_id.desc())
+                .first::<schema::Snapshot>(&self.conn)
+                .optional()
+                .expect("Error reading latest snapshot");
+
+            row_opt.map(|snap| {
+                (
+                    SnapshotInfo {
+                        unique_id: snap.id as u64,
+                        family_id: snap.family_id as u64,
+                        snapshot_id: snap.snapshot_id as u64,
+                    },
+                    ::hash::Hash { bytes: snap.hash.expect("Snapshot without top hash") },
+                    snap.hash_ref.and_then(|r| {
+                        ::hash::tree::HashRef::from_bytes(&mut &r[..]).ok()
+                    }),
+                )
+            })
+        })
+    }
+
+    fn snapshot_list(&mut self, skip_tag: Option<tags::Tag>) -> Vec<SnapshotStatus> {
+        use diesel::*;
+        use db::schema::snapshots::dsl::*;
+        use db::schema::family::dsl::family;
+        let rows = match skip_tag {
+            None => {
+                snapshots
+                    .inner_join(family)
+                    .load::<(schema::Snapshot, schema::Family)>(&self.conn)
+            }
+            Some(skip) => {
+                snapshots
+                    .inner_join(family)
+                    .filter(tag.ne(skip as i32))
+                    .load::<(schema::Snapshot, schema::Family)>(&self.conn)
+            }
+        }.unwrap();
+
+        rows.into_iter()
+            .map(|(snap, fam)| {
+                let status = tags::tag_from_num(snap.tag as i64).map_or(
+                    SnapshotWorkStatus::CommitComplete,
+                    tag_to_work_status,
+                );
+                let hash_ = snap.hash.and_then(|bytes| if bytes.is_empty() {
+                    None
+                } else {
+                    Some(::hash::Hash { bytes: bytes })
+                });
+
+                SnapshotStatus {
+                    family_name: fam.name,
+                    created: chrono::DateTime::from_utc(snap.utc_datetime, chrono::Utc),
+                    msg: snap.msg,
+                    hash: hash_,
+                    hash_ref: snap.hash_ref,
+                    status: status,
+                    info: SnapshotInfo {
+                        unique_id: snap.id as u64,
+                        snapshot_id: snap.snapshot_id as u64,
+                        family_id: fam.id as u64,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Recover snapshot information.
+    fn snapshot_recover(
+        &mut self,
+        snapshot_id_: u64,
+        family: &str,
+        created: chrono::DateTime<chrono::Utc>,
+        msg_: &str,
+        hash_ref_: &hash::tree::HashRef,
+        work_opt_: Option<SnapshotWorkStatus>,
+    ) {
+        let family_id_ = self.get_or_create_family_id(&family);
+        let insert = match self.snapshot_lookup(family, snapshot_id_) {
+            Some((_info, h, _r)) => {
+                if h.bytes != hash_ref_.hash.bytes {
+                    panic!("Snapshot already exists, but with different hash");
+                }
+                false
+            }
+            None => true,
+        };
+        if insert {
+            use db::schema::snapshots::dsl::*;
+
+            let hash_ref_bytes = hash_ref_.as_bytes();
+            let new = schema::NewSnapshot {
+                family_id: family_id_,
+                snapshot_id: snapshot_id_ as i64,
+                utc_datetime: created.naive_utc(),
+                msg: Some(msg_),
+                hash: Some(&hash_ref_.hash.bytes[..]),
+                hash_ref: Some(&hash_ref_bytes[..]),
+                tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
+            };
+
+            diesel::insert(&new)
+                .into(snapshots)
+                .execute(&self.conn)
+                .expect("Error inserting new snapshot");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_copies_the_database() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("hat-backup-from-{}", rand::random::<u64>()));
+        let copy = dir.join(format!("hat-backup-to-{}", rand::random::<u64>()));
+        let path_str = path.to_string_lossy().into_owned();
+
+        let mut store = SqliteStore::new(Path::new("migrations"), &path_str).unwrap();
+        store.gc_run_record(1);
+        store.gc_run_record(2);
+        store.flush();
+        backup_database(&path_str, &copy).unwrap();
+
+        let mut copied =
+            SqliteStore::new(Path::new("migrations"), &copy.to_string_lossy()).unwrap();
+        assert_eq!(copied.gc_generation(), 2);
+        assert!(backup_database(":memory:", &copy).is_err());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(copy).unwrap();
+    }
+}
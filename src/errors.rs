@@ -16,7 +16,8 @@ pub use self::crypto_error::CryptoError;
 pub use self::diesel_error::DieselError;
 
 pub use self::hat_error::HatError;
-use std::{error, fmt};
+use std::{error, fmt, io};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Copy, Debug)]
 pub struct RetryError;
@@ -33,8 +34,422 @@ impl error::Error for RetryError {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct CancelledError;
+
+impl fmt::Display for CancelledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        (self as &fmt::Debug).fmt(f)
+    }
+}
+
+impl error::Error for CancelledError {
+    fn description(&self) -> &str {
+        "Operation was cancelled"
+    }
+}
+
+/// The store was written by a newer version of hat than the one running.
+#[derive(Clone, Copy, Debug)]
+pub struct StoreVersionError {
+    pub required: i64,
+    pub supported: i64,
+}
+
+impl fmt::Display for StoreVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "This store needs a hat that reads format version {}, but this binary only reads up \
+             to version {}. Please upgrade hat.",
+            self.required,
+            self.supported
+        )
+    }
+}
+
+impl error::Error for StoreVersionError {
+    fn description(&self) -> &str {
+        "Store format is too new"
+    }
+}
+
+/// A snapshot is sealed with a different key than the one given.
+#[derive(Clone, Debug)]
+pub struct WrongKeyError {
+    pub snapshot_key_id: String,
+    pub given_key_id: String,
+}
+
+impl fmt::Display for WrongKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Wrong key for this snapshot: it needs key {}, but key {} was given",
+            self.snapshot_key_id,
+            self.given_key_id
+        )
+    }
+}
+
+impl error::Error for WrongKeyError {
+    fn description(&self) -> &str {
+        "Wrong key for this snapshot"
+    }
+}
+
+/// The store's latest root is older than one this host has seen before, as when an older copy of
+/// the store was put back in place of the current one.
+#[derive(Clone, Copy, Debug)]
+pub struct RollbackError {
+    pub seen: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "The store has been rolled back: its latest root is number {}, but root {} has been \
+             seen before. Refusing to use it.",
+            self.found,
+            self.seen
+        )
+    }
+}
+
+impl error::Error for RollbackError {
+    fn description(&self) -> &str {
+        "Store was rolled back"
+    }
+}
+
+/// A blob is in archival storage, and the backend cannot return it until it has been thawed.
+#[derive(Clone, Debug)]
+pub struct NeedsThawError {
+    pub blob_name: String,
+}
+
+impl fmt::Display for NeedsThawError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Blob {} is in archival storage and needs to be restored (thawed) in the backend \
+             before it can be read",
+            self.blob_name
+        )
+    }
+}
+
+impl error::Error for NeedsThawError {
+    fn description(&self) -> &str {
+        "Blob needs to be thawed"
+    }
+}
+
+/// A backend failed to store, list or return blobs.
+#[derive(Clone, Debug)]
+pub struct BackendError {
+    pub message: String,
+}
+
+impl BackendError {
+    pub fn new<S: Into<String>>(message: S) -> BackendError {
+        BackendError { message: message.into() }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for BackendError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// A directory to snapshot, or a path given along with it, could not be read.
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+    message: String,
+}
+
+impl WalkError {
+    pub fn new(path: &Path, error: io::Error) -> WalkError {
+        WalkError {
+            message: format!("Could not read {}: {}", path.display(), error),
+            path: path.to_owned(),
+            error: error,
+        }
+    }
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for WalkError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A failure of an operation, by the subsystem it comes from, as reported where hat is used.
+/// Unlike `HatError`, each variant leads through `source` to the error of its subsystem, and on
+/// to what caused that, so that the whole chain can be reported or searched with `find_source`.
+#[derive(Debug)]
+pub enum BackupError {
+    Crypto(CryptoError),
+    Backend(BackendError),
+    Index(DieselError),
+    Io(io::Error),
+    Walk(WalkError),
+    /// Failures of no particular subsystem, like a cancelled operation.
+    Other(HatError),
+}
+
+impl BackupError {
+    /// The first error of type `E` in the chain of sources.
+    pub fn find_source<E: error::Error + 'static>(&self) -> Option<&E> {
+        let mut source = error::Error::source(self);
+        while let Some(e) = source {
+            if let Some(found) = e.downcast_ref::<E>() {
+                return Some(found);
+            }
+            source = e.source();
+        }
+        None
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            BackupError::Crypto(_) => ErrorKind::Crypto,
+            BackupError::Backend(_) => ErrorKind::Backend,
+            BackupError::Index(_) => ErrorKind::Index,
+            BackupError::Io(_) |
+            BackupError::Walk(_) => ErrorKind::Io,
+            BackupError::Other(ref e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            BackupError::Crypto(ref e) => write!(f, "{}", e),
+            BackupError::Backend(ref e) => write!(f, "{}", e),
+            BackupError::Index(ref e) => write!(f, "{}", e),
+            BackupError::Io(ref e) => write!(f, "{}", e),
+            BackupError::Walk(ref e) => write!(f, "{}", e),
+            BackupError::Other(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for BackupError {
+    fn description(&self) -> &str {
+        "Backup operation failed"
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            BackupError::Crypto(ref e) => Some(e),
+            BackupError::Backend(ref e) => Some(e),
+            BackupError::Index(ref e) => Some(e),
+            BackupError::Io(ref e) => Some(e),
+            BackupError::Walk(ref e) => Some(e),
+            BackupError::Other(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<HatError> for BackupError {
+    fn from(err: HatError) -> BackupError {
+        use blob::BlobError;
+        use key::MsgError;
+
+        match err {
+            HatError::Crypto(e) |
+            HatError::Blob(BlobError::CryptoError(e)) |
+            HatError::Keys(MsgError::Blob(BlobError::CryptoError(e))) => BackupError::Crypto(e),
+            HatError::Blob(BlobError::Backend(e)) |
+            HatError::Keys(MsgError::Blob(BlobError::Backend(e))) => BackupError::Backend(e),
+            HatError::DieselError(e) |
+            HatError::Keys(MsgError::DieselError(e)) => BackupError::Index(e),
+            HatError::IO(e) => BackupError::Io(e),
+            HatError::Walk(e) => BackupError::Walk(e),
+            other => BackupError::Other(other),
+        }
+    }
+}
+
+/// Broad classes of failures, each with its own exit code, so that scripts can react to them
+/// without parsing messages. The names and codes are stable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Anything not covered below.
+    Other,
+    /// The command line was not understood.
+    Usage,
+    /// Reading or writing local files failed.
+    Io,
+    /// The backend failed to store or return a blob.
+    Backend,
+    /// The local index could not be read or updated.
+    Index,
+    /// Data did not decrypt or authenticate.
+    Crypto,
+    /// Decrypted data could not be decoded.
+    Data,
+    StoreVersion,
+    WrongKey,
+    /// Data is in archival storage and must be thawed in the backend first.
+    NeedsThaw,
+    /// The store is older than it was seen to be before.
+    Rollback,
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::Io => "io",
+            ErrorKind::Backend => "backend",
+            ErrorKind::Index => "index",
+            ErrorKind::Crypto => "crypto",
+            ErrorKind::Data => "data",
+            ErrorKind::StoreVersion => "store_version",
+            ErrorKind::WrongKey => "wrong_key",
+            ErrorKind::NeedsThaw => "needs_thaw",
+            ErrorKind::Rollback => "rollback",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::Io => 3,
+            ErrorKind::Backend => 4,
+            ErrorKind::Index => 5,
+            ErrorKind::Crypto => 6,
+            ErrorKind::Data => 7,
+            ErrorKind::StoreVersion => 8,
+            ErrorKind::WrongKey => 9,
+            ErrorKind::NeedsThaw => 10,
+            ErrorKind::Rollback => 11,
+            ErrorKind::Cancelled => 130,
+        }
+    }
+}
+
+/// A failure as reported to the user: what kind it is, what went wrong and what it concerns,
+/// like the path or blob involved.
+#[derive(Clone, Debug)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub context: Vec<(String, String)>,
+}
+
+impl ErrorReport {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> ErrorReport {
+        ErrorReport {
+            kind: kind,
+            message: message.into(),
+            context: vec![],
+        }
+    }
+
+    pub fn with_context<S: ToString>(mut self, key: &str, value: S) -> ErrorReport {
+        self.context.push((key.to_owned(), value.to_string()));
+        self
+    }
+
+    /// A single-line JSON object, e.g.
+    /// `{"code":4,"kind":"backend","message":"...","context":{"blob_id":"7"}}`.
+    pub fn to_json(&self) -> String {
+        let context: Vec<String> = self.context
+            .iter()
+            .map(|&(ref k, ref v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect();
+        format!(
+            "{{\"code\":{},\"kind\":{},\"message\":{},\"context\":{{{}}}}}",
+            self.kind.exit_code(),
+            json_string(self.kind.name()),
+            json_string(&self.message),
+            context.join(",")
+        )
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.message)?;
+        for &(ref key, ref value) in self.context.iter() {
+            write!(f, " ({}: {})", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a HatError> for ErrorReport {
+    fn from(err: &'a HatError) -> ErrorReport {
+        ErrorReport::new(err.kind(), err.to_string())
+    }
+}
+
+impl<'a> From<&'a BackupError> for ErrorReport {
+    /// The message follows the chain of sources, leaving out those it already tells.
+    fn from(err: &'a BackupError) -> ErrorReport {
+        let mut message = err.to_string();
+        let mut source = error::Error::source(err);
+        while let Some(e) = source {
+            let cause = e.to_string();
+            if !message.contains(&cause[..]) {
+                message = format!("{}: {}", message, cause);
+            }
+            source = e.source();
+        }
+        ErrorReport::new(err.kind(), message)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 mod hat_error {
 
+    use super::ErrorKind;
     use blob;
     use capnp;
     use key;
@@ -72,6 +487,62 @@ mod hat_error {
             Blob(blob::BlobError) {
                 cause;
             },
+            Cancelled(super::CancelledError) {
+                cause;
+            },
+            StoreVersion(super::StoreVersionError) {
+                cause;
+            },
+            WrongKey(super::WrongKeyError) {
+                cause;
+            },
+            Rollback(super::RollbackError) {
+                cause;
+            },
+            Walk(super::WalkError) {
+                cause;
+            },
+        }
+    }
+
+    impl HatError {
+        /// Whether this error was caused by a cancelled operation, either directly or by way of
+        /// one of the key stores.
+        pub fn is_cancelled(&self) -> bool {
+            match self {
+                &HatError::Cancelled(_) |
+                &HatError::Keys(key::MsgError::Cancelled(_)) => true,
+                _ => false,
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            fn blob_kind(e: &blob::BlobError) -> ErrorKind {
+                match *e {
+                    // Failures reported by backends that are not told apart yet are carried
+                    // as messages.
+                    blob::BlobError::Message(_) |
+                    blob::BlobError::Backend(_) => ErrorKind::Backend,
+                    blob::BlobError::CryptoError(_) => ErrorKind::Crypto,
+                    blob::BlobError::DataSerialization(_) => ErrorKind::Data,
+                    blob::BlobError::NeedsThaw(_) => ErrorKind::NeedsThaw,
+                }
+            }
+            match *self {
+                HatError::IO(_) |
+                HatError::Walk(_) => ErrorKind::Io,
+                HatError::DieselError(_) |
+                HatError::Keys(key::MsgError::DieselError(_)) => ErrorKind::Index,
+                HatError::Crypto(_) => ErrorKind::Crypto,
+                HatError::Blob(ref e) |
+                HatError::Keys(key::MsgError::Blob(ref e)) => blob_kind(e),
+                HatError::DataSerialization(_) => ErrorKind::Data,
+                HatError::StoreVersion(_) => ErrorKind::StoreVersion,
+                HatError::WrongKey(_) => ErrorKind::WrongKey,
+                HatError::Rollback(_) => ErrorKind::Rollback,
+                _ if self.is_cancelled() => ErrorKind::Cancelled,
+                _ => ErrorKind::Other,
+            }
         }
     }
 
@@ -84,10 +555,20 @@ mod hat_error {
 
 mod diesel_error {
     use diesel;
+    use std::borrow::Cow;
+    use std::io;
 
     error_type! {
         #[derive(Debug)]
         pub enum DieselError {
+            Message(Cow<'static, str>) {
+                desc (e) &**e;
+                from (s: &'static str) s.into();
+                from (s: String) s.into();
+            },
+            Io(io::Error) {
+                cause;
+            },
             SqlConnection(diesel::ConnectionError) {
                 cause;
             },
@@ -16,6 +16,7 @@
 use db::{GcData, UpdateFn, SnapshotInfo};
 #[cfg(test)]
 use std::collections::HashMap;
+use std::collections::HashSet;
 #[cfg(test)]
 use std::fmt;
 #[cfg(test)]
@@ -56,6 +57,8 @@ pub trait GcBackend {
         family_id: Id,
         fns: I,
     ) -> Result<(), Self::Err>;
+    /// The hashes that have data for the family.
+    fn list_ids_with_data(&self, family_id: Id) -> Result<mpsc::Receiver<Id>, Self::Err>;
 
     fn set_tag(&mut self, hash_id: Id, tag: tags::Tag) -> Result<(), Self::Err>;
     fn get_tag(&self, hash_id: Id) -> Result<Option<tags::Tag>, Self::Err>;
@@ -109,6 +112,15 @@ pub trait Gc<B> {
 
     fn list_unused_ids(&mut self, refs: mpsc::Sender<Id>) -> Result<(), Self::Err>;
 
+    /// The hashes that are in use, found again without relying on the tags that
+    /// `list_unused_ids()` marks with, so that the two can be checked against each other. Leaves
+    /// the tags as they are.
+    fn list_used_ids(&mut self) -> Result<HashSet<Id>, Self::Err>;
+
+    /// The hashes that snapshots use directly; every hash in use is reachable from one of them.
+    /// GCs that are not exact may return none. Leaves the tags as they are.
+    fn list_roots(&mut self) -> Result<Vec<Id>, Self::Err>;
+
     fn status(&mut self, final_ref: Id) -> Result<Option<Status>, Self::Err>;
 }
 
@@ -250,6 +262,17 @@ impl GcBackend for SafeMemoryBackend {
         Ok(())
     }
 
+    fn list_ids_with_data(&self, family_id: Id) -> Result<mpsc::Receiver<Id>, Self::Err> {
+        let (sender, receiver) = mpsc::channel();
+        for &(hash_id, family) in self.backend.lock().unwrap().gc_data.keys() {
+            if family == family_id {
+                sender.send(hash_id).unwrap();
+            }
+        }
+
+        Ok(receiver)
+    }
+
     fn set_tag(&mut self, hash_id: Id, tag: tags::Tag) -> Result<(), Self::Err> {
         self.backend.lock().unwrap().tags.insert(hash_id, tag);
         Ok(())
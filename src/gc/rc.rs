@@ -15,6 +15,7 @@
 
 use db::{GcData, SnapshotInfo};
 use gc;
+use std::collections::HashSet;
 use std::sync::mpsc;
 use tags;
 
@@ -139,6 +140,28 @@ impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
         Ok(())
     }
 
+    fn list_used_ids(&mut self) -> Result<HashSet<gc::Id>, Self::Err> {
+        let mut pending = self.list_roots()?;
+        let mut used = HashSet::new();
+        while let Some(r) = pending.pop() {
+            if used.insert(r) {
+                pending.extend(self.backend.reverse_refs(r)?);
+            }
+        }
+
+        Ok(used)
+    }
+
+    fn list_roots(&mut self) -> Result<Vec<gc::Id>, Self::Err> {
+        let mut roots = vec![];
+        for r in self.backend.list_ids_with_data(DATA_FAMILY)? {
+            if self.backend.get_data(r, DATA_FAMILY)?.num > 0 {
+                roots.push(r);
+            }
+        }
+        Ok(roots)
+    }
+
     fn status(&mut self, final_ref: gc::Id) -> Result<Option<gc::Status>, Self::Err> {
         Ok(match self.backend.get_tag(final_ref)? {
             Some(tags::Tag::Complete) |
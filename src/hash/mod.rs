@@ -20,6 +20,7 @@ use crypto;
 use db;
 
 use errors::{DieselError, RetryError};
+use hex::{FromHex, ToHex};
 
 use std::sync::{Arc, Mutex, MutexGuard};
 use tags;
@@ -191,6 +192,17 @@ impl InternalHashIndex {
         });
     }
 
+    fn unreserve(
+        &self,
+        id: u64,
+        mut queue: &mut MutexGuard<Queue>,
+        mut index: &mut db::IndexGuard,
+    ) {
+        assert!(queue.remove(&id).is_some(), "Tried to unreserve unknown hash.");
+        index.hash_delete(id);
+        self.insert_completed_in_order(&mut queue, &mut index);
+    }
+
     fn insert_completed_in_order(
         &self,
         mut queue: &mut MutexGuard<Queue>,
@@ -245,6 +257,27 @@ impl HashIndex {
         self.0.locate(hash, &queue, &mut index).is_some()
     }
 
+    /// List the leaf hashes of the tree below `top`, in order. Returns `None` if part of the tree
+    /// is not known locally.
+    pub fn leaf_hashes(&self, top: &Hash) -> Option<Vec<Hash>> {
+        let mut leafs = vec![];
+        let mut stack = match self.get_id(top) {
+            Some(id) => vec![id],
+            None => return None,
+        };
+        while let Some(id) = stack.pop() {
+            let entry = match self.get_hash(id) {
+                Some(entry) => entry,
+                None => return None,
+            };
+            match entry.childs {
+                Some(childs) => stack.extend(childs.into_iter().rev()),
+                None => leafs.push(entry.hash),
+            }
+        }
+        Some(leafs)
+    }
+
     /// Locate the local childs of the `Hash`.
     pub fn fetch_childs(&self, hash: &Hash) -> Option<Option<Vec<u64>>> {
         assert!(!hash.bytes.is_empty());
@@ -316,6 +349,12 @@ impl HashIndex {
         self.0.update_reserved(id, hash_entry, &mut queue);
     }
 
+    /// Drop a reserved `Hash` whose data could not be stored, so that it is not waited upon.
+    pub fn unreserve(&self, id: u64) {
+        let (mut queue, mut index) = self.0.lock();
+        self.0.unreserve(id, &mut queue, &mut index);
+    }
+
     /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit`
     /// includes the persistent reference that the content is available at.
     pub fn commit(&self, id: u64, entry: Option<Entry>) {
@@ -323,16 +362,72 @@ impl HashIndex {
         self.0.commit(id, entry, &mut queue, &mut index);
     }
 
+    /// Find the hashes whose hex form starts with `prefix`. At most two are returned, which is
+    /// enough to tell a unique prefix from an ambiguous one.
+    pub fn find_by_prefix(&self, prefix: &str) -> Vec<Hash> {
+        // Hashes sort bytewise, so all matches follow the smallest hash with this prefix.
+        let padded = if prefix.len() % 2 == 0 {
+            prefix.to_owned()
+        } else {
+            format!("{}0", prefix)
+        };
+        let from = match Vec::from_hex(&padded) {
+            Ok(from) => from,
+            Err(_) => return vec![],
+        };
+        let prefix = prefix.to_lowercase();
+        self.0
+            .index
+            .lock()
+            .hash_list_from(&from[..], 2)
+            .into_iter()
+            .filter(|bytes| bytes.to_hex().starts_with(&prefix))
+            .map(|bytes| Hash { bytes: bytes })
+            .collect()
+    }
+
     /// List all hash entries.
     pub fn list(&self) -> Vec<db::Entry> {
         self.0.index.lock().hash_list()
     }
 
+    /// Point the hash with this ID at another copy of its data, e.g. after its blob was rewritten.
+    pub fn set_persistent_ref(&self, id: u64, chunk_ref: &blob::ChunkRef) {
+        self.0.index.lock().hash_set_persistent_ref(id, chunk_ref)
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: u64) {
         self.0.index.lock().hash_delete(id)
     }
 
+    /// Mark a hash as unused since `utc`, keeping any earlier mark. Returns the time it was first
+    /// found unused.
+    pub fn mark_unused(&self, id: u64, utc: i64) -> i64 {
+        self.0.index.lock().hash_gc_mark(id, utc)
+    }
+
+    /// Forget that a hash was found unused, as it is in use again.
+    pub fn unmark_unused(&self, id: u64) {
+        self.0.index.lock().hash_gc_unmark(id)
+    }
+
+    /// List all hashes that are marked as unused.
+    pub fn list_marked_unused(&self) -> Vec<u64> {
+        self.0.index.lock().hash_gc_marked()
+    }
+
+    /// Remember that the data of the hash with this ID was read back and checked at `utc`.
+    pub fn set_verified(&self, id: u64, utc: i64) {
+        self.0.index.lock().hash_set_verified(id, utc)
+    }
+
+    /// When the data of each hash was last checked, by hash ID. Hashes never checked are not
+    /// listed.
+    pub fn list_verified(&self) -> Vec<(u64, i64)> {
+        self.0.index.lock().hash_list_verified()
+    }
+
     /// API related to tagging, which is useful to indicate state during operation stages.
     /// It operates directly on the underlying IDs.
     pub fn set_tag(&self, id: u64, tag: tags::Tag) {
@@ -367,6 +462,11 @@ impl HashIndex {
         self.0.index.lock().hash_read_gc_data(hash_id, family_id)
     }
 
+    /// The hashes with garbage collector metadata for the family.
+    pub fn list_gc_data_ids(&self, family_id: u64) -> Vec<u64> {
+        self.0.index.lock().hash_list_gc_data_ids(family_id)
+    }
+
     /// API related to garbage collector metadata tied to (hash id, family id) pairs.
     pub fn update_gc_data<F: db::UpdateFn>(
         &self,
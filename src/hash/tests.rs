@@ -74,6 +74,7 @@ impl HashTreeBackend for MemoryBackend {
                     offset: 0,
                     length: chunk.len(),
                     packing: None,
+                    pipeline: None,
                     key: None,
                 })
             }
@@ -112,6 +113,7 @@ impl HashTreeBackend for MemoryBackend {
                     offset: 0,
                     length: len,
                     packing: None,
+                    pipeline: None,
                     key: None,
                 },
             },
@@ -280,3 +282,48 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+fn tree_with_fanout(fanout: usize, leaves: &[Vec<u8>]) -> (MemoryBackend, HashRef) {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(LeafType::FileChunk, fanout, backend.clone());
+    for leaf in leaves {
+        ht.append(&leaf[..]).unwrap();
+    }
+    let root = ht.hash(None).unwrap();
+    (backend, root)
+}
+
+#[test]
+fn fanout_sets_tree_shape() {
+    let leaves: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 3]).collect();
+
+    let (narrow_backend, narrow) = tree_with_fanout(4, &leaves);
+    let (wide_backend, wide) = tree_with_fanout(16, &leaves);
+    assert_eq!(narrow.hash, tree_with_fanout(4, &leaves).1.hash);
+    assert_eq!(wide.hash, tree_with_fanout(16, &leaves).1.hash);
+    assert!(narrow.hash != wide.hash);
+
+    // 40 leaves take 3 levels of branches 4 wide, but only 2 levels 16 wide.
+    assert_eq!(narrow.node, NodeType::Branch(3));
+    assert_eq!(wide.node, NodeType::Branch(2));
+
+    for (backend, root) in vec![(narrow_backend, narrow), (wide_backend, wide)] {
+        let read: Vec<Vec<u8>> = LeafIterator::new(backend, root).unwrap().unwrap().collect();
+        assert_eq!(read, leaves);
+    }
+}
+
+#[test]
+fn hash_salt_namespaces_hashes() {
+    let salted = |salt: &[u8]| crypto::keys::Keeper::new_for_testing().with_hash_salt(salt);
+    let (a, b) = (crypto::keys::new_hash_salt(), crypto::keys::new_hash_salt());
+    let hash = |keys: &crypto::keys::Keeper| {
+        Hash::new(keys, NodeType::Leaf, LeafType::FileChunk, b"same contents")
+    };
+
+    // The same salt gives the same hashes, so a store still deduplicates its own data.
+    assert_eq!(hash(&salted(&a[..])), hash(&salted(&a[..])));
+    // Another salt, or none, gives other hashes for the same contents under the same key.
+    assert!(hash(&salted(&a[..])) != hash(&salted(&b[..])));
+    assert!(hash(&salted(&a[..])) != hash(&crypto::keys::Keeper::new_for_testing()));
+}
@@ -23,6 +23,7 @@ use blob::{ChunkRef, NodeType, LeafType};
 
 use capnp;
 use hash::Hash;
+use hex::ToHex;
 
 #[cfg(test)]
 use quickcheck;
@@ -40,7 +41,19 @@ pub struct HashRef {
     pub info: Option<key::Info>,
 }
 
+/// Number of hash bytes shown in a fingerprint.
+pub const FINGERPRINT_BYTES: usize = 6;
+
 impl HashRef {
+    /// A short hex prefix of the hash, for showing to humans.
+    ///
+    /// Any unambiguous prefix can be turned back into a full reference with
+    /// `Hat::resolve_hash_ref`.
+    pub fn fingerprint(&self) -> String {
+        let len = ::std::cmp::min(FINGERPRINT_BYTES, self.hash.bytes.len());
+        self.hash.bytes[..len].to_vec().to_hex()
+    }
+
     pub fn populate_msg(&self, mut msg: root_capnp::hash_ref::Builder) {
         msg.set_hash(&self.hash.bytes[..]);
         msg.set_height(From::from(self.node));
@@ -77,7 +90,7 @@ impl HashRef {
 
     pub fn from_bytes(bytes: &mut &[u8]) -> Result<HashRef, capnp::Error> {
         let reader =
-            capnp::serialize_packed::read_message(bytes, capnp::message::ReaderOptions::new())?;
+            ::util::read_packed_message(bytes, capnp::message::ReaderOptions::new())?;
         let root = reader.get_root::<root_capnp::hash_ref::Reader>()?;
         Ok(HashRef::read_msg(&root)?)
     }
@@ -90,7 +103,7 @@ impl HashRef {
         }
 
         let mut out = Vec::new();
-        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        ::util::write_packed_message(&mut out, &message).unwrap();
 
         out
     }
@@ -114,7 +127,7 @@ pub trait HashTreeBackend: Clone {
 }
 
 
-fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
+pub fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
     let mut message = capnp::message::Builder::new_default();
     {
         let root = message.init_root::<root_capnp::hash_ref_list::Builder>();
@@ -124,17 +137,17 @@ fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
         }
     }
     let mut out = Vec::new();
-    capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+    ::util::write_packed_message(&mut out, &message).unwrap();
     out
 }
 
-fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
+pub fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
     let mut out = Vec::new();
     if bytes.is_empty() {
         return Some(out);
     }
 
-    let reader = capnp::serialize_packed::read_message(
+    let reader = ::util::read_packed_message(
         &mut &bytes[..],
         capnp::message::ReaderOptions::new(),
     ).unwrap();
@@ -158,6 +171,7 @@ fn test_hash_refs_identity() {
             offset: n,
             length: n,
             packing: None,
+            pipeline: None,
             key: None,
         };
         let mut v = vec![];
@@ -190,6 +204,11 @@ fn test_hash_refs_identity() {
 }
 
 
+/// Children of each branch node of a hash tree, unless configured otherwise. Wider trees are
+/// shallower, so fewer branch nodes are fetched to reach the leaves, but every branch node is
+/// larger and more of it is written again when a tree is updated.
+pub const DEFAULT_FANOUT: usize = 8;
+
 /// A simple implementation of a hash-tree stream writer.
 ///
 /// The hash-tree is "created" as append-only and is streamed from first to last data-block. The
@@ -213,6 +232,7 @@ pub struct SimpleHashTreeWriter<B> {
 impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
     /// Create a new hash-tree to be stored through 'backend' with node order 'order'.
     pub fn new(leaf_type: LeafType, order: usize, backend: B) -> SimpleHashTreeWriter<B> {
+        assert!(order >= 2, "Hash tree nodes need room for at least 2 children");
         SimpleHashTreeWriter {
             backend: backend,
             order: order,
@@ -241,6 +261,13 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
         self.append_at(0, chunk, None, None)
     }
 
+    /// Append a data-block that is already stored, by its hash id and reference.
+    ///
+    /// This is equivalent to `append()` of the same data, but skips storing the block again.
+    pub fn append_known(&mut self, id: u64, hash_ref: HashRef) -> Result<(), B::Err> {
+        self.append_hashref_at(0, id, hash_ref, None)
+    }
+
     fn append_at(
         &mut self,
         level: usize,
@@ -463,11 +490,19 @@ impl Visitor for LeafVisitor {
     }
 }
 
+impl<B: HashTreeBackend> LeafIterator<B> {
+    /// The next leaf, or the error that kept it from being fetched. Iterating instead panics on
+    /// errors.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
+        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor)? {}
+        Ok(self.visitor.leafs.pop_front())
+    }
+}
+
 impl<B: HashTreeBackend> Iterator for LeafIterator<B> {
     type Item = Vec<u8>;
 
     fn next(&mut self) -> Option<Vec<u8>> {
-        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor).unwrap() {}
-        self.visitor.leafs.pop_front()
+        self.try_next().unwrap()
     }
 }
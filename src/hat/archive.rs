@@ -0,0 +1,315 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Restoring a snapshot as a tar archive instead of as loose files.
+//!
+//! Archives are POSIX ustar, with pax extended headers for paths, link targets, sizes and ids
+//! that do not fit in the ustar fields. The size of a file is written before its contents, so
+//! it is added up from the chunk references of the file first; the chunks themselves are then
+//! fetched one at a time as they are written.
+
+use backend::StoreBackend;
+use crypto;
+use errors::HatError;
+use hash;
+use hash::tree::HashTreeBackend;
+use hat::cat::FileReader;
+use hat::family::Family;
+use hat::walker;
+use key;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use util::CancellationToken;
+
+
+const BLOCK: usize = 512;
+
+const REGULAR: u8 = b'0';
+const SYMLINK: u8 = b'2';
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+
+/// Largest number that fits in an octal field of `len` bytes, with its terminating NUL.
+fn octal_max(len: usize) -> u64 {
+    (1 << (3 * (len - 1))) - 1
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// A pax record: its own length in decimal, a space, `key=value` and a newline.
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    out.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+/// What an archive entry says about itself, besides its contents.
+struct Member<'a> {
+    path: &'a [u8],
+    kind: u8,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    size: u64,
+    mtime: u64,
+    link: &'a [u8],
+}
+
+/// Writes tar archive members to `out`, one after the other.
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out: out }
+    }
+
+    fn write_header(&mut self, member: &Member) -> io::Result<()> {
+        // Whatever does not fit in the ustar header goes in a pax header before it.
+        let mut pax = vec![];
+        if member.path.len() > 100 {
+            pax_record(&mut pax, "path", member.path);
+        }
+        if member.link.len() > 100 {
+            pax_record(&mut pax, "linkpath", member.link);
+        }
+        if member.size > octal_max(12) {
+            pax_record(&mut pax, "size", member.size.to_string().as_bytes());
+        }
+        if member.uid > octal_max(8) {
+            pax_record(&mut pax, "uid", member.uid.to_string().as_bytes());
+        }
+        if member.gid > octal_max(8) {
+            pax_record(&mut pax, "gid", member.gid.to_string().as_bytes());
+        }
+        if member.mtime > octal_max(12) {
+            pax_record(&mut pax, "mtime", member.mtime.to_string().as_bytes());
+        }
+        if !pax.is_empty() {
+            let pax_member = Member {
+                path: b"././@PaxHeader",
+                kind: PAX_HEADER,
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: pax.len() as u64,
+                mtime: 0,
+                link: b"",
+            };
+            self.write_header(&pax_member)?;
+            self.out.write_all(&pax[..])?;
+            self.pad(pax.len() as u64)?;
+        }
+
+        let mut header = [0u8; BLOCK];
+        let path = &member.path[..cmp::min(member.path.len(), 100)];
+        header[..path.len()].copy_from_slice(path);
+        write_octal(&mut header[100..108], u64::from(member.mode & 0o7777));
+        write_octal(&mut header[108..116], member.uid.min(octal_max(8)));
+        write_octal(&mut header[116..124], member.gid.min(octal_max(8)));
+        write_octal(&mut header[124..136], member.size.min(octal_max(12)));
+        write_octal(&mut header[136..148], member.mtime.min(octal_max(12)));
+        header[156] = member.kind;
+        let link = &member.link[..cmp::min(member.link.len(), 100)];
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is taken with its own field filled with spaces.
+        for b in header[148..156].iter_mut() {
+            *b = b' ';
+        }
+        let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+        write_octal(&mut header[148..155], sum);
+        header[155] = b' ';
+
+        self.out.write_all(&header[..])
+    }
+
+    /// Fill up the block that `len` bytes of contents ended in.
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rest = (len % BLOCK as u64) as usize;
+        if rest > 0 {
+            self.out.write_all(&[0u8; BLOCK][rest..])?;
+        }
+        Ok(())
+    }
+
+    pub fn append_dir(&mut self, path: &[u8], info: &key::Info) -> io::Result<()> {
+        let mut path = path.to_vec();
+        path.push(b'/');
+        self.write_header(&Member {
+            path: &path[..],
+            kind: DIRECTORY,
+            size: 0,
+            link: b"",
+            ..member_info(info, 0o755)
+        })
+    }
+
+    pub fn append_symlink(
+        &mut self,
+        path: &[u8],
+        target: &[u8],
+        info: &key::Info,
+    ) -> io::Result<()> {
+        self.write_header(&Member {
+            path: path,
+            kind: SYMLINK,
+            size: 0,
+            link: target,
+            ..member_info(info, 0o777)
+        })
+    }
+
+    /// Append a file of `size` bytes read from `data`, which must hold exactly that many.
+    pub fn append_file<R: Read>(
+        &mut self,
+        path: &[u8],
+        info: &key::Info,
+        size: u64,
+        data: &mut R,
+    ) -> io::Result<()> {
+        self.write_header(&Member {
+            path: path,
+            kind: REGULAR,
+            size: size,
+            link: b"",
+            ..member_info(info, 0o644)
+        })?;
+        let copied = io::copy(&mut data.by_ref().take(size), &mut self.out)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Expected {} bytes, but read {}", size, copied),
+            ));
+        }
+        if data.read(&mut [0u8; 1])? > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File is longer than {} bytes", size),
+            ));
+        }
+        self.pad(size)
+    }
+
+    /// End the archive, and return where it was written.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn member_info(info: &key::Info, default_mode: u32) -> Member<'static> {
+    Member {
+        path: b"",
+        kind: REGULAR,
+        mode: info.permissions.as_ref().map_or(default_mode, |p| p.mode()),
+        uid: info.user_id.unwrap_or(0),
+        gid: info.group_id.unwrap_or(0),
+        size: 0,
+        mtime: info.modified_ts_secs.unwrap_or(0),
+        link: b"",
+    }
+}
+
+/// Adds up the plaintext length of the leaf chunks of a hash tree, without fetching them.
+struct SizeVisitor<'a, B: 'a> {
+    backend: &'a key::HashStoreBackend<B>,
+    size: u64,
+    unknown: bool,
+}
+
+impl<'a, B: StoreBackend> hash::tree::Visitor for SizeVisitor<'a, B> {
+    fn leaf_enter(&mut self, href: &hash::tree::HashRef) -> bool {
+        // The chunk may have been sealed again since `href` was written.
+        let chunk_ref = self.backend.fetch_persistent_ref(&href.hash).unwrap_or_else(|| {
+            href.persistent_ref.clone()
+        });
+        if chunk_ref.length > 0 {
+            match crypto::RefKey::plaintext_len(&chunk_ref) {
+                Some(len) if chunk_ref.packing.is_none() => self.size += len as u64,
+                _ => self.unknown = true,
+            }
+        }
+        false
+    }
+}
+
+fn file_size<B: StoreBackend>(
+    backend: &key::HashStoreBackend<B>,
+    file_ref: hash::tree::HashRef,
+) -> Result<u64, HatError> {
+    let mut visitor = SizeVisitor {
+        backend: backend,
+        size: 0,
+        unknown: false,
+    };
+    if let Some(mut walker) = hash::tree::Walker::new(backend.clone(), file_ref)? {
+        while walker.resume(&mut visitor)? {}
+    }
+    if visitor.unknown {
+        return Err(From::from("Cannot tell the size of a file with packed chunks"));
+    }
+    Ok(visitor.size)
+}
+
+/// Append everything in the directory `dir_ref` to `tar`, with paths starting with `prefix`.
+pub fn write_dir<B: StoreBackend, W: Write>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    tar: &mut TarWriter<W>,
+    prefix: &mut Vec<u8>,
+    dir_ref: hash::tree::HashRef,
+    cancel: &CancellationToken,
+) -> Result<(), HatError> {
+    for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+        cancel.check()?;
+        let prefix_len = prefix.len();
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+        }
+        prefix.extend_from_slice(&entry.info.name[..]);
+
+        match content {
+            walker::Content::Data(file_ref) => {
+                let size = file_size(backend, file_ref.clone())?;
+                let mut reader = FileReader::new(backend.clone(), file_ref)?;
+                tar.append_file(&prefix[..], &entry.info, size, &mut reader)?;
+            }
+            walker::Content::Dir(dir_ref) => {
+                tar.append_dir(&prefix[..], &entry.info)?;
+                write_dir(family, backend, tar, prefix, dir_ref, cancel)?;
+            }
+            walker::Content::Link(target) => {
+                tar.append_symlink(&prefix[..], target.as_os_str().as_bytes(), &entry.info)?;
+            }
+        }
+        prefix.truncate(prefix_len);
+    }
+    Ok(())
+}
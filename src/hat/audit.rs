@@ -0,0 +1,104 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An append-only log of the changes made to the store.
+//!
+//! Each entry carries a chain hash: a keyed fingerprint of the entry and the chain hash of the
+//! entry before it. Changing, inserting or removing an entry breaks the chain from there on, and
+//! without the store's keys the chain can not be forged again. Removing entries from the end is
+//! only noticed against a chain hash kept from before.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use crypto;
+use db;
+use errors::HatError;
+use hex::ToHex;
+
+/// A snapshot was committed; affects its family id and snapshot id.
+pub const SNAPSHOT_COMMIT: &'static str = "snapshot-commit";
+/// A snapshot was deleted; affects its family id and snapshot id.
+pub const SNAPSHOT_DELETE: &'static str = "snapshot-delete";
+/// The GC deleted unused data; affects the ids of the deleted blobs.
+pub const GC: &'static str = "gc";
+/// Chunks were moved out of blobs, which were deleted; affects the ids of those blobs.
+pub const COMPACTION: &'static str = "compaction";
+
+const CHAIN_BYTES: usize = 32;
+
+fn chain_hash(keys: &crypto::keys::Keeper, prev: &[u8], entry: &db::AuditEntry) -> Vec<u8> {
+    let mut msg = prev.to_vec();
+    msg.write_i64::<LittleEndian>(entry.seq).unwrap();
+    msg.write_i64::<LittleEndian>(entry.utc).unwrap();
+    msg.write_u64::<LittleEndian>(entry.operation.len() as u64).unwrap();
+    msg.extend_from_slice(entry.operation.as_bytes());
+    msg.write_u64::<LittleEndian>(entry.affected.len() as u64).unwrap();
+    for id in entry.affected.iter() {
+        msg.write_i64::<LittleEndian>(*id).unwrap();
+    }
+    let mut out = vec![0; CHAIN_BYTES];
+    keys.fingerprint(&msg[..], b"hat-audit-log~~~", &mut out[..]);
+    out
+}
+
+/// Add an entry for `operation` to the end of the log. It is committed with the next flush of
+/// the index.
+pub fn append(
+    keys: &crypto::keys::Keeper,
+    index: &db::Index,
+    utc: i64,
+    operation: &str,
+    affected: Vec<i64>,
+) {
+    let mut index = index.lock();
+    let (seq, prev) = match index.audit_last() {
+        Some(last) => (last.seq + 1, last.chain),
+        None => (1, vec![]),
+    };
+    let mut entry = db::AuditEntry {
+        seq: seq,
+        utc: utc,
+        operation: operation.to_owned(),
+        affected: affected,
+        chain: vec![],
+    };
+    entry.chain = chain_hash(keys, &prev[..], &entry);
+    index.audit_append(&entry);
+}
+
+/// Check that `entries` are a whole, unbroken log. Returns the chain hash of the last entry.
+pub fn verify(
+    keys: &crypto::keys::Keeper,
+    entries: &[db::AuditEntry],
+) -> Result<Vec<u8>, HatError> {
+    let mut prev = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.seq != i as i64 + 1 {
+            return Err(From::from(format!(
+                "Audit log is broken: expected entry {} but found entry {}",
+                i + 1,
+                entry.seq
+            )));
+        }
+        if chain_hash(keys, &prev[..], entry) != entry.chain {
+            return Err(From::from(format!(
+                "Audit log is broken at entry {} ({}): chain hash {} does not match",
+                entry.seq,
+                entry.operation,
+                entry.chain.to_hex()
+            )));
+        }
+        prev = entry.chain.clone();
+    }
+    Ok(prev)
+}
@@ -0,0 +1,110 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading a single file of a snapshot as a stream of bytes.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker;
+use key;
+use std::cmp;
+use std::io;
+
+
+/// The contents of a file in a snapshot, fetched and checked one chunk at a time as they are
+/// read. A chunk that cannot be fetched or fails its check is a read error; everything before it
+/// has been read already.
+pub struct FileReader<B: StoreBackend> {
+    tree: Option<hash::tree::LeafIterator<key::HashStoreBackend<B>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<B: StoreBackend> FileReader<B> {
+    pub fn new(
+        backend: key::HashStoreBackend<B>,
+        file_ref: hash::tree::HashRef,
+    ) -> Result<FileReader<B>, HatError> {
+        Ok(FileReader {
+            tree: hash::tree::LeafIterator::new(backend, file_ref)?,
+            chunk: vec![],
+            pos: 0,
+        })
+    }
+
+    /// What is left of the file up to the end of its next chunk, or `None` at the end of the
+    /// file. Unlike reading, this keeps the error that stopped the chunk from being fetched.
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, HatError> {
+        if self.pos < self.chunk.len() {
+            let rest = self.chunk[self.pos..].to_vec();
+            self.pos = self.chunk.len();
+            return Ok(Some(rest));
+        }
+        match self.tree {
+            Some(ref mut tree) => Ok(tree.try_next()?),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<B: StoreBackend> io::Read for FileReader<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            let next = self.next_chunk().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            })?;
+            match next {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Find the file at `path`, given as names separated by `/`, in the directory `dir_ref`.
+pub fn find_file<B: StoreBackend>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    dir_ref: hash::tree::HashRef,
+    path: &str,
+) -> Result<hash::tree::HashRef, HatError> {
+    let not_found = || HatError::from(format!("No file {} in the snapshot", path));
+    let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+    let mut dir_ref = dir_ref;
+    while let Some(name) = names.next() {
+        let content = family
+            .fetch_dir_data(dir_ref, backend.clone())?
+            .into_iter()
+            .find(|&(ref entry, _)| &entry.info.name[..] == name.as_bytes())
+            .map(|(_, content)| content);
+        match (content, names.peek().is_some()) {
+            (Some(walker::Content::Dir(href)), true) => dir_ref = href,
+            (Some(walker::Content::Data(href)), false) => return Ok(href),
+            (Some(walker::Content::Dir(_)), false) => {
+                return Err(From::from(format!("{} is a directory", path)))
+            }
+            _ => return Err(not_found()),
+        }
+    }
+    Err(not_found())
+}
@@ -0,0 +1,167 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compare a snapshot against the directory it was taken from.
+//!
+//! File contents are compared by recomputing the chunk hashes of the source files and matching
+//! them against the leaves of the snapshot's hash trees, as found in the hash index. No data is
+//! fetched from the backend apart from directory listings.
+
+use backend::StoreBackend;
+use blob;
+use crypto;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker;
+use key;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// The path exists in the source but not in the snapshot.
+    MissingFromSnapshot(PathBuf),
+    /// The path exists in the snapshot but not in the source.
+    MissingFromSource(PathBuf),
+    /// The path exists in both, but its contents or kind differ.
+    ContentChanged(PathBuf),
+}
+
+pub struct SourceComparer<'a, B: 'a> {
+    hash_index: &'a hash::HashIndex,
+    keys: &'a crypto::keys::Keeper,
+    family: &'a Family<B>,
+    backend: key::HashStoreBackend<B>,
+    chunker: key::Chunker,
+    divergences: Vec<Divergence>,
+}
+
+impl<'a, B: StoreBackend> SourceComparer<'a, B> {
+    pub fn new(
+        hash_index: &'a hash::HashIndex,
+        keys: &'a crypto::keys::Keeper,
+        family: &'a Family<B>,
+        backend: key::HashStoreBackend<B>,
+        chunker: key::Chunker,
+    ) -> SourceComparer<'a, B> {
+        SourceComparer {
+            hash_index: hash_index,
+            keys: keys,
+            family: family,
+            backend: backend,
+            chunker: chunker,
+            divergences: vec![],
+        }
+    }
+
+    pub fn into_divergences(self) -> Vec<Divergence> {
+        self.divergences
+    }
+
+    /// Compare the snapshot directory `dir_ref` with the source directory at `path`.
+    pub fn compare_dir(
+        &mut self,
+        dir_ref: hash::tree::HashRef,
+        path: PathBuf,
+    ) -> Result<(), HatError> {
+        let mut stored: BTreeMap<Vec<u8>, walker::Content> = self.family
+            .fetch_dir_data(dir_ref, self.backend.clone())?
+            .into_iter()
+            .map(|(entry, content)| (entry.info.name, content))
+            .collect();
+
+        let mut names = vec![];
+        for dir_entry in fs::read_dir(&path)? {
+            let dir_entry = dir_entry?;
+            // Names that are not valid UTF-8 are skipped during backup as well.
+            if let Some(name) = dir_entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort();
+
+        for name in names {
+            let mut full_path = path.clone();
+            full_path.push(&name);
+
+            let meta = fs::symlink_metadata(&full_path)?;
+            let is_link = meta.file_type().is_symlink();
+            if !meta.is_file() && !meta.is_dir() && !is_link {
+                // Unsupported file kinds are never backed up.
+                continue;
+            }
+
+            match stored.remove(name.as_bytes()) {
+                None => {
+                    self.divergences.push(
+                        Divergence::MissingFromSnapshot(full_path),
+                    )
+                }
+                Some(walker::Content::Dir(href)) if meta.is_dir() => {
+                    self.compare_dir(href, full_path)?
+                }
+                Some(walker::Content::Data(href)) if meta.is_file() => {
+                    if !self.same_contents(&full_path, &href.hash)? {
+                        self.divergences.push(Divergence::ContentChanged(full_path));
+                    }
+                }
+                Some(walker::Content::Link(target)) if is_link => {
+                    if fs::read_link(&full_path)? != target {
+                        self.divergences.push(Divergence::ContentChanged(full_path));
+                    }
+                }
+                Some(_) => self.divergences.push(Divergence::ContentChanged(full_path)),
+            }
+        }
+
+        for (name, _) in stored {
+            let mut full_path = path.clone();
+            full_path.push(&*String::from_utf8_lossy(&name[..]));
+            self.divergences.push(Divergence::MissingFromSource(full_path));
+        }
+
+        Ok(())
+    }
+
+    fn same_contents(&self, path: &PathBuf, top: &hash::Hash) -> Result<bool, HatError> {
+        let stored = match self.hash_index.leaf_hashes(top) {
+            Some(leafs) => leafs,
+            None => return Ok(false),
+        };
+
+        // Chunk the file exactly like the key store did.
+        let mut source = vec![];
+        for chunk in self.chunker.chunks(fs::File::open(path)?) {
+            source.push(self.leaf_hash(&chunk?[..]));
+        }
+        if source.is_empty() {
+            // Empty files are stored as a single empty chunk.
+            source.push(self.leaf_hash(&[]));
+        }
+
+        Ok(source == stored)
+    }
+
+    fn leaf_hash(&self, chunk: &[u8]) -> hash::Hash {
+        hash::Hash::new(
+            self.keys,
+            blob::NodeType::Leaf,
+            blob::LeafType::FileChunk,
+            chunk,
+        )
+    }
+}
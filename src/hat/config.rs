@@ -0,0 +1,194 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operational defaults kept with a store, so that they need not be given with every command.
+//!
+//! The file holds one `key = value` setting per line, as in TOML; `#` starts a comment. Values
+//! are numbers or `true`/`false`. Every key is checked when the file is loaded: an unknown key
+//! or a value out of range is an error, not ignored. Flags and environment variables are parsed
+//! into a `StoreConfig` the same way, and take precedence over the file.
+
+use errors::HatError;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+
+/// Settings for a store. `None` leaves a setting to the next source, and in the end to its
+/// default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreConfig {
+    /// Largest blob to store.
+    pub max_blob_size: Option<usize>,
+    /// Blobs to upload at the same time.
+    pub max_uploads: Option<usize>,
+    /// Blobs covered by one fsync.
+    pub fsync_batch: Option<usize>,
+    /// Most time, in milliseconds, that a blob waits for fsync.
+    pub fsync_delay_ms: Option<u64>,
+    /// Map blob files into memory to read them.
+    pub mmap_reads: Option<bool>,
+    /// Levels of subdirectories to keep blob files in.
+    pub shard_depth: Option<usize>,
+    /// Files and file segments to read at the same time during a commit.
+    pub read_concurrency: Option<usize>,
+    /// Files larger than this are stored apart from the others during a commit.
+    pub max_file_size: Option<u64>,
+}
+
+fn parse_number<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} must be a number, not {:?}", key, value))
+}
+
+fn parse_positive(key: &str, value: &str) -> Result<usize, String> {
+    match parse_number(key, value)? {
+        0 => Err(format!("{} must be at least 1", key)),
+        n => Ok(n),
+    }
+}
+
+impl StoreConfig {
+    /// Set `key` to `value`, given as text.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "max_blob_size" => {
+                let size = parse_number(key, value)?;
+                if size < 1024 {
+                    return Err(format!("{} must be at least 1024 bytes", key));
+                }
+                self.max_blob_size = Some(size);
+            }
+            "max_uploads" => self.max_uploads = Some(parse_positive(key, value)?),
+            "fsync_batch" => self.fsync_batch = Some(parse_positive(key, value)?),
+            "fsync_delay_ms" => self.fsync_delay_ms = Some(parse_number(key, value)?),
+            "mmap_reads" => {
+                self.mmap_reads = Some(match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(format!("{} must be true or false, not {:?}", key, value)),
+                })
+            }
+            "shard_depth" => self.shard_depth = Some(parse_number(key, value)?),
+            "read_concurrency" => self.read_concurrency = Some(parse_positive(key, value)?),
+            "max_file_size" => self.max_file_size = Some(parse_number(key, value)?),
+            _ => return Err(format!("Unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Parse the contents of a configuration file.
+    pub fn parse(text: &str) -> Result<StoreConfig, String> {
+        let mut config = StoreConfig::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.splitn(2, '#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => {
+                    if let Err(e) = config.set(key.trim(), value.trim()) {
+                        return Err(format!("line {}: {}", i + 1, e));
+                    }
+                }
+                _ => return Err(format!("line {}: expected `key = value`", i + 1)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Load the configuration file at `path`. Without that file, nothing is set.
+    pub fn load(path: &Path) -> Result<StoreConfig, HatError> {
+        let mut text = String::new();
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut text)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(StoreConfig::default())
+            }
+            Err(e) => return Err(From::from(e)),
+        }
+        StoreConfig::parse(&text).map_err(|e| From::from(format!("{}: {}", path.display(), e)))
+    }
+
+    /// These settings, with those not set taken from `fallback`.
+    pub fn or(self, fallback: StoreConfig) -> StoreConfig {
+        StoreConfig {
+            max_blob_size: self.max_blob_size.or(fallback.max_blob_size),
+            max_uploads: self.max_uploads.or(fallback.max_uploads),
+            fsync_batch: self.fsync_batch.or(fallback.fsync_batch),
+            fsync_delay_ms: self.fsync_delay_ms.or(fallback.fsync_delay_ms),
+            mmap_reads: self.mmap_reads.or(fallback.mmap_reads),
+            shard_depth: self.shard_depth.or(fallback.shard_depth),
+            read_concurrency: self.read_concurrency.or(fallback.read_concurrency),
+            max_file_size: self.max_file_size.or(fallback.max_file_size),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_config() {
+        let config = StoreConfig::parse(
+            "# Defaults for this store.\n\
+             max_blob_size = 8388608\n\
+             \n\
+             max_uploads = 8  # a fast link\n\
+             mmap_reads = true\n\
+             max_file_size=1073741824\n",
+        ).unwrap();
+        assert_eq!(
+            config,
+            StoreConfig {
+                max_blob_size: Some(8388608),
+                max_uploads: Some(8),
+                mmap_reads: Some(true),
+                max_file_size: Some(1073741824),
+                ..StoreConfig::default()
+            }
+        );
+        assert_eq!(StoreConfig::parse("").unwrap(), StoreConfig::default());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_config() {
+        let err = StoreConfig::parse("max_uploads = 4\nmax_uploads = 0\n").unwrap_err();
+        assert_eq!(err, "line 2: max_uploads must be at least 1");
+        let err = StoreConfig::parse("max_upload = 4").unwrap_err();
+        assert_eq!(err, "line 1: Unknown setting \"max_upload\"");
+        assert!(StoreConfig::parse("mmap_reads = yes").is_err());
+        assert!(StoreConfig::parse("shard_depth = -1").is_err());
+        assert!(StoreConfig::parse("max_blob_size = 10").is_err());
+        assert!(StoreConfig::parse("max_uploads").is_err());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let file = StoreConfig::parse("max_uploads = 8\nshard_depth = 2\n").unwrap();
+        let mut flags = StoreConfig::default();
+        flags.set("max_uploads", "2").unwrap();
+        flags.set("mmap_reads", "true").unwrap();
+
+        let config = flags.or(file);
+        assert_eq!(config.max_uploads, Some(2));
+        assert_eq!(config.shard_depth, Some(2));
+        assert_eq!(config.mmap_reads, Some(true));
+        assert_eq!(config.fsync_batch, None);
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health checks of a host, its store and its backend.
+//!
+//! Nothing is changed beyond applying pending index migrations: the index is opened without
+//! resuming unfinished commands, and blobs are only fetched and decrypted.
+
+use backend::StoreBackend;
+use blob;
+use chrono;
+use crypto;
+use db;
+use errors::{ErrorKind, ErrorReport, HatError, StoreVersionError};
+use hat::{READER_VERSION, check_environment, hash_index_name};
+use hex::ToHex;
+use snapshot;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tags;
+use util::{Clock, SystemClock};
+
+
+/// Blobs fetched and decrypted by the blob check.
+const SAMPLE_BLOBS: usize = 3;
+
+/// How far ahead of the clock a snapshot may have been taken before the clock is suspect.
+const CLOCK_SLACK_MINUTES: i64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Worth a look, but nothing is broken.
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug)]
+pub struct DoctorCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found, and for warnings and failures what to do about it.
+    pub message: String,
+    /// The error behind a failure.
+    pub error: Option<ErrorReport>,
+}
+
+impl DoctorCheck {
+    fn pass<S: Into<String>>(name: &'static str, message: S) -> DoctorCheck {
+        DoctorCheck {
+            name: name,
+            status: CheckStatus::Pass,
+            message: message.into(),
+            error: None,
+        }
+    }
+
+    fn warn<S: Into<String>>(name: &'static str, message: S) -> DoctorCheck {
+        DoctorCheck {
+            name: name,
+            status: CheckStatus::Warn,
+            message: message.into(),
+            error: None,
+        }
+    }
+
+    fn fail<S: Into<String>>(name: &'static str, message: S, error: ErrorReport) -> DoctorCheck {
+        DoctorCheck {
+            name: name,
+            status: CheckStatus::Fail,
+            message: message.into(),
+            error: Some(error),
+        }
+    }
+}
+
+/// Run every check against the store in `repository_root` and `backend`.
+pub fn doctor<B: StoreBackend>(
+    migrations_dir: &Path,
+    repository_root: PathBuf,
+    backend: &B,
+) -> Vec<DoctorCheck> {
+    let crypto_check = match check_environment() {
+        Ok(()) => {
+            DoctorCheck::pass(
+                "crypto",
+                format!(
+                    "The crypto library passed its self-test; available: {}",
+                    crypto::capabilities().available().join(", ")
+                ),
+            )
+        }
+        Err(e) => {
+            DoctorCheck::fail(
+                "crypto",
+                "The crypto library failed its self-test; reinstall libsodium for this host",
+                ErrorReport::from(&e),
+            )
+        }
+    };
+    // Keys cannot be derived without a working crypto library.
+    let keys = match crypto_check.status {
+        CheckStatus::Pass => Some(Arc::new(crypto::keys::Keeper::new("hat-master-key"))),
+        _ => None,
+    };
+
+    let mut checks = vec![crypto_check];
+    checks.extend(run_checks(
+        keys,
+        open_index(migrations_dir, repository_root),
+        backend,
+        &SystemClock,
+    ));
+    checks
+}
+
+/// Open the index of the store in `repository_root`, without creating it if it is missing.
+pub fn open_index(
+    migrations_dir: &Path,
+    repository_root: PathBuf,
+) -> Result<Arc<db::Index>, HatError> {
+    let path = hash_index_name(repository_root);
+    if !Path::new(&path).is_file() {
+        return Err(From::from(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No index at {}", path),
+        )));
+    }
+    let db = db::Index::new(&migrations_dir.canonicalize()?, &path)?;
+
+    let required = db.lock().store_min_reader_version().unwrap_or(1);
+    if required > READER_VERSION {
+        return Err(From::from(StoreVersionError {
+            required: required,
+            supported: READER_VERSION,
+        }));
+    }
+    Ok(Arc::new(db))
+}
+
+/// The checks that need the store: backend, index, blobs and clock. Checks that depend on a
+/// part that failed are reported as warnings.
+pub fn run_checks<B: StoreBackend>(
+    keys: Option<Arc<crypto::keys::Keeper>>,
+    index: Result<Arc<db::Index>, HatError>,
+    backend: &B,
+    clock: &Clock,
+) -> Vec<DoctorCheck> {
+    let mut checks = vec![check_backend(backend)];
+    let backend_ok = checks[0].status == CheckStatus::Pass;
+
+    let db = match index {
+        Ok(db) => {
+            checks.push(DoctorCheck::pass("index", "The index opened"));
+            db
+        }
+        Err(e) => {
+            checks.push(DoctorCheck::fail(
+                "index",
+                "The index could not be opened; check --hat_cache_dir and the permissions of \
+                 the files in it",
+                ErrorReport::from(&e),
+            ));
+            checks.push(DoctorCheck::warn("blobs", "Not checked without an index"));
+            checks.push(DoctorCheck::warn("clock", "Not checked without an index"));
+            return checks;
+        }
+    };
+
+    checks.push(match keys {
+        Some(_) if !backend_ok => DoctorCheck::warn("blobs", "Not checked without a backend"),
+        Some(keys) => check_blobs(keys, db.clone(), backend),
+        None => DoctorCheck::warn("blobs", "Not checked without a working crypto library"),
+    });
+    checks.push(check_clock(db, clock));
+    checks
+}
+
+fn check_backend<B: StoreBackend>(backend: &B) -> DoctorCheck {
+    match backend.list_blobs().next() {
+        Some(Err(e)) => {
+            DoctorCheck::fail(
+                "backend",
+                "The backend could not list its blobs; check that it is reachable and that its \
+                 credentials are valid",
+                ErrorReport::new(ErrorKind::Backend, e),
+            )
+        }
+        _ => DoctorCheck::pass("backend", "The backend listed its blobs"),
+    }
+}
+
+fn check_blobs<B: StoreBackend>(
+    keys: Arc<crypto::keys::Keeper>,
+    db: Arc<db::Index>,
+    backend: &B,
+) -> DoctorCheck {
+    let blob_index = match blob::BlobIndex::new(keys.clone(), db) {
+        Ok(blob_index) => blob_index,
+        Err(e) => {
+            return DoctorCheck::fail(
+                "blobs",
+                "The blob index could not be read",
+                ErrorReport::from(&HatError::from(e)),
+            )
+        }
+    };
+
+    let blobs = blob_index.list_by_tag(tags::Tag::Done);
+    if blobs.is_empty() {
+        return DoctorCheck::warn("blobs", "There are no blobs to check yet");
+    }
+    // Spread the sample over the store, from the oldest blob to the newest.
+    let step = (blobs.len() + SAMPLE_BLOBS - 1) / SAMPLE_BLOBS;
+    let sample: Vec<&blob::BlobDesc> = blobs
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i % step == 0)
+        .map(|(_, desc)| desc)
+        .collect();
+
+    for desc in sample.iter() {
+        let fail = |message: String, error: ErrorReport| {
+            DoctorCheck::fail(
+                "blobs",
+                message,
+                error.with_context("blob", desc.name.to_hex()),
+            )
+        };
+        let ct = match backend.retrieve(&desc.name[..]) {
+            Ok(Some(ct)) => ct,
+            Ok(None) => {
+                return fail(
+                    format!(
+                        "Blob {} is missing from the backend; run verify to find what it held",
+                        desc.id
+                    ),
+                    ErrorReport::new(ErrorKind::Backend, "blob not found"),
+                )
+            }
+            Err(e) => {
+                return fail(
+                    format!("Blob {} could not be fetched", desc.id),
+                    ErrorReport::new(ErrorKind::Backend, e),
+                )
+            }
+        };
+        let ct = crypto::CipherTextRef::new(&ct[..]);
+        if let Some(checksum) = blob_index.checksum(desc) {
+            if ct.checksum() != checksum {
+                return fail(
+                    format!(
+                        "Blob {} changed since it was uploaded; the backend is corrupting data",
+                        desc.id
+                    ),
+                    ErrorReport::new(ErrorKind::Crypto, "checksum mismatch"),
+                );
+            }
+        }
+        let refs = blob::BlobReader::new(keys.clone(), ct)
+            .map_err(HatError::from)
+            .and_then(|reader| reader.refs().map_err(HatError::from));
+        if let Err(e) = refs {
+            return fail(
+                format!(
+                    "Blob {} could not be decrypted; check that the store is opened with its own \
+                     keys",
+                    desc.id
+                ),
+                ErrorReport::from(&e),
+            );
+        }
+    }
+
+    DoctorCheck::pass(
+        "blobs",
+        format!("Fetched and decrypted {} of {} blobs", sample.len(), blobs.len()),
+    )
+}
+
+fn check_clock(db: Arc<db::Index>, clock: &Clock) -> DoctorCheck {
+    let now = clock.now();
+    let newest = snapshot::SnapshotIndex::new(db)
+        .list_all()
+        .into_iter()
+        .map(|s| s.created)
+        .max();
+    match newest {
+        Some(created) if created > now + chrono::Duration::minutes(CLOCK_SLACK_MINUTES) => {
+            DoctorCheck::warn(
+                "clock",
+                format!(
+                    "A snapshot was taken at {}, after the current time {}; fix the system \
+                     clock before pruning, or retention will keep and delete the wrong snapshots",
+                    created,
+                    now
+                ),
+            )
+        }
+        _ => DoctorCheck::pass("clock", format!("The clock reads {}", now)),
+    }
+}
@@ -16,17 +16,21 @@
 use backend::StoreBackend;
 use blob;
 use capnp;
-use errors::HatError;
+use errors::{HatError, WalkError};
 use hash;
-use hat::insert_path_handler::InsertPathHandler;
+use hat::insert_path_handler::{InsertPathHandler, SnapshotOptions, SnapshotStats};
+use hat::source_snapshot::FrozenSource;
 use hat::walker;
+use hat::xattrs;
 use key;
 use root_capnp;
 use std::fs;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
-use util::{FileIterator, FnBox, PathHandler};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use util::{CancellationToken, FileIterator, FnBox, PathHandler};
 use filetime;
 
 fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
@@ -173,7 +177,7 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
         return Ok(());
     }
 
-    let reader = capnp::serialize_packed::read_message(
+    let reader = ::util::read_packed_message(
         &mut &chunk[..],
         capnp::message::ReaderOptions::new(),
     ).unwrap();
@@ -230,10 +234,28 @@ fn parse_dir_data(chunk: &[u8], mut out: &mut Vec<walker::FileEntry>) -> Result<
     Ok(())
 }
 
+/// What a commit did with each directory in the snapshot, by its path in the snapshot.
+#[derive(Clone, Debug, Default)]
+pub struct CommitStats {
+    /// Directories whose listing was built again, because something in them changed.
+    pub rebuilt_dirs: Vec<PathBuf>,
+    /// Directories whose listing from an earlier commit was reused as it was.
+    pub reused_dirs: Vec<PathBuf>,
+}
+
 pub struct Family<B> {
     pub name: String,
     pub key_store: key::Store<B>,
     pub key_store_process: Vec<key::StoreProcess<FileIterator, B>>,
+    /// Stores the files above `SnapshotOptions::max_file_size`, in blobs of its own.
+    pub large_file_process: key::StoreProcess<FileIterator, B>,
+    pub cancel: CancellationToken,
+    /// Statistics of the last `commit`.
+    pub commit_stats: CommitStats,
+    /// Whether a metadata-only snapshot was taken since the last commit.
+    pub metadata_only: Arc<AtomicBool>,
+    /// Whether a snapshot that records the holes of sparse files was taken since the last commit.
+    pub sparse_files: Arc<AtomicBool>,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
@@ -241,20 +263,110 @@ impl<B: StoreBackend> Clone for Family<B> {
             name: self.name.clone(),
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            large_file_process: self.large_file_process.clone(),
+            cancel: self.cancel.clone(),
+            commit_stats: self.commit_stats.clone(),
+            metadata_only: self.metadata_only.clone(),
+            sparse_files: self.sparse_files.clone(),
         }
     }
 }
 
 impl<B: StoreBackend> Family<B> {
-    pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
+    pub fn snapshot_dir(&self, dir: PathBuf) -> Result<SnapshotStats, HatError> {
+        self.snapshot_dir_with_options(dir, SnapshotOptions::default())
+    }
 
-        let mut parent_path = PathBuf::from("/");
+    /// Snapshot several directories to commit as one snapshot. Each keeps its place below `/`,
+    /// next to the others, and their files share chunks like any files of the family. Fails
+    /// before walking any of them if one of them is inside another.
+    pub fn snapshot_dirs_with_options(
+        &self,
+        dirs: Vec<PathBuf>,
+        options: SnapshotOptions,
+    ) -> Result<SnapshotStats, HatError> {
+        let mut roots = vec![];
+        for dir in dirs {
+            roots.push(fs::canonicalize(&dir).map_err(|e| WalkError::new(&dir, e))?);
+        }
+        for (i, root) in roots.iter().enumerate() {
+            for other in roots[i + 1..].iter() {
+                if root.starts_with(other) || other.starts_with(root) {
+                    return Err(From::from(format!(
+                        "Directories to snapshot overlap: {} and {}",
+                        root.display(),
+                        other.display()
+                    )));
+                }
+            }
+        }
 
+        let mut stats = SnapshotStats::default();
+        for root in roots {
+            let root_stats = self.snapshot_dir_with_options(root, options.clone())?;
+            stats.files += root_stats.files;
+            stats.read_files.extend(root_stats.read_files);
+            stats.large_files += root_stats.large_files;
+        }
+        stats.read_files.sort();
+        Ok(stats)
+    }
+
+    pub fn snapshot_dir_with_options(
+        &self,
+        dir: PathBuf,
+        mut options: SnapshotOptions,
+    ) -> Result<SnapshotStats, HatError> {
         let dir = fs::canonicalize(dir).unwrap();
         info!("Committing: {}", dir.display());
         assert!(dir.is_absolute());
 
+        // The reference was committed under its canonical path, like any other directory.
+        if let Some(link_dest) = options.link_dest.take() {
+            options.link_dest = Some(fs::canonicalize(&link_dest).map_err(|e| {
+                WalkError::new(&link_dest, e)
+            })?);
+        }
+
+        // Mounts to follow, by where they are below `dir`.
+        let mut follow_mounts = vec![];
+        for mount in &options.follow_mounts {
+            let canonical = fs::canonicalize(mount).map_err(|e| WalkError::new(mount, e))?;
+            match canonical.strip_prefix(&dir) {
+                Ok(relative) => follow_mounts.push(relative.to_owned()),
+                Err(_) => {
+                    return Err(From::from(format!(
+                        "Mount to follow {} is not below {}",
+                        mount.display(),
+                        dir.display()
+                    )))
+                }
+            }
+        }
+
+        // Entries are named after `dir`, but their contents are read from the frozen view.
+        let frozen = match options.source_snapshot.clone() {
+            Some(snapshot) => FrozenSource::freeze(snapshot, &dir)?,
+            None => None,
+        };
+        let read_dir = frozen.as_ref().map_or(dir.clone(), |f| f.path().to_owned());
+        options.follow_mounts = follow_mounts.into_iter().map(|m| read_dir.join(m)).collect();
+
+        if options.metadata_only {
+            self.metadata_only.store(true, Ordering::SeqCst);
+        }
+        if options.sparse_files {
+            self.sparse_files.store(true, Ordering::SeqCst);
+        }
+        let mut handler = InsertPathHandler::new(
+            self.key_store_process.clone(),
+            self.large_file_process.clone(),
+            self.cancel.clone(),
+            options,
+        );
+
+        let mut parent_path = PathBuf::from("/");
+
         let mut bailout = false;
         let mut parent = None;
         let mut inside_non_dir = false;
@@ -280,7 +392,11 @@ impl<B: StoreBackend> Family<B> {
         }
 
         if !bailout && dir.is_dir() {
-            handler.recurse(PathBuf::from(&dir), parent);
+            handler.set_root(&read_dir).map_err(|e| WalkError::new(&read_dir, e))?;
+            handler.recurse(read_dir, parent);
+
+            // Leave the reserved nodes uncommitted if we were interrupted while walking.
+            self.cancel.check()?;
 
             match self.key_store_process[0].send_reply(
                 key::Msg::CommitReservedNodes(
@@ -296,6 +412,11 @@ impl<B: StoreBackend> Family<B> {
                 _ => panic!("Unexpected reply from keystore"),
             }
         }
+
+        if let Some(frozen) = frozen {
+            frozen.destroy()?;
+        }
+        Ok(handler.stats())
     }
 
     pub fn snapshot_direct(
@@ -322,7 +443,7 @@ impl<B: StoreBackend> Family<B> {
     }
 
     pub fn flush(&self) -> Result<(), HatError> {
-        for ks in &self.key_store_process {
+        for ks in self.key_store_process.iter().chain(Some(&self.large_file_process)) {
             if let key::Reply::FlushOk = ks.send_reply(key::Msg::Flush)? {
                 continue;
             }
@@ -331,18 +452,23 @@ impl<B: StoreBackend> Family<B> {
         Ok(())
     }
 
-    pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
+    pub fn write_file_chunks<W, HTB>(
         &self,
-        fd: &mut fs::File,
-        tree: hash::tree::LeafIterator<HTB>,
-    ) {
-        for chunk in tree {
+        fd: &mut W,
+        mut tree: hash::tree::LeafIterator<HTB>,
+    ) -> Result<(), HatError>
+    where
+        W: Write,
+        HTB: hash::tree::HashTreeBackend<Err = key::MsgError>,
+    {
+        while let Some(chunk) = tree.try_next()? {
             try_a_few_times_then_panic(
                 || fd.write_all(&chunk[..]).is_ok(),
                 "Could not write chunk.",
             );
         }
         try_a_few_times_then_panic(|| fd.flush().is_ok(), "Could not flush file.");
+        Ok(())
     }
 
     // FIXME(jos): Merge with hat's checkout_in_dir which checks out snapshots.
@@ -367,7 +493,7 @@ impl<B: StoreBackend> Family<B> {
                     // This is a file, write it
                     let mut fd = fs::File::create(&path).unwrap();
                     if let Some(tree) = read_fn_opt.expect("File has data").init()? {
-                        self.write_file_chunks(&mut fd, tree);
+                        self.write_file_chunks(&mut fd, tree)?;
                     }
                 }
                 key::Data::Symlink(link_path) => {
@@ -387,6 +513,10 @@ impl<B: StoreBackend> Family<B> {
                 filetime::set_file_times(&path, atime, mtime).unwrap();
             }
 
+            if let Err(e) = xattrs::restore(&path, &entry.info.extended_attributes) {
+                xattrs::warn_unsupported(&path, &e);
+            }
+
             // Prepare for next filename:
             path.pop();
         }
@@ -411,12 +541,12 @@ impl<B: StoreBackend> Family<B> {
         dir_hash: hash::tree::HashRef,
         backend: HTB,
     ) -> Result<Vec<(key::Entry, walker::Content)>, HatError> {
-        let it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect(
+        let mut it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect(
             "unable to open dir",
         );
 
         let mut out = Vec::new();
-        for chunk in it {
+        while let Some(chunk) = it.try_next()? {
             if !chunk.is_empty() {
                 parse_dir_data(&chunk[..], &mut out)?;
             }
@@ -429,8 +559,9 @@ impl<B: StoreBackend> Family<B> {
     where
         F: Fn(&hash::Hash),
     {
+        self.commit_stats = CommitStats::default();
         let mut top_tree = self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-        self.commit_to_tree(&mut top_tree, None, top_hash_fn)?;
+        self.commit_to_tree(&mut top_tree, None, Path::new(""), top_hash_fn)?;
 
         let info = key::Info::new(self.name.clone().into_bytes(), None);
         Ok(top_tree.hash(Some(&info))?)
@@ -440,11 +571,13 @@ impl<B: StoreBackend> Family<B> {
         &mut self,
         tree: &mut hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
         dir_id: Option<u64>,
+        path: &Path,
         top_hash_fn: &F,
     ) -> Result<(), HatError>
     where
         F: Fn(&hash::Hash),
     {
+        self.cancel.check()?;
 
         let files_at_a_time = 1024;
         let mut it = self.list_from_key_store(dir_id)?.into_iter();
@@ -491,16 +624,36 @@ impl<B: StoreBackend> Family<B> {
                             top_hash_fn(&hash::Hash { bytes: href.hash.bytes });
                         }
                         key::Data::DirPlaceholder => {
-                            // This is a directory, recurse!
-                            let mut inner_tree =
-                                self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-                            self.commit_to_tree(
-                                &mut inner_tree,
-                                entry.node_id,
-                                top_hash_fn,
-                            )?;
-                            // Store a reference for the sub-tree in our tree:
-                            let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
+                            let dir_id = entry.node_id.expect("Directory has no id");
+                            let dir_path =
+                                path.join(&*String::from_utf8_lossy(&entry.info.name[..]));
+                            let dir_hash_ref = match self.key_store.unchanged_dir(dir_id)? {
+                                Some((dir_hash_ref, tops)) => {
+                                    // Nothing in here changed since the last commit: reuse its
+                                    // listing, but register the data below it all the same.
+                                    for top in tops.iter() {
+                                        top_hash_fn(top);
+                                    }
+                                    self.commit_stats.reused_dirs.push(dir_path);
+                                    dir_hash_ref
+                                }
+                                None => {
+                                    // This is a directory, recurse!
+                                    let mut inner_tree =
+                                        self.key_store.hash_tree_writer(blob::LeafType::TreeList);
+                                    self.commit_to_tree(
+                                        &mut inner_tree,
+                                        entry.node_id,
+                                        &dir_path,
+                                        top_hash_fn,
+                                    )?;
+                                    // Store a reference for the sub-tree in our tree:
+                                    let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
+                                    self.key_store.set_dir_ref(dir_id, &dir_hash_ref)?;
+                                    self.commit_stats.rebuilt_dirs.push(dir_path);
+                                    dir_hash_ref
+                                }
+                            };
 
                             let mut hash_ref_msg = capnp::message::Builder::new_default();
                             let mut hash_ref_root =
@@ -534,7 +687,7 @@ impl<B: StoreBackend> Family<B> {
                 break;
             } else {
                 let mut buf = vec![];
-                capnp::serialize_packed::write_message(&mut buf, &file_block_msg)?;
+                ::util::write_packed_message(&mut buf, &file_block_msg)?;
                 tree.append(&buf[..])?;
             }
         }
@@ -0,0 +1,125 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypted copies of the index, kept apart from the blob store.
+//!
+//! An export is the format name, a version byte, a signature with the manifest key and, sealed
+//! with the data key, the SHA-256 of the index dump followed by the dump itself. The signature
+//! covers everything else in the export. Only the store's keys can open it, and the signature
+//! and hash are checked before anything is imported.
+
+use crypto;
+use errors::HatError;
+
+const MAGIC: &'static [u8] = b"hat-index-export";
+/// Version 1 exports were not signed, and are refused.
+const FORMAT_VERSION: u8 = 2;
+
+fn sha256(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = crypto::Sha256::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// Seal an index dump for writing to a file.
+pub fn seal(keys: &crypto::keys::Keeper, dump: &[u8]) -> Vec<u8> {
+    let mut plain = sha256(dump);
+    plain.extend_from_slice(dump);
+
+    let mut signed = MAGIC.to_vec();
+    signed.push(FORMAT_VERSION);
+    signed.extend_from_slice(&keys.data_lock(&plain[..])[..]);
+
+    let header = MAGIC.len() + 1;
+    let mut out = signed[..header].to_vec();
+    out.extend_from_slice(&keys.manifest_sign(&signed[..])[..]);
+    out.extend_from_slice(&signed[header..]);
+    out
+}
+
+/// The index dump sealed in `bytes`, if they are an intact export made with these keys.
+pub fn open(keys: &crypto::keys::Keeper, bytes: &[u8]) -> Result<Vec<u8>, HatError> {
+    if bytes.len() <= MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(From::from("Not an index export"));
+    }
+    if bytes[MAGIC.len()] != FORMAT_VERSION {
+        return Err(From::from(
+            format!("Unknown index export version: {}", bytes[MAGIC.len()]),
+        ));
+    }
+    let header = MAGIC.len() + 1;
+    let signature_end = header + crypto::keys::MANIFEST_SIGNATURE_BYTES;
+    if bytes.len() <= signature_end {
+        return Err(From::from("Index export is truncated"));
+    }
+    let mut signed = bytes[..header].to_vec();
+    signed.extend_from_slice(&bytes[signature_end..]);
+    if !crypto::keys::manifest_verify(
+        &keys.manifest_public_key()[..],
+        &signed[..],
+        &bytes[header..signature_end],
+    )
+    {
+        return Err(From::from(
+            "Index export is not signed with the manifest key of this store",
+        ));
+    }
+
+    let mut plain = match keys.try_data_unlock(&bytes[signature_end..]) {
+        Some(plain) if plain.len() >= crypto::SHA256_BYTES => plain,
+        _ => {
+            return Err(From::from(
+                "Index export is damaged or was made with another key",
+            ))
+        }
+    };
+    let dump = plain.split_off(crypto::SHA256_BYTES);
+    if sha256(&dump[..]) != plain {
+        return Err(From::from("Index export does not match its content hash"));
+    }
+    Ok(dump)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let keys = crypto::keys::Keeper::new_for_testing();
+        let sealed = seal(&keys, b"index");
+        assert_eq!(open(&keys, &sealed[..]).unwrap(), b"index".to_vec());
+
+        let mut damaged = sealed.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        assert!(open(&keys, &damaged[..]).is_err());
+
+        let mut newer = sealed.clone();
+        newer[MAGIC.len()] += 1;
+        assert!(open(&keys, &newer[..]).is_err());
+        assert!(open(&keys, b"hat-index").is_err());
+
+        // Another store's signature is refused, even over the same contents.
+        let other = crypto::keys::Keeper::new_for_testing_with_key(vec![9; 32]);
+        let mut signed = sealed[..MAGIC.len() + 1].to_vec();
+        let signature_end = MAGIC.len() + 1 + crypto::keys::MANIFEST_SIGNATURE_BYTES;
+        signed.extend_from_slice(&sealed[signature_end..]);
+        let mut resigned = sealed.clone();
+        resigned[MAGIC.len() + 1..signature_end]
+            .copy_from_slice(&other.manifest_sign(&signed[..])[..]);
+        assert!(open(&keys, &resigned[..]).is_err());
+    }
+}
@@ -14,15 +14,132 @@
 
 
 use backend::StoreBackend;
+use chrono::Duration;
+use hat::source_snapshot::SourceSnapshot;
+use hat::xattrs;
 use key;
 use std::error::Error;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 use std::str;
-use std::sync::{Mutex, atomic};
+use std::sync::{Arc, Mutex, atomic};
 use time;
-use util::{FileIterator, PathHandler, SyncPool};
+use util::{CancellationToken, DataRegions, FileIterator, FnBox, PathHandler, ReadAt, ReadPool,
+           SyncPool, SystemClock, Throughput, find_holes, may_have_holes};
+
+/// Seconds of progress that the throughput shown is averaged over.
+const PROGRESS_WINDOW_SECS: i64 = 30;
+
+/// What a cache directory tag file starts with, per the Cache Directory Tagging Specification.
+const CACHEDIR_TAG_SIGNATURE: &'static [u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Settings for walking a directory tree during a snapshot.
+#[derive(Clone)]
+pub struct SnapshotOptions {
+    /// Stay on the device of the snapshot root, like `rsync -x`. Directories on other devices,
+    /// i.e. mount points, are stored without their contents.
+    pub one_file_system: bool,
+    /// With `one_file_system`, still descend into mount points at or below these directories,
+    /// such as a bind-mounted data volume, and into everything mounted below those in turn.
+    pub follow_mounts: Vec<PathBuf>,
+    /// Store directories tagged as caches, by a `CACHEDIR.TAG` file that starts with the
+    /// signature of the Cache Directory Tagging Specification, without their contents.
+    pub exclude_caches: bool,
+    /// Files, and segments of large files, to read at the same time. With more than one, every
+    /// file being stored keeps up to this many segments of `READ_SEGMENT_SIZE` in memory.
+    pub read_concurrency: usize,
+    /// Files larger than this, such as disk images and databases, are stored apart from the
+    /// others: one at a time, by a key store that packs their chunks into blobs of their own,
+    /// with `large_file_concurrency` of their segments read at the same time. Smaller files are
+    /// stored as before. Either way the snapshot is the same.
+    pub max_file_size: Option<u64>,
+    /// Segments of a file above `max_file_size` to read at the same time, and so to keep in
+    /// memory at most.
+    pub large_file_concurrency: usize,
+    /// Freeze the snapshot root with this and read the frozen view of it, so that the snapshot
+    /// is consistent even if the directory changes meanwhile. Falls back to reading it as it is,
+    /// with a warning, if it cannot be frozen.
+    pub source_snapshot: Option<Arc<SourceSnapshot>>,
+    /// Also store the extended attributes of every entry, POSIX ACLs included. Where they are
+    /// not supported, this warns once and stores none.
+    pub extended_attributes: bool,
+    /// Store the holes of sparse files, like thin disk images, as ranges instead of reading
+    /// them as zeros, so that checkout can leave them as holes again.
+    pub sparse_files: bool,
+    /// Record the tree and the metadata of every file without reading any contents. Files that
+    /// look the same as in the snapshot before keep their contents from it; the others are
+    /// marked as not captured, and checkout can not restore them.
+    pub metadata_only: bool,
+    /// Match the files against those at the same place below this directory, which must have
+    /// been committed to the same family before, like `rsync --link-dest`. Files are still read
+    /// and hashed, but chunks with the same hash as those of the reference file are reused
+    /// without being looked up again.
+    pub link_dest: Option<PathBuf>,
+    /// Bytes of file contents the snapshot is expected to read, if known, e.g. from the size of
+    /// the last snapshot of the same directory. Progress then includes the time left.
+    pub expected_bytes: Option<u64>,
+    device_id: Arc<Fn(&Path, &fs::Metadata) -> u64 + Send + Sync>,
+    open_file: Arc<Fn(&Path) -> io::Result<Arc<ReadAt>> + Send + Sync>,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> SnapshotOptions {
+        SnapshotOptions {
+            one_file_system: false,
+            follow_mounts: vec![],
+            exclude_caches: false,
+            read_concurrency: 1,
+            max_file_size: None,
+            large_file_concurrency: 4,
+            source_snapshot: None,
+            extended_attributes: false,
+            sparse_files: false,
+            metadata_only: false,
+            link_dest: None,
+            expected_bytes: None,
+            device_id: Arc::new(|_, meta| meta.dev()),
+            open_file: Arc::new(|path| {
+                fs::File::open(path).map(|f| Arc::new(f) as Arc<ReadAt>)
+            }),
+        }
+    }
+}
+
+impl SnapshotOptions {
+    /// Report devices with `device_id` instead of asking the filesystem.
+    #[cfg(test)]
+    pub fn with_device_id<F>(mut self, device_id: F) -> SnapshotOptions
+    where
+        F: Fn(&Path, &fs::Metadata) -> u64 + Send + Sync + 'static,
+    {
+        self.device_id = Arc::new(device_id);
+        self
+    }
+
+    /// Read file contents through `open_file` instead of from the filesystem.
+    #[cfg(test)]
+    pub fn with_open_file<F>(mut self, open_file: F) -> SnapshotOptions
+    where
+        F: Fn(&Path) -> io::Result<Arc<ReadAt>> + Send + Sync + 'static,
+    {
+        self.open_file = Arc::new(open_file);
+        self
+    }
+}
+
+/// What a snapshot did with the files it walked.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotStats {
+    /// Files walked, whether or not they were read.
+    pub files: usize,
+    /// Files whose contents were read, by the path they were read from. Files that look the same
+    /// as when they were last stored are not read again.
+    pub read_files: Vec<PathBuf>,
+    /// Files above `max_file_size` that were stored apart from the others.
+    pub large_files: usize,
+}
 
 struct FileEntry {
     key_entry: key::Entry,
@@ -72,15 +189,168 @@ impl FileEntry {
 pub struct InsertPathHandler<B: StoreBackend> {
     count: atomic::AtomicIsize,
     last_print: Mutex<time::Timespec>,
+    throughput: Mutex<Throughput>,
+    files: atomic::AtomicUsize,
+    large_files: atomic::AtomicUsize,
+    read_files: Arc<Mutex<Vec<PathBuf>>>,
     key_store: SyncPool<key::StoreProcess<FileIterator, B>>,
+    large_file_store: Mutex<key::StoreProcess<FileIterator, B>>,
+    cancel: CancellationToken,
+    options: SnapshotOptions,
+    root: Option<PathBuf>,
+    root_device: Option<u64>,
+    read_pool: Option<Arc<ReadPool>>,
+    large_file_pool: Option<Arc<ReadPool>>,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
-    pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>) -> InsertPathHandler<B> {
+    pub fn new(
+        key_stores: Vec<key::StoreProcess<FileIterator, B>>,
+        large_file_store: key::StoreProcess<FileIterator, B>,
+        cancel: CancellationToken,
+        options: SnapshotOptions,
+    ) -> InsertPathHandler<B> {
+        let read_pool = if options.read_concurrency > 1 {
+            Some(Arc::new(ReadPool::new(options.read_concurrency)))
+        } else {
+            None
+        };
+        let large_file_pool = options.max_file_size.map(|_| {
+            Arc::new(ReadPool::new(options.large_file_concurrency))
+        });
         InsertPathHandler {
             count: atomic::AtomicIsize::new(0),
             last_print: Mutex::new(time::now().to_timespec()),
+            throughput: Mutex::new(Throughput::new(
+                Arc::new(SystemClock),
+                Duration::seconds(PROGRESS_WINDOW_SECS),
+            )),
+            files: atomic::AtomicUsize::new(0),
+            large_files: atomic::AtomicUsize::new(0),
+            read_files: Arc::new(Mutex::new(vec![])),
             key_store: SyncPool::new(key_stores),
+            large_file_store: Mutex::new(large_file_store),
+            cancel: cancel,
+            options: options,
+            root: None,
+            root_device: None,
+            read_pool: read_pool,
+            large_file_pool: large_file_pool,
+        }
+    }
+
+    /// Start walking from `root`. With `one_file_system`, this is the device to stay on; the
+    /// parents of the root are not held to it.
+    pub fn set_root(&mut self, root: &Path) -> io::Result<()> {
+        self.root = Some(root.to_owned());
+        if self.options.one_file_system {
+            let meta = fs::metadata(root)?;
+            self.root_device = Some((self.options.device_id)(root, &meta));
+        }
+        Ok(())
+    }
+
+    fn on_other_device(&self, file_entry: &FileEntry) -> bool {
+        match self.root_device {
+            Some(dev) => {
+                (self.options.device_id)(&file_entry.full_path, &file_entry.metadata) != dev &&
+                    !self.options.follow_mounts.iter().any(
+                        |m| file_entry.full_path.starts_with(m),
+                    )
+            }
+            None => false,
+        }
+    }
+
+    fn is_cache(&self, file_entry: &FileEntry) -> bool {
+        if !self.options.exclude_caches {
+            return false;
+        }
+        let mut signature = vec![];
+        match fs::File::open(file_entry.full_path.join("CACHEDIR.TAG")) {
+            Ok(file) => {
+                file.take(CACHEDIR_TAG_SIGNATURE.len() as u64)
+                    .read_to_end(&mut signature)
+                    .is_ok() && &signature[..] == CACHEDIR_TAG_SIGNATURE
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Where `path` is found in the reference directory, as names from the root.
+    fn reference_names(&self, path: &Path) -> Option<Vec<Vec<u8>>> {
+        let (link_dest, root) = match (&self.options.link_dest, &self.root) {
+            (&Some(ref link_dest), &Some(ref root)) => (link_dest, root),
+            _ => return None,
+        };
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return None,
+        };
+        link_dest
+            .join(relative)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_str().map(|s| s.as_bytes().to_vec())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The files walked so far, and which of them were read.
+    pub fn stats(&self) -> SnapshotStats {
+        let mut read_files = self.read_files.lock().unwrap().clone();
+        read_files.sort();
+        SnapshotStats {
+            files: self.files.load(atomic::Ordering::SeqCst),
+            read_files: read_files,
+            large_files: self.large_files.load(atomic::Ordering::SeqCst),
+        }
+    }
+
+    /// How fast file contents are read, and how long the rest will take if that is known.
+    fn progress(&self) -> String {
+        let mut throughput = self.throughput.lock().unwrap();
+        let rate = match throughput.rate() {
+            Some(rate) => rate,
+            None => return String::new(),
+        };
+        let mib_per_sec = rate / (1024.0 * 1024.0);
+        match self.options.expected_bytes.and_then(|total| throughput.eta(total)) {
+            Some(eta) => {
+                format!(" ({:.1} MiB/s, about {}s left)", mib_per_sec, eta.num_seconds())
+            }
+            None => format!(" ({:.1} MiB/s)", mib_per_sec),
+        }
+    }
+
+    fn read_extended_attributes(&self, file_entry: &mut FileEntry) {
+        match xattrs::read(&file_entry.full_path) {
+            Ok(attrs) => file_entry.key_entry.info.extended_attributes = attrs,
+            Err(ref e) if xattrs::is_unsupported(e) => {
+                xattrs::warn_unsupported(&file_entry.full_path, e)
+            }
+            Err(e) => {
+                warn!(
+                    "Could not read extended attributes of '{}': {}",
+                    file_entry.full_path.display(),
+                    e
+                )
+            }
+        }
+    }
+
+    fn read_holes(&self, file_entry: &mut FileEntry) {
+        let len = file_entry.metadata.len();
+        match fs::File::open(&file_entry.full_path).and_then(|f| find_holes(&f, len)) {
+            Ok(holes) => file_entry.key_entry.info.holes = holes,
+            Err(e) => {
+                warn!(
+                    "Could not find the holes of '{}', reading all of it: {}",
+                    file_entry.full_path.display(),
+                    e
+                )
+            }
         }
     }
 }
@@ -94,6 +364,11 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
     }
 
     fn handle_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
+        if self.cancel.is_cancelled() {
+            // Do not descend any further; the caller checks the token when we return.
+            return None;
+        }
+
         let count = self.count.fetch_add(1, atomic::Ordering::SeqCst) + 1;
 
         if count % 16 == 0 {
@@ -101,7 +376,7 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             let mut guarded_last_print = self.last_print.lock().unwrap();
             let now = time::now().to_timespec();
             if guarded_last_print.sec <= now.sec - 1 {
-                println!("#{}: {}", count, path.display());
+                println!("#{}: {}{}", count, path.display(), self.progress());
                 *guarded_last_print = now;
             }
         }
@@ -110,34 +385,103 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             Err(e) => {
                 println!("Skipping '{}': {}", path.display(), e);
             }
-            Ok(file_entry) => {
+            Ok(mut file_entry) => {
+                if self.options.extended_attributes {
+                    self.read_extended_attributes(&mut file_entry);
+                }
                 let is_file = file_entry.is_file();
+                let metadata_only = is_file && self.options.metadata_only;
+                if metadata_only {
+                    // Only used if the file changed; unchanged files keep what they had.
+                    file_entry.key_entry.info.content_not_captured = true;
+                } else if is_file && self.options.sparse_files &&
+                           may_have_holes(&file_entry.metadata)
+                {
+                    self.read_holes(&mut file_entry);
+                }
+                let file_size = if is_file { file_entry.metadata.len() } else { 0 };
+                let is_large = is_file &&
+                    self.options.max_file_size.map_or(false, |max| file_size > max);
                 let is_directory = file_entry.is_directory();
+                let on_other_device = self.on_other_device(&file_entry);
+                let is_cache = is_directory && !on_other_device && self.is_cache(&file_entry);
+                if on_other_device && !is_directory {
+                    println!("Skipping '{}': on another filesystem", path.display());
+                    return None;
+                }
                 let local_root = path.clone();
                 let full_path = file_entry.full_path.clone();
+                let open_file = self.options.open_file.clone();
+                let read_pool = if is_large {
+                    self.large_file_pool.clone()
+                } else {
+                    self.read_pool.clone()
+                };
+                let read_files = self.read_files.clone();
+                let holes = file_entry.key_entry.info.holes.clone();
 
-                let ks = self.key_store.lock().unwrap();
-                match ks.send_reply(key::Msg::Insert(
-                    file_entry.key_entry,
-                    if is_file {
-                        Some(Box::new(move |()| {
-                        match FileIterator::new(&full_path) {
-                            Err(e) => {
-                                println!("Skipping '{}': {}", local_root.display(), e.to_string());
-                                None
-                            }
-                            Ok(it) => Some(it),
+                let open: Option<Box<FnBox<(), Option<FileIterator>>>> = if metadata_only {
+                    self.files.fetch_add(1, atomic::Ordering::SeqCst);
+                    Some(Box::new(|()| Some(FileIterator::from_bytes(vec![]))))
+                } else if is_file {
+                    self.files.fetch_add(1, atomic::Ordering::SeqCst);
+                    // The key store only opens files that it does not have already.
+                    Some(Box::new(move |()| match open_file(&full_path) {
+                        Err(e) => {
+                            println!("Skipping '{}': {}", local_root.display(), e.to_string());
+                            None
+                        }
+                        Ok(source) => {
+                            read_files.lock().unwrap().push(local_root);
+                            let source = if holes.is_empty() {
+                                source
+                            } else {
+                                Arc::new(DataRegions::new(source, &holes, file_size)) as Arc<ReadAt>
+                            };
+                            Some(match read_pool {
+                                Some(pool) => FileIterator::read_ahead(source, pool),
+                                None => FileIterator::from_read_at(source),
+                            })
                         }
                     }))
-                    } else {
-                        None
-                    },
-                )) {
+                } else {
+                    None
+                };
+                let msg = match self.reference_names(path) {
+                    Some(reference) if is_file => {
+                        key::Msg::InsertWithReference(file_entry.key_entry, open, reference)
+                    }
+                    _ => key::Msg::Insert(file_entry.key_entry, open),
+                };
+
+                let reply = if is_large {
+                    self.large_files.fetch_add(1, atomic::Ordering::SeqCst);
+                    // Large files take turns, so that only one of them is read at a time.
+                    self.large_file_store.lock().unwrap().send_reply(msg)
+                } else {
+                    self.key_store.lock().unwrap().send_reply(msg)
+                };
+                match reply {
                     Ok(key::Reply::Id(id)) => {
-                        if is_directory {
+                        self.throughput.lock().unwrap().add(file_size);
+                        if on_other_device {
+                            // Keep the mount point itself, but not what is mounted there.
+                            println!(
+                                "Not descending into '{}': on another filesystem",
+                                path.display()
+                            );
+                        } else if is_cache {
+                            println!(
+                                "Not descending into '{}': tagged as a cache",
+                                path.display()
+                            );
+                        } else if is_directory {
                             return Some(Some(id));
                         }
                     }
+                    Err(ref e) if self.cancel.is_cancelled() => {
+                        debug!("Cancelled while inserting '{}': {}", path.display(), e);
+                    }
                     Err(e) => panic!("Error from key store: {:?}", e),
                     _ => panic!("Unexpected reply from key store."),
                 }
@@ -0,0 +1,72 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Plain SHA-256 digests of the files in a snapshot, for checking restores with standard tools.
+//!
+//! Digests are only kept for files stored while `Hat::set_file_digests` was on; they are read
+//! from the directory listings, so file contents are never fetched.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker;
+use hex::ToHex;
+use key;
+use std::path::PathBuf;
+
+
+#[derive(Clone, Debug)]
+pub struct FileDigest {
+    /// Path of the file relative to the snapshot root.
+    pub path: PathBuf,
+    pub sha256: Option<Vec<u8>>,
+}
+
+/// Add the digests of all files below `dir_ref` to `out`.
+pub fn collect<B: StoreBackend>(
+    family: &Family<B>,
+    backend: &key::HashStoreBackend<B>,
+    dir_ref: hash::tree::HashRef,
+    path: PathBuf,
+    out: &mut Vec<FileDigest>,
+) -> Result<(), HatError> {
+    for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+        let mut sub_path = path.clone();
+        sub_path.push(&*String::from_utf8_lossy(&entry.info.name[..]));
+        match content {
+            walker::Content::Data(_) => {
+                out.push(FileDigest {
+                    path: sub_path,
+                    sha256: entry.info.sha256,
+                })
+            }
+            walker::Content::Dir(href) => collect(family, backend, href, sub_path, out)?,
+            walker::Content::Link(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// One line per file with a digest, as printed by `sha256sum`, so that the output can be
+/// checked with `sha256sum -c` from the directory the snapshot was restored into.
+pub fn to_sha256sum(digests: &[FileDigest]) -> String {
+    let mut out = String::new();
+    for d in digests {
+        if let Some(ref sha256) = d.sha256 {
+            out.push_str(&format!("{}  {}\n", sha256.to_hex(), d.path.display()));
+        }
+    }
+    out
+}
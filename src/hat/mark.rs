@@ -0,0 +1,68 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding the hashes in use on several threads at once.
+//!
+//! The trees below the roots are walked a level at a time. Each level is split between the
+//! threads, which add the children they find to one shared set; a child already in the set has
+//! been reached another way and is not walked again. The set is only returned once a level
+//! turns up nothing new, so it is complete before the caller deletes anything based on it.
+
+use gc;
+use hash::HashIndex;
+use scoped_pool;
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+
+/// The hashes reachable from `roots`, the roots included, found by `concurrency` threads.
+pub fn used_ids(
+    hash_index: &HashIndex,
+    roots: Vec<gc::Id>,
+    concurrency: usize,
+) -> HashSet<gc::Id> {
+    let concurrency = cmp::max(1, concurrency);
+    let mut used = HashSet::new();
+    let mut level: Vec<gc::Id> = roots.into_iter().filter(|&id| used.insert(id)).collect();
+
+    let used = Mutex::new(used);
+    let pool = scoped_pool::Pool::new(concurrency);
+    while !level.is_empty() {
+        let next = Mutex::new(vec![]);
+        let per_thread = (level.len() + concurrency - 1) / concurrency;
+        pool.scoped(|scope| {
+            for part in level.chunks(per_thread) {
+                let used = &used;
+                let next = &next;
+                scope.execute(move || {
+                    let mut found = vec![];
+                    for &id in part {
+                        let childs = match hash_index.get_hash(id) {
+                            Some(entry) => entry.childs.unwrap_or_else(Vec::new),
+                            None => panic!("HashNotKnown in hash index."),
+                        };
+                        let mut used = used.lock().unwrap();
+                        found.extend(childs.into_iter().filter(|&c| used.insert(c)));
+                    }
+                    next.lock().unwrap().extend(found);
+                });
+            }
+        });
+        level = next.into_inner().unwrap();
+    }
+    pool.shutdown();
+
+    used.into_inner().unwrap()
+}
@@ -18,27 +18,78 @@ use backend::StoreBackend;
 use blob;
 use capnp;
 use db;
-use errors::HatError;
+use errors::{CancelledError, StoreVersionError, WrongKeyError};
+pub use errors::{BackendError, BackupError, ErrorKind, ErrorReport, HatError, WalkError};
 use filetime;
 use gc::{self, Gc, GcRc};
 use hash;
 use key;
+use libc;
 use root_capnp;
+use scoped_pool;
 use snapshot;
 use std::cmp;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::str;
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tags;
-use util::Process;
-use void::Void;
+use util::{Clock, Process, SparseWriter, SystemClock};
+pub use util::CancellationToken;
 use hex::ToHex;
 
+mod archive;
+mod audit;
+mod cat;
+mod compare;
+mod config;
+mod doctor;
 mod family;
+mod index_export;
 mod insert_path_handler;
+mod manifest;
+mod mark;
+pub mod paths;
+mod prefetch;
+mod proof;
+mod relocation;
+mod restore_plan;
+mod scrub;
+mod sharing;
+mod source_snapshot;
+mod store_info;
+mod trust_anchor;
+mod usage;
+mod verify;
 mod walker;
-use self::family::Family;
+mod xattrs;
+use self::family::{CommitStats, Family};
+pub use blob::{ChunkInfo, DEFAULT_MAX_UPLOADS, StoragePolicy};
+pub use db::AuditEntry;
+pub use crypto::SEAL_ALGORITHM;
+pub use crypto::keys::Keyring;
+pub use key::{Chunker, RollingParams};
+pub use self::archive::TarWriter;
+pub use self::cat::FileReader;
+pub use self::compare::Divergence;
+pub use self::config::StoreConfig;
+pub use self::doctor::{CheckStatus, DoctorCheck, doctor};
+pub use self::insert_path_handler::{SnapshotOptions, SnapshotStats};
+pub use self::manifest::{FileDigest, to_sha256sum};
+pub use self::paths::{PathFilter, PathPolicy, PosixPolicy, RestoreConflict, RestoreOptions,
+                      WindowsPolicy};
+pub use self::prefetch::Prefetch;
+pub use self::proof::Proof;
+pub use self::restore_plan::{PlannedEntry, RestorePlan};
+pub use self::scrub::{ScrubOptions, ScrubReport};
+pub use self::sharing::SnapshotSharing;
+pub use self::source_snapshot::{BtrfsSnapshot, SourceSnapshot};
+pub use self::trust_anchor::TrustAnchor;
+pub use self::usage::DirUsage;
+pub use self::verify::{FailedChunk, VerifyReport};
+pub use util::MemoryBudget;
 
 #[cfg(test)]
 mod tests;
@@ -47,12 +98,87 @@ mod benchmarks;
 
 
 
+/// Newest store format version this binary can read.
+pub const READER_VERSION: i64 = 8;
+
+/// Oldest reader able to read what this binary writes.
+/// Only bumped when the written format changes in a backward-incompatible way.
+/// Version 2 is the first version with key-committed chunks.
+pub const MIN_READER_VERSION: i64 = 2;
+
+/// Oldest reader able to read family indexes that keep their filenames encrypted. Older readers
+/// would take the lookup tags for the names.
+pub const ENCRYPTED_NAMES_READER_VERSION: i64 = 3;
+
+/// Oldest reader able to find blobs kept in shard directories by the backend.
+pub const SHARDED_READER_VERSION: i64 = 4;
+
+/// Oldest reader able to undo chunk pipeline stages other than sealing.
+pub const PIPELINE_READER_VERSION: i64 = 5;
+
+/// Oldest reader able to restore the holes recorded for sparse files. Older readers would
+/// restore their data back to back.
+pub const SPARSE_READER_VERSION: i64 = 6;
+
+/// Oldest reader able to read stores whose content hashes are salted. Older readers would look
+/// for every chunk under its unsalted hash.
+pub const SALTED_READER_VERSION: i64 = 7;
+
+/// Oldest reader able to read blobs that record their padding length in the footer. Older
+/// readers fail on the footer entry that holds it.
+pub const PADDED_READER_VERSION: i64 = 8;
+
+/// Number of chunks read back from their new blobs before a blob rewrite is trusted.
+const REWRITE_VERIFY_SAMPLES: usize = 16;
+
+/// Blobs worth of live data moved at a time by `consolidate_blobs`. Each batch ends in at most
+/// one partly filled blob.
+const CONSOLIDATE_BATCH_BLOBS: usize = 16;
+
+/// Chunks per thread read back between checkpoint writes by `verify_parallel`.
+const VERIFY_BATCH_PER_THREAD: usize = 16;
+
+/// Check that this host can run hat, by running a self-test of the crypto library and checking
+/// that it can seal chunks the way new snapshots do.
+pub fn check_environment() -> Result<(), HatError> {
+    crypto::self_test()?;
+    crypto::capabilities().require(crypto::SEAL_ALGORITHM)?;
+    Ok(())
+}
+
+/// Refuse to open stores that need a newer reader than `reader_version`. Opening a store does
+/// not change what it needs; only writing to it does, see `Hat::require_reader_version`.
+fn check_store_version(db: &db::Index, reader_version: i64) -> Result<(), HatError> {
+    // Stores from before versioning was introduced are version 1.
+    let required = db.lock().store_min_reader_version().unwrap_or(1);
+    check_reader_version(required, reader_version)
+}
+
+fn check_reader_version(required: i64, reader_version: i64) -> Result<(), HatError> {
+    if required > reader_version {
+        return Err(From::from(StoreVersionError {
+            required: required,
+            supported: reader_version,
+        }));
+    }
+    Ok(())
+}
+
+/// `keys`, with the hashing salt of the store if it has one.
+fn salted_keys(db: &db::Index, keys: crypto::keys::Keeper) -> crypto::keys::Keeper {
+    match db.lock().store_hash_salt() {
+        Some(salt) => keys.with_hash_salt(&salt[..]),
+        None => keys,
+    }
+}
+
+
 pub struct GcBackend {
     hash_index: Arc<hash::HashIndex>,
 }
 
 impl gc::GcBackend for GcBackend {
-    type Err = Void;
+    type Err = HatError;
 
     fn get_data(&self, hash_id: gc::Id, family_id: gc::Id) -> Result<db::GcData, Self::Err> {
         Ok(self.hash_index.read_gc_data(hash_id, family_id))
@@ -73,6 +199,14 @@ impl gc::GcBackend for GcBackend {
         self.hash_index.update_family_gc_data(family_id, fns);
         Ok(())
     }
+    fn list_ids_with_data(&self, family_id: gc::Id) -> Result<mpsc::Receiver<gc::Id>, Self::Err> {
+        let (sender, receiver) = mpsc::channel();
+        for id in self.hash_index.list_gc_data_ids(family_id) {
+            sender.send(id).unwrap();
+        }
+
+        Ok(receiver)
+    }
 
     fn get_tag(&self, hash_id: gc::Id) -> Result<Option<tags::Tag>, Self::Err> {
         Ok(self.hash_index.get_tag(hash_id))
@@ -91,7 +225,7 @@ impl gc::GcBackend for GcBackend {
     fn reverse_refs(&self, hash_id: gc::Id) -> Result<Vec<gc::Id>, Self::Err> {
         let entry = match self.hash_index.get_hash(hash_id) {
             Some(entry) => entry,
-            None => panic!("HashNotKnown in hash index."),
+            None => return Err(From::from(format!("Hash {} is not in the hash index", hash_id))),
         };
         if entry.childs.is_none() {
             return Ok(Vec::new());
@@ -117,6 +251,81 @@ impl gc::GcBackend for GcBackend {
 }
 
 
+/// How a garbage collection runs.
+pub struct GcOptions {
+    /// Only delete hashes that have been unused for at least this long, as seen by earlier runs.
+    pub grace: chrono::Duration,
+    /// Before deleting anything, find the hashes in use a second way and abort if any of them
+    /// are about to be deleted.
+    pub verify_reachability: bool,
+    /// Like `verify_reachability`, and also mark unused hashes twice and abort unless both runs
+    /// agree.
+    pub paranoid: bool,
+    /// Threads that find the hashes in use, and that delete unused blobs, at the same time.
+    pub concurrency: usize,
+    mark_hook: Option<Box<Fn(&mut HashSet<gc::Id>)>>,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            grace: chrono::Duration::zero(),
+            verify_reachability: false,
+            paranoid: false,
+            concurrency: 1,
+            mark_hook: None,
+        }
+    }
+}
+
+impl GcOptions {
+    /// Tamper with the unused hashes found by each mark run, as a buggy GC would.
+    #[cfg(test)]
+    pub fn with_mark_hook<F>(mut self, hook: F) -> GcOptions
+    where
+        F: Fn(&mut HashSet<gc::Id>) + 'static,
+    {
+        self.mark_hook = Some(Box::new(hook));
+        self
+    }
+}
+
+/// Outcome of `consolidate_blobs`.
+#[derive(Clone, Debug, Default)]
+pub struct ConsolidateReport {
+    /// Small blobs whose chunks were moved, and which were then deleted.
+    pub blobs_merged: u64,
+    /// Live chunks moved out of them.
+    pub chunks_moved: u64,
+    /// Whether every small blob was merged; false if the run was cancelled part way.
+    pub complete: bool,
+}
+
+/// Progress and outcome of `rekey`.
+#[derive(Clone, Debug, Default)]
+pub struct RekeyReport {
+    /// Blobs holding chunks sealed with another algorithm when the run started.
+    pub blobs_total: u64,
+    /// Those of them rewritten so far, and then deleted.
+    pub blobs_rewritten: u64,
+    /// Live chunks sealed again so far.
+    pub chunks_rekeyed: u64,
+    /// Whether every such blob was rewritten; false if the run was cancelled part way.
+    pub complete: bool,
+}
+
+/// Outcome of `commit_incremental`.
+#[derive(Clone, Debug)]
+pub struct IncrementalCommit {
+    /// The snapshot that was committed.
+    pub snapshot_id: u64,
+    /// The latest snapshot of the family before it, if there was one.
+    pub parent: Option<u64>,
+    /// Which files were walked and which of them had to be read.
+    pub stats: SnapshotStats,
+}
+
+
 pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     keys: Arc<crypto::keys::Keeper>,
     repository_root: Option<PathBuf>,
@@ -129,7 +338,21 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     blob_max_size: usize,
+    chunker: key::Chunker,
+    fanout: usize,
+    chunk_pipeline: Arc<blob::ChunkPipeline>,
+    record_padding: bool,
+    encrypt_filenames: bool,
+    storage_policy: blob::StoragePolicy,
+    file_digests: bool,
+    verify_dedup: bool,
+    keyring: crypto::keys::Keyring,
+    /// Where chunks moved to by rewriting their blobs are, as found by `recover`.
+    relocations: Arc<HashMap<hash::Hash, blob::ChunkRef>>,
+    trust_anchor: TrustAnchor,
     gc: G,
+    cancel: CancellationToken,
+    clock: Arc<Clock>,
 }
 
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
@@ -147,6 +370,19 @@ fn synthetic_roots_family() -> String {
     From::from("__hat__roots__")
 }
 
+/// Change the owner and group of `path`, and not of what it links to.
+fn set_owner(path: &Path, uid: u64, gid: u64) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::lchown(c_path.as_ptr(), uid as libc::uid_t, gid as libc::gid_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 struct SnapshotLister<'a, B: StoreBackend> {
     backend: &'a key::HashStoreBackend<B>,
     family: &'a Family<B>,
@@ -212,11 +448,12 @@ impl<B: StoreBackend> HatRc<B> {
         backend: Arc<B>,
         max_blob_size: usize,
     ) -> Result<HatRc<B>, HatError> {
-        let keys = Arc::new(crypto::keys::Keeper::new("hat-master-key"));
         let migrations_path = migrations_dir.canonicalize().unwrap();
 
         let hash_index_path = hash_index_name(repository_root.clone());
         let db_p = Arc::new(db::Index::new(&migrations_path, &hash_index_path)?);
+        check_store_version(&db_p, READER_VERSION)?;
+        let keys = Arc::new(salted_keys(&db_p, crypto::keys::Keeper::new("hat-master-key")));
 
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
@@ -232,6 +469,8 @@ impl<B: StoreBackend> HatRc<B> {
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
 
+        let trust_anchor = TrustAnchor::open(repository_root.join("trust_anchor"))?;
+
         let mut hat = Hat {
             keys: keys,
             repository_root: Some(repository_root),
@@ -244,7 +483,20 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            chunker: key::Chunker::default(),
+            fanout: hash::tree::DEFAULT_FANOUT,
+            chunk_pipeline: Arc::new(blob::ChunkPipeline::default()),
+            record_padding: false,
+            encrypt_filenames: false,
+            storage_policy: blob::StoragePolicy::default(),
+            file_digests: false,
+            verify_dedup: false,
+            keyring: crypto::keys::Keyring::new(),
+            relocations: Arc::new(HashMap::new()),
+            trust_anchor: trust_anchor,
             gc: gc,
+            cancel: CancellationToken::new(),
+            clock: Arc::new(SystemClock),
         };
 
         // Resume any unfinished commands.
@@ -255,9 +507,18 @@ impl<B: StoreBackend> HatRc<B> {
 
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<HatRc<B>, HatError> {
-        let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+        Hat::new_for_testing_with_index(backend, max_blob_size, db::Index::new_for_testing())
+    }
 
-        let db_p = Arc::new(db::Index::new_for_testing());
+    #[cfg(test)]
+    pub fn new_for_testing_with_index(
+        backend: Arc<B>,
+        max_blob_size: usize,
+        index: db::Index,
+    ) -> Result<HatRc<B>, HatError> {
+        let db_p = Arc::new(index);
+        check_store_version(&db_p, READER_VERSION)?;
+        let keys = Arc::new(salted_keys(&db_p, crypto::keys::Keeper::new_for_testing()));
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let bi_p = Arc::new(blob::BlobIndex::new(keys.clone(), db_p.clone()).unwrap());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone()).unwrap());
@@ -283,8 +544,21 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            chunker: key::Chunker::default(),
+            fanout: hash::tree::DEFAULT_FANOUT,
+            chunk_pipeline: Arc::new(blob::ChunkPipeline::default()),
+            record_padding: false,
+            encrypt_filenames: false,
+            storage_policy: blob::StoragePolicy::default(),
+            file_digests: false,
+            verify_dedup: false,
+            keyring: crypto::keys::Keyring::new(),
+            relocations: Arc::new(HashMap::new()),
+            trust_anchor: TrustAnchor::in_memory(),
             backend: backend,
             gc: gc,
+            cancel: CancellationToken::new(),
+            clock: Arc::new(SystemClock),
         };
 
         // Resume any unfinished commands.
@@ -297,7 +571,188 @@ impl<B: StoreBackend> HatRc<B> {
         &self,
         leaf: blob::LeafType,
     ) -> hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>> {
-        hash::tree::SimpleHashTreeWriter::new(leaf, 8, self.hash_backend())
+        hash::tree::SimpleHashTreeWriter::new(
+            leaf,
+            hash::tree::DEFAULT_FANOUT,
+            self.hash_backend(),
+        )
+    }
+
+    /// Returns a handle to the token shared by all families opened from this hat.
+    /// Cancelling it makes running snapshots, checkouts and garbage collection stop at the next
+    /// safe point with a `Cancelled` error. The token is reset when the stopped operation returns
+    /// (for a snapshot, when its commit is rolled back), so the next one runs.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Choose how families split files into chunks. Families that are already open are flushed
+    /// and reopened on next use. Files chunked with different parameters do not share chunks,
+    /// so this should only change when starting a new store.
+    pub fn set_chunker(&mut self, chunker: key::Chunker) -> Result<(), HatError> {
+        chunker.validate()?;
+        if let Err(e) = chunker.check_overhead(
+            self.blob_store.seal_algorithm(),
+            key::MAX_SEAL_OVERHEAD,
+        )
+        {
+            warn!("{}", e);
+        }
+        if chunker != self.chunker {
+            self.data_flush()?;
+            self.families.clear();
+            self.chunker = chunker;
+        }
+        Ok(())
+    }
+
+    /// Choose how many children the branch nodes of the hash trees of new snapshots have. It is
+    /// recorded per snapshot; reading a tree follows its branch nodes, whatever their width.
+    /// Families that are already open are flushed and reopened on next use.
+    pub fn set_fanout(&mut self, fanout: usize) -> Result<(), HatError> {
+        if fanout < 2 {
+            return Err(From::from(format!(
+                "Hash tree fan-out must be at least 2, not {}",
+                fanout
+            )));
+        }
+        if fanout != self.fanout {
+            self.data_flush()?;
+            self.families.clear();
+            self.fanout = fanout;
+        }
+        Ok(())
+    }
+
+    /// Put chunks stored from now on through `pipeline`, like "gzip+seal", instead of only
+    /// sealing them. Chunks already stored are read back with the stages recorded for them.
+    /// Families that are already open are flushed and reopened on next use.
+    pub fn set_chunk_pipeline(&mut self, pipeline: &str) -> Result<(), HatError> {
+        let pipeline = Arc::new(blob::ChunkPipeline::parse(pipeline)?);
+        if pipeline.describe() != self.chunk_pipeline.describe() {
+            self.data_flush()?;
+            self.families.clear();
+            self.blob_store.set_chunk_pipeline(pipeline.clone());
+            self.chunk_pipeline = pipeline;
+        }
+        Ok(())
+    }
+
+    /// Record the length of the padding in the authenticated footer of new blobs, so that a blob
+    /// whose padding was cut short or added to fails to read. The store then needs a reader of
+    /// at least `PADDED_READER_VERSION`. Families that are already open are flushed and
+    /// reopened on next use.
+    pub fn set_record_padding(&mut self, record: bool) -> Result<(), HatError> {
+        if record != self.record_padding {
+            self.data_flush()?;
+            self.families.clear();
+            self.blob_store.set_record_padding(record);
+            self.record_padding = record;
+        }
+        Ok(())
+    }
+
+    /// Store filenames in the local family indexes encrypted, for when the indexes themselves
+    /// cannot be kept encrypted at rest. See `KeyIndex::set_filename_keys` for what stays
+    /// visible. A family opened with this set keeps its names encrypted from then on.
+    /// Families that are already open are flushed and reopened on next use.
+    pub fn set_encrypt_filenames(&mut self, encrypt: bool) -> Result<(), HatError> {
+        if encrypt != self.encrypt_filenames {
+            self.data_flush()?;
+            self.families.clear();
+            self.encrypt_filenames = encrypt;
+        }
+        Ok(())
+    }
+
+    /// Key the content hashes of this store with a secret salt, kept in its metadata and sealed
+    /// in the backend, so that they can not be matched against the hashes of any other store.
+    /// Chunks are then never shared with other stores, even under the same key. Every hash of a
+    /// store must use the same salt, so this can only be done before anything is stored; on a
+    /// store that already has a salt it does nothing. Call it right after opening the store, as
+    /// it starts over with a fresh blob store.
+    pub fn enable_hash_salt(&mut self) -> Result<(), HatError> {
+        let mut index = self.db.lock();
+        if index.store_hash_salt().is_some() {
+            return Ok(());
+        }
+        if !index.hash_list_from(&[], 1).is_empty() {
+            return Err(From::from(
+                "Can not salt the hashes of a store that already holds data",
+            ));
+        }
+        drop(index);
+
+        // The salt goes to the backend first, so that a store recovered from it has it as well.
+        let salt = crypto::keys::new_hash_salt();
+        let mut info = self.local_store_info();
+        info.min_reader_version = cmp::max(info.min_reader_version, SALTED_READER_VERSION);
+        info.hash_salt = Some(salt.clone());
+        info.write(&*self.backend, &self.keys)?;
+
+        let mut index = self.db.lock();
+        index.store_set_hash_salt(&salt[..]);
+        index.store_set_min_reader_version(info.min_reader_version);
+        index.flush();
+        drop(index);
+
+        self.use_hash_salt(&salt[..])
+    }
+
+    /// Hash with `salt` from now on. Only for stores that do not hold any data yet.
+    fn use_hash_salt(&mut self, salt: &[u8]) -> Result<(), HatError> {
+        let keys = Arc::new(self.keys.with_hash_salt(salt));
+        self.blob_index = Arc::new(blob::BlobIndex::new(keys.clone(), self.db.clone())?);
+        self.blob_store = Arc::new(blob::BlobStore::new(
+            keys.clone(),
+            self.blob_index.clone(),
+            self.backend.clone(),
+            self.blob_max_size,
+        ));
+        self.blob_store.set_chunk_pipeline(self.chunk_pipeline.clone());
+        self.blob_store.set_record_padding(self.record_padding);
+        self.keys = keys;
+        self.families.clear();
+        Ok(())
+    }
+
+    /// Choose the storage class that the backend is asked to keep file data in. Families that
+    /// are already open are flushed and reopened on next use.
+    pub fn set_storage_policy(&mut self, policy: blob::StoragePolicy) -> Result<(), HatError> {
+        self.data_flush()?;
+        self.families.clear();
+        self.storage_policy = policy;
+        Ok(())
+    }
+
+    /// Keep a plain SHA-256 of every file in its snapshot metadata, next to the tree hash, so
+    /// that restores can be checked against a `sha256sum` manifest. Families that are already
+    /// open are flushed and reopened on next use.
+    pub fn set_file_digests(&mut self, file_digests: bool) -> Result<(), HatError> {
+        if file_digests != self.file_digests {
+            self.data_flush()?;
+            self.families.clear();
+            self.file_digests = file_digests;
+        }
+        Ok(())
+    }
+
+    /// Compare every new chunk with the stored chunk it would be deduplicated against, and fail
+    /// on a hash collision instead of losing the new contents. So that the stored chunk can
+    /// always be read back, the key stores of families share one blob store while this is on.
+    /// Families that are already open are flushed and reopened on next use.
+    pub fn set_verify_dedup(&mut self, verify_dedup: bool) -> Result<(), HatError> {
+        if verify_dedup != self.verify_dedup {
+            self.data_flush()?;
+            self.families.clear();
+            self.verify_dedup = verify_dedup;
+        }
+        Ok(())
+    }
+
+    /// Every chunker that snapshots in this store were recorded with.
+    pub fn chunkers_in_use(&mut self) -> Vec<String> {
+        self.snapshot_index.chunkers_in_use()
     }
 
     pub fn open_family(&mut self, name: String) -> Result<Family<B>, HatError> {
@@ -316,38 +771,70 @@ impl<B: StoreBackend> HatRc<B> {
             None => ":memory:".to_string(),
         };
 
-        let ki_p = Arc::new(key::KeyIndex::new(&self.migrations_dir, &key_index_path)?);
+        let ki = key::KeyIndex::new(&self.migrations_dir, &key_index_path)?;
+        if ki.set_filename_keys(self.keys.clone(), self.encrypt_filenames)? {
+            self.require_reader_version(ENCRYPTED_NAMES_READER_VERSION)?;
+        }
+        let ki_p = Arc::new(ki);
 
         let mut kss = vec![];
-        for _ in 0..2 {
+        let large_file_process = {
             // To avoid mixing chunks from different files, each key store gets its own dedicated
-            // blob store.
-            let bs = Arc::new(blob::BlobStore::new(
-                self.keys.clone(),
-                self.blob_index.clone(),
-                self.backend.clone(),
-                self.blob_max_size,
-            ));
-            kss.push(Process::new(key::Store::new(
-                ki_p.clone(),
-                self.hash_index.clone(),
-                bs,
-                self.keys.clone(),
-            )));
-        }
+            // blob store, unless chunks are verified before reuse and must be readable from any.
+            let dedicated_store = || {
+                let bs = if self.verify_dedup {
+                    self.blob_store.clone()
+                } else {
+                    let bs = Arc::new(blob::BlobStore::new(
+                        self.keys.clone(),
+                        self.blob_index.clone(),
+                        self.backend.clone(),
+                        self.blob_max_size,
+                    ));
+                    bs.set_chunk_pipeline(self.chunk_pipeline.clone());
+                    bs.set_record_padding(self.record_padding);
+                    bs
+                };
+                key::Store::new(
+                    ki_p.clone(),
+                    self.hash_index.clone(),
+                    bs,
+                    self.keys.clone(),
+                    self.cancel.clone(),
+                    self.chunker.clone(),
+                    self.storage_policy.clone(),
+                ).with_file_digests(self.file_digests)
+                    .with_verify_dedup(self.verify_dedup)
+                    .with_fanout(self.fanout)
+            };
+            for _ in 0..2 {
+                kss.push(Process::new(dedicated_store()));
+            }
+            Process::new(dedicated_store())
+        };
 
         let ks = key::Store::new(
             ki_p.clone(),
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        );
+            self.cancel.clone(),
+            self.chunker.clone(),
+            self.storage_policy.clone(),
+        ).with_file_digests(self.file_digests)
+            .with_verify_dedup(self.verify_dedup)
+            .with_fanout(self.fanout);
         kss.push(Process::new(ks.clone()));
 
         let family = Family {
             name: name.clone(),
             key_store: ks,
             key_store_process: kss,
+            large_file_process: large_file_process,
+            cancel: self.cancel.clone(),
+            commit_stats: CommitStats::default(),
+            metadata_only: Arc::new(AtomicBool::new(false)),
+            sparse_files: Arc::new(AtomicBool::new(false)),
         };
         self.families.push(family.clone());
 
@@ -365,13 +852,16 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn meta_commit(&mut self) -> Result<(), HatError> {
-        let all_snapshots = self.snapshot_index.list_all();
+        self.require_reader_version(self.written_reader_version())?;
+        let all_snapshots = self.snapshot_index.list_committed();
+        let sequence = self.trust_anchor.seen() + 1;
 
         let mut message = capnp::message::Builder::new_default();
         let mut all_root_ids = vec![];
 
         {
-            let root = message.init_root::<root_capnp::snapshot_list::Builder>();
+            let mut root = message.init_root::<root_capnp::snapshot_list::Builder>();
+            root.set_sequence(sequence);
             let mut snapshots = root.init_snapshots(all_snapshots.len() as u32);
 
             for (i, snapshot) in all_snapshots.into_iter().enumerate() {
@@ -391,7 +881,7 @@ impl<B: StoreBackend> HatRc<B> {
         }
 
         let mut listing = Vec::new();
-        capnp::serialize_packed::write_message(&mut listing, &message).unwrap();
+        ::util::write_packed_message(&mut listing, &message).unwrap();
 
         // FIXME(jos): Split into N-entries per append().
         let mut tree = self.hash_tree_writer(blob::LeafType::SnapshotList);
@@ -415,6 +905,7 @@ impl<B: StoreBackend> HatRc<B> {
         self.gc.register_final(&snap_info, top_id)?;
         self.meta_flush();
         self.commit_finalize(snap_info, &top_ref.hash)?;
+        self.trust_anchor.advance(sequence)?;
 
         // Delete old root snapshots, but always keep the past 10.
         // FIXME(jos): Number of meta snapshots to keep to be configurable.
@@ -426,37 +917,92 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
-    fn recover_root(&mut self) -> Result<Option<hash::tree::HashRef>, HatError> {
+    /// The highest sequence number in the snapshot list at `root`.
+    fn root_sequence(&self, root: &hash::tree::HashRef) -> Result<u64, HatError> {
+        let mut sequence = 0;
+        for msg in hash::tree::LeafIterator::new(self.hash_backend(), root.clone())?
+            .into_iter()
+            .flat_map(|it| it)
+        {
+            let message_reader = ::util::read_packed_message(
+                &mut &msg[..],
+                capnp::message::ReaderOptions::new(),
+            )?;
+            let snapshot_list = message_reader.get_root::<root_capnp::snapshot_list::Reader>()?;
+            sequence = cmp::max(sequence, snapshot_list.get_sequence());
+        }
+        Ok(sequence)
+    }
+
+    /// The newest root found in the backend, and its sequence number.
+    fn recover_root(&mut self) -> Result<Option<(hash::tree::HashRef, u64)>, HatError> {
         let blobs = self.blob_store.list_by_tag(tags::Tag::Done);
         info!("{} blobs to investigate", blobs.len());
+        let mut newest: Option<(hash::tree::HashRef, u64)> = None;
         for b in blobs.into_iter() {
             info!("Inspecting blob: {}", b.name.to_hex());
             for r in self.blob_store.retrieve_refs(b)?.unwrap_or(vec![]) {
                 match r.leaf {
                     blob::LeafType::SnapshotList => {
-                        // FIXME(jos): Allow skipping first root in case it is not working.
-                        return Ok(Some(r));
+                        let sequence = match self.root_sequence(&r) {
+                            Ok(sequence) => sequence,
+                            Err(e) => {
+                                warn!("Skipping unreadable root {}: {}", r.fingerprint(), e);
+                                continue;
+                            }
+                        };
+                        if newest.as_ref().map_or(true, |&(_, newest)| sequence > newest) {
+                            newest = Some((r, sequence));
+                        }
                     }
                     // FIXME(jos): Recover file-listings stored after commit
                     blob::LeafType::TreeList => {
-                        warn!("Skipping directory listing: {}", r.hash.bytes.to_hex())
+                        warn!("Skipping directory listing: {}", r.fingerprint())
                     }
                     blob::LeafType::FileChunk => {
-                        warn!("Skipping file contents: {}", r.hash.bytes.to_hex())
+                        warn!("Skipping file contents: {}", r.fingerprint())
                     }
                 }
             }
         }
-        Ok(None)
+        Ok(newest)
     }
 
     pub fn recover(&mut self) -> Result<(), HatError> {
+        // A store that older readers can not read stays that way, and its hashes keep their salt.
+        if let Some(info) = store_info::StoreInfo::read(&*self.backend, &self.keys)? {
+            check_reader_version(info.min_reader_version, READER_VERSION)?;
+            let mut index = self.db.lock();
+            if index.store_min_reader_version().unwrap_or(1) < info.min_reader_version {
+                index.store_set_min_reader_version(info.min_reader_version);
+            }
+            let new_salt = match (index.store_hash_salt(), info.hash_salt) {
+                (None, Some(salt)) => {
+                    index.store_set_hash_salt(&salt[..]);
+                    Some(salt)
+                }
+                (Some(ref ours), Some(ref theirs)) if ours != theirs => {
+                    return Err(From::from(
+                        "The hash salt of the index differs from that of the backend",
+                    ));
+                }
+                _ => None,
+            };
+            index.flush();
+            drop(index);
+            if let Some(salt) = new_salt {
+                self.use_hash_salt(&salt[..])?;
+            }
+        }
         self.blob_store.recover()?;
-        let root_href = self.recover_root()?.expect(
+        self.recover_relocations()?;
+        let (root_href, sequence) = self.recover_root()?.expect(
             "Failed to find a commit-ed root.",
         );
+        // An older root than one seen before means that the store was rolled back.
+        self.trust_anchor.check(sequence)?;
 
-        info!("Recovering using root: {}", root_href.hash.bytes.to_hex());
+        info!("Recovering using root: {}", root_href.fingerprint());
         info!(
             ".. from blob: {}",
             root_href.persistent_ref.blob_name.to_hex()
@@ -468,7 +1014,7 @@ impl<B: StoreBackend> HatRc<B> {
         for msg in hash::tree::LeafIterator::new(self.hash_backend(), root_href.clone())?
             .unwrap()
         {
-            let message_reader = capnp::serialize_packed::read_message(
+            let message_reader = ::util::read_packed_message(
                 &mut &msg[..],
                 capnp::message::ReaderOptions::new(),
             ).unwrap();
@@ -494,6 +1040,7 @@ impl<B: StoreBackend> HatRc<B> {
 
         self.flush_snapshot_index();
         self.resume()?;
+        self.trust_anchor.advance(sequence)?;
 
         // Register the newly found root. This is needed because root cannot contain itself.
         let latest = self.snapshot_index.latest(&synthetic_roots_family());
@@ -517,6 +1064,24 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Read back where rewritten blobs moved their chunks to, keeping for each chunk the copy in
+    /// a blob that still exists.
+    fn recover_relocations(&mut self) -> Result<(), HatError> {
+        let mut relocations = HashMap::new();
+        for (hash, hrefs) in relocation::read_all(&*self.backend, &self.keys)? {
+            for href in hrefs {
+                if let Some(blob) = self.blob_store.find(&href.persistent_ref.blob_name) {
+                    let mut chunk_ref = href.persistent_ref;
+                    chunk_ref.blob_id = Some(blob.id);
+                    relocations.insert(hash, chunk_ref);
+                    break;
+                }
+            }
+        }
+        self.relocations = Arc::new(relocations);
+        Ok(())
+    }
+
     fn recover_snapshot(
         &mut self,
         info: db::SnapshotInfo,
@@ -525,35 +1090,42 @@ impl<B: StoreBackend> HatRc<B> {
         fn recover_entry<B: StoreBackend>(
             hashes: &hash::HashIndex,
             blobs: &blob::BlobStore<B>,
+            relocations: &HashMap<hash::Hash, blob::ChunkRef>,
             node: family::recover::Node,
-        ) {
-            let mut pref = node.href.persistent_ref.clone();
-            pref.blob_id = Some(blobs.find(&pref.blob_name).map(|b| b.id).expect(&format!(
-                "unknown blob: {:?}",
-                pref.blob_name
-            )));
-
-            fn entry(href: hash::tree::HashRef, childs: Option<Vec<u64>>) -> hash::Entry {
-                hash::Entry {
-                    hash: href.hash,
-                    persistent_ref: Some(href.persistent_ref),
-                    node: href.node,
-                    leaf: href.leaf,
-                    childs: childs,
+        ) -> Result<(), HatError> {
+            // Chunks of rewritten blobs are where the relocations say.
+            let chunk_ref = |href: &hash::tree::HashRef| -> Result<blob::ChunkRef, HatError> {
+                let mut pref = href.persistent_ref.clone();
+                match blobs.find(&pref.blob_name) {
+                    Some(blob) => pref.blob_id = Some(blob.id),
+                    None => {
+                        pref = relocations.get(&href.hash).cloned().ok_or_else(|| {
+                            format!("unknown blob: {}", pref.blob_name.to_hex())
+                        })?
+                    }
                 }
-            }
+                Ok(pref)
+            };
+            let pref = chunk_ref(&node.href)?;
 
             // Convert child hashes to child IDs.
             let child_ids = match node.childs {
                 Some(ref hs) => {
-                    Some(
-                        hs.iter()
-                            .map(|h| match hashes.reserve(&entry(h.clone(), None)) {
-                                hash::ReserveResult::HashKnown(id) |
-                                hash::ReserveResult::ReserveOk(id) => id,
-                            })
-                            .collect(),
-                    )
+                    let mut ids = vec![];
+                    for h in hs.iter() {
+                        let child = hash::Entry {
+                            hash: h.hash.clone(),
+                            persistent_ref: Some(chunk_ref(h)?),
+                            node: h.node,
+                            leaf: h.leaf,
+                            childs: None,
+                        };
+                        ids.push(match hashes.reserve(&child) {
+                            hash::ReserveResult::HashKnown(id) |
+                            hash::ReserveResult::ReserveOk(id) => id,
+                        });
+                    }
+                    Some(ids)
                 }
                 None => None,
             };
@@ -571,7 +1143,7 @@ impl<B: StoreBackend> HatRc<B> {
                 hash::ReserveResult::HashKnown(id) => {
                     if hashes.reserved_id(&entry.hash).is_none() {
                         // This is a repeat hash that was already fully committed.
-                        return;
+                        return Ok(());
                     }
                     id
                 }
@@ -579,6 +1151,7 @@ impl<B: StoreBackend> HatRc<B> {
             };
             // Commit hash.
             hashes.commit(id, Some(entry));
+            Ok(())
         }
 
         let mut dir_v = family::recover::DirVisitor::new();
@@ -588,11 +1161,11 @@ impl<B: StoreBackend> HatRc<B> {
         let mut tops = vec![];
         while {
             for node in file_v.nodes() {
-                recover_entry(&self.hash_index, &self.blob_store, node);
+                recover_entry(&self.hash_index, &self.blob_store, &self.relocations, node)?;
             }
             tops.append(&mut file_v.tops());
             for node in dir_v.nodes() {
-                recover_entry(&self.hash_index, &self.blob_store, node);
+                recover_entry(&self.hash_index, &self.blob_store, &self.relocations, node)?;
             }
             walk.resume(&mut file_v, &mut dir_v)?
         }
@@ -606,7 +1179,7 @@ impl<B: StoreBackend> HatRc<B> {
                 self.hash_index.set_tag(id, tags::Tag::Reserved);
             }
         }
-        self.flush_blob_store();
+        self.flush_blob_store()?;
 
         let final_id = self.hash_index.get_id(&final_hash.hash).expect(
             "final hash has no id",
@@ -643,11 +1216,14 @@ impl<B: StoreBackend> HatRc<B> {
                             self.commit_finalize(snapshot.info, hash)?
                         }
                         (None, db::SnapshotWorkStatus::CommitInProgress) => {
-                            println!("Resuming commit of: {}", snapshot.family_name);
-                            self.commit_by_name(
+                            // We stopped before the GC knew about the snapshot, so nothing refers
+                            // to the data written for it; the next GC run sweeps it.
+                            warn!(
+                                "Rolling back incomplete commit of: {} #{}",
                                 snapshot.family_name,
-                                Some(snapshot.info),
-                            )?
+                                snapshot.info.snapshot_id
+                            );
+                            self.rollback_commit(snapshot.info);
                         }
                         (None, db::SnapshotWorkStatus::RecoverInProgress) => {
                             println!("Resuming recovery of: {}", snapshot.family_name);
@@ -738,11 +1314,77 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Commit the snapshot in progress for `family`. It becomes visible to listing and restore
+    /// only once all its data is stored and registered; see `commit_finalize`.
     pub fn commit(
         &mut self,
         family: &mut Family<B>,
         resume_info: Option<db::SnapshotInfo>,
     ) -> Result<(), HatError> {
+        // A cancelled snapshot is rolled back here, which ends its cancellation.
+        let _operation = self.cancel.operation();
+        let mut version = self.written_reader_version();
+        if family.sparse_files.swap(false, Ordering::SeqCst) {
+            version = cmp::max(version, SPARSE_READER_VERSION);
+        }
+        self.require_reader_version(version)?;
+        let (snap_info, hash) = self.commit_prepare(family, resume_info)?;
+        self.commit_finalize(snap_info, &hash)?;
+
+        Ok(())
+    }
+
+    /// Snapshot `dir` into `family` and commit it, after the latest snapshot of the family.
+    /// Files that look the same as in that snapshot, by name and modification time, are not read
+    /// again, so that this is cheap enough to run every hour on a tree where little changes.
+    pub fn commit_incremental(
+        &mut self,
+        family: &mut Family<B>,
+        dir: PathBuf,
+        options: SnapshotOptions,
+    ) -> Result<IncrementalCommit, HatError> {
+        let _operation = self.cancel.operation();
+        let stats = family.snapshot_dir_with_options(dir, options)?;
+        family.flush()?;
+        self.commit(family, None)?;
+        self.data_flush()?;
+
+        let info = match self.snapshot_index.latest(&family.name) {
+            Some((info, _, _)) => info,
+            None => return Err(From::from("Committed snapshot is not listed")),
+        };
+        Ok(IncrementalCommit {
+            snapshot_id: info.snapshot_id,
+            parent: self.snapshot_index.parent(&info),
+            stats: stats,
+        })
+    }
+
+    /// The snapshot of `family_name` that `snapshot_id` was taken after, if any.
+    pub fn snapshot_parent(&mut self, family_name: &str, snapshot_id: u64) -> Option<u64> {
+        match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((info, _, _)) => self.snapshot_index.parent(&info),
+            None => None,
+        }
+    }
+
+    /// Whether the snapshot of `family_name` only captured the contents of unchanged files, so
+    /// that changed files in it can not be restored.
+    pub fn snapshot_is_metadata_only(&mut self, family_name: &str, snapshot_id: u64) -> bool {
+        match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((info, _, _)) => self.snapshot_index.metadata_only(&info),
+            None => false,
+        }
+    }
+
+    /// Everything of a commit but making the snapshot visible: store its data and listings,
+    /// record its hash and register it with the GC. A crash in here leaves a snapshot that
+    /// `resume` either rolls back or finishes.
+    fn commit_prepare(
+        &mut self,
+        family: &mut Family<B>,
+        resume_info: Option<db::SnapshotInfo>,
+    ) -> Result<(db::SnapshotInfo, hash::Hash), HatError> {
         //  Tag 1:
         //  Reserve the snapshot and commit the reservation.
         //  Register all but the last hashes.
@@ -750,14 +1392,38 @@ impl<B: StoreBackend> HatRc<B> {
         let snap_info = match resume_info {
             Some(info) => info,  // Resume already started commit.
             None => {
-                // Create new commit.
-                self.snapshot_index.reserve(family.name.clone())
+                // Create new commit, following the latest one of the family.
+                let parent = self.snapshot_index.latest(&family.name).map(|(info, _, _)| {
+                    info.snapshot_id
+                });
+                let info = self.snapshot_index.reserve(family.name.clone());
+                if let Some(parent) = parent {
+                    self.snapshot_index.set_parent(&info, parent);
+                }
+                info
             }
         };
+        self.snapshot_index.set_key_id(&snap_info, &self.keys.key_id());
+
+        let chunker = family.key_store.chunker().describe();
+        for other in self.snapshot_index.chunkers_in_use() {
+            if other != chunker {
+                warn!(
+                    "Other snapshots were chunked with {}, which does not share chunks with {}",
+                    other,
+                    chunker
+                );
+            }
+        }
+        self.snapshot_index.set_chunker(&snap_info, &chunker);
+        self.snapshot_index.set_fanout(&snap_info, family.key_store.fanout());
+        if family.metadata_only.swap(false, Ordering::SeqCst) {
+            self.snapshot_index.set_metadata_only(&snap_info);
+        }
         self.meta_flush();
 
         // Commit metadata while registering needed data-hashes (files and dirs).
-        let top_ref = {
+        let top_ref_res = {
             let local_hash_index = self.hash_index.clone();
             family.commit(&|hash| {
                 let id = local_hash_index.get_id(hash).expect(&format!(
@@ -765,8 +1431,25 @@ impl<B: StoreBackend> HatRc<B> {
                     hash.bytes
                 ));
                 local_hash_index.set_tag(id, tags::Tag::Reserved);
-            })?
+            })
+        };
+        let top_ref = match top_ref_res {
+            Ok(top_ref) => top_ref,
+            Err(e) => {
+                if e.is_cancelled() {
+                    self.rollback_commit(snap_info);
+                }
+                return Err(e);
+            }
         };
+        if self.cancel.is_cancelled() {
+            // Last chance to back out; past this point the GC knows about the snapshot.
+            self.rollback_commit(snap_info);
+            return Err(From::from(CancelledError));
+        }
+
+        // Every blob the snapshot needs is stored before anything can refer to it.
+        self.data_flush()?;
 
         // Tag 2:
         // We update the snapshot entry with the tree hash, which we then register.
@@ -788,9 +1471,15 @@ impl<B: StoreBackend> HatRc<B> {
         self.gc.register_final(&snap_info, hash_id)?;
         self.meta_flush();
 
-        self.commit_finalize(snap_info, &top_ref.hash)?;
+        Ok((snap_info, top_ref.hash))
+    }
 
-        Ok(())
+    fn rollback_commit(&mut self, snap_info: db::SnapshotInfo) {
+        // The snapshot was never registered with the GC, so dropping the reservation is enough.
+        // Any data written so far is unreferenced and will be swept by the next GC run.
+        self.hash_index.set_all_tags(tags::Tag::Done);
+        self.snapshot_index.delete(snap_info);
+        self.meta_flush();
     }
 
     fn commit_finalize(
@@ -798,16 +1487,22 @@ impl<B: StoreBackend> HatRc<B> {
         snap_info: db::SnapshotInfo,
         hash: &hash::Hash,
     ) -> Result<(), HatError> {
-        // Commit locally. Let the GC perform any needed cleanup.
+        // Commit locally. This single update is what makes the snapshot visible: before it,
+        // listing and restore skip the snapshot, and after it, all of it is stored.
         self.snapshot_index.ready_commit(&snap_info);
         self.meta_flush();
 
+        // Let the GC perform any needed cleanup.
         let hash_id = self.hash_index.get_id(hash).expect("Hash does not exist");
         self.gc.register_cleanup(&snap_info, hash_id)?;
         self.meta_flush();
 
         // Tag 0: All is done.
         self.snapshot_index.commit(&snap_info);
+        self.audit(
+            audit::SNAPSHOT_COMMIT,
+            vec![snap_info.family_id as i64, snap_info.snapshot_id as i64],
+        );
         self.meta_flush();
 
         Ok(())
@@ -821,7 +1516,7 @@ impl<B: StoreBackend> HatRc<B> {
         for family in &self.families {
             family.flush()?
         }
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.meta_flush();
         Ok(())
     }
@@ -830,8 +1525,61 @@ impl<B: StoreBackend> HatRc<B> {
         self.snapshot_index.flush();
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        self.blob_store.flush()?;
+        Ok(())
+    }
+
+    /// The settings of the store, as the index has them.
+    fn local_store_info(&self) -> store_info::StoreInfo {
+        let mut index = self.db.lock();
+        store_info::StoreInfo {
+            // Stores from before versioning was introduced are version 1.
+            min_reader_version: index.store_min_reader_version().unwrap_or(1),
+            hash_salt: index.store_hash_salt(),
+        }
+    }
+
+    /// Oldest reader able to read what this store is writing.
+    fn written_reader_version(&self) -> i64 {
+        let mut version = MIN_READER_VERSION;
+        if self.backend.is_sharded() {
+            version = cmp::max(version, SHARDED_READER_VERSION);
+        }
+        if self.chunk_pipeline.describe() != blob::ChunkPipeline::default().describe() {
+            version = cmp::max(version, PIPELINE_READER_VERSION);
+        }
+        if self.record_padding {
+            version = cmp::max(version, PADDED_READER_VERSION);
+        }
+        version
+    }
+
+    /// Mark the store as needing a reader of at least format `version`, before something that
+    /// older readers would misread becomes visible. The mark goes to the backend first, so that
+    /// a store recovered from it is marked as well.
+    fn require_reader_version(&self, version: i64) -> Result<(), HatError> {
+        if self.db.lock().store_min_reader_version().unwrap_or(1) >= version {
+            return Ok(());
+        }
+        let mut info = self.local_store_info();
+        info.min_reader_version = version;
+        info.write(&*self.backend, &self.keys)?;
+        let mut index = self.db.lock();
+        index.store_set_min_reader_version(version);
+        index.flush();
+        Ok(())
+    }
+
+    /// Limit the number of blobs that are uploaded to the backend at the same time.
+    pub fn set_max_uploads(&self, max_uploads: usize) {
+        self.blob_store.set_max_uploads(max_uploads);
+    }
+
+    /// Bound the memory of the cache of chunks stored during this run to `bytes`. Chunks that
+    /// do not fit are still deduplicated, through the hash index.
+    pub fn set_chunk_cache_size(&self, bytes: usize) {
+        self.blob_store.set_chunk_cache_size(bytes);
     }
 
     pub fn checkout_in_dir(
@@ -839,72 +1587,685 @@ impl<B: StoreBackend> HatRc<B> {
         family_name: String,
         output_dir: PathBuf,
     ) -> Result<(), HatError> {
+        for conflict in self.checkout_in_dir_with_policy(family_name, output_dir, PosixPolicy)? {
+            println!("Not restored: {}", conflict);
+        }
+        Ok(())
+    }
+
+    /// Check out the latest snapshot of a family, mapping names with `policy`. Entries that
+    /// cannot be restored under the policy, or that would overwrite one another, are skipped and
+    /// returned.
+    pub fn checkout_in_dir_with_policy<P: PathPolicy + 'static>(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+        policy: P,
+    ) -> Result<Vec<RestoreConflict>, HatError> {
+        let options = RestoreOptions {
+            policy: Box::new(policy),
+            ..RestoreOptions::default()
+        };
+        self.checkout_in_dir_with_options(family_name, output_dir, &options)
+    }
+
+    /// Check out the part of the latest snapshot of a family selected by `options.filter`.
+    /// Subtrees that the filter rules out are not fetched at all.
+    pub fn checkout_in_dir_with_options(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+        options: &RestoreOptions,
+    ) -> Result<Vec<RestoreConflict>, HatError> {
+        let _operation = self.cancel.operation();
         // Extract latest snapshot info:
-        let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
+        let (info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
             Some((i, h, Some(r))) => (i, h, r),
             _ => {
-                panic!(
-                    "Tried to checkout family '{}' before first completed commit",
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
                     family_name
-                )
+                )))
             }
         };
+        let keys = self.snapshot_keys(&info)?;
+        if self.snapshot_index.metadata_only(&info) {
+            println!(
+                "Warning: snapshot {} is metadata-only; files that changed before it are not \
+                 restored",
+                info.snapshot_id
+            );
+        }
+        if let Some(ref budget) = options.memory_budget {
+            // A chunk is at most a blob, and the whole blob is read to get at it.
+            let needed = 2 * self.blob_max_size;
+            if budget.limit() < needed {
+                return Err(From::from(format!(
+                    "Memory budget of {} bytes is too small: restoring needs {} bytes",
+                    budget.limit(),
+                    needed
+                )));
+            }
+        }
 
-        let family = self.open_family(family_name.clone()).expect(&format!(
-            "Could not open family '{}'",
-            family_name
-        ));
+        let family = self.open_family(family_name.clone())?;
 
+        let backend = self.hash_backend_for(keys);
         let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, dir_ref)
+        let mut conflicts = vec![];
+        self.checkout_dir_ref(
+            &family,
+            &backend,
+            &mut output_dir,
+            &mut vec![],
+            dir_ref,
+            options,
+            &mut conflicts,
+        )?;
+        Ok(conflicts)
     }
 
-    fn checkout_dir_ref(
-        &self,
-        family: &Family<B>,
-        output: &mut PathBuf,
-        dir_hash: hash::tree::HashRef,
-    ) -> Result<(), HatError> {
-        fs::create_dir_all(&output).unwrap();
-        for (entry, hash_ref) in family.fetch_dir_data(dir_hash, self.hash_backend())? {
-            assert!(entry.info.name.len() > 0);
-
-            output.push(str::from_utf8(&entry.info.name[..]).unwrap());
-            println!("{}", output.display());
-
-            match hash_ref {
-                walker::Content::Data(hash_ref) => {
-                    let mut fd = fs::File::create(&output).unwrap();
-                    let tree_opt = hash::tree::LeafIterator::new(self.hash_backend(), hash_ref)?;
-                    if let Some(tree) = tree_opt {
-                        family.write_file_chunks(&mut fd, tree);
-                    }
-                }
-                walker::Content::Dir(hash_ref) => {
-                    self.checkout_dir_ref(family, output, hash_ref)?;
-                }
-                walker::Content::Link(link_path) => {
-                    use std::os::unix::fs::symlink;
-                    symlink(link_path, &output)?
-                }
-            }
-
-            if let Some(perms) = entry.info.permissions {
-                fs::set_permissions(&output, perms)?;
-            }
-
-            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
-                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
-                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
-                filetime::set_file_times(&output, atime, mtime)?;
+    /// What `checkout_in_dir_with_options` would restore into `output_dir`, without restoring
+    /// anything: the entries it would create, whether something is already in their place, and
+    /// the entries it would skip. Only directory listings are fetched.
+    pub fn restore_plan(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+        options: &RestoreOptions,
+    ) -> Result<RestorePlan, HatError> {
+        let (info, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
             }
+        };
+        let keys = self.snapshot_keys(&info)?;
 
-            output.pop();
-        }
-        Ok(())
+        let family = self.open_family(family_name)?;
+        let mut planner = restore_plan::Planner::new(&family, self.hash_backend_for(keys), options);
+        planner.plan_dir(&mut output_dir.clone(), &mut vec![], dir_ref)?;
+        Ok(planner.into_plan())
     }
 
-    pub fn deregister_by_name(
+    /// Open the file at `path` in the latest snapshot of a family for reading. Its chunks are
+    /// fetched and checked as they are read.
+    pub fn open_file(
+        &mut self,
+        family_name: String,
+        path: &str,
+    ) -> Result<FileReader<B>, HatError> {
+        let (info, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+        let keys = self.snapshot_keys(&info)?;
+
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend_for(keys);
+        let file_ref = cat::find_file(&family, &backend, dir_ref, path)?;
+        FileReader::new(backend, file_ref)
+    }
+
+    /// Start fetching the blobs that hold the files at `paths` in the latest snapshot of a family
+    /// into the blob cache, so that reading or restoring them later does not wait for the
+    /// backend. The paths are looked up before this returns; the file contents are fetched in
+    /// the background, and the returned handle can be waited on.
+    ///
+    /// Blobs are only kept in room the cache has left (see `set_blob_cache_size`), so blobs
+    /// that were actually read are never forgotten to make room for prefetched ones.
+    pub fn prefetch(&mut self, family_name: String, paths: &[&str]) -> Result<Prefetch, HatError> {
+        let (info, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+        let keys = self.snapshot_keys(&info)?;
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend_for(keys);
+        let mut file_refs = vec![];
+        for path in paths {
+            file_refs.push(cat::find_file(&family, &backend, dir_ref.clone(), path)?);
+        }
+        Ok(Prefetch::start(backend, self.blob_store.clone(), file_refs))
+    }
+
+    /// Keep up to `bytes` of blobs that were read in memory, so that reading more chunks from
+    /// them, or reading chunks that were prefetched, does not fetch them again.
+    pub fn set_blob_cache_size(&self, bytes: usize) {
+        self.blob_store.set_blob_cache_size(bytes);
+    }
+
+    /// Write the contents of the file at `path` in the latest snapshot of a family to `out`, as
+    /// they are read. Returns the number of bytes written. On error, what was read before the
+    /// failing chunk has been written already.
+    pub fn cat<W: io::Write>(
+        &mut self,
+        family_name: String,
+        path: &str,
+        out: &mut W,
+    ) -> Result<u64, HatError> {
+        let mut reader = self.open_file(family_name, path)?;
+        let mut written = 0;
+        while let Some(chunk) = reader.next_chunk()? {
+            out.write_all(&chunk[..])?;
+            written += chunk.len() as u64;
+        }
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Write the latest snapshot of a family to `out` as a tar archive, instead of restoring it
+    /// to disk. File contents are streamed one chunk at a time. Returns `out` once the archive is
+    /// complete.
+    pub fn checkout_to_tar<W: io::Write>(
+        &mut self,
+        family_name: String,
+        out: W,
+    ) -> Result<W, HatError> {
+        let _operation = self.cancel.operation();
+        let (info, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+        let keys = self.snapshot_keys(&info)?;
+
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend_for(keys);
+        let mut tar = TarWriter::new(out);
+        archive::write_dir(&family, &backend, &mut tar, &mut vec![], dir_ref, &self.cancel)?;
+        Ok(tar.finish()?)
+    }
+
+    /// Also read snapshots sealed with the keys in `keyring`, besides those sealed with our own.
+    pub fn set_keyring(&mut self, keyring: crypto::keys::Keyring) {
+        self.keyring = keyring;
+    }
+
+    /// The keys that the snapshot is sealed with: ours, or those with its key id in the keyring.
+    /// Fails early if we have neither, rather than when the first chunk does not decrypt.
+    fn snapshot_keys(
+        &mut self,
+        info: &db::SnapshotInfo,
+    ) -> Result<Arc<crypto::keys::Keeper>, HatError> {
+        let given_key_id = self.keys.key_id();
+        match self.snapshot_index.key_id(info) {
+            Some(ref snapshot_key_id) if *snapshot_key_id != given_key_id => {
+                match self.keyring.lookup(snapshot_key_id) {
+                    // Older keys of the store hash with its salt as well.
+                    Some(keys) => Ok(Arc::new(salted_keys(&self.db, (**keys).clone()))),
+                    None => Err(From::from(WrongKeyError {
+                        snapshot_key_id: snapshot_key_id.clone(),
+                        given_key_id: given_key_id,
+                    })),
+                }
+            }
+            _ => Ok(self.keys.clone()),
+        }
+    }
+
+    /// Look up the full reference of a hash from a prefix of its hex form, like the one given by
+    /// `HashRef::fingerprint`. The prefix must match exactly one known hash.
+    pub fn resolve_hash_ref(&self, prefix: &str) -> Result<hash::tree::HashRef, HatError> {
+        let mut matches = self.hash_index.find_by_prefix(prefix);
+        if matches.len() > 1 {
+            return Err(From::from(format!("Hash prefix {} is ambiguous", prefix)));
+        }
+        let hash = match matches.pop() {
+            Some(hash) => hash,
+            None => return Err(From::from(format!("No hash found with prefix {}", prefix))),
+        };
+        match self.hash_index.fetch_hash_ref(&hash) {
+            Ok(Some(href)) => Ok(href),
+            Ok(None) => Err(From::from(format!("No hash found with prefix {}", prefix))),
+            Err(_) => Err(From::from(format!("Hash {} is not committed yet", prefix))),
+        }
+    }
+
+    /// Describe the chunks in a blob from its metadata, without decrypting their contents.
+    pub fn blob_info(&self, blob_id: i64) -> Result<Vec<ChunkInfo>, HatError> {
+        match self.blob_store.inspect(blob_id)? {
+            Some(chunks) => Ok(chunks),
+            None => Err(From::from(format!("No blob with id {}", blob_id))),
+        }
+    }
+
+    /// Check that the backend still holds exactly the bytes that were uploaded for a blob,
+    /// without decrypting it.
+    pub fn verify_blob_checksum(&self, blob_id: i64) -> Result<bool, HatError> {
+        match self.blob_store.verify_checksum(blob_id)? {
+            Some(matches) => Ok(matches),
+            None => Err(From::from(format!("No checksum recorded for blob {}", blob_id))),
+        }
+    }
+
+    /// Create a signed proof of every complete snapshot in the store and the chunks they consist
+    /// of. The proof can be checked offline with `Proof::verify` and the manifest public key.
+    pub fn export_proof(&mut self) -> Result<Proof, HatError> {
+        let mut all_chunks = BTreeSet::new();
+        let mut snapshots = vec![];
+
+        let backend = self.hash_backend();
+        for snapshot in self.snapshot_index.list_all() {
+            if snapshot.family_name == synthetic_roots_family() {
+                continue;
+            }
+            match snapshot.status {
+                db::SnapshotWorkStatus::CommitComplete => (),
+                _ => continue,
+            }
+            let (root, dir_ref) = match (snapshot.hash, snapshot.hash_ref) {
+                (Some(hash), Some(bytes)) => {
+                    (hash, hash::tree::HashRef::from_bytes(&mut &bytes[..])?)
+                }
+                _ => {
+                    return Err(From::from(format!(
+                        "Snapshot {} #{} is complete, but has no hash",
+                        snapshot.family_name,
+                        snapshot.info.snapshot_id
+                    )))
+                }
+            };
+
+            let family = self.open_family(snapshot.family_name.clone())?;
+            let mut chunks = BTreeSet::new();
+            for content in list_snapshot(&backend, &family, dir_ref) {
+                let href = match content? {
+                    walker::Content::Dir(href) |
+                    walker::Content::Data(href) => href,
+                    walker::Content::Link(_) => continue,
+                };
+                match self.hash_index.leaf_hashes(&href.hash) {
+                    Some(leafs) => chunks.extend(leafs.into_iter().map(|h| h.bytes)),
+                    None => {
+                        return Err(From::from(format!(
+                            "Snapshot {} #{} refers to unknown hash {}",
+                            snapshot.family_name,
+                            snapshot.info.snapshot_id,
+                            href.fingerprint()
+                        )))
+                    }
+                }
+            }
+
+            snapshots.push(proof::SnapshotProof {
+                family_name: snapshot.family_name,
+                snapshot_id: snapshot.info.snapshot_id,
+                root: root.bytes,
+                chunk_count: chunks.len() as u64,
+            });
+            all_chunks.extend(chunks);
+        }
+
+        Proof::sign(
+            &self.keys,
+            chrono::Utc::now().timestamp(),
+            snapshots,
+            proof::merkle_root(&all_chunks),
+        )
+    }
+
+    /// Write an encrypted copy of the index to `path`, to be kept apart from the blob store. The
+    /// copy can be read back with `import_index`, which is much faster than rebuilding the index
+    /// from the blobs with `recover`.
+    pub fn export_index(&mut self, path: &Path) -> Result<(), HatError> {
+        self.data_flush()?;
+        let dump = self.db.lock().export()?;
+
+        // Never leave a partial export where an older, intact one was.
+        let partial = path.with_extension("partial");
+        fs::write(&partial, index_export::seal(&self.keys, &dump[..]))?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Replace the index with one written by `export_index`. The export's signature is checked,
+    /// and it is decrypted and checked against its content hash first; the live index is left
+    /// alone if any of that fails.
+    ///
+    /// Work that was in progress when the export was made is picked up by the next `resume`.
+    pub fn import_index(&mut self, path: &Path) -> Result<(), HatError> {
+        let dump = index_export::open(&self.keys, &fs::read(path)?[..])?;
+
+        self.data_flush()?;
+        self.db.lock().import(&dump[..])?;
+        self.blob_index.refresh_next_id();
+        Ok(())
+    }
+
+    /// Report logical and deduplicated sizes per directory of the latest snapshot of a family.
+    /// Data that other snapshots reference too is not counted as unique.
+    pub fn disk_usage(
+        &mut self,
+        family_name: String,
+        max_depth: Option<usize>,
+    ) -> Result<Vec<DirUsage>, HatError> {
+        let (info, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+
+        let mut other_tops = vec![];
+        for (name, snapshot_id, tops) in self.snapshot_tops()? {
+            if name != family_name || snapshot_id != info.snapshot_id {
+                other_tops.extend(tops);
+            }
+        }
+        let shared = sharing::reachable(&self.hash_index, other_tops);
+
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend();
+        let mut counter = usage::UsageCounter::new(&self.hash_index, max_depth, shared);
+        counter.walk(&family, &backend, dir_ref, PathBuf::new(), 0)?;
+
+        Ok(counter.into_dirs())
+    }
+
+    /// Split the stored bytes of every complete snapshot into those only it references and those
+    /// it shares with other snapshots. Sharing is counted across all snapshots in the store, but
+    /// only snapshots of `family_name` are reported when one is given.
+    pub fn snapshot_sharing(
+        &mut self,
+        family_name: Option<String>,
+    ) -> Result<Vec<SnapshotSharing>, HatError> {
+        let snapshots = self.snapshot_tops()?;
+        let mut counter = sharing::SharingCounter::new(&self.hash_index);
+        for (name, snapshot_id, tops) in snapshots {
+            counter.add(name, snapshot_id, tops);
+        }
+
+        Ok(
+            counter
+                .into_snapshots()
+                .into_iter()
+                .filter(|s| family_name.as_ref().map_or(true, |f| *f == s.family_name))
+                .collect(),
+        )
+    }
+
+    /// The family name, id and directly referenced hash ids of every complete snapshot, as
+    /// `sharing::SharingCounter::add` takes them.
+    fn snapshot_tops(&mut self) -> Result<Vec<(String, u64, Vec<u64>)>, HatError> {
+        let backend = self.hash_backend();
+        let mut snapshots = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            if snapshot.family_name == synthetic_roots_family() {
+                continue;
+            }
+            let (root, dir_ref) = match (snapshot.status, snapshot.hash, snapshot.hash_ref) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(hash), Some(bytes)) => {
+                    (hash, hash::tree::HashRef::from_bytes(&mut &bytes[..])?)
+                }
+                _ => continue,
+            };
+
+            // Files and directories are only referenced from the listings that hold them.
+            let family = self.open_family(snapshot.family_name.clone())?;
+            let mut tops: Vec<u64> = self.hash_index.get_id(&root).into_iter().collect();
+            for content in list_snapshot(&backend, &family, dir_ref) {
+                let href = match content? {
+                    walker::Content::Dir(href) |
+                    walker::Content::Data(href) => href,
+                    walker::Content::Link(_) => continue,
+                };
+                tops.extend(self.hash_index.get_id(&href.hash));
+            }
+            snapshots.push((snapshot.family_name, snapshot.info.snapshot_id, tops));
+        }
+        Ok(snapshots)
+    }
+
+    /// The SHA-256 kept for every file in the latest snapshot of a family, ordered by path. Files
+    /// stored without `set_file_digests` have none.
+    pub fn file_digests(&mut self, family_name: String) -> Result<Vec<FileDigest>, HatError> {
+        let dir_ref = match self.snapshot_index.latest(&family_name) {
+            Some((_, _, Some(r))) => r,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+
+        let family = self.open_family(family_name)?;
+        let backend = self.hash_backend();
+        let mut digests = vec![];
+        manifest::collect(&family, &backend, dir_ref, PathBuf::new(), &mut digests)?;
+        digests.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(digests)
+    }
+
+    /// Compare the latest snapshot of a family with the current state of `source`, reporting
+    /// every path that was added, removed or changed since.
+    pub fn compare_to_source(
+        &mut self,
+        family_name: String,
+        source: PathBuf,
+    ) -> Result<Vec<Divergence>, HatError> {
+        let (info, mut dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((info, _, Some(r))) => (info, r),
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {}",
+                    family_name
+                )))
+            }
+        };
+
+        let family = self.open_family(family_name)?;
+        let source = fs::canonicalize(source)?;
+
+        // Snapshots contain the full path of the committed directory; locate it.
+        for name in source.iter().map(PathBuf::from).filter(|p| !p.has_root()) {
+            let name = name.to_str().map(|s| s.as_bytes().to_vec());
+            let found = family
+                .fetch_dir_data(dir_ref, self.hash_backend())?
+                .into_iter()
+                .find(|&(ref entry, _)| Some(&entry.info.name) == name.as_ref());
+            dir_ref = match found {
+                Some((_, walker::Content::Dir(href))) => href,
+                _ => {
+                    return Err(From::from(format!(
+                        "{} is not a directory in the latest snapshot",
+                        source.display()
+                    )))
+                }
+            };
+        }
+
+        // Chunk the source the way the snapshot was chunked, or nothing will match.
+        let chunker = match self.snapshot_index.chunker(&info) {
+            Some(chunker) => key::Chunker::parse(&chunker)?,
+            None => key::Chunker::default(),
+        };
+        let mut comparer = compare::SourceComparer::new(
+            &self.hash_index,
+            &self.keys,
+            &family,
+            self.hash_backend(),
+            chunker,
+        );
+        comparer.compare_dir(dir_ref, source)?;
+
+        Ok(comparer.into_divergences())
+    }
+
+    fn checkout_dir_ref(
+        &self,
+        family: &Family<B>,
+        backend: &key::HashStoreBackend<B>,
+        output: &mut PathBuf,
+        snapshot_path: &mut Vec<String>,
+        dir_hash: hash::tree::HashRef,
+        options: &RestoreOptions,
+        conflicts: &mut Vec<RestoreConflict>,
+    ) -> Result<(), HatError> {
+        // Directories that are only passed through are created once something inside them is.
+        if options.filter.selects(&snapshot_path[..]) {
+            fs::create_dir_all(&output).unwrap();
+        }
+        let mut restored = HashMap::new();
+        for (entry, hash_ref) in family.fetch_dir_data(dir_hash, backend.clone())? {
+            self.cancel.check()?;
+            assert!(entry.info.name.len() > 0);
+
+            snapshot_path.push(String::from_utf8_lossy(&entry.info.name[..]).into_owned());
+            let is_dir = match hash_ref {
+                walker::Content::Dir(_) => true,
+                _ => false,
+            };
+            let local_name = match options.target_name(
+                &snapshot_path[..],
+                &entry.info.name[..],
+                is_dir,
+                &mut restored,
+                conflicts,
+            ) {
+                Some(name) => name,
+                None => {
+                    snapshot_path.pop();
+                    continue;
+                }
+            };
+
+            output.push(&local_name);
+            if !is_dir {
+                fs::create_dir_all(output.parent().unwrap()).unwrap();
+                println!("{}", output.display());
+            }
+
+            if !is_dir && entry.info.content_not_captured {
+                println!("Skipping '{}': contents not captured", output.display());
+                output.pop();
+                snapshot_path.pop();
+                continue;
+            }
+
+            match hash_ref {
+                walker::Content::Data(hash_ref) => {
+                    let mut fd = fs::File::create(&output).unwrap();
+                    let backend = match options.memory_budget {
+                        Some(ref budget) => {
+                            backend.clone().within_budget(budget.clone(), self.blob_max_size)
+                        }
+                        None => backend.clone(),
+                    };
+                    // A sparse file gets its length first, so that the holes skipped stay holes.
+                    let holes = entry.info.holes.clone();
+                    let len = entry.info.byte_length.unwrap_or(0);
+                    if !holes.is_empty() {
+                        fd.set_len(len)?;
+                    }
+                    let tree_opt = hash::tree::LeafIterator::new(backend, hash_ref)?;
+                    match tree_opt {
+                        Some(tree) if holes.is_empty() => family.write_file_chunks(&mut fd, tree)?,
+                        Some(tree) => {
+                            family.write_file_chunks(&mut SparseWriter::new(&mut fd, holes), tree)?
+                        }
+                        None => (),
+                    }
+                }
+                walker::Content::Dir(hash_ref) => {
+                    self.checkout_dir_ref(
+                        family,
+                        backend,
+                        output,
+                        snapshot_path,
+                        hash_ref,
+                        options,
+                        conflicts,
+                    )?;
+                    if fs::symlink_metadata(&output).is_err() {
+                        // Nothing in it was restored.
+                        output.pop();
+                        snapshot_path.pop();
+                        continue;
+                    }
+                    println!("{}", output.display());
+                }
+                walker::Content::Link(link_path) => {
+                    use std::os::unix::fs::symlink;
+                    symlink(link_path, &output)?
+                }
+            }
+
+            // Before the permissions, as changing the owner clears the setuid and setgid bits.
+            if options.numeric_ids {
+                if let (Some(uid), Some(gid)) = (entry.info.user_id, entry.info.group_id) {
+                    set_owner(&output, uid, gid)?;
+                }
+            }
+
+            if let Some(perms) = entry.info.permissions {
+                fs::set_permissions(&output, perms)?;
+            }
+
+            if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
+                let atime = filetime::FileTime::from_seconds_since_1970(a, 0 /* nanos */);
+                let mtime = filetime::FileTime::from_seconds_since_1970(m, 0 /* nanos */);
+                filetime::set_file_times(&output, atime, mtime)?;
+            }
+
+            if let Err(e) = xattrs::restore(&output, &entry.info.extended_attributes) {
+                xattrs::warn_unsupported(&output, &e);
+            }
+
+            output.pop();
+            snapshot_path.pop();
+        }
+        Ok(())
+    }
+
+    /// Delete a snapshot without reclaiming any space.
+    ///
+    /// The snapshot is removed from the index and the snapshot listing, and the GC is told that
+    /// it no longer references its data. Data used only by this snapshot is reclaimed by the next
+    /// `gc`, while data shared with other snapshots is kept. Returns `false` if there was no such
+    /// snapshot, e.g. because it was already deleted.
+    pub fn delete_snapshot(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+    ) -> Result<bool, HatError> {
+        if self.snapshot_index
+            .lookup(&family_name, snapshot_id)
+            .is_none()
+        {
+            return Ok(false);
+        }
+        self.deregister_by_name(family_name, snapshot_id)?;
+        self.meta_commit()?;
+        // The new root is only in the open blob until this.
+        self.data_flush()?;
+
+        Ok(true)
+    }
+
+    pub fn deregister_by_name(
         &mut self,
         family_name: String,
         snapshot_id: u64,
@@ -933,52 +2294,47 @@ impl<B: StoreBackend> HatRc<B> {
         self.snapshot_index.will_delete(&info);
         self.flush_snapshot_index();
 
-        let final_ref = self.hash_index.get_id(&top_hash).expect(
-            "Snapshot hash does not exist",
-        );
-
-        {
-            let hash_backend = self.hash_backend();
-            let &mut Hat {
-                ref hash_index,
-                ref mut gc,
-                ..
-            } = self;
-
-            let listing = || {
-                let (id_sender, id_receiver) = mpsc::channel();
-                match top_ref.leaf {
-                    blob::LeafType::TreeList => {
-                        // Recursive tree structure.
-                        // We need all top hashes from all sub-trees.
-                        for hash in list_snapshot(&hash_backend, &family, top_ref) {
-                            let res = hash.expect("Invalid hash ref");
-                            let href = match res {
-                                walker::Content::Data(href) => href,
-                                walker::Content::Dir(href) => href,
-                                walker::Content::Link(_) => continue,
-                            };
-                            match hash_index.get_id(&href.hash) {
-                                Some(id) => id_sender.send(id).unwrap(),
-                                None => panic!("Unexpected reply from hash index."),
-                            }
-                        }
-                    }
-                    blob::LeafType::SnapshotList => {
-                        // Only the top ref is needed for snapshot lists.
-                        id_sender
-                            .send(hash_index.get_id(&top_ref.hash).expect("Unknown top ref"))
-                            .unwrap();
-                    }
-                    blob::LeafType::FileChunk => {
-                        unreachable!("Called deregister directly on filechunk tree")
-                    }
+        let unknown = |hash: &hash::Hash| -> HatError {
+            From::from(format!("Hash {} of snapshot is not in the hash index", hash.bytes.to_hex()))
+        };
+        let final_ref = self.hash_index.get_id(&top_hash).ok_or_else(|| unknown(&top_hash))?;
+
+        // Listed up front, so that a damaged snapshot fails before the GC has seen any of it.
+        let mut ids = vec![];
+        match top_ref.leaf {
+            blob::LeafType::TreeList => {
+                // Recursive tree structure.
+                // We need all top hashes from all sub-trees.
+                let hash_backend = self.hash_backend();
+                for hash in list_snapshot(&hash_backend, &family, top_ref) {
+                    let href = match hash? {
+                        walker::Content::Data(href) => href,
+                        walker::Content::Dir(href) => href,
+                        walker::Content::Link(_) => continue,
+                    };
+                    ids.push(self.hash_index.get_id(&href.hash).ok_or_else(
+                        || unknown(&href.hash),
+                    )?);
                 }
-                id_receiver
-            };
-
-            gc.deregister(&info, final_ref, listing)?;
+            }
+            blob::LeafType::SnapshotList => {
+                // Only the top ref is needed for snapshot lists.
+                ids.push(self.hash_index.get_id(&top_ref.hash).ok_or_else(
+                    || unknown(&top_ref.hash),
+                )?);
+            }
+            blob::LeafType::FileChunk => {
+                return Err(From::from("Cannot deregister a file chunk tree as a snapshot"));
+            }
         }
+        let listing = || {
+            let (id_sender, id_receiver) = mpsc::channel();
+            for id in ids {
+                id_sender.send(id).unwrap();
+            }
+            id_receiver
+        };
+        self.gc.deregister(&info, final_ref, listing)?;
         family.flush()?;
 
         self.deregister_finalize(family, info, final_ref)
@@ -1011,7 +2367,9 @@ impl<B: StoreBackend> HatRc<B> {
         family.flush()?;
 
         // Delete snapshot metadata.
+        let affected = vec![snap_info.family_id as i64, snap_info.snapshot_id as i64];
         self.snapshot_index.delete(snap_info);
+        self.audit(audit::SNAPSHOT_DELETE, affected);
         self.flush_snapshot_index();
 
         self.snapshot_index.flush();
@@ -1020,15 +2378,86 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn gc(&mut self) -> Result<(u64, u64), HatError> {
-        // Remove unused hashes.
+        self.gc_with_grace(chrono::Duration::zero())
+    }
+
+    /// Like `gc()`, but only delete hashes that have been unused for at least `grace`, as seen by
+    /// earlier GC runs. Hashes that are unused for the first time are marked and kept, so that
+    /// data of a snapshot deleted by mistake can still be recovered for a while.
+    pub fn gc_with_grace(&mut self, grace: chrono::Duration) -> Result<(u64, u64), HatError> {
+        let mut options = GcOptions::default();
+        options.grace = grace;
+        self.gc_with_options(&options)
+    }
+
+    /// Like `gc()`, with the grace period and safety checks given by `options`.
+    pub fn gc_with_options(&mut self, options: &GcOptions) -> Result<(u64, u64), HatError> {
+        let _operation = self.cancel.operation();
+        self.cancel.check()?;
+        let now = self.clock.now().timestamp();
+
+        let unused = self.list_unused_ids(options)?;
+        if options.paranoid {
+            let again = self.list_unused_ids(options)?;
+            let differ = unused.symmetric_difference(&again).count();
+            if differ > 0 {
+                return Err(From::from(format!(
+                    "GC aborted before deleting anything: two runs disagree on whether {} \
+                     hashes are in use",
+                    differ
+                )));
+            }
+        }
+        if options.verify_reachability || options.paranoid {
+            let used = self.gc.list_used_ids()?;
+            let mut overlap: Vec<gc::Id> = unused.intersection(&used).cloned().collect();
+            if !overlap.is_empty() {
+                overlap.sort();
+                let example = self.hash_index
+                    .get_hash(overlap[0])
+                    .map(|e| e.hash.bytes.to_hex())
+                    .unwrap_or_else(|| format!("id {}", overlap[0]));
+                return Err(From::from(format!(
+                    "GC aborted before deleting anything: {} hashes marked unused are still \
+                     reachable, e.g. {}",
+                    overlap.len(),
+                    example
+                )));
+            }
+        }
+
+        // Remove unused hashes. This stays serial whatever the concurrency: every delete is a
+        // row in the index, which takes one writer at a time.
         let mut deleted_hashes = 0;
-        let (sender, receiver) = mpsc::channel();
-        self.gc.list_unused_ids(sender)?;
-        for id in receiver.iter() {
+        for &id in unused.iter() {
+            if self.cancel.is_cancelled() {
+                // The hashes deleted so far were unused; the rest are found again next time.
+                break;
+            }
+            if options.grace > chrono::Duration::zero() {
+                let unused_since = self.hash_index.mark_unused(id, now);
+                if chrono::Duration::seconds(now - unused_since) < options.grace {
+                    continue;
+                }
+            }
             deleted_hashes += 1;
             self.hash_index.delete(id);
         }
+        if !self.cancel.is_cancelled() {
+            // Hashes that were marked before but are used again must start over.
+            for id in self.hash_index.list_marked_unused() {
+                if !unused.contains(&id) {
+                    self.hash_index.unmark_unused(id);
+                }
+            }
+        }
         self.hash_index.flush();
+        if deleted_hashes > 0 {
+            self.db.lock().gc_run_record(now);
+        }
+        // Stop before touching blobs, as their tags must not be left half-way.
+        self.cancel.check()?;
+
         // Mark used blobs.
         let entries = self.hash_index.list();
         self.blob_store.tag_all(tags::Tag::InProgress);
@@ -1041,18 +2470,587 @@ impl<B: StoreBackend> HatRc<B> {
             }
         }
         // Anything still marked "in progress" is not referenced by any hash.
-        self.blob_store.delete_by_tag(tags::Tag::InProgress)?;
+        let unused_blobs: Vec<i64> = self.blob_store
+            .list_by_tag(tags::Tag::InProgress)
+            .iter()
+            .map(|b| b.id)
+            .collect();
+        self.blob_store.delete_by_tag_concurrently(tags::Tag::InProgress, options.concurrency)?;
+        if deleted_hashes == 0 && !unused_blobs.is_empty() {
+            self.db.lock().gc_run_record(now);
+        }
+        if deleted_hashes > 0 || !unused_blobs.is_empty() {
+            self.audit(audit::GC, unused_blobs);
+        }
         self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
+        self.blob_store.flush()?;
 
         Ok((deleted_hashes, live_blobs))
     }
 
+    fn list_unused_ids(&mut self, options: &GcOptions) -> Result<HashSet<gc::Id>, HatError> {
+        let mut unused = if options.concurrency > 1 {
+            self.list_unused_ids_concurrently(options.concurrency)?
+        } else {
+            let (sender, receiver) = mpsc::channel();
+            self.gc.list_unused_ids(sender)?;
+            receiver.iter().collect()
+        };
+        if let Some(ref hook) = options.mark_hook {
+            hook(&mut unused);
+        }
+        Ok(unused)
+    }
+
+    /// Like the GC's `list_unused_ids`, with the trees walked on `concurrency` threads.
+    fn list_unused_ids_concurrently(
+        &mut self,
+        concurrency: usize,
+    ) -> Result<HashSet<gc::Id>, HatError> {
+        self.hash_index.set_all_tags(tags::Tag::Done);
+        let roots = self.gc.list_roots()?;
+        let used = mark::used_ids(&self.hash_index, roots, concurrency);
+
+        // Leave the tags as the GC's own mark does: used hashes reserved, the rest done.
+        let mut unused = HashSet::new();
+        for id in self.hash_index.get_ids_by_tag(tags::Tag::Done as u64) {
+            if used.contains(&id) {
+                self.hash_index.set_tag(id, tags::Tag::Reserved);
+            } else {
+                unused.insert(id);
+            }
+        }
+        Ok(unused)
+    }
+
+    /// Read back every chunk in the store and check it against its hash.
+    ///
+    /// Progress is appended to the file at `checkpoint`. With `resume`, chunks recorded there by
+    /// an earlier run are skipped, unless the GC has deleted data since. The run stops early
+    /// after `max_chunks` chunks, or when cancelled; the report says whether it got through.
+    pub fn verify(
+        &mut self,
+        checkpoint: &Path,
+        resume: bool,
+        max_chunks: Option<u64>,
+    ) -> Result<VerifyReport, HatError> {
+        self.verify_parallel(checkpoint, resume, max_chunks, 1)
+    }
+
+    /// Like `verify`, but with up to `concurrency` chunks read back at the same time.
+    ///
+    /// Chunks are recorded in the checkpoint in the same order as by `verify`, a batch at a time,
+    /// so an interrupted run loses at most the batch it was on.
+    pub fn verify_parallel(
+        &mut self,
+        checkpoint: &Path,
+        resume: bool,
+        max_chunks: Option<u64>,
+        concurrency: usize,
+    ) -> Result<VerifyReport, HatError> {
+        let _operation = self.cancel.operation();
+        let generation = self.db.lock().gc_generation();
+        let (mut checkpoint, restarted) = verify::Checkpoint::open(checkpoint, generation, resume)?;
+        let mut report = VerifyReport {
+            restarted: restarted,
+            complete: true,
+            ..VerifyReport::default()
+        };
+
+        let mut pending = vec![];
+        for entry in self.hash_index.list() {
+            if !entry.ready {
+                continue;
+            }
+            let persistent_ref = match entry.persistent_ref {
+                Some(r) => r,
+                None => continue,
+            };
+            if checkpoint.is_done(&entry.hash) {
+                report.skipped += 1;
+                continue;
+            }
+            if max_chunks.map_or(false, |max| pending.len() as u64 >= max) {
+                report.complete = false;
+                break;
+            }
+            pending.push(hash::tree::HashRef {
+                hash: entry.hash,
+                node: entry.node,
+                leaf: entry.leaf,
+                info: None,
+                persistent_ref: persistent_ref,
+            });
+        }
+
+        let concurrency = cmp::max(1, concurrency);
+        let pool = scoped_pool::Pool::new(concurrency);
+        for batch in pending.chunks(concurrency * VERIFY_BATCH_PER_THREAD) {
+            if self.cancel.is_cancelled() {
+                report.complete = false;
+                break;
+            }
+            for (href, failure) in batch.iter().zip(self.verify_chunks(&pool, batch)) {
+                match failure {
+                    None => {
+                        checkpoint.record_ok(&href.hash)?;
+                        report.passed += 1;
+                    }
+                    Some(reason) => {
+                        checkpoint.record_failure(&href.hash, &reason)?;
+                        report.failed += 1;
+                    }
+                }
+                report.verified += 1;
+            }
+        }
+        pool.shutdown();
+
+        report.failures = checkpoint.failures().to_vec();
+        if !report.failures.is_empty() {
+            report.locations = self.locate_failures(&report.failures)?;
+        }
+        Ok(report)
+    }
+
+    /// Verify a slice of the store: the chunks that have gone longest without being checked,
+    /// enough of them that `options.period_runs` runs check every chunk. When each chunk was
+    /// checked is kept in the index, so that runs carry on from each other.
+    pub fn scrub(&mut self, options: &ScrubOptions) -> Result<ScrubReport, HatError> {
+        let _operation = self.cancel.operation();
+        let mut chunks = vec![];
+        for entry in self.hash_index.list() {
+            if !entry.ready {
+                continue;
+            }
+            let persistent_ref = match entry.persistent_ref {
+                Some(r) => r,
+                None => continue,
+            };
+            let id = match self.hash_index.get_id(&entry.hash) {
+                Some(id) => id,
+                None => continue,
+            };
+            chunks.push((
+                id,
+                hash::tree::HashRef {
+                    hash: entry.hash,
+                    node: entry.node,
+                    leaf: entry.leaf,
+                    info: None,
+                    persistent_ref: persistent_ref,
+                },
+            ));
+        }
+        let mut verified: HashMap<u64, i64> = self.hash_index.list_verified().into_iter().collect();
+        let mut report = ScrubReport {
+            total: chunks.len() as u64,
+            ..ScrubReport::default()
+        };
+        let live: HashSet<u64> = chunks.iter().map(|&(id, _)| id).collect();
+        let picked = scrub::pick(chunks, &verified, options.period_runs);
+
+        let concurrency = cmp::max(1, options.concurrency);
+        let pool = scoped_pool::Pool::new(concurrency);
+        for batch in picked.chunks(concurrency * VERIFY_BATCH_PER_THREAD) {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let hrefs: Vec<hash::tree::HashRef> =
+                batch.iter().map(|&(_, ref href)| href.clone()).collect();
+            let now = self.clock.now().timestamp();
+            for (&(id, ref href), failure) in batch.iter().zip(self.verify_chunks(&pool, &hrefs)) {
+                match failure {
+                    None => report.passed += 1,
+                    Some(reason) => {
+                        report.failures.push((href.hash.bytes.to_hex(), reason));
+                        report.failed += 1;
+                    }
+                }
+                self.hash_index.set_verified(id, now);
+                verified.insert(id, now);
+                report.verified += 1;
+            }
+        }
+        pool.shutdown();
+        self.meta_flush();
+
+        report.never_verified = live.iter().filter(|id| !verified.contains_key(id)).count() as u64;
+        if report.never_verified == 0 {
+            report.oldest_verified_utc =
+                live.iter().filter_map(|id| verified.get(id)).cloned().min();
+        }
+        if !report.failures.is_empty() {
+            report.locations = self.locate_failures(&report.failures)?;
+        }
+        self.cancel.check()?;
+        Ok(report)
+    }
+
+    /// Read back `hrefs` on the threads of `pool`, and tell for each why it failed, if it did.
+    fn verify_chunks(
+        &self,
+        pool: &scoped_pool::Pool,
+        hrefs: &[hash::tree::HashRef],
+    ) -> Vec<Option<String>> {
+        let failures: Vec<Mutex<Option<String>>> = hrefs.iter().map(|_| Mutex::new(None)).collect();
+        let blob_store = &self.blob_store;
+        let keys = &self.keys;
+        pool.scoped(|scope| {
+            for (href, failure) in hrefs.iter().zip(failures.iter()) {
+                scope.execute(move || {
+                    let reason = match blob_store.retrieve_concurrently(href) {
+                        Ok(Some(chunk)) => {
+                            let hash = hash::Hash::new(keys, href.node, href.leaf, &chunk[..]);
+                            if hash == href.hash {
+                                None
+                            } else {
+                                Some("does not match its hash".to_owned())
+                            }
+                        }
+                        Ok(None) => Some("missing from the backend".to_owned()),
+                        Err(e) => Some(e.to_string()),
+                    };
+                    *failure.lock().unwrap() = reason;
+                });
+            }
+        });
+        failures.into_iter().map(|f| f.into_inner().unwrap()).collect()
+    }
+
+    /// Find where the failed chunks are used, in every complete snapshot.
+    fn locate_failures(
+        &mut self,
+        failures: &[(String, String)],
+    ) -> Result<Vec<verify::FailedChunk>, HatError> {
+        let backend = self.hash_backend();
+        let hash_index = self.hash_index.clone();
+        let mut attributor = verify::Attributor::new(&hash_index, failures);
+        for snapshot in self.snapshot_index.list_all() {
+            if snapshot.family_name == synthetic_roots_family() {
+                continue;
+            }
+            let dir_ref = match (snapshot.status, snapshot.hash_ref) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(bytes)) => {
+                    hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                }
+                _ => continue,
+            };
+            let family = self.open_family(snapshot.family_name.clone())?;
+            attributor.walk(
+                &family,
+                &backend,
+                snapshot.info.snapshot_id,
+                &mut PathBuf::new(),
+                dir_ref,
+            );
+        }
+        Ok(attributor.into_locations())
+    }
+
+    /// Move the live chunks of a blob to new blobs, and delete it. Returns the number of chunks
+    /// moved.
+    ///
+    /// Chunks are authenticated as they are read from the old blob. Once the new blobs are
+    /// stored, a sample of the chunks is read back from them; only if that succeeds are the
+    /// hashes pointed at their new copies and the old blob deleted. If it fails, the old blob is
+    /// kept and the new copies are left for the GC.
+    pub fn rewrite_blob(&mut self, blob_id: i64) -> Result<u64, HatError> {
+        let blob = match self.blob_store.find_by_id(blob_id) {
+            Some(blob) => blob,
+            None => return Err(From::from(format!("No blob with id {}", blob_id))),
+        };
+        self.rewrite_blobs(&[blob])
+    }
+
+    /// Repack the live chunks of blobs holding less than `min_live_bytes` of them into full
+    /// blobs, and delete the originals. Snapshots are not touched; only where their chunks are
+    /// kept changes. Returns how many blobs were merged.
+    ///
+    /// Blobs are moved in batches, each like `rewrite_blob`: the new copies are stored and
+    /// checked before any hash points at them, and the old blobs are deleted only after that.
+    /// A crash at any point leaves every chunk readable, at worst with unused copies for the GC
+    /// to delete, and running again carries on with the blobs that are still small.
+    pub fn consolidate_blobs(
+        &mut self,
+        min_live_bytes: usize,
+    ) -> Result<ConsolidateReport, HatError> {
+        let _operation = self.cancel.operation();
+        // Chunks only count once their blob is stored.
+        self.blob_store.flush()?;
+
+        let mut live_bytes: HashMap<i64, usize> = HashMap::new();
+        for entry in self.hash_index.list() {
+            if !entry.ready {
+                continue;
+            }
+            match entry.persistent_ref {
+                Some(ref r) if r.blob_id.map_or(false, |id| id > 0) => {
+                    *live_bytes.entry(r.blob_id.unwrap()).or_insert(0) += r.length;
+                }
+                _ => (),
+            }
+        }
+        let mut small: Vec<(i64, usize)> = live_bytes
+            .into_iter()
+            .filter(|&(_, bytes)| bytes < min_live_bytes)
+            .collect();
+        small.sort();
+
+        let mut report = ConsolidateReport {
+            complete: true,
+            ..ConsolidateReport::default()
+        };
+        // A single blob has nothing to be merged with.
+        if small.len() < 2 {
+            return Ok(report);
+        }
+
+        let batch_bytes = CONSOLIDATE_BATCH_BLOBS * self.blob_max_size;
+        let mut small = small.into_iter().peekable();
+        while small.peek().is_some() {
+            if self.cancel.is_cancelled() {
+                report.complete = false;
+                break;
+            }
+            let mut batch = vec![];
+            let mut bytes = 0;
+            while bytes < batch_bytes {
+                match small.next() {
+                    Some((id, live)) => {
+                        if let Some(blob) = self.blob_store.find_by_id(id) {
+                            batch.push(blob);
+                            bytes += live;
+                        }
+                    }
+                    None => break,
+                }
+            }
+            report.chunks_moved += self.rewrite_blobs(&batch)?;
+            report.blobs_merged += batch.len() as u64;
+        }
+
+        Ok(report)
+    }
+
+    /// Seal every live chunk again with the chunk key algorithm `algorithm`, under a new key,
+    /// and seal new chunks with it from then on. Chunks are not split again; only the blobs
+    /// holding chunks sealed with another algorithm are rewritten, in batches like
+    /// `consolidate_blobs`. `progress` is called after each batch.
+    ///
+    /// The old blobs are deleted once their chunks are readable from the new ones, so that no
+    /// chunk is left readable under the old algorithm. Interrupting the run leaves every chunk
+    /// readable under one of the two, and running again carries on with the blobs not yet done.
+    ///
+    /// Moving to an algorithm without key commitment is refused unless `allow_uncommitted`.
+    pub fn rekey<F>(
+        &mut self,
+        algorithm: &str,
+        allow_uncommitted: bool,
+        mut progress: F,
+    ) -> Result<RekeyReport, HatError>
+    where
+        F: FnMut(&RekeyReport),
+    {
+        let _operation = self.cancel.operation();
+        if !allow_uncommitted && !blob::Key::commits_to_key(algorithm) {
+            return Err(From::from(format!(
+                "{} does not commit to the key of each chunk; allow it explicitly to rekey to it",
+                algorithm
+            )));
+        }
+        self.blob_store.set_seal_algorithm(algorithm)?;
+        let algorithm = self.blob_store.seal_algorithm();
+        // Chunks only count once their blob is stored.
+        self.blob_store.flush()?;
+
+        let mut stale = BTreeSet::new();
+        for entry in self.hash_index.list() {
+            if !entry.ready {
+                continue;
+            }
+            match entry.persistent_ref {
+                Some(ref r) if r.blob_id.map_or(false, |id| id > 0) &&
+                                   r.key.as_ref().map_or(false, |k| k.algorithm() != algorithm) => {
+                    stale.insert(r.blob_id.unwrap());
+                }
+                _ => (),
+            }
+        }
+
+        let mut report = RekeyReport {
+            blobs_total: stale.len() as u64,
+            ..RekeyReport::default()
+        };
+        let mut stale = stale.into_iter().peekable();
+        while stale.peek().is_some() {
+            if self.cancel.is_cancelled() {
+                progress(&report);
+                return Ok(report);
+            }
+            let mut batch = vec![];
+            while batch.len() < CONSOLIDATE_BATCH_BLOBS {
+                match stale.next() {
+                    Some(id) => {
+                        if let Some(blob) = self.blob_store.find_by_id(id) {
+                            batch.push(blob);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            report.chunks_rekeyed += self.rewrite_blobs(&batch)?;
+            report.blobs_rewritten += batch.len() as u64;
+            progress(&report);
+        }
+
+        report.complete = true;
+        Ok(report)
+    }
+
+    fn rewrite_blobs(&mut self, blobs: &[blob::BlobDesc]) -> Result<u64, HatError> {
+        self.require_reader_version(self.written_reader_version())?;
+        // Leave behind chunks that no hash points at anymore.
+        let mut live = vec![];
+        for blob in blobs {
+            let hrefs = self.blob_store.retrieve_refs(blob.clone())?.unwrap_or_else(Vec::new);
+            for href in hrefs {
+                let id = match self.hash_index.get_id(&href.hash) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                match self.hash_index.get_hash(id).and_then(|e| e.persistent_ref) {
+                    Some(ref r) if r.blob_name == blob.name &&
+                                       r.offset == href.persistent_ref.offset => {
+                        live.push((id, blob.id, href))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // The cache would point the new copies right back at the old blobs.
+        self.blob_store.clear_chunk_cache();
+        let mut moved = vec![];
+        for (id, blob_id, href) in live {
+            let chunk = match self.blob_store.retrieve(&href)? {
+                Some(chunk) => chunk,
+                None => return Err(From::from(format!("Blob {} disappeared", blob_id))),
+            };
+            if hash::Hash::new(&self.keys, href.node, href.leaf, &chunk[..]) != href.hash {
+                return Err(From::from(
+                    format!("Chunk in blob {} does not match its hash", blob_id),
+                ));
+            }
+            let new_href = self.blob_store.store(
+                &chunk[..],
+                href.hash.clone(),
+                href.node,
+                href.leaf,
+                href.info.as_ref(),
+                Box::new(|()| {}),
+            )?;
+            moved.push((id, blob_id, new_href));
+        }
+        self.blob_store.flush()?;
+
+        let stride = cmp::max(1, moved.len() / REWRITE_VERIFY_SAMPLES);
+        for (i, &(_, blob_id, ref new_href)) in moved.iter().enumerate() {
+            if i % stride != 0 && i + 1 != moved.len() {
+                continue;
+            }
+            let verified = match self.blob_store.retrieve(new_href) {
+                Ok(Some(chunk)) => {
+                    hash::Hash::new(&self.keys, new_href.node, new_href.leaf, &chunk[..]) ==
+                        new_href.hash
+                }
+                _ => false,
+            };
+            if !verified {
+                return Err(From::from(format!(
+                    "New copy of blob {} failed verification; keeping the original",
+                    blob_id
+                )));
+            }
+        }
+
+        // Trees still refer to the old copies. A store recovered from the backend alone finds the
+        // new ones through the relocation, so it has to be durable before the old ones are gone.
+        if !moved.is_empty() {
+            let relocated = moved
+                .iter()
+                .map(|&(_, _, ref new_href)| {
+                    hash::tree::HashRef {
+                        info: None,
+                        ..new_href.clone()
+                    }
+                })
+                .collect();
+            relocation::store(&*self.backend, &self.keys, &relocated)?;
+        }
+        for &(id, _, ref new_href) in moved.iter() {
+            self.hash_index.set_persistent_ref(id, &new_href.persistent_ref);
+        }
+        self.hash_index.flush();
+        if !blobs.is_empty() {
+            // What was verified in the old blobs says nothing about the new ones.
+            self.db.lock().gc_run_record(self.clock.now().timestamp());
+        }
+        for blob in blobs {
+            self.blob_store.delete(blob)?;
+        }
+        self.audit(audit::COMPACTION, blobs.iter().map(|b| b.id).collect());
+        self.blob_store.flush()?;
+
+        Ok(moved.len() as u64)
+    }
+
+    /// Add an entry to the audit log, committed with the next flush of the index.
+    fn audit(&self, operation: &str, affected: Vec<i64>) {
+        let now = self.clock.now().timestamp();
+        audit::append(&self.keys, &self.db, now, operation, affected);
+    }
+
+    /// The audit log of snapshot commits and deletions, GC runs and compactions, oldest first.
+    pub fn audit_log(&self) -> Vec<AuditEntry> {
+        self.db.lock().audit_list()
+    }
+
+    /// Check that no entry of the audit log was changed, inserted or removed. Returns the chain
+    /// hash of the last entry; keeping it elsewhere lets a later check notice entries removed
+    /// from the end.
+    pub fn verify_audit_log(&self) -> Result<Vec<u8>, HatError> {
+        audit::verify(&self.keys, &self.audit_log()[..])
+    }
+
+    /// Replace the clock used for time dependent decisions, like the GC grace period.
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+    }
+
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
         key::HashStoreBackend::new(
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        )
+        ).with_verify_dedup(self.verify_dedup)
+            .with_relocations(self.relocations.clone())
+    }
+
+    /// A hash backend for reading chunks sealed with `keys`, which need not be ours.
+    fn hash_backend_for(&self, keys: Arc<crypto::keys::Keeper>) -> key::HashStoreBackend<B> {
+        if keys.key_id() == self.keys.key_id() {
+            return self.hash_backend();
+        }
+        let blob_store = Arc::new(blob::BlobStore::new(
+            keys.clone(),
+            self.blob_index.clone(),
+            self.backend.clone(),
+            self.blob_max_size,
+        ));
+        // Blobs are cached as stored, so what was prefetched serves every key.
+        blob_store.share_read_cache(&self.blob_store);
+        key::HashStoreBackend::new(self.hash_index.clone(), blob_store, keys)
+            .with_relocations(self.relocations.clone())
     }
 }
@@ -0,0 +1,393 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Paths inside snapshots, and how they are mapped onto a filesystem on restore.
+//!
+//! Snapshots store a tree of names, never whole paths, so nothing about the platform they were
+//! taken on is baked into them. Where a path is needed as text, e.g. in reports, it is written
+//! in the internal form: names separated by `/`, with a leading `/` for the root of the
+//! snapshot. When parsing, `\` is accepted as a separator too, and empty and `.` components are
+//! dropped.
+//!
+//! On restore, a `PathPolicy` decides what each name becomes on the target. Names that the
+//! target cannot tell apart, like `README` and `Readme` on a case-insensitive filesystem, are
+//! reported as conflicts instead of overwriting each other. A `PathFilter` can limit the restore
+//! to part of the snapshot.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str;
+use util::MemoryBudget;
+
+
+/// Parse a path into its names. Both `/` and `\` separate names.
+pub fn parse(path: &str) -> Vec<String> {
+    path.split(|c| c == '/' || c == '\\')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(|name| name.to_owned())
+        .collect()
+}
+
+/// Write names in the internal form.
+pub fn format<S: AsRef<str>>(names: &[S]) -> String {
+    let mut out = String::new();
+    for name in names {
+        out.push('/');
+        out.push_str(name.as_ref());
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+/// Maps names from a snapshot onto the conventions of the filesystem they are restored to.
+pub trait PathPolicy {
+    /// The name to restore an entry under, or why it cannot be restored.
+    fn local_name(&self, name: &[u8]) -> Result<String, String>;
+
+    /// Names with the same key refer to the same file on the target.
+    fn collision_key(&self, local_name: &str) -> String;
+}
+
+fn check_name(name: &[u8]) -> Result<&str, String> {
+    let name = str::from_utf8(name).map_err(|_| "name is not valid UTF-8".to_owned())?;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("{:?} is not a file name", name));
+    }
+    Ok(name)
+}
+
+/// Restore names unchanged, to a case-sensitive filesystem with `/` as its only separator.
+pub struct PosixPolicy;
+
+impl PathPolicy for PosixPolicy {
+    fn local_name(&self, name: &[u8]) -> Result<String, String> {
+        let name = check_name(name)?;
+        if name.contains('/') {
+            return Err(format!("{:?} contains a path separator", name));
+        }
+        Ok(name.to_owned())
+    }
+
+    fn collision_key(&self, local_name: &str) -> String {
+        local_name.to_owned()
+    }
+}
+
+/// Restore to a Windows-style filesystem: case-insensitive, with a number of characters that are
+/// not allowed in names. These are replaced with `_`, as are trailing dots and spaces.
+pub struct WindowsPolicy;
+
+impl PathPolicy for WindowsPolicy {
+    fn local_name(&self, name: &[u8]) -> Result<String, String> {
+        let name = check_name(name)?;
+        let mut out: String = name.chars()
+            .map(|c| match c {
+                '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+                c if (c as u32) < 32 => '_',
+                c => c,
+            })
+            .collect();
+        let trimmed = out.trim_right_matches(|c| c == '.' || c == ' ').len();
+        let replaced = out.len() - trimmed;
+        out.truncate(trimmed);
+        for _ in 0..replaced {
+            out.push('_');
+        }
+        Ok(out)
+    }
+
+    fn collision_key(&self, local_name: &str) -> String {
+        local_name.to_lowercase()
+    }
+}
+
+/// A glob over snapshot paths. `*` and `?` match within a name, and a `**` name matches any
+/// number of names. Patterns with a separator are matched from the root of the snapshot, others
+/// against every name in a path. A pattern that matches a directory also matches everything in
+/// it, so `/etc` selects the whole directory.
+#[derive(Clone, Debug)]
+struct Pattern {
+    anchored: bool,
+    names: Vec<String>,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Pattern {
+        Pattern {
+            anchored: pattern.contains(|c| c == '/' || c == '\\'),
+            names: parse(pattern),
+        }
+    }
+
+    fn matches(&self, path: &[String]) -> bool {
+        if self.anchored {
+            (1..path.len() + 1).any(|len| match_names(&self.names[..], &path[..len]))
+        } else {
+            path.iter().any(|name| match_names(&self.names[..], &[name.clone()]))
+        }
+    }
+
+    /// Whether something below the directory at `path` could match.
+    fn may_match_below(&self, path: &[String]) -> bool {
+        if !self.anchored {
+            return true;
+        }
+        let mut pattern = &self.names[..];
+        for name in path {
+            match pattern.first() {
+                // An ancestor matched already.
+                None => return true,
+                Some(first) if first == "**" => return true,
+                Some(first) if !glob(first.as_bytes(), name.as_bytes()) => return false,
+                Some(_) => pattern = &pattern[1..],
+            }
+        }
+        true
+    }
+}
+
+fn match_names(pattern: &[String], names: &[String]) -> bool {
+    match pattern.first() {
+        None => names.is_empty(),
+        Some(first) if first == "**" => {
+            (0..names.len() + 1).any(|skip| match_names(&pattern[1..], &names[skip..]))
+        }
+        Some(first) => {
+            !names.is_empty() && glob(first.as_bytes(), names[0].as_bytes()) &&
+                match_names(&pattern[1..], &names[1..])
+        }
+    }
+}
+
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&b'*') => (0..name.len() + 1).any(|skip| glob(&pattern[1..], &name[skip..])),
+        Some(&b'?') => !name.is_empty() && glob(&pattern[1..], &name[1..]),
+        Some(c) => name.first() == Some(c) && glob(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Selects the paths of a snapshot to restore: those matching any of the included patterns, or
+/// all paths if there are none, except those matching an excluded pattern. Paths are matched as
+/// they are stored in the snapshot.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn include(mut self, pattern: &str) -> PathFilter {
+        self.includes.push(Pattern::new(pattern));
+        self
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> PathFilter {
+        self.excludes.push(Pattern::new(pattern));
+        self
+    }
+
+    /// Whether the file at `path` is restored.
+    pub fn selects(&self, path: &[String]) -> bool {
+        !self.excludes.iter().any(|p| p.matches(path)) &&
+            (self.includes.is_empty() || self.includes.iter().any(|p| p.matches(path)))
+    }
+
+    /// Whether the directory at `path` needs to be looked into. Directories that are not
+    /// selected themselves are only created when something inside them is restored.
+    pub fn descends_into(&self, path: &[String]) -> bool {
+        !self.excludes.iter().any(|p| p.matches(path)) &&
+            (self.includes.is_empty() || self.includes.iter().any(|p| p.may_match_below(path)))
+    }
+}
+
+/// How a snapshot is restored.
+pub struct RestoreOptions {
+    pub policy: Box<PathPolicy>,
+    pub filter: PathFilter,
+    /// Restore one chunk at a time, holding no more than this many bytes of blobs and chunks
+    /// in memory. Needs room for at least two blobs of the repository's maximum size.
+    pub memory_budget: Option<MemoryBudget>,
+    /// Give restored entries the owner and group they were stored with, by the numeric ids in
+    /// the snapshot; no names are looked up. Needs the privilege to change owners. Without this,
+    /// entries are owned by whoever restores them.
+    pub numeric_ids: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> RestoreOptions {
+        RestoreOptions {
+            policy: Box::new(PosixPolicy),
+            filter: PathFilter::default(),
+            memory_budget: None,
+            numeric_ids: false,
+        }
+    }
+}
+
+impl RestoreOptions {
+    /// The name to restore the entry at `path` under, the last name of which is `name`, or
+    /// `None` if it is not restored. `restored` maps the collision keys already used in the
+    /// directory to the paths using them; entries that cannot be restored are added to
+    /// `conflicts`.
+    pub fn target_name(
+        &self,
+        path: &[String],
+        name: &[u8],
+        is_dir: bool,
+        restored: &mut HashMap<String, String>,
+        conflicts: &mut Vec<RestoreConflict>,
+    ) -> Option<String> {
+        let wanted = if is_dir {
+            self.filter.descends_into(path)
+        } else {
+            self.filter.selects(path)
+        };
+        if !wanted {
+            return None;
+        }
+        let local_name = match self.policy.local_name(name) {
+            Ok(name) => name,
+            Err(reason) => {
+                conflicts.push(RestoreConflict::InvalidName {
+                    path: format(path),
+                    reason: reason,
+                });
+                return None;
+            }
+        };
+        // Names that end up in the same place on the target must not overwrite each other.
+        let key = self.policy.collision_key(&local_name);
+        if let Some(existing) = restored.get(&key) {
+            conflicts.push(RestoreConflict::Collision {
+                path: format(path),
+                existing: existing.clone(),
+            });
+            return None;
+        }
+        restored.insert(key, format(path));
+        Some(local_name)
+    }
+}
+
+/// An entry that was not restored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreConflict {
+    /// The name of the entry at `path` cannot be restored under this policy.
+    InvalidName { path: String, reason: String },
+    /// The entry at `path` would end up in the same place as the one at `existing`.
+    Collision { path: String, existing: String },
+}
+
+impl fmt::Display for RestoreConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RestoreConflict::InvalidName { ref path, ref reason } => {
+                write!(f, "{}: {}", path, reason)
+            }
+            RestoreConflict::Collision { ref path, ref existing } => {
+                write!(f, "{}: would overwrite {}", path, existing)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mixed_separators() {
+        assert_eq!(parse("/home/user\\docs//./a.txt"), vec!["home", "user", "docs", "a.txt"]);
+        assert_eq!(parse("C:\\Users\\me"), vec!["C:", "Users", "me"]);
+        assert!(parse("/").is_empty());
+    }
+
+    #[test]
+    fn format_round_trip() {
+        for path in &["/", "/a", "/home/user/docs/a.txt"] {
+            assert_eq!(&format(&parse(path)), path);
+        }
+        assert_eq!(format(&parse("home\\user/a.txt")), "/home/user/a.txt");
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        parse(path)
+    }
+
+    #[test]
+    fn glob_names() {
+        assert!(glob(b"*.conf", b"a.conf"));
+        assert!(glob(b"*.conf", b".conf"));
+        assert!(!glob(b"*.conf", b"a.conf.bak"));
+        assert!(glob(b"a?c*", b"abcdef"));
+        assert!(!glob(b"a?c", b"ac"));
+    }
+
+    #[test]
+    fn filter_by_prefix() {
+        let filter = PathFilter::default().include("/etc");
+        assert!(filter.selects(&path("/etc/passwd")));
+        assert!(filter.selects(&path("/etc/ssh/sshd_config")));
+        assert!(!filter.selects(&path("/etcetera/a")));
+        assert!(!filter.selects(&path("/home/etc")));
+        assert!(filter.descends_into(&path("/etc/ssh")));
+        assert!(!filter.descends_into(&path("/home")));
+    }
+
+    #[test]
+    fn filter_by_glob() {
+        let filter = PathFilter::default().include("*.conf").exclude("/etc/old");
+        assert!(filter.selects(&path("/etc/a.conf")));
+        assert!(filter.selects(&path("/home/user/b.conf")));
+        assert!(!filter.selects(&path("/etc/passwd")));
+        assert!(!filter.selects(&path("/etc/old/c.conf")));
+        assert!(filter.descends_into(&path("/home")));
+        assert!(!filter.descends_into(&path("/etc/old")));
+
+        let deep = PathFilter::default().include("/home/**/*.txt");
+        assert!(deep.selects(&path("/home/a.txt")));
+        assert!(deep.selects(&path("/home/user/docs/a.txt")));
+        assert!(!deep.selects(&path("/etc/a.txt")));
+        assert!(deep.descends_into(&path("/home/user")));
+        assert!(!deep.descends_into(&path("/etc")));
+    }
+
+    #[test]
+    fn empty_filter_selects_everything() {
+        let filter = PathFilter::default();
+        assert!(filter.selects(&path("/a/b")));
+        assert!(filter.descends_into(&path("/a")));
+    }
+
+    #[test]
+    fn posix_policy() {
+        assert_eq!(PosixPolicy.local_name(b"a\\b").unwrap(), "a\\b");
+        assert!(PosixPolicy.local_name(b"a/b").is_err());
+        assert!(PosixPolicy.local_name(b"..").is_err());
+        assert!(PosixPolicy.collision_key("README") != PosixPolicy.collision_key("Readme"));
+    }
+
+    #[test]
+    fn windows_policy() {
+        assert_eq!(WindowsPolicy.local_name(b"a:b?.txt").unwrap(), "a_b_.txt");
+        assert_eq!(WindowsPolicy.local_name(b"trailing. ").unwrap(), "trailing__");
+        assert!(WindowsPolicy.local_name(b".").is_err());
+        assert_eq!(WindowsPolicy.collision_key("README"), WindowsPolicy.collision_key("Readme"));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fetching the blobs of files into the blob cache before they are read.
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use hash::tree::HashTreeBackend;
+use key;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::thread;
+
+
+/// Collects the blobs holding the leafs of a hash tree, without reading the leafs.
+struct BlobVisitor<'a, B: 'a> {
+    backend: &'a key::HashStoreBackend<B>,
+    names: BTreeSet<Vec<u8>>,
+}
+
+impl<'a, B: StoreBackend> hash::tree::Visitor for BlobVisitor<'a, B> {
+    fn leaf_enter(&mut self, href: &hash::tree::HashRef) -> bool {
+        // The chunk may have been moved to another blob after `href` was written.
+        let chunk_ref = self.backend.fetch_persistent_ref(&href.hash).unwrap_or_else(|| {
+            href.persistent_ref.clone()
+        });
+        if chunk_ref.length > 0 {
+            self.names.insert(chunk_ref.blob_name);
+        }
+        false
+    }
+}
+
+/// Blobs being fetched in the background by `Hat::prefetch`.
+pub struct Prefetch {
+    handle: thread::JoinHandle<Result<usize, String>>,
+}
+
+impl Prefetch {
+    /// Start fetching the blobs of the files `file_refs` into the blob cache of `blob_store`.
+    pub fn start<B: StoreBackend>(
+        backend: key::HashStoreBackend<B>,
+        blob_store: Arc<blob::BlobStore<B>>,
+        file_refs: Vec<hash::tree::HashRef>,
+    ) -> Prefetch {
+        let handle = thread::spawn(move || {
+            let mut visitor = BlobVisitor {
+                backend: &backend,
+                names: BTreeSet::new(),
+            };
+            for file_ref in file_refs {
+                let walker = hash::tree::Walker::new(backend.clone(), file_ref);
+                if let Some(mut walker) = walker.map_err(|e| e.to_string())? {
+                    while walker.resume(&mut visitor).map_err(|e| e.to_string())? {}
+                }
+            }
+            let mut kept = 0;
+            for name in visitor.names {
+                if blob_store.prefetch(&name[..]).map_err(|e| e.to_string())? {
+                    kept += 1;
+                }
+            }
+            Ok(kept)
+        });
+        Prefetch { handle: handle }
+    }
+
+    /// Wait for the fetching to finish. Returns how many blobs are kept in the cache; blobs that
+    /// did not fit are not.
+    pub fn wait(self) -> Result<usize, HatError> {
+        match self.handle.join() {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(From::from("Prefetching panicked")),
+        }
+    }
+}
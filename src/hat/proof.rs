@@ -0,0 +1,222 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity proofs: signed statements of which snapshots a store holds.
+//!
+//! A proof lists the snapshots with their root hashes and chunk counts, and a Merkle root over
+//! the hashes of all chunks they consist of. It is signed with the store's manifest key and can
+//! be checked with only the public half of that key, so it can be handed to an auditor without
+//! revealing any contents or any key that can decrypt them.
+//!
+//! Proofs are plain text, one field per line, starting with the format name and version:
+//!
+//! ```text
+//! hat-integrity-proof 1
+//! created-utc <seconds since 1970>
+//! snapshot <id> <chunk count> <root hash> <family name>
+//! merkle-root <hash>
+//! public-key <key>
+//! signature <signature of all lines above>
+//! ```
+//!
+//! All hashes, keys and signatures are hex encoded.
+
+use crypto;
+use errors::HatError;
+use hex::{FromHex, ToHex};
+use std::collections::BTreeSet;
+
+
+pub const PROOF_FORMAT: &'static str = "hat-integrity-proof";
+pub const PROOF_VERSION: u64 = 1;
+
+const MERKLE_HASH_BYTES: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotProof {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    pub root: Vec<u8>,
+    pub chunk_count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    pub version: u64,
+    pub created_utc: i64,
+    pub snapshots: Vec<SnapshotProof>,
+    pub merkle_root: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Merkle root over a set of chunk hashes, taken in sorted order.
+///
+/// Leaves and branches are hashed with distinct prefixes, and an odd node at the end of a level
+/// is carried up unchanged.
+pub fn merkle_root(chunk_hashes: &BTreeSet<Vec<u8>>) -> Vec<u8> {
+    let digest = |prefix: u8, parts: &[&[u8]]| {
+        let mut msg = vec![prefix];
+        for p in parts {
+            msg.extend_from_slice(p);
+        }
+        let mut out = vec![0; MERKLE_HASH_BYTES];
+        crypto::keys::digest(&msg[..], &mut out[..]);
+        out
+    };
+
+    let mut level: Vec<Vec<u8>> = chunk_hashes.iter().map(|h| digest(0, &[&h[..]])).collect();
+    if level.is_empty() {
+        return digest(2, &[]);
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| if pair.len() == 2 {
+                digest(1, &[&pair[0][..], &pair[1][..]])
+            } else {
+                pair[0].clone()
+            })
+            .collect();
+    }
+    level.pop().unwrap()
+}
+
+fn parse_hex(field: &str, value: &str) -> Result<Vec<u8>, HatError> {
+    Vec::from_hex(value).map_err(|_| {
+        From::from(format!("Invalid proof: {} is not hex", field))
+    })
+}
+
+fn parse_number<T: ::std::str::FromStr>(field: &str, value: &str) -> Result<T, HatError> {
+    value.parse::<T>().map_err(|_| {
+        From::from(format!("Invalid proof: {} is not a number", field))
+    })
+}
+
+impl Proof {
+    /// Create and sign a proof for the given snapshots.
+    pub fn sign(
+        keys: &crypto::keys::Keeper,
+        created_utc: i64,
+        snapshots: Vec<SnapshotProof>,
+        merkle_root: Vec<u8>,
+    ) -> Result<Proof, HatError> {
+        for s in snapshots.iter() {
+            if s.family_name.contains('\n') {
+                return Err(From::from(format!(
+                    "Cannot write proof for family with newline in its name: {:?}",
+                    s.family_name
+                )));
+            }
+        }
+        let mut proof = Proof {
+            version: PROOF_VERSION,
+            created_utc: created_utc,
+            snapshots: snapshots,
+            merkle_root: merkle_root,
+            public_key: keys.manifest_public_key(),
+            signature: vec![],
+        };
+        proof.signature = keys.manifest_sign(proof.signed_text().as_bytes());
+        Ok(proof)
+    }
+
+    /// Check that this proof is signed by the holder of `public_key`.
+    pub fn verify(&self, public_key: &[u8]) -> bool {
+        self.public_key == public_key &&
+            crypto::keys::manifest_verify(
+                public_key,
+                self.signed_text().as_bytes(),
+                &self.signature[..],
+            )
+    }
+
+    fn signed_text(&self) -> String {
+        let mut out = format!("{} {}\n", PROOF_FORMAT, self.version);
+        out.push_str(&format!("created-utc {}\n", self.created_utc));
+        for s in self.snapshots.iter() {
+            out.push_str(&format!(
+                "snapshot {} {} {} {}\n",
+                s.snapshot_id,
+                s.chunk_count,
+                s.root.to_hex(),
+                s.family_name
+            ));
+        }
+        out.push_str(&format!("merkle-root {}\n", self.merkle_root.to_hex()));
+        out.push_str(&format!("public-key {}\n", self.public_key.to_hex()));
+        out
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{}signature {}\n", self.signed_text(), self.signature.to_hex())
+    }
+
+    /// Parse a proof. This does not check its signature; see `verify()`.
+    pub fn from_text(text: &str) -> Result<Proof, HatError> {
+        let mut lines = text.lines();
+
+        let version = match lines.next().map(|l| l.splitn(2, ' ').collect::<Vec<_>>()) {
+            Some(ref header) if header.len() == 2 && header[0] == PROOF_FORMAT => {
+                parse_number::<u64>("version", header[1])?
+            }
+            _ => return Err(From::from("Not an integrity proof")),
+        };
+        if version > PROOF_VERSION {
+            return Err(From::from(format!(
+                "Integrity proof has version {}, but only up to version {} is supported",
+                version,
+                PROOF_VERSION
+            )));
+        }
+
+        let mut proof = Proof {
+            version: version,
+            created_utc: 0,
+            snapshots: vec![],
+            merkle_root: vec![],
+            public_key: vec![],
+            signature: vec![],
+        };
+        for line in lines {
+            let mut parts = line.splitn(2, ' ');
+            let (field, value) = match (parts.next(), parts.next()) {
+                (Some(field), Some(value)) => (field, value),
+                _ => return Err(From::from(format!("Invalid proof line: {:?}", line))),
+            };
+            match field {
+                "created-utc" => proof.created_utc = parse_number("created-utc", value)?,
+                "snapshot" => {
+                    let values: Vec<&str> = value.splitn(4, ' ').collect();
+                    if values.len() != 4 {
+                        return Err(From::from(format!("Invalid snapshot line: {:?}", line)));
+                    }
+                    proof.snapshots.push(SnapshotProof {
+                        snapshot_id: parse_number("snapshot id", values[0])?,
+                        chunk_count: parse_number("chunk count", values[1])?,
+                        root: parse_hex("root", values[2])?,
+                        family_name: values[3].to_owned(),
+                    });
+                }
+                "merkle-root" => proof.merkle_root = parse_hex("merkle-root", value)?,
+                "public-key" => proof.public_key = parse_hex("public-key", value)?,
+                "signature" => proof.signature = parse_hex("signature", value)?,
+                _ => return Err(From::from(format!("Unknown proof field: {:?}", field))),
+            }
+        }
+
+        Ok(proof)
+    }
+}
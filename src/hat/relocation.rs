@@ -0,0 +1,79 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where chunks went when their blobs were rewritten, kept in the backend.
+//!
+//! Tree nodes and snapshot lists refer to chunks in the blobs they were first stored in.
+//! Rewriting a blob only points the index at the new copies, so a store recovered from the
+//! backend alone would look for moved chunks in blobs that are gone. The new references of the
+//! moved chunks are therefore stored as a relocation before any old blob is deleted, and
+//! `recover` follows them. Relocations are sealed like the store settings.
+
+use backend::StoreBackend;
+use crypto;
+use errors::HatError;
+use hash;
+use hash::tree::HashRef;
+use hat::store_info;
+use rand;
+use std::collections::HashMap;
+
+
+/// Prefix of the names of relocations in the backend. Blob names are sealed boxes, so they are
+/// never this short.
+pub const RELOCATION_PREFIX: &'static [u8] = b"hat-relocation-";
+
+pub fn is_relocation_name(name: &[u8]) -> bool {
+    name.starts_with(RELOCATION_PREFIX)
+}
+
+/// Record in `backend` that chunks were moved to `moved`, and wait for it to be durable.
+pub fn store<B: StoreBackend>(
+    backend: &B,
+    keys: &crypto::keys::Keeper,
+    moved: &Vec<HashRef>,
+) -> Result<(), HatError> {
+    let mut name = RELOCATION_PREFIX.to_vec();
+    name.extend_from_slice(format!("{:016x}", rand::random::<u64>()).as_bytes());
+    let sealed = store_info::seal(keys, &hash::tree::hash_refs_to_bytes(moved)[..]);
+    backend.store(&name[..], &sealed)?;
+    backend.flush()?;
+    Ok(())
+}
+
+/// Every chunk reference recorded in the relocations in `backend`, by the hash of the chunk.
+/// A chunk that was moved more than once has a reference for every move; only those into blobs
+/// that still exist are current.
+pub fn read_all<B: StoreBackend>(
+    backend: &B,
+    keys: &crypto::keys::Keeper,
+) -> Result<HashMap<hash::Hash, Vec<HashRef>>, HatError> {
+    let mut refs: HashMap<hash::Hash, Vec<HashRef>> = HashMap::new();
+    for name in backend.list()? {
+        if !is_relocation_name(&name) {
+            continue;
+        }
+        let sealed = match backend.retrieve(&name)? {
+            Some(sealed) => sealed,
+            None => continue,
+        };
+        let bytes = store_info::open(keys, &sealed[..])?;
+        let moved = hash::tree::hash_refs_from_bytes(&bytes[..])
+            .ok_or("Could not read relocation")?;
+        for href in moved {
+            refs.entry(href.hash.clone()).or_insert_with(Vec::new).push(href);
+        }
+    }
+    Ok(refs)
+}
@@ -0,0 +1,144 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What a restore would write, worked out without restoring anything.
+//!
+//! Entries are selected and named exactly as `Hat::checkout_in_dir_with_options` does. Only the
+//! directory listings are read, so no file contents are fetched, and the target is only looked
+//! at to tell which entries are already there.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::paths::{RestoreConflict, RestoreOptions};
+use hat::walker;
+use key;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedEntry {
+    /// Where the entry would be restored.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// Size of a file as it was snapshotted; 0 for directories and symbolic links.
+    pub size: u64,
+    /// Something is already at `path`, and would be overwritten.
+    pub exists: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RestorePlan {
+    /// Every entry that would be restored, each directory before what is in it.
+    pub entries: Vec<PlannedEntry>,
+    /// Entries that would not be restored.
+    pub conflicts: Vec<RestoreConflict>,
+}
+
+impl RestorePlan {
+    /// Bytes of file contents that would be written.
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+}
+
+pub struct Planner<'a, B: StoreBackend> {
+    family: &'a Family<B>,
+    backend: key::HashStoreBackend<B>,
+    options: &'a RestoreOptions,
+    plan: RestorePlan,
+}
+
+impl<'a, B: StoreBackend> Planner<'a, B> {
+    pub fn new(
+        family: &'a Family<B>,
+        backend: key::HashStoreBackend<B>,
+        options: &'a RestoreOptions,
+    ) -> Planner<'a, B> {
+        Planner {
+            family: family,
+            backend: backend,
+            options: options,
+            plan: RestorePlan::default(),
+        }
+    }
+
+    /// Plan the restore of the directory `dir_ref`, found at `snapshot_path`, into `output`.
+    /// Returns whether the directory itself would be created.
+    pub fn plan_dir(
+        &mut self,
+        output: &mut PathBuf,
+        snapshot_path: &mut Vec<String>,
+        dir_ref: hash::tree::HashRef,
+    ) -> Result<bool, HatError> {
+        // Directories that are only passed through are created once something inside them is.
+        let mut created = self.options.filter.selects(&snapshot_path[..]);
+        let mut restored = HashMap::new();
+        for (entry, content) in self.family.fetch_dir_data(dir_ref, self.backend.clone())? {
+            snapshot_path.push(String::from_utf8_lossy(&entry.info.name[..]).into_owned());
+            let is_dir = match content {
+                walker::Content::Dir(_) => true,
+                _ => false,
+            };
+            let local_name = match self.options.target_name(
+                &snapshot_path[..],
+                &entry.info.name[..],
+                is_dir,
+                &mut restored,
+                &mut self.plan.conflicts,
+            ) {
+                Some(name) => name,
+                None => {
+                    snapshot_path.pop();
+                    continue;
+                }
+            };
+
+            output.push(&local_name);
+            let index = self.plan.entries.len();
+            self.plan.entries.push(PlannedEntry {
+                path: output.clone(),
+                is_dir: is_dir,
+                size: match content {
+                    walker::Content::Data(_) => entry.info.byte_length.unwrap_or(0),
+                    _ => 0,
+                },
+                exists: fs::symlink_metadata(&output).is_ok(),
+            });
+            match content {
+                walker::Content::Dir(dir_ref) => {
+                    if self.plan_dir(output, snapshot_path, dir_ref)? {
+                        created = true;
+                    } else {
+                        // Nothing in it would be restored.
+                        self.plan.entries.truncate(index);
+                    }
+                }
+                walker::Content::Data(_) |
+                walker::Content::Link(_) => created = true,
+            }
+
+            output.pop();
+            snapshot_path.pop();
+        }
+        Ok(created)
+    }
+
+    pub fn into_plan(self) -> RestorePlan {
+        self.plan
+    }
+}
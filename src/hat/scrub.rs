@@ -0,0 +1,80 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verifying a store a slice at a time, instead of all of it at once.
+//!
+//! Each run reads back the chunks that have gone longest without being checked, chunks never
+//! checked first, and records when it checked them in the index. A run checks enough chunks
+//! that running it `period_runs` times checks every chunk in the store, as long as the store
+//! does not grow meanwhile; chunks added since are checked first in the next run.
+
+use hash;
+use hat::verify::FailedChunk;
+use std::collections::HashMap;
+
+
+/// Settings for one scrub run.
+#[derive(Clone, Debug)]
+pub struct ScrubOptions {
+    /// Runs that together check every chunk, e.g. 30 for a month of daily runs.
+    pub period_runs: u64,
+    /// Chunks to read back at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> ScrubOptions {
+        ScrubOptions {
+            period_runs: 30,
+            concurrency: 1,
+        }
+    }
+}
+
+/// What a scrub run did.
+#[derive(Clone, Debug, Default)]
+pub struct ScrubReport {
+    /// Chunks checked by this run.
+    pub verified: u64,
+    /// Chunks checked by this run that matched their hash.
+    pub passed: u64,
+    /// Chunks checked by this run that did not.
+    pub failed: u64,
+    /// Hashes of the chunks that failed, with the reason.
+    pub failures: Vec<(String, String)>,
+    /// Where the failed chunks are used in complete snapshots.
+    pub locations: Vec<FailedChunk>,
+    /// Chunks in the store.
+    pub total: u64,
+    /// Chunks in the store that have never been checked, after this run.
+    pub never_verified: u64,
+    /// When the chunk that has gone longest without a check was last checked, after this run;
+    /// `None` while some chunks have never been checked.
+    pub oldest_verified_utc: Option<i64>,
+}
+
+/// Pick the chunks for one run out of `chunks`, given as (hash id, reference): the ones whose
+/// last check in `verified` is oldest, and never checked ones before those.
+pub fn pick(
+    mut chunks: Vec<(u64, hash::tree::HashRef)>,
+    verified: &HashMap<u64, i64>,
+    period_runs: u64,
+) -> Vec<(u64, hash::tree::HashRef)> {
+    let period_runs = period_runs.max(1);
+    let per_run = (chunks.len() as u64 + period_runs - 1) / period_runs;
+    // `None` sorts before any time, so chunks never checked come first.
+    chunks.sort_by_key(|&(id, _)| (verified.get(&id).cloned(), id));
+    chunks.truncate(per_run as usize);
+    chunks
+}
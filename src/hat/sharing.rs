@@ -0,0 +1,123 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Attribution of stored bytes to the snapshots that reference them.
+//!
+//! Each snapshot is given as the ids of its root and of every file and directory its listings
+//! name, the same references GC counts. The trees below them are followed through the child ids
+//! recorded in the hash index.
+
+use hash;
+use std::collections::{HashMap, HashSet};
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotSharing {
+    pub family_name: String,
+    pub snapshot_id: u64,
+
+    /// Stored bytes of every chunk reachable from this snapshot.
+    pub referenced_bytes: u64,
+    /// Stored bytes of chunks no other snapshot references; freed if this snapshot is deleted.
+    pub exclusive_bytes: u64,
+    /// Stored bytes of chunks also referenced by at least one other snapshot.
+    pub shared_bytes: u64,
+}
+
+/// Every node reachable from `tops`, the hash ids a snapshot references directly.
+pub fn reachable(hash_index: &hash::HashIndex, tops: Vec<u64>) -> HashSet<u64> {
+    let mut seen = HashSet::new();
+    let mut queue = tops;
+    while let Some(id) = queue.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let Some(childs) = hash_index.get_hash(id).and_then(|entry| entry.childs) {
+            queue.extend(childs);
+        }
+    }
+    seen
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    Only(usize),
+    Shared,
+}
+
+pub struct SharingCounter<'a> {
+    hash_index: &'a hash::HashIndex,
+    owners: HashMap<u64, (Owner, u64)>,
+    snapshots: Vec<SnapshotSharing>,
+}
+
+impl<'a> SharingCounter<'a> {
+    pub fn new(hash_index: &'a hash::HashIndex) -> SharingCounter<'a> {
+        SharingCounter {
+            hash_index: hash_index,
+            owners: HashMap::new(),
+            snapshots: vec![],
+        }
+    }
+
+    /// Record every node reachable from `tops`, the hash ids a snapshot references directly.
+    /// Each node is visited once per snapshot that reaches it.
+    pub fn add(&mut self, family_name: String, snapshot_id: u64, tops: Vec<u64>) {
+        let index = self.snapshots.len();
+        let mut referenced = 0;
+        let mut seen = HashSet::new();
+        let mut queue = tops;
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let entry = match self.hash_index.get_hash(id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let length = entry.persistent_ref.map_or(0, |pref| pref.length as u64);
+            referenced += length;
+
+            let owner = self.owners.entry(id).or_insert((Owner::Only(index), length));
+            if owner.0 != Owner::Only(index) {
+                owner.0 = Owner::Shared;
+            }
+            if let Some(childs) = entry.childs {
+                queue.extend(childs);
+            }
+        }
+
+        self.snapshots.push(SnapshotSharing {
+            family_name: family_name,
+            snapshot_id: snapshot_id,
+            referenced_bytes: referenced,
+            exclusive_bytes: 0,
+            shared_bytes: 0,
+        });
+    }
+
+    /// One entry per added snapshot, in the order they were added.
+    pub fn into_snapshots(self) -> Vec<SnapshotSharing> {
+        let mut snapshots = self.snapshots;
+        for &(owner, length) in self.owners.values() {
+            if let Owner::Only(index) = owner {
+                snapshots[index].exclusive_bytes += length;
+            }
+        }
+        for s in snapshots.iter_mut() {
+            s.shared_bytes = s.referenced_bytes - s.exclusive_bytes;
+        }
+        snapshots
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Frozen views of a source directory, to take a snapshot from.
+//!
+//! A directory that changes while it is read ends up in the snapshot as a mix of its old and new
+//! states. Where the filesystem can freeze it, the snapshot is read from a frozen view instead,
+//! which is removed again once the snapshot is done with it, whether or not that succeeded.
+
+use rand;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+
+/// A way to freeze a directory, e.g. by a filesystem or volume manager snapshot.
+pub trait SourceSnapshot: Send + Sync {
+    /// Freeze `source` and return where the frozen view of it can be read. Gives `None` if
+    /// `source` cannot be frozen this way, e.g. because it is on another kind of filesystem.
+    fn create(&self, source: &Path) -> Result<Option<PathBuf>, String>;
+
+    /// Remove a view made by `create`.
+    fn destroy(&self, view: &Path) -> Result<(), String>;
+}
+
+/// Read-only snapshots of btrfs subvolumes, kept next to the subvolume while in use. Only the
+/// root of a subvolume can be frozen.
+pub struct BtrfsSnapshot;
+
+impl SourceSnapshot for BtrfsSnapshot {
+    fn create(&self, source: &Path) -> Result<Option<PathBuf>, String> {
+        let name = match source.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Ok(None),
+        };
+        let is_subvolume = Command::new("btrfs")
+            .args(&["subvolume", "show"])
+            .arg(source)
+            .output()
+            .map(|out| out.status.success())
+            .unwrap_or(false);
+        if !is_subvolume {
+            return Ok(None);
+        }
+
+        let view = source.with_file_name(format!(".{}.hat-{:x}", name, rand::random::<u32>()));
+        run(
+            Command::new("btrfs")
+                .args(&["subvolume", "snapshot", "-r"])
+                .arg(source)
+                .arg(&view),
+        )?;
+        Ok(Some(view))
+    }
+
+    fn destroy(&self, view: &Path) -> Result<(), String> {
+        run(Command::new("btrfs").args(&["subvolume", "delete"]).arg(view))
+    }
+}
+
+fn run(cmd: &mut Command) -> Result<(), String> {
+    let out = cmd.output().map_err(
+        |e| format!("Could not run {:?}: {}", cmd, e),
+    )?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr).trim()))
+    }
+}
+
+/// A frozen view of a source directory. It is removed when dropped; `destroy` does the same,
+/// but reports failure.
+pub struct FrozenSource {
+    snapshot: Arc<SourceSnapshot>,
+    view: Option<PathBuf>,
+}
+
+impl FrozenSource {
+    /// Freeze `source` with `snapshot`. Gives `None`, after a warning, if it cannot be frozen
+    /// and has to be read as it is.
+    pub fn freeze(
+        snapshot: Arc<SourceSnapshot>,
+        source: &Path,
+    ) -> Result<Option<FrozenSource>, String> {
+        match snapshot.create(source)? {
+            Some(view) => {
+                let frozen = FrozenSource {
+                    snapshot: snapshot,
+                    view: Some(view),
+                };
+                if !frozen.path().is_dir() {
+                    return Err(format!(
+                        "Frozen view of {} is not a directory: {}",
+                        source.display(),
+                        frozen.path().display()
+                    ));
+                }
+                Ok(Some(frozen))
+            }
+            None => {
+                warn!(
+                    "Cannot freeze {}; reading it while it may change",
+                    source.display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.view.as_ref().expect("Frozen source was destroyed")
+    }
+
+    pub fn destroy(mut self) -> Result<(), String> {
+        match self.view.take() {
+            Some(view) => self.snapshot.destroy(&view),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FrozenSource {
+    fn drop(&mut self) {
+        if let Some(view) = self.view.take() {
+            if let Err(e) = self.snapshot.destroy(&view) {
+                warn!("Could not remove frozen view {}: {}", view.display(), e);
+            }
+        }
+    }
+}
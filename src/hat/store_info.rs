@@ -0,0 +1,133 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Settings of the whole store, kept in the backend next to the blobs.
+//!
+//! The index has a copy of them, but a store recovered from the backend alone has to find them
+//! there. They are sealed with the data key and authenticated like a blob, so that they can
+//! neither be read nor changed without the keys.
+//!
+//! Backends do not replace what they store, so every change is stored under a new name: the
+//! prefix followed by a generation number. The highest generation is the current one.
+
+use backend::StoreBackend;
+use capnp;
+use crypto::{self, CipherText, CipherTextRef, FixedKey, PlainTextRef};
+use errors::HatError;
+use root_capnp;
+
+
+/// Prefix of the names of the settings in the backend. Blob names are sealed boxes, so they are
+/// never this short.
+pub const STORE_INFO_PREFIX: &'static [u8] = b"hat-store-info-";
+
+/// The generation of the settings stored under `name`, if that is what the name is.
+pub fn store_info_generation(name: &[u8]) -> Option<u64> {
+    if !name.starts_with(STORE_INFO_PREFIX) {
+        return None;
+    }
+    ::std::str::from_utf8(&name[STORE_INFO_PREFIX.len()..]).ok().and_then(|g| g.parse().ok())
+}
+
+fn store_info_name(generation: u64) -> Vec<u8> {
+    let mut name = STORE_INFO_PREFIX.to_vec();
+    name.extend_from_slice(generation.to_string().as_bytes());
+    name
+}
+
+fn latest_generation<B: StoreBackend>(backend: &B) -> Result<Option<u64>, HatError> {
+    Ok(backend.list()?.iter().filter_map(|name| store_info_generation(name)).max())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreInfo {
+    /// Oldest store format version that can read the store.
+    pub min_reader_version: i64,
+    /// Secret salt of the content hashes, see `Keeper::with_hash_salt`.
+    pub hash_salt: Option<Vec<u8>>,
+}
+
+impl StoreInfo {
+    /// The settings kept in `backend`, if they have been stored yet.
+    pub fn read<B: StoreBackend>(
+        backend: &B,
+        keys: &crypto::keys::Keeper,
+    ) -> Result<Option<StoreInfo>, HatError> {
+        let generation = match latest_generation(backend)? {
+            Some(generation) => generation,
+            None => return Ok(None),
+        };
+        let sealed = backend.retrieve(&store_info_name(generation)[..])?
+            .ok_or("Store settings disappeared while reading them")?;
+        let bytes = open(keys, &sealed[..])?;
+
+        let reader = ::util::read_packed_message(
+            &mut &bytes[..],
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let info = reader.get_root::<root_capnp::store_info::Reader>()?;
+        let hash_salt = info.get_hash_salt()?;
+        Ok(Some(StoreInfo {
+            min_reader_version: info.get_min_reader_version(),
+            hash_salt: if hash_salt.is_empty() {
+                None
+            } else {
+                Some(hash_salt.to_vec())
+            },
+        }))
+    }
+
+    /// Replace the settings kept in `backend` with these, and wait for them to be durable. The
+    /// previous generation is only deleted after that.
+    pub fn write<B: StoreBackend>(
+        &self,
+        backend: &B,
+        keys: &crypto::keys::Keeper,
+    ) -> Result<(), HatError> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::store_info::Builder>();
+            root.set_min_reader_version(self.min_reader_version);
+            if let Some(ref salt) = self.hash_salt {
+                root.set_hash_salt(&salt[..]);
+            }
+        }
+        let mut bytes = Vec::new();
+        ::util::write_packed_message(&mut bytes, &message)?;
+
+        let ct = seal(keys, &bytes[..]);
+        let previous = latest_generation(backend)?;
+        let generation = previous.map_or(0, |g| g + 1);
+        backend.store(&store_info_name(generation)[..], &ct)?;
+        backend.flush()?;
+        if let Some(previous) = previous {
+            backend.delete(&store_info_name(previous)[..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Seal `bytes` to be kept in the backend, like the contents of a blob, and authenticate them.
+pub fn seal(keys: &crypto::keys::Keeper, bytes: &[u8]) -> CipherText {
+    let mut ct = FixedKey::new(keys).seal_blob_data(PlainTextRef::new(bytes));
+    ct.append_authentication(keys);
+    ct
+}
+
+/// Check and open what `seal` gave.
+pub fn open(keys: &crypto::keys::Keeper, sealed: &[u8]) -> Result<Vec<u8>, HatError> {
+    let authed = CipherTextRef::new(sealed);
+    let ct = authed.strip_authentication(keys)?;
+    Ok(keys.try_data_unlock(&ct.to_vec()[..]).ok_or("Could not open sealed store data")?)
+}
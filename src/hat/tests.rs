@@ -13,14 +13,43 @@
 // limitations under the License.
 
 
-use backend::{MemoryBackend, StoreBackend};
-use errors::HatError;
-use hat::HatRc;
+use backend::{FileBackend, ListPage, MemoryBackend, StorageClass, StoreBackend};
+use blob;
+use crypto;
+use db;
+use errors::{ErrorKind, ErrorReport, HatError};
+use filetime;
+use gc::Gc;
+use hash;
+use hex::ToHex;
+use hat::{BackendError, BackupError, CheckStatus, Chunker, DirUsage, Divergence,
+          ENCRYPTED_NAMES_READER_VERSION, FailedChunk, GcOptions, HatRc, Keyring,
+          MIN_READER_VERSION, PADDED_READER_VERSION, PIPELINE_READER_VERSION, PathFilter, Proof,
+          READER_VERSION, RestoreConflict, RestoreOptions, RollingParams, SALTED_READER_VERSION,
+          SHARDED_READER_VERSION, SPARSE_READER_VERSION, ScrubOptions, SnapshotOptions,
+          SnapshotStats, SourceSnapshot, StoragePolicy, TrustAnchor, WindowsPolicy,
+          check_store_version, to_sha256sum};
+use hat::audit;
+use hat::cat;
+use hat::doctor;
+use hat::store_info::{StoreInfo, store_info_generation};
 use hat::family::Family;
 use key;
-use std::collections::HashMap;
-use std::sync::Arc;
-use util::FileIterator;
+use rand;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tags;
+use util::{CancellationToken, FakeClock, FileIterator, MemoryBudget, ReadAt, SystemClock};
 
 
 pub fn setup_hat<B: StoreBackend>(backend: Arc<B>) -> HatRc<B> {
@@ -68,6 +97,7 @@ fn snapshot_files<B: StoreBackend>(
             // We have a file to insert.
             let mut e = entry(current.bytes().collect());
             e.parent_id = parent.clone();
+            e.info.byte_length = Some(contents.len() as u64);
             family.snapshot_direct(
                 e,
                 false,
@@ -182,6 +212,107 @@ fn snapshot_commit_many_empty_directories() {
     assert_eq!(live, 0);
 }
 
+#[test]
+fn gc_cross_check_aborts_on_mark_bug() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    // Never committed, so unused.
+    snapshot_files(&fam, vec![("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.data_flush().unwrap();
+
+    let leaf = |contents: &[u8]| {
+        hash::Hash::new(&hat.keys, blob::NodeType::Leaf, blob::LeafType::FileChunk, contents)
+    };
+    let (used, unused) = (leaf(&[1; 1000]), leaf(&[2; 1000]));
+    let used_id = hat.hash_index.get_id(&used).unwrap();
+    let unused_id = hat.hash_index.get_id(&unused).unwrap();
+    let hashes = hat.hash_index.list().len();
+
+    // The mark phase forgets about a hash that is in use.
+    let mut options = GcOptions::default().with_mark_hook(move |ids| { ids.insert(used_id); });
+    options.verify_reachability = true;
+    assert!(hat.gc_with_options(&options).is_err());
+    assert_eq!(hat.hash_index.list().len(), hashes);
+
+    // The first of two mark runs misses an unused hash.
+    let runs = Cell::new(0);
+    let mut options = GcOptions::default().with_mark_hook(move |ids| {
+        runs.set(runs.get() + 1);
+        if runs.get() == 1 {
+            ids.remove(&unused_id);
+        }
+    });
+    options.paranoid = true;
+    assert!(hat.gc_with_options(&options).is_err());
+    assert_eq!(hat.hash_index.list().len(), hashes);
+
+    // Without the bug, only the unused hashes go.
+    let mut options = GcOptions::default();
+    options.paranoid = true;
+    let (deleted, _) = hat.gc_with_options(&options).unwrap();
+    assert!(deleted > 0);
+    assert!(hat.hash_index.hash_exists(&used));
+    assert!(!hat.hash_index.hash_exists(&unused));
+}
+
+#[test]
+fn parallel_gc_matches_serial_gc() {
+    let build = || {
+        let (backend, mut hat, mut fam) = setup_family();
+        for i in 0..6u8 {
+            snapshot_files(&fam, vec![("shared", vec![9; 5000]), ("own", vec![i; 20000])])
+                .unwrap();
+            fam.flush().unwrap();
+            hat.commit(&mut fam, None).unwrap();
+            hat.data_flush().unwrap();
+        }
+        for id in &[2, 3, 5] {
+            assert!(hat.delete_snapshot("familyname".to_owned(), *id).unwrap());
+        }
+        (backend, hat)
+    };
+    let mut parallel = GcOptions::default();
+    parallel.concurrency = 4;
+
+    // Both ways of marking agree on exactly which hashes are in use, whatever the tags were.
+    let (_, mut hat) = build();
+    let serial_unused = hat.list_unused_ids(&GcOptions::default()).unwrap();
+    let stale = *serial_unused.iter().next().unwrap();
+    hat.hash_index.set_tag(stale, tags::Tag::InProgress);
+    let parallel_unused = hat.list_unused_ids(&parallel).unwrap();
+    assert!(!serial_unused.is_empty());
+    assert_eq!(serial_unused, parallel_unused);
+    let roots = hat.gc.list_roots().unwrap();
+    let used = super::mark::used_ids(&hat.hash_index, roots.clone(), 4);
+    assert_eq!(used, hat.gc.list_used_ids().unwrap());
+
+    // Finding them again leaves the tags alone.
+    hat.hash_index.set_tag(roots[0], tags::Tag::Reserved);
+    assert_eq!(used, hat.gc.list_used_ids().unwrap());
+    assert_eq!(hat.hash_index.get_tag(roots[0]), Some(tags::Tag::Reserved));
+
+    // And the same data is deleted.
+    let (serial_backend, mut serial_hat) = build();
+    let (parallel_backend, mut parallel_hat) = build();
+    let serial_result = serial_hat.gc().unwrap();
+    let parallel_result = parallel_hat.gc_with_options(&parallel).unwrap();
+    assert!(serial_result.0 > 0);
+    assert_eq!(serial_result, parallel_result);
+    assert_eq!(
+        serial_hat.hash_index.list().len(),
+        parallel_hat.hash_index.list().len()
+    );
+    assert_eq!(
+        serial_backend.list().unwrap().len(),
+        parallel_backend.list().unwrap().len()
+    );
+    assert_eq!(parallel_hat.gc_with_options(&parallel).unwrap().0, 0);
+}
+
 #[test]
 fn snapshot_reuse_index() {
     let (_, mut hat, mut fam) = setup_family();
@@ -277,3 +408,3508 @@ fn recover() {
     assert!(deleted > 0);
     assert_eq!(live4, 0);
 }
+
+#[test]
+fn recover_refuses_rolled_back_store() {
+    let anchor_path = env::temp_dir().join(format!("hat-anchor-{}", rand::random::<u64>()));
+    let (backend, mut hat, mut fam) = setup_family();
+    hat.trust_anchor = TrustAnchor::open(anchor_path.clone()).unwrap();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.trust_anchor.seen(), 1);
+    let old_blobs: HashSet<Box<[u8]>> = backend.list().unwrap().into_iter().collect();
+
+    // Every root is numbered one higher than the last.
+    snapshot_files(&fam, vec![("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.trust_anchor.seen(), 2);
+    assert_eq!(TrustAnchor::open(anchor_path.clone()).unwrap().seen(), 2);
+
+    // The newest root is recovered and accepted, even with older ones still around.
+    let mut hat2 = setup_hat(backend.clone());
+    hat2.trust_anchor = TrustAnchor::open(anchor_path.clone()).unwrap();
+    hat2.recover().unwrap();
+    assert_eq!(hat2.trust_anchor.seen(), 2);
+    assert_eq!(hat2.snapshot_index.latest("familyname").unwrap().0.snapshot_id, 2);
+
+    // Put the store back the way it was after the first root.
+    for name in backend.list().unwrap() {
+        if !old_blobs.contains(&name) {
+            backend.delete(&name).unwrap();
+        }
+    }
+
+    // A host that saw the second root refuses the first, but a new host does not know better.
+    let mut hat3 = setup_hat(backend.clone());
+    hat3.trust_anchor = TrustAnchor::open(anchor_path.clone()).unwrap();
+    let err = hat3.recover().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Rollback);
+    match err {
+        HatError::Rollback(e) => assert_eq!((e.seen, e.found), (2, 1)),
+        e => panic!("Unexpected error: {}", e),
+    }
+    setup_hat(backend).recover().unwrap();
+
+    fs::remove_file(anchor_path).unwrap();
+}
+
+fn chunk_stored<B: StoreBackend>(hat: &HatRc<B>, chunk: &[u8]) -> bool {
+    let hash = hash::Hash::new(
+        &hat.keys,
+        blob::NodeType::Leaf,
+        blob::LeafType::FileChunk,
+        chunk,
+    );
+    hat.hash_index.hash_exists(&hash)
+}
+
+#[test]
+fn delete_snapshot_keeps_shared_data() {
+    let (_, mut hat, mut fam) = setup_family();
+    let shared = vec![5; 1000];
+    let unique1 = vec![6; 1000];
+    let unique2 = vec![7; 1000];
+
+    snapshot_files(
+        &fam,
+        vec![("shared", shared.clone()), ("unique1", unique1.clone())],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // A family keeps the files of its earlier snapshots, so the second one goes in another.
+    let mut other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(
+        &other,
+        vec![("shared", shared.clone()), ("unique2", unique2.clone())],
+    ).unwrap();
+    other.flush().unwrap();
+    hat.commit(&mut other, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Deleting only touches metadata; the data stays until the GC runs.
+    assert!(hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+    assert!(chunk_stored(&hat, &unique1[..]));
+
+    hat.gc().unwrap();
+    assert!(chunk_stored(&hat, &shared[..]));
+    assert!(!chunk_stored(&hat, &unique1[..]));
+    assert!(chunk_stored(&hat, &unique2[..]));
+
+    // Deleting it again does nothing.
+    assert!(!hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+    let (deleted, _) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+
+    // The shared data goes away with the last snapshot using it.
+    assert!(hat.delete_snapshot("other".to_owned(), 1).unwrap());
+    hat.gc().unwrap();
+    assert!(!chunk_stored(&hat, &shared[..]));
+    assert!(!chunk_stored(&hat, &unique2[..]));
+}
+
+/// Runs snapshots, a deletion and GC on a store backed by `index`, and returns what can be
+/// observed: the snapshot list, the GC counts, the number of stored hashes and the restored
+/// files. Tree nodes embed freshly generated chunk keys, so the hashes themselves differ per run.
+fn metadata_store_trace(
+    index: db::Index,
+) -> (Vec<(String, u64, String, bool)>, Vec<(u64, u64)>, usize, Vec<String>) {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing_with_index(backend, 4 * 1024 * 1024, index).unwrap();
+    let mut basic = hat.open_family("basic".to_owned()).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let mut gc_counts = vec![];
+
+    basic_snapshot(&basic);
+    basic.flush().unwrap();
+    hat.commit(&mut basic, None).unwrap();
+
+    snapshot_files(
+        &fam,
+        vec![("shared", vec![5; 1000]), ("unique", vec![6; 1000])],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    gc_counts.push(hat.gc().unwrap());
+
+    assert!(hat.delete_snapshot("basic".to_owned(), 1).unwrap());
+    gc_counts.push(hat.gc().unwrap());
+
+    let snapshots = hat.snapshot_index
+        .list_all()
+        .into_iter()
+        .map(|s| {
+            (
+                s.family_name,
+                s.info.snapshot_id,
+                format!("{:?}", s.status),
+                s.hash.is_some(),
+            )
+        })
+        .collect();
+    let hashes = hat.hash_index.list().len();
+
+    (snapshots, gc_counts, hashes, restored_names(&mut hat))
+}
+
+#[test]
+fn metadata_stores_agree() {
+    let sqlite = metadata_store_trace(db::Index::new_for_testing());
+    let memory = metadata_store_trace(db::Index::with_store(Box::new(db::MemoryStore::new())));
+
+    assert_eq!(sqlite.3, vec!["shared".to_owned(), "unique".to_owned()]);
+    assert_eq!(sqlite, memory);
+}
+
+#[test]
+fn gc_grace_period_keeps_unreferenced_data() {
+    use chrono::{self, TimeZone};
+
+    let (_, mut hat, mut fam) = setup_family();
+    let clock = Arc::new(FakeClock::new(chrono::Utc.timestamp(1500000000, 0)));
+    hat.set_clock(clock.clone());
+    let grace = chrono::Duration::hours(1);
+
+    let unique = vec![6; 1000];
+    snapshot_files(&fam, vec![("unique", unique.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert!(hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+
+    // The first GC only marks the data that is no longer referenced.
+    let (deleted, _) = hat.gc_with_grace(grace).unwrap();
+    assert_eq!(deleted, 0);
+    assert!(chunk_stored(&hat, &unique[..]));
+
+    // It stays within the grace period, counted from the first GC that saw it unused.
+    clock.advance(chrono::Duration::minutes(59));
+    hat.gc_with_grace(grace).unwrap();
+    assert!(chunk_stored(&hat, &unique[..]));
+
+    clock.advance(chrono::Duration::minutes(1));
+    let (deleted, _) = hat.gc_with_grace(grace).unwrap();
+    assert!(deleted > 0);
+    assert!(!chunk_stored(&hat, &unique[..]));
+}
+
+#[test]
+fn gc_grace_period_restarts_when_data_is_used_again() {
+    use chrono::{self, TimeZone};
+
+    let (_, mut hat, mut fam) = setup_family();
+    let clock = Arc::new(FakeClock::new(chrono::Utc.timestamp(1500000000, 0)));
+    hat.set_clock(clock.clone());
+    let grace = chrono::Duration::hours(1);
+
+    let data = vec![6; 1000];
+    snapshot_files(&fam, vec![("data", data.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert!(hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+    hat.gc_with_grace(grace).unwrap();
+
+    // A new snapshot refers to the marked data before the grace period ends.
+    snapshot_files(&fam, vec![("data", data.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    hat.gc_with_grace(grace).unwrap();
+
+    clock.advance(chrono::Duration::hours(2));
+    hat.gc_with_grace(grace).unwrap();
+    assert!(chunk_stored(&hat, &data[..]));
+
+    // Once unused again, it gets a full grace period.
+    let (info, _, _) = hat.snapshot_index.latest("familyname").unwrap();
+    assert!(hat.delete_snapshot("familyname".to_owned(), info.snapshot_id).unwrap());
+    hat.gc_with_grace(grace).unwrap();
+    assert!(chunk_stored(&hat, &data[..]));
+    clock.advance(chrono::Duration::hours(1));
+    hat.gc_with_grace(grace).unwrap();
+    assert!(!chunk_stored(&hat, &data[..]));
+}
+
+#[test]
+fn resume_rolls_back_incomplete_commit() {
+    let (_, mut hat, mut fam) = setup_family();
+    let kept = vec![5; 1000];
+    let orphan = vec![6; 1000];
+
+    snapshot_files(&fam, vec![("kept", kept.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+
+    // Crash after the data of the next snapshot reached the blobs, but before it was committed.
+    snapshot_files(&fam, vec![("orphan", orphan.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.data_flush().unwrap();
+    hat.snapshot_index.reserve("familyname".to_owned());
+    hat.meta_flush();
+    assert_eq!(hat.snapshot_index.list_not_done().len(), 1);
+    assert!(chunk_stored(&hat, &orphan[..]));
+
+    // Recovery drops the incomplete snapshot, and doing it again changes nothing.
+    for _ in 0..2 {
+        hat.resume().unwrap();
+        assert!(hat.snapshot_index.list_not_done().is_empty());
+        let snapshots: Vec<_> = hat.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == "familyname")
+            .collect();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].info.snapshot_id, 1);
+    }
+
+    // The orphaned data is left for the GC, which keeps the committed snapshot intact.
+    hat.gc().unwrap();
+    assert!(!chunk_stored(&hat, &orphan[..]));
+    assert!(chunk_stored(&hat, &kept[..]));
+}
+
+#[test]
+fn hash_salt_keeps_hashes_store_specific() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    hat.enable_hash_salt().unwrap();
+    let salt = hat.db.lock().store_hash_salt().unwrap();
+    // Enabling it again keeps the salt.
+    hat.enable_hash_salt().unwrap();
+    assert_eq!(hat.db.lock().store_hash_salt(), Some(salt.clone()));
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(SALTED_READER_VERSION));
+
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // The chunk is known by its salted hash, and not by the hash that an unsalted store under
+    // the same key would give it.
+    let leaf = |keys: &crypto::keys::Keeper| {
+        hash::Hash::new(keys, blob::NodeType::Leaf, blob::LeafType::FileChunk, &[1; 1000])
+    };
+    assert!(hat.hash_index.get_id(&leaf(&hat.keys)).is_some());
+    assert!(hat.hash_index.get_id(&leaf(&crypto::keys::Keeper::new_for_testing())).is_none());
+    assert_eq!(restored_names(&mut hat), vec!["a"]);
+
+    // The salt is kept in the backend, and recovered from it with the rest of the store.
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let mut recovered = setup_hat(backend);
+    recovered.recover().unwrap();
+    assert_eq!(recovered.db.lock().store_hash_salt(), Some(salt));
+    assert_eq!(recovered.db.lock().store_min_reader_version(), Some(SALTED_READER_VERSION));
+    assert!(recovered.hash_index.get_id(&leaf(&hat.keys)).is_some());
+    assert_eq!(restored_names(&mut recovered), vec!["a"]);
+
+    // A store that already holds unsalted data can not be salted.
+    let (_backend, mut other, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    other.commit(&mut fam, None).unwrap();
+    assert!(other.enable_hash_salt().is_err());
+    assert_eq!(other.db.lock().store_hash_salt(), None);
+}
+
+/// Names restored from the latest visible snapshot of "familyname".
+fn restored_names<B: StoreBackend>(hat: &mut HatRc<B>) -> Vec<String> {
+    let out = env::temp_dir().join(format!("hat-visible-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let mut names: Vec<String> = fs::read_dir(&out)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    fs::remove_dir_all(out).unwrap();
+    names
+}
+
+fn visible_ids<B: StoreBackend>(hat: &mut HatRc<B>) -> Vec<u64> {
+    hat.snapshot_index
+        .list_committed()
+        .into_iter()
+        .filter(|s| s.family_name == "familyname")
+        .map(|s| s.info.snapshot_id)
+        .collect()
+}
+
+#[test]
+fn commit_is_invisible_before_flip() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // Crash with all of the next snapshot stored and registered, but before the flip.
+    snapshot_files(&fam, vec![("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let (info, _) = hat.commit_prepare(&mut fam, None).unwrap();
+    assert_eq!(info.snapshot_id, 2);
+
+    assert_eq!(visible_ids(&mut hat), vec![1]);
+    assert!(hat.snapshot_index.lookup("familyname", 2).is_none());
+    assert_eq!(hat.snapshot_index.latest("familyname").unwrap().0.snapshot_id, 1);
+    assert_eq!(restored_names(&mut hat), vec!["a".to_owned()]);
+    assert!(!hat.delete_snapshot("familyname".to_owned(), 2).unwrap());
+
+    // The GC knows about the snapshot, so resuming finishes the commit, flip included.
+    hat.resume().unwrap();
+    assert_eq!(visible_ids(&mut hat), vec![1, 2]);
+    assert_eq!(restored_names(&mut hat), vec!["a".to_owned(), "b".to_owned()]);
+}
+
+#[test]
+fn commit_is_complete_after_flip() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+
+    // Crash right after the flip, before the GC cleaned up after the commit.
+    let (info, _) = hat.commit_prepare(&mut fam, None).unwrap();
+    hat.snapshot_index.ready_commit(&info);
+    hat.meta_flush();
+    assert_eq!(hat.snapshot_index.list_not_done().len(), 1);
+
+    // The snapshot is visible and restorable as it is.
+    assert_eq!(visible_ids(&mut hat), vec![1]);
+    assert!(hat.snapshot_index.lookup("familyname", 1).is_some());
+    assert_eq!(restored_names(&mut hat), vec!["a".to_owned(), "b".to_owned()]);
+
+    hat.resume().unwrap();
+    assert!(hat.snapshot_index.list_not_done().is_empty());
+    assert_eq!(visible_ids(&mut hat), vec![1]);
+    assert_eq!(restored_names(&mut hat), vec!["a".to_owned(), "b".to_owned()]);
+}
+
+fn insert_hash<B: StoreBackend>(hat: &HatRc<B>, bytes: Vec<u8>) -> hash::tree::HashRef {
+    let entry = hash::Entry {
+        hash: hash::Hash { bytes: bytes },
+        node: blob::NodeType::Leaf,
+        leaf: blob::LeafType::FileChunk,
+        childs: None,
+        persistent_ref: Some(blob::ChunkRef {
+            blob_id: Some(1),
+            blob_name: vec![1],
+            offset: 0,
+            length: 0,
+            packing: None,
+            pipeline: None,
+            key: None,
+        }),
+    };
+    match hat.hash_index.reserve(&entry) {
+        hash::ReserveResult::ReserveOk(id) => hat.hash_index.commit(id, Some(entry.clone())),
+        hash::ReserveResult::HashKnown(_) => panic!("hash already known"),
+    }
+    hat.hash_index.fetch_hash_ref(&entry.hash).unwrap().unwrap()
+}
+
+#[test]
+fn resolve_hash_prefix() {
+    let (_, hat, _) = setup_family();
+
+    let mut first = vec![0xab, 0xcd, 0x01];
+    first.extend_from_slice(&[0; 61]);
+    let mut second = vec![0xab, 0xcd, 0x02];
+    second.extend_from_slice(&[0; 61]);
+    let first = insert_hash(&hat, first);
+    insert_hash(&hat, second);
+
+    assert_eq!(first.fingerprint(), "abcd01000000");
+    assert_eq!(first.fingerprint(), first.clone().fingerprint());
+
+    // A unique prefix, including the full fingerprint, resolves to the full reference.
+    for prefix in vec!["abcd01", "ABCD01", &first.fingerprint()[..]] {
+        let href = hat.resolve_hash_ref(prefix).unwrap();
+        assert_eq!(href.hash, first.hash);
+    }
+
+    // A shared prefix is an error, as is a prefix of nothing.
+    for prefix in vec!["abcd0", "abcd", "abc", "a"] {
+        match hat.resolve_hash_ref(prefix) {
+            Err(HatError::Message(ref msg)) => assert!(msg.contains("ambiguous")),
+            _ => panic!("expected ambiguity error"),
+        }
+    }
+    assert!(hat.resolve_hash_ref("abcd03").is_err());
+    assert!(hat.resolve_hash_ref("not hex").is_err());
+}
+
+#[test]
+fn export_and_verify_proof() {
+    let (_, mut hat, mut fam) = setup_family();
+    let public_key = hat.keys.manifest_public_key();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let proof = hat.export_proof().unwrap();
+    assert_eq!(proof.snapshots.len(), 1);
+    assert_eq!(proof.snapshots[0].family_name, "familyname");
+    assert!(proof.snapshots[0].chunk_count >= 2);
+    assert!(proof.verify(&public_key[..]));
+
+    // The text form round-trips and still verifies.
+    let text = proof.to_text();
+    let parsed = Proof::from_text(&text).unwrap();
+    assert_eq!(parsed, proof);
+    assert!(parsed.verify(&public_key[..]));
+
+    // A different key does not verify it.
+    let mut other_key = public_key.clone();
+    other_key[0] ^= 1;
+    assert!(!parsed.verify(&other_key[..]));
+
+    // Neither does a proof with an altered snapshot root.
+    let root = proof.snapshots[0].root.to_hex();
+    let mut altered_root = root.clone().into_bytes();
+    altered_root[0] = if altered_root[0] == b'0' { b'1' } else { b'0' };
+    let altered = text.replace(&root, str::from_utf8(&altered_root).unwrap());
+    assert!(!Proof::from_text(&altered).unwrap().verify(&public_key[..]));
+
+    // Proofs from a newer format are refused.
+    let newer = text.replace("hat-integrity-proof 1", "hat-integrity-proof 2");
+    assert!(Proof::from_text(&newer).is_err());
+}
+
+/// An endless reader that cancels the given token after a number of reads.
+struct CancelAfter {
+    token: CancellationToken,
+    reads_left: usize,
+}
+
+impl io::Read for CancelAfter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reads_left == 0 {
+            self.token.cancel();
+        } else {
+            self.reads_left -= 1;
+        }
+        for b in buf.iter_mut() {
+            *b = self.reads_left as u8;
+        }
+        Ok(buf.len())
+    }
+}
+
+#[test]
+fn snapshot_cancel() {
+    let (backend, mut hat, mut fam) = setup_family();
+    let token = hat.cancellation_token();
+
+    basic_snapshot(&fam);
+
+    // Cancel after the first few chunks of a file have been stored.
+    let reader = CancelAfter {
+        token: token.clone(),
+        reads_left: 3,
+    };
+    let res = fam.snapshot_direct(
+        entry("endless".into()),
+        false,
+        Some(FileIterator::from_reader(Box::new(reader))),
+    );
+    assert!(res.unwrap_err().is_cancelled());
+
+    // The commit is rolled back, which ends the cancellation.
+    assert!(hat.commit(&mut fam, None).unwrap_err().is_cancelled());
+    assert!(hat.snapshot_index.list_all().is_empty());
+    assert!(!token.is_cancelled());
+
+    // Nothing references the stored data, so the GC sweeps all of it.
+    fam.flush().unwrap();
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+    let names = backend.list().unwrap();
+    assert!(names.iter().all(|name| store_info_generation(name).is_some()));
+}
+
+#[test]
+fn snapshot_disk_usage() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(
+        &fam,
+        vec![
+            ("a/b/ones", vec![1; 1000]),
+            ("a/b/twos", vec![2; 2000]),
+            ("a/zeros", vec![0; 40000]),
+            ("c/zeros", vec![0; 40000]),
+            ("threes", vec![3; 8000]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let usage = hat.disk_usage("familyname".to_owned(), None).unwrap();
+    let find = |p: &str| {
+        usage
+            .iter()
+            .find(|u| u.path == PathBuf::from(p))
+            .expect("missing directory")
+            .clone()
+    };
+
+    assert_eq!(find("a/b").logical_bytes, 3000);
+    assert_eq!(find("a").logical_bytes, 43000);
+    assert_eq!(find("c").logical_bytes, 40000);
+    assert_eq!(find("").logical_bytes, 91000);
+    assert_eq!(usage[0].path, PathBuf::from(""));
+
+    // The shared zeros chunk is attributed to exactly one of the directories.
+    let (a, c) = (find("a").unique_bytes, find("c").unique_bytes);
+    assert!((a >= 40000) != (c >= 40000));
+    assert!(find("").unique_bytes < find("").logical_bytes);
+
+    // Directories below the limit are still counted towards their parents.
+    let shallow = hat.disk_usage("familyname".to_owned(), Some(1)).unwrap();
+    assert_eq!(shallow.len(), 3);
+    assert!(shallow.iter().all(|u| u.depth <= 1));
+    assert_eq!(shallow[0].logical_bytes, 91000);
+}
+
+#[test]
+fn snapshot_disk_usage_leaves_out_shared_data() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+
+    let random = |len: usize| (0..len).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    let common = random(50000);
+    let own = random(20000);
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a/common", common.clone()), ("b/own", own)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    // Only the first snapshot references the data, so all of it is unique.
+    let usage = hat.disk_usage("familyname".to_owned(), None).unwrap();
+    let unique = |usage: &Vec<DirUsage>, p: &str| {
+        usage.iter().find(|u| u.path == PathBuf::from(p)).unwrap().unique_bytes
+    };
+    assert!(unique(&usage, "a") >= 50000);
+    assert!(unique(&usage, "b") >= 20000);
+
+    // Once another snapshot stores the same file, it is no longer unique to the first.
+    let mut other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(&other, vec![("common", common)]).unwrap();
+    other.flush().unwrap();
+    hat.commit(&mut other, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let usage = hat.disk_usage("familyname".to_owned(), None).unwrap();
+    assert!(unique(&usage, "a") < 50000);
+    assert!(unique(&usage, "b") >= 20000);
+}
+
+#[test]
+fn snapshot_sharing_attribution() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+
+    let random = |len: usize| (0..len).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    let common = random(100000);
+    for &(name, ref own) in &[("a", random(50000)), ("b", random(20000))] {
+        let mut fam = hat.open_family(name.to_owned()).unwrap();
+        snapshot_files(&fam, vec![("common", common.clone()), ("own", own.clone())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.data_flush().unwrap();
+
+    let sharing = hat.snapshot_sharing(None).unwrap();
+    assert_eq!(sharing.len(), 2);
+    let (a, b) = (sharing[0].clone(), sharing[1].clone());
+    assert_eq!((&a.family_name[..], &b.family_name[..]), ("a", "b"));
+    for s in &sharing {
+        assert_eq!(s.referenced_bytes, s.exclusive_bytes + s.shared_bytes);
+    }
+
+    // With two snapshots, whatever one shares is shared with the other.
+    assert_eq!(a.shared_bytes, b.shared_bytes);
+    assert!(a.shared_bytes >= 100000);
+    assert!(a.exclusive_bytes >= 50000 && a.exclusive_bytes < 100000);
+    assert!(b.exclusive_bytes >= 20000 && b.exclusive_bytes < 50000);
+
+    let only_a = hat.snapshot_sharing(Some("a".to_owned())).unwrap();
+    assert_eq!(only_a, vec![a.clone()]);
+
+    // Once b is deleted, everything a references is its own.
+    hat.deregister_by_name("b".to_owned(), 1).unwrap();
+    let sharing = hat.snapshot_sharing(None).unwrap();
+    assert_eq!(sharing.len(), 1);
+    assert_eq!(sharing[0].referenced_bytes, a.referenced_bytes);
+    assert_eq!(sharing[0].exclusive_bytes, a.referenced_bytes);
+    assert_eq!(sharing[0].shared_bytes, 0);
+}
+
+fn write_file(path: &PathBuf, contents: &[u8]) {
+    fs::File::create(path).unwrap().write_all(contents).unwrap();
+}
+
+#[test]
+fn snapshot_compare_to_source() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-compare-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"aaa");
+    write_file(&root.join("empty"), b"");
+    write_file(&root.join("sub").join("b"), &vec![1; 300000][..]);
+    write_file(&root.join("sub").join("c"), b"ccc");
+
+    fam.snapshot_dir(root.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let diffs = hat.compare_to_source("familyname".to_owned(), root.clone())
+        .unwrap();
+    assert!(diffs.is_empty());
+
+    // Change the last chunk of a multi-chunk file.
+    let mut contents = vec![1; 300000];
+    contents[299999] = 2;
+    write_file(&root.join("sub").join("b"), &contents[..]);
+
+    let diffs = hat.compare_to_source("familyname".to_owned(), root.clone())
+        .unwrap();
+    assert_eq!(
+        diffs,
+        vec![Divergence::ContentChanged(root.join("sub").join("b"))]
+    );
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn rolling_chunker_is_recorded_per_snapshot() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let rolling = Chunker::Rolling(RollingParams {
+        window_size: 16,
+        table_seed: 3,
+        min_size: 64,
+        avg_size: 256,
+        max_size: 1024,
+        hints: vec![],
+    });
+    hat.set_chunker(rolling.clone()).unwrap();
+    assert!(hat.set_chunker(Chunker::Fixed(0)).is_err());
+
+    let root = env::temp_dir().join(format!("hat-chunker-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let contents: Vec<u8> = (0..20000u32).map(|i| (i * i % 251) as u8).collect();
+    write_file(&root.join("a"), &contents[..]);
+
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    fam.snapshot_dir(root.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.chunkers_in_use(), vec![rolling.describe()]);
+
+    // The source is chunked the way the snapshot was when comparing.
+    let diffs = hat.compare_to_source("familyname".to_owned(), root.clone()).unwrap();
+    assert!(diffs.is_empty());
+
+    let out = env::temp_dir().join(format!("hat-chunker-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let mut restored = vec![];
+    fs::File::open(out.join(root.strip_prefix("/").unwrap()).join("a"))
+        .unwrap()
+        .read_to_end(&mut restored)
+        .unwrap();
+    assert_eq!(restored, contents);
+
+    // Snapshots taken with other parameters are told apart.
+    hat.set_chunker(Chunker::default()).unwrap();
+    let mut other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(&other, vec![("b", vec![1; 100])]).unwrap();
+    other.flush().unwrap();
+    hat.commit(&mut other, None).unwrap();
+    hat.data_flush().unwrap();
+    let mut in_use = vec![rolling.describe(), Chunker::default().describe()];
+    in_use.sort();
+    assert_eq!(hat.chunkers_in_use(), in_use);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn fanout_is_recorded_per_snapshot() {
+    let (_, mut hat, mut fam) = setup_family();
+    assert!(hat.set_fanout(1).is_err());
+
+    let contents: Vec<u8> = (0..20u8).flat_map(|i| vec![i; key::CHUNK_SIZE]).collect();
+    snapshot_files(&fam, vec![("file", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    hat.set_fanout(32).unwrap();
+    let mut wide = hat.open_family("wide".to_owned()).unwrap();
+    snapshot_files(&wide, vec![("file", contents.clone())]).unwrap();
+    wide.flush().unwrap();
+    hat.commit(&mut wide, None).unwrap();
+    hat.data_flush().unwrap();
+
+    for &(family, fanout, height) in &[("familyname", 8, 2), ("wide", 32, 1)] {
+        let (info, _, dir_ref) = hat.snapshot_index.latest(family).unwrap();
+        assert_eq!(hat.snapshot_index.fanout(&info), fanout);
+
+        // 20 chunks take two levels of branches 8 wide, but one 32 wide.
+        let fam = hat.open_family(family.to_owned()).unwrap();
+        let file_ref = cat::find_file(&fam, &hat.hash_backend(), dir_ref.unwrap(), "file").unwrap();
+        assert_eq!(file_ref.node, blob::NodeType::Branch(height));
+
+        // Both read back, whatever the fan-out in use now.
+        let mut out = vec![];
+        hat.cat(family.to_owned(), "file", &mut out).unwrap();
+        assert!(out == contents);
+    }
+}
+
+#[test]
+fn snapshot_one_file_system() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-one-fs-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("mnt").join("sub")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"aaa");
+    write_file(&root.join("mnt").join("b"), b"bbb");
+    write_file(&root.join("mnt").join("sub").join("c"), b"ccc");
+
+    // Pretend that everything below "mnt" is on another device.
+    let mnt = root.join("mnt");
+    let mut options = SnapshotOptions::default()
+        .with_device_id(move |path, _| if path.starts_with(&mnt) { 2 } else { 1 });
+    options.one_file_system = true;
+    fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-one-fs-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    // The mount point is kept as an empty directory.
+    let restored = out.join(root.strip_prefix("/").unwrap());
+    assert!(restored.join("a").is_file());
+    assert!(restored.join("mnt").is_dir());
+    assert_eq!(fs::read_dir(restored.join("mnt")).unwrap().count(), 0);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn snapshot_excludes_tagged_caches() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-caches-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("cache").join("sub")).unwrap();
+    fs::create_dir_all(root.join("not-cache")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"aaa");
+    write_file(
+        &root.join("cache").join("CACHEDIR.TAG"),
+        b"Signature: 8a477f597d28d172789f06886806bc55\n# A cache.\n",
+    );
+    write_file(&root.join("cache").join("b"), b"bbb");
+    write_file(&root.join("cache").join("sub").join("c"), b"ccc");
+    write_file(&root.join("not-cache").join("CACHEDIR.TAG"), b"Signature: not a cache\n");
+    write_file(&root.join("not-cache").join("d"), b"ddd");
+
+    let mut options = SnapshotOptions::default();
+    options.exclude_caches = true;
+    fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-caches-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    // The cache is kept as an empty directory; a tag without the signature does not count.
+    let restored = out.join(root.strip_prefix("/").unwrap());
+    assert!(restored.join("a").is_file());
+    assert!(restored.join("cache").is_dir());
+    assert_eq!(fs::read_dir(restored.join("cache")).unwrap().count(), 0);
+    assert!(restored.join("not-cache").join("CACHEDIR.TAG").is_file());
+    assert!(restored.join("not-cache").join("d").is_file());
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn restore_keeps_sparse_files_sparse() {
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::MetadataExt;
+
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-sparse-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let len = 8 * 1024 * 1024;
+    let first: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    let second = vec![7; 50000];
+    {
+        let mut f = fs::File::create(root.join("disk.img")).unwrap();
+        f.set_len(len).unwrap();
+        f.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        f.write_all(&first).unwrap();
+        f.seek(SeekFrom::Start(6 * 1024 * 1024)).unwrap();
+        f.write_all(&second).unwrap();
+    }
+    if fs::metadata(root.join("disk.img")).unwrap().blocks() * 512 >= len {
+        // The filesystem does not do holes, so there are none to keep.
+        fs::remove_dir_all(root).unwrap();
+        return;
+    }
+
+    let mut options = SnapshotOptions::default();
+    options.sparse_files = true;
+    fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    // Older readers would restore the data back to back.
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(SPARSE_READER_VERSION));
+
+    let out = env::temp_dir().join(format!("hat-sparse-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    let restored = out.join(root.strip_prefix("/").unwrap()).join("disk.img");
+    let meta = fs::metadata(&restored).unwrap();
+    assert_eq!(meta.len(), len);
+    assert!(meta.blocks() * 512 < len / 2);
+    let mut contents = vec![];
+    fs::File::open(&restored).unwrap().read_to_end(&mut contents).unwrap();
+    let mut expected = vec![0; len as usize];
+    expected[1024 * 1024..1024 * 1024 + first.len()].copy_from_slice(&first);
+    expected[6 * 1024 * 1024..6 * 1024 * 1024 + second.len()].copy_from_slice(&second);
+    assert!(contents == expected);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn metadata_only_snapshot_reuses_unchanged_contents() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-metadata-only-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("dir")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("same"), b"same");
+    write_file(&root.join("changed"), b"before");
+    write_file(&root.join("dir").join("inner"), b"inner");
+    fam.snapshot_dir(root.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    write_file(&root.join("changed"), b"after");
+    let later = filetime::FileTime::from_seconds_since_1970(2000000000, 0);
+    filetime::set_file_times(&root.join("changed"), later, later).unwrap();
+    write_file(&root.join("new"), b"new");
+
+    let mut options = SnapshotOptions::default();
+    options.metadata_only = true;
+    let stats = fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(stats.files, 4);
+    assert!(stats.read_files.is_empty());
+    assert!(!hat.snapshot_is_metadata_only("familyname", 1));
+    assert!(hat.snapshot_is_metadata_only("familyname", 2));
+
+    // Unchanged files keep their contents from the snapshot before; the others have none.
+    let out = env::temp_dir().join(format!("hat-metadata-only-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let restored = out.join(root.strip_prefix("/").unwrap());
+    let read = |path: PathBuf| {
+        let mut contents = vec![];
+        fs::File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    };
+    assert_eq!(read(restored.join("same")), b"same");
+    assert_eq!(read(restored.join("dir").join("inner")), b"inner");
+    assert!(!restored.join("changed").exists());
+    assert!(!restored.join("new").exists());
+    fs::remove_dir_all(&out).unwrap();
+
+    // A full snapshot reads the files whose contents were not captured.
+    let stats = fam.snapshot_dir(root.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(stats.read_files.len(), 2);
+    assert!(!hat.snapshot_is_metadata_only("familyname", 3));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(read(restored.join("changed")), b"after");
+    assert_eq!(read(restored.join("new")), b"new");
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn snapshot_of_several_roots_is_one_snapshot() {
+    let (backend, mut hat, mut fam) = setup_family();
+
+    let base = env::temp_dir().join(format!("hat-batch-{}", rand::random::<u64>()));
+    fs::create_dir_all(base.join("etc").join("sub")).unwrap();
+    fs::create_dir_all(base.join("home").join("user")).unwrap();
+    let base = fs::canonicalize(base).unwrap();
+    let (etc, home) = (base.join("etc"), base.join("home"));
+    let shared: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    write_file(&etc.join("shared"), &shared[..]);
+    write_file(&home.join("user").join("copy"), &shared[..]);
+    write_file(&etc.join("sub").join("a.conf"), b"etc");
+    write_file(&home.join("user").join("notes"), b"home");
+
+    // Roots inside one another are refused before anything is walked.
+    assert!(
+        fam.snapshot_dirs_with_options(vec![base.clone(), etc.clone()], SnapshotOptions::default())
+            .is_err()
+    );
+    assert!(
+        fam.snapshot_dirs_with_options(vec![home.clone(), home.clone()], SnapshotOptions::default())
+            .is_err()
+    );
+
+    let stats = fam.snapshot_dirs_with_options(
+        vec![etc.clone(), home.clone()],
+        SnapshotOptions::default(),
+    ).unwrap();
+    assert_eq!(stats.files, 4);
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.snapshot_index.latest("familyname").unwrap().0.snapshot_id, 1);
+
+    // The contents found under both roots were stored once.
+    let mut chunks = 0;
+    for name in backend.list().unwrap() {
+        if store_info_generation(&name).is_some() {
+            continue;
+        }
+        let data = backend.retrieve(&name[..]).unwrap().unwrap();
+        let reader = blob::BlobReader::new(hat.keys.clone(), crypto::CipherTextRef::new(&data[..]))
+            .unwrap();
+        chunks += reader
+            .refs()
+            .unwrap()
+            .iter()
+            .filter(|r| r.node == blob::NodeType::Leaf && r.leaf == blob::LeafType::FileChunk)
+            .count();
+    }
+    let shared_chunks = (shared.len() + key::CHUNK_SIZE - 1) / key::CHUNK_SIZE;
+    assert_eq!(chunks, shared_chunks + 2);
+
+    let read = |path: PathBuf| {
+        let mut contents = vec![];
+        fs::File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    };
+    let in_out = |out: &PathBuf, dir: &PathBuf| out.join(dir.strip_prefix("/").unwrap());
+
+    // Both roots come back together.
+    let out = env::temp_dir().join(format!("hat-batch-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(read(in_out(&out, &etc).join("shared")), shared);
+    assert_eq!(read(in_out(&out, &etc).join("sub").join("a.conf")), b"etc");
+    assert_eq!(read(in_out(&out, &home).join("user").join("copy")), shared);
+    assert_eq!(read(in_out(&out, &home).join("user").join("notes")), b"home");
+    fs::remove_dir_all(&out).unwrap();
+
+    // Or one of them alone.
+    let options = RestoreOptions {
+        filter: PathFilter::default().include(home.to_str().unwrap()),
+        ..RestoreOptions::default()
+    };
+    hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+        .unwrap();
+    assert_eq!(read(in_out(&out, &home).join("user").join("copy")), shared);
+    assert!(!in_out(&out, &etc).exists());
+
+    fs::remove_dir_all(base).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn snapshot_follows_allowed_mounts() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-follow-mounts-{}", rand::random::<u64>()));
+    for dir in &["data/inner", "nfs", "other/bind"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"aaa");
+    write_file(&root.join("data").join("b"), b"bbb");
+    write_file(&root.join("data").join("inner").join("c"), b"ccc");
+    write_file(&root.join("nfs").join("d"), b"ddd");
+    write_file(&root.join("other").join("e"), b"eee");
+    write_file(&root.join("other").join("bind").join("f"), b"fff");
+
+    // A device per mount point; "data/inner" is mounted inside "data".
+    let mounts = vec![
+        (root.join("data").join("inner"), 4),
+        (root.join("data"), 2),
+        (root.join("nfs"), 3),
+        (root.join("other").join("bind"), 5),
+    ];
+    let device_id = move |path: &Path, _: &fs::Metadata| {
+        mounts.iter().find(|&&(ref m, _)| path.starts_with(m)).map_or(1, |&(_, dev)| dev)
+    };
+
+    // Mounts to follow must be inside what is snapshotted.
+    let mut options = SnapshotOptions::default().with_device_id(device_id.clone());
+    options.one_file_system = true;
+    options.follow_mounts = vec![env::temp_dir()];
+    assert!(fam.snapshot_dir_with_options(root.clone(), options).is_err());
+
+    let mut options = SnapshotOptions::default().with_device_id(device_id);
+    options.one_file_system = true;
+    options.follow_mounts = vec![root.join("data")];
+    fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-follow-mounts-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let restored = out.join(root.strip_prefix("/").unwrap());
+
+    // The allowed mount is stored, along with what is mounted below it.
+    assert!(restored.join("a").is_file());
+    assert!(restored.join("data").join("b").is_file());
+    assert!(restored.join("data").join("inner").join("c").is_file());
+    // Other mount points are kept as empty directories.
+    assert!(restored.join("other").join("e").is_file());
+    for stub in &["nfs", "other/bind"] {
+        assert!(restored.join(stub).is_dir());
+        assert_eq!(fs::read_dir(restored.join(stub)).unwrap().count(), 0);
+    }
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn tiny_chunk_cache_still_deduplicates() {
+    let (backend, hat, fam) = setup_family();
+    hat.set_chunk_cache_size(1000);
+
+    // Every file is stored twice, under different names.
+    let mut files = vec![];
+    for i in 0..20 {
+        let contents: Vec<u8> = (0..5000).map(|j| (i * 7 + j * 13) as u8).collect();
+        files.push((format!("a{}", i), contents.clone()));
+        files.push((format!("b{}", i), contents));
+    }
+    snapshot_files(
+        &fam,
+        files.iter().map(|&(ref name, ref c)| (&name[..], c.clone())).collect(),
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.data_flush().unwrap();
+
+    let mut chunks = 0;
+    for name in backend.list().unwrap() {
+        if store_info_generation(&name).is_some() {
+            continue;
+        }
+        let data = backend.retrieve(&name[..]).unwrap().unwrap();
+        let reader = blob::BlobReader::new(hat.keys.clone(), crypto::CipherTextRef::new(&data[..]))
+            .unwrap();
+        chunks += reader
+            .refs()
+            .unwrap()
+            .iter()
+            .filter(|r| r.node == blob::NodeType::Leaf && r.leaf == blob::LeafType::FileChunk)
+            .count();
+    }
+    assert_eq!(chunks, 20);
+    assert!(hat.blob_store.chunk_cache_memory() <= 1000);
+}
+
+#[test]
+fn snapshot_link_dest_reuses_reference_chunks() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing(backend.clone(), 1024 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    let base = env::temp_dir().join(format!("hat-link-dest-{}", rand::random::<u64>()));
+    let reference = base.join("reference");
+    let copy = base.join("copy");
+    fs::create_dir_all(reference.join("sub")).unwrap();
+    fs::create_dir_all(copy.join("sub")).unwrap();
+    let random = |len| (0..len).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+    let same = random(300000);
+    let nested = random(200000);
+    let changed = random(300000);
+    for dir in vec![&reference, &copy] {
+        write_file(&dir.join("same"), &same[..]);
+        write_file(&dir.join("sub").join("nested"), &nested[..]);
+    }
+    // Matched by contents, not by name.
+    write_file(&reference.join("changed"), &random(300000)[..]);
+    write_file(&copy.join("changed"), &changed[..]);
+
+    fam.snapshot_dir(reference.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    let before: HashSet<Box<[u8]>> = backend.list().unwrap().into_iter().collect();
+
+    let mut options = SnapshotOptions::default();
+    options.link_dest = Some(reference.clone());
+    fam.snapshot_dir_with_options(copy.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // The new blobs only hold the 3 chunks of the changed file, besides the listings.
+    let mut new_chunks = 0;
+    for name in backend.list().unwrap() {
+        if before.contains(&name) {
+            continue;
+        }
+        let data = backend.retrieve(&name[..]).unwrap().unwrap();
+        let reader = blob::BlobReader::new(hat.keys.clone(), crypto::CipherTextRef::new(&data[..]))
+            .unwrap();
+        new_chunks += reader
+            .refs()
+            .unwrap()
+            .iter()
+            .filter(|r| r.node == blob::NodeType::Leaf && r.leaf == blob::LeafType::FileChunk)
+            .count();
+    }
+    assert_eq!(new_chunks, 3);
+
+    let copy = fs::canonicalize(&copy).unwrap();
+    for &(path, contents) in &[("same", &same), ("sub/nested", &nested), ("changed", &changed)] {
+        let path = copy.join(path);
+        let mut out = vec![];
+        hat.cat(
+            "familyname".to_owned(),
+            path.strip_prefix("/").unwrap().to_str().unwrap(),
+            &mut out,
+        ).unwrap();
+        assert!(&out == contents);
+    }
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+/// An ACL that also lets user 12345 read, as Linux keeps it in `system.posix_acl_access`.
+#[cfg(feature = "xattrs")]
+fn acl_with_extra_user() -> Vec<u8> {
+    let undefined = 0xffff_ffff;
+    // (tag, permissions, id): owner, user 12345, group, mask and others.
+    let entries: [(u16, u16, u32); 5] = [
+        (0x01, 6, undefined),
+        (0x02, 4, 12345),
+        (0x04, 4, undefined),
+        (0x10, 4, undefined),
+        (0x20, 0, undefined),
+    ];
+    let mut acl = vec![2, 0, 0, 0];
+    for &(tag, perm, id) in entries.iter() {
+        acl.extend_from_slice(&[tag as u8, (tag >> 8) as u8, perm as u8, (perm >> 8) as u8]);
+        acl.extend_from_slice(
+            &[id as u8, (id >> 8) as u8, (id >> 16) as u8, (id >> 24) as u8],
+        );
+    }
+    acl
+}
+
+#[cfg(feature = "xattrs")]
+#[test]
+fn snapshot_keeps_extended_attributes() {
+    use xattr;
+
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-xattrs-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let file = root.join("labelled");
+    write_file(&file, b"data");
+    if xattr::set(&file, "user.hat-test", b"value").is_err() {
+        // The filesystem keeps no user attributes; there is nothing to round-trip.
+        fs::remove_dir_all(root).unwrap();
+        return;
+    }
+    let with_acl = xattr::set(&file, "system.posix_acl_access", &acl_with_extra_user()[..]).is_ok();
+
+    let mut options = SnapshotOptions::default();
+    options.extended_attributes = true;
+    fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-xattrs-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    let restored = out.join(root.strip_prefix("/").unwrap()).join("labelled");
+    assert_eq!(
+        xattr::get(&restored, "user.hat-test").unwrap(),
+        Some(b"value".to_vec())
+    );
+    if with_acl {
+        assert_eq!(
+            xattr::get(&restored, "system.posix_acl_access").unwrap(),
+            xattr::get(&file, "system.posix_acl_access").unwrap()
+        );
+    }
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+/// Freezes a directory by handing out a prepared copy of it, or cannot freeze it without one.
+/// Records what is done with it, next to the files read.
+struct MockSourceSnapshot {
+    view: Option<PathBuf>,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl SourceSnapshot for MockSourceSnapshot {
+    fn create(&self, _source: &Path) -> Result<Option<PathBuf>, String> {
+        self.events.lock().unwrap().push("create".to_owned());
+        Ok(self.view.clone())
+    }
+
+    fn destroy(&self, view: &Path) -> Result<(), String> {
+        assert_eq!(Some(view), self.view.as_ref().map(|v| v.as_path()));
+        self.events.lock().unwrap().push("destroy".to_owned());
+        Ok(())
+    }
+}
+
+/// Snapshot `root` into a new family, through a `MockSourceSnapshot` that hands out `view`.
+fn snapshot_with_source_snapshot(
+    hat: &mut HatRc<MemoryBackend>,
+    family: &str,
+    root: &PathBuf,
+    view: Option<PathBuf>,
+    cancel_on_read: bool,
+) -> (Family<MemoryBackend>, Result<SnapshotStats, HatError>, Vec<String>) {
+    let fam = hat.open_family(family.to_owned()).unwrap();
+    let events = Arc::new(Mutex::new(vec![]));
+    let reads = events.clone();
+    let cancel = fam.cancel.clone();
+    let mut options = SnapshotOptions::default().with_open_file(move |path| {
+        reads.lock().unwrap().push(format!("read {}", path.display()));
+        if cancel_on_read {
+            cancel.cancel();
+        }
+        fs::File::open(path).map(|f| Arc::new(f) as Arc<ReadAt>)
+    });
+    options.source_snapshot = Some(Arc::new(MockSourceSnapshot {
+        view: view,
+        events: events.clone(),
+    }));
+    let res = fam.snapshot_dir_with_options(root.clone(), options);
+    let events = events.lock().unwrap().clone();
+    (fam, res, events)
+}
+
+#[test]
+fn snapshot_reads_frozen_source() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+
+    let root = env::temp_dir().join(format!("hat-live-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"live");
+    let view = env::temp_dir().join(format!("hat-frozen-{}", rand::random::<u64>()));
+    fs::create_dir_all(&view).unwrap();
+    write_file(&view.join("a"), b"frozen");
+    let read_view = format!("read {}", view.join("a").display());
+
+    // The view is made before the walk and removed after it.
+    let (mut fam, res, events) =
+        snapshot_with_source_snapshot(&mut hat, "frozen", &root, Some(view.clone()), false);
+    res.unwrap();
+    assert_eq!(events, vec!["create".to_owned(), read_view.clone(), "destroy".to_owned()]);
+
+    // The frozen contents are stored under the name of the source.
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    let out = env::temp_dir().join(format!("hat-frozen-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("frozen".to_owned(), out.clone()).unwrap();
+    let mut contents = vec![];
+    fs::File::open(out.join(root.strip_prefix("/").unwrap()).join("a"))
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"frozen".to_vec());
+
+    // The view is also removed when the snapshot fails, during the walk or before it.
+    let (_, res, events) =
+        snapshot_with_source_snapshot(&mut hat, "cancelled", &root, Some(view.clone()), true);
+    assert!(res.is_err());
+    assert_eq!(events, vec!["create".to_owned(), read_view, "destroy".to_owned()]);
+    hat.cancellation_token().reset();
+
+    let missing = env::temp_dir().join(format!("hat-missing-{}", rand::random::<u64>()));
+    let (_, res, events) =
+        snapshot_with_source_snapshot(&mut hat, "missing", &root, Some(missing), false);
+    assert!(res.is_err());
+    assert_eq!(events, vec!["create".to_owned(), "destroy".to_owned()]);
+
+    // A source that cannot be frozen is read as it is.
+    let (_, res, events) = snapshot_with_source_snapshot(&mut hat, "live", &root, None, false);
+    res.unwrap();
+    assert_eq!(events, vec!["create".to_owned(), format!("read {}", root.join("a").display())]);
+    hat.data_flush().unwrap();
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(view).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+/// A file on a device that takes 1ms for every 16 KiB read, however many reads are waiting.
+struct SlowFile(fs::File);
+
+impl ReadAt for SlowFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = ReadAt::read_at(&self.0, buf, offset)?;
+        thread::sleep(Duration::from_millis((len / (16 * 1024)) as u64));
+        Ok(len)
+    }
+}
+
+/// Names and data hashes below `dir_id`, in listing order.
+fn list_files<B: StoreBackend>(
+    fam: &Family<B>,
+    dir_id: Option<u64>,
+    path: PathBuf,
+    out: &mut Vec<(PathBuf, Option<Vec<u8>>)>,
+) {
+    for (entry, hash_ref, _) in fam.list_from_key_store(dir_id).unwrap() {
+        let path = path.join(str::from_utf8(&entry.info.name[..]).unwrap());
+        out.push((path.clone(), hash_ref.map(|h| h.hash.bytes)));
+        if let key::Data::DirPlaceholder = entry.data {
+            list_files(fam, entry.node_id, path, out);
+        }
+    }
+}
+
+#[test]
+fn snapshot_read_concurrency() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+
+    let root = env::temp_dir().join(format!("hat-read-concurrency-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let big: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+    write_file(&root.join("big"), &big[..]);
+    write_file(&root.join("sub").join("big2"), &big[..3 * 1024 * 1024 + 5]);
+    for i in 0..8 {
+        write_file(&root.join("sub").join(format!("small{}", i)), &big[i..i + 1000]);
+    }
+    write_file(&root.join("empty"), b"");
+
+    let mut elapsed = vec![];
+    let mut listings = vec![];
+    for &read_concurrency in &[1, 4] {
+        let fam = hat.open_family(format!("read-{}", read_concurrency)).unwrap();
+        let mut options = SnapshotOptions::default().with_open_file(|path| {
+            Ok(Arc::new(SlowFile(fs::File::open(path)?)) as Arc<ReadAt>)
+        });
+        options.read_concurrency = read_concurrency;
+
+        let start = Instant::now();
+        fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+        elapsed.push(start.elapsed());
+        fam.flush().unwrap();
+
+        let mut files = vec![];
+        list_files(&fam, None, PathBuf::from("/"), &mut files);
+        listings.push(files);
+    }
+
+    // Reading ahead is faster, but stores the same snapshot.
+    assert!(elapsed[1] < elapsed[0], "{:?}", elapsed);
+    assert_eq!(listings[0], listings[1]);
+    assert!(listings[0].iter().any(|&(ref path, _)| path == &root.join("sub").join("small7")));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn snapshot_stores_large_files_apart() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+
+    let root = env::temp_dir().join(format!("hat-large-files-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let big: Vec<u8> = (0..5 * 1024 * 1024 + 17).map(|_| rand::random::<u8>()).collect();
+    write_file(&root.join("disk.img"), &big[..]);
+    for i in 0..40 {
+        write_file(&root.join("sub").join(format!("small{}", i)), &big[i..i + 1000 + i]);
+    }
+
+    let mut listings = vec![];
+    for &max_file_size in &[None, Some(1024 * 1024)] {
+        let name = format!("large-{:?}", max_file_size);
+        let mut fam = hat.open_family(name.clone()).unwrap();
+        let mut options = SnapshotOptions::default();
+        options.read_concurrency = 2;
+        options.max_file_size = max_file_size;
+        options.large_file_concurrency = 3;
+
+        let stats = fam.snapshot_dir_with_options(root.clone(), options).unwrap();
+        assert_eq!(stats.files, 41);
+        assert_eq!(stats.large_files, if max_file_size.is_some() { 1 } else { 0 });
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.data_flush().unwrap();
+
+        let mut files = vec![];
+        list_files(&fam, None, PathBuf::from("/"), &mut files);
+        listings.push(files);
+
+        let out = env::temp_dir().join(format!("hat-large-out-{}", rand::random::<u64>()));
+        hat.checkout_in_dir(name, out.clone()).unwrap();
+        let restored = out.join(root.strip_prefix("/").unwrap());
+        let read = |path: PathBuf| {
+            let mut contents = vec![];
+            fs::File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read(restored.join("disk.img")), big);
+        for i in 0..40 {
+            assert_eq!(
+                read(restored.join("sub").join(format!("small{}", i))),
+                &big[i..i + 1000 + i]
+            );
+        }
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    // Both ways store the same snapshot.
+    assert_eq!(listings[0], listings[1]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn checkout_reports_case_collisions() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(
+        &fam,
+        vec![
+            ("docs/README", vec![1; 10]),
+            ("docs/Readme", vec![2; 10]),
+            ("docs/a:b", vec![3; 10]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-collision-{}", rand::random::<u64>()));
+    let conflicts = hat.checkout_in_dir_with_policy(
+        "familyname".to_owned(),
+        out.clone(),
+        WindowsPolicy,
+    ).unwrap();
+
+    // Only one of the two names is restored, and the other is reported.
+    assert_eq!(conflicts.len(), 1);
+    let restored = match conflicts[0] {
+        RestoreConflict::Collision { ref path, ref existing } => {
+            let mut names = vec![path.clone(), existing.clone()];
+            names.sort();
+            assert_eq!(names, vec!["/docs/README", "/docs/Readme"]);
+            existing.trim_left_matches("/docs/").to_owned()
+        }
+        ref c => panic!("unexpected conflict: {}", c),
+    };
+    let names: Vec<String> = fs::read_dir(out.join("docs"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&restored));
+    assert!(names.contains(&"a_b".to_owned()));
+
+    fs::remove_dir_all(out).unwrap();
+}
+
+/// Remembers the name of every blob that is read.
+struct RecordingBackend {
+    inner: MemoryBackend,
+    retrieved: Mutex<Vec<Vec<u8>>>,
+}
+
+impl StoreBackend for RecordingBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retrieved.lock().unwrap().push(name.to_vec());
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        self.inner.list_page(token)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn checkout_selected_paths() {
+    let backend = Arc::new(RecordingBackend {
+        inner: MemoryBackend::new(),
+        retrieved: Mutex::new(vec![]),
+    });
+    // Small blobs, so that every file ends up in a blob of its own.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 1536).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(
+        &fam,
+        vec![
+            ("etc/a.conf", vec![1; 600]),
+            ("etc/sub/b", vec![2; 600]),
+            ("home/c", vec![3; 600]),
+            ("home/d.conf", vec![4; 600]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let blob_of = |hat: &HatRc<RecordingBackend>, contents: &[u8]| {
+        let hash = hash::Hash::new(
+            &hat.keys,
+            blob::NodeType::Leaf,
+            blob::LeafType::FileChunk,
+            contents,
+        );
+        hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name
+    };
+
+    // A whole directory.
+    backend.retrieved.lock().unwrap().clear();
+    let out = env::temp_dir().join(format!("hat-selected-{}", rand::random::<u64>()));
+    let options = RestoreOptions {
+        filter: PathFilter::default().include("/etc"),
+        ..RestoreOptions::default()
+    };
+    let conflicts = hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+        .unwrap();
+    assert!(conflicts.is_empty());
+    assert!(out.join("etc").join("a.conf").is_file());
+    assert!(out.join("etc").join("sub").join("b").is_file());
+    assert!(!out.join("home").exists());
+
+    let retrieved = backend.retrieved.lock().unwrap().clone();
+    assert!(retrieved.contains(&blob_of(&hat, &[2; 600])));
+    assert!(!retrieved.contains(&blob_of(&hat, &[3; 600])));
+    assert!(!retrieved.contains(&blob_of(&hat, &[4; 600])));
+    fs::remove_dir_all(&out).unwrap();
+
+    // Files by name, with the directories above them.
+    let options = RestoreOptions {
+        filter: PathFilter::default().include("*.conf").exclude("/home"),
+        ..RestoreOptions::default()
+    };
+    hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+        .unwrap();
+    assert!(out.join("etc").join("a.conf").is_file());
+    assert!(!out.join("etc").join("sub").exists());
+    assert!(!out.join("home").exists());
+    fs::remove_dir_all(&out).unwrap();
+}
+
+fn paths_below(dir: &Path, out: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        out.push(path.clone());
+        if path.is_dir() {
+            paths_below(&path, out);
+        }
+    }
+}
+
+#[test]
+fn restore_plan_matches_checkout() {
+    let backend = Arc::new(RecordingBackend {
+        inner: MemoryBackend::new(),
+        retrieved: Mutex::new(vec![]),
+    });
+    // Small blobs, so that file contents are kept apart from directory listings.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 1536).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(
+        &fam,
+        vec![
+            ("etc/a.conf", vec![1; 600]),
+            ("etc/sub/b", vec![2; 600]),
+            ("home/c", vec![3; 600]),
+            ("home/d.conf", vec![4; 300]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-plan-{}", rand::random::<u64>()));
+    fs::create_dir_all(out.join("etc")).unwrap();
+    write_file(&out.join("etc").join("a.conf"), b"old");
+    let options = RestoreOptions {
+        filter: PathFilter::default().include("*.conf"),
+        ..RestoreOptions::default()
+    };
+
+    backend.retrieved.lock().unwrap().clear();
+    let plan = hat.restore_plan("familyname".to_owned(), out.clone(), &options).unwrap();
+    let data_blob = |contents: &[u8]| {
+        let hash = hash::Hash::new(
+            &hat.keys,
+            blob::NodeType::Leaf,
+            blob::LeafType::FileChunk,
+            contents,
+        );
+        hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name
+    };
+    let retrieved = backend.retrieved.lock().unwrap().clone();
+    assert!(!retrieved.contains(&data_blob(&[1; 600])));
+    assert!(!retrieved.contains(&data_blob(&[4; 300])));
+
+    assert!(plan.conflicts.is_empty());
+    assert_eq!(plan.total_bytes(), 900);
+    let overwritten: Vec<&PathBuf> = plan.entries
+        .iter()
+        .filter(|e| e.exists)
+        .map(|e| &e.path)
+        .collect();
+    assert_eq!(overwritten, vec![&out.join("etc"), &out.join("etc").join("a.conf")]);
+
+    // Nothing was written: the old file is still there, and alone.
+    let mut before = vec![];
+    paths_below(&out, &mut before);
+    assert_eq!(before.len(), 2);
+    assert_eq!(fs::read(out.join("etc").join("a.conf")).unwrap(), b"old".to_vec());
+
+    hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+        .unwrap();
+    let mut restored = vec![];
+    paths_below(&out, &mut restored);
+    restored.sort();
+    let mut planned: Vec<PathBuf> = plan.entries.into_iter().map(|e| e.path).collect();
+    planned.sort();
+    assert_eq!(planned, restored);
+
+    fs::remove_dir_all(&out).unwrap();
+}
+
+/// Remembers the storage class of every blob, and refuses to return archived ones.
+struct TieredBackend {
+    inner: MemoryBackend,
+    classes: Mutex<HashMap<Vec<u8>, StorageClass>>,
+}
+
+impl StoreBackend for TieredBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        self.store_in_class(name, data, StorageClass::Standard)
+    }
+
+    fn store_in_class(
+        &self,
+        name: &[u8],
+        data: &crypto::CipherText,
+        class: StorageClass,
+    ) -> Result<(), String> {
+        self.classes.lock().unwrap().insert(name.to_vec(), class);
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.needs_thaw(name)? {
+            return Err("InvalidObjectState".to_owned());
+        }
+        self.inner.retrieve(name)
+    }
+
+    fn needs_thaw(&self, name: &[u8]) -> Result<bool, String> {
+        Ok(self.classes.lock().unwrap().get(name) == Some(&StorageClass::Archive))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        self.inner.list_page(token)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn storage_class_follows_policy() {
+    use chrono;
+
+    let backend = Arc::new(TieredBackend {
+        inner: MemoryBackend::new(),
+        classes: Mutex::new(HashMap::new()),
+    });
+    // Small blobs, so that every file ends up in a blob of its own.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 1536).unwrap();
+    hat.set_storage_policy(StoragePolicy {
+        cold_after: Some(chrono::Duration::days(30)),
+        cold_class: StorageClass::Archive,
+    }).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    let now = chrono::Utc::now().timestamp() as u64;
+    for &(name, byte, modified) in &[("old", 1u8, now - 365 * 24 * 3600), ("new", 2u8, now)] {
+        let mut e = entry(name.bytes().collect());
+        e.info.modified_ts_secs = Some(modified);
+        e.info.byte_length = Some(600);
+        fam.snapshot_direct(e, false, Some(FileIterator::from_bytes(vec![byte; 600])))
+            .unwrap();
+    }
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let blob_of = |hat: &HatRc<TieredBackend>, contents: &[u8]| {
+        let hash = hash::Hash::new(
+            &hat.keys,
+            blob::NodeType::Leaf,
+            blob::LeafType::FileChunk,
+            contents,
+        );
+        hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name
+    };
+    let (old_blob, new_blob) = (blob_of(&hat, &[1; 600]), blob_of(&hat, &[2; 600]));
+
+    // Only the data of the old file is archived; listings and tree nodes stay at hand.
+    {
+        let classes = backend.classes.lock().unwrap();
+        let archived: Vec<&Vec<u8>> = classes
+            .iter()
+            .filter(|&(_, class)| *class == StorageClass::Archive)
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(archived, vec![&old_blob]);
+        assert_eq!(classes.get(&new_blob), Some(&StorageClass::Standard));
+    }
+
+    // Restoring the old file needs it thawed first.
+    let out = env::temp_dir().join(format!("hat-tiered-{}", rand::random::<u64>()));
+    let err = hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NeedsThaw);
+    let _ = fs::remove_dir_all(&out);
+}
+
+/// Counts the bytes held by allocations made on threads that ask for it.
+struct CountingAllocator;
+
+thread_local! {
+    static COUNTING: Cell<bool> = Cell::new(false);
+    static ALLOCATED: Cell<isize> = Cell::new(0);
+    static PEAK_ALLOCATED: Cell<isize> = Cell::new(0);
+}
+
+fn count_allocated(bytes: isize) {
+    let _ = COUNTING.try_with(|counting| if counting.get() {
+        ALLOCATED.with(|allocated| {
+            allocated.set(allocated.get() + bytes);
+            PEAK_ALLOCATED.with(|peak| if allocated.get() > peak.get() {
+                peak.set(allocated.get());
+            });
+        });
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocated(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocated(layout.size() as isize);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocated(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocated(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The most bytes that `f` had allocated on this thread at any one time.
+fn peak_allocated<F, R>(f: F) -> (R, usize)
+where
+    F: FnOnce() -> R,
+{
+    ALLOCATED.with(|a| a.set(0));
+    PEAK_ALLOCATED.with(|p| p.set(0));
+    COUNTING.with(|c| c.set(true));
+    let result = f();
+    COUNTING.with(|c| c.set(false));
+    (result, PEAK_ALLOCATED.with(|p| p.get()) as usize)
+}
+
+#[test]
+fn restore_within_memory_budget() {
+    let max_blob_size = 256 * 1024;
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing(backend, max_blob_size).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    let contents: Vec<u8> = (0..16 * 1024 * 1024).map(|_| rand::random::<u8>()).collect();
+    snapshot_files(&fam, vec![("large", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    let out = env::temp_dir().join(format!("hat-budget-{}", rand::random::<u64>()));
+
+    // Not even room for one chunk.
+    let options = RestoreOptions {
+        memory_budget: Some(MemoryBudget::new(max_blob_size)),
+        ..RestoreOptions::default()
+    };
+    assert!(
+        hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+            .is_err()
+    );
+
+    // A sixteenth of the file.
+    let budget = MemoryBudget::new(4 * max_blob_size);
+    let options = RestoreOptions {
+        memory_budget: Some(budget.clone()),
+        ..RestoreOptions::default()
+    };
+    let (conflicts, peak) = peak_allocated(|| {
+        hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+            .unwrap()
+    });
+    assert!(conflicts.is_empty());
+    assert!(budget.peak() <= budget.limit());
+    assert!(
+        peak <= budget.limit(),
+        "{} bytes allocated at once, over the budget of {}",
+        peak,
+        budget.limit()
+    );
+
+    let mut restored = vec![];
+    fs::File::open(out.join("large")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == contents);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn restore_numeric_ids() {
+    use libc;
+    use std::os::unix::fs::MetadataExt;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let root = env::temp_dir().join(format!("hat-numeric-ids-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a"), b"aaa");
+
+    // Only root can give a file to someone else; others keep their own ids.
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if is_root {
+        super::set_owner(&root.join("a"), 1234, 5678).unwrap();
+    }
+    let meta = fs::metadata(root.join("a")).unwrap();
+    let (uid, gid) = (meta.uid(), meta.gid());
+
+    fam.snapshot_dir_with_options(root.clone(), SnapshotOptions::default()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-numeric-ids-out-{}", rand::random::<u64>()));
+    let options = RestoreOptions {
+        numeric_ids: true,
+        ..RestoreOptions::default()
+    };
+    hat.checkout_in_dir_with_options("familyname".to_owned(), out.clone(), &options)
+        .unwrap();
+    let restored = out.join(root.strip_prefix("/").unwrap()).join("a");
+    let meta = fs::metadata(&restored).unwrap();
+    assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+    fs::remove_dir_all(&out).unwrap();
+
+    // By default, whoever restores owns the files.
+    if is_root {
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        let meta = fs::metadata(&restored).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (0, 0));
+        fs::remove_dir_all(&out).unwrap();
+    }
+    fs::remove_dir_all(root).unwrap();
+}
+
+fn verify_checkpoint() -> PathBuf {
+    env::temp_dir().join(format!("hat-verify-{}", rand::random::<u64>()))
+}
+
+#[test]
+fn verify_resumes_where_it_stopped() {
+    let backend = Arc::new(RecordingBackend {
+        inner: MemoryBackend::new(),
+        retrieved: Mutex::new(vec![]),
+    });
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(
+        &fam,
+        vec![
+            ("a", vec![1; 1000]),
+            ("b", vec![2; 1000]),
+            ("c/d", vec![3; 1000]),
+            ("c/e", vec![4; 1000]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let checkpoint = verify_checkpoint();
+    let first = hat.verify(&checkpoint, false, Some(2)).unwrap();
+    assert_eq!(first.verified, 2);
+    assert!(!first.complete);
+
+    // The second run only reads what the first one did not get to.
+    backend.retrieved.lock().unwrap().clear();
+    let second = hat.verify(&checkpoint, true, None).unwrap();
+    assert!(second.complete);
+    assert!(!second.restarted);
+    assert_eq!(second.skipped, 2);
+    assert!(second.failures.is_empty());
+    assert!(backend.retrieved.lock().unwrap().len() as u64 <= second.verified);
+
+    // Together they covered every chunk.
+    let full = hat.verify(&checkpoint, false, None).unwrap();
+    assert_eq!(full.verified, first.verified + second.verified);
+    assert_eq!(full.skipped, 0);
+
+    // Nothing is left to do after a complete run.
+    let again = hat.verify(&checkpoint, true, None).unwrap();
+    assert_eq!(again.verified, 0);
+    assert_eq!(again.skipped, full.verified);
+
+    fs::remove_file(checkpoint).unwrap();
+}
+
+#[test]
+fn verify_starts_over_after_gc() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let checkpoint = verify_checkpoint();
+    assert!(hat.verify(&checkpoint, false, None).unwrap().complete);
+
+    hat.deregister(&fam, 1).unwrap();
+    let (deleted, _) = hat.gc().unwrap();
+    assert!(deleted > 0);
+
+    let report = hat.verify(&checkpoint, true, None).unwrap();
+    assert!(report.restarted);
+    assert_eq!(report.skipped, 0);
+
+    fs::remove_file(checkpoint).unwrap();
+}
+
+#[test]
+fn verify_starts_over_after_rewrite() {
+    let (_, mut hat, blob) = setup_rewrite();
+
+    let checkpoint = verify_checkpoint();
+    assert!(hat.verify(&checkpoint, false, None).unwrap().complete);
+
+    assert!(hat.rewrite_blob(blob.id).unwrap() > 0);
+
+    let report = hat.verify(&checkpoint, true, None).unwrap();
+    assert!(report.restarted);
+    assert_eq!(report.skipped, 0);
+
+    fs::remove_file(checkpoint).unwrap();
+}
+
+#[test]
+fn verify_parallel_attributes_failures() {
+    let backend = Arc::new(MemoryBackend::new());
+    // Blobs that hold a single full chunk each.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let chunks: Vec<Vec<u8>> = (1..4).map(|i| vec![i; key::CHUNK_SIZE]).collect();
+    snapshot_files(
+        &fam,
+        vec![
+            ("a", vec![7; 1000]),
+            ("b/c", chunks.concat()),
+            ("d", vec![8; key::CHUNK_SIZE]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Damage the blob of the second chunk of b/c.
+    let hash = hash::Hash::new(
+        &hat.keys,
+        blob::NodeType::Leaf,
+        blob::LeafType::FileChunk,
+        &chunks[1][..],
+    );
+    let name = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name;
+    let mut bytes = backend.retrieve(&name[..]).unwrap().unwrap();
+    bytes[10] ^= 1;
+    backend.delete(&name[..]).unwrap();
+    backend.store(&name[..], &crypto::CipherText::new(bytes)).unwrap();
+    let in_damaged_blob = hat.hash_index
+        .list()
+        .into_iter()
+        .filter(|e| e.persistent_ref.as_ref().map_or(false, |r| r.blob_name == name))
+        .count() as u64;
+
+    let serial_checkpoint = verify_checkpoint();
+    let serial = hat.verify(&serial_checkpoint, false, None).unwrap();
+    let checkpoint = verify_checkpoint();
+    let report = hat.verify_parallel(&checkpoint, false, None, 4).unwrap();
+    assert!(report.complete);
+    assert_eq!(report.failed, in_damaged_blob);
+    assert_eq!(report.passed + report.failed, report.verified);
+    assert_eq!(report.verified, serial.verified);
+
+    // Nothing is reordered: the checkpoints are the same as that of a serial run.
+    assert_eq!(report.failures, serial.failures);
+    let mut serial_lines = String::new();
+    fs::File::open(&serial_checkpoint).unwrap().read_to_string(&mut serial_lines).unwrap();
+    let mut lines = String::new();
+    fs::File::open(&checkpoint).unwrap().read_to_string(&mut lines).unwrap();
+    assert_eq!(lines, serial_lines);
+
+    // The damaged chunk is found in the file it belongs to, and only there.
+    let hex = hash.bytes.to_hex();
+    let locations: Vec<&FailedChunk> = report.locations.iter().filter(|l| l.hash == hex).collect();
+    assert_eq!(locations.len(), 1);
+    assert_eq!(locations[0].path, PathBuf::from("b/c"));
+    assert_eq!(locations[0].chunk, 1);
+    assert_eq!(locations[0].family, "familyname");
+    let failed: HashSet<&String> = report.failures.iter().map(|&(ref hash, _)| hash).collect();
+    assert!(report.locations.iter().all(|l| failed.contains(&l.hash)));
+
+    // A parallel run can be resumed by another.
+    let first = hat.verify_parallel(&checkpoint, false, Some(3), 4).unwrap();
+    assert!(!first.complete);
+    let second = hat.verify_parallel(&checkpoint, true, None, 4).unwrap();
+    assert!(second.complete);
+    assert_eq!(first.verified + second.verified, report.verified);
+    assert_eq!(second.failures.len(), report.failures.len());
+
+    fs::remove_file(serial_checkpoint).unwrap();
+    fs::remove_file(checkpoint).unwrap();
+}
+
+#[test]
+fn scrub_checks_least_recently_verified_chunks() {
+    use chrono::{self, TimeZone};
+    use util::Clock;
+
+    let (_, mut hat, mut fam) = setup_family();
+    let clock = Arc::new(FakeClock::new(chrono::Utc.timestamp(1500000000, 0)));
+    hat.set_clock(clock.clone());
+    snapshot_files(
+        &fam,
+        vec![
+            ("a", vec![1; 1000]),
+            ("b", vec![2; 1000]),
+            ("c/d", vec![3; 1000]),
+            ("c/e", vec![4; 1000]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let options = ScrubOptions {
+        period_runs: 3,
+        concurrency: 2,
+    };
+    let first = hat.scrub(&options).unwrap();
+    let total = first.total;
+    let per_run = (total + 2) / 3;
+    assert!(total > 3);
+    for run in 1..4 {
+        let report = if run == 1 { first.clone() } else { hat.scrub(&options).unwrap() };
+        assert_eq!(report.verified, per_run);
+        assert_eq!(report.passed, report.verified);
+        assert!(report.failures.is_empty());
+        // Chunks never checked go first, so a period of runs gets to all of them.
+        assert_eq!(report.never_verified, total.saturating_sub(run * per_run));
+        clock.advance(chrono::Duration::days(1));
+    }
+    let mut before = hat.hash_index.list_verified();
+    assert_eq!(before.len() as u64, total);
+
+    // After that, each run checks the chunks that went longest without a check.
+    before.sort_by_key(|&(id, utc)| (utc, id));
+    let oldest: HashSet<u64> = before.iter().take(per_run as usize).map(|&(id, _)| id).collect();
+    let report = hat.scrub(&options).unwrap();
+    assert_eq!(report.never_verified, 0);
+    assert!(report.oldest_verified_utc.unwrap() >= before[0].1);
+    let now = clock.now().timestamp();
+    let rechecked: HashSet<u64> = hat.hash_index
+        .list_verified()
+        .into_iter()
+        .filter(|&(_, utc)| utc == now)
+        .map(|(id, _)| id)
+        .collect();
+    assert_eq!(rechecked, oldest);
+}
+
+#[test]
+fn checkout_checks_snapshot_key() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-checkout-{}", rand::random::<u64>()));
+    let right_keys = hat.keys.clone();
+
+    // Another key is refused before anything is decrypted.
+    hat.keys = Arc::new(crypto::keys::Keeper::new_for_testing_with_key(vec![1; 32]));
+    match hat.checkout_in_dir("familyname".to_owned(), out.clone()) {
+        Err(HatError::WrongKey(e)) => {
+            assert_eq!(e.snapshot_key_id, right_keys.key_id());
+            assert!(format!("{}", e).contains("Wrong key for this snapshot"));
+        }
+        _ => panic!("expected wrong key error"),
+    }
+    assert!(!out.join("a").exists());
+
+    hat.keys = right_keys;
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let mut contents = vec![];
+    fs::File::open(out.join("a")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, vec![1; 1000]);
+
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn checkout_picks_snapshot_keys_from_keyring() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    // Keys read into a keyring do not have the salt of the store; it is added to them.
+    hat.enable_hash_salt().unwrap();
+    let salt = hat.db.lock().store_hash_salt().unwrap();
+    let old_keys = hat.keys.clone();
+    let old_store = hat.blob_store.clone();
+
+    let mut fam = hat.open_family("old".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Move the store to a new key; the old snapshot stays sealed with the old one.
+    let unsalted_new_keys = crypto::keys::Keeper::new_for_testing_with_key(vec![1; 32]);
+    let new_keys = Arc::new(unsalted_new_keys.with_hash_salt(&salt[..]));
+    hat.keys = new_keys.clone();
+    hat.blob_store = Arc::new(blob::BlobStore::new(
+        new_keys.clone(),
+        hat.blob_index.clone(),
+        backend.clone(),
+        4 * 1024 * 1024,
+    ));
+    hat.families.clear();
+    let mut fam = hat.open_family("new".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let read = |hat: &mut HatRc<MemoryBackend>, family: &str, name: &str| {
+        let out = env::temp_dir().join(format!("hat-keyring-{}", rand::random::<u64>()));
+        let res = hat.checkout_in_dir(family.to_owned(), out.clone()).map(|_| {
+            let mut contents = vec![];
+            fs::File::open(out.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        });
+        let _ = fs::remove_dir_all(out);
+        res
+    };
+
+    // Without a keyring, only the snapshot with our own key can be read.
+    assert_eq!(read(&mut hat, "new", "b").unwrap(), vec![2; 1000]);
+    match read(&mut hat, "old", "a") {
+        Err(HatError::WrongKey(e)) => assert_eq!(e.snapshot_key_id, old_keys.key_id()),
+        _ => panic!("expected wrong key error"),
+    }
+
+    // With both keys in the keyring, each snapshot is read with its own, from either side.
+    let mut keyring = Keyring::new();
+    assert!(keyring.lookup(&old_keys.key_id()).is_none());
+    keyring.insert(Arc::new(crypto::keys::Keeper::new_for_testing())).unwrap();
+    keyring.insert(Arc::new(unsalted_new_keys)).unwrap();
+    hat.set_keyring(keyring.clone());
+    assert_eq!(read(&mut hat, "old", "a").unwrap(), vec![1; 1000]);
+    assert_eq!(read(&mut hat, "new", "b").unwrap(), vec![2; 1000]);
+
+    // Prefetching walks the old snapshot with its own keys as well.
+    hat.set_blob_cache_size(16 * 1024 * 1024);
+    assert!(hat.prefetch("old".to_owned(), &["a"]).unwrap().wait().unwrap() > 0);
+
+    hat.keys = old_keys;
+    hat.blob_store = old_store;
+    hat.families.clear();
+    hat.set_keyring(keyring);
+    assert_eq!(read(&mut hat, "old", "a").unwrap(), vec![1; 1000]);
+    assert_eq!(read(&mut hat, "new", "b").unwrap(), vec![2; 1000]);
+}
+
+#[test]
+fn store_version_protection() {
+    let db = db::Index::new_for_testing();
+
+    // Opening a store does not mark it.
+    check_store_version(&db, READER_VERSION).unwrap();
+    assert_eq!(db.lock().store_min_reader_version(), None);
+
+    // An older reader refuses to touch a store marked for a newer one.
+    db.lock().store_set_min_reader_version(MIN_READER_VERSION);
+    match check_store_version(&db, MIN_READER_VERSION - 1) {
+        Err(HatError::StoreVersion(e)) => {
+            assert_eq!(e.required, MIN_READER_VERSION);
+            assert_eq!(e.supported, MIN_READER_VERSION - 1);
+        }
+        _ => panic!("expected store version error"),
+    }
+
+    // A newer reader proceeds without changing the requirement.
+    check_store_version(&db, READER_VERSION + 1).unwrap();
+    assert_eq!(db.lock().store_min_reader_version(), Some(MIN_READER_VERSION));
+}
+
+#[test]
+fn encrypted_filenames_need_a_newer_reader() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    hat.set_encrypt_filenames(true).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    let required = StoreInfo::read(&*backend, &hat.keys).unwrap().unwrap().min_reader_version;
+    assert_eq!(required, ENCRYPTED_NAMES_READER_VERSION);
+
+    snapshot_files(&fam, vec![("secret.txt", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(ENCRYPTED_NAMES_READER_VERSION));
+    assert_eq!(restored_names(&mut hat), vec!["secret.txt".to_string()]);
+}
+
+#[test]
+fn sharded_store_needs_a_newer_reader() {
+    let root = env::temp_dir().join(format!("hat-sharded-store-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let backend = Arc::new(FileBackend::new(root.clone()).with_shard_depth(1));
+    backend.check_shard_depth().unwrap();
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), None);
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(SHARDED_READER_VERSION));
+    let required = StoreInfo::read(&*backend, &hat.keys).unwrap().unwrap().min_reader_version;
+    assert_eq!(required, SHARDED_READER_VERSION);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn chunk_pipeline_needs_a_newer_reader() {
+    let (backend, mut hat, _) = setup_family();
+    assert!(hat.set_chunk_pipeline("gzip").is_err());
+    hat.set_chunk_pipeline("gzip+seal").unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+
+    snapshot_files(&fam, vec![("zeros", vec![0; 100000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(PIPELINE_READER_VERSION));
+    let required = StoreInfo::read(&*backend, &hat.keys).unwrap().unwrap().min_reader_version;
+    assert_eq!(required, PIPELINE_READER_VERSION);
+
+    // The chunks were compressed, and read back as the file.
+    assert!(hat.hash_index.list().iter().any(|e| {
+        let pipeline = e.persistent_ref.as_ref().and_then(|r| r.pipeline.clone());
+        pipeline == Some("gzip+seal".to_string())
+    }));
+    let out = env::temp_dir().join(format!("hat-pipeline-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(fs::read(out.join("zeros")).unwrap(), vec![0; 100000]);
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn padded_blobs_need_a_newer_reader() {
+    let (backend, mut hat, _) = setup_family();
+    hat.set_record_padding(true).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+
+    snapshot_files(&fam, vec![("padded", vec![7; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(PADDED_READER_VERSION));
+    let required = StoreInfo::read(&*backend, &hat.keys).unwrap().unwrap().min_reader_version;
+    assert_eq!(required, PADDED_READER_VERSION);
+
+    let out = env::temp_dir().join(format!("hat-padded-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(fs::read(out.join("padded")).unwrap(), vec![7; 1000]);
+    fs::remove_dir_all(out).unwrap();
+}
+
+#[test]
+fn store_version_is_kept_in_backend() {
+    let (backend, mut hat, mut fam) = setup_family();
+    let stored = |backend: &MemoryBackend, hat: &HatRc<MemoryBackend>| {
+        StoreInfo::read(backend, &hat.keys).unwrap().map(|i| i.min_reader_version)
+    };
+
+    // Only writing marks the store, in the index and in the backend.
+    assert_eq!(stored(&backend, &hat), None);
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(hat.db.lock().store_min_reader_version(), Some(MIN_READER_VERSION));
+    assert_eq!(stored(&backend, &hat), Some(MIN_READER_VERSION));
+
+    // The settings are not taken for a blob, and the mark is recovered with the store.
+    let mut hat2 = setup_hat(backend.clone());
+    hat2.recover().unwrap();
+    assert_eq!(hat2.db.lock().store_min_reader_version(), Some(MIN_READER_VERSION));
+    for name in backend.list().unwrap() {
+        if store_info_generation(&name).is_some() {
+            assert!(hat2.blob_index.find(&name).is_none());
+        }
+    }
+
+    // A store marked for a newer reader is refused by recover as well.
+    let newer = StoreInfo {
+        min_reader_version: READER_VERSION + 1,
+        hash_salt: None,
+    };
+    newer.write(&*backend, &hat.keys).unwrap();
+    let err = setup_hat(backend).recover().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::StoreVersion);
+}
+
+/// Backend that damages every blob it stores while `corrupt` is set.
+struct CorruptingBackend {
+    inner: MemoryBackend,
+    corrupt: AtomicBool,
+}
+
+impl StoreBackend for CorruptingBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        if self.corrupt.load(Ordering::SeqCst) {
+            let mut bytes = data.to_vec();
+            let middle = bytes.len() / 2;
+            bytes[middle] ^= 1;
+            self.inner.store(name, &crypto::CipherText::new(bytes))
+        } else {
+            self.inner.store(name, data)
+        }
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn list_page(&self, token: Option<&[u8]>) -> Result<ListPage, String> {
+        self.inner.list_page(token)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+fn setup_rewrite() -> (Arc<CorruptingBackend>, HatRc<CorruptingBackend>, blob::BlobDesc) {
+    let backend = Arc::new(CorruptingBackend {
+        inner: MemoryBackend::new(),
+        corrupt: AtomicBool::new(false),
+    });
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let blob_id = hat.hash_index
+        .list()
+        .into_iter()
+        .filter_map(|e| e.persistent_ref.and_then(|r| r.blob_id))
+        .find(|id| *id > 0)
+        .unwrap();
+    let blob = hat.blob_store.find_by_id(blob_id).unwrap();
+    (backend, hat, blob)
+}
+
+fn checkout_file(hat: &mut HatRc<CorruptingBackend>, name: &str) -> Vec<u8> {
+    let out = env::temp_dir().join(format!("hat-rewrite-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let mut contents = vec![];
+    fs::File::open(out.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+    fs::remove_dir_all(out).unwrap();
+    contents
+}
+
+#[test]
+fn rewrite_blob_moves_live_chunks() {
+    let (backend, mut hat, blob) = setup_rewrite();
+
+    assert!(hat.rewrite_blob(blob.id).unwrap() > 0);
+    assert!(backend.retrieve(&blob.name[..]).unwrap().is_none());
+    assert!(hat.blob_store.find_by_id(blob.id).is_none());
+
+    // The snapshot is read through the new copies.
+    assert_eq!(checkout_file(&mut hat, "a"), vec![1; 1000]);
+    assert_eq!(checkout_file(&mut hat, "b"), vec![2; 1000]);
+
+    // So is it after recovering from the backend alone.
+    let mut recovered = setup_hat(backend);
+    recovered.recover().unwrap();
+    assert_eq!(checkout_file(&mut recovered, "a"), vec![1; 1000]);
+    assert_eq!(checkout_file(&mut recovered, "b"), vec![2; 1000]);
+}
+
+#[test]
+fn rekey_moves_chunks_to_another_algorithm() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend.clone());
+    hat.blob_store.set_seal_algorithm("chacha20poly1305").unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let sealed_refs = |hat: &HatRc<MemoryBackend>| -> Vec<hash::tree::HashRef> {
+        hat.hash_index
+            .list()
+            .into_iter()
+            .filter_map(|e| {
+                let (hash, node, leaf) = (e.hash, e.node, e.leaf);
+                e.persistent_ref.and_then(|r| if r.key.is_some() {
+                    Some(hash::tree::HashRef {
+                        hash: hash,
+                        node: node,
+                        leaf: leaf,
+                        persistent_ref: r,
+                        info: None,
+                    })
+                } else {
+                    None
+                })
+            })
+            .collect()
+    };
+    let before = sealed_refs(&hat);
+    assert!(!before.is_empty());
+    for href in &before {
+        assert_eq!(href.persistent_ref.key.as_ref().unwrap().algorithm(), "chacha20poly1305");
+    }
+
+    let mut progress = vec![];
+    let report = hat.rekey("chacha20poly1305-committed", false, |r| progress.push(r.blobs_rewritten))
+        .unwrap();
+    assert!(report.complete);
+    assert_eq!(report.chunks_rekeyed, before.len() as u64);
+    assert_eq!(progress.last(), Some(&report.blobs_total));
+
+    // The old blobs are gone, and every chunk opens only as the new algorithm.
+    for href in &before {
+        assert!(backend.retrieve(&href.persistent_ref.blob_name[..]).unwrap().is_none());
+    }
+    for href in sealed_refs(&hat) {
+        let key = href.persistent_ref.key.clone().unwrap();
+        assert_eq!(key.algorithm(), "chacha20poly1305-committed");
+        assert!(hat.blob_store.retrieve(&href).unwrap().is_some());
+
+        let mut as_old = href.clone();
+        as_old.persistent_ref.key = Some(blob::Key::AeadChacha20Poly1305(match key {
+            blob::Key::AeadChacha20Poly1305Committed(k) => k,
+            blob::Key::AeadChacha20Poly1305(_) => unreachable!(),
+        }));
+        assert!(hat.blob_store.retrieve(&as_old).is_err());
+    }
+
+    let mut out = vec![];
+    hat.cat("familyname".to_owned(), "b", &mut out).unwrap();
+    assert_eq!(out, vec![2; 1000]);
+
+    // Running again finds nothing left to do.
+    let report = hat.rekey("chacha20poly1305-committed", false, |_| ()).unwrap();
+    assert!(report.complete);
+    assert_eq!(report.blobs_total, 0);
+    assert!(hat.rekey("rot13", true, |_| ()).is_err());
+
+    // Going back to chunks without key commitment has to be asked for.
+    assert!(hat.rekey("chacha20poly1305", false, |_| ()).is_err());
+    let report = hat.rekey("chacha20poly1305", true, |_| ()).unwrap();
+    assert!(report.complete);
+    assert!(report.blobs_rewritten > 0);
+
+    // The snapshot still checks out after recovering from the backend alone.
+    let mut recovered = setup_hat(backend);
+    recovered.recover().unwrap();
+    let mut out = vec![];
+    recovered.cat("familyname".to_owned(), "b", &mut out).unwrap();
+    assert_eq!(out, vec![2; 1000]);
+}
+
+#[test]
+fn rewrite_blob_keeps_original_if_copy_is_corrupt() {
+    let (backend, mut hat, blob) = setup_rewrite();
+
+    backend.corrupt.store(true, Ordering::SeqCst);
+    assert!(hat.rewrite_blob(blob.id).is_err());
+    backend.corrupt.store(false, Ordering::SeqCst);
+
+    // Nothing was switched over, and the original is still there.
+    assert!(backend.retrieve(&blob.name[..]).unwrap().is_some());
+    assert_eq!(checkout_file(&mut hat, "a"), vec![1; 1000]);
+    assert_eq!(checkout_file(&mut hat, "b"), vec![2; 1000]);
+}
+
+#[test]
+fn consolidate_merges_small_blobs() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    // Every commit leaves a few blobs that are mostly empty.
+    let mut files = vec![];
+    for i in 0..10 {
+        files.push((format!("file{}", i), vec![i as u8; 1000 + i]));
+        let last = files.last().unwrap();
+        snapshot_files(&fam, vec![(&last.0[..], last.1.clone())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    }
+    let blobs_before = backend.list().unwrap().len();
+    let mut blob_before = HashMap::new();
+    for entry in hat.hash_index.list() {
+        if let Some(id) = entry.persistent_ref.and_then(|r| r.blob_id) {
+            blob_before.insert(entry.hash.bytes, id);
+        }
+    }
+
+    let report = hat.consolidate_blobs(32 * 1024).unwrap();
+    assert!(report.complete);
+    assert!(report.blobs_merged >= 10);
+    assert!(backend.list().unwrap().len() < blobs_before);
+
+    // Every chunk moved, and unseals from its new place.
+    for entry in hat.hash_index.list() {
+        let href = hash::tree::HashRef {
+            hash: entry.hash.clone(),
+            node: entry.node,
+            leaf: entry.leaf,
+            info: None,
+            persistent_ref: entry.persistent_ref.unwrap(),
+        };
+        let before = blob_before[&href.hash.bytes];
+        if before > 0 {
+            assert!(href.persistent_ref.blob_id != Some(before));
+        }
+        let chunk = hat.blob_store.retrieve(&href).unwrap().unwrap();
+        assert_eq!(hash::Hash::new(&hat.keys, href.node, href.leaf, &chunk[..]), href.hash);
+    }
+
+    // The snapshots check out, also after recovering from the backend alone.
+    let mut recovered = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    recovered.recover().unwrap();
+    for hat in vec![&mut hat, &mut recovered] {
+        let out = env::temp_dir().join(format!("hat-consolidate-{}", rand::random::<u64>()));
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        for &(ref name, ref contents) in files.iter() {
+            let mut restored = vec![];
+            fs::File::open(out.join(name)).unwrap().read_to_end(&mut restored).unwrap();
+            assert_eq!(&restored, contents);
+        }
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    // Nothing is left to merge.
+    assert_eq!(hat.consolidate_blobs(32 * 1024).unwrap().blobs_merged, 0);
+}
+
+#[test]
+fn audit_log_chains_every_mutation() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    for i in 0..3 {
+        let name = format!("file{}", i);
+        snapshot_files(&fam, vec![(&name[..], vec![i as u8; 1000 + i])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.data_flush().unwrap();
+    }
+    assert!(hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+    hat.gc().unwrap();
+    assert!(hat.consolidate_blobs(32 * 1024).unwrap().blobs_merged > 0);
+
+    // Deleting also commits a new listing of the snapshots, which is logged like any commit.
+    let log = hat.audit_log();
+    let family_id = log[0].affected[0];
+    for (i, entry) in log[..3].iter().enumerate() {
+        assert_eq!(entry.operation, audit::SNAPSHOT_COMMIT);
+        assert_eq!(entry.affected, vec![family_id, i as i64 + 1]);
+    }
+    let position = |operation: &str| log.iter().position(|e| e.operation == operation).unwrap();
+    assert_eq!(log[position(audit::SNAPSHOT_DELETE)].affected, vec![family_id, 1]);
+    assert!(position(audit::SNAPSHOT_DELETE) < position(audit::GC));
+    assert!(position(audit::GC) < position(audit::COMPACTION));
+    for (i, entry) in log.iter().enumerate() {
+        assert_eq!(entry.seq, i as i64 + 1);
+    }
+
+    let head = hat.verify_audit_log().unwrap();
+    assert_eq!(head, log.last().unwrap().chain);
+    // A log cut short is whole, but ends on another chain hash.
+    assert!(audit::verify(&hat.keys, &log[..4]).unwrap() != head);
+
+    let mut changed = log.clone();
+    changed[1].affected[1] = 3;
+    assert!(audit::verify(&hat.keys, &changed[..]).is_err());
+
+    let mut removed = log.clone();
+    removed.remove(1);
+    assert!(audit::verify(&hat.keys, &removed[..]).is_err());
+
+    let mut renumbered = removed.clone();
+    for (i, entry) in renumbered.iter_mut().enumerate() {
+        entry.seq = i as i64 + 1;
+    }
+    assert!(audit::verify(&hat.keys, &renumbered[..]).is_err());
+
+    let mut inserted = log.clone();
+    inserted.insert(2, log[1].clone());
+    assert!(audit::verify(&hat.keys, &inserted[..]).is_err());
+}
+
+#[test]
+fn empty_files_back_up_and_restore() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(
+        &fam,
+        vec![
+            ("empty", vec![]),
+            ("dir/empty", vec![]),
+            ("dir/full", vec![7; 100]),
+            ("hollow/", vec![]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let mut out: Vec<u8> = vec![];
+    assert_eq!(hat.cat("familyname".to_owned(), "empty", &mut out).unwrap(), 0);
+    assert_eq!(hat.cat("familyname".to_owned(), "dir/empty", &mut out).unwrap(), 0);
+    assert!(out.is_empty());
+
+    let dir = env::temp_dir().join(format!("hat-empty-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), dir.clone()).unwrap();
+    let read = |name: &str| {
+        let mut contents = vec![];
+        fs::File::open(dir.join(name)).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    };
+    assert!(read("empty").is_empty());
+    assert!(read("dir/empty").is_empty());
+    assert_eq!(read("dir/full"), vec![7; 100]);
+    assert!(dir.join("hollow").is_dir());
+    assert_eq!(fs::read_dir(dir.join("hollow")).unwrap().count(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sha256_manifest_matches_sha256sum() {
+    use std::process::Command;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_file_digests(true).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    let big: Vec<u8> = (0..300000).map(|_| rand::random::<u8>()).collect();
+    let files = vec![
+        ("a", "hello\n".as_bytes().to_vec()),
+        ("dir/big", big),
+        ("dir/sub/empty", vec![]),
+        ("z", vec![7; 1000]),
+    ];
+    snapshot_files(&fam, files.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    let digests = hat.file_digests("familyname".to_owned()).unwrap();
+    assert_eq!(digests.len(), files.len());
+    assert!(digests.iter().all(|d| d.sha256.is_some()));
+    let manifest = to_sha256sum(&digests[..]);
+
+    // The same files on disk, hashed by the standard tool.
+    let dir = env::temp_dir().join(format!("hat-sha256-{}", rand::random::<u64>()));
+    for &(name, ref contents) in files.iter() {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(path).unwrap().write_all(&contents[..]).unwrap();
+    }
+    let output = Command::new("sha256sum")
+        .args(files.iter().map(|&(name, _)| name))
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    assert_eq!(manifest, String::from_utf8(output.stdout).unwrap());
+}
+
+#[test]
+fn errors_have_stable_kinds() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-checkout-{}", rand::random::<u64>()));
+    let right_keys = hat.keys.clone();
+    hat.keys = Arc::new(crypto::keys::Keeper::new_for_testing_with_key(vec![1; 32]));
+    let e = hat.checkout_in_dir("familyname".to_owned(), out).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::WrongKey);
+    assert_eq!(e.kind().exit_code(), 9);
+    hat.keys = right_keys;
+
+    let db = db::Index::new_for_testing();
+    db.lock().store_set_min_reader_version(READER_VERSION + 1);
+    let e = check_store_version(&db, READER_VERSION).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::StoreVersion);
+    assert_eq!(e.kind().exit_code(), 8);
+
+    let e = HatError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+    assert_eq!(e.kind(), ErrorKind::Io);
+
+    let e = hat.blob_info(12345).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::Other);
+    let report = ErrorReport::from(&e).with_context("blob_id", 12345);
+    assert!(report.message.contains("No blob with id 12345"));
+    assert!(format!("{}", report).ends_with(" (blob_id: 12345)"));
+
+    let report = ErrorReport::new(ErrorKind::Backend, "Upload of \"a\\b\" failed\n")
+        .with_context("blob_id", 7);
+    assert_eq!(
+        report.to_json(),
+        "{\"code\":4,\"kind\":\"backend\",\"message\":\"Upload of \\\"a\\\\b\\\" failed\\n\",\
+         \"context\":{\"blob_id\":\"7\"}}"
+    );
+}
+
+fn commit_dir(hat: &mut HatRc<MemoryBackend>, fam: &mut Family<MemoryBackend>, root: &PathBuf) {
+    fam.snapshot_dir(root.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(fam, None).unwrap();
+    hat.data_flush().unwrap();
+}
+
+#[test]
+fn commit_reuses_unchanged_dir_listings() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-incremental-{}", rand::random::<u64>()));
+    for dir in &["a/x", "a/y", "b"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    let root = fs::canonicalize(root).unwrap();
+    write_file(&root.join("a/x/f1"), b"one");
+    write_file(&root.join("a/y/f2"), b"two");
+    write_file(&root.join("b/f3"), b"three");
+
+    // The directories above `root` change as other tests use the temp dir; only look below.
+    let snapshot_root = root.strip_prefix("/").unwrap().to_owned();
+    let below_root = |dirs: &Vec<PathBuf>| {
+        let mut dirs: Vec<String> = dirs.iter()
+            .filter_map(|d| d.strip_prefix(&snapshot_root).ok())
+            .map(|d| d.display().to_string())
+            .collect();
+        dirs.sort();
+        dirs
+    };
+
+    commit_dir(&mut hat, &mut fam, &root);
+    assert_eq!(below_root(&fam.commit_stats.rebuilt_dirs), vec!["", "a", "a/x", "a/y", "b"]);
+    assert!(below_root(&fam.commit_stats.reused_dirs).is_empty());
+
+    // Change one file, without touching the directories it is in.
+    let f1 = root.join("a/x/f1");
+    let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(&f1).unwrap());
+    write_file(&f1, b"changed");
+    let later =
+        filetime::FileTime::from_seconds_since_1970(mtime.seconds_relative_to_1970() + 10, 0);
+    filetime::set_file_times(&f1, later, later).unwrap();
+
+    commit_dir(&mut hat, &mut fam, &root);
+    assert_eq!(below_root(&fam.commit_stats.rebuilt_dirs), vec!["", "a", "a/x"]);
+    assert_eq!(below_root(&fam.commit_stats.reused_dirs), vec!["a/y", "b"]);
+
+    commit_dir(&mut hat, &mut fam, &root);
+    assert!(below_root(&fam.commit_stats.rebuilt_dirs).is_empty());
+
+    // The reused listings keep their data alive on their own.
+    assert!(hat.delete_snapshot("familyname".to_owned(), 1).unwrap());
+    assert!(hat.delete_snapshot("familyname".to_owned(), 2).unwrap());
+    hat.gc().unwrap();
+
+    let out = env::temp_dir().join(format!("hat-incremental-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let restored = out.join(&snapshot_root);
+    let expected = vec![
+        ("a/x/f1", &b"changed"[..]),
+        ("a/y/f2", &b"two"[..]),
+        ("b/f3", &b"three"[..]),
+    ];
+    for (path, contents) in expected {
+        let mut data = vec![];
+        fs::File::open(restored.join(path)).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], contents);
+    }
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
+
+/// A backend that cannot be reached at all.
+struct UnreachableBackend;
+
+impl StoreBackend for UnreachableBackend {
+    fn store(&self, _name: &[u8], _data: &crypto::CipherText) -> Result<(), String> {
+        Err("connection refused".to_owned())
+    }
+
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Err("connection refused".to_owned())
+    }
+
+    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+        Err("connection refused".to_owned())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        Err("connection refused".to_owned())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn doctor_statuses(checks: &[doctor::DoctorCheck]) -> Vec<(&'static str, CheckStatus)> {
+    checks.iter().map(|c| (c.name, c.status)).collect()
+}
+
+#[test]
+fn doctor_reports_per_check() {
+    use chrono::{self, TimeZone};
+
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // A healthy store.
+    let checks = doctor::run_checks(
+        Some(hat.keys.clone()),
+        Ok(hat.db.clone()),
+        &*backend,
+        &SystemClock,
+    );
+    assert_eq!(
+        doctor_statuses(&checks[..]),
+        vec![
+            ("backend", CheckStatus::Pass),
+            ("index", CheckStatus::Pass),
+            ("blobs", CheckStatus::Pass),
+            ("clock", CheckStatus::Pass),
+        ]
+    );
+    assert!(checks.iter().all(|c| c.error.is_none()));
+
+    // A clock that runs behind the snapshots.
+    let slow = FakeClock::new(chrono::Utc.timestamp(1500000000, 0));
+    let checks = doctor::run_checks(Some(hat.keys.clone()), Ok(hat.db.clone()), &*backend, &slow);
+    assert_eq!(checks[3].status, CheckStatus::Warn);
+
+    // A backend that cannot be reached; the blobs cannot be checked either.
+    let checks = doctor::run_checks(
+        Some(hat.keys.clone()),
+        Ok(hat.db.clone()),
+        &UnreachableBackend,
+        &SystemClock,
+    );
+    assert_eq!(
+        doctor_statuses(&checks[..]),
+        vec![
+            ("backend", CheckStatus::Fail),
+            ("index", CheckStatus::Pass),
+            ("blobs", CheckStatus::Warn),
+            ("clock", CheckStatus::Pass),
+        ]
+    );
+    assert_eq!(checks[0].error.as_ref().unwrap().kind, ErrorKind::Backend);
+
+    // An index that is missing, and one that is not a database.
+    let root = env::temp_dir().join(format!("hat-doctor-{}", rand::random::<u64>()));
+    fs::create_dir_all(&root).unwrap();
+    let missing = doctor::open_index(Path::new("migrations"), root.clone());
+    assert_eq!(missing.as_ref().err().unwrap().kind(), ErrorKind::Io);
+
+    write_file(&root.join("hash_index.sqlite3"), &[0xff; 4096]);
+    let garbage = doctor::open_index(Path::new("migrations"), root.clone());
+    let checks = doctor::run_checks(Some(hat.keys.clone()), garbage, &*backend, &SystemClock);
+    assert_eq!(
+        doctor_statuses(&checks[..]),
+        vec![
+            ("backend", CheckStatus::Pass),
+            ("index", CheckStatus::Fail),
+            ("blobs", CheckStatus::Warn),
+            ("clock", CheckStatus::Warn),
+        ]
+    );
+    assert_eq!(checks[1].error.as_ref().unwrap().kind, ErrorKind::Index);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn cat_writes_file_contents() {
+    let (_, mut hat, mut fam) = setup_family();
+    // Every byte value, line endings included, and more than one chunk.
+    let contents: Vec<u8> = (0..2 * key::CHUNK_SIZE + 1000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(
+        &fam,
+        vec![("dir/sub/data.bin", contents.clone()), ("empty", vec![])],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut out = vec![];
+    let written = hat.cat("familyname".to_owned(), "dir/sub/data.bin", &mut out).unwrap();
+    assert_eq!(written, contents.len() as u64);
+    assert!(out == contents);
+
+    let mut read = vec![];
+    hat.open_file("familyname".to_owned(), "/dir//sub/data.bin")
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert!(read == contents);
+
+    let mut out = vec![];
+    assert_eq!(hat.cat("familyname".to_owned(), "empty", &mut out).unwrap(), 0);
+    assert!(out.is_empty());
+
+    assert!(hat.cat("familyname".to_owned(), "dir/sub", &mut vec![]).is_err());
+    assert!(hat.cat("familyname".to_owned(), "dir/missing", &mut vec![]).is_err());
+}
+
+#[test]
+fn cat_fails_on_corrupt_chunk() {
+    let backend = Arc::new(MemoryBackend::new());
+    // Blobs that hold a single full chunk each.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let chunks: Vec<Vec<u8>> = (1..4).map(|i| vec![i; key::CHUNK_SIZE]).collect();
+    snapshot_files(&fam, vec![("file", chunks.concat())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Damage the blob of the second chunk.
+    let hash = hash::Hash::new(
+        &hat.keys,
+        blob::NodeType::Leaf,
+        blob::LeafType::FileChunk,
+        &chunks[1][..],
+    );
+    let name = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name;
+    let mut bytes = backend.retrieve(&name[..]).unwrap().unwrap();
+    bytes[10] ^= 1;
+    backend.delete(&name[..]).unwrap();
+    backend.store(&name[..], &crypto::CipherText::new(bytes)).unwrap();
+
+    let mut out = vec![];
+    let err = hat.cat("familyname".to_owned(), "file", &mut out).unwrap_err();
+    assert_eq!(ErrorReport::from(&err).kind, ErrorKind::Crypto);
+    assert!(ErrorReport::from(&err).kind.exit_code() != 0);
+    // The first chunk was written before the damage was found.
+    assert!(out == chunks[0]);
+
+    let mut read = vec![];
+    let mut reader = hat.open_file("familyname".to_owned(), "file").unwrap();
+    assert!(reader.read_to_end(&mut read).is_err());
+}
+
+#[test]
+fn incremental_commits_read_only_changed_files() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let root = env::temp_dir().join(format!("hat-time-machine-{}", rand::random::<u64>()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let root = fs::canonicalize(root).unwrap();
+    let files = vec![root.join("a"), root.join("b"), root.join("sub").join("c")];
+    let base = filetime::FileTime::from_seconds_since_1970(1_500_000_000, 0);
+    for (i, file) in files.iter().enumerate() {
+        write_file(file, &vec![i as u8; 1000][..]);
+        filetime::set_file_times(file, base, base).unwrap();
+    }
+
+    let first = hat.commit_incremental(&mut fam, root.clone(), SnapshotOptions::default())
+        .unwrap();
+    assert_eq!(first.parent, None);
+    assert_eq!(first.stats.files, 3);
+    assert_eq!(first.stats.read_files, files);
+
+    let mut previous = first.snapshot_id;
+    for (run, file) in files.iter().enumerate() {
+        write_file(file, format!("run {}", run).as_bytes());
+        let later = filetime::FileTime::from_seconds_since_1970(
+            base.seconds_relative_to_1970() + run as u64 + 1,
+            0,
+        );
+        filetime::set_file_times(file, later, later).unwrap();
+
+        let done = hat.commit_incremental(&mut fam, root.clone(), SnapshotOptions::default())
+            .unwrap();
+        assert_eq!(done.stats.files, 3);
+        assert_eq!(done.stats.read_files, vec![file.clone()]);
+        assert_eq!(done.parent, Some(previous));
+        assert_eq!(hat.snapshot_parent("familyname", done.snapshot_id), Some(previous));
+        previous = done.snapshot_id;
+    }
+    assert_eq!(hat.snapshot_parent("familyname", first.snapshot_id), None);
+
+    let diffs = hat.compare_to_source("familyname".to_owned(), root.clone()).unwrap();
+    assert!(diffs.is_empty());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn checkout_reads_mapped_blobs() {
+    let blobs = env::temp_dir().join(format!("hat-mmap-blobs-{}", rand::random::<u64>()));
+    fs::create_dir_all(&blobs).unwrap();
+    let backend = Arc::new(FileBackend::new(blobs.clone()).with_mmap_reads(true));
+    let mut hat = HatRc::new_for_testing(backend.clone(), 200 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+
+    // Spread over many blobs, and with no two chunks alike.
+    let contents: Vec<u8> = (0..2_000_000u64)
+        .map(|i| (i * 7919 % 251) as u8 ^ (i >> 16) as u8)
+        .collect();
+    snapshot_files(&fam, vec![("large", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    assert!(backend.list().unwrap().len() > 10);
+
+    let before = backend.buffered_reads();
+    let out = env::temp_dir().join(format!("hat-mmap-out-{}", rand::random::<u64>()));
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let mut restored = vec![];
+    fs::File::open(out.join("large")).unwrap().read_to_end(&mut restored).unwrap();
+    assert!(restored == contents);
+    // Every blob was read through its mapping.
+    assert_eq!(backend.buffered_reads(), before);
+
+    // Without mapping, the same blobs are read into buffers.
+    let copying = FileBackend::new(blobs.clone());
+    let name = backend.list().unwrap().pop().unwrap();
+    let mapped = backend.retrieve_bytes(&name[..]).unwrap().unwrap();
+    let copied = copying.retrieve(&name[..]).unwrap().unwrap();
+    assert_eq!(&mapped[..], &copied[..]);
+    assert_eq!(copying.buffered_reads(), 1);
+
+    // A mapped blob outlives the file it was mapped from.
+    backend.delete(&name[..]).unwrap();
+    assert_eq!(copying.exists(&name[..]), Ok(false));
+    assert_eq!(&mapped[..], &copied[..]);
+
+    fs::remove_dir_all(&blobs).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+/// Backend that fails to return blobs while `fail` is set.
+struct FailingReadBackend {
+    inner: MemoryBackend,
+    fail: AtomicBool,
+}
+
+impl StoreBackend for FailingReadBackend {
+    fn store(&self, name: &[u8], data: &crypto::CipherText) -> Result<(), String> {
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.fail.load(Ordering::SeqCst) {
+            Err("connection reset by peer".to_owned())
+        } else {
+            self.inner.retrieve(name)
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn backup_errors_keep_their_source() {
+    let backend = Arc::new(FailingReadBackend {
+        inner: MemoryBackend::new(),
+        fail: AtomicBool::new(false),
+    });
+    let mut hat = setup_hat(backend.clone());
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let contents = vec![7; 1000];
+    snapshot_files(&fam, vec![("file", contents.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // A failing fetch surfaces as the backend's own error.
+    backend.fail.store(true, Ordering::SeqCst);
+    let mut out = vec![];
+    let err = BackupError::from(hat.cat("familyname".to_owned(), "file", &mut out).unwrap_err());
+    match err {
+        BackupError::Backend(_) => (),
+        ref other => panic!("Not a backend error: {:?}", other),
+    }
+    assert_eq!(
+        err.find_source::<BackendError>().unwrap().message,
+        "connection reset by peer"
+    );
+    assert!(err.find_source::<crypto::CryptoError>().is_none());
+    let report = ErrorReport::from(&err);
+    assert_eq!(report.kind, ErrorKind::Backend);
+    assert!(report.message.contains("connection reset by peer"));
+
+    // Damaged data surfaces as a crypto error.
+    backend.fail.store(false, Ordering::SeqCst);
+    let hash = hash::Hash::new(
+        &hat.keys,
+        blob::NodeType::Leaf,
+        blob::LeafType::FileChunk,
+        &contents[..],
+    );
+    let name = hat.hash_index.fetch_persistent_ref(&hash).unwrap().unwrap().blob_name;
+    let mut bytes = backend.retrieve(&name[..]).unwrap().unwrap();
+    bytes[10] ^= 1;
+    backend.delete(&name[..]).unwrap();
+    backend.store(&name[..], &crypto::CipherText::new(bytes)).unwrap();
+
+    let err = BackupError::from(hat.cat("familyname".to_owned(), "file", &mut out).unwrap_err());
+    match err {
+        BackupError::Crypto(_) => (),
+        ref other => panic!("Not a crypto error: {:?}", other),
+    }
+    assert!(err.find_source::<crypto::CryptoError>().is_some());
+    assert!(err.find_source::<BackendError>().is_none());
+    assert_eq!(ErrorReport::from(&err).kind, ErrorKind::Crypto);
+}
+
+#[test]
+fn index_export_recovers_damaged_index() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1; 1000]), ("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let snapshots = hat.snapshot_index.list_all().len();
+
+    let export = env::temp_dir().join(format!("hat-index-export-{}", rand::random::<u64>()));
+    hat.export_index(&export).unwrap();
+
+    // A damaged export is refused before the live index is touched.
+    let mut bytes = fs::read(&export).unwrap();
+    let damaged = export.with_extension("damaged");
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    fs::write(&damaged, &bytes[..]).unwrap();
+    assert!(hat.import_index(&damaged).is_err());
+    assert_eq!(hat.snapshot_index.list_all().len(), snapshots);
+    fs::remove_file(&damaged).unwrap();
+
+    // Lose the snapshots and hashes.
+    for s in hat.snapshot_index.list_all() {
+        hat.db.lock().snapshot_delete(s.info);
+    }
+    for e in hat.hash_index.list() {
+        let id = hat.hash_index.get_id(&e.hash).unwrap();
+        hat.hash_index.delete(id);
+    }
+    hat.db.lock().flush();
+    assert!(hat.snapshot_index.list_all().is_empty());
+    let mut out: Vec<u8> = vec![];
+    assert!(hat.cat("familyname".to_owned(), "a", &mut out).is_err());
+
+    hat.import_index(&export).unwrap();
+    fs::remove_file(&export).unwrap();
+    assert_eq!(hat.snapshot_index.list_all().len(), snapshots);
+    out.clear();
+    hat.cat("familyname".to_owned(), "a", &mut out).unwrap();
+    assert_eq!(out, vec![1; 1000]);
+
+    // New data does not collide with what the imported index knows about.
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("c", vec![3; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+    let mut out = vec![];
+    hat.cat("familyname".to_owned(), "b", &mut out).unwrap();
+    assert_eq!(out, vec![2; 1000]);
+}
+
+#[test]
+fn prefetched_files_are_read_from_cache() {
+    let backend = Arc::new(RecordingBackend {
+        inner: MemoryBackend::new(),
+        retrieved: Mutex::new(vec![]),
+    });
+    // Small blobs, so that every file ends up in a blob of its own.
+    let mut hat = HatRc::new_for_testing(backend.clone(), 1536).unwrap();
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    snapshot_files(
+        &fam,
+        vec![("a", vec![1; 600]), ("b", vec![2; 600]), ("dir/c", vec![3; 600])],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // Without room in the cache, nothing is kept.
+    let prefetch = hat.prefetch("familyname".to_owned(), &["a"]).unwrap();
+    assert_eq!(prefetch.wait().unwrap(), 0);
+    assert_eq!(hat.blob_store.blob_cache_memory(), 0);
+
+    hat.set_blob_cache_size(1024 * 1024);
+    let prefetch = hat.prefetch("familyname".to_owned(), &["a", "dir/c"]).unwrap();
+    assert!(prefetch.wait().unwrap() > 0);
+
+    backend.retrieved.lock().unwrap().clear();
+    for &(path, byte) in &[("a", 1), ("dir/c", 3)] {
+        let mut out = vec![];
+        hat.cat("familyname".to_owned(), path, &mut out).unwrap();
+        assert_eq!(out, vec![byte; 600]);
+    }
+    assert!(backend.retrieved.lock().unwrap().is_empty());
+    assert!(hat.blob_store.blob_cache_memory() <= 1024 * 1024);
+
+    assert!(hat.prefetch("familyname".to_owned(), &["missing"]).is_err());
+}
+
+#[test]
+fn checkout_without_snapshots_is_an_error() {
+    let (_, mut hat, _) = setup_family();
+    let out = env::temp_dir().join(format!("hat-empty-{}", rand::random::<u64>()));
+    assert!(hat.checkout_in_dir("familyname".to_owned(), out.clone()).is_err());
+    assert!(hat.checkout_to_tar("familyname".to_owned(), vec![]).is_err());
+    assert!(!out.exists());
+}
+
+#[test]
+fn checkout_to_tar_writes_every_entry() {
+    let (_, mut hat, mut fam) = setup_family();
+    let long_name: String = (0..120).map(|_| 'l').collect();
+    let long_path = format!("dir/{}", long_name);
+    let big: Vec<u8> = (0..100000).map(|_| rand::random::<u8>()).collect();
+    snapshot_files(
+        &fam,
+        vec![
+            ("a", b"hello\n".to_vec()),
+            ("empty", vec![]),
+            ("dir/big", big.clone()),
+            (&long_path[..], vec![1; 513]),
+            ("hollow/", vec![]),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let tar = hat.checkout_to_tar("familyname".to_owned(), vec![]).unwrap();
+    assert_eq!(tar.len() % 512, 0);
+
+    let octal = |field: &[u8]| {
+        let digits = str::from_utf8(field).unwrap().trim_matches(|c| c == '\0' || c == ' ');
+        u64::from_str_radix(digits, 8).unwrap()
+    };
+    let mut members = HashMap::new();
+    let mut long_path_record = None;
+    let mut pos = 0;
+    loop {
+        let header = &tar[pos..pos + 512];
+        pos += 512;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        assert_eq!(&header[257..263], b"ustar\0");
+        let sum: u64 = header[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&header[156..])
+            .map(|&b| u64::from(b))
+            .sum();
+        assert_eq!(octal(&header[148..156]), sum);
+
+        let name_len = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let mut name = str::from_utf8(&header[..name_len]).unwrap().to_owned();
+        let size = octal(&header[124..136]) as usize;
+        let contents = tar[pos..pos + size].to_vec();
+        pos += (size + 511) / 512 * 512;
+
+        if header[156] == b'x' {
+            let record = str::from_utf8(&contents[..]).unwrap().to_owned();
+            long_path_record = Some(record.split("path=").nth(1).unwrap().trim_right().to_owned());
+            continue;
+        }
+        if let Some(path) = long_path_record.take() {
+            name = path;
+        }
+        members.insert(name, (header[156], octal(&header[100..108]), contents));
+    }
+    assert!(tar[pos..].iter().all(|&b| b == 0));
+
+    assert_eq!(members.len(), 6);
+    assert_eq!(members["a"], (b'0', 0o644, b"hello\n".to_vec()));
+    assert_eq!(members["empty"], (b'0', 0o644, vec![]));
+    assert_eq!(members["dir/"], (b'5', 0o755, vec![]));
+    assert_eq!(members["dir/big"], (b'0', 0o644, big));
+    assert_eq!(members[&long_path], (b'0', 0o644, vec![1; 513]));
+    assert_eq!(members["hollow/"], (b'5', 0o755, vec![]));
+}
@@ -0,0 +1,96 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Remembers the newest root of a store that this host has seen, outside of the store.
+//!
+//! Every root carries a sequence number one higher than the root before it, sealed along with
+//! the rest of the root, so that it can not be changed without the keys. Someone who can write
+//! to the store can still put back an older root and the blobs it points to, each of which
+//! authenticates fine on its own. The anchor catches this: the older root's sequence number is
+//! lower than one this host has seen before.
+
+use errors::{HatError, RollbackError};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+
+pub struct TrustAnchor {
+    path: Option<PathBuf>,
+    seen: u64,
+}
+
+impl TrustAnchor {
+    /// The anchor kept in the file at `path`. Without that file, no root has been seen yet.
+    pub fn open(path: PathBuf) -> Result<TrustAnchor, HatError> {
+        let mut contents = String::new();
+        let seen = match fs::File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)?;
+                contents.trim().parse::<u64>().map_err(|_| {
+                    format!("Trust anchor {} is not a root sequence number", path.display())
+                })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(From::from(e)),
+        };
+        Ok(TrustAnchor {
+            path: Some(path),
+            seen: seen,
+        })
+    }
+
+    /// An anchor that is forgotten with the process.
+    pub fn in_memory() -> TrustAnchor {
+        TrustAnchor {
+            path: None,
+            seen: 0,
+        }
+    }
+
+    /// Sequence number of the newest root seen.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Refuse a root with sequence number `found` if a newer root has been seen.
+    pub fn check(&self, found: u64) -> Result<(), HatError> {
+        if found < self.seen {
+            return Err(From::from(RollbackError {
+                seen: self.seen,
+                found: found,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Remember that the root with sequence number `seen` was committed or read. The file is
+    /// replaced as a whole, so that it always holds either the old or the new number.
+    pub fn advance(&mut self, seen: u64) -> Result<(), HatError> {
+        if seen <= self.seen {
+            return Ok(());
+        }
+        if let Some(ref path) = self.path {
+            let tmp = path.with_extension("tmp");
+            {
+                let mut file = fs::File::create(&tmp)?;
+                writeln!(file, "{}", seen)?;
+                file.sync_all()?;
+            }
+            fs::rename(&tmp, path)?;
+        }
+        self.seen = seen;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-directory space accounting for snapshots.
+//!
+//! Only directory listings and the hash index are consulted; file contents are never fetched.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker;
+use key;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+
+#[derive(Clone, Debug)]
+pub struct DirUsage {
+    /// Path of the directory relative to the snapshot root.
+    pub path: PathBuf,
+    pub depth: usize,
+
+    /// Sum of the file sizes in this directory and below.
+    pub logical_bytes: u64,
+    /// Stored bytes of chunks in this directory and below that no other snapshot references.
+    /// A chunk shared by several files of the snapshot is only attributed to the first one
+    /// visited.
+    pub unique_bytes: u64,
+}
+
+pub struct UsageCounter<'a> {
+    hash_index: &'a hash::HashIndex,
+    max_depth: Option<usize>,
+    /// Hash ids referenced by other snapshots, which are never counted as unique.
+    shared: HashSet<u64>,
+    seen: HashSet<u64>,
+    dirs: Vec<DirUsage>,
+}
+
+impl<'a> UsageCounter<'a> {
+    /// A counter for one snapshot; `shared` holds every hash id other snapshots reach.
+    pub fn new(
+        hash_index: &'a hash::HashIndex,
+        max_depth: Option<usize>,
+        shared: HashSet<u64>,
+    ) -> UsageCounter<'a> {
+        UsageCounter {
+            hash_index: hash_index,
+            max_depth: max_depth,
+            shared: shared,
+            seen: HashSet::new(),
+            dirs: vec![],
+        }
+    }
+
+    /// All directories visited so far, largest first.
+    pub fn into_dirs(self) -> Vec<DirUsage> {
+        let mut dirs = self.dirs;
+        dirs.sort_by(|a, b| {
+            b.logical_bytes.cmp(&a.logical_bytes).then(a.path.cmp(&b.path))
+        });
+        dirs
+    }
+
+    /// Sum the stored length of all nodes in the tree below `hash` that have not been seen yet
+    /// and are not shared. Everything below a shared node is shared too.
+    fn unseen_bytes(&mut self, hash: &hash::Hash) -> u64 {
+        let mut total = 0;
+        let mut queue: Vec<u64> = self.hash_index.get_id(hash).into_iter().collect();
+        while let Some(id) = queue.pop() {
+            if !self.seen.insert(id) || self.shared.contains(&id) {
+                continue;
+            }
+            if let Some(entry) = self.hash_index.get_hash(id) {
+                if let Some(ref pref) = entry.persistent_ref {
+                    total += pref.length as u64;
+                }
+                if let Some(childs) = entry.childs {
+                    queue.extend(childs);
+                }
+            }
+        }
+        total
+    }
+
+    /// Walk the directory tree below `dir_ref`, recording a `DirUsage` for every directory no
+    /// deeper than `max_depth`. Deeper directories are still counted towards their ancestors.
+    /// Returns the totals for `dir_ref` itself.
+    pub fn walk<B: StoreBackend>(
+        &mut self,
+        family: &Family<B>,
+        backend: &key::HashStoreBackend<B>,
+        dir_ref: hash::tree::HashRef,
+        path: PathBuf,
+        depth: usize,
+    ) -> Result<DirUsage, HatError> {
+        let mut usage = DirUsage {
+            path: path,
+            depth: depth,
+            logical_bytes: 0,
+            unique_bytes: self.unseen_bytes(&dir_ref.hash),
+        };
+
+        for (entry, content) in family.fetch_dir_data(dir_ref, backend.clone())? {
+            match content {
+                walker::Content::Data(href) => {
+                    usage.logical_bytes += entry.info.byte_length.unwrap_or(0);
+                    usage.unique_bytes += self.unseen_bytes(&href.hash);
+                }
+                walker::Content::Dir(href) => {
+                    let mut sub_path = usage.path.clone();
+                    sub_path.push(&*String::from_utf8_lossy(&entry.info.name[..]));
+                    let sub = self.walk(family, backend, href, sub_path, depth + 1)?;
+                    usage.logical_bytes += sub.logical_bytes;
+                    usage.unique_bytes += sub.unique_bytes;
+                }
+                walker::Content::Link(_) => (),
+            }
+        }
+
+        if self.max_depth.map_or(true, |max| depth <= max) {
+            self.dirs.push(usage.clone());
+        }
+        Ok(usage)
+    }
+}
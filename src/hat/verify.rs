@@ -0,0 +1,230 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Progress of a store verification, kept in a checkpoint file so that it can be resumed.
+//!
+//! The file starts with a header naming the GC generation of the store it was written for,
+//! followed by one line per verified chunk: `ok <hash>` or `failed <hash> <reason>`. Lines are
+//! appended as chunks are verified, so an interrupted run loses at most the chunk it was on.
+//! Once the GC has run, chunks may have been deleted or moved, and the checkpoint no longer
+//! applies.
+//!
+//! Chunks may be read back by several threads at once, but they are recorded in the order they
+//! were listed, a batch at a time.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash;
+use hat::family::Family;
+use hat::walker;
+use hex::ToHex;
+use key;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+
+const HEADER: &'static str = "hat-verify-checkpoint 1";
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Chunks verified by this run.
+    pub verified: u64,
+    /// Chunks skipped because an earlier run verified them.
+    pub skipped: u64,
+    /// Chunks verified by this run that matched their hash.
+    pub passed: u64,
+    /// Chunks verified by this run that did not.
+    pub failed: u64,
+    /// Hashes of the chunks that failed, in this run or an earlier one, with the reason.
+    pub failures: Vec<(String, String)>,
+    /// Whether every chunk in the store has been verified.
+    pub complete: bool,
+    /// Whether an existing checkpoint was discarded, because the store changed since.
+    pub restarted: bool,
+    /// Where the failed chunks are used in complete snapshots. A chunk used by no snapshot is
+    /// not listed; one used by several is listed for each.
+    pub locations: Vec<FailedChunk>,
+}
+
+/// A place where a chunk that failed verification is used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FailedChunk {
+    pub hash: String,
+    pub family: String,
+    pub snapshot_id: u64,
+    /// The file, or directory listing, from the snapshot root.
+    pub path: PathBuf,
+    /// Position of the chunk in the file, counted in chunks from 0.
+    pub chunk: u64,
+}
+
+/// Finds the files that the failed chunks are part of, by walking snapshots.
+pub struct Attributor<'a> {
+    hash_index: &'a hash::HashIndex,
+    failed: HashSet<String>,
+    locations: Vec<FailedChunk>,
+}
+
+impl<'a> Attributor<'a> {
+    pub fn new(hash_index: &'a hash::HashIndex, failures: &[(String, String)]) -> Attributor<'a> {
+        Attributor {
+            hash_index: hash_index,
+            failed: failures.iter().map(|&(ref hash, _)| hash.clone()).collect(),
+            locations: vec![],
+        }
+    }
+
+    /// Look for the failed chunks in the directory `dir_ref` of a snapshot, found at `path`.
+    pub fn walk<B: StoreBackend>(
+        &mut self,
+        family: &Family<B>,
+        backend: &key::HashStoreBackend<B>,
+        snapshot_id: u64,
+        path: &mut PathBuf,
+        dir_ref: hash::tree::HashRef,
+    ) {
+        self.check(&family.name, snapshot_id, path, &dir_ref.hash);
+        // A listing that cannot be read has just been attributed; there is nothing below it.
+        let listing = match family.fetch_dir_data(dir_ref, backend.clone()) {
+            Ok(listing) => listing,
+            Err(_) => return,
+        };
+        for (entry, content) in listing {
+            path.push(String::from_utf8_lossy(&entry.info.name[..]).into_owned());
+            match content {
+                walker::Content::Dir(href) => self.walk(family, backend, snapshot_id, path, href),
+                walker::Content::Data(href) => {
+                    self.check(&family.name, snapshot_id, path, &href.hash)
+                }
+                walker::Content::Link(_) => (),
+            }
+            path.pop();
+        }
+    }
+
+    fn check(&mut self, family: &str, snapshot_id: u64, path: &Path, top: &hash::Hash) {
+        if self.failed.is_empty() {
+            return;
+        }
+        let leafs = match self.hash_index.leaf_hashes(top) {
+            Some(leafs) => leafs,
+            None => return,
+        };
+        for (i, leaf) in leafs.into_iter().enumerate() {
+            let hash = leaf.bytes.to_hex();
+            if self.failed.contains(&hash) {
+                self.locations.push(FailedChunk {
+                    hash: hash,
+                    family: family.to_owned(),
+                    snapshot_id: snapshot_id,
+                    path: path.to_owned(),
+                    chunk: i as u64,
+                });
+            }
+        }
+    }
+
+    pub fn into_locations(self) -> Vec<FailedChunk> {
+        self.locations
+    }
+}
+
+pub struct Checkpoint {
+    file: fs::File,
+    done: HashSet<String>,
+    failures: Vec<(String, String)>,
+}
+
+impl Checkpoint {
+    /// Start a new checkpoint at `path`, or, with `resume`, continue the one there if it was
+    /// written for the same GC generation. Also returns whether an existing checkpoint was
+    /// discarded.
+    pub fn open(
+        path: &Path,
+        generation: i64,
+        resume: bool,
+    ) -> Result<(Checkpoint, bool), HatError> {
+        let header = format!("{} {}", HEADER, generation);
+        if resume && path.exists() {
+            let mut lines = BufReader::new(fs::File::open(path)?).lines();
+            let matches = match lines.next() {
+                Some(Ok(line)) => line == header,
+                _ => false,
+            };
+            if matches {
+                let mut checkpoint = Checkpoint {
+                    file: fs::OpenOptions::new().append(true).open(path)?,
+                    done: HashSet::new(),
+                    failures: vec![],
+                };
+                for line in lines {
+                    let line = line?;
+                    let mut parts = line.splitn(3, ' ');
+                    match (parts.next(), parts.next(), parts.next()) {
+                        (Some("ok"), Some(hash), None) => {
+                            checkpoint.done.insert(hash.to_owned());
+                        }
+                        (Some("failed"), Some(hash), reason) => {
+                            checkpoint.done.insert(hash.to_owned());
+                            checkpoint.failures.push(
+                                (hash.to_owned(), reason.unwrap_or("").to_owned()),
+                            );
+                        }
+                        // Left behind by an interrupted write.
+                        _ => (),
+                    }
+                }
+                return Ok((checkpoint, false));
+            }
+        }
+
+        let restarted = resume && path.exists();
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", header)?;
+        Ok((
+            Checkpoint {
+                file: file,
+                done: HashSet::new(),
+                failures: vec![],
+            },
+            restarted,
+        ))
+    }
+
+    pub fn is_done(&self, hash: &hash::Hash) -> bool {
+        self.done.contains(&hash.bytes.to_hex())
+    }
+
+    pub fn record_ok(&mut self, hash: &hash::Hash) -> Result<(), HatError> {
+        let hash = hash.bytes.to_hex();
+        writeln!(self.file, "ok {}", hash)?;
+        self.done.insert(hash);
+        Ok(())
+    }
+
+    pub fn record_failure(&mut self, hash: &hash::Hash, reason: &str) -> Result<(), HatError> {
+        let hash = hash.bytes.to_hex();
+        let reason = reason.replace('\n', " ");
+        writeln!(self.file, "failed {} {}", hash, reason)?;
+        self.done.insert(hash.clone());
+        self.failures.push((hash, reason));
+        Ok(())
+    }
+
+    pub fn failures(&self) -> &[(String, String)] {
+        &self.failures[..]
+    }
+}
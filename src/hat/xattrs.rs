@@ -0,0 +1,132 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extended attributes of files, read for snapshots and written back on restore.
+//!
+//! POSIX ACLs need no handling of their own: Linux keeps them as the `system.posix_acl_access`
+//! and `system.posix_acl_default` attributes. Symbolic links are not followed. Without the
+//! `xattrs` feature, on a platform without extended attributes or on a filesystem without them,
+//! calls fail with an error that `is_unsupported` recognizes.
+
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
+
+
+/// `EOPNOTSUPP` on Linux: the filesystem does not support extended attributes.
+const EOPNOTSUPP: i32 = 95;
+
+static UNSUPPORTED_WARNED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Warn that attributes cannot be kept, once per process however many files are affected.
+pub fn warn_unsupported(path: &Path, err: &io::Error) {
+    if !UNSUPPORTED_WARNED.swap(true, Ordering::SeqCst) {
+        warn!(
+            "Extended attributes are not kept, starting at {}: {}{}",
+            path.display(),
+            err,
+            if cfg!(feature = "xattrs") {
+                ""
+            } else {
+                " (built without the xattrs feature)"
+            }
+        );
+    }
+}
+
+/// Whether `err` means that extended attributes are unavailable, rather than that one failed.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(EOPNOTSUPP)
+}
+
+#[cfg(feature = "xattrs")]
+fn unsupported_platform() -> bool {
+    !::xattr::SUPPORTED_PLATFORM
+}
+
+#[cfg(not(feature = "xattrs"))]
+fn unsupported_platform() -> bool {
+    true
+}
+
+fn unsupported() -> io::Error {
+    io::Error::from_raw_os_error(EOPNOTSUPP)
+}
+
+/// The extended attributes of `path` as (name, value), sorted by name.
+pub fn read(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if unsupported_platform() {
+        return Err(unsupported());
+    }
+    read_attributes(path)
+}
+
+#[cfg(feature = "xattrs")]
+fn read_attributes(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut out = vec![];
+    for name in ::xattr::list(path)? {
+        // An attribute removed since it was listed is simply not kept.
+        if let Some(value) = ::xattr::get(path, &name)? {
+            out.push((name.as_bytes().to_vec(), value));
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+#[cfg(not(feature = "xattrs"))]
+fn read_attributes(_path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Err(unsupported())
+}
+
+/// Set `attrs` on `path`. Attributes that cannot be set, e.g. `security.*` ones without the
+/// privilege to, are skipped with a warning; only the lack of support is returned as an error.
+pub fn restore(path: &Path, attrs: &[(Vec<u8>, Vec<u8>)]) -> io::Result<()> {
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    if unsupported_platform() {
+        return Err(unsupported());
+    }
+    for &(ref name, ref value) in attrs {
+        match set_attribute(path, name, value) {
+            Ok(()) => (),
+            Err(ref e) if is_unsupported(e) => return Err(unsupported()),
+            Err(e) => {
+                warn!(
+                    "Could not restore extended attribute {} of {}: {}",
+                    String::from_utf8_lossy(name),
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "xattrs")]
+fn set_attribute(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    ::xattr::set(path, OsStr::from_bytes(name), value)
+}
+
+#[cfg(not(feature = "xattrs"))]
+fn set_attribute(_path: &Path, _name: &[u8], _value: &[u8]) -> io::Result<()> {
+    Err(unsupported())
+}
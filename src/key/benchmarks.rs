@@ -199,6 +199,10 @@ fn insert_1_key_updated_empty(bench: &mut Bencher) {
                     permissions: None,
                     byte_length: None,
                     hat_snapshot_ts: 0,
+                    sha256: None,
+                    extended_attributes: vec![],
+                    holes: vec![],
+                    content_not_captured: false,
                 },
             },
         };
@@ -0,0 +1,596 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting file contents into chunks.
+//!
+//! By default files are cut into chunks of `CHUNK_SIZE` bytes. The rolling chunker instead cuts
+//! where a Buzhash over the last `window_size` bytes has its low bits zero, so that an insertion
+//! early in a file only changes the chunks around it. Its parameters decide every boundary:
+//! data chunked with different parameters does not deduplicate, which is why the parameters are
+//! recorded with each snapshot (see `Chunker::describe`).
+//!
+//! Boundary hints let the rolling chunker follow the structure of file formats it recognizes:
+//! a cut the format suggests is taken over the hash whenever it falls between the minimum and
+//! maximum chunk size. Files in other formats are chunked by the hash alone.
+
+use crypto::RefKey;
+use key::CHUNK_SIZE;
+use key::tar_hint::TarHint;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+
+/// A file format whose structure suggests where to cut files in it.
+pub trait BoundaryHint: Send + Sync {
+    /// Name of the format, as given in `RollingParams::hints`.
+    fn name(&self) -> &'static str;
+
+    /// Start following a file that begins with `head`, which holds at least the first
+    /// `max_size` bytes of the file if it has that many. `None` if the file is not in this format.
+    fn follow(&self, head: &[u8]) -> Option<Box<BoundaryCursor>>;
+}
+
+/// Finds the preferred cuts in one file.
+pub trait BoundaryCursor: Send {
+    /// Preferred cuts, as offsets into the file in increasing order, that have not been returned
+    /// before. `data` starts `offset` bytes into the file; later calls never go back before it.
+    fn boundaries(&mut self, offset: u64, data: &[u8]) -> Vec<u64>;
+}
+
+/// The hint for the format called `name`.
+pub fn boundary_hint(name: &str) -> Option<Box<BoundaryHint>> {
+    match name {
+        "tar" => Some(Box::new(TarHint)),
+        _ => None,
+    }
+}
+
+
+/// Most that sealing should add to a chunk, as a share of its size, before chunks count as too
+/// small for their overhead.
+pub const MAX_SEAL_OVERHEAD: f64 = 0.01;
+
+/// The smallest chunk that sealing with the chunk key algorithm `algorithm` grows by at most
+/// `max_overhead` of its size. `None` for an unknown algorithm.
+pub fn suggested_min_chunk_size(algorithm: &str, max_overhead: f64) -> Option<usize> {
+    assert!(max_overhead > 0.0);
+    RefKey::seal_overhead(algorithm).map(|overhead| {
+        (overhead as f64 / max_overhead).ceil() as usize
+    })
+}
+
+
+/// Parameters of the rolling chunker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RollingParams {
+    /// Number of bytes the rolling hash covers.
+    pub window_size: usize,
+    /// Seed for the table of byte values the hash is built from.
+    pub table_seed: u64,
+    pub min_size: usize,
+    /// Expected distance between cut points after `min_size`; must be a power of two.
+    pub avg_size: usize,
+    pub max_size: usize,
+    /// Names of the formats to follow the structure of, see `boundary_hint`.
+    pub hints: Vec<String>,
+}
+
+impl Default for RollingParams {
+    fn default() -> RollingParams {
+        RollingParams {
+            window_size: 48,
+            table_seed: 0,
+            min_size: 32 * 1024,
+            avg_size: 128 * 1024,
+            max_size: 512 * 1024,
+            hints: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chunker {
+    /// Chunks of exactly this many bytes, except for the last one of a file.
+    Fixed(usize),
+    /// Content-defined chunks.
+    Rolling(RollingParams),
+}
+
+impl Default for Chunker {
+    fn default() -> Chunker {
+        Chunker::Fixed(CHUNK_SIZE)
+    }
+}
+
+impl Chunker {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Chunker::Fixed(0) => Err("Chunk size must be positive".to_owned()),
+            Chunker::Fixed(_) => Ok(()),
+            Chunker::Rolling(ref p) => {
+                if p.window_size == 0 || p.window_size > p.min_size {
+                    Err(format!(
+                        "Window size must be between 1 and the minimum chunk size ({})",
+                        p.min_size
+                    ))
+                } else if !p.avg_size.is_power_of_two() {
+                    Err(format!("Average chunk size {} is not a power of two", p.avg_size))
+                } else if p.min_size > p.avg_size || p.avg_size > p.max_size {
+                    Err("Chunk sizes must satisfy min <= avg <= max".to_owned())
+                } else if let Some(name) = p.hints.iter().find(|h| boundary_hint(h).is_none()) {
+                    Err(format!("Unknown boundary hint {:?}", name))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Stable text form of the chunker, to record which one produced a snapshot.
+    pub fn describe(&self) -> String {
+        match *self {
+            Chunker::Fixed(size) => format!("fixed:{}", size),
+            Chunker::Rolling(ref p) => {
+                let mut text = format!(
+                    "buzhash:window={},seed={},min={},avg={},max={}",
+                    p.window_size,
+                    p.table_seed,
+                    p.min_size,
+                    p.avg_size,
+                    p.max_size
+                );
+                if !p.hints.is_empty() {
+                    text.push_str(&format!(",hints={}", p.hints.join("+")));
+                }
+                text
+            }
+        }
+    }
+
+    /// Parse the output of `describe`.
+    pub fn parse(text: &str) -> Result<Chunker, String> {
+        let invalid = || format!("Invalid chunker: {:?}", text);
+        let mut parts = text.splitn(2, ':');
+        let chunker = match (parts.next(), parts.next()) {
+            (Some("fixed"), Some(size)) => Chunker::Fixed(size.parse().map_err(|_| invalid())?),
+            (Some("buzhash"), Some(params)) => {
+                let mut p = RollingParams::default();
+                for param in params.split(',') {
+                    let mut kv = param.splitn(2, '=');
+                    let (key, value) = match (kv.next(), kv.next()) {
+                        (Some(key), Some(value)) => (key, value),
+                        _ => return Err(invalid()),
+                    };
+                    match key {
+                        "window" => p.window_size = value.parse().map_err(|_| invalid())?,
+                        "seed" => p.table_seed = value.parse().map_err(|_| invalid())?,
+                        "min" => p.min_size = value.parse().map_err(|_| invalid())?,
+                        "avg" => p.avg_size = value.parse().map_err(|_| invalid())?,
+                        "max" => p.max_size = value.parse().map_err(|_| invalid())?,
+                        "hints" => p.hints = value.split('+').map(|h| h.to_owned()).collect(),
+                        _ => return Err(invalid()),
+                    }
+                }
+                Chunker::Rolling(p)
+            }
+            _ => return Err(invalid()),
+        };
+        chunker.validate()?;
+        Ok(chunker)
+    }
+
+    /// Smallest chunk this chunker cuts before the end of a file.
+    pub fn min_chunk_size(&self) -> usize {
+        match *self {
+            Chunker::Fixed(size) => size,
+            Chunker::Rolling(ref p) => p.min_size,
+        }
+    }
+
+    /// Fail, suggesting a larger size, if this chunker cuts chunks so small that sealing them
+    /// with `algorithm` adds more than `max_overhead` of their size.
+    pub fn check_overhead(&self, algorithm: &str, max_overhead: f64) -> Result<(), String> {
+        let suggested = match suggested_min_chunk_size(algorithm, max_overhead) {
+            Some(size) => size,
+            None => return Err(format!("Unknown key algorithm: {}", algorithm)),
+        };
+        if self.min_chunk_size() >= suggested {
+            return Ok(());
+        }
+        Err(format!(
+            "Chunks of {} bytes grow by more than {}% when sealed with {}; use at least {} bytes",
+            self.min_chunk_size(),
+            max_overhead * 100.0,
+            algorithm,
+            suggested
+        ))
+    }
+
+    /// Largest chunk this chunker produces.
+    pub fn max_chunk_size(&self) -> usize {
+        match *self {
+            Chunker::Fixed(size) => size,
+            Chunker::Rolling(ref p) => p.max_size,
+        }
+    }
+
+    /// Split the contents of `reader` into chunks.
+    pub fn chunks<R: Read>(&self, reader: R) -> Chunks<R> {
+        let (table, hints) = match *self {
+            Chunker::Fixed(_) => (vec![], vec![]),
+            Chunker::Rolling(ref p) => {
+                (
+                    buzhash_table(p.table_seed),
+                    p.hints.iter().filter_map(|h| boundary_hint(h)).collect(),
+                )
+            }
+        };
+        Chunks {
+            chunker: self.clone(),
+            table: table,
+            reader: reader,
+            pending: Vec::new(),
+            eof: false,
+            hints: hints,
+            cursor: None,
+            offset: 0,
+            preferred: VecDeque::new(),
+        }
+    }
+
+    /// Length of the first chunk of `data`, which holds all data that is left if it is at most
+    /// `max_chunk_size()` long. `preferred` are the cuts suggested by a boundary hint, as
+    /// increasing offsets into `data`.
+    fn cut(&self, table: &[u64], data: &[u8], preferred: &[usize]) -> usize {
+        let end = cmp::min(data.len(), self.max_chunk_size());
+        let p = match *self {
+            Chunker::Fixed(_) => return end,
+            Chunker::Rolling(ref p) => p,
+        };
+        if end <= p.min_size {
+            return end;
+        }
+        if let Some(&at) = preferred.iter().find(|&&at| at >= p.min_size && at <= end) {
+            return at;
+        }
+
+        let mask = (p.avg_size - 1) as u64;
+        let out_rotation = (p.window_size % 64) as u32;
+        let mut hash = 0u64;
+        // Only the last window before `min_size` matters for the first possible cut.
+        let start = p.min_size - p.window_size;
+        for i in start..end {
+            hash = hash.rotate_left(1) ^ table[data[i] as usize];
+            if i >= start + p.window_size {
+                hash ^= table[data[i - p.window_size] as usize].rotate_left(out_rotation);
+            }
+            if i + 1 >= p.min_size && hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Byte values for the Buzhash, from a SplitMix64 sequence.
+fn buzhash_table(seed: u64) -> Vec<u64> {
+    let mut state = seed;
+    (0..256)
+        .map(|_| {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        })
+        .collect()
+}
+
+pub struct Chunks<R> {
+    chunker: Chunker,
+    table: Vec<u64>,
+    reader: R,
+    pending: Vec<u8>,
+    eof: bool,
+    // Hints that may recognize the file, until it has been given to them.
+    hints: Vec<Box<BoundaryHint>>,
+    cursor: Option<Box<BoundaryCursor>>,
+    // Where `pending` starts in the file.
+    offset: u64,
+    // Cuts suggested by `cursor` that have not been reached yet.
+    preferred: VecDeque<u64>,
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let max_len = self.chunker.max_chunk_size();
+        while !self.eof && self.pending.len() < max_len {
+            let old_len = self.pending.len();
+            self.pending.resize(max_len, 0);
+            match self.reader.read(&mut self.pending[old_len..]) {
+                Ok(0) => {
+                    self.pending.truncate(old_len);
+                    self.eof = true;
+                }
+                Ok(size) => self.pending.truncate(old_len + size),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.pending.truncate(old_len)
+                }
+                Err(e) => {
+                    self.pending.truncate(old_len);
+                    self.eof = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        if !self.hints.is_empty() {
+            let hints = ::std::mem::replace(&mut self.hints, vec![]);
+            let cursor = hints.iter().filter_map(|h| h.follow(&self.pending[..])).next();
+            self.cursor = cursor;
+        }
+        if let Some(ref mut cursor) = self.cursor {
+            self.preferred.extend(cursor.boundaries(self.offset, &self.pending[..]));
+        }
+        while self.preferred.front().map_or(false, |&at| at <= self.offset) {
+            self.preferred.pop_front();
+        }
+        let offset = self.offset;
+        let pending_len = self.pending.len() as u64;
+        let preferred: Vec<usize> = self.preferred
+            .iter()
+            .take_while(|&&at| at - offset <= pending_len)
+            .map(|&at| (at - offset) as usize)
+            .collect();
+
+        let len = self.chunker.cut(&self.table[..], &self.pending[..], &preferred[..]);
+        self.offset += len as u64;
+        let rest = self.pending.split_off(len);
+        Some(Ok(::std::mem::replace(&mut self.pending, rest)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::SEAL_ALGORITHM;
+    use std::collections::HashSet;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn small_params(window_size: usize) -> RollingParams {
+        RollingParams {
+            window_size: window_size,
+            table_seed: 7,
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+            hints: vec![],
+        }
+    }
+
+    fn boundaries<R: Read>(chunker: &Chunker, data: R) -> Vec<usize> {
+        let mut pos = 0;
+        chunker
+            .chunks(data)
+            .map(|c| {
+                pos += c.unwrap().len();
+                pos
+            })
+            .collect()
+    }
+
+    /// Hands out data a few bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = cmp::min(cmp::min(7, buf.len()), self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn fixed_boundaries() {
+        let data = test_data(300);
+        assert_eq!(boundaries(&Chunker::Fixed(128), &data[..]), vec![128, 256, 300]);
+        assert!(boundaries(&Chunker::Fixed(128), &[][..]).is_empty());
+    }
+
+    #[test]
+    fn rolling_boundaries_are_deterministic() {
+        let data = test_data(64 * 1024);
+        let chunker = Chunker::Rolling(small_params(16));
+        chunker.validate().unwrap();
+
+        let first = boundaries(&chunker, &data[..]);
+        assert_eq!(first, boundaries(&chunker, &data[..]));
+        assert_eq!(first, boundaries(&chunker, Trickle(&data[..])));
+        assert_eq!(*first.last().unwrap(), data.len());
+
+        let mut last = 0;
+        for b in &first[..first.len() - 1] {
+            assert!(b - last >= 64 && b - last <= 1024);
+            last = *b;
+        }
+        // Cuts are made by content, not just at the maximum size.
+        assert!(first.windows(2).any(|w| w[1] - w[0] < 1024));
+    }
+
+    #[test]
+    fn window_size_changes_boundaries() {
+        let data = test_data(64 * 1024);
+        assert!(
+            boundaries(&Chunker::Rolling(small_params(16)), &data[..]) !=
+                boundaries(&Chunker::Rolling(small_params(32)), &data[..])
+        );
+    }
+
+    #[test]
+    fn suggested_min_chunk_size_bounds_overhead() {
+        let overhead = RefKey::seal_overhead(SEAL_ALGORITHM).unwrap();
+        let size = suggested_min_chunk_size(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD).unwrap();
+        assert!(overhead as f64 <= size as f64 * MAX_SEAL_OVERHEAD);
+        assert!(overhead as f64 > (size - 1) as f64 * MAX_SEAL_OVERHEAD);
+
+        // Without the key commitment, sealing adds less and smaller chunks will do.
+        let plain = suggested_min_chunk_size("chacha20poly1305", MAX_SEAL_OVERHEAD).unwrap();
+        assert!(plain < size);
+        assert_eq!(
+            suggested_min_chunk_size(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD / 2.0),
+            Some(2 * size)
+        );
+        assert_eq!(suggested_min_chunk_size("rot13", MAX_SEAL_OVERHEAD), None);
+    }
+
+    #[test]
+    fn small_chunks_fail_overhead_check() {
+        Chunker::default().check_overhead(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD).unwrap();
+        Chunker::Rolling(RollingParams::default())
+            .check_overhead(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD)
+            .unwrap();
+
+        let size = suggested_min_chunk_size(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD).unwrap();
+        let err = Chunker::Fixed(1024).check_overhead(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD);
+        assert!(err.unwrap_err().contains(&format!("use at least {} bytes", size)));
+        Chunker::Fixed(size).check_overhead(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD).unwrap();
+
+        // A size that is enough for one algorithm can be too small for another.
+        let plain = suggested_min_chunk_size("chacha20poly1305", MAX_SEAL_OVERHEAD).unwrap();
+        let chunker = Chunker::Rolling(RollingParams {
+            min_size: plain,
+            avg_size: 128 * 1024,
+            ..RollingParams::default()
+        });
+        chunker.check_overhead("chacha20poly1305", MAX_SEAL_OVERHEAD).unwrap();
+        assert!(chunker.check_overhead(SEAL_ALGORITHM, MAX_SEAL_OVERHEAD).is_err());
+    }
+
+    #[test]
+    fn describe_and_parse() {
+        for chunker in vec![
+            Chunker::default(),
+            Chunker::Rolling(RollingParams::default()),
+            Chunker::Rolling(small_params(16)),
+            Chunker::Rolling(RollingParams {
+                hints: vec!["tar".to_owned()],
+                ..small_params(16)
+            }),
+        ]
+        {
+            assert_eq!(Chunker::parse(&chunker.describe()).unwrap(), chunker);
+        }
+        assert!(Chunker::parse("fixed:0").is_err());
+        assert!(Chunker::parse("buzhash:window=16,avg=100").is_err());
+        assert!(Chunker::parse("rabin:window=16").is_err());
+        assert!(Chunker::parse("buzhash:window=16,hints=zip").is_err());
+    }
+
+    /// A ustar member holding `data`, padded to whole records.
+    fn tar_member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        {
+            let mut field = |at: usize, value: &[u8]| {
+                header[at..at + value.len()].copy_from_slice(value)
+            };
+            field(0, name.as_bytes());
+            field(100, b"0000644\0");
+            field(124, format!("{:011o}\0", data.len()).as_bytes());
+            field(156, b"0");
+            field(257, b"ustar\000");
+            field(148, b"        ");
+        }
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+
+        let mut member = header;
+        member.extend_from_slice(data);
+        let padded = (member.len() + 511) / 512 * 512;
+        member.resize(padded, 0);
+        member
+    }
+
+    fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive: Vec<u8> = members.iter().flat_map(|&(n, d)| tar_member(n, d)).collect();
+        archive.extend_from_slice(&[0; 1024]);
+        archive
+    }
+
+    /// Bytes and number of the chunks of `new` that are not chunks of `old`.
+    fn changed_chunks(chunker: &Chunker, old: &[u8], new: &[u8]) -> (usize, usize) {
+        let old: HashSet<Vec<u8>> = chunker.chunks(old).map(|c| c.unwrap()).collect();
+        let changed: Vec<Vec<u8>> = chunker
+            .chunks(new)
+            .map(|c| c.unwrap())
+            .filter(|c| !old.contains(c))
+            .collect();
+        (changed.iter().map(|c| c.len()).sum(), changed.len())
+    }
+
+    #[test]
+    fn tar_hint_keeps_members_when_appending() {
+        let data = test_data(20000);
+        let mut members: Vec<(&str, &[u8])> = vec![
+            ("a", &data[0..700]),
+            ("b", &data[1000..2500]),
+            ("c", &data[3000..3300]),
+            ("d", &data[4000..6000]),
+            ("e", &data[7000..7900]),
+        ];
+        let old = tar(&members[..]);
+        members.push(("f", &data[9000..10200]));
+        let new = tar(&members[..]);
+
+        let plain = Chunker::Rolling(small_params(16));
+        let hinted = Chunker::Rolling(RollingParams {
+            hints: vec!["tar".to_owned()],
+            ..small_params(16)
+        });
+        hinted.validate().unwrap();
+        let (plain_bytes, plain_count) = changed_chunks(&plain, &old[..], &new[..]);
+        let (hinted_bytes, hinted_count) = changed_chunks(&hinted, &old[..], &new[..]);
+
+        // Only the appended member is new; the end of the archive is as it was.
+        assert_eq!(hinted_bytes, 512 + 1536);
+        assert!(hinted_bytes < plain_bytes);
+        assert!(hinted_count < plain_count);
+
+        // Chunks still stay within the size limits, and cover the whole archive.
+        let sizes: Vec<usize> = hinted.chunks(&new[..]).map(|c| c.unwrap().len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), new.len());
+        assert!(sizes[..sizes.len() - 1].iter().all(|&s| s >= 64 && s <= 1024));
+
+        // Other files are chunked by the hash alone.
+        assert_eq!(boundaries(&hinted, &data[..]), boundaries(&plain, &data[..]));
+    }
+}
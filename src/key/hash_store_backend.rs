@@ -13,20 +13,29 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{StorageClass, StoreBackend};
 use blob;
 use crypto;
 use errors::RetryError;
 use hash;
 use hash::tree::HashTreeBackend;
+use hex::ToHex;
 use key::MsgError;
 use key;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use util::{MemoryBudget, Reservation};
 
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    fetch_budget: Option<FetchBudget>,
+    data_class: StorageClass,
+    verify_dedup: bool,
+    relocations: Arc<HashMap<hash::Hash, blob::ChunkRef>>,
+    #[cfg(test)]
+    hasher: Option<Arc<Fn(&[u8]) -> hash::Hash + Send + Sync>>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
@@ -34,10 +43,24 @@ impl<B> Clone for HashStoreBackend<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            fetch_budget: self.fetch_budget.clone(),
+            data_class: self.data_class,
+            verify_dedup: self.verify_dedup,
+            relocations: self.relocations.clone(),
+            #[cfg(test)]
+            hasher: self.hasher.clone(),
         }
     }
 }
 
+#[derive(Clone)]
+struct FetchBudget {
+    budget: MemoryBudget,
+    max_blob_size: usize,
+    // The reservation for the chunk fetched last.
+    held: Arc<Mutex<Option<Reservation>>>,
+}
+
 impl<B: StoreBackend> HashStoreBackend<B> {
     pub fn new(
         hash_index: Arc<hash::HashIndex>,
@@ -48,6 +71,99 @@ impl<B: StoreBackend> HashStoreBackend<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            fetch_budget: None,
+            data_class: StorageClass::Standard,
+            verify_dedup: false,
+            relocations: Arc::new(HashMap::new()),
+            #[cfg(test)]
+            hasher: None,
+        }
+    }
+
+    /// Store leaf chunks in blobs of `class`. The tree nodes above them stay standard, as they
+    /// are read to find the leaves.
+    pub fn in_class(self, class: StorageClass) -> HashStoreBackend<B> {
+        HashStoreBackend {
+            data_class: class,
+            ..self
+        }
+    }
+
+    /// Account for every fetch in `budget`: a whole blob of up to `max_blob_size` bytes is read
+    /// to get at a chunk, and the chunk itself is kept. The chunk stays reserved until the next
+    /// fetch, so a caller must be done with one chunk before it asks for another.
+    pub fn within_budget(self, budget: MemoryBudget, max_blob_size: usize) -> HashStoreBackend<B> {
+        HashStoreBackend {
+            fetch_budget: Some(FetchBudget {
+                budget: budget,
+                max_blob_size: max_blob_size,
+                held: Arc::new(Mutex::new(None)),
+            }),
+            ..self
+        }
+    }
+
+    /// Before reusing a stored chunk with the same hash as a new one, fetch it and compare their
+    /// contents. A chunk that differs is refused with an error instead of silently being
+    /// replaced by the stored one. The stored chunk must be readable through this backend's
+    /// blob store, from the backend or from a blob that is still being filled.
+    pub fn with_verify_dedup(self, verify_dedup: bool) -> HashStoreBackend<B> {
+        HashStoreBackend {
+            verify_dedup: verify_dedup,
+            ..self
+        }
+    }
+
+    /// Also look for chunks that are not where their references say, nor where the hash index
+    /// says, at these references, such as those read back from the backend by `recover`.
+    pub fn with_relocations(
+        self,
+        relocations: Arc<HashMap<hash::Hash, blob::ChunkRef>>,
+    ) -> HashStoreBackend<B> {
+        HashStoreBackend {
+            relocations: relocations,
+            ..self
+        }
+    }
+
+    /// Hash chunks with `hasher` instead of the keyed hash, to force collisions.
+    #[cfg(test)]
+    pub fn with_hasher(
+        self,
+        hasher: Arc<Fn(&[u8]) -> hash::Hash + Send + Sync>,
+    ) -> HashStoreBackend<B> {
+        HashStoreBackend {
+            hasher: Some(hasher),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    fn hash(&self, node: blob::NodeType, leaf: blob::LeafType, chunk: &[u8]) -> hash::Hash {
+        match self.hasher {
+            Some(ref hasher) => hasher(chunk),
+            None => hash::Hash::new(&self.keys, node, leaf, chunk),
+        }
+    }
+
+    #[cfg(not(test))]
+    fn hash(&self, node: blob::NodeType, leaf: blob::LeafType, chunk: &[u8]) -> hash::Hash {
+        hash::Hash::new(&self.keys, node, leaf, chunk)
+    }
+
+    /// Compare `chunk` with the stored chunk that `href` refers to.
+    fn verify_known(&self, href: &hash::tree::HashRef, chunk: &[u8]) -> Result<(), MsgError> {
+        match self.blob_store.retrieve(href)? {
+            Some(ref stored) if &stored[..] == chunk => Ok(()),
+            Some(_) => Err(From::from(format!(
+                "Hash collision: a chunk of {} bytes differs from the stored chunk with hash {}",
+                chunk.len(),
+                href.hash.bytes.to_hex()
+            ))),
+            None => Err(From::from(format!(
+                "Could not fetch the stored chunk with hash {} to compare with",
+                href.hash.bytes.to_hex()
+            ))),
         }
     }
 }
@@ -58,8 +174,37 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
     fn fetch_chunk(&self, href: &hash::tree::HashRef) -> Result<Option<Vec<u8>>, MsgError> {
         assert!(!href.hash.bytes.is_empty());
 
-        Ok(self.blob_store.retrieve(&href)?.and_then(|data| {
-            let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
+        if let Some(ref b) = self.fetch_budget {
+            let mut held = b.held.lock().unwrap();
+            // The caller is done with the previous chunk; give its room to this one.
+            *held = None;
+            *held = Some(b.budget.reserve(
+                b.max_blob_size + href.persistent_ref.length,
+            )?);
+        }
+
+        let data = match self.blob_store.retrieve(&href)? {
+            Some(data) => Some(data),
+            None => {
+                // The chunk may have been moved to another blob after `href` was written.
+                let mut moved_to = self.fetch_persistent_ref(&href.hash);
+                let unmoved = |r: &blob::ChunkRef| r.blob_name == href.persistent_ref.blob_name;
+                if moved_to.as_ref().map_or(true, &unmoved) {
+                    moved_to = self.relocations.get(&href.hash).cloned();
+                }
+                match moved_to {
+                    Some(ref r) if r.blob_name != href.persistent_ref.blob_name => {
+                        let mut moved = href.clone();
+                        moved.persistent_ref = r.clone();
+                        self.blob_store.retrieve(&moved)?
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        Ok(data.and_then(|data| {
+            let actual_hash = self.hash(href.node, href.leaf, &data[..]);
             if href.hash == actual_hash {
                 Some(data)
             } else {
@@ -100,7 +245,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         info: Option<&key::Info>,
     ) -> Result<(u64, hash::tree::HashRef), MsgError> {
         let mut hash_entry = hash::Entry {
-            hash: hash::Hash::new(&self.keys, node, leaf, chunk),
+            hash: self.hash(node, leaf, chunk),
             node: node,
             leaf: leaf,
             childs: childs,
@@ -121,16 +266,17 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 let pref = self.fetch_persistent_ref(&hash_entry.hash).expect(
                     "Could not find persistent ref for known hash",
                 );
-                Ok((
-                    id,
-                    hash::tree::HashRef {
-                        hash: hash_entry.hash,
-                        node: node,
-                        leaf: leaf,
-                        info: None,
-                        persistent_ref: pref,
-                    },
-                ))
+                let href = hash::tree::HashRef {
+                    hash: hash_entry.hash,
+                    node: node,
+                    leaf: leaf,
+                    info: None,
+                    persistent_ref: pref,
+                };
+                if self.verify_dedup {
+                    self.verify_known(&href, chunk)?;
+                }
+                Ok((id, href))
             }
             hash::ReserveResult::ReserveOk(id) => {
                 debug!(
@@ -155,14 +301,25 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                     drop(guard);
                 });
 
-                let href = self.blob_store.store(
+                let class = match node {
+                    blob::NodeType::Leaf => self.data_class,
+                    blob::NodeType::Branch(_) => StorageClass::Standard,
+                };
+                let href = match self.blob_store.store_in_class(
                     chunk,
                     hash_entry.hash.clone(),
                     node,
                     leaf,
                     info,
+                    class,
                     callback,
-                );
+                ) {
+                    Ok(href) => href,
+                    Err(e) => {
+                        self.hash_index.unreserve(id);
+                        return Err(From::from(e));
+                    }
+                };
 
                 // Update the hash entry now to enable reuse before the hash is fully committed.
                 hash_entry.persistent_ref = Some(href.persistent_ref.clone());
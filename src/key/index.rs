@@ -15,11 +15,13 @@
 //! Local state for keys in the snapshot in progress (the "index").
 
 
+use std::collections::HashMap;
 use std::str;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 
 use chrono;
+use crypto::keys::Keeper;
 use diesel;
 use diesel::prelude::*;
 use diesel::connection::TransactionManager;
@@ -29,7 +31,7 @@ use hash;
 use capnp;
 use filetime::FileTime;
 
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use super::schema;
 use time::Duration;
@@ -69,6 +71,19 @@ pub struct Info {
 
     pub byte_length: Option<u64>,
     pub hat_snapshot_ts: i64,
+
+    /// Plain SHA-256 of the file contents, for checking restores with standard tools.
+    pub sha256: Option<Vec<u8>>,
+
+    /// Extended attributes as (name, value), sorted by name. On Linux, POSIX ACLs are the
+    /// `system.posix_acl_*` attributes. Empty unless the snapshot asked for them.
+    pub extended_attributes: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// Holes of a sparse file as (offset, length), in order, if it was stored without them.
+    pub holes: Vec<(u64, u64)>,
+
+    /// The file changed, but a metadata-only snapshot stored it without reading its contents.
+    pub content_not_captured: bool,
 }
 
 impl Entry {
@@ -123,6 +138,11 @@ impl Info {
 
             byte_length: meta.map(|m| m.len()),
             hat_snapshot_ts: chrono::Utc::now().timestamp(),
+
+            sha256: None,
+            extended_attributes: vec![],
+            holes: vec![],
+            content_not_captured: false,
         }
     }
 
@@ -137,6 +157,15 @@ impl Info {
                 Some((ug.get_user_id(), ug.get_group_id()))
             }
         };
+        let sha256 = msg.get_sha256()?;
+        let mut extended_attributes = vec![];
+        for attr in msg.get_extended_attributes()?.iter() {
+            extended_attributes.push((attr.get_name()?.to_vec(), attr.get_value()?.to_vec()));
+        }
+        let mut holes = vec![];
+        for hole in msg.get_holes()?.iter() {
+            holes.push((hole.get_offset(), hole.get_length()));
+        }
         Ok(Info {
             name: msg.get_name()?.to_vec(),
             created_ts_secs: none_if_zero(msg.get_created_timestamp_secs()),
@@ -153,6 +182,16 @@ impl Info {
             byte_length: Some(msg.get_byte_length()),
 
             hat_snapshot_ts: msg.get_utc_timestamp(),
+
+            sha256: if sha256.is_empty() {
+                None
+            } else {
+                Some(sha256.to_vec())
+            },
+
+            extended_attributes: extended_attributes,
+            holes: holes,
+            content_not_captured: msg.get_content_not_captured(),
         })
     }
     pub fn populate_msg(&self, mut msg: root_capnp::file_info::Builder) {
@@ -186,7 +225,71 @@ impl Info {
         }
 
         msg.borrow().set_utc_timestamp(self.hat_snapshot_ts);
+
+        if let Some(ref digest) = self.sha256 {
+            msg.borrow().set_sha256(digest);
+        }
+
+        if !self.extended_attributes.is_empty() {
+            let mut list = msg.borrow().init_extended_attributes(
+                self.extended_attributes.len() as u32,
+            );
+            for (i, &(ref name, ref value)) in self.extended_attributes.iter().enumerate() {
+                let mut attr = list.borrow().get(i as u32);
+                attr.set_name(name);
+                attr.set_value(value);
+            }
+        }
+
+        msg.borrow().set_content_not_captured(self.content_not_captured);
+
+        if !self.holes.is_empty() {
+            let mut list = msg.borrow().init_holes(self.holes.len() as u32);
+            for (i, &(offset, length)) in self.holes.iter().enumerate() {
+                let mut hole = list.borrow().get(i as u32);
+                hole.set_offset(offset);
+                hole.set_length(length);
+            }
+        }
+    }
+}
+
+fn encode_extended_attributes(attributes: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::extended_attribute_list::Builder>();
+        let mut list = root.init_attributes(attributes.len() as u32);
+        for (i, &(ref name, ref value)) in attributes.iter().enumerate() {
+            let mut attr = list.borrow().get(i as u32);
+            attr.set_name(name);
+            attr.set_value(value);
+        }
+    }
+    let mut out = Vec::new();
+    ::util::write_packed_message(&mut out, &message).unwrap();
+    out
+}
+
+fn decode_extended_attributes(bytes: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, capnp::Error> {
+    let reader = ::util::read_packed_message(
+        &mut &bytes[..],
+        capnp::message::ReaderOptions::new(),
+    )?;
+    let msg = reader.get_root::<root_capnp::extended_attribute_list::Reader>()?;
+
+    let mut out = vec![];
+    for attr in msg.get_attributes()?.iter() {
+        out.push((attr.get_name()?.to_vec(), attr.get_value()?.to_vec()));
     }
+    Ok(out)
+}
+
+/// The file info kept with the data of a stored entry, in its hash ref.
+fn stored_info(data: &schema::KeyData) -> Option<Info> {
+    data.hash_ref
+        .as_ref()
+        .and_then(|p| ::hash::tree::HashRef::from_bytes(&mut &p[..]).ok())
+        .and_then(|r| r.info)
 }
 
 pub struct KeyIndex(Mutex<InternalKeyIndex>);
@@ -194,6 +297,8 @@ pub struct KeyIndex(Mutex<InternalKeyIndex>);
 pub struct InternalKeyIndex {
     conn: SqliteConnection,
     flush_timer: PeriodicTimer,
+    filename_keys: Option<Arc<Keeper>>,
+    encrypt_filenames: bool,
 }
 
 
@@ -204,6 +309,8 @@ impl InternalKeyIndex {
         let ki = InternalKeyIndex {
             conn: conn,
             flush_timer: PeriodicTimer::new(Duration::seconds(5)),
+            filename_keys: None,
+            encrypt_filenames: false,
         };
 
         {
@@ -258,6 +365,55 @@ impl InternalKeyIndex {
         Ok(())
     }
 
+    /// The name column for `name`: the name itself, or its tag when filenames are encrypted.
+    fn stored_name(&self, name_: &[u8]) -> Vec<u8> {
+        match self.filename_keys {
+            Some(ref keys) if self.encrypt_filenames => keys.filename_tag(name_),
+            _ => name_.to_vec(),
+        }
+    }
+
+    /// Replace the tags of encrypted names in `rows` with the names they stand for.
+    fn unlock_names(
+        &self,
+        rows: &mut Vec<(schema::KeyNode, schema::KeyData)>,
+    ) -> Result<(), DieselError> {
+        use super::schema::key_names::dsl::*;
+
+        let keys = match self.filename_keys {
+            Some(ref keys) => keys,
+            None => return Ok(()),
+        };
+        let ids: Vec<i64> = rows.iter()
+            .map(|&(ref node, _)| node.node_id.expect("listed node has an id"))
+            .collect();
+        let mut locked_names = HashMap::new();
+        // Stay below the limit on the number of variables in a SQLite statement.
+        for ids_chunk in ids.chunks(500) {
+            let found = key_names
+                .filter(node_id.eq_any(ids_chunk))
+                .load::<(i64, Vec<u8>)>(&self.conn)?;
+            locked_names.extend(found);
+        }
+
+        let mut any_locked = false;
+        for &mut (ref mut node, _) in rows.iter_mut() {
+            if let Some(locked) = locked_names.remove(&node.node_id.unwrap()) {
+                node.name = keys.filename_unlock(&locked[..], &node.name[..]).ok_or_else(|| {
+                    diesel::result::Error::DeserializationError(From::from(
+                        "could not decrypt filename",
+                    ))
+                })?;
+                any_locked = true;
+            }
+        }
+        if any_locked {
+            // Tags are in no useful order; list by name, as without encryption.
+            rows.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        }
+        Ok(())
+    }
+
     /// Insert an entry in the key index.
     /// Returns `Id` with the new entry ID.
     fn insert(
@@ -266,16 +422,35 @@ impl InternalKeyIndex {
         hash_ref_opt: Option<&hash::tree::HashRef>,
     ) -> Result<Entry, DieselError> {
         if entry.node_id.is_none() {
-            let new = schema::NewKeyNode {
-                node_id: None, // new row id
-                parent_id: entry.parent_id.map(|p| p as i64),
-                name: &entry.info.name[..],
-            };
-            use super::schema::key_tree::dsl::*;
-            diesel::insert(&new).into(key_tree).execute(&self.conn)?;
-            entry.node_id = Some(self.last_insert_rowid()? as u64);
+            let stored_name = self.stored_name(&entry.info.name[..]);
+            {
+                let new = schema::NewKeyNode {
+                    node_id: None, // new row id
+                    parent_id: entry.parent_id.map(|p| p as i64),
+                    name: &stored_name[..],
+                };
+                use super::schema::key_tree::dsl::*;
+                diesel::insert(&new).into(key_tree).execute(&self.conn)?;
+                entry.node_id = Some(self.last_insert_rowid()? as u64);
+            }
+
+            if let Some(ref keys) = self.filename_keys {
+                if self.encrypt_filenames {
+                    let locked = keys.filename_lock(&entry.info.name[..], &stored_name[..]);
+                    let new = schema::NewKeyName {
+                        node_id: entry.node_id.unwrap() as i64,
+                        ciphertext: &locked[..],
+                    };
+                    use super::schema::key_names::dsl::*;
+                    diesel::insert(&new).into(key_names).execute(&self.conn)?;
+                }
+            }
         }
 
+        // The listings of this entry and the directories it is in have to be built again.
+        self.invalidate_dir_refs(entry.node_id)?;
+        self.store_extended_attributes(entry.node_id.unwrap(), &entry.info.extended_attributes)?;
+
         {
             let link_path = match &entry.data {
                 &Data::DirPlaceholder |
@@ -320,12 +495,13 @@ impl InternalKeyIndex {
         use super::schema::key_tree::dsl::{name, parent_id, key_tree};
         use super::schema::key_data::dsl::*;
 
+        let stored_name = self.stored_name(&name_[..]);
         let row_opt = match parent_ {
             Some(p) => {
                 key_tree
                     .inner_join(key_data)
                     .filter(parent_id.eq(p as i64))
-                    .filter(name.eq(&name_[..]))
+                    .filter(name.eq(&stored_name[..]))
                     .order(committed)
                     .first::<(schema::KeyNode, schema::KeyData)>(&self.conn)
                     .optional()?
@@ -334,7 +510,7 @@ impl InternalKeyIndex {
                 key_tree
                     .inner_join(key_data)
                     .filter(parent_id.is_null())
-                    .filter(name.eq(&name_[..]))
+                    .filter(name.eq(&stored_name[..]))
                     .order(committed)
                     .first::<(schema::KeyNode, schema::KeyData)>(&self.conn)
                     .optional()?
@@ -342,6 +518,8 @@ impl InternalKeyIndex {
         };
 
         if let Some((node, data)) = row_opt {
+            let stored = stored_info(&data);
+            let extended_attributes = self.extended_attributes(node.node_id.unwrap() as u64)?;
             Ok(Some(Entry {
                 node_id: node.node_id.map(|n| n as u64),
                 parent_id: node.parent_id.map(|p| p as u64),
@@ -358,8 +536,12 @@ impl InternalKeyIndex {
                     ),
                     user_id: data.user_id.map(|x| x as u64),
                     group_id: data.group_id.map(|x| x as u64),
-                    byte_length: None,
+                    byte_length: stored.as_ref().and_then(|i| i.byte_length),
                     hat_snapshot_ts: 0,
+                    sha256: stored.as_ref().and_then(|i| i.sha256.clone()),
+                    extended_attributes: extended_attributes,
+                    content_not_captured: stored.as_ref().map_or(false, |i| i.content_not_captured),
+                    holes: stored.map(|i| i.holes).unwrap_or_else(Vec::new),
                 },
             }))
         } else {
@@ -368,7 +550,8 @@ impl InternalKeyIndex {
     }
 
     /// List a directory (aka. `level`) in the index.
-    /// Returns `ListResult` with all the entries under the given parent.
+    /// Returns `ListResult` with all the entries under the given parent, ordered by name so that
+    /// listings do not depend on the order in which entries were inserted.
     fn list_dir(
         &mut self,
         parent_opt: Option<u64>,
@@ -377,12 +560,13 @@ impl InternalKeyIndex {
         use super::schema::key_tree::dsl::*;
         use super::schema::key_data::dsl::{committed, key_data};
 
-        let rows = match parent_opt {
+        let mut rows = match parent_opt {
             Some(p) => {
                 key_tree
                     .inner_join(key_data)
                     .filter(parent_id.eq(p as i64))
                     .filter(committed.eq(true))
+                    .order(name)
                     .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?
             }
             None => {
@@ -390,13 +574,31 @@ impl InternalKeyIndex {
                     .inner_join(key_data)
                     .filter(parent_id.is_null())
                     .filter(committed.eq(true))
+                    .order(name)
                     .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?
             }
         };
+        self.unlock_names(&mut rows)?;
+        let mut attributes = Vec::with_capacity(rows.len());
+        for &(ref node, _) in rows.iter() {
+            attributes.push(self.extended_attributes(
+                node.node_id.expect("listed node has an id") as u64,
+            )?);
+        }
 
         Ok(
             rows.into_iter()
-                .map(|(node, mut data)| {
+                .zip(attributes)
+                .map(|((node, data), extended_attributes)| {
+                    let mut hash_ref = data.hash_ref.as_ref().map(|p| {
+                        ::hash::tree::HashRef::from_bytes(&mut &p[..]).unwrap()
+                    });
+                    // The info is only kept for the index; listings carry their own.
+                    let (byte_length, sha256, holes, content_not_captured) =
+                        match hash_ref.as_mut().and_then(|r| r.info.take()) {
+                            Some(i) => (i.byte_length, i.sha256, i.holes, i.content_not_captured),
+                            None => (None, None, vec![], false),
+                        };
                     (
                         Entry {
                             node_id: node.node_id.map(|n| n as u64),
@@ -424,13 +626,15 @@ impl InternalKeyIndex {
                                 }),
                                 user_id: data.user_id.map(|x| x as u64),
                                 group_id: data.group_id.map(|x| x as u64),
-                                byte_length: None,
+                                byte_length: byte_length,
                                 hat_snapshot_ts: 0,
+                                sha256: sha256,
+                                extended_attributes: extended_attributes,
+                                holes: holes,
+                                content_not_captured: content_not_captured,
                             },
                         },
-                        data.hash_ref.as_mut().map(|p| {
-                            ::hash::tree::HashRef::from_bytes(&mut &p[..]).unwrap()
-                        }),
+                        hash_ref,
                     )
                 })
                 .collect(),
@@ -484,6 +688,7 @@ impl InternalKeyIndex {
             }
         };
 
+        let mut deleted_any = false;
         for (node_id_, tag_) in children {
             let id = node_id_.unwrap();
             if tag_ == Tag::Reserved as i64 {
@@ -492,13 +697,119 @@ impl InternalKeyIndex {
                 diesel::delete(key_tree.filter(node_id.eq(id))).execute(
                     &self.conn,
                 )?;
+                deleted_any = true;
             }
         }
+        if deleted_any {
+            self.invalidate_dir_refs(parent_opt)?;
+        }
 
         self.flush()?;
 
         Ok(())
     }
+
+    /// The listing last committed for the directory `dir_id`, unless something in the
+    /// directory changed since.
+    fn dir_ref(&mut self, dir_id: u64) -> Result<Option<hash::tree::HashRef>, DieselError> {
+        use super::schema::key_dir_refs::dsl::*;
+
+        let bytes_opt = key_dir_refs
+            .find(dir_id as i64)
+            .select(hash_ref)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()?;
+        Ok(bytes_opt.map(|bytes| {
+            ::hash::tree::HashRef::from_bytes(&mut &bytes[..]).unwrap()
+        }))
+    }
+
+    fn set_dir_ref(
+        &mut self,
+        dir_id: u64,
+        dir_ref: &hash::tree::HashRef,
+    ) -> Result<(), DieselError> {
+        use super::schema::key_dir_refs::dsl::*;
+
+        let bytes = dir_ref.as_bytes();
+        let new = schema::NewKeyDirRef {
+            node_id: dir_id as i64,
+            hash_ref: &bytes[..],
+        };
+        diesel::insert(&new).into(key_dir_refs).execute(&self.conn)?;
+        self.maybe_flush()?;
+        Ok(())
+    }
+
+    /// Forget the listings of `node_opt` and of every directory above it.
+    fn invalidate_dir_refs(&mut self, mut node_opt: Option<u64>) -> Result<(), DieselError> {
+        use super::schema::key_dir_refs::dsl::{key_dir_refs, node_id as dir_node_id};
+        use super::schema::key_tree::dsl::*;
+
+        while let Some(node) = node_opt {
+            diesel::delete(key_dir_refs.filter(dir_node_id.eq(node as i64)))
+                .execute(&self.conn)?;
+            node_opt = key_tree
+                .filter(node_id.eq(node as i64))
+                .select(parent_id)
+                .first::<Option<i64>>(&self.conn)
+                .optional()?
+                .and_then(|p| p.map(|p| p as u64));
+        }
+        Ok(())
+    }
+
+    fn extended_attributes(&mut self, node: u64) -> Result<Vec<(Vec<u8>, Vec<u8>)>, DieselError> {
+        use super::schema::key_xattrs::dsl::*;
+
+        let bytes_opt = key_xattrs
+            .find(node as i64)
+            .select(attributes)
+            .first::<Vec<u8>>(&self.conn)
+            .optional()?;
+        match bytes_opt {
+            Some(bytes) => {
+                decode_extended_attributes(&bytes[..]).map_err(|e| {
+                    From::from(diesel::result::Error::DeserializationError(
+                        From::from(e.to_string()),
+                    ))
+                })
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Keep `attrs` for `node`, replacing what was kept for it before.
+    fn store_extended_attributes(
+        &mut self,
+        node: u64,
+        attrs: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<(), DieselError> {
+        use super::schema::key_xattrs::dsl::*;
+
+        if attrs.is_empty() {
+            diesel::delete(key_xattrs.find(node as i64)).execute(&self.conn)?;
+        } else {
+            let bytes = encode_extended_attributes(attrs);
+            let new = schema::NewKeyXattrs {
+                node_id: node as i64,
+                attributes: &bytes[..],
+            };
+            diesel::insert(&new).into(key_xattrs).execute(&self.conn)?;
+        }
+        Ok(())
+    }
+
+    /// Update the extended attributes of an entry that is otherwise unchanged.
+    fn set_extended_attributes(
+        &mut self,
+        node: u64,
+        attrs: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<(), DieselError> {
+        self.invalidate_dir_refs(Some(node))?;
+        self.store_extended_attributes(node, attrs)?;
+        self.maybe_flush()
+    }
 }
 
 impl KeyIndex {
@@ -511,6 +822,60 @@ impl KeyIndex {
         KeyIndex::new(Path::new("migrations"), ":memory:")
     }
 
+    /// Read encrypted names with `keys`, and with `encrypt` also store new names encrypted.
+    /// The family remembers that its names are encrypted, and keeps encrypting them from then
+    /// on whether `encrypt` is given or not. Returns whether new names are stored encrypted.
+    ///
+    /// An encrypted name is kept in the `key_names` table, under a random nonce, while the name
+    /// column holds a deterministic tag of it (see `Keeper::filename_tag`) to look it up by.
+    /// The tag hides the name, but not which entries share a name, e.g. every `.git` directory,
+    /// nor how many entries each directory has: parent ids are kept in the clear so that
+    /// directories can still be listed. Entries already stored keep the form they have; a
+    /// snapshot that changes the setting stores every entry again under its new form.
+    pub fn set_filename_keys(&self, keys: Arc<Keeper>, encrypt: bool) -> Result<bool, DieselError> {
+        use super::schema::key_settings::dsl::*;
+
+        let mut index = self.lock();
+        let stored = key_settings
+            .find(1)
+            .select(encrypt_filenames)
+            .first::<bool>(&index.conn)
+            .optional()?
+            .unwrap_or(false);
+        if encrypt && !stored {
+            let new = schema::NewKeySettings {
+                id: 1,
+                encrypt_filenames: true,
+            };
+            diesel::insert(&new).into(key_settings).execute(&index.conn)?;
+            index.flush()?;
+        }
+
+        index.filename_keys = Some(keys);
+        index.encrypt_filenames = encrypt || stored;
+        Ok(index.encrypt_filenames)
+    }
+
+    /// The stored name column and name ciphertext of every entry, in no particular order.
+    #[cfg(test)]
+    pub fn stored_names(&self) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>, DieselError> {
+        use super::schema::key_tree::dsl::*;
+
+        let index = self.lock();
+        let nodes = key_tree.load::<schema::KeyNode>(&index.conn)?;
+        let mut out = vec![];
+        for node in nodes {
+            use super::schema::key_names::dsl::{ciphertext, key_names};
+            let locked = key_names
+                .find(node.node_id.unwrap())
+                .select(ciphertext)
+                .first::<Vec<u8>>(&index.conn)
+                .optional()?;
+            out.push((node.name, locked));
+        }
+        Ok(out)
+    }
+
     fn lock(&self) -> MutexGuard<InternalKeyIndex> {
         self.0.lock().expect("index-process has failed")
     }
@@ -542,6 +907,26 @@ impl KeyIndex {
         self.lock().mark_reserved(entry)
     }
 
+    pub fn set_extended_attributes(
+        &self,
+        node: u64,
+        attrs: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<(), DieselError> {
+        self.lock().set_extended_attributes(node, attrs)
+    }
+
+    pub fn dir_ref(&self, dir_id: u64) -> Result<Option<hash::tree::HashRef>, DieselError> {
+        self.lock().dir_ref(dir_id)
+    }
+
+    pub fn set_dir_ref(
+        &self,
+        dir_id: u64,
+        dir_ref: &hash::tree::HashRef,
+    ) -> Result<(), DieselError> {
+        self.lock().set_dir_ref(dir_id, dir_ref)
+    }
+
     pub fn commit_reserved_nodes(&self) -> Result<(), DieselError> {
         self.lock().commit_reserved_nodes()
     }
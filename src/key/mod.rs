@@ -15,27 +15,32 @@
 //! External API for creating and manipulating snapshots.
 
 
-use backend::StoreBackend;
+use backend::{StorageClass, StoreBackend};
 use blob;
+use chrono;
 use crypto;
-use errors::{DieselError, RetryError};
+use errors::{CancelledError, DieselError, RetryError};
 use hash;
 use hash::tree::{LeafIterator, SimpleHashTreeWriter};
 use std::borrow::Cow;
 use std::io;
 use std::sync::Arc;
 
-use util::{FnBox, MsgHandler, Process};
+use util::{CancellationToken, FnBox, HoleFiller, MsgHandler, Process, hole_bytes};
 
 mod schema;
 mod index;
 mod hash_store_backend;
+mod chunker;
+mod tar_hint;
 
 #[cfg(test)]
 mod tests;
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 
+pub use self::chunker::{BoundaryCursor, BoundaryHint, Chunker, MAX_SEAL_OVERHEAD, RollingParams,
+                        suggested_min_chunk_size};
 pub use self::hash_store_backend::HashStoreBackend;
 pub use self::index::{Data, Entry, Info, KeyIndex};
 
@@ -51,6 +56,9 @@ error_type! {
         RetryError(RetryError) {
             cause;
         },
+        Cancelled(CancelledError) {
+            cause;
+        },
         DieselError(DieselError) {
             cause;
         },
@@ -61,6 +69,10 @@ error_type! {
 }
 
 
+/// Files are split into chunks of this size before being hashed and stored, unless the store
+/// is given another `Chunker`.
+pub const CHUNK_SIZE: usize = 128 * 1024;
+
 pub type StoreProcess<IT, B> = Process<Msg<IT>, Reply<B>, MsgError>;
 
 pub type DirElem<B> = (Entry, Option<hash::tree::HashRef>, Option<HashTreeReaderInitializer<B>>);
@@ -86,6 +98,11 @@ pub enum Msg<IT> {
     /// can return `None`. Returns `Id` with the new entry ID.
     Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>),
 
+    /// Like `Insert`, but the chunks of the data are first matched against those of the file
+    /// stored at the given path, one name per component from the root of the index. Chunks
+    /// whose hashes match are reused without being looked up again.
+    InsertWithReference(Entry, Option<Box<FnBox<(), Option<IT>>>>, Vec<Vec<u8>>),
+
     /// List a "directory" (aka. a `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
     ListDir(Option<u64>),
@@ -111,6 +128,12 @@ pub struct Store<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    cancel: CancellationToken,
+    chunker: Chunker,
+    fanout: usize,
+    storage_policy: blob::StoragePolicy,
+    file_digests: bool,
+    verify_dedup: bool,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -119,6 +142,12 @@ impl<B> Clone for Store<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            cancel: self.cancel.clone(),
+            chunker: self.chunker.clone(),
+            fanout: self.fanout,
+            storage_policy: self.storage_policy.clone(),
+            file_digests: self.file_digests,
+            verify_dedup: self.verify_dedup,
         }
     }
 }
@@ -130,15 +159,44 @@ impl<B: StoreBackend> Store<B> {
         hash_index: Arc<hash::HashIndex>,
         blob_store: Arc<blob::BlobStore<B>>,
         keys: Arc<crypto::keys::Keeper>,
+        cancel: CancellationToken,
+        chunker: Chunker,
+        storage_policy: blob::StoragePolicy,
     ) -> Store<B> {
         Store {
             index: index,
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            cancel: cancel,
+            chunker: chunker,
+            fanout: hash::tree::DEFAULT_FANOUT,
+            storage_policy: storage_policy,
+            file_digests: false,
+            verify_dedup: false,
         }
     }
 
+    /// Also keep a plain SHA-256 of the contents of every file stored from now on, in its file
+    /// info. Files that look unchanged are read again if they were stored without one.
+    pub fn with_file_digests(mut self, file_digests: bool) -> Store<B> {
+        self.file_digests = file_digests;
+        self
+    }
+
+    /// Build hash trees whose branch nodes have `fanout` children.
+    pub fn with_fanout(mut self, fanout: usize) -> Store<B> {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Compare the contents of every chunk that is deduplicated against the stored chunk with
+    /// the same hash, see `HashStoreBackend::with_verify_dedup`.
+    pub fn with_verify_dedup(mut self, verify_dedup: bool) -> Store<B> {
+        self.verify_dedup = verify_dedup;
+        self
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         use crypto;
@@ -160,27 +218,331 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hi_p,
             blob_store: bs_p,
             keys: Arc::new(crypto::keys::Keeper::new_for_testing()),
+            cancel: CancellationToken::new(),
+            chunker: Chunker::default(),
+            fanout: hash::tree::DEFAULT_FANOUT,
+            storage_policy: blob::StoragePolicy::default(),
+            file_digests: false,
+            verify_dedup: false,
         })
     }
 
+    /// How this store splits files into chunks.
+    pub fn chunker(&self) -> &Chunker {
+        &self.chunker
+    }
+
+    /// How many children the branch nodes of the hash trees of this store have.
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    /// The listing committed earlier for the directory `dir_id`, if nothing below it has changed
+    /// since, with the data hashes of all files and directories below it. Returns `None` if the
+    /// listing has to be built again, also when some of its data is no longer stored.
+    pub fn unchanged_dir(
+        &self,
+        dir_id: u64,
+    ) -> Result<Option<(hash::tree::HashRef, Vec<hash::Hash>)>, MsgError> {
+        let dir_ref = match self.index.dir_ref(dir_id)? {
+            Some(dir_ref) => dir_ref,
+            None => return Ok(None),
+        };
+
+        let mut tops = vec![];
+        let mut stack = vec![dir_id];
+        while let Some(dir) = stack.pop() {
+            for (entry, hash_ref) in self.index.list_dir(Some(dir))? {
+                match entry.data {
+                    Data::FilePlaceholder => {
+                        match hash_ref {
+                            Some(href) => tops.push(href.hash),
+                            None => return Ok(None),
+                        }
+                    }
+                    Data::DirPlaceholder => {
+                        let id = entry.node_id.expect("Directory has no id");
+                        match self.index.dir_ref(id)? {
+                            Some(href) => tops.push(href.hash),
+                            None => return Ok(None),
+                        }
+                        stack.push(id);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if !self.hash_index.hash_exists(&dir_ref.hash) ||
+            tops.iter().any(|h| !self.hash_index.hash_exists(h))
+        {
+            return Ok(None);
+        }
+        Ok(Some((dir_ref, tops)))
+    }
+
+    /// Remember the listing just committed for the directory `dir_id`.
+    pub fn set_dir_ref(&self, dir_id: u64, dir_ref: &hash::tree::HashRef) -> Result<(), MsgError> {
+        Ok(self.index.set_dir_ref(dir_id, dir_ref)?)
+    }
+
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.hash_index.flush();
         self.index.flush()?;
 
         Ok(())
     }
 
+    /// List the chunks of a stored file, except for its last one, in order.
+    ///
+    /// With fixed-size chunking these are exactly the full chunks of the file. Returns an empty
+    /// list if the file is not known locally.
+    fn full_chunks(&self, top: &hash::Hash) -> Vec<(u64, hash::tree::HashRef)> {
+        let mut chunks = vec![];
+        let mut stack = match self.hash_index.get_id(top) {
+            Some(id) => vec![id],
+            None => return vec![],
+        };
+        while let Some(id) = stack.pop() {
+            let entry = match self.hash_index.get_hash(id) {
+                Some(entry) => entry,
+                None => return vec![],
+            };
+            match (entry.childs, entry.persistent_ref) {
+                (Some(childs), _) => stack.extend(childs.into_iter().rev()),
+                (None, Some(pref)) => {
+                    chunks.push((
+                        id,
+                        hash::tree::HashRef {
+                            hash: entry.hash,
+                            node: entry.node,
+                            leaf: entry.leaf,
+                            info: None,
+                            persistent_ref: pref,
+                        },
+                    ))
+                }
+                (None, None) => return vec![],
+            }
+        }
+        chunks.pop();
+        chunks
+    }
+
+    /// The data hash of the file stored at `names`, one name per component from the root.
+    fn reference_hash(&self, names: &[Vec<u8>]) -> Result<Option<hash::Hash>, MsgError> {
+        let mut parent = None;
+        let mut found = None;
+        for name in names {
+            match self.index.lookup(parent, name.clone())? {
+                Some(entry) => {
+                    parent = entry.node_id;
+                    found = Some(entry);
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(found.and_then(|entry| match entry.data {
+            Data::FileHash(bytes) => Some(hash::Hash { bytes: bytes }),
+            _ => None,
+        }))
+    }
+
     pub fn hash_tree_writer(
         &mut self,
         leaf: blob::LeafType,
+    ) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
+        self.hash_tree_writer_in_class(leaf, StorageClass::Standard)
+    }
+
+    /// A tree writer that asks for its leaf chunks to be stored in `class`.
+    pub fn hash_tree_writer_in_class(
+        &mut self,
+        leaf: blob::LeafType,
+        class: StorageClass,
     ) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
         let backend = HashStoreBackend::new(
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
+        ).in_class(class)
+            .with_verify_dedup(self.verify_dedup);
+        SimpleHashTreeWriter::new(leaf, self.fanout, backend)
+    }
+
+    /// Insert an entry and its data, if any, and return its id. See `Msg::InsertWithReference`
+    /// for `reference`.
+    fn insert<IT: io::Read>(
+        &mut self,
+        insert_entry: Entry,
+        chunk_it_opt: Option<Box<FnBox<(), Option<IT>>>>,
+        reference: Option<Vec<Vec<u8>>>,
+    ) -> Result<u64, MsgError> {
+        let stored_opt = self.index.lookup(
+            insert_entry.parent_id,
+            insert_entry.info.name.clone(),
+        )?;
+        let previous = stored_opt.as_ref().and_then(|stored| match stored.data {
+            Data::FileHash(ref bytes) => Some(hash::Hash { bytes: bytes.clone() }),
+            _ => None,
+        });
+        let mut entry = match stored_opt {
+            Some(ref stored_entry) if insert_entry.data_looks_unchanged(stored_entry) => {
+                if insert_entry.info.extended_attributes !=
+                    stored_entry.info.extended_attributes
+                {
+                    // Changing attributes leaves the modification time alone.
+                    self.index.set_extended_attributes(
+                        stored_entry.node_id.unwrap(),
+                        &insert_entry.info.extended_attributes,
+                    )?;
+                }
+                match &stored_entry.data {
+                    &Data::FileHash(ref hash_bytes) if chunk_it_opt.is_some() => {
+                        let hash = hash::Hash { bytes: hash_bytes.to_vec() };
+                        let has_digest = !self.file_digests ||
+                            stored_entry.info.sha256.is_some();
+                        // Contents that a metadata-only snapshot skipped are read this time.
+                        let has_contents = !stored_entry.info.content_not_captured ||
+                            insert_entry.info.content_not_captured;
+                        if has_digest && has_contents && self.hash_index.hash_exists(&hash) {
+                            // Short-circuit: We have the data.
+                            debug!("Skip entry: {:?}", stored_entry.info.name);
+                            self.index.mark_reserved(&stored_entry)?;
+                            return Ok(stored_entry.node_id.unwrap());
+                        }
+                    }
+                    _ if chunk_it_opt.is_none() => {
+                        // Short-circuit: No data needed.
+                        debug!("Skip empty entry: {:?}", stored_entry.info.name);
+                        self.index.mark_reserved(&stored_entry)?;
+                        return Ok(stored_entry.node_id.unwrap());
+                    }
+                    _ => (),
+                }
+                // Our stored entry is incomplete.
+                Entry {
+                    node_id: stored_entry.node_id,
+                    ..insert_entry
+                }
+            }
+            Some(entry) => {
+                Entry {
+                    node_id: entry.node_id,
+                    ..insert_entry
+                }
+            }
+            None => insert_entry,
+        };
+
+        // Check if we have an data source:
+        let it_opt = chunk_it_opt.and_then(|open| open.call(()));
+        if it_opt.is_none() {
+            // No data is associated with this entry.
+            debug!("Insert entry: {:?}", entry.info.name);
+            let entry = self.index.insert(entry, None)?;
+
+            // Bail out before storing data that does not exist:
+            return Ok(entry.node_id.unwrap());
+        }
+
+        // Setup hash tree structure
+        let class = self.storage_policy.file_data_class(
+            &entry.info,
+            chrono::Utc::now().timestamp(),
         );
-        SimpleHashTreeWriter::new(leaf, 8, backend)
+        let mut tree = self.hash_tree_writer_in_class(blob::LeafType::FileChunk, class);
+
+        // A file that grew by appending starts with the same full chunks as before, and a
+        // copy of the reference file with the same chunks as that. These are still read and
+        // hashed to confirm that they are unchanged, but are then reused as-is instead of being
+        // looked up and stored again. A file stored here before takes precedence.
+        let previous = match (previous, reference) {
+            (Some(hash), _) => Some(hash),
+            (None, Some(names)) => self.reference_hash(&names[..])?,
+            (None, None) => None,
+        };
+        let mut prefix = previous
+            .map(|hash| self.full_chunks(&hash))
+            .unwrap_or_else(Vec::new)
+            .into_iter();
+
+        // Read and insert all file chunks:
+        // (see HashStoreBackend::insert_chunk above)
+        let mut chunks = self.chunker.chunks(it_opt.unwrap());
+        let mut file_len = 0u64;
+        let mut sha256 = if self.file_digests {
+            Some(crypto::Sha256::new())
+        } else {
+            None
+        };
+        // The digest is of the whole file, so the holes of a sparse file are hashed as zeros.
+        let mut holes = HoleFiller::new(entry.info.holes.clone());
+        loop {
+            // Stop between chunks if asked to. Chunks already stored are left for the
+            // garbage collector, as no entry will reference them.
+            self.cancel.check()?;
+
+            // A read error ends the file; the size warning below reports it.
+            let chunk = match chunks.next() {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(_)) | None => break,
+            };
+            let chunk_len = chunk.len();
+            file_len += chunk_len as u64;
+            if let Some(ref mut sha) = sha256 {
+                holes.feed(&chunk[..chunk_len], |data| sha.update(data));
+            }
+            match prefix.next() {
+                Some((id, href)) => {
+                    let hash = hash::Hash::new(
+                        &self.keys,
+                        blob::NodeType::Leaf,
+                        blob::LeafType::FileChunk,
+                        &chunk[..chunk_len],
+                    );
+                    if hash == href.hash {
+                        tree.append_known(id, href)?;
+                    } else {
+                        // The file was rewritten: chunk the rest as usual.
+                        prefix = vec![].into_iter();
+                        tree.append(&chunk[..chunk_len])?;
+                    }
+                }
+                None => tree.append(&chunk[..chunk_len])?,
+            }
+        }
+
+        if entry.info.content_not_captured {
+            // Nothing was read, so there is neither a size to check nor a digest.
+            sha256 = None;
+        } else {
+            // Warn the user if we did not read the expected size:
+            entry.info.byte_length.map(|s| {
+                file_size_warning(&entry.info.name, s - hole_bytes(&entry.info.holes), file_len);
+            });
+        }
+
+        if let Some(ref mut sha) = sha256 {
+            holes.finish(|data| sha.update(data));
+        }
+        entry.info.sha256 = sha256.map(|sha| sha.finish());
+
+        // Get top tree hash:
+        let mut hash_ref = tree.hash(Some(&entry.info))?;
+
+        // The blob store keeps the info to itself; the index needs it to list the entry again.
+        // The name is already in the index, where it may be sealed.
+        let mut info = entry.info.clone();
+        info.name = vec![];
+        hash_ref.info = Some(info);
+
+        // It is OK that this has is not yet valid, as we check hashes at snapshot time.
+        debug!("Insert entry: {:?}", entry.info.name);
+        let entry = self.index.insert(entry, Some(&hash_ref))?;
+
+        Ok(entry.node_id.unwrap())
     }
 }
 
@@ -264,93 +626,13 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
             }
 
             Msg::Insert(insert_entry, chunk_it_opt) => {
-                let entry = match self.index.lookup(
-                    insert_entry.parent_id,
-                    insert_entry.info.name.clone(),
-                )? {
-                    Some(ref stored_entry) if insert_entry.data_looks_unchanged(stored_entry) => {
-                        match &stored_entry.data {
-                            &Data::FileHash(ref hash_bytes) if chunk_it_opt.is_some() => {
-                                let hash = hash::Hash { bytes: hash_bytes.to_vec() };
-                                if self.hash_index.hash_exists(&hash) {
-                                    // Short-circuit: We have the data.
-                                    debug!("Skip entry: {:?}", stored_entry.info.name);
-                                    self.index.mark_reserved(&stored_entry)?;
-                                    return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                                }
-                            }
-                            _ if chunk_it_opt.is_none() => {
-                                // Short-circuit: No data needed.
-                                debug!("Skip empty entry: {:?}", stored_entry.info.name);
-                                self.index.mark_reserved(&stored_entry)?;
-                                return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                            }
-                            _ => (),
-                        }
-                        // Our stored entry is incomplete.
-                        Entry {
-                            node_id: stored_entry.node_id,
-                            ..insert_entry
-                        }
-                    }
-                    Some(entry) => {
-                        Entry {
-                            node_id: entry.node_id,
-                            ..insert_entry
-                        }
-                    }
-                    None => insert_entry,
-                };
-
-                // Check if we have an data source:
-                let it_opt = chunk_it_opt.and_then(|open| open.call(()));
-                if it_opt.is_none() {
-                    // No data is associated with this entry.
-                    debug!("Insert entry: {:?}", entry.info.name);
-                    let entry = self.index.insert(entry, None)?;
-
-                    // Bail out before storing data that does not exist:
-                    return reply_ok!(Reply::Id(entry.node_id.unwrap()));
-                }
-
-                // Setup hash tree structure
-                let mut tree = self.hash_tree_writer(blob::LeafType::FileChunk);
-
-                // Read and insert all file chunks:
-                // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
-                let mut reader = it_opt.unwrap();
-                let mut file_len = 0u64;
-                loop {
-                    let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
-                        chunk_len += match reader.read(&mut chunk[chunk_len..]) {
-                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
-                            Ok(size) => size,
-                        }
-                    }
-                    if chunk_len == 0 {
-                        break;
-                    }
-                    file_len += chunk_len as u64;
-                    tree.append(&chunk[..chunk_len])?
-                }
-
-                // Warn the user if we did not read the expected size:
-                entry.info.byte_length.map(|s| {
-                    file_size_warning(&entry.info.name, s, file_len);
-                });
-
-                // Get top tree hash:
-                let hash_ref = tree.hash(Some(&entry.info))?;
-
-                // It is OK that this has is not yet valid, as we check hashes at snapshot time.
-                debug!("Insert entry: {:?}", entry.info.name);
-                let entry = self.index.insert(entry, Some(&hash_ref))?;
+                let id = self.insert(insert_entry, chunk_it_opt, None)?;
+                reply_ok!(Reply::Id(id))
+            }
 
-                return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+            Msg::InsertWithReference(insert_entry, chunk_it_opt, reference) => {
+                let id = self.insert(insert_entry, chunk_it_opt, Some(reference))?;
+                reply_ok!(Reply::Id(id))
             }
         }
     }
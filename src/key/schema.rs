@@ -45,6 +45,34 @@ table! {
     }
 }
 
+table! {
+    key_dir_refs (node_id) {
+        node_id -> BigInt,
+        hash_ref -> Binary,
+    }
+}
+
+table! {
+    key_names (node_id) {
+        node_id -> BigInt,
+        ciphertext -> Binary,
+    }
+}
+
+table! {
+    key_settings {
+        id -> BigInt,
+        encrypt_filenames -> Bool,
+    }
+}
+
+table! {
+    key_xattrs (node_id) {
+        node_id -> BigInt,
+        attributes -> Binary,
+    }
+}
+
 joinable!(key_data -> key_tree (node_id));
 
 // Rust models.
@@ -104,3 +132,31 @@ pub struct NewKeyData<'a> {
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
 }
+
+#[derive(Insertable)]
+#[table_name = "key_dir_refs"]
+pub struct NewKeyDirRef<'a> {
+    pub node_id: i64,
+    pub hash_ref: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "key_names"]
+pub struct NewKeyName<'a> {
+    pub node_id: i64,
+    pub ciphertext: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "key_settings"]
+pub struct NewKeySettings {
+    pub id: i64,
+    pub encrypt_filenames: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "key_xattrs"]
+pub struct NewKeyXattrs<'a> {
+    pub node_id: i64,
+    pub attributes: &'a [u8],
+}
@@ -0,0 +1,119 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Boundaries between the members of a tar archive.
+//!
+//! Each member is a 512-byte header followed by its data, padded to a multiple of 512 bytes, so
+//! the position of every header follows from the size in the header before it. Cutting at
+//! headers keeps the chunks of a member the same when members around it change, are added or
+//! are removed. Only ustar archives, as written by GNU tar and most others, are recognized.
+
+use key::chunker::{BoundaryCursor, BoundaryHint};
+
+
+const BLOCK: usize = 512;
+
+pub struct TarHint;
+
+impl BoundaryHint for TarHint {
+    fn name(&self) -> &'static str {
+        "tar"
+    }
+
+    fn follow(&self, head: &[u8]) -> Option<Box<BoundaryCursor>> {
+        let header = match head.get(..BLOCK) {
+            Some(header) => header,
+            None => return None,
+        };
+        if &header[257..262] == b"ustar" && member_size(header).is_some() {
+            Some(Box::new(TarCursor { header: Some(0) }))
+        } else {
+            None
+        }
+    }
+}
+
+struct TarCursor {
+    /// Where the next header to read starts, once it has been found.
+    header: Option<u64>,
+}
+
+impl BoundaryCursor for TarCursor {
+    fn boundaries(&mut self, offset: u64, data: &[u8]) -> Vec<u64> {
+        let mut out = vec![];
+        while let Some(header) = self.header {
+            if header < offset {
+                // Chunked past before it could be read; the rest is left to the rolling hash.
+                self.header = None;
+                break;
+            }
+            let at = (header - offset) as usize;
+            if at + BLOCK > data.len() {
+                break;
+            }
+            self.header = member_size(&data[at..at + BLOCK]).map(|size| {
+                let padded = (size + BLOCK as u64 - 1) / BLOCK as u64 * BLOCK as u64;
+                header + BLOCK as u64 + padded
+            });
+            // The end of the archive is a boundary as well, so that the last member keeps its
+            // chunks when another is appended after it.
+            if let Some(next) = self.header {
+                out.push(next);
+            }
+        }
+        out
+    }
+}
+
+/// Size of the data after the header `block`, or `None` if it is not a valid header, like the
+/// zero blocks at the end of an archive.
+fn member_size(block: &[u8]) -> Option<u64> {
+    let stored = match parse_octal(&block[148..156]) {
+        Some(sum) => sum,
+        None => return None,
+    };
+    // The checksum is taken with its own field as spaces.
+    let sum: u64 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| (if i >= 148 && i < 156 { b' ' } else { b }) as u64)
+        .sum();
+    if sum != stored {
+        return None;
+    }
+
+    let field = &block[124..136];
+    if field[0] & 0x80 != 0 {
+        // GNU base-256 for sizes that do not fit in octal.
+        Some(field[1..].iter().fold(0u64, |n, &b| (n << 8) | b as u64))
+    } else {
+        parse_octal(field)
+    }
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits: Vec<u8> = field
+        .iter()
+        .cloned()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect();
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().fold(Some(0u64), |n, &b| match (n, b) {
+        (Some(n), b'0'...b'7') => Some((n << 3) | (b - b'0') as u64),
+        _ => None,
+    })
+}
@@ -14,6 +14,8 @@
 
 
 use backend::{MemoryBackend, StoreBackend};
+use crypto::CipherText;
+use hash;
 use key::*;
 
 use quickcheck;
@@ -22,6 +24,7 @@ use rand::Rng;
 use rand::thread_rng;
 use std::io;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use util::Process;
 
 fn random_ascii_bytes() -> Vec<u8> {
@@ -105,6 +108,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
                         group_id: None,
 
                         hat_snapshot_ts: 0,
+                        sha256: None,
+                        extended_attributes: vec![],
+                        holes: vec![],
+                        content_not_captured: false,
                     },
                 },
             };
@@ -134,6 +141,10 @@ fn rng_filesystem(size: usize) -> FileSystem {
                 group_id: None,
                 byte_length: None,
                 hat_snapshot_ts: 0,
+                sha256: None,
+                extended_attributes: vec![],
+                holes: vec![],
+                content_not_captured: false,
             },
         },
     };
@@ -255,3 +266,249 @@ fn identity() {
     }
     quickcheck::quickcheck(prop as fn(u8) -> bool);
 }
+
+fn insert_file<B: StoreBackend>(
+    ks_p: &StoreProcess<io::Cursor<Vec<u8>>, B>,
+    contents: Vec<u8>,
+    modified: u64,
+) -> hash::tree::HashRef {
+    let mut entry = Entry::new(None, b"log".to_vec(), Data::FilePlaceholder, None);
+    entry.info.modified_ts_secs = Some(modified);
+    entry.info.byte_length = Some(contents.len() as u64);
+    match ks_p.send_reply(Msg::Insert(
+        entry,
+        Some(Box::new(move |()| Some(io::Cursor::new(contents)))),
+    )).unwrap() {
+        Reply::Id(_) => (),
+        _ => panic!("unexpected reply from key store"),
+    }
+    match ks_p.send_reply(Msg::CommitReservedNodes(None)).unwrap() {
+        Reply::Ok => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+    match ks_p.send_reply(Msg::Flush).unwrap() {
+        Reply::FlushOk => (),
+        _ => panic!("Unexpected result from key store."),
+    }
+
+    match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls.into_iter().next().unwrap().1.unwrap(),
+        _ => panic!("Unexpected result from key store."),
+    }
+}
+
+fn leaf_ids(hash_index: &hash::HashIndex, top: &hash::tree::HashRef) -> Vec<u64> {
+    let mut leafs = vec![];
+    let mut stack = vec![hash_index.get_id(&top.hash).unwrap()];
+    while let Some(id) = stack.pop() {
+        match hash_index.get_hash(id).unwrap().childs {
+            Some(childs) => stack.extend(childs.into_iter().rev()),
+            None => leafs.push(id),
+        }
+    }
+    leafs
+}
+
+fn read_file<B: StoreBackend>(ks_p: &StoreProcess<io::Cursor<Vec<u8>>, B>) -> Vec<u8> {
+    let tree = match ks_p.send_reply(Msg::ListDir(None)).unwrap() {
+        Reply::ListResult(ls) => ls.into_iter().next().unwrap().2.unwrap(),
+        _ => panic!("Unexpected result from key store."),
+    };
+    let mut contents = vec![];
+    for chunk in tree.init().unwrap().unwrap() {
+        contents.extend_from_slice(&chunk[..]);
+    }
+    contents
+}
+
+/// Counts the blobs read back, to tell chunks that were reused from chunks that were compared
+/// with the stored copy before being deduplicated.
+struct CountingBackend {
+    inner: MemoryBackend,
+    retrieves: AtomicUsize,
+}
+
+impl StoreBackend for CountingBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+        self.inner.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retrieves.fetch_add(1, Ordering::SeqCst);
+        self.inner.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn append_reuses_prefix_chunks() {
+    let backend = Arc::new(CountingBackend {
+        inner: MemoryBackend::new(),
+        retrieves: AtomicUsize::new(0),
+    });
+    // Deduplicated chunks are fetched and compared, reused ones are not.
+    let store = Store::new_for_testing(backend.clone(), 4 * 1024 * 1024)
+        .unwrap()
+        .with_verify_dedup(true);
+    let hash_index = store.hash_index.clone();
+    let ks_p = Process::new(store);
+
+    let mut contents: Vec<u8> = (0..3 * CHUNK_SIZE + 100).map(|i| i as u8).collect();
+    let v1 = leaf_ids(&hash_index, &insert_file(&ks_p, contents.clone(), 1));
+    assert_eq!(v1.len(), 4);
+    let known = hash_index.list().len();
+
+    // Only the grown last chunk and the new top of the tree are added, and the unchanged
+    // chunks are not fetched to be compared.
+    contents.extend_from_slice(&[7; 1000]);
+    let retrieves = backend.retrieves.load(Ordering::SeqCst);
+    let v2 = leaf_ids(&hash_index, &insert_file(&ks_p, contents.clone(), 2));
+    // Only the previous tree is read back, to find its chunks.
+    assert!(backend.retrieves.load(Ordering::SeqCst) - retrieves <= 1);
+    assert_eq!(&v1[..3], &v2[..3]);
+    assert!(v1[3] != v2[3]);
+    assert_eq!(hash_index.list().len(), known + 2);
+    assert_eq!(read_file(&ks_p), contents);
+
+    // A rewritten and truncated file is chunked from the first changed chunk onwards.
+    contents.truncate(2 * CHUNK_SIZE + 10);
+    contents[CHUNK_SIZE] ^= 1;
+    let v3 = leaf_ids(&hash_index, &insert_file(&ks_p, contents.clone(), 3));
+    assert_eq!(v3.len(), 3);
+    assert_eq!(v3[0], v1[0]);
+    assert!(v3[1] != v1[1]);
+    assert_eq!(read_file(&ks_p), contents);
+}
+
+#[test]
+fn oversize_chunk_is_refused() {
+    let backend = Arc::new(MemoryBackend::new());
+    let ks_p = Process::new(Store::new_for_testing(backend, 4096).unwrap());
+
+    let entry = Entry::new(None, b"big".to_vec(), Data::FilePlaceholder, None);
+    let res = ks_p.send_reply(Msg::Insert(
+        entry,
+        Some(Box::new(move |()| Some(io::Cursor::new(vec![3; 8192])))),
+    ));
+    assert!(res.is_err());
+
+    // The refused chunk does not hold up later inserts or flushes.
+    insert_file(&ks_p, vec![4; 100], 1);
+    assert_eq!(read_file(&ks_p), vec![4; 100]);
+}
+
+#[test]
+fn encrypted_filenames() {
+    use crypto::keys::Keeper;
+
+    let index = KeyIndex::new_for_testing().unwrap();
+    assert!(index.set_filename_keys(Arc::new(Keeper::new_for_testing()), true).unwrap());
+
+    let names: Vec<Vec<u8>> = vec![b"zeta".to_vec(), b"alpha".to_vec(), b"secret.txt".to_vec()];
+    for name in names.iter() {
+        index.insert(Entry::new(None, name.clone(), Data::DirPlaceholder, None), None).unwrap();
+    }
+    index.commit_reserved_nodes().unwrap();
+
+    // Neither the name column nor the ciphertext holds the name.
+    let stored = index.stored_names().unwrap();
+    assert_eq!(stored.len(), names.len());
+    for (tag, locked) in stored {
+        let locked = locked.expect("name is encrypted");
+        for name in names.iter() {
+            assert!(tag != *name);
+            assert!(!locked.windows(name.len()).any(|w| w == &name[..]));
+        }
+    }
+
+    // Listing recovers the names, in name order.
+    let listed: Vec<Vec<u8>> = index.list_dir(None)
+        .unwrap()
+        .into_iter()
+        .map(|(entry, _)| entry.info.name)
+        .collect();
+    assert_eq!(listed, vec![b"alpha".to_vec(), b"secret.txt".to_vec(), b"zeta".to_vec()]);
+
+    // Names are found through their tag.
+    let found = index.lookup(None, b"secret.txt".to_vec()).unwrap().unwrap();
+    assert_eq!(found.info.name, b"secret.txt".to_vec());
+    assert!(index.lookup(None, b"missing".to_vec()).unwrap().is_none());
+
+    // The same name encrypts the same way, so that no nonce is ever used for two names.
+    let parent = index.lookup(None, b"alpha".to_vec()).unwrap().unwrap().node_id;
+    index.insert(Entry::new(parent, b"zeta".to_vec(), Data::DirPlaceholder, None), None).unwrap();
+    index.commit_reserved_nodes().unwrap();
+    let mut locked: Vec<Vec<u8>> =
+        index.stored_names().unwrap().into_iter().map(|(_, l)| l.unwrap()).collect();
+    locked.sort();
+    locked.dedup();
+    assert_eq!(locked.len(), names.len());
+
+    // The family keeps encrypting names once it has, even when not asked to.
+    assert!(index.set_filename_keys(Arc::new(Keeper::new_for_testing()), false).unwrap());
+    index.insert(Entry::new(None, b"later".to_vec(), Data::DirPlaceholder, None), None).unwrap();
+    index.commit_reserved_nodes().unwrap();
+    assert!(index.stored_names().unwrap().iter().all(|&(ref tag, ref locked)| {
+        tag != b"later" && locked.is_some()
+    }));
+
+    // Another key neither finds nor reads them.
+    index.set_filename_keys(Arc::new(Keeper::new_for_testing_with_key(vec![1; 32])), true)
+        .unwrap();
+    assert!(index.lookup(None, b"secret.txt".to_vec()).unwrap().is_none());
+    assert!(index.list_dir(None).is_err());
+}
+
+#[test]
+fn verify_dedup_detects_collisions() {
+    use blob::{LeafType, NodeType};
+    use hash::tree::HashTreeBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let store = Store::new_for_testing(backend, 4096).unwrap();
+    // Every chunk gets the same hash.
+    let collide: Arc<Fn(&[u8]) -> hash::Hash + Send + Sync> =
+        Arc::new(|_: &[u8]| hash::Hash { bytes: vec![7; 32] });
+    let hash_backend = |verify| {
+        HashStoreBackend::new(
+            store.hash_index.clone(),
+            store.blob_store.clone(),
+            store.keys.clone(),
+        ).with_hasher(collide.clone())
+            .with_verify_dedup(verify)
+    };
+    let insert = |backend: &HashStoreBackend<MemoryBackend>, chunk: &[u8]| {
+        backend.insert_chunk(chunk, NodeType::Leaf, LeafType::FileChunk, None, None)
+    };
+
+    let (id, _) = insert(&hash_backend(true), b"original").unwrap();
+
+    // Without verification the colliding chunk silently becomes the stored one.
+    let unverified = hash_backend(false);
+    assert_eq!(insert(&unverified, b"imposter").unwrap().0, id);
+    assert_eq!(
+        unverified
+            .fetch_chunk(&insert(&unverified, b"imposter").unwrap().1)
+            .unwrap(),
+        Some(b"original".to_vec())
+    );
+
+    // Verification compares with the chunk in the unflushed blob, and after upload.
+    let verified = hash_backend(true);
+    assert!(insert(&verified, b"imposter").is_err());
+    assert_eq!(insert(&verified, b"original").unwrap().0, id);
+    store.blob_store.flush().unwrap();
+    assert!(insert(&verified, b"imposter").is_err());
+    assert_eq!(insert(&verified, b"original").unwrap().0, id);
+}
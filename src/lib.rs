@@ -36,6 +36,10 @@ extern crate secstr;
 extern crate scoped_pool;
 extern crate void;
 extern crate filetime;
+extern crate flate2;
+extern crate libc;
+#[cfg(feature = "xattrs")]
+extern crate xattr;
 
 // Error definition macros.
 #[macro_use]
@@ -46,6 +50,7 @@ extern crate error_type;
 extern crate diesel;
 #[macro_use]
 extern crate diesel_codegen;
+extern crate libsqlite3_sys;
 
 // Testing utilities.
 #[cfg(test)]
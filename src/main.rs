@@ -16,17 +16,22 @@
 extern crate hat;
 
 // Rust crates.
+extern crate chrono;
 extern crate env_logger;
-extern crate libsodium_sys;
+extern crate hex;
 
 // We use Clap for argument parsing.
 #[macro_use]
 extern crate clap;
 
 use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
 use clap::{App, SubCommand};
+use hex::{FromHex, ToHex};
 
 use hat::backend;
+use hat::hat::{BackupError, ErrorKind, ErrorReport, HatError, HatRc};
 use std::borrow::ToOwned;
 use std::convert::From;
 use std::path::{Path, PathBuf};
@@ -38,6 +43,108 @@ fn blob_dir() -> PathBuf {
     PathBuf::from("blobs")
 }
 
+/// Parse a duration like "90s", "30m", "12h" or "7d". A plain number is in seconds.
+fn parse_duration(s: &str) -> Option<chrono::Duration> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let n = match number.parse::<i64>() {
+        Ok(n) if n >= 0 => n,
+        _ => return None,
+    };
+    match unit {
+        's' => Some(chrono::Duration::seconds(n)),
+        'm' => Some(chrono::Duration::minutes(n)),
+        'h' => Some(chrono::Duration::hours(n)),
+        'd' => Some(chrono::Duration::days(n)),
+        _ => None,
+    }
+}
+
+/// Where the store is kept and how its blobs are written and read, taken once from the flags,
+/// the environment and the configuration file.
+struct RepositorySettings {
+    migrations_dir: PathBuf,
+    cache_dir: PathBuf,
+    max_blob_size: usize,
+    sync_batch: backend::SyncBatch,
+    mmap_reads: bool,
+    shard_depth: usize,
+}
+
+/// Reports failures and exits with the code of their kind. With `--json-errors`, failures are
+/// written to stderr as a JSON object (see `ErrorReport::to_json`) instead of as text.
+struct Reporter {
+    json: bool,
+    /// Report failures as text on stderr too, for commands whose stdout is data.
+    stderr: bool,
+}
+
+impl Reporter {
+    fn fail(&self, report: ErrorReport) -> ! {
+        if self.json {
+            let _ = writeln!(io::stderr(), "{}", report.to_json());
+        } else if self.stderr {
+            let _ = writeln!(io::stderr(), "{}", report);
+        } else {
+            println!("{}", report);
+        }
+        std::process::exit(report.kind.exit_code());
+    }
+
+    fn usage<S: Into<String>>(&self, message: S) -> ! {
+        self.fail(ErrorReport::new(ErrorKind::Usage, message))
+    }
+
+    fn check<T>(&self, result: Result<T, HatError>, context: &[(&str, &str)]) -> T {
+        match result {
+            Ok(value) => value,
+            Err(e) => {
+                let mut report = ErrorReport::from(&BackupError::from(e));
+                for &(key, value) in context {
+                    report = report.with_context(key, value);
+                }
+                self.fail(report)
+            }
+        }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, flag: &str, value: &str) -> T {
+        match value.parse() {
+            Ok(value) => value,
+            Err(_) => self.usage(format!("{} must be a number, not {:?}", flag, value)),
+        }
+    }
+
+    fn open_repository(&self, settings: &RepositorySettings) -> HatRc<backend::FileBackend> {
+        let backend = Arc::new(
+            backend::FileBackend::new(blob_dir())
+                .with_sync_batch(settings.sync_batch)
+                .with_mmap_reads(settings.mmap_reads)
+                .with_shard_depth(settings.shard_depth),
+        );
+        let blob_dir_str = blob_dir().display().to_string();
+        self.check(
+            backend.check_shard_depth().map_err(From::from),
+            &[("blob_dir", &blob_dir_str[..])],
+        );
+        let cache_dir_str = settings.cache_dir.display().to_string();
+        let hat = self.check(
+            hat::Hat::open_repository(
+                &settings.migrations_dir,
+                settings.cache_dir.clone(),
+                backend,
+                settings.max_blob_size,
+            ),
+            &[("cache_dir", &cache_dir_str[..])],
+        );
+        // Ctrl-C stops the command at the next safe point, leaving the store consistent.
+        hat.cancellation_token().cancel_on_interrupt();
+        hat
+    }
+}
+
 fn license() {
     println!(include_str!("../LICENSE"));
     println!("clap (Command Line Argument Parser) License:");
@@ -60,17 +167,92 @@ fn main() {
         .args_from_usage(
             "-l, --license 'Display the license'
                           --hat_migrations_dir=[DIR] 'Location of Hat SQL migrations'
-                          --hat_cache_dir=[DIR] 'Location of Hat local state'",
+                          --hat_cache_dir=[DIR] 'Location of Hat local state'
+                          --hat_config=[FILE] 'Settings for the store \
+                          (default: hat.conf in the cache dir)'
+                          --hat_max_blob_size=[BYTES] 'Largest blob to store (default: 4 MiB)'
+                          --hat_max_uploads=[N] 'Blobs to upload at the same time (default: 4)'
+                          --hat_fsync_batch=[N] 'Blobs covered by one fsync (default: 1)'
+                          --hat_fsync_delay_ms=[MS] 'Most time a blob waits for fsync \
+                          (default: 1000)'
+                          --hat_mmap_reads 'Map blob files into memory to read them, instead of \
+                          copying them'
+                          --hat_shard_depth=[N] 'Levels of subdirectories to keep blob files in \
+                          (default: 0)'
+                          --json-errors 'Report failures as a JSON object on stderr'",
         )
         .subcommand(
             SubCommand::with_name("commit")
                 .about("Commit a new snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(arg_template)
+                .args_from_usage(
+                    "-x --one-file-system 'Do not descend into directories on other filesystems'
+                     --follow-mounts=[DIR]... 'With -x, still descend into filesystems mounted \
+                     at or below DIR'
+                     --exclude-caches 'Do not store the contents of directories tagged as \
+                     caches with a CACHEDIR.TAG file'
+                     --rolling-chunker 'Cut files where their contents say, instead of into \
+                     fixed-size chunks'
+                     --rolling-window=[BYTES] 'Bytes covered by the rolling hash (default: 48)'
+                     --rolling-seed=[N] 'Seed for the rolling hash table (default: 0)'
+                     --boundary-hints=[FORMATS] 'With --rolling-chunker, cut files in these \
+                     formats between their records where possible, e.g. tar'
+                     --fanout=[N] 'Children per branch node of the hash trees (default: 8)'
+                     --read-concurrency=[N] 'Files and file segments to read at the same time \
+                     (default: 1)'
+                     --max-file-size=[BYTES] 'Store files larger than this apart from the \
+                     others, one at a time and in blobs of their own'
+                     --large-file-concurrency=[N] 'Segments of such a file to read at the same \
+                     time (default: 4)'
+                     --chunk-cache-size=[BYTES] 'Memory for remembering the chunks stored \
+                     during this commit (default: 16 MiB)'
+                     --link-dest=[DIR] 'Reuse the chunks of identical files at the same place \
+                     below DIR, committed to this family before'
+                     --expected-size=[BYTES] 'Bytes of file contents expected, to show the time \
+                     left along with the progress'
+                     --encrypt-filenames 'Keep file names encrypted in the local index'
+                     --chunk-pipeline=[STAGES] 'Stages to put chunks through, joined by +, \
+                     e.g. gzip+seal or seal+pad:4096 (default: seal)'
+                     --record-padding 'Authenticate the padding length of new blobs; \
+                     the store then needs a newer hat to read'
+                     --cold-after=[DURATION] 'Ask the backend to keep data of files unmodified \
+                     for this long in a colder storage class, e.g. 90d'
+                     --cold-class=[CLASS] 'Storage class for such data: infrequent-access \
+                     (default) or archive'
+                     --sha256 'Also keep a plain SHA-256 of every file, for sha256-manifest'
+                     --xattrs 'Also keep extended attributes and ACLs, restored by checkout'
+                     --sparse 'Keep the holes of sparse files instead of reading them as \
+                     zeros, restored by checkout as holes'
+                     --metadata-only 'Record the tree and file metadata without reading any \
+                     file; changed files can not be restored from the snapshot'
+                     --verify-dedup 'Compare chunks with the stored chunk of the same hash \
+                     before reusing it, and fail on a hash collision'
+                     --atomic-source-snapshot 'Read PATH from a btrfs snapshot of it, taken \
+                     first and removed after, so that changes meanwhile are not seen'
+                     --time-machine 'Commit after the latest snapshot of this family, reading \
+                     only files whose modification time changed, and report what was read'
+                     --batch=[DIR]... 'Also back up DIR, next to PATH in the same snapshot; \
+                     none of them may be inside another'
+                     --hash-salt 'Key the content hashes of a new store with a secret salt, so \
+                     that they can not be matched against those of other stores'",
+                ),
         )
         .subcommand(
             SubCommand::with_name("checkout")
                 .about("Checkout a snapshot")
-                .args_from_usage(arg_template),
+                .args_from_usage(arg_template)
+                .args_from_usage(
+                    "--path-policy=[POLICY] 'How to map names onto the target filesystem: \
+                     posix (default) or windows'
+                     --include=[PATTERN]... 'Only restore paths matching this prefix or glob'
+                     --exclude=[PATTERN]... 'Do not restore paths matching this prefix or glob'
+                     --max-memory=[BYTES] 'Restore one chunk at a time, buffering at most this \
+                     many bytes'
+                     --dry-run 'Only list what would be restored and what it would overwrite'
+                     --numeric-ids 'Give entries the owner and group ids they were stored with'
+                     --keyfile=[FILE]... 'Also read snapshots sealed with the key derived from \
+                     the passphrase in FILE'",
+                ),
         )
         .subcommand(SubCommand::with_name("recover").about(
             "Recover list of commit'ed snapshots",
@@ -87,11 +269,145 @@ fn main() {
         .subcommand(
             SubCommand::with_name("gc")
                 .about("Garbage collect: identify and remove unused data blocks.")
-                .args_from_usage("-p --pretend 'Do not modify any data'"),
+                .args_from_usage(
+                    "-p --pretend 'Do not modify any data'
+                     --keep-unreferenced-for=[DURATION] 'Only delete data that has been \
+                     unreferenced for this long, e.g. 3600s, 30m, 12h or 7d'
+                     --verify-reachability 'Abort if data about to be deleted is still \
+                     reachable'
+                     --paranoid 'Also find unused data twice, and abort unless both agree'
+                     --parallel=[N] 'Threads to find used data and delete unused blobs with \
+                     (default: 1)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("consolidate")
+                .about("Merge blobs holding little live data into fewer, fuller blobs")
+                .args_from_usage(
+                    "--min-live=[BYTES] 'Merge blobs with less live data than this \
+                     (default: half the max blob size)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about("Seal all stored chunks again with another key algorithm")
+                .args_from_usage(
+                    "--algorithm=[NAME] 'Key algorithm to move to (default: the one new \
+                     backups use)'
+                     --allow-uncommitted 'Allow moving to an algorithm without key commitment'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compare-to-source")
+                .about("Compare the latest snapshot with the current contents of its source")
+                .args_from_usage(arg_template),
+        )
+        .subcommand(
+            SubCommand::with_name("resolve")
+                .about("Show the full hash matching a short hash prefix")
+                .args_from_usage("<PREFIX> 'Leading hex digits of the hash'"),
+        )
+        .subcommand(
+            SubCommand::with_name("blob-info")
+                .about("Show the chunks stored in a blob, without decrypting their contents")
+                .args_from_usage(
+                    "--json 'Print one JSON object per chunk'
+                              <BLOB_ID> 'Id of the blob'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("blob-checksum")
+                .about("Check that the backend holds exactly the bytes uploaded for a blob")
+                .args_from_usage("<BLOB_ID> 'Id of the blob'"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Read back every chunk in the store and check it against its hash")
+                .args_from_usage(
+                    "--continue 'Resume the last verify, skipping chunks it already checked'
+                     --checkpoint=[FILE] 'Where to keep track of progress \
+                     (default: verify.checkpoint in the cache dir)'
+                     --max-chunks=[N] 'Stop after verifying this many chunks'
+                     --parallel=[N] 'Chunks to read back at the same time (default: 1)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Read back the chunks that have gone longest without being checked")
+                .args_from_usage(
+                    "--period-runs=[N] 'Runs that together check every chunk (default: 30)'
+                     --concurrency=[N] 'Chunks to read back at the same time (default: 1)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Write the contents of a file in the latest snapshot to stdout")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     <PATH> 'Path of the file in the snapshot'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("checkout-tar")
+                .about("Write the latest snapshot as a tar archive")
+                .args_from_usage(
+                    "<NAME> 'Name of the snapshot family'
+                     [FILE] 'Where to write the archive; stdout if missing or -'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sha256-manifest")
+                .about("Print the SHA-256 of every file in the latest snapshot, as sha256sum does")
+                .args_from_usage("<NAME> 'Name of the snapshot family'"),
+        )
+        .subcommand(
+            SubCommand::with_name("du")
+                .about("Show logical and deduplicated size per directory in the latest snapshot")
+                .args_from_usage(
+                    "-d, --max-depth=[DEPTH] 'Only list directories this deep below the root'
+                              <NAME> 'Name of the snapshot family'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sharing")
+                .about("Show bytes each snapshot references alone and shares with others")
+                .args_from_usage("[NAME] 'Only list snapshots of this family'"),
         )
         .subcommand(SubCommand::with_name("resume").about(
             "Resume previous failed command.",
         ))
+        .subcommand(SubCommand::with_name("env-check").about(
+            "Check that the crypto library works on this host.",
+        ))
+        .subcommand(SubCommand::with_name("doctor").about(
+            "Check the crypto library, backend, index, a sample of blobs and the clock.",
+        ))
+        .subcommand(SubCommand::with_name("export-proof").about(
+            "Print a signed proof of all snapshots and the data they consist of.",
+        ))
+        .subcommand(
+            SubCommand::with_name("index-export")
+                .about("Write an encrypted copy of the index, to keep apart from the blobs")
+                .args_from_usage("<FILE> 'Where to write the copy'"),
+        )
+        .subcommand(
+            SubCommand::with_name("audit-log")
+                .about("List the logged snapshot commits and deletions, GC runs and compactions")
+                .args_from_usage("--verify 'Check that the log was not tampered with'"),
+        )
+        .subcommand(
+            SubCommand::with_name("index-import")
+                .about("Replace the index with a copy written by index-export")
+                .args_from_usage("<FILE> 'Copy to read'"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-proof")
+                .about("Check the signature of a proof created by export-proof")
+                .args_from_usage(
+                    "<FILE> 'Proof to check'
+                              <PUBLIC_KEY> 'Expected manifest public key, in hex'",
+                ),
+        )
         .get_matches();
 
     // Check for license flag
@@ -100,6 +416,51 @@ fn main() {
         std::process::exit(0);
     }
 
+    let reporter = Reporter {
+        json: matches.is_present("json-errors"),
+        stderr: false,
+    };
+
+    // The environment check does not need a repository.
+    if matches.subcommand_matches("env-check").is_some() {
+        match hat::hat::check_environment() {
+            Ok(()) => println!("Crypto OK"),
+            Err(e) => {
+                let report = ErrorReport::from(&e);
+                reporter.fail(ErrorReport {
+                    message: format!("Crypto check failed: {}", report.message),
+                    ..report
+                })
+            }
+        }
+        std::process::exit(0);
+    }
+
+    // Proofs are meant to be checked without access to the repository.
+    if let Some(cmd) = matches.subcommand_matches("verify-proof") {
+        let file = cmd.value_of("FILE").unwrap();
+        let mut text = String::new();
+        if let Err(e) = fs::File::open(file).and_then(|mut f| f.read_to_string(&mut text)) {
+            reporter.fail(
+                ErrorReport::new(ErrorKind::Io, format!("Could not read proof: {}", e))
+                    .with_context("path", file),
+            );
+        }
+        let public_key = match Vec::from_hex(cmd.value_of("PUBLIC_KEY").unwrap()) {
+            Ok(key) => key,
+            Err(_) => reporter.usage("PUBLIC_KEY must be hex"),
+        };
+        let proof = reporter.check(hat::hat::Proof::from_text(&text), &[("path", file)]);
+        if !proof.verify(&public_key[..]) {
+            reporter.fail(
+                ErrorReport::new(ErrorKind::Crypto, "Proof signature is not valid for this key")
+                    .with_context("path", file),
+            );
+        }
+        println!("Proof OK: {} snapshots", proof.snapshots.len());
+        std::process::exit(0);
+    }
+
     let flag_or_env = |name: &str| {
         matches
             .value_of(name)
@@ -107,95 +468,727 @@ fn main() {
             .or_else(|| {
                 env::var_os(name.to_uppercase()).map(|s| s.into_string().unwrap())
             })
-            .expect(&format!("{} required", name))
+            .unwrap_or_else(|| reporter.usage(format!("{} required", name)))
     };
 
     // Setup config variables that can take their value from either flag or environment.
     let migrations_dir_str = flag_or_env("hat_migrations_dir");
-    let migrations_dir = Path::new(&migrations_dir_str);
     let cache_dir = PathBuf::from(flag_or_env("hat_cache_dir"));
 
-    // Initialize sodium (must only be called once)
-    unsafe { libsodium_sys::sodium_init() };
+    // Settings come from flags, then the environment, then the configuration file of the store.
+    let mut overrides = hat::hat::StoreConfig::default();
+    for key in &["max_blob_size", "max_uploads", "fsync_batch", "fsync_delay_ms", "shard_depth"] {
+        let flag = format!("hat_{}", key);
+        let value = matches.value_of(&flag[..]).map(|x| x.to_string()).or_else(|| {
+            env::var_os(flag.to_uppercase()).map(|s| s.into_string().unwrap())
+        });
+        if let Some(value) = value {
+            if let Err(e) = overrides.set(key, &value) {
+                reporter.usage(format!("{}: {}", flag, e));
+            }
+        }
+    }
+    if matches.is_present("hat_mmap_reads") || env::var_os("HAT_MMAP_READS").is_some() {
+        overrides.mmap_reads = Some(true);
+    }
+    let config_path = matches
+        .value_of("hat_config")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| cache_dir.join("hat.conf"));
+    let config_path_str = config_path.display().to_string();
+    let config = overrides.or(reporter.check(
+        hat::hat::StoreConfig::load(&config_path),
+        &[("path", &config_path_str[..])],
+    ));
+
+    let max_uploads = config.max_uploads.unwrap_or(hat::hat::DEFAULT_MAX_UPLOADS);
+    let mut sync_batch = backend::SyncBatch::default();
+    if let Some(n) = config.fsync_batch {
+        sync_batch.max_blobs = n;
+    }
+    if let Some(ms) = config.fsync_delay_ms {
+        sync_batch.max_delay = std::time::Duration::from_millis(ms);
+    }
+    let settings = RepositorySettings {
+        migrations_dir: PathBuf::from(migrations_dir_str),
+        cache_dir: cache_dir,
+        max_blob_size: config.max_blob_size.unwrap_or(MAX_BLOB_SIZE),
+        sync_batch: sync_batch,
+        mmap_reads: config.mmap_reads.unwrap_or(false),
+        shard_depth: config.shard_depth.unwrap_or(0),
+    };
 
     match matches.subcommand() {
         ("resume", Some(_cmd)) => {
             // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+            reporter.open_repository(&settings);
         }
         ("commit", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = reporter.open_repository(&settings);
+            if cmd.is_present("hash-salt") {
+                reporter.check(hat.enable_hash_salt(), &[]);
+            }
+            hat.set_max_uploads(max_uploads);
+            if let Some(bytes) = cmd.value_of("chunk-cache-size") {
+                hat.set_chunk_cache_size(reporter.parse("chunk-cache-size", bytes));
+            }
+            if cmd.is_present("encrypt-filenames") {
+                reporter.check(hat.set_encrypt_filenames(true), &[]);
+            }
+            if let Some(stages) = cmd.value_of("chunk-pipeline") {
+                reporter.check(hat.set_chunk_pipeline(stages), &[("chunk_pipeline", stages)]);
+            }
+            if cmd.is_present("record-padding") {
+                reporter.check(hat.set_record_padding(true), &[]);
+            }
+            if cmd.is_present("sha256") {
+                reporter.check(hat.set_file_digests(true), &[]);
+            }
+            if cmd.is_present("verify-dedup") {
+                reporter.check(hat.set_verify_dedup(true), &[]);
+            }
+            if let Some(d) = cmd.value_of("cold-after") {
+                let mut policy = hat::hat::StoragePolicy::default();
+                policy.cold_after = Some(parse_duration(d).unwrap_or_else(|| {
+                    reporter.usage(format!("Invalid --cold-after: {}", d))
+                }));
+                if let Some(class) = cmd.value_of("cold-class") {
+                    policy.cold_class = hat::backend::StorageClass::from_name(class)
+                        .unwrap_or_else(|| {
+                            reporter.usage(format!("Unknown storage class: {}", class))
+                        });
+                }
+                reporter.check(hat.set_storage_policy(policy), &[]);
+            }
+            if cmd.is_present("rolling-chunker") {
+                let mut params = hat::hat::RollingParams::default();
+                if let Some(window) = cmd.value_of("rolling-window") {
+                    params.window_size = reporter.parse("rolling-window", window);
+                }
+                if let Some(seed) = cmd.value_of("rolling-seed") {
+                    params.table_seed = reporter.parse("rolling-seed", seed);
+                }
+                if let Some(hints) = cmd.value_of("boundary-hints") {
+                    params.hints = hints.split(',').map(|h| h.to_owned()).collect();
+                }
+                if let Err(e) = hat.set_chunker(hat::hat::Chunker::Rolling(params)) {
+                    reporter.usage(e.to_string());
+                }
+            }
+            if let Some(n) = cmd.value_of("fanout") {
+                if let Err(e) = hat.set_fanout(reporter.parse("fanout", n)) {
+                    reporter.usage(e.to_string());
+                }
+            }
 
             // Update the family index.
-            let mut family = hat.open_family(name.clone()).expect(&format!(
-                "Could not open family '{}'",
-                name
-            ));
-            family.snapshot_dir(PathBuf::from(path));
+            let context = [("family", &name[..]), ("path", path)];
+            let mut family = reporter.check(hat.open_family(name.clone()), &context);
+            let mut options = hat::hat::SnapshotOptions::default();
+            options.one_file_system = cmd.is_present("one-file-system");
+            options.follow_mounts = cmd.values_of("follow-mounts")
+                .into_iter()
+                .flat_map(|v| v)
+                .map(PathBuf::from)
+                .collect();
+            options.exclude_caches = cmd.is_present("exclude-caches");
+            options.extended_attributes = cmd.is_present("xattrs");
+            options.sparse_files = cmd.is_present("sparse");
+            options.metadata_only = cmd.is_present("metadata-only");
+            if cmd.is_present("atomic-source-snapshot") {
+                options.source_snapshot = Some(Arc::new(hat::hat::BtrfsSnapshot));
+            }
+            options.link_dest = cmd.value_of("link-dest").map(PathBuf::from);
+            if let Some(bytes) = cmd.value_of("expected-size") {
+                options.expected_bytes = Some(reporter.parse("expected-size", bytes));
+            }
+            if let Some(n) = config.read_concurrency {
+                options.read_concurrency = n;
+            }
+            options.max_file_size = config.max_file_size;
+            if let Some(n) = cmd.value_of("read-concurrency") {
+                options.read_concurrency = reporter.parse("read-concurrency", n);
+                if options.read_concurrency == 0 {
+                    reporter.usage("read-concurrency must be at least 1");
+                }
+            }
+            if let Some(bytes) = cmd.value_of("max-file-size") {
+                options.max_file_size = Some(reporter.parse("max-file-size", bytes));
+            }
+            if let Some(n) = cmd.value_of("large-file-concurrency") {
+                options.large_file_concurrency = reporter.parse("large-file-concurrency", n);
+                if options.large_file_concurrency == 0 {
+                    reporter.usage("large-file-concurrency must be at least 1");
+                }
+            }
+            let batch: Vec<PathBuf> = cmd.values_of("batch")
+                .into_iter()
+                .flat_map(|v| v)
+                .map(PathBuf::from)
+                .collect();
+            if !batch.is_empty() && cmd.is_present("time-machine") {
+                reporter.usage("--batch can not be used with --time-machine");
+            }
+            if cmd.is_present("time-machine") {
+                let done = reporter.check(
+                    hat.commit_incremental(&mut family, PathBuf::from(path), options),
+                    &context,
+                );
+                match done.parent {
+                    Some(parent) => {
+                        println!("Snapshot {} follows snapshot {}", done.snapshot_id, parent)
+                    }
+                    None => println!("Snapshot {} is the first of its family", done.snapshot_id),
+                }
+                println!(
+                    "Read {} of {} files",
+                    done.stats.read_files.len(),
+                    done.stats.files
+                );
+            } else if !batch.is_empty() {
+                let mut dirs = vec![PathBuf::from(path)];
+                dirs.extend(batch);
+                reporter.check(family.snapshot_dirs_with_options(dirs, options), &context);
+
+                // Commit the updated index.
+                reporter.check(hat.commit(&mut family, None), &context);
+            } else {
+                reporter.check(
+                    family.snapshot_dir_with_options(PathBuf::from(path), options),
+                    &context,
+                );
 
-            // Commit the updated index.
-            hat.commit(&mut family, None).unwrap();
+                // Commit the updated index.
+                reporter.check(hat.commit(&mut family, None), &context);
+            }
 
             // Meta commit.
-            hat.meta_commit().unwrap();
+            reporter.check(hat.meta_commit(), &context);
 
             // Flush any remaining blobs.
-            hat.data_flush().unwrap();
+            reporter.check(hat.data_flush(), &context);
+
+            if cmd.is_present("metadata-only") {
+                println!(
+                    "Metadata-only snapshot: files that changed were not read, and can not be \
+                     restored from it"
+                );
+            }
         }
         ("checkout", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = reporter.open_repository(&settings);
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            let mut options = hat::hat::RestoreOptions::default();
+            match cmd.value_of("path-policy") {
+                None | Some("posix") => (),
+                Some("windows") => options.policy = Box::new(hat::hat::WindowsPolicy),
+                Some(other) => reporter.usage(format!("Unknown path policy: {}", other)),
+            };
+            for pattern in cmd.values_of("include").into_iter().flat_map(|v| v) {
+                options.filter = options.filter.include(pattern);
+            }
+            for pattern in cmd.values_of("exclude").into_iter().flat_map(|v| v) {
+                options.filter = options.filter.exclude(pattern);
+            }
+            if let Some(bytes) = cmd.value_of("max-memory") {
+                options.memory_budget =
+                    Some(hat::hat::MemoryBudget::new(reporter.parse("max-memory", bytes)));
+            }
+            options.numeric_ids = cmd.is_present("numeric-ids");
+            let context = [("family", &name[..]), ("path", path)];
+            if let Some(files) = cmd.values_of("keyfile") {
+                let files: Vec<PathBuf> = files.map(PathBuf::from).collect();
+                let keyring = reporter.check(
+                    hat::hat::Keyring::from_keyfiles(&files[..]).map_err(HatError::from),
+                    &context,
+                );
+                hat.set_keyring(keyring);
+            }
+            let conflicts = if cmd.is_present("dry-run") {
+                let plan = reporter.check(
+                    hat.restore_plan(name.clone(), PathBuf::from(path), &options),
+                    &context,
+                );
+                for entry in plan.entries.iter() {
+                    println!(
+                        "{}{}\t{}{}",
+                        entry.path.display(),
+                        if entry.is_dir { "/" } else { "" },
+                        entry.size,
+                        if entry.exists { "\toverwrite" } else { "" }
+                    );
+                }
+                println!(
+                    "Would restore {} entries, {} bytes",
+                    plan.entries.len(),
+                    plan.total_bytes()
+                );
+                plan.conflicts
+            } else {
+                reporter.check(
+                    hat.checkout_in_dir_with_options(name.clone(), PathBuf::from(path), &options),
+                    &context,
+                )
+            };
+            for conflict in conflicts.iter() {
+                println!("Not restored: {}", conflict);
+            }
+            if !conflicts.is_empty() {
+                std::process::exit(1);
+            }
         }
         ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = reporter.open_repository(&settings);
 
-            hat.recover().unwrap();
+            reporter.check(hat.recover(), &[]);
         }
         ("delete", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let id = cmd.value_of("ID").unwrap().to_owned();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
+            let mut hat = reporter.open_repository(&settings);
 
-            hat.deregister_by_name(name, id.parse::<u64>().unwrap())
-                .unwrap();
+            let deleted = reporter.check(
+                hat.delete_snapshot(name.clone(), reporter.parse("ID", &id)),
+                &[("family", &name[..]), ("snapshot", &id[..])],
+            );
+            if !deleted {
+                println!("No snapshot {} #{}: nothing to delete", name, id);
+            }
         }
-        ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat =
-                hat::Hat::open_repository(migrations_dir, cache_dir, backend, MAX_BLOB_SIZE)
-                    .unwrap();
-            let (deleted_hashes, live_blobs) = hat.gc().unwrap();
+        ("gc", Some(cmd)) => {
+            let mut options = hat::hat::GcOptions::default();
+            options.grace = cmd.value_of("keep-unreferenced-for")
+                .map(|d| {
+                    parse_duration(d).unwrap_or_else(|| {
+                        reporter.usage(format!("Invalid --keep-unreferenced-for: {}", d))
+                    })
+                })
+                .unwrap_or(chrono::Duration::zero());
+            options.verify_reachability = cmd.is_present("verify-reachability");
+            options.paranoid = cmd.is_present("paranoid");
+            options.concurrency = cmd.value_of("parallel")
+                .map(|n| reporter.parse::<usize>("parallel", n))
+                .unwrap_or(1);
+            if options.concurrency == 0 {
+                reporter.usage("parallel must be at least 1");
+            }
+
+            let mut hat = reporter.open_repository(&settings);
+            let (deleted_hashes, live_blobs) = reporter.check(hat.gc_with_options(&options), &[]);
             println!("Deleted hashes: {:?}", deleted_hashes);
             println!("Live data blobs after deletion: {:?}", live_blobs);
 
         }
-        _ => {
+        ("consolidate", Some(cmd)) => {
+            let min_live = cmd.value_of("min-live")
+                .map(|n| reporter.parse::<usize>("min-live", n))
+                .unwrap_or(settings.max_blob_size / 2);
+
+            let mut hat = reporter.open_repository(&settings);
+            let report = reporter.check(hat.consolidate_blobs(min_live), &[]);
+            println!(
+                "Merged {} blobs ({} chunks moved)",
+                report.blobs_merged,
+                report.chunks_moved
+            );
+            if !report.complete {
+                println!("Stopped early; run `hat consolidate` again to continue");
+            }
+        }
+        ("rekey", Some(cmd)) => {
+            let algorithm = cmd.value_of("algorithm").unwrap_or(hat::hat::SEAL_ALGORITHM).to_owned();
+
+            let mut hat = reporter.open_repository(&settings);
+            let report = reporter.check(
+                hat.rekey(&algorithm, cmd.is_present("allow-uncommitted"), |r| {
+                    println!("Rekeyed {} of {} blobs", r.blobs_rewritten, r.blobs_total)
+                }),
+                &[],
+            );
+            println!(
+                "Sealed {} chunks with {} ({} blobs rewritten)",
+                report.chunks_rekeyed,
+                algorithm,
+                report.blobs_rewritten
+            );
+            if !report.complete {
+                println!("Stopped early; run `hat rekey` again to continue");
+            }
+        }
+        ("compare-to-source", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap();
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let divergences = reporter.check(
+                hat.compare_to_source(name.clone(), PathBuf::from(path)),
+                &[("family", &name[..]), ("path", path)],
+            );
+            for d in divergences.iter() {
+                match *d {
+                    hat::hat::Divergence::MissingFromSnapshot(ref p) => {
+                        println!("+ {}", p.display())
+                    }
+                    hat::hat::Divergence::MissingFromSource(ref p) => {
+                        println!("- {}", p.display())
+                    }
+                    hat::hat::Divergence::ContentChanged(ref p) => println!("M {}", p.display()),
+                }
+            }
+            if !divergences.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("resolve", Some(cmd)) => {
+            let prefix = cmd.value_of("PREFIX").unwrap();
+
+            let hat = reporter.open_repository(&settings);
+
+            let href = reporter.check(hat.resolve_hash_ref(prefix), &[("prefix", prefix)]);
+            println!("{}", href.hash.bytes.to_hex());
+        }
+        ("export-proof", Some(_cmd)) => {
+            let mut hat = reporter.open_repository(&settings);
+
+            print!("{}", reporter.check(hat.export_proof(), &[]).to_text());
+        }
+        ("index-export", Some(cmd)) => {
+            let file = cmd.value_of("FILE").unwrap();
+
+            let mut hat = reporter.open_repository(&settings);
+            reporter.check(hat.export_index(Path::new(file)), &[("file", file)]);
+        }
+        ("index-import", Some(cmd)) => {
+            let file = cmd.value_of("FILE").unwrap();
+
+            let mut hat = reporter.open_repository(&settings);
+            reporter.check(hat.import_index(Path::new(file)), &[("file", file)]);
+            println!("Index imported from {}", file);
+        }
+        ("audit-log", Some(cmd)) => {
+            let hat = reporter.open_repository(&settings);
+
+            for entry in hat.audit_log() {
+                let affected: Vec<String> = entry.affected.iter().map(|id| id.to_string()).collect();
+                println!(
+                    "{:>6} {} {:<16} {:<24} {}",
+                    entry.seq,
+                    chrono::NaiveDateTime::from_timestamp(entry.utc, 0),
+                    entry.operation,
+                    affected.join(","),
+                    entry.chain.to_hex()
+                );
+            }
+            if cmd.is_present("verify") {
+                let head = reporter.check(hat.verify_audit_log(), &[]);
+                println!("Audit log is intact; last chain hash {}", head.to_hex());
+            }
+        }
+        ("blob-info", Some(cmd)) => {
+            let blob_id_str = cmd.value_of("BLOB_ID").unwrap();
+            let blob_id = reporter.parse::<i64>("BLOB_ID", blob_id_str);
+
+            let hat = reporter.open_repository(&settings);
+
+            let chunks = reporter.check(hat.blob_info(blob_id), &[("blob_id", blob_id_str)]);
+            let or_none = |s: Option<String>| s.unwrap_or("-".to_owned());
+            if cmd.is_present("json") {
+                let json_or_null = |s: Option<String>| {
+                    s.map(|s| format!("\"{}\"", s)).unwrap_or("null".to_owned())
+                };
+                for c in chunks {
+                    println!(
+                        "{{\"hash\":\"{}\",\"leaf\":\"{:?}\",\"offset\":{},\"length\":{},\
+                         \"plaintext_length\":{},\"algorithm\":{},\"key\":{},\"packing\":{}}}",
+                        c.hash.bytes.to_hex(),
+                        c.leaf,
+                        c.offset,
+                        c.length,
+                        c.plaintext_length.map(|l| l.to_string()).unwrap_or("null".to_owned()),
+                        json_or_null(c.algorithm.map(|a| a.to_owned())),
+                        json_or_null(c.key_fingerprint),
+                        json_or_null(c.packing.map(|p| p.name().to_owned())),
+                    );
+                }
+            } else {
+                println!(
+                    "{:>10} {:>10} {:>10}  {:<26} {:<8} {:<7} {:<12} {}",
+                    "offset",
+                    "length",
+                    "plaintext",
+                    "algorithm",
+                    "key",
+                    "packing",
+                    "leaf",
+                    "hash"
+                );
+                for c in chunks {
+                    println!(
+                        "{:>10} {:>10} {:>10}  {:<26} {:<8} {:<7} {:<12} {}",
+                        c.offset,
+                        c.length,
+                        or_none(c.plaintext_length.map(|l| l.to_string())),
+                        c.algorithm.unwrap_or("-"),
+                        or_none(c.key_fingerprint),
+                        c.packing.map(|p| p.name()).unwrap_or("-"),
+                        format!("{:?}", c.leaf),
+                        c.hash.bytes.to_hex()
+                    );
+                }
+            }
+        }
+        ("blob-checksum", Some(cmd)) => {
+            let blob_id_str = cmd.value_of("BLOB_ID").unwrap();
+            let blob_id = reporter.parse::<i64>("BLOB_ID", blob_id_str);
+
+            let hat = reporter.open_repository(&settings);
+
+            let context = [("blob_id", blob_id_str)];
+            if reporter.check(hat.verify_blob_checksum(blob_id), &context) {
+                println!("OK");
+            } else {
+                reporter.fail(
+                    ErrorReport::new(
+                        ErrorKind::Data,
+                        format!("Blob {} does not match its checksum", blob_id),
+                    ).with_context("blob_id", blob_id),
+                );
+            }
+        }
+        ("verify", Some(cmd)) => {
+            let checkpoint = cmd.value_of("checkpoint")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| settings.cache_dir.join("verify.checkpoint"));
+            let max_chunks = cmd.value_of("max-chunks")
+                .map(|n| reporter.parse::<u64>("max-chunks", n));
+            let parallel = cmd.value_of("parallel")
+                .map(|n| reporter.parse::<usize>("parallel", n))
+                .unwrap_or(1);
+            if parallel == 0 {
+                reporter.usage("parallel must be at least 1");
+            }
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let checkpoint_str = checkpoint.display().to_string();
+            let report = reporter.check(
+                hat.verify_parallel(&checkpoint, cmd.is_present("continue"), max_chunks, parallel),
+                &[("checkpoint", &checkpoint_str[..])],
+            );
+            if report.restarted {
+                println!("The store changed since the last verify; started over");
+            }
             println!(
+                "Verified {} chunks: {} passed, {} failed ({} verified earlier)",
+                report.verified,
+                report.passed,
+                report.failed,
+                report.skipped
+            );
+            for &(ref hash, ref reason) in report.failures.iter() {
+                println!("Failed: {}: {}", hash, reason);
+            }
+            for location in report.locations.iter() {
+                println!(
+                    "  {} is chunk {} of {} in {} #{}",
+                    location.hash,
+                    location.chunk,
+                    location.path.display(),
+                    location.family,
+                    location.snapshot_id
+                );
+            }
+            if !report.complete {
+                println!("Stopped early; run `hat verify --continue` to resume");
+            }
+            if !report.failures.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("scrub", Some(cmd)) => {
+            use chrono::TimeZone;
+
+            let mut options = hat::hat::ScrubOptions::default();
+            if let Some(n) = cmd.value_of("period-runs") {
+                options.period_runs = reporter.parse::<u64>("period-runs", n);
+            }
+            if let Some(n) = cmd.value_of("concurrency") {
+                options.concurrency = reporter.parse::<usize>("concurrency", n);
+            }
+            if options.period_runs == 0 {
+                reporter.usage("period-runs must be at least 1");
+            }
+            if options.concurrency == 0 {
+                reporter.usage("concurrency must be at least 1");
+            }
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let report = reporter.check(hat.scrub(&options), &[]);
+            println!(
+                "Verified {} of {} chunks: {} passed, {} failed",
+                report.verified,
+                report.total,
+                report.passed,
+                report.failed
+            );
+            match report.oldest_verified_utc {
+                Some(utc) => println!(
+                    "Every chunk has been verified; the oldest check was at {}",
+                    chrono::Utc.timestamp(utc, 0).to_rfc3339()
+                ),
+                None => println!("{} chunks have never been verified", report.never_verified),
+            }
+            for &(ref hash, ref reason) in report.failures.iter() {
+                println!("Failed: {}: {}", hash, reason);
+            }
+            for location in report.locations.iter() {
+                println!(
+                    "  {} is chunk {} of {} in {} #{}",
+                    location.hash,
+                    location.chunk,
+                    location.path.display(),
+                    location.family,
+                    location.snapshot_id
+                );
+            }
+            if !report.failures.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("cat", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let path = cmd.value_of("PATH").unwrap();
+            // Failures must not end up in the middle of the file contents.
+            let reporter = Reporter {
+                json: reporter.json,
+                stderr: true,
+            };
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let stdout = io::stdout();
+            reporter.check(
+                hat.cat(name.clone(), path, &mut stdout.lock()),
+                &[("family", &name[..]), ("path", path)],
+            );
+        }
+        ("checkout-tar", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let file = cmd.value_of("FILE").unwrap_or("-");
+            // Failures must not end up in the middle of the archive.
+            let reporter = Reporter {
+                json: reporter.json,
+                stderr: true,
+            };
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let context = [("family", &name[..]), ("file", file)];
+            if file == "-" {
+                let stdout = io::stdout();
+                reporter.check(hat.checkout_to_tar(name.clone(), stdout.lock()), &context);
+            } else {
+                let out = reporter.check(fs::File::create(file).map_err(HatError::from), &context);
+                let out = reporter.check(
+                    hat.checkout_to_tar(name.clone(), io::BufWriter::new(out)),
+                    &context,
+                );
+                reporter.check(
+                    out.into_inner().map_err(|e| HatError::from(e.into_error())),
+                    &context,
+                );
+            }
+        }
+        ("sha256-manifest", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let digests = reporter.check(hat.file_digests(name.clone()), &[("family", &name[..])]);
+            print!("{}", hat::hat::to_sha256sum(&digests[..]));
+            let missing: Vec<_> = digests.iter().filter(|d| d.sha256.is_none()).collect();
+            for d in missing.iter() {
+                let _ = writeln!(io::stderr(), "No SHA-256 stored for {}", d.path.display());
+            }
+            if !missing.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("du", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let max_depth = cmd.value_of("max-depth")
+                .map(|d| reporter.parse::<usize>("max-depth", d));
+
+            let mut hat = reporter.open_repository(&settings);
+
+            println!("{:>14} {:>14}  {}", "logical", "unique", "path");
+            let dirs = reporter.check(
+                hat.disk_usage(name.clone(), max_depth),
+                &[("family", &name[..])],
+            );
+            for dir in dirs {
+                println!(
+                    "{:>14} {:>14}  ./{}",
+                    dir.logical_bytes,
+                    dir.unique_bytes,
+                    dir.path.display()
+                );
+            }
+        }
+        ("doctor", Some(_cmd)) => {
+            let backend =
+                backend::FileBackend::new(blob_dir()).with_shard_depth(settings.shard_depth);
+            let checks = hat::hat::doctor(&settings.migrations_dir, settings.cache_dir, &backend);
+
+            let mut first_failure = None;
+            for check in checks {
+                let status = match check.status {
+                    hat::hat::CheckStatus::Pass => "ok",
+                    hat::hat::CheckStatus::Warn => "warn",
+                    hat::hat::CheckStatus::Fail => "FAIL",
+                };
+                println!("{:<5} {:<8} {}", status, check.name, check.message);
+                if let Some(error) = check.error {
+                    println!("{:<14} {}: {}", "", error.kind.name(), error);
+                    first_failure = first_failure.or(Some(error.kind));
+                }
+            }
+            if let Some(kind) = first_failure {
+                std::process::exit(kind.exit_code());
+            }
+        }
+        ("sharing", Some(cmd)) => {
+            let name = cmd.value_of("NAME").map(|n| n.to_owned());
+
+            let mut hat = reporter.open_repository(&settings);
+
+            let snapshots = reporter.check(hat.snapshot_sharing(name), &[]);
+            println!("{:>14} {:>14} {:>14}  {}", "referenced", "exclusive", "shared", "snapshot");
+            for s in snapshots {
+                println!(
+                    "{:>14} {:>14} {:>14}  {}#{}",
+                    s.referenced_bytes,
+                    s.exclusive_bytes,
+                    s.shared_bytes,
+                    s.family_name,
+                    s.snapshot_id
+                );
+            }
+        }
+        _ => {
+            reporter.usage(format!(
                 "No subcommand specified\n{}\nFor more information re-run with --help",
                 matches.usage()
-            );
-            std::process::exit(1);
+            ))
         }
     }
 }
@@ -37,13 +37,21 @@ impl SnapshotIndex {
         self.index.lock().snapshot_delete(info);
     }
 
-    /// Lookup exact snapshot info from family and snapshot id.
+    /// Lookup exact snapshot info from family and snapshot id. Snapshots that are not yet
+    /// committed are not found.
     pub fn lookup(
         &mut self,
         family_name: &str,
         snapshot_id: u64,
     ) -> Option<(db::SnapshotInfo, hash::Hash, Option<hash::tree::HashRef>)> {
-        self.index.lock().snapshot_lookup(family_name, snapshot_id)
+        let visible = self.list_committed().into_iter().any(|s| {
+            s.family_name == family_name && s.info.snapshot_id == snapshot_id
+        });
+        if visible {
+            self.index.lock().snapshot_lookup(family_name, snapshot_id)
+        } else {
+            None
+        }
     }
 
     pub fn reserve(&mut self, family: String) -> db::SnapshotInfo {
@@ -65,6 +73,66 @@ impl SnapshotIndex {
         );
     }
 
+    /// Record which key seals the snapshot.
+    pub fn set_key_id(&mut self, snapshot: &db::SnapshotInfo, key_id: &str) {
+        self.index.lock().snapshot_set_key_id(snapshot, key_id)
+    }
+
+    /// The id of the key that seals the snapshot. Snapshots recovered from blobs or written by
+    /// older versions of hat have none.
+    pub fn key_id(&mut self, snapshot: &db::SnapshotInfo) -> Option<String> {
+        self.index.lock().snapshot_key_id(snapshot)
+    }
+
+    /// Record how the files of the snapshot were split into chunks.
+    pub fn set_chunker(&mut self, snapshot: &db::SnapshotInfo, chunker: &str) {
+        self.index.lock().snapshot_set_chunker(snapshot, chunker)
+    }
+
+    /// How the files of the snapshot were split into chunks. Snapshots written by older versions
+    /// of hat have none recorded, and used fixed-size chunks.
+    pub fn chunker(&mut self, snapshot: &db::SnapshotInfo) -> Option<String> {
+        self.index.lock().snapshot_chunker(snapshot)
+    }
+
+    /// Every chunker recorded for a snapshot in this store.
+    pub fn chunkers_in_use(&mut self) -> Vec<String> {
+        self.index.lock().snapshot_chunkers()
+    }
+
+    /// Record how many children the branch nodes of the snapshot's hash trees have.
+    pub fn set_fanout(&mut self, snapshot: &db::SnapshotInfo, fanout: usize) {
+        self.index.lock().snapshot_set_fanout(snapshot, fanout)
+    }
+
+    /// How many children the branch nodes of the snapshot's hash trees have. Snapshots written
+    /// by older versions of hat have none recorded, and used `DEFAULT_FANOUT`.
+    pub fn fanout(&mut self, snapshot: &db::SnapshotInfo) -> usize {
+        self.index.lock().snapshot_fanout(snapshot).unwrap_or(hash::tree::DEFAULT_FANOUT)
+    }
+
+    /// Record that the snapshot only captured the contents of files that were unchanged since
+    /// the snapshot before it; changed files were stored without their contents.
+    pub fn set_metadata_only(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index.lock().snapshot_set_metadata_only(snapshot)
+    }
+
+    /// Whether the snapshot only captured the contents of files that were unchanged.
+    pub fn metadata_only(&mut self, snapshot: &db::SnapshotInfo) -> bool {
+        self.index.lock().snapshot_metadata_only(snapshot)
+    }
+
+    /// Record that the snapshot was taken after the snapshot `parent_id` of the same family.
+    pub fn set_parent(&mut self, snapshot: &db::SnapshotInfo, parent_id: u64) {
+        self.index.lock().snapshot_set_parent(snapshot, parent_id)
+    }
+
+    /// The snapshot of the same family that the snapshot was taken after. The first snapshot of
+    /// a family, and snapshots written by older versions of hat, have none.
+    pub fn parent(&mut self, snapshot: &db::SnapshotInfo) -> Option<u64> {
+        self.index.lock().snapshot_parent(snapshot)
+    }
+
     /// ReadyCommit.
     pub fn ready_commit(&mut self, snapshot: &db::SnapshotInfo) {
         self.index.lock().snapshot_set_tag(
@@ -119,6 +187,19 @@ impl SnapshotIndex {
         self.list(None)
     }
 
+    /// List the snapshots that were committed, including those being deleted. Snapshots that
+    /// are still being committed or recovered are left out.
+    pub fn list_committed(&mut self) -> Vec<db::SnapshotStatus> {
+        self.list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                db::SnapshotWorkStatus::CommitInProgress |
+                db::SnapshotWorkStatus::RecoverInProgress => false,
+                _ => true,
+            })
+            .collect()
+    }
+
     /// Recover snapshot information.
     pub fn recover(
         &mut self,
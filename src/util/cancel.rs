@@ -0,0 +1,121 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use errors::CancelledError;
+use libc;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+
+/// The flag set by `cancel_on_interrupt`, read from the signal handler.
+static INTERRUPT_FLAG: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+
+/// A shared flag that long running operations poll at safe points.
+/// Cloning a token yields a handle to the same flag.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken { cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Start an operation that stops when cancelled. The token is reset when the returned guard
+    /// is dropped, so that a cancellation ends with the operation it stopped.
+    pub fn operation(&self) -> Operation {
+        Operation { token: self.clone() }
+    }
+
+    /// Cancel this token on SIGINT, instead of the process being killed. A second SIGINT kills
+    /// the process as usual, for when the running operation does not get to a safe point.
+    pub fn cancel_on_interrupt(&self) {
+        // The flag is never released, as the handler may read it at any time.
+        let flag = Arc::into_raw(self.cancelled.clone()) as *mut AtomicBool;
+        INTERRUPT_FLAG.store(flag, Ordering::SeqCst);
+        let handler = on_interrupt as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<(), CancelledError> {
+        if self.is_cancelled() {
+            Err(CancelledError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+/// Resets its token when dropped; see `CancellationToken::operation`.
+pub struct Operation {
+    token: CancellationToken,
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        self.token.reset();
+    }
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    let flag = INTERRUPT_FLAG.load(Ordering::SeqCst);
+    if !flag.is_null() {
+        // Only an atomic store and `signal` are done here, both of which are signal safe.
+        unsafe {
+            (*flag).store(true, Ordering::SeqCst);
+        }
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_cancels_and_operation_resets() {
+        let token = CancellationToken::new();
+        token.cancel_on_interrupt();
+        {
+            let _operation = token.operation();
+            unsafe {
+                libc::raise(libc::SIGINT);
+            }
+            assert!(token.is_cancelled());
+        }
+        assert!(!token.is_cancelled());
+    }
+}
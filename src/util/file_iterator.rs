@@ -12,30 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::PathBuf;
+use std::sync::Arc;
+use util::read_ahead::{ReadAhead, ReadAt, ReadPool};
 
 pub enum FileIterator {
-    File(io::BufReader<fs::File>),
     Buf(Vec<u8>, usize),
-    #[cfg(all(test, feature = "benchmarks"))]
+    At(Arc<ReadAt>, u64),
+    ReadAhead(ReadAhead),
+    #[cfg(test)]
     Reader(Box<Read + Send>),
 }
 
 impl FileIterator {
-    pub fn new(path: &PathBuf) -> io::Result<FileIterator> {
-        match fs::File::open(path) {
-            Ok(f) => Ok(FileIterator::File(io::BufReader::new(f))),
-            Err(e) => Err(e),
-        }
-    }
     pub fn from_bytes(contents: Vec<u8>) -> FileIterator {
         FileIterator::Buf(contents, 0)
     }
 
-    #[cfg(all(test, feature = "benchmarks"))]
+    /// Read `source` front to back, one read at a time.
+    pub fn from_read_at(source: Arc<ReadAt>) -> FileIterator {
+        FileIterator::At(source, 0)
+    }
+
+    /// Read `source` in segments on `pool`, ahead of the consumer.
+    pub fn read_ahead(source: Arc<ReadAt>, pool: Arc<ReadPool>) -> FileIterator {
+        FileIterator::ReadAhead(ReadAhead::new(source, pool))
+    }
+
+    #[cfg(test)]
     pub fn from_reader<R>(r: Box<R>) -> FileIterator
     where
         R: Read + Send + 'static,
@@ -47,7 +52,6 @@ impl FileIterator {
 impl Read for FileIterator {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
-            FileIterator::File(ref mut f) => f.read(buf),
             FileIterator::Buf(ref vec, ref mut pos) => {
                 use std::cmp;
                 if *pos >= vec.len() {
@@ -59,7 +63,13 @@ impl Read for FileIterator {
                     Ok(next.len())
                 }
             }
-            #[cfg(all(test, feature = "benchmarks"))]
+            FileIterator::At(ref source, ref mut pos) => {
+                let len = source.read_at(buf, *pos)?;
+                *pos += len as u64;
+                Ok(len)
+            }
+            FileIterator::ReadAhead(ref mut r) => r.read(buf),
+            #[cfg(test)]
             FileIterator::Reader(ref mut r) => r.read(buf),
         }
     }
@@ -19,6 +19,7 @@ use scoped_pool;
 use std::fs;
 use std::io;
 use std::iter;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
 
@@ -40,18 +41,17 @@ pub trait PathHandler<P: Send + 'static>: Sync {
     fn read_dir(&self, &PathBuf) -> io::Result<Self::DirIter>;
     fn handle_path(&self, &P, &PathBuf) -> Option<P>;
 
+    /// Handle the entries of `root` in byte-wise order of their names, whatever order the
+    /// directory lists them in, so that the same tree is always walked the same way. The
+    /// directories among them are walked once all of the entries are handled.
     fn recurse_worker<'a>(&'a self, scope: &scoped_pool::Scope<'a>, root: PathBuf, payload: P) {
         scope.recurse(move |scope| {
+            let mut paths = vec![];
             match self.read_dir(&root) {
                 Ok(dir) => {
                     for entry_res in dir {
                         match entry_res {
-                            Ok(entry) => {
-                                let path = entry.path();
-                                if let Some(dir) = self.handle_path(&payload, &path) {
-                                    self.recurse_worker(scope, path, dir);
-                                }
-                            }
+                            Ok(entry) => paths.push(entry.path()),
                             Err(err) => {
                                 // For some reason, we failed to read this entry.
                                 // Just skip it and continue with the next.
@@ -65,6 +65,17 @@ pub trait PathHandler<P: Send + 'static>: Sync {
                     warn!("Skipping unreadable directory {:?}: {}", root, err);
                 }
             }
+            paths.sort_by(|a, b| a.as_os_str().as_bytes().cmp(b.as_os_str().as_bytes()));
+
+            let mut dirs = vec![];
+            for path in paths {
+                if let Some(dir) = self.handle_path(&payload, &path) {
+                    dirs.push((path, dir));
+                }
+            }
+            for (path, dir) in dirs {
+                self.recurse_worker(scope, path, dir);
+            }
         });
     }
 
@@ -156,6 +167,93 @@ mod tests {
         }
     }
 
+    /// Lists directories in an order of its own, and records the order entries are handled in.
+    struct ShuffledPathHandler {
+        paths: Vec<PathBuf>,
+        shuffle: fn(&mut Vec<PathBuf>),
+        handled: Mutex<btree_map::BTreeMap<PathBuf, Vec<PathBuf>>>,
+    }
+
+    impl PathHandler<PathBuf> for ShuffledPathHandler {
+        type DirItem = PathBuf;
+        type DirIter = vec::IntoIter<io::Result<Self::DirItem>>;
+
+        fn read_dir(&self, dir: &PathBuf) -> io::Result<Self::DirIter> {
+            let mut contents: Vec<PathBuf> = self.paths
+                .iter()
+                .filter(|p| p.parent() == Some(dir))
+                .cloned()
+                .collect();
+            (self.shuffle)(&mut contents);
+            Ok(contents.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+        }
+
+        fn handle_path(&self, parent: &PathBuf, path: &PathBuf) -> Option<PathBuf> {
+            let mut handled = self.handled.lock().unwrap();
+            handled.entry(parent.clone()).or_insert_with(Vec::new).push(path.clone());
+            if self.paths.iter().any(|p| p.parent() == Some(path)) {
+                Some(path.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn walk_order_ignores_listing_order() {
+        let paths: Vec<PathBuf> = [
+            "/b",
+            "/a/",
+            "/a/z",
+            "/a/Z",
+            "/a/10",
+            "/a/9",
+            "/c/",
+            "/c/d/",
+            "/c/d/e",
+            "/c/a-b",
+            "/c/a",
+            "/c/\u{e9}",
+        ].iter()
+            .map(PathBuf::from)
+            .collect();
+
+        fn reverse(paths: &mut Vec<PathBuf>) {
+            paths.sort();
+            paths.reverse();
+        }
+        fn rotate(paths: &mut Vec<PathBuf>) {
+            paths.sort();
+            if !paths.is_empty() {
+                let first = paths.remove(0);
+                paths.push(first);
+            }
+        }
+
+        let mut walks = vec![];
+        for &shuffle in &[reverse as fn(&mut Vec<PathBuf>), rotate] {
+            let handler = ShuffledPathHandler {
+                paths: paths.clone(),
+                shuffle: shuffle,
+                handled: Mutex::new(btree_map::BTreeMap::new()),
+            };
+            handler.recurse(PathBuf::from("/"), PathBuf::from("/"));
+            walks.push(handler.handled.into_inner().unwrap());
+        }
+        assert_eq!(walks[0], walks[1]);
+
+        let names = |dir: &str| -> Vec<String> {
+            walks[0][&PathBuf::from(dir)]
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(names("/"), vec!["a", "b", "c"]);
+        assert_eq!(names("/a"), vec!["10", "9", "Z", "z"]);
+        assert_eq!(names("/c"), vec!["a", "a-b", "d", "\u{e9}"]);
+        assert_eq!(names("/c/d"), vec!["e"]);
+    }
+
     #[test]
     fn can_visit_all() {
         let paths: [&str; 20] = [
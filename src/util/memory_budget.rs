@@ -0,0 +1,100 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A hard ceiling on the bytes held in buffers at the same time.
+
+use std::sync::{Arc, Mutex};
+
+struct Usage {
+    in_use: usize,
+    peak: usize,
+}
+
+/// Bytes that may be held in buffers at once. Clones share the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit: limit,
+            usage: Arc::new(Mutex::new(Usage { in_use: 0, peak: 0 })),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The most bytes that were reserved at the same time.
+    pub fn peak(&self) -> usize {
+        self.usage.lock().unwrap().peak
+    }
+
+    /// Set aside `bytes` until the returned reservation is dropped. Fails rather than waits when
+    /// the budget is exhausted, as the callers hold on to at most one reservation each.
+    pub fn reserve(&self, bytes: usize) -> Result<Reservation, String> {
+        let mut usage = self.usage.lock().unwrap();
+        if bytes > self.limit - usage.in_use {
+            return Err(format!(
+                "Memory budget of {} bytes exceeded: {} bytes in use, {} more requested",
+                self.limit,
+                usage.in_use,
+                bytes
+            ));
+        }
+        usage.in_use += bytes;
+        if usage.in_use > usage.peak {
+            usage.peak = usage.in_use;
+        }
+        Ok(Reservation {
+            budget: self.clone(),
+            bytes: bytes,
+        })
+    }
+}
+
+/// Bytes set aside from a `MemoryBudget`, returned to it when dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.usage.lock().unwrap().in_use -= self.bytes;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_stay_within_limit() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.reserve(60).unwrap();
+        assert!(budget.reserve(41).is_err());
+        let b = budget.reserve(40).unwrap();
+        drop(a);
+        drop(b);
+        let _c = budget.reserve(100).unwrap();
+        assert_eq!(budget.peak(), 100);
+        assert!(budget.reserve(1).is_err());
+    }
+}
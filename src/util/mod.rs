@@ -12,23 +12,40 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cancel;
+mod clock;
 mod counter;
 mod file_iterator;
 mod fnbox;
 mod infowriter;
 mod listdir;
+mod memory_budget;
 mod sync_pool;
 mod ordered_collection;
+mod packed;
 mod periodic_timer;
 mod process;
+mod read_ahead;
+mod sparse;
+mod throughput;
 mod unique_priority_queue;
 
+pub use self::cancel::CancellationToken;
+pub use self::clock::{Clock, SystemClock};
+#[cfg(test)]
+pub use self::clock::FakeClock;
 pub use self::counter::Counter;
 pub use self::file_iterator::FileIterator;
 pub use self::fnbox::FnBox;
 pub use self::infowriter::InfoWriter;
 pub use self::listdir::{HasPath, PathHandler};
+pub use self::memory_budget::{MemoryBudget, Reservation};
+pub use self::packed::{read_packed_message, write_packed_message};
 pub use self::periodic_timer::PeriodicTimer;
 pub use self::process::{MsgHandler, Process};
+pub use self::read_ahead::{ReadAt, ReadPool};
+pub use self::sparse::{DataRegions, HoleFiller, SparseWriter, find_holes, hole_bytes,
+                       may_have_holes};
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
+pub use self::throughput::Throughput;
 pub use self::unique_priority_queue::UniquePriorityQueue;
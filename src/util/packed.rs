@@ -0,0 +1,172 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cap'n Proto messages in the packed encoding, without capnp's own packed stream.
+//!
+//! The packed reader and writer in capnp 0.8 take a pointer one past the end of their buffers
+//! with `get_unchecked`, which is undefined behaviour. These do the same packing on safe slices
+//! and leave the unpacked framing to `capnp::serialize`. The bytes are the same either way.
+
+use capnp;
+use capnp::message::{Allocator, Builder, Reader, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+use std::io::{self, Read, Write};
+
+
+/// Write `message` to `write` in the packed encoding.
+pub fn write_packed_message<W, A>(write: &mut W, message: &Builder<A>) -> io::Result<()>
+where
+    W: Write,
+    A: Allocator,
+{
+    let words = serialize::write_message_to_words(message);
+    write.write_all(&pack(capnp::Word::words_to_bytes(&words[..]))[..])
+}
+
+/// Read a message in the packed encoding. All of `read` is taken to be the one message.
+pub fn read_packed_message<R: Read>(
+    read: &mut R,
+    options: ReaderOptions,
+) -> capnp::Result<Reader<OwnedSegments>> {
+    let mut packed = vec![];
+    read.read_to_end(&mut packed)?;
+    let bytes = unpack(&packed[..])?;
+    serialize::read_message(&mut &bytes[..], options)
+}
+
+fn pack(bytes: &[u8]) -> Vec<u8> {
+    assert_eq!(bytes.len() % 8, 0);
+    let words: Vec<&[u8]> = bytes.chunks(8).collect();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        i += 1;
+
+        let mut tag = 0u8;
+        for (bit, &b) in word.iter().enumerate() {
+            if b != 0 {
+                tag |= 1 << bit;
+            }
+        }
+        out.push(tag);
+        out.extend(word.iter().filter(|&&b| b != 0));
+
+        if tag == 0 {
+            // A zero word is followed by the count of zero words after it.
+            let run = words[i..]
+                .iter()
+                .take(255)
+                .take_while(|w| w.iter().all(|&b| b == 0))
+                .count();
+            out.push(run as u8);
+            i += run;
+        } else if tag == 0xff {
+            // A word with no zero bytes is followed by the count of words stored as they are.
+            // Words with at most one zero byte are not worth packing.
+            let run = words[i..]
+                .iter()
+                .take(255)
+                .take_while(|w| w.iter().filter(|&&b| b == 0).count() < 2)
+                .count();
+            out.push(run as u8);
+            for w in &words[i..i + run] {
+                out.extend_from_slice(w);
+            }
+            i += run;
+        }
+    }
+    out
+}
+
+fn unpack(packed: &[u8]) -> capnp::Result<Vec<u8>> {
+    fn premature_end() -> capnp::Error {
+        capnp::Error::failed("Premature end of packed input.".to_string())
+    }
+
+    let mut out = Vec::with_capacity(packed.len() * 2);
+    let mut input = packed.iter();
+    while let Some(&tag) = input.next() {
+        for bit in 0..8 {
+            if tag & (1 << bit) != 0 {
+                out.push(*input.next().ok_or_else(premature_end)?);
+            } else {
+                out.push(0);
+            }
+        }
+
+        if tag == 0 {
+            let run = *input.next().ok_or_else(premature_end)? as usize;
+            let len = out.len();
+            out.resize(len + run * 8, 0);
+        } else if tag == 0xff {
+            let run = *input.next().ok_or_else(premature_end)? as usize;
+            for _ in 0..run * 8 {
+                out.push(*input.next().ok_or_else(premature_end)?);
+            }
+        }
+    }
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck;
+
+    #[test]
+    fn packs_like_the_encoding_spec() {
+        // The examples from https://capnproto.org/encoding.html#packing.
+        let bytes = [8, 0, 0, 0, 3, 0, 2, 0, 0x19, 0, 0, 0, 0xaa, 1, 0, 0];
+        assert_eq!(pack(&bytes), vec![0x51, 8, 3, 2, 0x31, 0x19, 0xaa, 1]);
+        assert_eq!(unpack(&pack(&bytes)).unwrap(), bytes.to_vec());
+
+        assert_eq!(pack(&[0; 16]), vec![0, 1]);
+
+        let mut bytes = vec![0x8a; 8];
+        bytes.extend(vec![1, 2, 3, 0, 5, 6, 7, 8]);
+        bytes.extend(vec![0, 0, 1, 0, 0, 0, 0, 2]);
+        let mut expected = vec![0xff, 0x8a, 0x8a, 0x8a, 0x8a, 0x8a, 0x8a, 0x8a, 0x8a, 1];
+        expected.extend(vec![1, 2, 3, 0, 5, 6, 7, 8, 0x84, 1, 2]);
+        assert_eq!(pack(&bytes[..]), expected);
+    }
+
+    #[test]
+    fn unpack_reverses_pack() {
+        fn prop(words: Vec<(u8, u8, u8, u8)>) -> bool {
+            // Mostly zero bytes, so that all three kinds of word turn up.
+            let mut bytes = vec![];
+            for &(a, b, c, d) in &words {
+                bytes.extend_from_slice(&[a, 0, b, 0, 0, c, 0, d]);
+                if a % 3 == 0 {
+                    bytes.extend_from_slice(&[0; 8]);
+                }
+                if b % 3 == 0 {
+                    bytes.extend_from_slice(&[a | 1, b | 1, c | 1, d | 1, 1, 2, 3, 4]);
+                }
+            }
+            unpack(&pack(&bytes[..])[..]).unwrap() == bytes
+        }
+        quickcheck::quickcheck(prop as fn(Vec<(u8, u8, u8, u8)>) -> bool);
+    }
+
+    #[test]
+    fn unpack_refuses_truncated_input() {
+        assert!(unpack(&[0x51, 8, 3]).is_err());
+        assert!(unpack(&[0]).is_err());
+        assert!(unpack(&[0xff, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading files in segments on a pool of threads.
+//!
+//! A `ReadAhead` keeps a bounded number of segments of its file in flight on a shared
+//! `ReadPool`, and hands them out strictly in file order.
+
+use scoped_pool;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, mpsc};
+
+/// Bytes read by a single request to the pool.
+pub const READ_SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// Positional reads, so that segments of one file can be read at the same time.
+pub trait ReadAt: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+impl ReadAt for fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+}
+
+/// Threads shared by all files read during a snapshot.
+pub struct ReadPool {
+    pool: scoped_pool::Pool,
+    threads: usize,
+}
+
+impl ReadPool {
+    pub fn new(threads: usize) -> ReadPool {
+        assert!(threads > 0);
+        ReadPool {
+            pool: scoped_pool::Pool::new(threads),
+            threads: threads,
+        }
+    }
+
+    /// Read up to `len` bytes at `offset`. A short segment means that the file ended there.
+    fn read_segment(
+        &self,
+        source: Arc<ReadAt>,
+        offset: u64,
+        len: usize,
+    ) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+        let (sender, receiver) = mpsc::channel();
+        self.pool.spawn(move || {
+            let _ = sender.send(read_fully_at(&*source, offset, len));
+        });
+        receiver
+    }
+}
+
+impl Drop for ReadPool {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+fn read_fully_at(source: &ReadAt, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    let mut filled = 0;
+    while filled < len {
+        match source.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// Reads a file front to back, with up to one segment per pool thread requested ahead of the
+/// reader. At most that many segments of the file are held in memory at once.
+pub struct ReadAhead {
+    source: Arc<ReadAt>,
+    pool: Arc<ReadPool>,
+    next_offset: u64,
+    in_flight: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+    current: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl ReadAhead {
+    pub fn new(source: Arc<ReadAt>, pool: Arc<ReadPool>) -> ReadAhead {
+        let mut reader = ReadAhead {
+            source: source,
+            pool: pool,
+            next_offset: 0,
+            in_flight: VecDeque::new(),
+            current: vec![],
+            pos: 0,
+            eof: false,
+        };
+        reader.fill();
+        reader
+    }
+
+    fn fill(&mut self) {
+        while !self.eof && self.in_flight.len() < self.pool.threads {
+            let segment = self.pool.read_segment(
+                self.source.clone(),
+                self.next_offset,
+                READ_SEGMENT_SIZE,
+            );
+            self.in_flight.push_back(segment);
+            self.next_offset += READ_SEGMENT_SIZE as u64;
+        }
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        let segment = match self.in_flight.pop_front() {
+            None => return Ok(()),
+            Some(receiver) => {
+                match receiver.recv() {
+                    Ok(res) => res,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::Other, "read pool stopped")),
+                }
+            }
+        };
+        match segment {
+            Ok(segment) => {
+                if segment.len() < READ_SEGMENT_SIZE {
+                    // Segments after this one lie past the end of the file.
+                    self.eof = true;
+                    self.in_flight.clear();
+                }
+                self.current = segment;
+                self.pos = 0;
+                self.fill();
+                Ok(())
+            }
+            Err(e) => {
+                self.eof = true;
+                self.in_flight.clear();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.current.len() {
+            self.next_segment()?;
+        }
+        let len = cmp::min(buf.len(), self.current.len() - self.pos);
+        buf[..len].clone_from_slice(&self.current[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl ReadAt for Vec<u8> {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let start = cmp::min(offset as usize, self.len());
+            let len = cmp::min(buf.len(), self.len() - start);
+            buf[..len].clone_from_slice(&self[start..start + len]);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn reads_segments_in_order() {
+        let pool = Arc::new(ReadPool::new(3));
+        for &size in &[0, 1, READ_SEGMENT_SIZE, 3 * READ_SEGMENT_SIZE + 17] {
+            let contents: Vec<u8> = (0..size).map(|i| (i * 7 % 251) as u8).collect();
+            let mut reader = ReadAhead::new(Arc::new(contents.clone()), pool.clone());
+            let mut read = vec![];
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, contents);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sparse files: finding their holes, reading only their data, and writing them back with the
+//! same holes.
+//!
+//! Holes are given as (offset, length) in the file, in order. The data of a sparse file is the
+//! rest of it, read front to back as if the holes were cut out.
+
+use libc;
+use std::cmp;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use util::ReadAt;
+
+
+/// Whether the file takes less space on disk than its length, so that it may have holes.
+pub fn may_have_holes(meta: &fs::Metadata) -> bool {
+    meta.blocks() * 512 < meta.len()
+}
+
+/// The holes in the first `len` bytes of `file`, as the filesystem reports them. Filesystems
+/// that can not tell report none.
+pub fn find_holes(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut holes = vec![];
+    let mut pos = 0;
+    while pos < len {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // Nothing but a hole up to the end.
+                Some(libc::ENXIO) => {
+                    holes.push((pos, len - pos));
+                    Ok(holes)
+                }
+                Some(libc::EINVAL) => Ok(vec![]),
+                _ => Err(err),
+            };
+        }
+        let data = cmp::min(data as u64, len);
+        if data > pos {
+            holes.push((pos, data - pos));
+        }
+        if data == len {
+            break;
+        }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        pos = hole as u64;
+    }
+    Ok(holes)
+}
+
+/// Bytes in `holes`.
+pub fn hole_bytes(holes: &[(u64, u64)]) -> u64 {
+    holes.iter().map(|&(_, len)| len).sum()
+}
+
+/// Reads the data of a sparse file of `len` bytes from `source`, skipping its holes.
+pub struct DataRegions {
+    source: Arc<ReadAt>,
+    /// (offset in the data, offset in the file, length) of each region of data.
+    regions: Vec<(u64, u64, u64)>,
+}
+
+impl DataRegions {
+    pub fn new(source: Arc<ReadAt>, holes: &[(u64, u64)], len: u64) -> DataRegions {
+        let mut regions = vec![];
+        let (mut data_pos, mut file_pos) = (0, 0);
+        for &(start, hole_len) in holes.iter().chain(Some(&(len, 0))) {
+            if start > file_pos {
+                regions.push((data_pos, file_pos, start - file_pos));
+                data_pos += start - file_pos;
+            }
+            file_pos = start + hole_len;
+        }
+        DataRegions {
+            source: source,
+            regions: regions,
+        }
+    }
+}
+
+impl ReadAt for DataRegions {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let i = match self.regions.binary_search_by_key(&offset, |&(data_pos, _, _)| data_pos) {
+            Ok(i) => i,
+            Err(0) => return Ok(0),
+            Err(i) => i - 1,
+        };
+        let (data_pos, file_pos, len) = self.regions[i];
+        let skip = offset - data_pos;
+        if skip >= len {
+            return Ok(0);
+        }
+        let want = cmp::min(buf.len() as u64, len - skip) as usize;
+        self.source.read_at(&mut buf[..want], file_pos + skip)
+    }
+}
+
+/// Writes the data of a sparse file to a file that is already as long as the whole file,
+/// seeking past the holes instead of writing them.
+pub struct SparseWriter<W> {
+    inner: W,
+    holes: Vec<(u64, u64)>,
+    next_hole: usize,
+    pos: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    pub fn new(inner: W, holes: Vec<(u64, u64)>) -> SparseWriter<W> {
+        SparseWriter {
+            inner: inner,
+            holes: holes,
+            next_hole: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        while let Some(&(start, len)) = self.holes.get(self.next_hole) {
+            if start > self.pos {
+                break;
+            }
+            self.pos = start + len;
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.next_hole += 1;
+        }
+        let room = match self.holes.get(self.next_hole) {
+            Some(&(start, _)) => start - self.pos,
+            None => buf.len() as u64,
+        };
+        let written = self.inner.write(&buf[..cmp::min(buf.len() as u64, room) as usize])?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Puts the holes back into the data of a sparse file as zeros, as the file reads, e.g. to
+/// hash it.
+pub struct HoleFiller {
+    holes: Vec<(u64, u64)>,
+    next_hole: usize,
+    pos: u64,
+}
+
+impl HoleFiller {
+    pub fn new(holes: Vec<(u64, u64)>) -> HoleFiller {
+        HoleFiller {
+            holes: holes,
+            next_hole: 0,
+            pos: 0,
+        }
+    }
+
+    /// Pass the next `data` to `out`, after the zeros of any holes before it.
+    pub fn feed<F: FnMut(&[u8])>(&mut self, mut data: &[u8], mut out: F) {
+        while !data.is_empty() {
+            self.fill_holes(&mut out, false);
+            let room = match self.holes.get(self.next_hole) {
+                Some(&(start, _)) => start - self.pos,
+                None => data.len() as u64,
+            };
+            let (now, rest) = data.split_at(cmp::min(data.len() as u64, room) as usize);
+            out(now);
+            self.pos += now.len() as u64;
+            data = rest;
+        }
+    }
+
+    /// Pass the zeros of the holes after the last data to `out`.
+    pub fn finish<F: FnMut(&[u8])>(&mut self, mut out: F) {
+        self.fill_holes(&mut out, true);
+    }
+
+    fn fill_holes<F: FnMut(&[u8])>(&mut self, out: &mut F, to_end: bool) {
+        let zeros = [0u8; 64 * 1024];
+        while let Some(&(start, len)) = self.holes.get(self.next_hole) {
+            if start > self.pos && !to_end {
+                break;
+            }
+            let mut left = len;
+            while left > 0 {
+                let n = cmp::min(left, zeros.len() as u64);
+                out(&zeros[..n as usize]);
+                left -= n;
+            }
+            self.pos = start + len;
+            self.next_hole += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_regions_skip_holes() {
+        let file: Vec<u8> = (0..100).collect();
+        let source: Arc<ReadAt> = Arc::new(file.clone());
+        let holes = vec![(0, 10), (40, 20), (90, 10)];
+        let regions = DataRegions::new(source, &holes, 100);
+
+        let mut data = vec![];
+        let mut buf = [0; 7];
+        loop {
+            let n = regions.read_at(&mut buf, data.len() as u64).unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        let expected: Vec<u8> = (10..40).chain(60..90).collect();
+        assert_eq!(data, expected);
+
+        // Filling the holes back in gives the file with zeros in them.
+        let mut filled = vec![];
+        let mut filler = HoleFiller::new(holes);
+        for piece in data.chunks(11) {
+            filler.feed(piece, |b| filled.extend_from_slice(b));
+        }
+        filler.finish(|b| filled.extend_from_slice(b));
+        let zeroed: Vec<u8> = file.iter()
+            .enumerate()
+            .map(|(i, &b)| if i < 10 || (i >= 40 && i < 60) || i >= 90 { 0 } else { b })
+            .collect();
+        assert_eq!(filled, zeroed);
+    }
+}
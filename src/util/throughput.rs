@@ -0,0 +1,151 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use util::Clock;
+
+
+/// Bytes per second over the last `window` of progress, and the time left at that rate.
+///
+/// Averaging over the whole window, rather than looking at the last few files, keeps a burst of
+/// small files or a single slow one from swinging the estimate.
+pub struct Throughput {
+    clock: Arc<Clock>,
+    window: Duration,
+    started: DateTime<Utc>,
+    // Bytes completed and when, oldest first; only those within the window are kept.
+    samples: VecDeque<(DateTime<Utc>, u64)>,
+    done: u64,
+}
+
+impl Throughput {
+    pub fn new(clock: Arc<Clock>, window: Duration) -> Throughput {
+        let started = clock.now();
+        Throughput {
+            clock: clock,
+            window: window,
+            started: started,
+            samples: VecDeque::new(),
+            done: 0,
+        }
+    }
+
+    /// Record that `bytes` more have been completed.
+    pub fn add(&mut self, bytes: u64) {
+        let now = self.clock.now();
+        self.done += bytes;
+        self.samples.push_back((now, bytes));
+        self.forget_before(now - self.window);
+    }
+
+    /// Bytes completed so far.
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    /// Bytes per second over the window, or `None` before any time has passed.
+    pub fn rate(&mut self) -> Option<f64> {
+        let now = self.clock.now();
+        let from = ::std::cmp::max(self.started, now - self.window);
+        self.forget_before(from);
+        let seconds = now.signed_duration_since(from).num_milliseconds() as f64 / 1000.0;
+        if seconds <= 0.0 {
+            return None;
+        }
+        let bytes: u64 = self.samples.iter().map(|&(_, bytes)| bytes).sum();
+        Some(bytes as f64 / seconds)
+    }
+
+    /// Time left to complete `total` bytes at the current rate. `None` while nothing is moving;
+    /// zero once `total` has been reached.
+    pub fn eta(&mut self, total: u64) -> Option<Duration> {
+        let left = total.saturating_sub(self.done);
+        if left == 0 {
+            return Some(Duration::zero());
+        }
+        match self.rate() {
+            Some(rate) if rate > 0.0 => Some(Duration::milliseconds(
+                (left as f64 / rate * 1000.0) as i64,
+            )),
+            _ => None,
+        }
+    }
+
+    fn forget_before(&mut self, from: DateTime<Utc>) {
+        while self.samples.front().map_or(false, |&(at, _)| at <= from) {
+            self.samples.pop_front();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::FakeClock;
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected * 0.01,
+            "{} is not within 1% of {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn rate_and_eta_follow_a_steady_stream() {
+        let clock = Arc::new(FakeClock::new(Utc::now()));
+        let mut throughput = Throughput::new(clock.clone(), Duration::seconds(10));
+        assert_eq!(throughput.rate(), None);
+
+        for _ in 0..30 {
+            clock.advance(Duration::milliseconds(100));
+            throughput.add(100);
+        }
+        assert_near(throughput.rate().unwrap(), 1000.0);
+        // 3000 of 10000 bytes are done; the other 7000 take 7 seconds.
+        let eta = throughput.eta(10_000).unwrap();
+        assert_near(eta.num_milliseconds() as f64, 7000.0);
+        assert_eq!(throughput.eta(3000), Some(Duration::zero()));
+    }
+
+    #[test]
+    fn rate_moves_with_the_window() {
+        let clock = Arc::new(FakeClock::new(Utc::now()));
+        let mut throughput = Throughput::new(clock.clone(), Duration::seconds(10));
+        for _ in 0..20 {
+            clock.advance(Duration::seconds(1));
+            throughput.add(5000);
+        }
+        for _ in 0..10 {
+            clock.advance(Duration::seconds(1));
+            throughput.add(1000);
+        }
+        // Only the last ten seconds count.
+        assert_near(throughput.rate().unwrap(), 1000.0);
+
+        // Half a second of nothing barely moves the estimate.
+        clock.advance(Duration::milliseconds(500));
+        assert!(throughput.rate().unwrap() > 900.0);
+
+        // With nothing done for a whole window, there is no telling when it will finish.
+        clock.advance(Duration::seconds(10));
+        assert_eq!(throughput.rate(), Some(0.0));
+        assert_eq!(throughput.eta(1_000_000), None);
+        assert_eq!(throughput.done(), 110_000);
+    }
+}
@@ -91,6 +91,13 @@ impl<P: Clone + Ord, K: Clone + Ord, V> UniquePriorityQueue<P, K, V> {
         cur.0 = Status::Ready;
     }
 
+    pub fn remove(&mut self, p: &P) -> Option<(K, V)> {
+        self.priority.remove(p).map(|(_status, k, v)| {
+            self.key_to_priority.remove(&k);
+            (k, v)
+        })
+    }
+
     pub fn pop_min_if_complete(&mut self) -> Option<(P, K, V)> {
         let min_opt = self.priority.pop_min_when(|_k, min| min.0 == Status::Ready);
         min_opt.map(|(p, (_status, k, v))| {